@@ -0,0 +1,38 @@
+-- optional email digests for mentions and DMs received while offline
+-- user_emails holds one address per matrix user plus their digest preference
+-- digest_frequency: 'off' | 'immediate' | 'daily'
+CREATE TABLE IF NOT EXISTS user_emails (
+    user_id VARCHAR(255) PRIMARY KEY,          -- matrix user_id
+    email VARCHAR(254) NOT NULL,
+    verified BOOLEAN NOT NULL DEFAULT FALSE,
+    -- single-use token mailed in the verification link
+    verify_token VARCHAR(64),
+    -- long-lived token for the no-login unsubscribe link
+    unsubscribe_token VARCHAR(64) NOT NULL,
+    digest_frequency VARCHAR(20) NOT NULL DEFAULT 'daily',
+    last_digest_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    CONSTRAINT user_emails_frequency_check CHECK (digest_frequency IN ('off', 'immediate', 'daily'))
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_user_emails_verify_token ON user_emails(verify_token);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_user_emails_unsubscribe_token ON user_emails(unsubscribe_token);
+
+-- pending / sent digest items. emailed_at is set once the item went out in a digest,
+-- and the unique constraint keeps a message from ever being recorded twice
+CREATE TABLE IF NOT EXISTS email_notifications (
+    id BIGSERIAL PRIMARY KEY,
+    recipient_id VARCHAR(255) NOT NULL REFERENCES user_emails(user_id) ON DELETE CASCADE,
+    room_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL,
+    sender_id VARCHAR(255) NOT NULL,
+    kind VARCHAR(20) NOT NULL,                 -- 'dm' | 'mention'
+    snippet TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    emailed_at TIMESTAMP WITH TIME ZONE,
+    CONSTRAINT email_notifications_unique UNIQUE (recipient_id, event_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_email_notifications_pending
+    ON email_notifications(recipient_id) WHERE emailed_at IS NULL;
@@ -0,0 +1,16 @@
+-- invite links for servers. a code can be limited by use count and/or expiry;
+-- revoked codes are kept (revoked_at set) so the audit trail survives
+CREATE TABLE IF NOT EXISTS server_invites (
+    code VARCHAR(32) PRIMARY KEY,
+    server_id VARCHAR(255) NOT NULL,        -- matrix room id of the server space
+    created_by VARCHAR(255) NOT NULL,       -- matrix user_id
+    max_uses INTEGER,                       -- NULL = unlimited
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP WITH TIME ZONE,    -- NULL = never
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    CONSTRAINT server_invites_uses_check CHECK (max_uses IS NULL OR uses <= max_uses)
+);
+
+CREATE INDEX IF NOT EXISTS idx_server_invites_server ON server_invites(server_id);
+CREATE INDEX IF NOT EXISTS idx_server_invites_creator ON server_invites(created_by);
@@ -0,0 +1,16 @@
+-- incoming webhooks that post into a channel without a matrix account.
+-- only a hash of the secret token is stored
+CREATE TABLE IF NOT EXISTS webhooks (
+    id VARCHAR(32) PRIMARY KEY,
+    server_id VARCHAR(255) NOT NULL,        -- matrix room id of the server space
+    room_id VARCHAR(255) NOT NULL,          -- channel the webhook posts into
+    name VARCHAR(80) NOT NULL,
+    avatar_url TEXT,
+    token_hash VARCHAR(64) NOT NULL,
+    created_by VARCHAR(255) NOT NULL,       -- matrix user_id
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhooks_server ON webhooks(server_id);
+CREATE INDEX IF NOT EXISTS idx_webhooks_room ON webhooks(room_id);
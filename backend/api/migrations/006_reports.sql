@@ -0,0 +1,24 @@
+-- user reports of messages, queued for the moderators of the server the room belongs to
+-- status: 'open' | 'dismissed' | 'actioned'
+CREATE TABLE IF NOT EXISTS reports (
+    id BIGSERIAL PRIMARY KEY,
+    server_id VARCHAR(255),                 -- NULL when the room isn't part of a server (e.g. a DM)
+    room_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL,
+    reporter_id VARCHAR(255) NOT NULL,      -- matrix user_id
+    reported_user_id VARCHAR(255) NOT NULL, -- sender of the reported event
+    reason TEXT,
+    score INTEGER,                          -- matrix report score, -100 (worst) to 0
+    status VARCHAR(20) NOT NULL DEFAULT 'open',
+    resolved_by VARCHAR(255),
+    resolution VARCHAR(32),                 -- 'dismiss' | 'redact' | 'timeout'
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    resolved_at TIMESTAMP WITH TIME ZONE,
+    CONSTRAINT reports_unique UNIQUE (reporter_id, event_id),
+    CONSTRAINT reports_status_check CHECK (status IN ('open', 'dismissed', 'actioned')),
+    CONSTRAINT reports_score_check CHECK (score IS NULL OR score BETWEEN -100 AND 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_reports_server_status ON reports(server_id, status);
+CREATE INDEX IF NOT EXISTS idx_reports_room ON reports(room_id);
+CREATE INDEX IF NOT EXISTS idx_reports_reported_user ON reports(reported_user_id);
@@ -0,0 +1,21 @@
+-- audit log of moderator actions (kicks, bans, timeouts, voice mutes, redactions)
+CREATE TABLE IF NOT EXISTS moderation_actions (
+    id BIGSERIAL PRIMARY KEY,
+    server_id VARCHAR(255),                 -- NULL for actions outside a server
+    room_id VARCHAR(255),                   -- NULL for server-wide actions
+    actor_id VARCHAR(255) NOT NULL,         -- matrix user_id of the moderator
+    target_user_id VARCHAR(255) NOT NULL,
+    action VARCHAR(32) NOT NULL,
+    reason TEXT,
+    -- the report this action resolved, if any
+    report_id BIGINT REFERENCES reports(id) ON DELETE SET NULL,
+    expires_at TIMESTAMP WITH TIME ZONE,    -- for timeouts
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    CONSTRAINT moderation_actions_action_check CHECK (action IN (
+        'kick', 'ban', 'unban', 'timeout', 'untimeout', 'mute', 'unmute', 'disconnect', 'redact'
+    ))
+);
+
+CREATE INDEX IF NOT EXISTS idx_moderation_actions_server ON moderation_actions(server_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_moderation_actions_target ON moderation_actions(target_user_id);
+CREATE INDEX IF NOT EXISTS idx_moderation_actions_actor ON moderation_actions(actor_id);
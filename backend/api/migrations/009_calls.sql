@@ -0,0 +1,27 @@
+-- call history for DM / group DM calls (signaling itself goes through matrix events)
+-- end_reason: 'completed' | 'missed' | 'declined' | 'cancelled' | 'timeout'
+CREATE TABLE IF NOT EXISTS calls (
+    call_id VARCHAR(255) PRIMARY KEY,
+    room_id VARCHAR(255) NOT NULL,
+    caller_id VARCHAR(255) NOT NULL,        -- matrix user_id
+    started_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    answered_at TIMESTAMP WITH TIME ZONE,
+    ended_at TIMESTAMP WITH TIME ZONE,
+    end_reason VARCHAR(20),
+    CONSTRAINT calls_end_reason_check CHECK (end_reason IS NULL OR end_reason IN (
+        'completed', 'missed', 'declined', 'cancelled', 'timeout'
+    ))
+);
+
+CREATE INDEX IF NOT EXISTS idx_calls_room ON calls(room_id, started_at);
+CREATE INDEX IF NOT EXISTS idx_calls_caller ON calls(caller_id);
+
+CREATE TABLE IF NOT EXISTS call_participants (
+    call_id VARCHAR(255) NOT NULL REFERENCES calls(call_id) ON DELETE CASCADE,
+    user_id VARCHAR(255) NOT NULL,
+    joined_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    left_at TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (call_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_call_participants_user ON call_participants(user_id);
@@ -0,0 +1,11 @@
+-- tokens that allow registration when REGISTRATION_MODE=token
+CREATE TABLE IF NOT EXISTS registration_tokens (
+    token VARCHAR(64) PRIMARY KEY,
+    uses_remaining INTEGER,                 -- NULL = unlimited
+    expires_at TIMESTAMP WITH TIME ZONE,    -- NULL = never
+    created_by VARCHAR(255) NOT NULL,       -- matrix user_id of the admin
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    CONSTRAINT registration_tokens_uses_check CHECK (uses_remaining IS NULL OR uses_remaining >= 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_registration_tokens_expiry ON registration_tokens(expires_at);
@@ -0,0 +1,7 @@
+-- per-user client settings, for homeservers that refuse custom account data
+-- (agora.settings is kept in matrix account data when the homeserver allows it)
+CREATE TABLE IF NOT EXISTS user_settings (
+    user_id VARCHAR(255) PRIMARY KEY,
+    settings JSONB NOT NULL DEFAULT '{}'::jsonb,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
@@ -0,0 +1,11 @@
+-- profile fields matrix has no place for: the bio, banner and accent colour of
+-- the profile card, and pronouns. merged into the matrix profile by /profile/get.
+-- length limits are checked in characters by the api, so the text columns are unbounded.
+CREATE TABLE IF NOT EXISTS agora_profiles (
+    user_id VARCHAR(255) PRIMARY KEY,
+    bio TEXT,
+    banner_url TEXT,
+    accent_color VARCHAR(7),
+    pronouns TEXT,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
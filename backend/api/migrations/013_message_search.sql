@@ -0,0 +1,22 @@
+-- the full-text search index: every m.room.message the search indexer's
+-- account sees, kept current with edits and redactions. 'simple' rather than
+-- a language config since channels aren't all in english — no stemming, but
+-- no wrong stemming either.
+CREATE TABLE IF NOT EXISTS messages (
+    event_id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    body TEXT NOT NULL,
+    ts BIGINT NOT NULL,
+    body_tsv TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', body)) STORED
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_body_tsv ON messages USING GIN (body_tsv);
+CREATE INDEX IF NOT EXISTS idx_messages_room_ts ON messages (room_id, ts DESC);
+
+-- where the indexer's /sync left off, so a restart resumes instead of starting over
+CREATE TABLE IF NOT EXISTS search_index_state (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    next_batch TEXT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
@@ -0,0 +1,14 @@
+-- channels following an announcement channel: published messages are copied
+-- into every target. the copy is sent with the token of whoever followed, since
+-- they're the one with a say over the target channel
+CREATE TABLE IF NOT EXISTS announcement_follows (
+    source_room_id VARCHAR(255) NOT NULL,   -- the announcement channel
+    target_room_id VARCHAR(255) NOT NULL,   -- the channel copies land in
+    followed_by VARCHAR(255) NOT NULL,      -- matrix user_id
+    access_token TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (source_room_id, target_room_id),
+    CONSTRAINT announcement_follows_distinct_check CHECK (source_room_id <> target_room_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_announcement_follows_target ON announcement_follows(target_room_id);
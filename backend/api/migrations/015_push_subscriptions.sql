@@ -0,0 +1,16 @@
+-- web push subscriptions, one row per browser a user turned notifications on in.
+-- the endpoint is the push service url the browser handed out, and unique to it
+CREATE TABLE IF NOT EXISTS push_subscriptions (
+    id BIGSERIAL PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL,             -- matrix user_id
+    device_id VARCHAR(255),                    -- the matrix device that subscribed, when known
+    endpoint TEXT NOT NULL UNIQUE,
+    p256dh TEXT NOT NULL,                      -- the browser's public key, base64url
+    auth TEXT NOT NULL,                        -- the shared auth secret, base64url
+    -- keep pushing while the user is on do not disturb
+    during_dnd BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_push_subscriptions_user ON push_subscriptions(user_id);
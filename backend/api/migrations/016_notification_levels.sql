@@ -0,0 +1,12 @@
+-- per-room notification levels, the api's copy of agora.notifications.room.*
+-- account data (the push fan-out can't read another user's account data).
+-- level: 'all' | 'mentions' | 'muted'; a row on a space covers the rooms under it
+CREATE TABLE IF NOT EXISTS room_notification_levels (
+    user_id VARCHAR(255) NOT NULL,             -- matrix user_id
+    room_id VARCHAR(255) NOT NULL,             -- a room, or a space
+    level VARCHAR(20) NOT NULL,
+    until_ts BIGINT,                           -- unix ms it stops applying at, when temporary
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    PRIMARY KEY (user_id, room_id),
+    CONSTRAINT room_notification_levels_level_check CHECK (level IN ('all', 'mentions', 'muted'))
+);
@@ -0,0 +1,83 @@
+// avatar.rs — checking and shrinking uploaded avatars
+// avatars are shown at 128px at most, so anything bigger than MAX_AVATAR_SIDE is
+// scaled down before it reaches the media repo. the format is sniffed from the
+// bytes rather than trusted from the upload, and anything that doesn't decode
+// as an image is refused. gifs are passed through untouched so animated
+// avatars keep their frames.
+
+use image::{ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+/// the largest avatar upload accepted, before any resizing
+pub const MAX_AVATAR_BYTES: usize = 8 * 1024 * 1024;
+
+/// avatars are scaled to fit within this many pixels on each side
+pub const MAX_AVATAR_SIDE: u32 = 512;
+
+/// refuse to decode anything claiming to be larger than this, whatever its
+/// file size — a tiny png can declare a huge canvas
+const MAX_DECODE_SIDE: u32 = 16384;
+
+/// an avatar ready for upload
+pub struct Avatar {
+    pub content_type: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum AvatarError {
+    TooLarge,
+    NotAnImage,
+}
+
+impl std::fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge => write!(f, "avatar exceeds {} bytes", MAX_AVATAR_BYTES),
+            Self::NotAnImage => write!(f, "avatar must be a png, jpeg, gif or webp image"),
+        }
+    }
+}
+
+/// validate an uploaded avatar and downscale it if needed. jpegs stay jpegs;
+/// other resized formats come back as png.
+pub fn prepare(bytes: Vec<u8>) -> Result<Avatar, AvatarError> {
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(AvatarError::TooLarge);
+    }
+
+    let format = image::guess_format(&bytes).map_err(|_| AvatarError::NotAnImage)?;
+    let content_type = match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        _ => return Err(AvatarError::NotAnImage),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODE_SIDE);
+    limits.max_image_height = Some(MAX_DECODE_SIDE);
+    let mut reader = ImageReader::with_format(Cursor::new(&bytes), format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|_| AvatarError::NotAnImage)?;
+
+    let fits = image.width() <= MAX_AVATAR_SIDE && image.height() <= MAX_AVATAR_SIDE;
+    if fits || format == ImageFormat::Gif {
+        return Ok(Avatar { content_type, bytes });
+    }
+
+    let resized = image.resize(MAX_AVATAR_SIDE, MAX_AVATAR_SIDE, image::imageops::FilterType::Lanczos3);
+    let (format, content_type) = match format {
+        ImageFormat::Jpeg => (ImageFormat::Jpeg, "image/jpeg"),
+        _ => (ImageFormat::Png, "image/png"),
+    };
+    let resized = match format {
+        // the jpeg encoder has no alpha channel
+        ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(resized.to_rgb8()),
+        _ => resized,
+    };
+    let mut out = Cursor::new(Vec::new());
+    resized.write_to(&mut out, format).map_err(|_| AvatarError::NotAnImage)?;
+    Ok(Avatar { content_type, bytes: out.into_inner() })
+}
@@ -0,0 +1,355 @@
+// content.rs — the one sanitization path for user-supplied rich text
+// everything that ends up as org.matrix.custom.html goes through sanitize_html:
+// message sends, markdown rendering, and anything else that accepts html later.
+// the allowlist follows the matrix spec's recommended tags/attributes, with
+// links limited to safe schemes and images limited to mxc:// media.
+// mentions live here too: finding @names in a body and turning them into pills.
+
+use ammonia::Builder;
+use pulldown_cmark::{html, Options, Parser};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+/// html input larger than this is truncated before parsing (matrix events cap at 64KiB anyway)
+pub const MAX_HTML_LEN: usize = 64 * 1024;
+/// markdown input larger than this is truncated before rendering
+pub const MAX_MARKDOWN_LEN: usize = 64 * 1024;
+/// elements nested deeper than this are unwrapped (their text is kept)
+pub const MAX_NESTING_DEPTH: usize = 100;
+
+// matrix spec: client-server api, m.room.message msgtypes, "m.text" html subset
+const ALLOWED_TAGS: &[&str] = &[
+    "font", "del", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "p", "a", "ul", "ol",
+    "sup", "sub", "li", "b", "i", "u", "strong", "em", "s", "code", "hr", "br", "div",
+    "table", "thead", "tbody", "tr", "th", "td", "caption", "pre", "span", "img",
+    "details", "summary",
+];
+const LINK_SCHEMES: &[&str] = &["http", "https", "ftp", "mailto", "magnet"];
+// void elements never take children so they don't count towards depth
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+fn sanitizer() -> &'static Builder<'static> {
+    static SANITIZER: OnceLock<Builder<'static>> = OnceLock::new();
+    SANITIZER.get_or_init(|| {
+        let mut tag_attributes: HashMap<&'static str, HashSet<&'static str>> = HashMap::new();
+        tag_attributes.insert("font", ["data-mx-bg-color", "data-mx-color", "color"].into());
+        tag_attributes.insert(
+            "span",
+            ["data-mx-bg-color", "data-mx-color", "data-mx-spoiler", "data-mx-maths"].into(),
+        );
+        tag_attributes.insert("div", ["data-mx-maths"].into());
+        tag_attributes.insert("a", ["name", "target", "href"].into());
+        tag_attributes.insert("img", ["width", "height", "alt", "title", "src"].into());
+        tag_attributes.insert("ol", ["start"].into());
+        tag_attributes.insert("code", ["class"].into());
+
+        let mut url_schemes: HashSet<&'static str> = LINK_SCHEMES.iter().copied().collect();
+        url_schemes.insert("mxc");
+
+        let mut builder = Builder::empty();
+        builder
+            .tags(ALLOWED_TAGS.iter().copied().collect())
+            .tag_attributes(tag_attributes)
+            .url_schemes(url_schemes)
+            .url_relative(ammonia::UrlRelative::Deny)
+            .link_rel(Some("noopener noreferrer"))
+            // reply fallbacks are dropped entirely — clients render the reply from m.relates_to
+            .clean_content_tags(["mx-reply", "script", "style"].into())
+            .strip_comments(true)
+            .attribute_filter(filter_attribute);
+        builder
+    })
+}
+
+/// per-attribute checks the allowlist can't express on its own
+fn filter_attribute<'u>(element: &str, attribute: &str, value: &'u str) -> Option<Cow<'u, str>> {
+    match (element, attribute) {
+        // images may only point at homeserver media, never at arbitrary urls
+        ("img", "src") => value.starts_with("mxc://").then_some(Cow::Borrowed(value)),
+        // mxc is allowed for images but isn't a link scheme
+        ("a", "href") => (!value.starts_with("mxc:")).then_some(Cow::Borrowed(value)),
+        // only syntax highlighting hints survive on code blocks
+        ("code", "class") => {
+            let ok = value.starts_with("language-") && !value.contains(char::is_whitespace);
+            ok.then_some(Cow::Borrowed(value))
+        }
+        ("font", "color") | (_, "data-mx-color") | (_, "data-mx-bg-color") => {
+            is_hex_color(value).then_some(Cow::Borrowed(value))
+        }
+        _ => Some(Cow::Borrowed(value)),
+    }
+}
+
+fn is_hex_color(value: &str) -> bool {
+    value.len() == 7
+        && value.starts_with('#')
+        && value[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// truncate to at most `max` bytes without splitting a utf-8 character
+fn truncate_str(input: &str, max: usize) -> &str {
+    if input.len() <= max {
+        return input;
+    }
+    let mut end = max;
+    while !input.is_char_boundary(end) {
+        end -= 1;
+    }
+    &input[..end]
+}
+
+/// sanitize user-supplied html down to the matrix-safe subset.
+/// never panics, and output size is bounded by MAX_HTML_LEN-ish input.
+pub fn sanitize_html(html: &str) -> String {
+    let input = truncate_str(html, MAX_HTML_LEN);
+    let cleaned = sanitizer().clean(input).to_string();
+    limit_depth(&cleaned, MAX_NESTING_DEPTH)
+}
+
+/// render commonmark (plus strikethrough and tables) and sanitize the result
+pub fn render_markdown(markdown: &str) -> String {
+    let input = truncate_str(markdown, MAX_MARKDOWN_LEN);
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+
+    let mut rendered = String::with_capacity(input.len() * 3 / 2);
+    html::push_html(&mut rendered, Parser::new_ext(input, options));
+    sanitize_html(rendered.trim_end())
+}
+
+/// the sanitized html of a received message, if it has any. events from other
+/// clients never went through sanitize_html on the way in, so they do here.
+pub fn html_body(content: &serde_json::Value) -> Option<String> {
+    if content.get("format")?.as_str()? != "org.matrix.custom.html" {
+        return None;
+    }
+    Some(sanitize_html(content.get("formatted_body")?.as_str()?))
+}
+
+/// the m.mentions block of a message: who it pings on purpose
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mentions {
+    pub user_ids: Vec<String>,
+    /// @everyone — the whole room
+    pub room: bool,
+}
+
+impl Mentions {
+    /// None for messages from clients that don't send m.mentions
+    pub fn from_content(content: &serde_json::Value) -> Option<Self> {
+        let mentions = content.get("m.mentions")?;
+        let user_ids = mentions["user_ids"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|u| u.as_str().map(String::from))
+            .collect();
+        Some(Self { user_ids, room: mentions["room"].as_bool() == Some(true) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.user_ids.is_empty() && !self.room
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut mentions = serde_json::json!({ "user_ids": self.user_ids });
+        if self.room {
+            mentions["room"] = serde_json::Value::Bool(true);
+        }
+        mentions
+    }
+}
+
+/// byte length of a mention of `user_id` starting at `text[i..]` — the full
+/// mxid, or @localpart ending on a word boundary so @al doesn't ping @alice
+fn mention_len(text: &str, i: usize, user_id: &str) -> Option<usize> {
+    let rest = &text[i..];
+    // (a bare @name like @everyone only matches below, on a word boundary)
+    if user_id.contains(':') && rest.starts_with(user_id) {
+        return Some(user_id.len());
+    }
+    let localpart = user_id.trim_start_matches('@').split(':').next().unwrap_or_default();
+    if localpart.is_empty() {
+        return None;
+    }
+    let after = rest.strip_prefix('@')?.strip_prefix(localpart)?;
+    let at_boundary = after
+        .chars()
+        .next()
+        .is_none_or(|c| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'));
+    at_boundary.then_some(localpart.len() + 1)
+}
+
+/// true if `body` mentions `user_id` either by full mxid or by @localpart
+pub fn mentions_user(body: &str, user_id: &str) -> bool {
+    body.match_indices('@').any(|(i, _)| mention_len(body, i, user_id).is_some())
+}
+
+/// true if `body` pings the whole room
+pub fn mentions_everyone(body: &str) -> bool {
+    mentions_user(body, "@everyone")
+}
+
+/// plain text as html: escaped, line breaks kept
+pub fn text_to_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "<br>")
+}
+
+/// link every mention of `user_ids` in sanitized `html` to its user, the way
+/// matrix clients render pills. text inside links and code is left alone.
+pub fn pill_mentions(html: &str, user_ids: &[String]) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    // how many <a>, <code> or <pre> elements we're inside
+    let mut verbatim = 0usize;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            let tag = &rest[..end];
+            let name: String = tag
+                .trim_start_matches(['<', '/'])
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            if matches!(name.as_str(), "a" | "code" | "pre") {
+                if tag.starts_with("</") {
+                    verbatim = verbatim.saturating_sub(1);
+                } else {
+                    verbatim += 1;
+                }
+            }
+            out.push_str(tag);
+            rest = &rest[end..];
+            continue;
+        }
+        let end = rest.find('<').unwrap_or(rest.len());
+        let text = &rest[..end];
+        if verbatim > 0 {
+            out.push_str(text);
+        } else {
+            pill_text(text, user_ids, &mut out);
+        }
+        rest = &rest[end..];
+    }
+    out
+}
+
+fn pill_text(text: &str, user_ids: &[String], out: &mut String) {
+    let mut copied = 0;
+    for (i, _) in text.match_indices('@') {
+        if i < copied {
+            continue;
+        }
+        // the longest match wins, so @alice:hs isn't cut short by @alice
+        let found = user_ids
+            .iter()
+            .filter_map(|user_id| mention_len(text, i, user_id).map(|len| (user_id, len)))
+            .max_by_key(|(_, len)| *len);
+        if let Some((user_id, len)) = found {
+            out.push_str(&text[copied..i]);
+            out.push_str(&format!("<a href=\"https://matrix.to/#/{}\">{}</a>", user_id, &text[i..i + len]));
+            copied = i + len;
+        }
+    }
+    out.push_str(&text[copied..]);
+}
+
+/// strip the "> <@user> quoted text" fallback from the plain body of a reply,
+/// so clients that render the reply themselves don't show the quote twice
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    let mut rest = body;
+    while rest.starts_with('>') {
+        match rest.find('\n') {
+            Some(i) => rest = &rest[i + 1..],
+            None => return "",
+        }
+    }
+    // the fallback is separated from the real message by a blank line
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+/// `mxc://<server>/<media id>` — the only kind of url attachments may point at
+pub fn is_mxc_uri(uri: &str) -> bool {
+    let Some((server, media_id)) = uri.strip_prefix("mxc://").and_then(|rest| rest.split_once('/')) else {
+        return false;
+    };
+    !server.is_empty() && !media_id.is_empty() && !media_id.contains('/')
+}
+
+/// an absolute http or https url — the only kind of link webhook embeds may carry
+pub fn is_web_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.has_host())
+}
+
+/// unwrap elements nested deeper than `max_depth`, keeping their text.
+/// only safe on sanitizer output: every `<` there starts a tag and attribute
+/// values are always double-quoted.
+fn limit_depth(html: &str, max_depth: usize) -> String {
+    let mut out = String::with_capacity(html.len());
+    // whether each currently open element was emitted
+    let mut open: Vec<bool> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // find the end of the tag, skipping '>' inside quoted attribute values
+        let mut in_quotes = false;
+        let mut end = None;
+        for (i, c) in rest.char_indices().skip(1) {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                '>' if !in_quotes => {
+                    end = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let Some(end) = end else {
+            // unterminated tag — can't happen with sanitizer output, drop the tail
+            break;
+        };
+
+        let tag = &rest[..=end];
+        let closing = tag.starts_with("</");
+        let name: String = tag
+            .trim_start_matches("</")
+            .trim_start_matches('<')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect::<String>()
+            .to_ascii_lowercase();
+
+        if closing {
+            if open.pop().unwrap_or(false) {
+                out.push_str(tag);
+            }
+        } else if VOID_TAGS.contains(&name.as_str()) {
+            if open.len() < max_depth {
+                out.push_str(tag);
+            }
+        } else {
+            let keep = open.len() < max_depth;
+            if keep {
+                out.push_str(tag);
+            }
+            open.push(keep);
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest);
+    out
+}
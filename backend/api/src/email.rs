@@ -0,0 +1,468 @@
+// email.rs — optional email digests for mentions and DMs received while offline
+// everything here is a no-op unless SMTP_HOST is configured and the database is up.
+// addresses, verification state and digest prefs live in postgres (user_emails)
+// because the digest worker has no user access token to read matrix account data.
+
+use lettre::{
+    message::header::ContentType,
+    transport::smtp::authentication::Credentials,
+    transport::stub::AsyncStubTransport,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use redis::AsyncCommands;
+use sqlx::Row;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::client::MatrixClient;
+use crate::matrix::encode_path_segment;
+
+// how often the digest worker wakes up to look for pending notifications
+const DIGEST_INTERVAL_SECS: u64 = 60;
+// "daily" digests are sent at most once per this many seconds
+const DAILY_DIGEST_SECS: i64 = 24 * 3600;
+// cap the number of notifications listed in a single digest email
+const MAX_DIGEST_ITEMS: usize = 50;
+// snippets are trimmed so a huge message doesn't end up verbatim in an inbox
+const SNIPPET_MAX_CHARS: usize = 140;
+
+/// how often a user wants to be emailed about missed mentions / DMs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestFrequency {
+    Off,
+    Immediate,
+    Daily,
+}
+
+impl DigestFrequency {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(Self::Off),
+            "immediate" => Some(Self::Immediate),
+            "daily" => Some(Self::Daily),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Immediate => "immediate",
+            Self::Daily => "daily",
+        }
+    }
+}
+
+/// the transport used to deliver mail — smtp in production, a stub in tests
+#[derive(Clone)]
+pub enum EmailTransport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Stub(AsyncStubTransport),
+}
+
+#[derive(Clone)]
+pub struct EmailConfig {
+    pub transport: EmailTransport,
+    /// the From: mailbox, e.g. "agora <noreply@example.org>"
+    pub from: String,
+    /// public base url of this api, used to build verify / unsubscribe links
+    pub public_url: String,
+}
+
+impl EmailConfig {
+    /// build the smtp config from env — returns None (email disabled) when SMTP_HOST is unset
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("SMTP_HOST").ok().filter(|h| !h.is_empty())?;
+        let port = std::env::var("SMTP_PORT").ok().and_then(|p| p.parse::<u16>().ok());
+
+        let builder = match AsyncSmtpTransport::<Tokio1Executor>::relay(&host) {
+            Ok(b) => b,
+            Err(e) => {
+                tracing::warn!("invalid SMTP_HOST {}: {}. email disabled.", host, e);
+                return None;
+            }
+        };
+        let builder = match port {
+            Some(p) => builder.port(p),
+            None => builder,
+        };
+        let builder = match (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD")) {
+            (Ok(user), Ok(pass)) => builder.credentials(Credentials::new(user, pass)),
+            _ => builder,
+        };
+
+        Some(Self {
+            transport: EmailTransport::Smtp(builder.build()),
+            from: std::env::var("SMTP_FROM")
+                .unwrap_or_else(|_| format!("agora <noreply@{}>", host)),
+            public_url: public_url(),
+        })
+    }
+
+    /// an email config that records messages instead of sending them
+    pub fn stub(transport: AsyncStubTransport) -> Self {
+        Self {
+            transport: EmailTransport::Stub(transport),
+            from: "agora <noreply@localhost>".to_string(),
+            public_url: public_url(),
+        }
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), String> {
+        let message = Message::builder()
+            .from(self.from.parse().map_err(|e| format!("bad from address: {e}"))?)
+            .to(to.parse().map_err(|e| format!("bad recipient address: {e}"))?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| e.to_string())?;
+
+        match &self.transport {
+            EmailTransport::Smtp(t) => t.send(message).await.map(|_| ()).map_err(|e| e.to_string()),
+            EmailTransport::Stub(t) => t.send(message).await.map(|_| ()).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+fn public_url() -> String {
+    std::env::var("PUBLIC_API_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+/// a random url-safe token for verification / unsubscribe links
+pub fn new_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// basic shape check — the real validation is the verification email bouncing or not
+pub fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else { return false };
+    !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && email.len() <= 254
+        && !email.chars().any(|c| c.is_whitespace())
+}
+
+// ── registration / verification ───────────────────────────────────────────────
+
+/// store (or replace) a user's email address and send the verification link.
+/// a changed address starts unverified again.
+pub async fn set_address(
+    email_config: &EmailConfig,
+    pool: &sqlx::PgPool,
+    user_id: &str,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    let verify_token = new_token();
+    sqlx::query(
+        r#"
+        INSERT INTO user_emails (user_id, email, verified, verify_token, unsubscribe_token)
+        VALUES ($1, $2, FALSE, $3, $4)
+        ON CONFLICT (user_id) DO UPDATE
+            SET email = EXCLUDED.email,
+                verified = FALSE,
+                verify_token = EXCLUDED.verify_token,
+                updated_at = NOW()
+        "#,
+    )
+    .bind(user_id)
+    .bind(email)
+    .bind(&verify_token)
+    .bind(new_token())
+    .execute(pool)
+    .await?;
+
+    let link = format!("{}/account/email/verify?token={}", email_config.public_url, verify_token);
+    let body = format!(
+        "hi {user_id},\n\nconfirm this address to receive agora notification digests:\n\n{link}\n\nif you didn't ask for this, ignore this email.\n"
+    );
+    if let Err(e) = email_config.send(email, "confirm your email for agora", body).await {
+        tracing::warn!("failed to send verification email: {}", e);
+    }
+    Ok(())
+}
+
+// ── notification capture ──────────────────────────────────────────────────────
+
+/// called after a message is sent through the api: records a pending email
+/// notification for every offline recipient who was DMed or mentioned and
+/// has a verified address with digests enabled. runs in a spawned task so the
+/// send request isn't slowed down.
+pub fn record_message(
+    state: Arc<AppState>,
+    matrix: MatrixClient,
+    room_id: String,
+    event_id: String,
+    body: String,
+) {
+    if state.email.is_none() || state.db_pool.is_none() || event_id.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = capture(&state, &matrix, &room_id, &event_id, &body).await {
+            tracing::debug!("email capture skipped for {}: {}", event_id, e);
+        }
+    });
+}
+
+async fn capture(
+    state: &AppState,
+    matrix: &MatrixClient,
+    room_id: &str,
+    event_id: &str,
+    body: &str,
+) -> Result<(), String> {
+    let pool = state.db_pool.as_ref().ok_or("no database")?;
+
+    // the send response doesn't include the sender, so read the event back
+    let event_url = format!(
+        "{}/rooms/{}/event/{}",
+        matrix.client_api_base().await,
+        encode_path_segment(room_id),
+        encode_path_segment(event_id)
+    );
+    let event = matrix.get_raw(&event_url).await.map_err(|e| e.to_string())?;
+    let sender = event["sender"].as_str().ok_or("event has no sender")?.to_string();
+
+    let recipients = notification_recipients(pool, matrix, room_id, &sender, body).await?;
+
+    for (recipient, kind) in recipients {
+        if is_online(state, &recipient).await {
+            continue;
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO email_notifications (recipient_id, room_id, event_id, sender_id, kind, snippet)
+            SELECT $1, $2, $3, $4, $5, $6
+            WHERE EXISTS (
+                SELECT 1 FROM user_emails
+                WHERE user_id = $1 AND verified AND digest_frequency != 'off'
+            )
+            ON CONFLICT (recipient_id, event_id) DO NOTHING
+            "#,
+        )
+        .bind(&recipient)
+        .bind(room_id)
+        .bind(event_id)
+        .bind(&sender)
+        .bind(kind)
+        .bind(snippet(body))
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// who a message notifies: the other side of a DM, or else every joined
+/// member it mentions, with "dm" / "mention". the web push fan-out asks too
+pub async fn notification_recipients(
+    pool: &sqlx::PgPool,
+    matrix: &MatrixClient,
+    room_id: &str,
+    sender: &str,
+    body: &str,
+) -> Result<Vec<(String, &'static str)>, String> {
+    let mut recipients: Vec<(String, &'static str)> = Vec::new();
+
+    // DMs are the rooms cached on accepted friendships
+    let dm_row = sqlx::query(
+        "SELECT requester_id, addressee_id FROM friends WHERE dm_room_id = $1 LIMIT 1",
+    )
+    .bind(room_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if let Some(row) = dm_row {
+        let requester: String = row.get("requester_id");
+        let addressee: String = row.get("addressee_id");
+        let other = if requester == sender { addressee } else { requester };
+        recipients.push((other, "dm"));
+    } else if body.contains('@') {
+        // mentions: match @localpart or the full mxid of any joined member
+        let members = matrix.get_room_members(room_id.to_string()).await.map_err(|e| e.to_string())?;
+        for m in members.members {
+            if m.content.membership.as_deref() != Some("join") || m.state_key == sender {
+                continue;
+            }
+            if crate::content::mentions_user(body, &m.state_key) {
+                recipients.push((m.state_key, "mention"));
+            }
+        }
+    }
+
+    Ok(recipients)
+}
+
+fn snippet(body: &str) -> String {
+    let mut s: String = body.chars().take(SNIPPET_MAX_CHARS).collect();
+    if body.chars().count() > SNIPPET_MAX_CHARS {
+        s.push('…');
+    }
+    s
+}
+
+/// a user is offline when their presence key is absent. without redis we
+/// can't tell, so we assume online and send nothing rather than spam.
+async fn is_online(state: &AppState, user_id: &str) -> bool {
+    let Some(mut redis) = state.get_redis().await else { return true };
+    let value: Option<String> = redis.get(format!("presence:{}", user_id)).await.unwrap_or(None);
+    value.is_some()
+}
+
+// ── digest batching ───────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone)]
+pub struct PendingNotification {
+    pub id: i64,
+    pub room_id: String,
+    pub sender_id: String,
+    pub kind: String,
+    pub snippet: String,
+}
+
+#[derive(Debug)]
+pub struct Digest {
+    pub subject: String,
+    pub body: String,
+    /// ids of the notifications included — marked as emailed once sent
+    pub included: Vec<i64>,
+}
+
+/// group pending notifications into one email. returns None when there is nothing to send.
+pub fn build_digest(
+    user_id: &str,
+    pending: &[PendingNotification],
+    unsubscribe_link: &str,
+) -> Option<Digest> {
+    if pending.is_empty() {
+        return None;
+    }
+
+    let items = &pending[..pending.len().min(MAX_DIGEST_ITEMS)];
+    let dms = items.iter().filter(|n| n.kind == "dm").count();
+    let mentions = items.len() - dms;
+
+    let subject = match (dms, mentions) {
+        (0, m) => format!("{m} new mention{} on agora", if m == 1 { "" } else { "s" }),
+        (d, 0) => format!("{d} new direct message{} on agora", if d == 1 { "" } else { "s" }),
+        (d, m) => format!("{d} direct message{} and {m} mention{} on agora",
+            if d == 1 { "" } else { "s" }, if m == 1 { "" } else { "s" }),
+    };
+
+    // keep rooms in first-seen order so the email reads chronologically
+    let mut rooms: Vec<(&str, Vec<&PendingNotification>)> = Vec::new();
+    for n in items {
+        match rooms.iter_mut().find(|(r, _)| *r == n.room_id) {
+            Some((_, list)) => list.push(n),
+            None => rooms.push((&n.room_id, vec![n])),
+        }
+    }
+
+    let mut body = format!("hi {user_id}, here's what you missed while you were away:\n");
+    for (room_id, list) in rooms {
+        let label = if list.iter().all(|n| n.kind == "dm") { "direct message" } else { "mentioned in" };
+        body.push_str(&format!("\n{label} {room_id}\n"));
+        for n in list {
+            body.push_str(&format!("  {}: {}\n", n.sender_id, n.snippet));
+        }
+    }
+    if pending.len() > items.len() {
+        body.push_str(&format!("\n…and {} more.\n", pending.len() - items.len()));
+    }
+    body.push_str(&format!("\nto stop these emails: {unsubscribe_link}\n"));
+
+    Some(Digest {
+        subject,
+        body,
+        included: pending.iter().map(|n| n.id).collect(),
+    })
+}
+
+/// background loop that emails digests — spawned from main.rs when email is enabled
+pub async fn run_digest_worker(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(DIGEST_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = send_due_digests(&state).await {
+            tracing::warn!("email digest run failed: {}", e);
+        }
+    }
+}
+
+pub async fn send_due_digests(state: &AppState) -> Result<usize, sqlx::Error> {
+    let (Some(email_config), Some(pool)) = (state.email.as_ref(), state.db_pool.as_ref()) else {
+        return Ok(0);
+    };
+
+    // users with something pending whose frequency says a digest is due now
+    let due = sqlx::query(
+        r#"
+        SELECT user_id, email, unsubscribe_token
+        FROM user_emails
+        WHERE verified
+          AND (digest_frequency = 'immediate'
+               OR (digest_frequency = 'daily'
+                   AND (last_digest_at IS NULL OR last_digest_at < NOW() - make_interval(secs => $1))))
+          AND EXISTS (
+              SELECT 1 FROM email_notifications n
+              WHERE n.recipient_id = user_emails.user_id AND n.emailed_at IS NULL
+          )
+        "#,
+    )
+    .bind(DAILY_DIGEST_SECS as f64)
+    .fetch_all(pool)
+    .await?;
+
+    let mut sent = 0;
+    for row in due {
+        let user_id: String = row.get("user_id");
+        let address: String = row.get("email");
+        let unsubscribe_token: String = row.get("unsubscribe_token");
+
+        let pending: Vec<PendingNotification> = sqlx::query(
+            r#"
+            SELECT id, room_id, sender_id, kind, snippet
+            FROM email_notifications
+            WHERE recipient_id = $1 AND emailed_at IS NULL
+            ORDER BY created_at ASC
+            "#,
+        )
+        .bind(&user_id)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| PendingNotification {
+            id: r.get("id"),
+            room_id: r.get("room_id"),
+            sender_id: r.get("sender_id"),
+            kind: r.get("kind"),
+            snippet: r.get("snippet"),
+        })
+        .collect();
+
+        let link = format!("{}/email/unsubscribe?token={}", email_config.public_url, unsubscribe_token);
+        let Some(digest) = build_digest(&user_id, &pending, &link) else { continue };
+
+        if let Err(e) = email_config.send(&address, &digest.subject, digest.body).await {
+            // leave the rows pending so the next run retries
+            tracing::warn!("failed to send digest to {}: {}", user_id, e);
+            continue;
+        }
+
+        sqlx::query("UPDATE email_notifications SET emailed_at = NOW() WHERE id = ANY($1)")
+            .bind(&digest.included)
+            .execute(pool)
+            .await?;
+        sqlx::query("UPDATE user_emails SET last_digest_at = NOW() WHERE user_id = $1")
+            .bind(&user_id)
+            .execute(pool)
+            .await?;
+        sent += 1;
+    }
+
+    Ok(sent)
+}
@@ -0,0 +1,63 @@
+// idempotency.rs — retried sends. a client that retries a send (after a timeout,
+// say) passes the txn_id it picked the first time again. the homeserver gets a
+// txn id made from it, so a retry while the first attempt is in flight is
+// deduplicated there; and once a send went out its event id is kept in redis
+// for TXN_TTL_SECS under the caller's token, so a retry that comes after
+// answers with it without sending anything or re-running slowmode and pushes.
+
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+use crate::app_state::AppState;
+
+const TXN_TTL_SECS: u64 = 600;
+const MAX_TXN_ID_LEN: usize = 64;
+
+/// what's being sent — each kind has its own txn ids
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Message,
+    Call,
+    Raid,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Message => "message",
+            Kind::Call => "call",
+            Kind::Raid => "raid",
+        }
+    }
+}
+
+/// txn ids go into a homeserver path: letters, digits and -_.~, not too long
+pub fn is_valid(txn_id: &str) -> bool {
+    !txn_id.is_empty()
+        && txn_id.len() <= MAX_TXN_ID_LEN
+        && txn_id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.~".contains(&b))
+}
+
+/// the txn id the homeserver sees for a client's
+pub fn homeserver_txn_id(kind: Kind, txn_id: &str) -> String {
+    format!("agora.{}.{}", kind.as_str(), txn_id)
+}
+
+fn key(access_token: &str, kind: Kind, txn_id: &str) -> String {
+    format!("txn:{:x}:{}:{}", Sha256::digest(access_token.as_bytes()), kind.as_str(), txn_id)
+}
+
+/// the event an earlier send with this txn id made, if it was recent
+pub async fn sent(state: &AppState, access_token: &str, kind: Kind, txn_id: &str) -> Option<String> {
+    let mut redis = state.get_redis().await?;
+    redis.get(key(access_token, kind, txn_id)).await.ok().flatten()
+}
+
+pub async fn remember(state: &AppState, access_token: &str, kind: Kind, txn_id: &str, event_id: &str) {
+    let Some(mut redis) = state.get_redis().await else {
+        return;
+    };
+    let stored: redis::RedisResult<()> = redis.set_ex(key(access_token, kind, txn_id), event_id, TXN_TTL_SECS).await;
+    if let Err(e) = stored {
+        tracing::warn!("failed to remember txn {}: {}", txn_id, e);
+    }
+}
@@ -0,0 +1,69 @@
+// the api as a library: main.rs runs it, tests/ drive the same router in-process
+
+pub mod app_state;
+pub mod avatar;
+pub mod content;
+pub mod email;
+pub mod idempotency;
+pub mod link_preview;
+pub mod matrix;
+pub mod media;
+pub mod notification_levels;
+pub mod openapi;
+pub mod pagination;
+pub mod profiles;
+pub mod push;
+pub mod rate_limit;
+pub mod redact;
+pub mod redis_manager;
+pub mod registration;
+pub mod request_id;
+pub mod room_summaries;
+pub mod routes;
+pub mod search;
+pub mod seed;
+pub mod service_account;
+pub mod session;
+pub mod shutdown;
+pub mod translate;
+
+use axum::Router;
+use std::sync::Arc;
+use crate::app_state::AppState;
+
+/// the router with its state and the layers every deployment gets
+pub fn app(state: Arc<AppState>) -> Router {
+    router()
+        .layer(axum::middleware::from_fn_with_state(state.clone(), rate_limit::limit))
+        // outside the rate limiter, so a 429 is traced and carries its id too
+        .layer(axum::middleware::from_fn(request_id::trace))
+        .with_state(state)
+}
+
+/// every route the api serves — add new route modules here, and to openapi::spec
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .merge(routes::health::router())
+        .merge(routes::auth::router())
+        .merge(routes::admin::router())
+        .merge(routes::rooms::router())
+        .merge(routes::announcements::router())
+        .merge(routes::sync::router())
+        .merge(routes::friends::router())
+        .merge(routes::dms::router())
+        .merge(routes::users::router())
+        .merge(routes::presence_ws::router())
+        .merge(routes::events_ws::router())
+        .merge(routes::voice::router())
+        .merge(routes::servers::router())
+        .merge(routes::reports::router())
+        .merge(routes::server_events::router())
+        .merge(routes::server_templates::router())
+        .merge(routes::webhooks::router())
+        .merge(routes::email::router())
+        .merge(routes::notifications::router())
+        .merge(routes::media::router())
+        .merge(routes::preview::router())
+        .merge(routes::search::router())
+        .merge(openapi::router())
+}
@@ -0,0 +1,438 @@
+// link_preview.rs — previews (unfurling) of links pasted into messages
+// the homeserver's preview_url is asked first. when it has previews turned off
+// or can't fetch the page, the api fetches it itself and reads the OpenGraph
+// tags. that fetch only goes to public addresses: every redirect hop is
+// resolved and checked, and the connection is pinned to the checked address
+// so a second DNS answer can't point it inwards. pages are read up to
+// MAX_BODY_BYTES.
+//
+// previews are cached in redis for a day, keyed by a hash of the url. a message
+// sent with a link whose preview is cached carries it under `agora.preview`, so
+// receiving clients don't each fetch it — the composer asking for the preview
+// while the message is typed is what usually fills the cache.
+
+use redis::AsyncCommands;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use utoipa::ToSchema;
+use crate::app_state::AppState;
+use crate::matrix::client::MatrixClient;
+
+/// where a sent message carries its link's preview
+pub const CONTENT_KEY: &str = "agora.preview";
+
+const CACHE_TTL_SECS: u64 = 24 * 3600;
+const MAX_URL_LEN: usize = 2048;
+// the <head> is all we read, and it's near the start
+const MAX_BODY_BYTES: usize = 512 * 1024;
+const MAX_REDIRECTS: usize = 3;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const TITLE_MAX_CHARS: usize = 200;
+const DESCRIPTION_MAX_CHARS: usize = 500;
+const USER_AGENT: &str = "agora-link-preview/1.0";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct LinkPreview {
+    /// the url that was previewed, as asked for
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// mxc:// when the homeserver made the preview, else the page's own image url
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+}
+
+impl LinkPreview {
+    fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none() && self.image.is_none()
+    }
+
+    /// from the homeserver's og:* answer
+    fn from_og(url: &str, og: &serde_json::Value) -> Self {
+        let field = |key: &str| og[key].as_str().map(str::trim).filter(|v| !v.is_empty()).map(String::from);
+        Self {
+            url: url.to_string(),
+            title: field("og:title").map(|t| trim(&t, TITLE_MAX_CHARS)),
+            description: field("og:description").map(|d| trim(&d, DESCRIPTION_MAX_CHARS)),
+            image: field("og:image"),
+            site_name: field("og:site_name"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PreviewError {
+    /// not an http(s) url, or one that leads to a private address
+    Invalid(String),
+    /// the caller's session is gone
+    Unauthorized,
+    /// nothing to show: the page couldn't be fetched, isn't html or has no tags
+    Unavailable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct PreviewConfig {
+    /// fetch pages ourselves when the homeserver can't preview them
+    /// (LINK_PREVIEW_FETCH, on unless "false")
+    pub fetch: bool,
+    /// let that fetch reach private and loopback addresses — for intranet
+    /// deployments (LINK_PREVIEW_ALLOW_PRIVATE)
+    pub allow_private: bool,
+}
+
+impl PreviewConfig {
+    pub fn from_env() -> Self {
+        Self {
+            fetch: !std::env::var("LINK_PREVIEW_FETCH").is_ok_and(|v| v == "false"),
+            allow_private: std::env::var("LINK_PREVIEW_ALLOW_PRIVATE").is_ok_and(|v| v == "true"),
+        }
+    }
+}
+
+fn cache_key(url: &str) -> String {
+    format!("link_preview:{:x}", Sha256::digest(url.as_bytes()))
+}
+
+/// the preview of `url`: from the cache, else the homeserver, else the page itself
+pub async fn preview(state: &AppState, matrix: &MatrixClient, url: &str) -> Result<LinkPreview, PreviewError> {
+    let parsed = parse_url(url)?;
+    if let Some(preview) = cached(state, url).await {
+        return Ok(preview);
+    }
+
+    let preview = match matrix.preview_url(url).await {
+        Ok(og) => LinkPreview::from_og(url, &og),
+        Err(e) if e.is_unknown_token() => return Err(PreviewError::Unauthorized),
+        Err(e) => {
+            tracing::debug!("homeserver can't preview {}: {}", url, e);
+            LinkPreview::default()
+        }
+    };
+    let preview = match preview {
+        preview if !preview.is_empty() => preview,
+        _ if state.link_previews.fetch => fetch_page(&state.link_previews, url, parsed).await?,
+        _ => return Err(PreviewError::Unavailable("the homeserver has no preview".to_string())),
+    };
+    if preview.is_empty() {
+        return Err(PreviewError::Unavailable("the page has nothing to preview".to_string()));
+    }
+
+    if let Some(mut redis) = state.get_redis().await {
+        if let Ok(json) = serde_json::to_string(&preview) {
+            let _: redis::RedisResult<()> = redis.set_ex(cache_key(url), json, CACHE_TTL_SECS).await;
+        }
+    }
+    Ok(preview)
+}
+
+/// a cached preview, without fetching anything
+pub async fn cached(state: &AppState, url: &str) -> Option<LinkPreview> {
+    let mut redis = state.get_redis().await?;
+    let json: Option<String> = redis.get(cache_key(url)).await.unwrap_or(None);
+    json.and_then(|json| serde_json::from_str(&json).ok())
+}
+
+/// the cached preview of the first link in a message body, for `agora.preview`
+pub async fn for_message(state: &AppState, body: &str) -> Option<LinkPreview> {
+    let link = first_link(body)?;
+    cached(state, link).await
+}
+
+/// the first http(s) url in `body`, without the punctuation a sentence ends it with
+pub fn first_link(body: &str) -> Option<&str> {
+    body.split_whitespace()
+        .map(|word| word.trim_start_matches(['<', '(']))
+        .find(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .map(|word| word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', '"', '\'']))
+}
+
+fn parse_url(url: &str) -> Result<Url, PreviewError> {
+    let invalid = |msg: &str| PreviewError::Invalid(msg.to_string());
+    if url.len() > MAX_URL_LEN {
+        return Err(invalid("that url is too long"));
+    }
+    let parsed = Url::parse(url).map_err(|_| invalid("that isn't a url"))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(invalid("only http and https urls can be previewed"));
+    }
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        return Err(invalid("urls with credentials can't be previewed"));
+    }
+    Ok(parsed)
+}
+
+// ── fetching the page ourselves ───────────────────────────────────────────────
+
+async fn fetch_page(config: &PreviewConfig, requested: &str, url: Url) -> Result<LinkPreview, PreviewError> {
+    let unavailable = |e: reqwest::Error| PreviewError::Unavailable(e.to_string());
+    let mut current = url;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let host = current.host_str().unwrap_or_default().to_string();
+        let addr = checked_address(&current, config.allow_private).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .user_agent(USER_AGENT)
+            .resolve(&host, addr)
+            .build()
+            .map_err(unavailable)?;
+        let mut response = client
+            .get(current.clone())
+            .header(ACCEPT, "text/html,application/xhtml+xml")
+            .send()
+            .await
+            .map_err(unavailable)?;
+
+        if response.status().is_redirection() {
+            let location = response.headers().get(LOCATION).and_then(|v| v.to_str().ok());
+            let next = location.and_then(|location| current.join(location).ok());
+            current = parse_url(next.as_ref().map(Url::as_str).unwrap_or_default())?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(PreviewError::Unavailable(format!("the page answered {}", response.status())));
+        }
+        let content_type = response.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if !content_type.contains("html") {
+            return Err(PreviewError::Unavailable(format!("not a page: {}", content_type)));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(unavailable)? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_BODY_BYTES {
+                body.truncate(MAX_BODY_BYTES);
+                break;
+            }
+        }
+        return Ok(parse_html(requested, &current, &String::from_utf8_lossy(&body)));
+    }
+    Err(PreviewError::Unavailable("too many redirects".to_string()))
+}
+
+/// the address to connect to for `url` — refused when any address the host
+/// resolves to isn't a public one
+async fn checked_address(url: &Url, allow_private: bool) -> Result<SocketAddr, PreviewError> {
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| PreviewError::Unavailable(format!("can't resolve {}: {}", host, e)))?
+            .collect(),
+    };
+    if !allow_private && addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(PreviewError::Invalid("that url doesn't lead to a public address".to_string()));
+    }
+    addrs.into_iter().next().ok_or_else(|| PreviewError::Unavailable(format!("{} has no addresses", host)))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                || a == 0
+                // carrier-grade nat, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                // protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking, 198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // reserved 240.0.0.0/4, broadcast included
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = embedded_v4(v6) {
+                return is_public(IpAddr::V4(v4));
+            }
+            let [first, second, ..] = v6.segments();
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // unique local fc00::/7 and link-local fe80::/10
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // nat64 (64:ff9b::/96 and the local-use 64:ff9b:1::/48), 6to4
+                // 2002::/16 and teredo 2001::/32 all lead on to ipv4 through a relay
+                || (first == 0x64 && second == 0xff9b)
+                || first == 0x2002
+                || (first == 0x2001 && second == 0))
+        }
+    }
+}
+
+/// the ipv4 address an ipv6 one is standing in for: mapped ::ffff:a.b.c.d or
+/// the old compatible ::a.b.c.d. `::` and `::1` stay ipv6
+fn embedded_v4(v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    if v6.is_loopback() || v6.is_unspecified() {
+        return None;
+    }
+    v6.to_ipv4()
+}
+
+// ── reading the tags ──────────────────────────────────────────────────────────
+
+/// the page's OpenGraph tags, with twitter:*, the description meta and <title>
+/// to fall back on
+fn parse_html(requested: &str, page_url: &Url, html: &str) -> LinkPreview {
+    // ascii lowercasing keeps every byte offset, so positions found in `lower` index `html`
+    let lower = html.to_ascii_lowercase();
+    let mut meta: HashMap<String, String> = HashMap::new();
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find("<meta") {
+        let start = pos + found;
+        let end = lower[start..].find('>').map_or(html.len(), |e| start + e);
+        let tag = &html[start..end];
+        let key = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+        if let (Some(key), Some(content)) = (key, attribute(tag, "content")) {
+            let content = content.trim().to_string();
+            if !content.is_empty() {
+                meta.entry(key.to_ascii_lowercase()).or_insert(content);
+            }
+        }
+        pos = end;
+    }
+    let title_tag = lower.find("<title").and_then(|start| {
+        let open = start + lower[start..].find('>')? + 1;
+        let close = open + lower[open..].find("</title")?;
+        Some(decode_entities(html[open..close].trim()))
+    });
+
+    let first = |keys: &[&str]| keys.iter().find_map(|key| meta.get(*key).cloned());
+    let image = first(&["og:image", "og:image:url", "twitter:image"])
+        .and_then(|image| page_url.join(&image).ok())
+        .filter(|image| matches!(image.scheme(), "http" | "https"))
+        .map(String::from);
+    LinkPreview {
+        url: requested.to_string(),
+        title: first(&["og:title", "twitter:title"])
+            .or(title_tag)
+            .filter(|t| !t.is_empty())
+            .map(|t| trim(&t, TITLE_MAX_CHARS)),
+        description: first(&["og:description", "twitter:description", "description"])
+            .map(|d| trim(&d, DESCRIPTION_MAX_CHARS)),
+        image,
+        site_name: first(&["og:site_name"]),
+    }
+}
+
+/// an attribute's value in a tag, quoted or not, entities decoded
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lower[from..].find(name) {
+        let at = from + found;
+        from = at + name.len();
+        let rest = lower[from..].trim_start();
+        if !lower[..at].ends_with(|c: char| c.is_ascii_whitespace()) || !rest.starts_with('=') {
+            continue;
+        }
+        let value = tag[tag.len() - rest.len() + 1..].trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next(),
+            _ => value.split(|c: char| c.is_ascii_whitespace()).next().map(|v| v.trim_end_matches('/')),
+        };
+        return value.map(decode_entities);
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match entity.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok().and_then(char::from_u32),
+                    Some(dec) => dec.parse().ok().and_then(char::from_u32),
+                    None => None,
+                },
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn trim(text: &str, max_chars: usize) -> String {
+    let mut s: String = text.chars().take(max_chars).collect();
+    if text.chars().count() > max_chars {
+        s.push('…');
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_pass() {
+        // including the neighbours of the refused ranges
+        for ip in ["93.184.216.34", "8.8.8.8", "198.20.0.1", "198.17.255.1", "192.0.1.1", "223.255.255.1", "2606:4700::1111"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn special_purpose_ipv4_is_refused() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1",
+            "224.0.0.1", "192.0.0.8", "198.18.0.1", "198.19.255.254", "240.0.0.1", "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn ipv6_that_leads_to_ipv4_is_refused() {
+        for ip in [
+            "::1", "::", "fd00::1", "fe80::1", "ff02::1",
+            // mapped and compatible forms of private addresses
+            "::ffff:127.0.0.1", "::ffff:169.254.169.254", "::10.0.0.1",
+            // nat64, 6to4 and teredo, whatever they carry
+            "64:ff9b::7f00:1", "64:ff9b::808:808", "64:ff9b:1::a00:1", "2002:7f00:1::", "2002:808:808::1", "2001:0:4136:e378::1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+        assert!(public("::ffff:8.8.8.8"));
+    }
+}
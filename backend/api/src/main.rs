@@ -1,12 +1,11 @@
-pub mod app_state;
-pub mod matrix;
-pub mod routes;
-
-use axum::Router;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use crate::app_state::AppState;
+use agora_api::app_state::AppState;
+use agora_api::routes::{server_events, users, voice, webhooks};
+use agora_api::service_account::{self, ServiceAccount};
+use agora_api::{app, email, push, redis_manager, search, seed, shutdown};
 
 #[tokio::main]
 async fn main() {
@@ -18,44 +17,146 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let seed_dev = std::env::args().skip(1).any(|arg| arg == "--seed-dev");
+    // STRICT_STARTUP=true turns database / migration failures into a failed start
+    let strict_startup = std::env::var("STRICT_STARTUP").is_ok_and(|v| v == "true");
+
     let mut state = AppState::new();
     
     // initialize database (optional - continues without db if it fails)
     if let Err(e) = state.init_database().await {
-        tracing::warn!("database connection failed: {}. continuing without database.", e);
+        if strict_startup || seed_dev {
+            tracing::error!("database setup failed: {}", e);
+            std::process::exit(1);
+        }
+        if state.db_pool.is_some() {
+            tracing::warn!("database migrations failed: {}. continuing with the existing schema.", e);
+        } else {
+            tracing::warn!("database connection failed: {}. continuing without database.", e);
+        }
     }
     
     // initialize redis (optional - continues without redis if it fails)
     if let Err(e) = state.init_redis().await {
-        tracing::warn!("redis connection failed: {}. continuing without redis.", e);
+        tracing::warn!("redis connection failed: {}. continuing, and retrying in the background.", e);
+    }
+
+    if seed_dev {
+        if let Err(e) = seed::seed_dev(&state).await {
+            tracing::error!("seeding failed: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
     }
 
     let state = Arc::new(state);
+    let mut workers = Vec::new();
+
+    // email digests only run when both smtp and the database are available
+    if state.email.is_some() && state.db_pool.is_some() {
+        workers.push(tokio::spawn(email::run_digest_worker(state.clone())));
+        tracing::info!("email digests enabled");
+    }
+
+    // redis is redialed after a restart, or when it comes up after us
+    if state.redis.is_configured() {
+        workers.push(tokio::spawn(redis_manager::run_watchdog(state.clone())));
+    }
 
-    let app = router()
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+    // unanswered calls time out through redis, so only track them when it's there
+    if state.redis.is_configured() {
+        workers.push(tokio::spawn(voice::run_ring_timeout_worker(state.clone())));
+    }
+
+    // afk channels are registered in redis, so the worker needs it too
+    if state.redis.is_configured() {
+        workers.push(tokio::spawn(voice::run_afk_worker(state.clone())));
+    }
+
+    // events waiting to be announced are kept in redis
+    if state.redis.is_configured() {
+        workers.push(tokio::spawn(server_events::run_announcement_worker(state.clone())));
+    }
+
+    // idle users and expired presence are noticed by sweeping redis
+    if state.redis.is_configured() {
+        workers.push(tokio::spawn(users::run_presence_sweeper(state.clone())));
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+    // the backend's own matrix account: signed in from SERVICE_ACCOUNT_*, or
+    // an access token handed over for search
+    let credentials = ServiceAccount::from_env();
+    if let Some(account) = &credentials {
+        match account.sign_in(&state).await {
+            Ok(_) => tracing::info!("signed in as service account {}", account.username),
+            Err(e) => tracing::warn!("service account sign-in failed: {}. retrying in the background.", e),
+        }
+    } else if let Some(token) = search::index_token_from_env() {
+        let mut indexer = state.matrix();
+        indexer.access_token = Some(token);
+        *state.matrix_client.write().await = Some(indexer);
+    }
+    let has_service_account = credentials.is_some() || state.matrix_client.read().await.is_some();
+
+    // its /sync is shown to whatever wants to watch rooms itself
+    if has_service_account {
+        state.sync_observers.register(service_account::MessageCounts);
+        if state.push.is_some() && state.db_pool.is_some() {
+            state.sync_observers.register(push::PushFanOut);
+            tracing::info!("web push enabled");
+        }
+        workers.push(tokio::spawn(service_account::run_sync_worker(state.clone(), credentials.clone())));
+    }
+
+    // message search follows the same account's /sync into postgres
+    if has_service_account && state.db_pool.is_some() {
+        workers.push(tokio::spawn(search::run_indexer(state.clone())));
+        tracing::info!("message search enabled");
+    }
+
+    // incoming webhooks post through a service account of their own
+    if let (Some(token), true) = (webhooks::service_token_from_env(), state.db_pool.is_some()) {
+        let mut poster = state.matrix();
+        poster.access_token = Some(token);
+        *state.webhook_client.write().await = Some(poster);
+        tracing::info!("incoming webhooks enabled");
+    }
+
+    // outside the rate limiter, so a 429 still carries cors headers
+    let app = app(state.clone()).layer(CorsLayer::permissive());
+
+    let addr = bind_addr();
+    let listener = tokio::net::TcpListener::bind(&addr)
         .await
-        .expect("failed to bind to port 3000");
+        .unwrap_or_else(|e| panic!("failed to bind to {}: {}", addr, e));
 
     tracing::info!("listening on {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app)
-        .await
-        .expect("server failed");
+    tokio::spawn(shutdown::on_signal(state.shutdown.clone()));
+    if let Err(e) = shutdown::serve(listener, app, state.shutdown.clone(), shutdown::grace_from_env()).await {
+        tracing::error!("server failed: {}", e);
+    }
+
+    // the workers keep their progress in redis and postgres, so stopping one mid-pass only delays it
+    tracing::info!("stopping {} background workers", workers.len());
+    for worker in &workers {
+        worker.abort();
+    }
+    for worker in workers {
+        let _ = worker.await;
+    }
+    tracing::info!("shut down");
 }
 
-fn router() -> Router<Arc<AppState>> {
-    Router::new()
-        .merge(routes::health::router())
-        .merge(routes::auth::router())
-        .merge(routes::rooms::router())
-        .merge(routes::sync::router())
-        .merge(routes::friends::router())
-        .merge(routes::users::router())
-        .merge(routes::presence_ws::router())
-        .merge(routes::voice::router())
-        .merge(routes::servers::router())
+/// BIND_ADDR (a host, or host:port) and PORT, else 0.0.0.0:3000
+fn bind_addr() -> String {
+    let host = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0".to_string());
+    if host.parse::<SocketAddr>().is_ok() {
+        return host;
+    }
+    let port = std::env::var("PORT").ok().and_then(|p| p.parse::<u16>().ok()).unwrap_or(3000);
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V6(ip)) => SocketAddr::from((ip, port)).to_string(),
+        _ => format!("{}:{}", host, port),
+    }
 }
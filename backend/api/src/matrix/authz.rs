@@ -0,0 +1,187 @@
+// authz.rs — server permissions, checked by the api
+// a server's roles (agora.roles) and who holds them (agora.member.roles, one
+// per member) live on the server space. the homeserver only knows power
+// levels, so an action gated on a role permission is checked here before the
+// request goes out. power 100 in the server room and the administrator role
+// both grant everything.
+// roles are ranked by `position`: below administrator, a caller can only
+// create, edit or hand out roles strictly below their own highest one.
+// a channel or category finds its server by following m.space.parent upwards,
+// like message_policy; rooms outside any server have no roles, and the
+// homeserver's power levels are all there is.
+
+use super::client::{MatrixClient, MatrixError, RoomStateEvent};
+use super::hierarchy;
+
+/// the flags of RolePermissions that gate an endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ManageChannels,
+    ManageRoles,
+    KickMembers,
+    BanMembers,
+    MentionEveryone,
+    ManageServer,
+    ManageMessages,
+}
+
+impl Permission {
+    /// the flag's name in a role's `permissions`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ManageChannels => "manage_channels",
+            Permission::ManageRoles => "manage_roles",
+            Permission::KickMembers => "kick_members",
+            Permission::BanMembers => "ban_members",
+            Permission::MentionEveryone => "mention_everyone",
+            Permission::ManageServer => "manage_server",
+            Permission::ManageMessages => "manage_messages",
+        }
+    }
+}
+
+/// why a check didn't pass
+#[derive(Debug)]
+pub enum AuthzError {
+    /// the token doesn't resolve to a user
+    Unauthenticated(MatrixError),
+    /// the server's state can't be read — usually the caller isn't in it
+    Unreadable(MatrixError),
+    /// none of the caller's roles grant this
+    Missing(Permission),
+    /// the role isn't below the caller's highest role
+    Hierarchy { role_id: String },
+}
+
+/// one caller's standing in one server, read once per request — check as many
+/// permissions against it as the handler needs
+#[derive(Debug, Clone)]
+pub struct ServerAccess {
+    pub user_id: String,
+    pub server_id: String,
+    /// the caller's power level in the server room
+    pub level: i64,
+    /// each role the caller holds, as stored in agora.roles
+    held: Vec<serde_json::Value>,
+}
+
+impl ServerAccess {
+    /// read the caller's power and roles from the server's state
+    pub async fn load(matrix: &MatrixClient, server_id: &str) -> Result<Self, AuthzError> {
+        let user_id = matrix.whoami().await.map_err(AuthzError::Unauthenticated)?.user_id;
+        let state = matrix.get_room_state(server_id.to_string()).await.map_err(AuthzError::Unreadable)?;
+        Ok(Self::from_state(&state, user_id, server_id))
+    }
+
+    pub fn from_state(server_state: &[RoomStateEvent], user_id: String, server_id: &str) -> Self {
+        let find = |event_type: &str, state_key: &str| {
+            server_state
+                .iter()
+                .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(state_key))
+                .map(|e| e.content.clone())
+        };
+
+        let power = find("m.room.power_levels", "").unwrap_or_default();
+        let level = power["users"][&user_id]
+            .as_i64()
+            .or_else(|| power["users_default"].as_i64())
+            .unwrap_or(0);
+        let role_ids: Vec<String> = find("agora.member.roles", &user_id)
+            .and_then(|c| serde_json::from_value(c["role_ids"].clone()).ok())
+            .unwrap_or_default();
+        let roles = find("agora.roles", "").unwrap_or_default();
+        let held = roles["roles"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|role| role["id"].as_str().is_some_and(|id| role_ids.iter().any(|r| r == id)))
+            .cloned()
+            .collect();
+        Self { user_id, server_id: server_id.to_string(), level, held }
+    }
+
+    /// power 100, or a role with `administrator`
+    pub fn is_admin(&self) -> bool {
+        self.level >= 100 || self.held.iter().any(|r| r["permissions"]["administrator"].as_bool() == Some(true))
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.is_admin() || self.held.iter().any(|r| r["permissions"][permission.as_str()].as_bool() == Some(true))
+    }
+
+    /// the position of the caller's highest role — None without any
+    pub fn highest_position(&self) -> Option<i64> {
+        self.held.iter().map(|r| r["position"].as_i64().unwrap_or(0)).max()
+    }
+
+    /// whether the caller may create, edit or hand out a role at `position`
+    pub fn outranks(&self, position: i64) -> bool {
+        self.is_admin() || self.highest_position().is_some_and(|top| position < top)
+    }
+
+    pub fn require_outranks(&self, role_id: &str, position: i64) -> Result<(), AuthzError> {
+        if self.outranks(position) {
+            Ok(())
+        } else {
+            Err(AuthzError::Hierarchy { role_id: role_id.to_string() })
+        }
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), AuthzError> {
+        if self.has(permission) {
+            Ok(())
+        } else {
+            Err(AuthzError::Missing(permission))
+        }
+    }
+}
+
+/// the caller's access to `server_id`, if it grants `permission`
+pub async fn require_permission(
+    matrix: &MatrixClient,
+    server_id: &str,
+    permission: Permission,
+) -> Result<ServerAccess, AuthzError> {
+    let access = ServerAccess::load(matrix, server_id).await?;
+    access.require(permission)?;
+    Ok(access)
+}
+
+/// the same for a room that may sit in a server (or be one) — Ok(None) when
+/// it's outside any, since there are no roles to check there
+pub async fn require_permission_in(
+    matrix: &MatrixClient,
+    room_id: &str,
+    permission: Permission,
+) -> Result<Option<ServerAccess>, AuthzError> {
+    match server_of(matrix, room_id).await.map_err(AuthzError::Unreadable)? {
+        Some(server_id) => require_permission(matrix, &server_id, permission).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// the top-most space above `room_id` following m.space.parent, or the room
+/// itself when it's a space with no parent. None for a room outside any space.
+pub async fn server_of(matrix: &MatrixClient, room_id: &str) -> Result<Option<String>, MatrixError> {
+    let mut current = room_id.to_string();
+    // server → category → channel is at most MAX_SPACE_NESTING hops up
+    for _ in 0..=hierarchy::MAX_SPACE_NESTING {
+        let state = matrix.get_room_state(current.clone()).await?;
+        let parent = state
+            .iter()
+            .filter(|e| e.event_type == "m.space.parent")
+            .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+            .find_map(|e| e.state_key.clone())
+            .filter(|k| !k.is_empty() && *k != room_id);
+        match parent {
+            Some(parent) => current = parent,
+            None => {
+                let is_space = state
+                    .iter()
+                    .any(|e| e.event_type == "m.room.create" && e.content["type"] == "m.space");
+                return Ok(is_space.then_some(current));
+            }
+        }
+    }
+    Ok(Some(current))
+}
@@ -0,0 +1,79 @@
+// channel_access.rs — private channels
+// a private channel only lets in members holding one of its allowed roles.
+// the allowance is stored on the parent space as agora.channel.permissions,
+// keyed by the channel's room id: a channel's own state can't be read before
+// joining it, and the join loop has to decide before it joins. server admins
+// (power 100 or the administrator role) are always let in.
+// on the matrix side a private channel is restricted to members of its parent
+// space, so the homeserver still keeps everyone outside the server out.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use super::client::RoomStateEvent;
+
+pub const EVENT_TYPE: &str = "agora.channel.permissions";
+
+/// the agora.channel.permissions state event for one channel
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct ChannelPermissions {
+    #[serde(default)]
+    pub private: bool,
+    /// roles that may see a private channel
+    #[serde(default)]
+    pub allowed_role_ids: Vec<String>,
+}
+
+impl ChannelPermissions {
+    /// the overrides a space holds for one of its children — public when there are none
+    pub fn of_child(space_state: &[RoomStateEvent], channel_id: &str) -> Self {
+        space_state
+            .iter()
+            .find(|e| e.event_type == EVENT_TYPE && e.state_key.as_deref() == Some(channel_id))
+            .and_then(|e| serde_json::from_value(e.content.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    /// whether `user_id` may be in the channel, going by the server's state
+    pub fn allows(&self, server_state: &[RoomStateEvent], user_id: &str) -> bool {
+        if !self.private {
+            return true;
+        }
+        let find = |event_type: &str, state_key: &str| {
+            server_state
+                .iter()
+                .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(state_key))
+                .map(|e| e.content.clone())
+        };
+
+        let power = find("m.room.power_levels", "").unwrap_or_default();
+        let level = power["users"][user_id]
+            .as_i64()
+            .or_else(|| power["users_default"].as_i64())
+            .unwrap_or(0);
+        if level >= 100 {
+            return true;
+        }
+
+        let role_ids: Vec<String> = find("agora.member.roles", user_id)
+            .and_then(|c| serde_json::from_value(c["role_ids"].clone()).ok())
+            .unwrap_or_default();
+        let roles = find("agora.roles", "").unwrap_or_default();
+        roles["roles"].as_array().into_iter().flatten().any(|role| {
+            role["id"].as_str().is_some_and(|id| role_ids.iter().any(|r| r == id))
+                && (self.allowed_role_ids.iter().any(|a| role["id"] == *a)
+                    || role["permissions"]["administrator"].as_bool() == Some(true))
+        })
+    }
+
+    /// m.room.join_rules content for a channel under `parent_id`
+    pub fn join_rules(&self, parent_id: Option<&str>) -> serde_json::Value {
+        match (self.private, parent_id) {
+            (false, _) => serde_json::json!({ "join_rule": "public" }),
+            (true, Some(parent_id)) => serde_json::json!({
+                "join_rule": "restricted",
+                "allow": [{ "type": "m.room_membership", "room_id": parent_id }],
+            }),
+            (true, None) => serde_json::json!({ "join_rule": "invite" }),
+        }
+    }
+}
@@ -1,10 +1,102 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use super::retry::{RetryPolicy, SendWithRetry};
+use super::encode_path_segment;
+use crate::redact::redact;
 
 #[derive(Debug, Clone)]
 pub struct MatrixClient {
     pub homeserver_url: String,
     pub access_token: Option<String>,
     pub user_id: Option<String>,
+    /// pooled connections to the homeserver — cloning shares the pool
+    http: reqwest::Client,
+    /// how transient failures are retried (MATRIX_RETRY_* by default)
+    retry: RetryPolicy,
+    /// which api version the homeserver speaks, probed on first use
+    api_version: ApiVersionCache,
+}
+
+/// the path version for client-server (and media) requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V3,
+    /// homeservers that predate matrix 1.1
+    R0,
+}
+
+impl ApiVersion {
+    /// v3 when any matrix 1.x spec version is advertised, r0 when only r0.x is
+    pub fn from_versions(versions: &[String]) -> Self {
+        let only_r0 = versions.iter().any(|v| v.starts_with("r0."))
+            && !versions.iter().any(|v| v.starts_with("v1."));
+        if only_r0 { ApiVersion::R0 } else { ApiVersion::V3 }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V3 => "v3",
+            ApiVersion::R0 => "r0",
+        }
+    }
+}
+
+/// the probed api version, shared by every client of one homeserver (AppState::matrix_api)
+pub type ApiVersionCache = Arc<tokio::sync::OnceCell<ApiVersion>>;
+
+// defaults for MATRIX_CONNECT_TIMEOUT_SECS / MATRIX_READ_TIMEOUT_SECS.
+// the read timeout has to outlast the 30s /sync long-poll
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 45;
+
+/// how long to wait on the homeserver before giving up
+#[derive(Debug, Clone, Copy)]
+pub struct HttpTimeouts {
+    pub connect: Duration,
+    /// between reads of the response, not for the whole request
+    pub read: Duration,
+}
+
+impl Default for HttpTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            read: Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl HttpTimeouts {
+    pub fn from_env() -> Self {
+        let secs = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_secs(default))
+        };
+        Self {
+            connect: secs("MATRIX_CONNECT_TIMEOUT_SECS", DEFAULT_CONNECT_TIMEOUT_SECS),
+            read: secs("MATRIX_READ_TIMEOUT_SECS", DEFAULT_READ_TIMEOUT_SECS),
+        }
+    }
+}
+
+/// the http client every MatrixClient should share — build it once (AppState::http)
+pub fn http_client(timeouts: HttpTimeouts) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(timeouts.connect)
+        .read_timeout(timeouts.read)
+        .build()
+        .expect("failed to build the homeserver http client")
+}
+
+/// for clients built without one, e.g. in tests and one-off tools
+fn default_http() -> reqwest::Client {
+    static HTTP: OnceLock<reqwest::Client> = OnceLock::new();
+    HTTP.get_or_init(|| http_client(HttpTimeouts::from_env())).clone()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,21 +167,187 @@ pub struct SyncResponse {
     #[serde(rename = "next_batch")]
     pub next_batch: String,
     pub rooms: Option<Rooms>,
+    /// the user's global account data, like m.direct
+    pub account_data: Option<AccountData>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Rooms {
     pub join: Option<std::collections::HashMap<String, JoinedRoom>>,
+    pub invite: Option<std::collections::HashMap<String, InvitedRoom>>,
+    pub leave: Option<std::collections::HashMap<String, LeftRoom>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvitedRoom {
+    pub invite_state: Option<InviteState>,
+}
+
+/// what an invitee may see of a room before joining it
+#[derive(Debug, Deserialize)]
+pub struct InviteState {
+    pub events: Vec<StrippedStateEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StrippedStateEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub state_key: String,
+    pub sender: String,
+    pub content: serde_json::Value,
+}
+
+/// one page of a space's tree from /hierarchy
+#[derive(Debug, Deserialize)]
+pub struct HierarchyResponse {
+    pub rooms: Vec<HierarchyRoom>,
+    /// `from` for the next page; absent on the last
+    pub next_batch: Option<String>,
+}
+
+/// a room in a space's tree, as far as the caller may see it
+#[derive(Debug, Deserialize)]
+pub struct HierarchyRoom {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    /// "m.space" for spaces
+    pub room_type: Option<String>,
+    #[serde(default)]
+    pub num_joined_members: i64,
+    /// a space's m.space.child events; empty for other rooms
+    #[serde(default)]
+    pub children_state: Vec<StrippedStateEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolvedAlias {
+    pub room_id: String,
+    /// servers that know the room, for joining over federation
+    #[serde(default)]
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeftRoom {
+    pub timeline: Option<Timeline>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JoinedRoom {
     pub timeline: Option<Timeline>,
+    /// typing and read receipts
+    pub ephemeral: Option<Ephemeral>,
+    /// the user's own data about the room, like its m.tag
+    pub account_data: Option<AccountData>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountData {
+    pub events: Vec<AccountDataEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountDataEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub content: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ephemeral {
+    pub events: Vec<EphemeralEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EphemeralEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub content: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Timeline {
     pub events: Vec<Event>,
+    /// token for /messages, to page back from the first event here
+    pub prev_batch: Option<String>,
+    /// true when events between `since` and these were left out
+    #[serde(default)]
+    pub limited: bool,
+}
+
+/// an inline /sync filter (the `filter` query param)
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncFilter {
+    pub room: RoomFilter,
+    pub presence: EventFilter,
+    pub account_data: EventFilter,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomFilter {
+    pub rooms: Vec<String>,
+    pub state: EventFilter,
+    pub timeline: EventFilter,
+    pub ephemeral: EventFilter,
+    pub account_data: EventFilter,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventFilter {
+    /// narrows RoomFilter's rooms further, for this section only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+}
+
+impl EventFilter {
+    fn none() -> Self {
+        EventFilter { types: Some(Vec::new()), ..Default::default() }
+    }
+}
+
+impl SyncFilter {
+    /// one room's timeline and nothing else: no other rooms, presence,
+    /// typing or account data
+    pub fn room_timeline(room_id: &str, limit: u32) -> Self {
+        SyncFilter {
+            room: RoomFilter {
+                rooms: vec![room_id.to_string()],
+                state: EventFilter::default(),
+                timeline: EventFilter { limit: Some(limit), ..Default::default() },
+                ephemeral: EventFilter::none(),
+                account_data: EventFilter::none(),
+            },
+            presence: EventFilter::none(),
+            account_data: EventFilter::none(),
+        }
+    }
+
+    /// what the rooms list shows besides room state: the m.tag of each of
+    /// `room_ids`, m.direct, and the last few messages of `timeline_room_ids`
+    pub fn room_list(room_ids: &[String], timeline_room_ids: &[String], messages: u32) -> Self {
+        SyncFilter {
+            room: RoomFilter {
+                rooms: room_ids.to_vec(),
+                state: EventFilter::none(),
+                timeline: EventFilter {
+                    rooms: Some(timeline_room_ids.to_vec()),
+                    limit: Some(messages),
+                    types: Some(vec!["m.room.message".to_string()]),
+                },
+                ephemeral: EventFilter::none(),
+                account_data: EventFilter { types: Some(vec!["m.tag".to_string()]), ..Default::default() },
+            },
+            presence: EventFilter::none(),
+            account_data: EventFilter { types: Some(vec!["m.direct".to_string()]), ..Default::default() },
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -101,41 +359,155 @@ pub struct Event {
     #[serde(rename = "event_id")]
     pub event_id: Option<String>,
     pub origin_server_ts: Option<i64>,
+    /// the target of an m.room.redaction (room versions from v11 move it into content)
+    pub redacts: Option<String>,
+    /// set on state events, which can also show up in a timeline
+    pub state_key: Option<String>,
 }
 
-// encode a matrix identifier for use in url paths
-// preserves sigils (!, @) and colon (:) but encodes # to %23 (fragment separator)
-fn encode_matrix_id(id: &str) -> String {
-    id.replace('%', "%25")
-        .replace('#', "%23")
-        .replace(' ', "%20")
-        .replace('?', "%3F")
-        .replace('&', "%26")
-        .replace('{', "%7B")
-        .replace('}', "%7D")
+impl Event {
+    /// the event an m.room.redaction removes
+    pub fn redacted_event_id(&self) -> Option<&str> {
+        if self.event_type != "m.room.redaction" {
+            return None;
+        }
+        self.redacts.as_deref().or_else(|| self.content.get("redacts")?.as_str())
+    }
+}
+
+/// who may read a room's history (m.room.history_visibility)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryVisibility {
+    /// members see everything, including what was sent before they joined
+    Shared,
+    /// members see what was sent since they were invited
+    Invited,
+    /// members see what was sent since they joined
+    Joined,
+    /// anyone can read it without joining
+    WorldReadable,
+}
+
+impl HistoryVisibility {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HistoryVisibility::Shared => "shared",
+            HistoryVisibility::Invited => "invited",
+            HistoryVisibility::Joined => "joined",
+            HistoryVisibility::WorldReadable => "world_readable",
+        }
+    }
+
+    pub fn content(self) -> serde_json::Value {
+        serde_json::json!({ "history_visibility": self.as_str() })
+    }
+
+    /// the createRoom initial_state entry that sets it
+    fn initial_state(self) -> serde_json::Value {
+        serde_json::json!({ "type": "m.room.history_visibility", "state_key": "", "content": self.content() })
+    }
+}
+
+/// which way /messages pages through a room's timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ToSchema)]
+pub enum Direction {
+    /// newest first, towards the start of the room
+    #[default]
+    #[serde(rename = "b")]
+    Backward,
+    #[serde(rename = "f")]
+    Forward,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Backward => "b",
+            Direction::Forward => "f",
+        }
+    }
+}
+
+/// one page of a room's timeline
+#[derive(Debug, Deserialize)]
+pub struct MessagesResponse {
+    pub start: String,
+    /// absent when there is nothing further in this direction
+    pub end: Option<String>,
+    pub chunk: Vec<Event>,
 }
 
 impl MatrixClient {
+    /// a client on the process-wide default pool — handlers use AppState::matrix instead
     pub fn new(homeserver_url: String) -> Self {
+        Self::with_http(homeserver_url, default_http())
+    }
+
+    /// a client on a given (shared) http client
+    pub fn with_http(homeserver_url: String, http: reqwest::Client) -> Self {
         Self {
             homeserver_url,
             access_token: None,
             user_id: None,
+            http,
+            retry: RetryPolicy::from_env(),
+            api_version: ApiVersionCache::default(),
         }
     }
 
+    /// the same client sharing an api version probe with others
+    pub fn with_api_version(self, api_version: ApiVersionCache) -> Self {
+        Self { api_version, ..self }
+    }
+
+    async fn api_version(&self) -> ApiVersion {
+        let probed = self
+            .api_version
+            .get_or_try_init(|| async {
+                let versions = self.get_versions().await?;
+                let version = ApiVersion::from_versions(&versions.versions);
+                tracing::info!("homeserver speaks the {} client api", version.as_str());
+                Ok::<_, MatrixError>(version)
+            })
+            .await;
+        match probed {
+            Ok(version) => *version,
+            // not cached — the next request probes again
+            Err(e) => {
+                tracing::warn!("could not read homeserver versions, assuming v3: {}", e);
+                ApiVersion::V3
+            }
+        }
+    }
+
+    /// e.g. `https://hs.example/_matrix/client/v3` — every client-server url starts here
+    pub async fn client_api_base(&self) -> String {
+        format!("{}/_matrix/client/{}", self.homeserver_url, self.api_version().await.as_str())
+    }
+
+    /// the media repository counterpart of client_api_base
+    pub async fn media_api_base(&self) -> String {
+        format!("{}/_matrix/media/{}", self.homeserver_url, self.api_version().await.as_str())
+    }
+
+    /// the same client with another retry policy
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
     pub fn with_auth(homeserver_url: String, access_token: String, user_id: String) -> Self {
         Self {
-            homeserver_url,
             access_token: Some(access_token),
             user_id: Some(user_id),
+            ..Self::new(homeserver_url)
         }
     }
 
-    pub async fn get_versions(&self) -> Result<MatrixVersions, reqwest::Error> {
-        let client = reqwest::Client::new();
+    pub async fn get_versions(&self) -> Result<MatrixVersions, MatrixError> {
+        let client = &self.http;
         let url = format!("{}/_matrix/client/versions", self.homeserver_url);
-        let response = client.get(&url).send().await?;
+        let response = client.get(&url).send_with_retry(self.retry).await?;
         let versions = response.json::<MatrixVersions>().await?;
         Ok(versions)
     }
@@ -145,31 +517,27 @@ impl MatrixClient {
         username: String,
         password: String,
     ) -> Result<RegistrationResponse, MatrixError> {
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/register?kind=user",
-            self.homeserver_url
+            "{}/register?kind=user",
+            self.client_api_base().await
         );
         
         // Step 1: Get UIA session
-        tracing::info!("getting uia session from conduit");
         let uia_response = client
             .post(&url)
             .header("content-type", "application/json")
             .body("{}")
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
-        let uia_status = uia_response.status();
         let uia_text = uia_response.text().await?;
-        tracing::info!("uia response status: {}, body: {}", uia_status, uia_text);
         
         let uia: UiaResponse = serde_json::from_str(&uia_text)
             .map_err(|e| MatrixError::ApiError(format!("failed to parse uia response: {}", e)))?;
         
         let session = uia.session.ok_or(MatrixError::NoSession)?;
-        tracing::info!("got uia session: {}", session);
-        
+
         // Step 2: Complete registration with auth
         let body = RegistrationRequest {
             username,
@@ -180,23 +548,22 @@ impl MatrixClient {
             }),
         };
 
-        tracing::info!("sending registration request with auth");
         let response = client
             .post(&url)
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
+        // the status is on the call's span; the body holds the new access token, so it isn't logged
         let status = response.status();
         let response_text = response.text().await?;
-        tracing::info!("registration response status: {}, body: {}", status, response_text);
         
         if status.is_success() {
             let reg_response = serde_json::from_str(&response_text)
                 .map_err(|e| MatrixError::ApiError(format!("failed to parse registration response: {}", e)))?;
             Ok(reg_response)
         } else {
-            Err(MatrixError::ApiError(response_text))
+            Err(MatrixError::from_body(status, None, response_text))
         }
     }
 
@@ -204,9 +571,9 @@ impl MatrixClient {
         &self,
         user: String,
         password: String,
-    ) -> Result<LoginResponse, reqwest::Error> {
-        let client = reqwest::Client::new();
-        let url = format!("{}/_matrix/client/r0/login", self.homeserver_url);
+    ) -> Result<LoginResponse, MatrixError> {
+        let client = &self.http;
+        let url = format!("{}/login", self.client_api_base().await);
         
         let body = LoginRequest {
             login_type: "m.login.password".to_string(),
@@ -217,69 +584,287 @@ impl MatrixClient {
         let response = client
             .post(&url)
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
+        if !response.status().is_success() {
+            return Err(MatrixError::from_response(response).await);
+        }
         let login_response = response.json::<LoginResponse>().await?;
         Ok(login_response)
     }
 
+    /// a guest session, for reading world-readable rooms without an account.
+    /// fails with M_GUEST_ACCESS_FORBIDDEN where the homeserver doesn't allow guests
+    pub async fn register_guest(&self) -> Result<RegistrationResponse, MatrixError> {
+        let url = format!("{}/register?kind=guest", self.client_api_base().await);
+        let response = self.http
+            .post(&url)
+            .json(&serde_json::json!({}))
+            .send_with_retry(self.retry)
+            .await?;
+        if !response.status().is_success() {
+            return Err(MatrixError::from_response(response).await);
+        }
+        Ok(response.json::<RegistrationResponse>().await?)
+    }
+
+    /// who the access token belongs to — fails with M_UNKNOWN_TOKEN once it's revoked
+    pub async fn whoami(&self) -> Result<WhoamiResponse, MatrixError> {
+        let url = format!("{}/account/whoami", self.client_api_base().await);
+        let body = self.get_raw(&url).await?;
+        Ok(serde_json::from_value(body)?)
+    }
+
+    /// invalidate the current access token — ends this device's session
+    pub async fn logout(&self) -> Result<(), MatrixError> {
+        let url = format!("{}/logout", self.client_api_base().await);
+        self.post_raw(&url, &serde_json::json!({})).await?;
+        Ok(())
+    }
+
+    /// invalidate every access token the user has, on all devices
+    pub async fn logout_all(&self) -> Result<(), MatrixError> {
+        let url = format!("{}/logout/all", self.client_api_base().await);
+        self.post_raw(&url, &serde_json::json!({})).await?;
+        Ok(())
+    }
+
+    /// change the account password. the homeserver wants the old password
+    /// through user-interactive auth; `logout_devices` also ends every other session
+    pub async fn change_password(
+        &self,
+        user_id: &str,
+        old_password: &str,
+        new_password: &str,
+        logout_devices: bool,
+    ) -> Result<(), MatrixError> {
+        let url = format!("{}/account/password", self.client_api_base().await);
+        let body = serde_json::json!({
+            "new_password": new_password,
+            "logout_devices": logout_devices,
+        });
+        self.with_password_auth(reqwest::Method::POST, &url, body, user_id, old_password).await?;
+        Ok(())
+    }
+
+    /// every session (device) the user is logged in on
+    pub async fn get_devices(&self) -> Result<Vec<Device>, MatrixError> {
+        let url = format!("{}/devices", self.client_api_base().await);
+        let body = self.get_raw(&url).await?;
+        let devices: DevicesResponse = serde_json::from_value(body)?;
+        Ok(devices.devices)
+    }
+
+    /// delete a device, which logs its session out. needs the account password
+    pub async fn delete_device(&self, user_id: &str, device_id: &str, password: &str) -> Result<(), MatrixError> {
+        let url = format!(
+            "{}/devices/{}",
+            self.client_api_base().await,
+            encode_path_segment(device_id)
+        );
+        self.with_password_auth(reqwest::Method::DELETE, &url, serde_json::json!({}), user_id, password)
+            .await?;
+        Ok(())
+    }
+
+    /// send a request guarded by user-interactive auth, the way register()
+    /// does: the first attempt comes back 401 with a session, the retry
+    /// completes m.login.password in it. a wrong password is M_FORBIDDEN.
+    async fn with_password_auth(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        mut body: serde_json::Value,
+        user_id: &str,
+        password: &str,
+    ) -> Result<serde_json::Value, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+
+        let response = client
+            .request(method.clone(), url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send_with_retry(self.retry)
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if status.is_success() {
+            return Ok(serde_json::from_str(&text).unwrap_or_default());
+        }
+        if status != reqwest::StatusCode::UNAUTHORIZED {
+            return Err(MatrixError::from_body(status, None, text));
+        }
+        let Ok(uia) = serde_json::from_str::<UiaResponse>(&text) else {
+            return Err(MatrixError::from_body(status, None, text));
+        };
+        let session = uia.session.ok_or(MatrixError::NoSession)?;
+
+        body["auth"] = serde_json::json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": user_id },
+            "user": user_id,
+            "password": password,
+            "session": session,
+        });
+        let response = client
+            .request(method, url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send_with_retry(self.retry)
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if status.is_success() {
+            Ok(serde_json::from_str(&text).unwrap_or_default())
+        } else {
+            Err(MatrixError::from_body(status, None, text))
+        }
+    }
+
     pub async fn sync(
         &self,
         since: Option<String>,
+    ) -> Result<SyncResponse, MatrixError> {
+        self.sync_with_filter(since, None, 30000).await
+    }
+
+    /// /sync with an inline filter (see [`SyncFilter`]), waiting at most
+    /// `timeout_ms` for new events — 0 answers straight away
+    pub async fn sync_with_filter(
+        &self,
+        since: Option<String>,
+        filter: Option<&SyncFilter>,
+        timeout_ms: u64,
     ) -> Result<SyncResponse, MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
-        let mut url = format!("{}/_matrix/client/r0/sync", self.homeserver_url);
+        let client = &self.http;
+        let mut url = format!("{}/sync", self.client_api_base().await);
         
         // add query parameters
-        url.push_str("?timeout=30000");
+        url.push_str(&format!("?timeout={}", timeout_ms));
         if let Some(s) = since {
-            url.push_str(&format!("&since={}", s));
+            url.push_str(&format!("&since={}", urlencoding::encode(&s)));
+        }
+        if let Some(filter) = filter {
+            let filter = serde_json::to_string(filter).expect("filter serializes");
+            url.push_str(&format!("&filter={}", urlencoding::encode(&filter)));
         }
         
         let response = client
             .get(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let sync_response = response.json::<SyncResponse>().await?;
             Ok(sync_response)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    /// send a message event with arbitrary content — used for call signaling
+    /// a page of room events from `from` (or the live end, going backward)
+    pub async fn get_messages(
+        &self,
+        room_id: &str,
+        from: Option<&str>,
+        dir: Direction,
+        limit: u32,
+    ) -> Result<MessagesResponse, MatrixError> {
+        self.get_messages_until(room_id, from, None, dir, limit).await
+    }
+
+    /// like get_messages, stopping at `to` — a pagination or sync token
+    pub async fn get_messages_until(
+        &self,
+        room_id: &str,
+        from: Option<&str>,
+        to: Option<&str>,
+        dir: Direction,
+        limit: u32,
+    ) -> Result<MessagesResponse, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let mut url = format!(
+            "{}/rooms/{}/messages?dir={}&limit={}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            dir.as_str(),
+            limit
+        );
+        if let Some(from) = from {
+            url.push_str(&format!("&from={}", urlencoding::encode(from)));
+        }
+        if let Some(to) = to {
+            url.push_str(&format!("&to={}", urlencoding::encode(to)));
+        }
+        let response = self
+            .http
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            Ok(response.json::<MessagesResponse>().await?)
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    /// send a message event with arbitrary content — used for call signaling.
+    /// passing the txn id of an earlier attempt makes the homeserver answer
+    /// with that event instead of sending another
     pub async fn send_message_content(
         &self,
         room_id: String,
         content: serde_json::Value,
+        txn_id: Option<&str>,
+    ) -> Result<serde_json::Value, MatrixError> {
+        self.send_event_with_txn(&room_id, "m.room.message", content, txn_id).await
+    }
+
+    /// send a room event of any type, e.g. m.reaction
+    pub async fn send_event(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        content: serde_json::Value,
+    ) -> Result<serde_json::Value, MatrixError> {
+        self.send_event_with_txn(room_id, event_type, content, None).await
+    }
+
+    /// send_event under the given txn id — a fresh one when None
+    pub async fn send_event_with_txn(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        content: serde_json::Value,
+        txn_id: Option<&str>,
     ) -> Result<serde_json::Value, MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
-        let txn_id = uuid::Uuid::new_v4().to_string();
+        let client = &self.http;
+        let txn_id = txn_id.map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
-            self.homeserver_url,
-            encode_matrix_id(&room_id),
-            txn_id
+            "{}/rooms/{}/send/{}/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            encode_path_segment(event_type),
+            encode_path_segment(&txn_id)
         );
         let response = client
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&content)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
             Ok(response.json::<serde_json::Value>().await?)
         } else {
-            Err(MatrixError::ApiError(response.text().await?))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -287,17 +872,18 @@ impl MatrixClient {
         &self,
         room_id: String,
         message: String,
+        txn_id: Option<&str>,
     ) -> Result<serde_json::Value, MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
-        let txn_id = uuid::Uuid::new_v4().to_string();
+        let client = &self.http;
+        let txn_id = txn_id.map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
-            self.homeserver_url,
-            encode_matrix_id(&room_id),
-            txn_id
+            "{}/rooms/{}/send/m.room.message/{}",
+            self.client_api_base().await,
+            encode_path_segment(&room_id),
+            encode_path_segment(&txn_id)
         );
         
         let body = serde_json::json!({
@@ -309,15 +895,14 @@ impl MatrixClient {
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<serde_json::Value>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -327,17 +912,13 @@ impl MatrixClient {
         name: String,
         topic: Option<String>,
         is_space: bool,
+        history_visibility: HistoryVisibility,
     ) -> Result<CreateRoomResponse, MatrixError> {
-        let token = self.access_token.as_ref()
-            .ok_or(MatrixError::NoSession)?;
-        
-        let client = reqwest::Client::new();
-        let url = format!("{}/_matrix/client/r0/createRoom", self.homeserver_url);
-        
         let mut body = serde_json::json!({
             "name": name,
             "preset": "public_chat",
-            "room_version": "9"
+            "room_version": "9",
+            "initial_state": [history_visibility.initial_state()],
         });
         
         if let Some(t) = topic {
@@ -350,19 +931,50 @@ impl MatrixClient {
             });
         }
 
+        self.create_room_from(body).await
+    }
+
+    /// an invite-only (or restricted, per `join_rules`) channel that doesn't show up in the directory
+    pub async fn create_private_room(
+        &self,
+        name: String,
+        topic: Option<String>,
+        join_rules: serde_json::Value,
+        history_visibility: HistoryVisibility,
+    ) -> Result<CreateRoomResponse, MatrixError> {
+        let mut body = serde_json::json!({
+            "name": name,
+            "preset": "private_chat",
+            "room_version": "9",
+            "initial_state": [
+                { "type": "m.room.join_rules", "state_key": "", "content": join_rules },
+                history_visibility.initial_state(),
+            ],
+        });
+        if let Some(t) = topic {
+            body["topic"] = serde_json::Value::String(t);
+        }
+        self.create_room_from(body).await
+    }
+
+    async fn create_room_from(&self, body: serde_json::Value) -> Result<CreateRoomResponse, MatrixError> {
+        let token = self.access_token.as_ref()
+            .ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let url = format!("{}/createRoom", self.client_api_base().await);
+
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<CreateRoomResponse>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -375,11 +987,11 @@ impl MatrixClient {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/directory/room/{}",
-            self.homeserver_url,
-            encode_matrix_id(&room_alias)
+            "{}/directory/room/{}",
+            self.client_api_base().await,
+            encode_path_segment(&room_alias)
         );
         
         let body = serde_json::json!({
@@ -390,14 +1002,48 @@ impl MatrixClient {
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    /// the room an alias points at — needs no session
+    pub async fn resolve_alias(&self, room_alias: &str) -> Result<ResolvedAlias, MatrixError> {
+        let url = format!(
+            "{}/directory/room/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_alias)
+        );
+        let response = self.http.get(&url).send_with_retry(self.retry).await?;
+        if !response.status().is_success() {
+            return Err(MatrixError::from_response(response).await);
+        }
+        Ok(response.json::<ResolvedAlias>().await?)
+    }
+
+    pub async fn delete_room_alias(&self, room_alias: String) -> Result<(), MatrixError> {
+        let token = self.access_token.as_ref()
+            .ok_or(MatrixError::NoSession)?;
+        let url = format!(
+            "{}/directory/room/{}",
+            self.client_api_base().await,
+            encode_path_segment(&room_alias)
+        );
+
+        let response = self.http
+            .delete(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -408,26 +1054,25 @@ impl MatrixClient {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/join/{}",
-            self.homeserver_url,
-            encode_matrix_id(&room_id_or_alias)
+            "{}/join/{}",
+            self.client_api_base().await,
+            encode_path_segment(&room_id_or_alias)
         );
 
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&serde_json::json!({}))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<JoinRoomResponse>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -435,21 +1080,20 @@ impl MatrixClient {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
-        let url = format!("{}/_matrix/client/r0/joined_rooms", self.homeserver_url);
+        let client = &self.http;
+        let url = format!("{}/joined_rooms", self.client_api_base().await);
 
         let response = client
             .get(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<JoinedRoomsResponse>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -460,25 +1104,24 @@ impl MatrixClient {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/members",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/rooms/{}/members",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
         );
 
         let response = client
             .get(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<RoomMembersResponse>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -489,355 +1132,517 @@ impl MatrixClient {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/state",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/rooms/{}/state",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
         );
 
         let response = client
             .get(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<Vec<RoomStateEvent>>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
     // add a room as a child of a space (m.space.child state event)
+    // refuses with HierarchyCycle if the space already sits below the child
     pub async fn add_space_child(
         &self,
         space_id: String,
         child_room_id: String,
+        via: &str,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
+
+        if super::hierarchy::creates_cycle(self, &space_id, &child_room_id).await {
+            return Err(MatrixError::HierarchyCycle);
+        }
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/state/m.space.child/{}",
-            self.homeserver_url,
-            encode_matrix_id(&space_id),
-            encode_matrix_id(&child_room_id)
+            "{}/rooms/{}/state/m.space.child/{}",
+            self.client_api_base().await,
+            encode_path_segment(&space_id),
+            encode_path_segment(&child_room_id)
         );
         
         let body = serde_json::json!({
-            "via": ["localhost"]
+            "via": [via]
         });
 
         let response = client
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    // remove a room as a child of a space (delete m.space.child state event)
+    // remove a room as a child of a space. state events can't be deleted — the spaces
+    // spec has the m.space.child event replaced with empty content instead
     pub async fn remove_space_child(
         &self,
         space_id: String,
         child_room_id: String,
+    ) -> Result<(), MatrixError> {
+        self.send_state_event(space_id, "m.space.child".to_string(), child_room_id, serde_json::json!({}))
+            .await
+    }
+
+    pub async fn invite_user(
+        &self,
+        room_id: String,
+        user_id: String,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/state/m.space.child/{}",
-            self.homeserver_url,
-            encode_matrix_id(&space_id),
-            encode_matrix_id(&child_room_id)
+            "{}/rooms/{}/invite",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
         );
+        
+        let body = serde_json::json!({
+            "user_id": user_id
+        });
 
         let response = client
-            .delete(&url)
+            .post(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .json(&body)
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn invite_user(
+    pub async fn leave_room(
         &self,
         room_id: String,
-        user_id: String,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/invite",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/rooms/{}/leave",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
         );
-        
-        let body = serde_json::json!({
-            "user_id": user_id
-        });
 
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .json(&body)
-            .send()
+            .json(&serde_json::json!({}))
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn leave_room(
+    pub async fn forget_room(
         &self,
         room_id: String,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/leave",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/rooms/{}/forget",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
         );
 
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&serde_json::json!({}))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn forget_room(
+    pub async fn get_power_levels(
+        &self,
+        room_id: String,
+    ) -> Result<PowerLevelsResponse, MatrixError> {
+        let token = self.access_token.as_ref()
+            .ok_or(MatrixError::NoSession)?;
+        
+        let client = &self.http;
+        let url = format!(
+            "{}/rooms/{}/state/m.room.power_levels",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
+        );
+
+        let response = client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send_with_retry(self.retry)
+            .await?;
+        
+        if response.status().is_success() {
+            let result = response.json::<PowerLevelsResponse>().await?;
+            Ok(result)
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    pub async fn set_power_levels(
         &self,
         room_id: String,
+        power_levels: PowerLevelsRequest,
+    ) -> Result<(), MatrixError> {
+        let token = self.access_token.as_ref()
+            .ok_or(MatrixError::NoSession)?;
+        
+        let client = &self.http;
+        let url = format!(
+            "{}/rooms/{}/state/m.room.power_levels",
+            self.client_api_base().await,
+            encode_path_segment(&room_id)
+        );
+
+        let response = client
+            .put(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&power_levels)
+            .send_with_retry(self.retry)
+            .await?;
+        
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    // ── presence ──────────────────────────────────────────────────────────────
+
+    pub async fn set_presence(
+        &self,
+        user_id: String,
+        presence: String,
+        status_msg: Option<String>,
+    ) -> Result<(), MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let url = format!(
+            "{}/presence/{}/status",
+            self.client_api_base().await,
+            encode_path_segment(&user_id)
+        );
+        let mut body = serde_json::json!({ "presence": presence });
+        if let Some(msg) = status_msg {
+            body["status_msg"] = serde_json::Value::String(msg);
+        }
+        let response = client
+            .put(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    pub async fn get_presence(
+        &self,
+        user_id: String,
+    ) -> Result<PresenceData, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let url = format!(
+            "{}/presence/{}/status",
+            self.client_api_base().await,
+            encode_path_segment(&user_id)
+        );
+        let response = client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            let data = response.json::<PresenceData>().await?;
+            Ok(data)
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    // ── profile ───────────────────────────────────────────────────────────────
+
+    pub async fn get_profile(
+        &self,
+        user_id: String,
+    ) -> Result<ProfileData, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let url = format!(
+            "{}/profile/{}",
+            self.client_api_base().await,
+            encode_path_segment(&user_id)
+        );
+        let response = client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            let data = response.json::<ProfileData>().await?;
+            Ok(data)
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    pub async fn set_displayname(
+        &self,
+        user_id: String,
+        displayname: String,
+    ) -> Result<(), MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let url = format!(
+            "{}/profile/{}/displayname",
+            self.client_api_base().await,
+            encode_path_segment(&user_id)
+        );
+        let body = serde_json::json!({ "displayname": displayname });
+        let response = client
+            .put(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    /// `avatar_url` is an mxc:// uri, usually one just returned by upload_media
+    pub async fn set_avatar_url(
+        &self,
+        user_id: String,
+        avatar_url: String,
     ) -> Result<(), MatrixError> {
-        let token = self.access_token.as_ref()
-            .ok_or(MatrixError::NoSession)?;
-        
-        let client = reqwest::Client::new();
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/forget",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/profile/{}/avatar_url",
+            self.client_api_base().await,
+            encode_path_segment(&user_id)
         );
-
+        let body = serde_json::json!({ "avatar_url": avatar_url });
         let response = client
-            .post(&url)
+            .put(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .json(&serde_json::json!({}))
-            .send()
+            .json(&body)
+            .send_with_retry(self.retry)
             .await?;
-        
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn get_power_levels(
-        &self,
-        room_id: String,
-    ) -> Result<PowerLevelsResponse, MatrixError> {
-        let token = self.access_token.as_ref()
-            .ok_or(MatrixError::NoSession)?;
-        
-        let client = reqwest::Client::new();
+    // ── account data ──────────────────────────────────────────────────────────
+
+    /// the session user's global account data of `data_type` — M_NOT_FOUND
+    /// when none was ever set
+    pub async fn get_account_data(&self, data_type: &str) -> Result<serde_json::Value, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let user_id = self.user_id.as_ref().ok_or(MatrixError::NoSession)?;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/state/m.room.power_levels",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/user/{}/account_data/{}",
+            self.client_api_base().await,
+            encode_path_segment(user_id),
+            encode_path_segment(data_type)
         );
-
-        let response = client
+        let response = self
+            .http
             .get(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
-        
         if response.status().is_success() {
-            let result = response.json::<PowerLevelsResponse>().await?;
-            Ok(result)
+            Ok(response.json::<serde_json::Value>().await?)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn set_power_levels(
-        &self,
-        room_id: String,
-        power_levels: PowerLevelsRequest,
-    ) -> Result<(), MatrixError> {
-        let token = self.access_token.as_ref()
-            .ok_or(MatrixError::NoSession)?;
-        
-        let client = reqwest::Client::new();
+    /// replace the session user's global account data of `data_type`
+    pub async fn set_account_data(&self, data_type: &str, content: &serde_json::Value) -> Result<(), MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let user_id = self.user_id.as_ref().ok_or(MatrixError::NoSession)?;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/state/m.room.power_levels",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/user/{}/account_data/{}",
+            self.client_api_base().await,
+            encode_path_segment(user_id),
+            encode_path_segment(data_type)
         );
-
-        let response = client
+        let response = self
+            .http
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .json(&power_levels)
-            .send()
+            .json(content)
+            .send_with_retry(self.retry)
             .await?;
-        
         if response.status().is_success() {
             Ok(())
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    // ── presence ──────────────────────────────────────────────────────────────
-
-    pub async fn set_presence(
+    /// tag one of the session user's rooms (m.favourite, m.lowpriority, u.*).
+    /// `order` in [0, 1] sorts rooms sharing the tag
+    pub async fn set_room_tag(
         &self,
-        user_id: String,
-        presence: String,
-        status_msg: Option<String>,
+        user_id: &str,
+        room_id: &str,
+        tag: &str,
+        order: Option<f64>,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
         let url = format!(
-            "{}/_matrix/client/r0/presence/{}/status",
-            self.homeserver_url,
-            encode_matrix_id(&user_id)
+            "{}/user/{}/rooms/{}/tags/{}",
+            self.client_api_base().await,
+            encode_path_segment(user_id),
+            encode_path_segment(room_id),
+            encode_path_segment(tag)
         );
-        let mut body = serde_json::json!({ "presence": presence });
-        if let Some(msg) = status_msg {
-            body["status_msg"] = serde_json::Value::String(msg);
-        }
-        let response = client
+        let body = match order {
+            Some(order) => serde_json::json!({ "order": order }),
+            None => serde_json::json!({}),
+        };
+        let response = self
+            .http
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
             Ok(())
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn get_presence(
-        &self,
-        user_id: String,
-    ) -> Result<PresenceData, MatrixError> {
+    /// remove a tag from one of the session user's rooms
+    pub async fn delete_room_tag(&self, user_id: &str, room_id: &str, tag: &str) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
         let url = format!(
-            "{}/_matrix/client/r0/presence/{}/status",
-            self.homeserver_url,
-            encode_matrix_id(&user_id)
+            "{}/user/{}/rooms/{}/tags/{}",
+            self.client_api_base().await,
+            encode_path_segment(user_id),
+            encode_path_segment(room_id),
+            encode_path_segment(tag)
         );
-        let response = client
-            .get(&url)
+        let response = self
+            .http
+            .delete(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
-            let data = response.json::<PresenceData>().await?;
-            Ok(data)
+            Ok(())
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    // ── profile ───────────────────────────────────────────────────────────────
-
-    pub async fn get_profile(
-        &self,
-        user_id: String,
-    ) -> Result<ProfileData, MatrixError> {
+    /// start or stop `user_id` typing in a room. the homeserver stops it by
+    /// itself after `timeout_ms` unless it's renewed
+    pub async fn set_typing(&self, user_id: &str, room_id: &str, typing: bool, timeout_ms: u64) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
         let url = format!(
-            "{}/_matrix/client/r0/profile/{}",
-            self.homeserver_url,
-            encode_matrix_id(&user_id)
+            "{}/rooms/{}/typing/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            encode_path_segment(user_id)
         );
-        let response = client
-            .get(&url)
+        let body = if typing {
+            serde_json::json!({ "typing": true, "timeout": timeout_ms })
+        } else {
+            serde_json::json!({ "typing": false })
+        };
+        let response = self
+            .http
+            .put(&url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .json(&body)
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
-            let data = response.json::<ProfileData>().await?;
-            Ok(data)
+            Ok(())
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
-    pub async fn set_displayname(
+    /// look users up in the homeserver's user directory by id or display name
+    pub async fn search_users(
         &self,
-        user_id: String,
-        displayname: String,
-    ) -> Result<(), MatrixError> {
+        search_term: &str,
+        limit: u32,
+    ) -> Result<UserDirectoryResponse, MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
-        let url = format!(
-            "{}/_matrix/client/r0/profile/{}/displayname",
-            self.homeserver_url,
-            encode_matrix_id(&user_id)
-        );
-        let body = serde_json::json!({ "displayname": displayname });
+        let client = &self.http;
+        let url = format!("{}/user_directory/search", self.client_api_base().await);
+        let body = serde_json::json!({ "search_term": search_term, "limit": limit });
         let response = client
-            .put(&url)
+            .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
-            Ok(())
+            Ok(response.json::<UserDirectoryResponse>().await?)
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -847,33 +1652,34 @@ impl MatrixClient {
         &self,
         other_user_id: String,
         display_name: String,
+        history_visibility: HistoryVisibility,
     ) -> Result<CreateRoomResponse, MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
 
-        let client = reqwest::Client::new();
-        let url = format!("{}/_matrix/client/r0/createRoom", self.homeserver_url);
+        let client = &self.http;
+        let url = format!("{}/createRoom", self.client_api_base().await);
 
         let body = serde_json::json!({
             "name": display_name,
             "preset": "trusted_private_chat",
             "is_direct": true,
-            "invite": [other_user_id]
+            "invite": [other_user_id],
+            "initial_state": [history_visibility.initial_state()],
         });
 
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
 
         if response.status().is_success() {
             let result = response.json::<CreateRoomResponse>().await?;
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -886,11 +1692,11 @@ impl MatrixClient {
         content: serde_json::Value,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/state/{}/{}",
-            self.homeserver_url,
-            encode_matrix_id(&room_id),
+            "{}/rooms/{}/state/{}/{}",
+            self.client_api_base().await,
+            encode_path_segment(&room_id),
             event_type,
             state_key
         );
@@ -898,13 +1704,12 @@ impl MatrixClient {
             .put(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&content)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
             Ok(())
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -912,41 +1717,162 @@ impl MatrixClient {
         &self,
         name: String,
         parent_space_id: String,
+        via: &str,
     ) -> Result<CreateRoomResponse, MatrixError> {
         let token = self.access_token.as_ref()
             .ok_or(MatrixError::NoSession)?;
         
-        let client = reqwest::Client::new();
-        let url = format!("{}/_matrix/client/r0/createRoom", self.homeserver_url);
+        let client = &self.http;
+        let url = format!("{}/createRoom", self.client_api_base().await);
         
+        // record the parent on the category itself so nesting depth can be
+        // checked later without scanning every space for its children
         let body = serde_json::json!({
             "name": name,
             "preset": "public_chat",
             "room_version": "9",
             "creation_content": {
                 "type": "m.space"
-            }
+            },
+            "initial_state": [{
+                "type": "m.space.parent",
+                "state_key": parent_space_id,
+                "content": { "via": [via], "canonical": true }
+            }]
         });
 
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         
         if response.status().is_success() {
             let result = response.json::<CreateRoomResponse>().await?;
             
             // Add the new category (subspace) as a child of the parent space
-            if let Err(e) = self.add_space_child(parent_space_id, result.room_id.clone()).await {
+            if let Err(e) = self.add_space_child(parent_space_id, result.room_id.clone(), via).await {
                 tracing::warn!("failed to add category to parent space: {}", e);
             }
             
             Ok(result)
         } else {
-            let error_text = response.text().await?;
-            Err(MatrixError::ApiError(error_text))
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    /// fetch a single event by id. event ids from room v4+ can contain '/' and '+',
+    /// so they're fully percent-encoded
+    pub async fn get_event(&self, room_id: &str, event_id: &str) -> Result<serde_json::Value, MatrixError> {
+        let url = format!(
+            "{}/rooms/{}/event/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            encode_path_segment(event_id)
+        );
+        self.get_raw(&url).await
+    }
+
+    /// a page of the rooms below `space_id` (itself first) that the caller can
+    /// see, at most `max_depth` levels down
+    pub async fn get_space_hierarchy(
+        &self,
+        space_id: &str,
+        limit: u32,
+        from: Option<&str>,
+        max_depth: usize,
+    ) -> Result<HierarchyResponse, MatrixError> {
+        // like relations, /hierarchy only exists under v1
+        let version = match self.api_version().await {
+            ApiVersion::V3 => "v1",
+            ApiVersion::R0 => "unstable/org.matrix.msc2946",
+        };
+        let mut url = format!(
+            "{}/_matrix/client/{}/rooms/{}/hierarchy?limit={}&max_depth={}",
+            self.homeserver_url,
+            version,
+            encode_path_segment(space_id),
+            limit,
+            max_depth
+        );
+        if let Some(from) = from {
+            url.push_str(&format!("&from={}", urlencoding::encode(from)));
+        }
+        Ok(serde_json::from_value(self.get_raw(&url).await?)?)
+    }
+
+    /// every event relating to `event_id` with `rel_type`, e.g. the m.reaction
+    /// annotations on a message. follows next_batch for at most MAX_RELATION_PAGES.
+    pub async fn get_relations(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        rel_type: &str,
+        event_type: &str,
+    ) -> Result<Vec<Event>, MatrixError> {
+        // relations were stabilised under v1 alongside the v3 endpoints
+        let version = match self.api_version().await {
+            ApiVersion::V3 => "v1",
+            ApiVersion::R0 => "unstable",
+        };
+        let base = format!(
+            "{}/_matrix/client/{}/rooms/{}/relations/{}/{}/{}?limit=100",
+            self.homeserver_url,
+            version,
+            encode_path_segment(room_id),
+            encode_path_segment(event_id),
+            encode_path_segment(rel_type),
+            encode_path_segment(event_type)
+        );
+        let mut events = Vec::new();
+        let mut from: Option<String> = None;
+        for _ in 0..MAX_RELATION_PAGES {
+            let url = match &from {
+                Some(from) => format!("{}&from={}", base, urlencoding::encode(from)),
+                None => base.clone(),
+            };
+            let page: RelationsResponse = serde_json::from_value(self.get_raw(&url).await?)?;
+            events.extend(page.chunk);
+            match page.next_batch {
+                Some(next) => from = Some(next),
+                None => break,
+            }
+        }
+        Ok(events)
+    }
+
+    /// redact (delete) an event — the homeserver checks the redact power level
+    pub async fn redact_event(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        reason: Option<String>,
+    ) -> Result<serde_json::Value, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let txn_id = uuid::Uuid::new_v4().to_string();
+        let url = format!(
+            "{}/rooms/{}/redact/{}/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            encode_path_segment(event_id),
+            txn_id
+        );
+        let mut body = serde_json::json!({});
+        if let Some(reason) = reason {
+            body["reason"] = serde_json::Value::String(reason);
+        }
+        let response = client
+            .put(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            Ok(response.json::<serde_json::Value>().await?)
+        } else {
+            Err(MatrixError::from_response(response).await)
         }
     }
 
@@ -956,13 +1882,40 @@ impl MatrixClient {
         room_id: String,
         user_id: String,
         reason: Option<String>,
+    ) -> Result<(), MatrixError> {
+        self.membership_action(&room_id, "kick", &user_id, reason).await
+    }
+
+    /// ban a user from a room — also removes them if they're in it (requires power)
+    pub async fn ban_user(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        reason: Option<String>,
+    ) -> Result<(), MatrixError> {
+        self.membership_action(room_id, "ban", user_id, reason).await
+    }
+
+    /// lift a ban, leaving the user free to join again (requires power)
+    pub async fn unban_user(&self, room_id: &str, user_id: &str) -> Result<(), MatrixError> {
+        self.membership_action(room_id, "unban", user_id, None).await
+    }
+
+    /// POST /rooms/{id}/{kick|ban|unban} for another user
+    async fn membership_action(
+        &self,
+        room_id: &str,
+        action: &str,
+        user_id: &str,
+        reason: Option<String>,
     ) -> Result<(), MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let url = format!(
-            "{}/_matrix/client/r0/rooms/{}/kick",
-            self.homeserver_url,
-            encode_matrix_id(&room_id)
+            "{}/rooms/{}/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            action
         );
         let mut body = serde_json::json!({ "user_id": user_id });
         if let Some(r) = reason {
@@ -972,35 +1925,198 @@ impl MatrixClient {
             .post(&url)
             .header("Authorization", format!("Bearer {}", token))
             .json(&body)
-            .send()
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
             Ok(())
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
         }
     }
 
     /// GET an arbitrary matrix url with the current access token, return parsed json body
     pub async fn get_raw(&self, url: &str) -> Result<serde_json::Value, MatrixError> {
         let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
-        let client = reqwest::Client::new();
+        let client = &self.http;
         let response = client
             .get(url)
             .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send_with_retry(self.retry)
+            .await?;
+        if response.status().is_success() {
+            let body = response.json::<serde_json::Value>().await?;
+            Ok(body)
+        } else {
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    /// POST json to an arbitrary matrix url with the current access token, return parsed json body
+    pub async fn post_raw(&self, url: &str, body: &serde_json::Value) -> Result<serde_json::Value, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+        let client = &self.http;
+        let response = client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token))
+            .json(body)
+            .send_with_retry(self.retry)
             .await?;
         if response.status().is_success() {
             let body = response.json::<serde_json::Value>().await?;
             Ok(body)
         } else {
-            let err = response.text().await?;
-            Err(MatrixError::ApiError(err))
+            Err(MatrixError::from_response(response).await)
+        }
+    }
+
+    /// report an event to the homeserver's admins — `score` runs from -100 (most
+    /// offensive) to 0
+    pub async fn report_event(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        reason: Option<&str>,
+        score: Option<i32>,
+    ) -> Result<(), MatrixError> {
+        let url = format!(
+            "{}/rooms/{}/report/{}",
+            self.client_api_base().await,
+            encode_path_segment(room_id),
+            encode_path_segment(event_id)
+        );
+        let mut body = serde_json::json!({});
+        if let Some(reason) = reason {
+            body["reason"] = serde_json::json!(reason);
+        }
+        if let Some(score) = score {
+            body["score"] = serde_json::json!(score);
+        }
+        self.post_raw(&url, &body).await?;
+        Ok(())
+    }
+
+    /// the homeserver's media settings (currently just the upload size limit)
+    pub async fn get_media_config(&self) -> Result<MediaConfig, MatrixError> {
+        let url = format!("{}/config", self.media_api_base().await);
+        let body = self.get_raw(&url).await?;
+        Ok(serde_json::from_value(body)?)
+    }
+
+    /// the homeserver's OpenGraph preview of a url, as it answers it: og:title,
+    /// og:description, og:image (an mxc:// uri) and so on
+    pub async fn preview_url(&self, url: &str) -> Result<serde_json::Value, MatrixError> {
+        let url = format!("{}/preview_url?url={}", self.media_api_base().await, urlencoding::encode(url));
+        self.get_raw(&url).await
+    }
+
+    /// upload bytes to the media repo, returning the mxc:// uri.
+    /// size and permission rejections come back as their own error variants.
+    pub async fn upload_media(
+        &self,
+        content_type: &str,
+        filename: Option<&str>,
+        bytes: Vec<u8>,
+    ) -> Result<String, MatrixError> {
+        let token = self.access_token.as_ref().ok_or(MatrixError::NoSession)?;
+
+        let client = &self.http;
+        let mut url = format!("{}/upload", self.media_api_base().await);
+        if let Some(filename) = filename {
+            url = format!("{}?filename={}", url, urlencoding::encode(filename));
+        }
+
+        let response = client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", content_type)
+            .body(bytes)
+            .send_with_retry(self.retry)
+            .await?;
+
+        if response.status().is_success() {
+            let body: UploadResponse = response.json().await?;
+            Ok(body.content_uri)
+        } else {
+            let err = MatrixError::from_response(response).await;
+            match err.errcode() {
+                Some("M_TOO_LARGE") => Err(MatrixError::MediaTooLarge(err.to_string())),
+                Some("M_FORBIDDEN") => Err(MatrixError::MediaForbidden(err.to_string())),
+                _ => Err(err),
+            }
+        }
+    }
+
+    /// fetch a piece of media by its mxc:// parts, returning its content type
+    /// and bytes. goes through the unauthenticated download endpoint, so no
+    /// session is needed.
+    pub async fn download_media(
+        &self,
+        server_name: &str,
+        media_id: &str,
+    ) -> Result<(String, Vec<u8>), MatrixError> {
+        let url = format!(
+            "{}/download/{}/{}",
+            self.media_api_base().await,
+            encode_path_segment(server_name),
+            encode_path_segment(media_id)
+        );
+        let response = self.http.get(&url).send_with_retry(self.retry).await?;
+        if response.status().is_success() {
+            let content_type = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let bytes = response.bytes().await?;
+            Ok((content_type, bytes.to_vec()))
+        } else {
+            Err(MatrixError::from_response(response).await)
         }
     }
 }
 
+/// a busy message has a few hundred reactions at most
+const MAX_RELATION_PAGES: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct RelationsResponse {
+    chunk: Vec<Event>,
+    next_batch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaConfig {
+    /// max upload size in bytes — None when the homeserver doesn't advertise one
+    #[serde(rename = "m.upload.size")]
+    pub upload_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadResponse {
+    content_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WhoamiResponse {
+    pub user_id: String,
+    pub device_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DevicesResponse {
+    devices: Vec<Device>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    /// ms since epoch — None for a device that was never seen after login
+    pub last_seen_ts: Option<i64>,
+    pub last_seen_ip: Option<String>,
+}
+
 // room/server response types
 #[derive(Debug, Deserialize)]
 pub struct CreateRoomResponse {
@@ -1045,7 +2161,7 @@ pub struct RoomMemberContent {
     pub membership: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomStateEvent {
     #[serde(rename = "type")]
     pub event_type: String,
@@ -1084,8 +2200,23 @@ pub struct PowerLevelsResponse {
 pub enum MatrixError {
     Reqwest(reqwest::Error),
     NoSession,
+    /// the homeserver refused with a standard `{errcode, error}` body
+    MatrixApiError {
+        status: u16,
+        errcode: String,
+        error: String,
+        /// how long M_LIMIT_EXCEEDED asks us to wait, from the body or Retry-After
+        retry_after_ms: Option<u64>,
+    },
+    /// a failure whose body wasn't a matrix error (a proxy page, an empty 502, ...)
     ApiError(String),
     JsonError(serde_json::Error),
+    /// refused locally: the child is the space itself or one of its ancestors
+    HierarchyCycle,
+    /// the homeserver refused an upload as too large (M_TOO_LARGE)
+    MediaTooLarge(String),
+    /// the homeserver refused an upload (M_FORBIDDEN — quota, store full, no permission)
+    MediaForbidden(String),
 }
 
 impl From<reqwest::Error> for MatrixError {
@@ -1103,16 +2234,107 @@ impl From<serde_json::Error> for MatrixError {
 impl std::fmt::Display for MatrixError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MatrixError::Reqwest(e) => write!(f, "request error: {}", e),
+            // reqwest's message names the url, and a body can echo what was sent
+            MatrixError::Reqwest(e) => write!(f, "request error: {}", redact(&e.to_string())),
             MatrixError::NoSession => write!(f, "no uia session returned"),
-            MatrixError::ApiError(e) => write!(f, "api error: {}", e),
+            MatrixError::MatrixApiError { status, errcode, error, .. } => {
+                write!(f, "{} {}: {}", status, errcode, error)
+            }
+            MatrixError::ApiError(e) => write!(f, "api error: {}", redact(e)),
             MatrixError::JsonError(e) => write!(f, "json error: {}", e),
+            MatrixError::HierarchyCycle => write!(f, "space hierarchy cycle"),
+            MatrixError::MediaTooLarge(e) => write!(f, "upload too large: {}", e),
+            MatrixError::MediaForbidden(e) => write!(f, "upload forbidden: {}", e),
         }
     }
 }
 
 impl std::error::Error for MatrixError {}
 
+impl MatrixError {
+    /// classify a failed response — a matrix error body when it has one,
+    /// otherwise the raw text
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        // Retry-After is in seconds; the body's retry_after_ms wins when both are present
+        let retry_after_ms = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(|secs| secs.saturating_mul(1000));
+        match response.text().await {
+            Ok(body) => Self::from_body(status, retry_after_ms, body),
+            Err(e) => MatrixError::Reqwest(e),
+        }
+    }
+
+    pub fn from_body(status: reqwest::StatusCode, retry_after_ms: Option<u64>, body: String) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            errcode: String,
+            #[serde(default)]
+            error: String,
+            retry_after_ms: Option<u64>,
+        }
+        match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(parsed) => MatrixError::MatrixApiError {
+                status: status.as_u16(),
+                errcode: parsed.errcode,
+                error: parsed.error,
+                retry_after_ms: parsed.retry_after_ms.or(retry_after_ms),
+            },
+            Err(_) => MatrixError::ApiError(body),
+        }
+    }
+
+    /// the matrix errcode of a homeserver rejection, when its body carried one
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            MatrixError::MatrixApiError { errcode, .. } => Some(errcode),
+            _ => None,
+        }
+    }
+
+    /// the http status the homeserver answered with, for api errors
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            MatrixError::MatrixApiError { status, .. } => Some(*status),
+            MatrixError::Reqwest(e) => e.status().map(|s| s.as_u16()),
+            _ => None,
+        }
+    }
+
+    pub fn is_forbidden(&self) -> bool {
+        self.errcode() == Some("M_FORBIDDEN")
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.errcode() == Some("M_NOT_FOUND")
+    }
+
+    /// the alias is already pointing at a room — synapse answers 409 M_UNKNOWN,
+    /// other homeservers M_ROOM_IN_USE
+    pub fn is_alias_taken(&self) -> bool {
+        self.errcode() == Some("M_ROOM_IN_USE") || self.status() == Some(409)
+    }
+
+    pub fn is_unknown_token(&self) -> bool {
+        matches!(self.errcode(), Some("M_UNKNOWN_TOKEN" | "M_MISSING_TOKEN"))
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.errcode() == Some("M_LIMIT_EXCEEDED") || self.status() == Some(429)
+    }
+
+    pub fn retry_after_ms(&self) -> Option<u64> {
+        match self {
+            MatrixError::MatrixApiError { retry_after_ms, .. } => *retry_after_ms,
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PresenceData {
     pub presence: String,
@@ -1122,6 +2344,22 @@ pub struct PresenceData {
 }
 
 #[derive(Debug, Deserialize)]
+pub struct UserDirectoryResponse {
+    #[serde(default)]
+    pub results: Vec<UserDirectoryEntry>,
+    /// more users matched than were returned
+    #[serde(default)]
+    pub limited: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserDirectoryEntry {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct ProfileData {
     pub displayname: Option<String>,
     pub avatar_url: Option<String>,
@@ -0,0 +1,238 @@
+// hierarchy.rs — walking space trees (server → categories → channels)
+// every traversal is a breadth-first walk with a visited set and a depth cap,
+// so a cycle in raw m.space.child state (which matrix itself doesn't prevent)
+// can never loop forever, and deeper nests are walked up to the cap instead of
+// being silently ignored. the rooms at each depth are read together, a few at a
+// time, rather than one after another.
+
+use futures_util::{stream, StreamExt};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::OnceLock;
+use super::channel_access::ChannelPermissions;
+use super::client::{MatrixClient, RoomStateEvent};
+
+/// default for HIERARCHY_MAX_DEPTH — server (0) → category (1) → channel (2), with headroom
+const DEFAULT_MAX_DEPTH: usize = 4;
+
+/// the ui only supports server → category → channel, so spaces nest at most two levels
+pub const MAX_SPACE_NESTING: usize = 2;
+
+/// state reads in flight at once during a walk
+const WALK_CONCURRENCY: usize = 8;
+
+/// how deep hierarchy walks go (HIERARCHY_MAX_DEPTH, read once)
+pub fn max_depth() -> usize {
+    static MAX_DEPTH: OnceLock<usize> = OnceLock::new();
+    *MAX_DEPTH.get_or_init(|| {
+        std::env::var("HIERARCHY_MAX_DEPTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_DEPTH)
+    })
+}
+
+/// one room reached during a walk, with the state it was read with
+pub struct SpaceNode {
+    pub room_id: String,
+    /// the space this room was reached through — None for the root
+    pub parent_id: Option<String>,
+    pub depth: usize,
+    /// the `order` the parent gives this room — None for the root and unordered children
+    pub order: Option<String>,
+    pub is_space: bool,
+    /// empty when the state couldn't be read (not joined, 403, ...)
+    pub state: Vec<RoomStateEvent>,
+}
+
+impl SpaceNode {
+    /// the starting point of a walk
+    pub fn root(room_id: &str, state: Vec<RoomStateEvent>) -> Self {
+        let is_space = is_space(&state);
+        SpaceNode { room_id: room_id.to_string(), parent_id: None, depth: 0, order: None, is_space, state }
+    }
+}
+
+pub fn is_space(state: &[RoomStateEvent]) -> bool {
+    state.iter().any(|e| {
+        e.event_type == "m.room.create"
+            && e.content.get("type").and_then(|v| v.as_str()) == Some("m.space")
+    })
+}
+
+/// a child's `order`, if it's one the spaces spec allows (≤ 50 printable ascii chars)
+fn valid_order(content: &serde_json::Value) -> Option<String> {
+    content
+        .get("order")
+        .and_then(|o| o.as_str())
+        .filter(|o| o.len() <= 50 && o.bytes().all(|b| (0x20..=0x7e).contains(&b)))
+        .map(String::from)
+}
+
+/// a space's children with their `order`, sorted the way they're shown: ordered
+/// children first (lexicographically), then the rest, ties broken by room id.
+/// children with emptied content have been removed and are skipped.
+pub fn children(state: &[RoomStateEvent]) -> Vec<(String, Option<String>)> {
+    let mut children: Vec<(String, Option<String>)> = state
+        .iter()
+        .filter(|e| e.event_type == "m.space.child")
+        .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+        .filter_map(|e| Some((e.state_key.clone()?, valid_order(&e.content))))
+        .filter(|(k, _)| !k.is_empty())
+        .collect();
+    children.sort_by(|(a_id, a_order), (b_id, b_order)| child_order((a_id, a_order.as_deref()), (b_id, b_order.as_deref())));
+    children
+}
+
+/// how two children of one space sort, as (room id, order)
+pub fn child_order(a: (&str, Option<&str>), b: (&str, Option<&str>)) -> Ordering {
+    match (a.1, b.1) {
+        (Some(a_order), Some(b_order)) => a_order.cmp(b_order).then_with(|| a.0.cmp(b.0)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.0.cmp(b.0),
+    }
+}
+
+/// room ids from a space's m.space.child state events, in display order
+pub fn child_ids(state: &[RoomStateEvent]) -> Vec<String> {
+    children(state).into_iter().map(|(id, _)| id).collect()
+}
+
+/// breadth-first walk from `root_id`, root included at depth 0. children of a
+/// space at `max_depth` are not visited, and each room is visited at most once.
+pub async fn walk_space(matrix: &MatrixClient, root_id: &str, max_depth: usize) -> Vec<SpaceNode> {
+    let state = read_state(matrix, root_id.to_string()).await;
+    walk_below(SpaceNode::root(root_id, state), None, max_depth, |room_id, _| read_state(matrix, room_id)).await
+}
+
+/// the rest of a walk whose root has already been read. `first` stands in for
+/// the root's own children (one page of them, say), and `load` reads each
+/// room's state, told whether the room's parent marks it a private channel.
+pub async fn walk_below<L, F>(
+    root: SpaceNode,
+    first: Option<Vec<(String, Option<String>)>>,
+    max_depth: usize,
+    load: L,
+) -> Vec<SpaceNode>
+where
+    L: Fn(String, bool) -> F,
+    F: Future<Output = Vec<RoomStateEvent>>,
+{
+    let mut visited: HashSet<String> = HashSet::new();
+    visited.insert(root.room_id.clone());
+    let mut level = Vec::new();
+    if root.is_space && root.depth < max_depth {
+        let children = first.unwrap_or_else(|| children(&root.state));
+        level = unvisited(&mut visited, &root, children);
+    }
+
+    let mut depth = root.depth + 1;
+    let mut nodes = vec![root];
+    while !level.is_empty() {
+        let reads: Vec<(String, bool)> = level.iter().map(|child| (child.room_id.clone(), child.private)).collect();
+        let states: Vec<Vec<RoomStateEvent>> = stream::iter(reads)
+            .map(|(room_id, private)| load(room_id, private))
+            .buffered(WALK_CONCURRENCY)
+            .collect()
+            .await;
+        let mut next = Vec::new();
+        for (child, state) in level.into_iter().zip(states) {
+            let room_is_space = is_space(&state);
+            let node = SpaceNode {
+                room_id: child.room_id,
+                parent_id: Some(child.parent_id),
+                depth,
+                order: child.order,
+                is_space: room_is_space,
+                state,
+            };
+            if room_is_space && depth < max_depth {
+                let children = children(&node.state);
+                next.extend(unvisited(&mut visited, &node, children));
+            }
+            nodes.push(node);
+        }
+        level = next;
+        depth += 1;
+    }
+
+    nodes
+}
+
+/// a room queued for the next level of a walk
+struct Pending {
+    room_id: String,
+    parent_id: String,
+    order: Option<String>,
+    private: bool,
+}
+
+fn unvisited(visited: &mut HashSet<String>, parent: &SpaceNode, children: Vec<(String, Option<String>)>) -> Vec<Pending> {
+    children
+        .into_iter()
+        .filter_map(|(child_id, order)| {
+            if !visited.insert(child_id.clone()) {
+                tracing::debug!("hierarchy walk: {} already visited (cycle or shared child)", child_id);
+                return None;
+            }
+            let private = ChannelPermissions::of_child(&parent.state, &child_id).private;
+            Some(Pending { room_id: child_id, parent_id: parent.room_id.clone(), order, private })
+        })
+        .collect()
+}
+
+async fn read_state(matrix: &MatrixClient, room_id: String) -> Vec<RoomStateEvent> {
+    match matrix.get_room_state(room_id.clone()).await {
+        Ok(state) => state,
+        Err(e) => {
+            tracing::debug!("hierarchy walk: cannot read state of {}: {}", room_id, e);
+            Vec::new()
+        }
+    }
+}
+
+/// true when making `child_id` a child of `parent_id` would close a loop, i.e.
+/// the parent is the child itself or already sits somewhere below it
+pub async fn creates_cycle(matrix: &MatrixClient, parent_id: &str, child_id: &str) -> bool {
+    if parent_id == child_id {
+        return true;
+    }
+    walk_space(matrix, child_id, max_depth())
+        .await
+        .iter()
+        .any(|node| node.room_id == parent_id)
+}
+
+/// how many levels of spaces sit at and above `space_id`, following m.space.parent
+/// (a server is 1, a category is 2). spaces created before parents were recorded
+/// count as top-level.
+pub async fn space_nesting(matrix: &MatrixClient, space_id: &str) -> usize {
+    let mut level = 1;
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut current = space_id.to_string();
+    visited.insert(current.clone());
+
+    while level <= MAX_SPACE_NESTING {
+        let Ok(state) = matrix.get_room_state(current.clone()).await else {
+            break;
+        };
+        let parent = state
+            .iter()
+            .filter(|e| e.event_type == "m.space.parent")
+            .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+            .find_map(|e| e.state_key.clone())
+            .filter(|k| !k.is_empty());
+
+        match parent {
+            Some(parent_id) if visited.insert(parent_id.clone()) => {
+                level += 1;
+                current = parent_id;
+            }
+            _ => break,
+        }
+    }
+
+    level
+}
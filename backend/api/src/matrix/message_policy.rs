@@ -0,0 +1,243 @@
+// message_policy.rs — who may edit or delete a message, and until when
+// servers can limit how long after sending a message may still be edited or
+// deleted (agora.server.settings on the server space, 0 = unlimited).
+// moderators — redact power in the room, or a server role with
+// manage_messages / administrator — are never held to the windows.
+// a channel finds its server by following m.space.parent upwards; rooms
+// outside any server (dms, channels created before parents were recorded)
+// have no settings and therefore no windows.
+// the same state decides who may ping @everyone: room power at least
+// notifications.room, or a server role with mention_everyone / administrator.
+// a channel can also be in slowmode (agora.channel.slowmode), which moderators
+// are exempt from.
+// a member timed out on the server (agora.member.timeout, keyed by user) can't
+// post or react anywhere in it until the timeout's `until` has passed, and a
+// locked forum thread (agora.thread.meta) takes no new messages.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::collections::HashMap;
+use super::client::{MatrixClient, RoomStateEvent};
+use super::hierarchy;
+
+// matrix default when power levels don't set `redact`
+const DEFAULT_REDACT_LEVEL: i64 = 50;
+// matrix default when power levels don't set `notifications.room`
+const DEFAULT_ROOM_NOTIFICATION_LEVEL: i64 = 50;
+
+pub const SLOWMODE_EVENT_TYPE: &str = "agora.channel.slowmode";
+
+pub const TIMEOUT_EVENT_TYPE: &str = "agora.member.timeout";
+
+/// longest slowmode a channel can be given — six hours
+pub const MAX_SLOWMODE_SECONDS: u64 = 6 * 60 * 60;
+
+/// seconds a member has to wait between messages in a room, 0 when slowmode is off
+pub fn slowmode_seconds(room_state: &[RoomStateEvent]) -> u64 {
+    room_state
+        .iter()
+        .find(|e| e.event_type == SLOWMODE_EVENT_TYPE && e.state_key.as_deref() == Some(""))
+        .and_then(|e| e.content["seconds"].as_u64())
+        .unwrap_or(0)
+        .min(MAX_SLOWMODE_SECONDS)
+}
+
+/// the agora.server.settings state event
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct ServerSettings {
+    /// minutes after sending that a message can still be edited — 0 = unlimited
+    #[serde(default)]
+    pub edit_window_minutes: u64,
+    /// minutes after sending that a message can still be deleted by its sender — 0 = unlimited
+    #[serde(default)]
+    pub delete_window_minutes: u64,
+}
+
+/// why an edit or delete was refused
+pub enum Denied {
+    /// the window closed at `cutoff` (ms since epoch)
+    WindowExpired { cutoff: i64 },
+    /// not the caller's message and they're not a moderator
+    NotAllowed,
+}
+
+/// the rules for one user in one room
+pub struct MessagePolicy {
+    pub user_id: String,
+    pub settings: ServerSettings,
+    pub is_moderator: bool,
+    /// may notify the whole room with @everyone
+    pub can_mention_everyone: bool,
+    /// seconds between messages, 0 when off or when the user is a moderator
+    pub slowmode_seconds: u64,
+}
+
+/// when a window opened at `sent_at` closes — None while it's unlimited
+fn cutoff(sent_at: i64, window_minutes: u64) -> Option<i64> {
+    (window_minutes > 0).then(|| sent_at.saturating_add(window_minutes as i64 * 60_000))
+}
+
+fn check_window(sent_at: Option<i64>, window_minutes: u64, now: i64) -> Result<(), Denied> {
+    // an event without a timestamp can't be placed in a window — treat it as old
+    match cutoff(sent_at.unwrap_or(0), window_minutes) {
+        Some(cutoff) if now > cutoff => Err(Denied::WindowExpired { cutoff }),
+        _ => Ok(()),
+    }
+}
+
+impl MessagePolicy {
+    /// only the sender can edit (matrix ignores edits by anyone else); moderators skip the window
+    pub fn can_edit(&self, sender: &str, sent_at: Option<i64>, now: i64) -> Result<(), Denied> {
+        if sender != self.user_id {
+            return Err(Denied::NotAllowed);
+        }
+        if self.is_moderator {
+            return Ok(());
+        }
+        check_window(sent_at, self.settings.edit_window_minutes, now)
+    }
+
+    /// moderators can delete anything; everyone else only their own messages, inside the window
+    pub fn can_delete(&self, sender: &str, sent_at: Option<i64>, now: i64) -> Result<(), Denied> {
+        if self.is_moderator {
+            return Ok(());
+        }
+        if sender != self.user_id {
+            return Err(Denied::NotAllowed);
+        }
+        check_window(sent_at, self.settings.delete_window_minutes, now)
+    }
+}
+
+/// builds policies for one user, caching room state so a batch of channels in
+/// the same server only reads the server's state once
+pub struct PolicyLoader<'a> {
+    matrix: &'a MatrixClient,
+    user_id: String,
+    states: HashMap<String, Vec<RoomStateEvent>>,
+}
+
+impl<'a> PolicyLoader<'a> {
+    /// None when the token doesn't resolve to a user
+    pub async fn new(matrix: &'a MatrixClient) -> Option<Self> {
+        let user_id = matrix.whoami().await.ok()?.user_id;
+        Some(Self { matrix, user_id, states: HashMap::new() })
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    async fn state(&mut self, room_id: &str) -> &[RoomStateEvent] {
+        if !self.states.contains_key(room_id) {
+            let state = self.matrix.get_room_state(room_id.to_string()).await.unwrap_or_else(|e| {
+                tracing::debug!("message policy: cannot read state of {}: {}", room_id, e);
+                Vec::new()
+            });
+            self.states.insert(room_id.to_string(), state);
+        }
+        &self.states[room_id]
+    }
+
+    /// the top-most space above `room_id`, following m.space.parent
+    async fn server_of(&mut self, room_id: &str) -> Option<String> {
+        let mut server = None;
+        let mut current = room_id.to_string();
+        // server → category → channel is at most MAX_SPACE_NESTING hops up
+        for _ in 0..=hierarchy::MAX_SPACE_NESTING {
+            let parent = self
+                .state(&current)
+                .await
+                .iter()
+                .filter(|e| e.event_type == "m.space.parent")
+                .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+                .find_map(|e| e.state_key.clone())
+                .filter(|k| !k.is_empty() && *k != room_id);
+            match parent {
+                Some(parent) => {
+                    server = Some(parent.clone());
+                    current = parent;
+                }
+                None => break,
+            }
+        }
+        server
+    }
+
+    /// when the user's timeout in the room's server runs out (ms since epoch) —
+    /// None when they aren't timed out there, or it's already over at `now`
+    pub async fn timed_out_until(&mut self, room_id: &str, now: i64) -> Option<i64> {
+        let server_id = self.server_of(room_id).await.unwrap_or_else(|| room_id.to_string());
+        let user_id = self.user_id.clone();
+        self.state(&server_id)
+            .await
+            .iter()
+            .find(|e| e.event_type == TIMEOUT_EVENT_TYPE && e.state_key.as_deref() == Some(user_id.as_str()))
+            .and_then(|e| e.content["until"].as_i64())
+            .filter(|until| *until > now)
+    }
+
+    /// whether `room_id` is a forum thread that's been locked
+    pub async fn is_locked_thread(&mut self, room_id: &str) -> bool {
+        let state = self.state(room_id).await;
+        let content = |event_type: &str| {
+            state.iter().find(|e| e.event_type == event_type && e.state_key.as_deref() == Some("")).map(|e| &e.content)
+        };
+        content("agora.room.type").is_some_and(|c| c["type"] == "thread")
+            && content("agora.thread.meta").is_some_and(|c| c["locked"] == true)
+    }
+
+    pub async fn for_room(&mut self, room_id: &str) -> MessagePolicy {
+        let user_id = self.user_id.clone();
+
+        let room_state = self.state(room_id).await;
+        let slowmode = slowmode_seconds(room_state);
+        let power = room_state
+            .iter()
+            .find(|e| e.event_type == "m.room.power_levels")
+            .map(|e| e.content.clone())
+            .unwrap_or_default();
+        let level = power["users"][&user_id]
+            .as_i64()
+            .or_else(|| power["users_default"].as_i64())
+            .unwrap_or(0);
+        let redact_level = power["redact"].as_i64().unwrap_or(DEFAULT_REDACT_LEVEL);
+        let mut is_moderator = level >= redact_level;
+        let room_notification_level = power["notifications"]["room"]
+            .as_i64()
+            .unwrap_or(DEFAULT_ROOM_NOTIFICATION_LEVEL);
+        let mut can_mention_everyone = level >= room_notification_level;
+
+        let mut settings = ServerSettings::default();
+        if let Some(server_id) = self.server_of(room_id).await {
+            let server_state = self.state(&server_id).await;
+            let find = |event_type: &str, state_key: &str| {
+                server_state
+                    .iter()
+                    .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(state_key))
+                    .map(|e| e.content.clone())
+            };
+
+            if let Some(content) = find("agora.server.settings", "") {
+                settings = serde_json::from_value(content).unwrap_or_default();
+            }
+
+            let role_ids: Vec<String> = find("agora.member.roles", &user_id)
+                .and_then(|c| serde_json::from_value(c["role_ids"].clone()).ok())
+                .unwrap_or_default();
+            let roles = find("agora.roles", "").unwrap_or_default();
+            let has_permission = |permission: &str| {
+                roles["roles"].as_array().into_iter().flatten().any(|role| {
+                    role["id"].as_str().is_some_and(|id| role_ids.iter().any(|r| r == id))
+                        && (role["permissions"][permission].as_bool() == Some(true)
+                            || role["permissions"]["administrator"].as_bool() == Some(true))
+                })
+            };
+            is_moderator |= has_permission("manage_messages");
+            can_mention_everyone |= has_permission("mention_everyone");
+        }
+
+        let slowmode_seconds = if is_moderator { 0 } else { slowmode };
+        MessagePolicy { user_id, settings, is_moderator, can_mention_everyone, slowmode_seconds }
+    }
+}
@@ -1 +1,50 @@
+pub mod authz;
+pub mod channel_access;
 pub mod client;
+pub mod hierarchy;
+pub mod message_policy;
+pub mod retry;
+pub mod revision;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// everything but the unreserved characters — ids can hold `#`, `/`, `?`, `+`, spaces and unicode
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// encode a matrix id (or any value) for use as one path segment of a homeserver url.
+/// input that is already percent-encoded is decoded first, so encoding twice is harmless.
+pub fn encode_path_segment(segment: &str) -> String {
+    let decoded = percent_decode_str(segment).decode_utf8_lossy();
+    utf8_percent_encode(&decoded, PATH_SEGMENT).to_string()
+}
+
+/// a homeserver url path with its ids swapped for placeholders, for spans:
+/// `/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{txn_id}`
+pub fn endpoint_template(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').collect();
+    let placeholder = |i: usize| -> Option<&'static str> {
+        match percent_decode_str(segments[i]).decode_utf8_lossy().chars().next() {
+            Some('!') => return Some("{room_id}"),
+            Some('@') => return Some("{user_id}"),
+            Some('#') => return Some("{room_alias}"),
+            Some('$') => return Some("{event_id}"),
+            _ => {}
+        }
+        let before = |n: usize| i.checked_sub(n).map(|j| segments[j]);
+        match (before(2), before(1)) {
+            (Some("send" | "redact"), _) => Some("{txn_id}"),
+            (Some("state"), _) => Some("{state_key}"),
+            (Some("download" | "thumbnail"), _) => Some("{media_id}"),
+            (_, Some("download" | "thumbnail")) => Some("{server_name}"),
+            (_, Some("devices")) => Some("{device_id}"),
+            (_, Some("tags")) => Some("{tag}"),
+            _ => None,
+        }
+    };
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| placeholder(i).unwrap_or(*segment))
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,144 @@
+// retry.rs — retrying homeserver requests that failed for reasons that pass:
+// rate limits (429 / M_LIMIT_EXCEEDED), dropped connections and gateway errors.
+// a rate-limited request was never processed, so any method is retried. other
+// failures are only retried for idempotent methods — a POST /createRoom or
+// /join that timed out may well have gone through.
+// every request, retries and all, runs in a `matrix` span under the api
+// request that made it.
+
+use reqwest::{Client, Method, Request, RequestBuilder, Response, StatusCode};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use super::client::MatrixError;
+use super::endpoint_template;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 250;
+const DEFAULT_MAX_DELAY_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// attempts after the first one — 0 turns retrying off
+    pub max_retries: u32,
+    /// the first backoff step, doubled on every retry
+    pub base_delay: Duration,
+    /// cap on any single wait, including one the homeserver asked for
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_MAX_DELAY_MS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// MATRIX_RETRY_MAX, MATRIX_RETRY_BASE_MS and MATRIX_RETRY_MAX_DELAY_MS, read once
+    pub fn from_env() -> Self {
+        static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+        *POLICY.get_or_init(|| {
+            let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+            let defaults = Self::default();
+            Self {
+                max_retries: var("MATRIX_RETRY_MAX").map(|n| n as u32).unwrap_or(defaults.max_retries),
+                base_delay: var("MATRIX_RETRY_BASE_MS").map(Duration::from_millis).unwrap_or(defaults.base_delay),
+                max_delay: var("MATRIX_RETRY_MAX_DELAY_MS").map(Duration::from_millis).unwrap_or(defaults.max_delay),
+            }
+        })
+    }
+
+    /// exponential backoff before retry number `retry` (0-based)
+    fn backoff(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(1 << retry.min(16)).min(self.max_delay)
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS)
+}
+
+pub(super) trait SendWithRetry {
+    /// `send()`, retried under `policy`. the last attempt's response is returned
+    /// untouched, so callers still see the error body when retries run out
+    fn send_with_retry(self, policy: RetryPolicy) -> impl Future<Output = Result<Response, MatrixError>> + Send;
+}
+
+impl SendWithRetry for RequestBuilder {
+    async fn send_with_retry(self, policy: RetryPolicy) -> Result<Response, MatrixError> {
+        let (client, request) = self.build_split();
+        let request = request?;
+        // the endpoint, not the url — queries carry sync tokens and what users typed
+        let span = tracing::info_span!(
+            "matrix",
+            method = %request.method(),
+            endpoint = %endpoint_template(request.url().path()),
+            status = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+
+        let started = Instant::now();
+        let result = send(client, request, policy).instrument(span.clone()).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(response) => {
+                span.record("status", response.status().as_u16());
+                span.record("latency_ms", latency_ms);
+                span.in_scope(|| tracing::debug!("answered"));
+            }
+            Err(e) => {
+                span.record("latency_ms", latency_ms);
+                span.in_scope(|| tracing::debug!("failed: {}", e));
+            }
+        }
+        result
+    }
+}
+
+/// one request through its retries under `policy`
+async fn send(client: Client, request: Request, policy: RetryPolicy) -> Result<Response, MatrixError> {
+    let idempotent = is_idempotent(request.method());
+
+    let mut retry = 0;
+    loop {
+        // streaming bodies can't be cloned — they get a single attempt
+        let attempt = match request.try_clone() {
+            Some(attempt) if retry < policy.max_retries => attempt,
+            _ => return Ok(client.execute(request).await?),
+        };
+
+        let wait = match client.execute(attempt).await {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                let err = MatrixError::from_response(response).await;
+                err.retry_after_ms()
+                    .map(Duration::from_millis)
+                    .unwrap_or_else(|| policy.backoff(retry))
+                    .min(policy.max_delay)
+            }
+            Ok(response)
+                if idempotent
+                    && matches!(
+                        response.status(),
+                        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+                    ) =>
+            {
+                policy.backoff(retry)
+            }
+            Ok(response) => return Ok(response),
+            // nothing reached the homeserver when the connection failed
+            Err(e) if e.is_connect() || (idempotent && (e.is_timeout() || e.is_request())) => {
+                policy.backoff(retry)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        retry += 1;
+        tracing::debug!("failed transiently, retry {}/{} in {:?}", retry, policy.max_retries, wait);
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,108 @@
+// revision.rs — optimistic concurrency for whole-document state events
+// events like agora.roles are replaced wholesale on every save, so two editors
+// would silently overwrite each other. each such event carries a `revision`
+// integer in its content; a write states the revision it was based on and is
+// refused with the current content when someone else got there first.
+// matrix has no compare-and-swap on state, so writes for the same event are
+// also serialized within this process to close the read→write window.
+
+use dashmap::DashMap;
+use std::sync::{Arc, OnceLock};
+use super::client::{MatrixClient, MatrixError};
+use super::encode_path_segment;
+
+pub enum CasError {
+    /// the event moved on since the client read it — carries what's there now
+    Conflict { revision: u64, current: serde_json::Value },
+    Matrix(MatrixError),
+}
+
+fn write_lock(key: String) -> Arc<tokio::sync::Mutex<()>> {
+    static LOCKS: OnceLock<DashMap<String, Arc<tokio::sync::Mutex<()>>>> = OnceLock::new();
+    LOCKS.get_or_init(DashMap::new).entry(key).or_default().clone()
+}
+
+fn revision_of(content: &serde_json::Value) -> u64 {
+    content.get("revision").and_then(|v| v.as_u64()).unwrap_or(0)
+}
+
+/// read a revisioned state event. a missing event reads as empty content at revision 0.
+pub async fn read(
+    matrix: &MatrixClient,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+) -> Result<(serde_json::Value, u64), MatrixError> {
+    let url = format!(
+        "{}/rooms/{}/state/{}/{}",
+        matrix.client_api_base().await,
+        encode_path_segment(room_id),
+        event_type,
+        encode_path_segment(state_key)
+    );
+    match matrix.get_raw(&url).await {
+        Ok(content) => {
+            let revision = revision_of(&content);
+            Ok((content, revision))
+        }
+        Err(e) if e.is_not_found() => {
+            Ok((serde_json::json!({}), 0))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// write `content` if the event is still at `expected` (or unconditionally with `force`),
+/// stamping it with the next revision. returns the new revision.
+pub async fn write(
+    matrix: &MatrixClient,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+    expected: Option<u64>,
+    force: bool,
+    mut content: serde_json::Value,
+) -> Result<u64, CasError> {
+    let lock = write_lock(format!("{}|{}|{}", room_id, event_type, state_key));
+    let _guard = lock.lock().await;
+
+    let (current, revision) = read(matrix, room_id, event_type, state_key)
+        .await
+        .map_err(CasError::Matrix)?;
+
+    // a write that doesn't say what it was based on can't be checked — treat it as stale
+    if !force && expected != Some(revision) {
+        return Err(CasError::Conflict { revision, current });
+    }
+
+    let next = revision + 1;
+    content["revision"] = serde_json::json!(next);
+    matrix
+        .send_state_event(room_id.to_string(), event_type.to_string(), state_key.to_string(), content)
+        .await
+        .map_err(CasError::Matrix)?;
+    Ok(next)
+}
+
+/// read-modify-write for server-side edits that don't come from a client's copy
+/// (e.g. appending to a list). runs under the same lock as `write` and bumps the revision,
+/// so a client holding the old revision gets a conflict instead of undoing the edit.
+pub async fn update(
+    matrix: &MatrixClient,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+    edit: impl FnOnce(&mut serde_json::Value),
+) -> Result<u64, MatrixError> {
+    let lock = write_lock(format!("{}|{}|{}", room_id, event_type, state_key));
+    let _guard = lock.lock().await;
+
+    let (mut content, revision) = read(matrix, room_id, event_type, state_key).await?;
+    edit(&mut content);
+    let next = revision + 1;
+    content["revision"] = serde_json::json!(next);
+    matrix
+        .send_state_event(room_id.to_string(), event_type.to_string(), state_key.to_string(), content)
+        .await?;
+    Ok(next)
+}
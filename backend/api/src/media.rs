@@ -0,0 +1,78 @@
+// media.rs — homeserver upload limits
+// conduit advertises its max upload size at /_matrix/media/{v3,r0}/config. we cache
+// it so every upload can be checked before any bytes are sent to the homeserver,
+// and so the frontend can read it from /health/features to gate its file picker.
+// the config endpoint needs an access token, so the cache is filled by whichever
+// request first needs it.
+
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use crate::matrix::client::MatrixClient;
+
+// default for MEDIA_CONFIG_TTL_SECS
+const DEFAULT_CONFIG_TTL_SECS: u64 = 600;
+
+/// the homeserver's advertised upload limit, refreshed every MEDIA_CONFIG_TTL_SECS
+pub struct MediaLimitCache {
+    ttl: Duration,
+    /// (limit, when it was fetched) — the limit is None if the homeserver doesn't set one
+    entry: RwLock<Option<(Option<u64>, Instant)>>,
+}
+
+impl Default for MediaLimitCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MediaLimitCache {
+    pub fn new() -> Self {
+        let ttl_secs = std::env::var("MEDIA_CONFIG_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CONFIG_TTL_SECS);
+        Self::with_ttl(Duration::from_secs(ttl_secs))
+    }
+
+    /// a cache that refetches after `ttl`, whatever MEDIA_CONFIG_TTL_SECS says
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self { ttl, entry: RwLock::new(None) }
+    }
+
+    /// the last known limit without touching the homeserver (may be stale or unknown)
+    pub fn cached(&self) -> Option<u64> {
+        self.entry
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .and_then(|(limit, _)| limit)
+    }
+
+    /// the current limit, refetched when the cached copy has expired. if the
+    /// homeserver can't be asked the stale value is kept — a missing limit
+    /// just means uploads aren't pre-checked.
+    pub async fn get(&self, matrix: &MatrixClient) -> Option<u64> {
+        let fresh = *self.entry.read().unwrap_or_else(|e| e.into_inner());
+        if let Some((limit, fetched_at)) = fresh {
+            if fetched_at.elapsed() < self.ttl {
+                return limit;
+            }
+        }
+
+        match matrix.get_media_config().await {
+            Ok(config) => {
+                *self.entry.write().unwrap_or_else(|e| e.into_inner()) = Some((config.upload_size, Instant::now()));
+                config.upload_size
+            }
+            Err(e) => {
+                tracing::debug!("failed to fetch media config: {}", e);
+                self.cached()
+            }
+        }
+    }
+
+    /// forget the cached limit so the next upload refetches it — used when the
+    /// homeserver rejects something we thought was small enough
+    pub fn invalidate(&self) {
+        *self.entry.write().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
@@ -0,0 +1,157 @@
+// notification_levels.rs — per-room notification levels: all, mentions or muted
+// each is kept in the user's matrix account data under
+// agora.notifications.room.{room_id}, for other clients, and in postgres
+// (room_notification_levels), which is what the api reads: the push fan-out
+// has no token of the recipient's to read their account data with.
+//
+// a level set on a space covers the rooms under it, down through categories,
+// unless a room has its own. one with an `until` (unix ms) stops applying once
+// that's passed — nothing clears it, it's just not read any more.
+
+use serde::Serialize;
+use sqlx::Row;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+use crate::app_state::AppState;
+use crate::matrix::client::MatrixClient;
+use crate::matrix::hierarchy::MAX_SPACE_NESTING;
+use crate::room_summaries;
+
+pub const ACCOUNT_DATA_PREFIX: &str = "agora.notifications.room.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    All,
+    /// only messages that mention the user notify
+    Mentions,
+    Muted,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "all" => Some(Self::All),
+            "mentions" => Some(Self::Mentions),
+            "muted" => Some(Self::Muted),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Mentions => "mentions",
+            Self::Muted => "muted",
+        }
+    }
+
+    /// whether a message notifies at this level
+    pub fn notifies(&self, mentions_me: bool) -> bool {
+        match self {
+            Self::All => true,
+            Self::Mentions => mentions_me,
+            Self::Muted => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct RoomLevel {
+    /// "all" | "mentions" | "muted"
+    pub level: String,
+    /// unix ms when it stops applying
+    pub until: Option<i64>,
+}
+
+/// a user's levels that still apply at `now`, by room id
+pub async fn for_user(pool: &sqlx::PgPool, user_id: &str, now: i64) -> Result<HashMap<String, RoomLevel>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT room_id, level, until_ts FROM room_notification_levels
+        WHERE user_id = $1 AND (until_ts IS NULL OR until_ts > $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| (r.get("room_id"), RoomLevel { level: r.get("level"), until: r.get("until_ts") }))
+        .collect())
+}
+
+/// the levels sync and the push fan-out go by. empty without a database, or
+/// when the lookup fails — everything notifies then
+#[derive(Debug, Clone, Default)]
+pub struct NotificationLevels {
+    rooms: HashMap<String, Level>,
+}
+
+impl NotificationLevels {
+    pub async fn load(state: &AppState, user_id: &str) -> Self {
+        let Some(pool) = state.db_pool.as_ref() else {
+            return Self::default();
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        match for_user(pool, user_id, now).await {
+            Ok(levels) => Self {
+                rooms: levels
+                    .into_iter()
+                    .filter_map(|(room_id, l)| Level::parse(&l.level).map(|level| (room_id, level)))
+                    .collect(),
+            },
+            Err(e) => {
+                tracing::warn!("failed to load notification levels for {}: {}", user_id, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// the level of each of `room_ids`: its own, else the nearest space's
+    /// above it. parents come from the cached room summaries, read a level at a
+    /// time, and only when there's a level set somewhere to inherit
+    pub async fn resolve(&self, state: &AppState, matrix: &MatrixClient, room_ids: &[String]) -> HashMap<String, Level> {
+        if self.rooms.is_empty() {
+            return room_ids.iter().map(|room_id| (room_id.clone(), Level::All)).collect();
+        }
+        let mut parents: HashMap<String, Option<String>> = HashMap::new();
+        let mut pending: Vec<String> = room_ids.to_vec();
+        // channel → category → server
+        for _ in 0..=MAX_SPACE_NESTING {
+            pending.sort();
+            pending.dedup();
+            pending.retain(|room_id| !self.rooms.contains_key(room_id) && !parents.contains_key(room_id));
+            if pending.is_empty() {
+                break;
+            }
+            let summaries = room_summaries::joined(state, matrix, &pending).await;
+            let mut next = Vec::new();
+            for (room_id, summary) in pending.drain(..).zip(summaries) {
+                let parent_id = summary.and_then(|s| s.parent_id);
+                next.extend(parent_id.clone());
+                parents.insert(room_id, parent_id);
+            }
+            pending = next;
+        }
+
+        room_ids
+            .iter()
+            .map(|room_id| {
+                let mut current = room_id;
+                let mut level = Level::All;
+                for _ in 0..=MAX_SPACE_NESTING + 1 {
+                    if let Some(own) = self.rooms.get(current) {
+                        level = *own;
+                        break;
+                    }
+                    match parents.get(current) {
+                        Some(Some(parent_id)) => current = parent_id,
+                        _ => break,
+                    }
+                }
+                (room_id.clone(), level)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,95 @@
+// openapi.rs — the machine-readable contract, for the frontend and bot authors.
+// handlers carry #[utoipa::path] and each routes module lists its handlers in
+// an ApiDoc; the spec here merges those in the order lib.rs merges routers, so
+// a new route module goes in both places (tests/routes.rs notices when it
+// doesn't). served at /openapi.json, with swagger ui at /docs when API_DOCS=true.
+
+use axum::{routing::get, Json, Router};
+use std::sync::{Arc, OnceLock};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::app_state::AppState;
+use crate::routes;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "agora api",
+        description = "send the access token from /login or /register as `Authorization: Bearer <token>`. \
+                       errors carry a matrix-style `{errcode, error}` body where the client needs to tell them apart."
+    ),
+    paths(openapi_json),
+    components(schemas(routes::ErrorBody)),
+    modifiers(&BearerAuth)
+)]
+struct RootDoc;
+
+/// the "bearer" scheme the handlers' security(...) refers to
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// the whole spec
+pub fn spec() -> utoipa::openapi::OpenApi {
+    let mut spec = RootDoc::openapi();
+    for doc in [
+        routes::health::ApiDoc::openapi(),
+        routes::auth::ApiDoc::openapi(),
+        routes::admin::ApiDoc::openapi(),
+        routes::rooms::ApiDoc::openapi(),
+        routes::announcements::ApiDoc::openapi(),
+        routes::sync::ApiDoc::openapi(),
+        routes::friends::ApiDoc::openapi(),
+        routes::dms::ApiDoc::openapi(),
+        routes::users::ApiDoc::openapi(),
+        routes::presence_ws::ApiDoc::openapi(),
+        routes::events_ws::ApiDoc::openapi(),
+        routes::voice::ApiDoc::openapi(),
+        routes::servers::ApiDoc::openapi(),
+        routes::reports::ApiDoc::openapi(),
+        routes::server_events::ApiDoc::openapi(),
+        routes::server_templates::ApiDoc::openapi(),
+        routes::webhooks::ApiDoc::openapi(),
+        routes::email::ApiDoc::openapi(),
+        routes::notifications::ApiDoc::openapi(),
+        routes::media::ApiDoc::openapi(),
+        routes::preview::ApiDoc::openapi(),
+        routes::search::ApiDoc::openapi(),
+    ] {
+        spec.merge(doc);
+    }
+    spec
+}
+
+/// /openapi.json, plus swagger ui at /docs when API_DOCS=true — off by
+/// default, it's for poking at a dev deployment
+pub fn router() -> Router<Arc<AppState>> {
+    let router = Router::new().route("/openapi.json", get(openapi_json));
+    if std::env::var("API_DOCS").is_ok_and(|v| v == "true") {
+        router.merge(SwaggerUi::new("/docs").config(utoipa_swagger_ui::Config::from("/openapi.json")))
+    } else {
+        router
+    }
+}
+
+/// this document
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "meta",
+    responses((status = 200, body = Object, description = "an OpenAPI 3.1 document"))
+)]
+async fn openapi_json() -> Json<&'static utoipa::openapi::OpenApi> {
+    // built once — the annotations can't change while the process runs
+    static SPEC: OnceLock<utoipa::openapi::OpenApi> = OnceLock::new();
+    Json(SPEC.get_or_init(spec))
+}
@@ -0,0 +1,197 @@
+// pagination.rs — the shared pagination contract for list endpoints
+// every paginated endpoint takes `limit` + `after` (an opaque cursor) and answers
+// with Paginated<T>. cursors are url-safe base64 of a small json position object,
+// always keyed on the endpoint's sort key rather than an offset so concurrent
+// inserts don't shift pages. endpoints keep their legacy un-paginated response
+// when no `limit` is supplied (for one release), so each handler picks the
+// response shape with `PageParams::is_paginated`.
+
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// default page size when a client passes a limit of 0
+pub const DEFAULT_LIMIT: usize = 50;
+/// hard cap so a single request can't ask for the whole table
+pub const MAX_LIMIT: usize = 200;
+// cursors are tiny — anything bigger than this is garbage or an attack
+const MAX_CURSOR_LEN: usize = 1024;
+
+#[derive(Debug, Serialize)]
+pub struct Paginated<T: Serialize> {
+    pub items: Vec<T>,
+    /// pass back as `after` to get the next page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// total number of items, only when it's cheap to compute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// the pagination params — endpoints declare `limit` / `after` on their own query
+/// struct (serde flatten doesn't play well with query strings) and build this from them
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct PageParams {
+    pub limit: Option<usize>,
+    pub after: Option<String>,
+}
+
+impl PageParams {
+    /// true when the caller opted into pagination
+    pub fn is_paginated(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// the effective page size, clamped to 1..=MAX_LIMIT
+    pub fn limit(&self) -> usize {
+        match self.limit {
+            Some(0) | None => DEFAULT_LIMIT,
+            Some(n) => n.min(MAX_LIMIT),
+        }
+    }
+
+    /// decode the `after` cursor into the endpoint's position type.
+    /// a malformed or tampered cursor is a 400, never a panic.
+    pub fn cursor<C: DeserializeOwned>(&self) -> Result<Option<C>, StatusCode> {
+        match self.after.as_deref() {
+            None | Some("") => Ok(None),
+            Some(raw) => decode_cursor(raw).map(Some).ok_or(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+pub fn encode_cursor<C: Serialize>(position: &C) -> String {
+    let json = serde_json::to_vec(position).unwrap_or_default();
+    URL_SAFE_NO_PAD.encode(json)
+}
+
+pub fn decode_cursor<C: DeserializeOwned>(raw: &str) -> Option<C> {
+    if raw.len() > MAX_CURSOR_LEN {
+        return None;
+    }
+    let bytes = URL_SAFE_NO_PAD.decode(raw.trim_end_matches('=')).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// page through an already-sorted in-memory list. `key` builds the cursor for an item
+/// and `cmp` orders an item against a decoded cursor in the list's sort order, so the
+/// page resumes right after the cursor position even if items were inserted or
+/// removed since the previous page was served.
+pub fn paginate_sorted<T, C, K, O>(
+    items: Vec<T>,
+    params: &PageParams,
+    key: K,
+    cmp: O,
+) -> Result<Paginated<T>, StatusCode>
+where
+    T: Serialize,
+    C: Serialize + DeserializeOwned,
+    K: Fn(&T) -> C,
+    O: Fn(&T, &C) -> std::cmp::Ordering,
+{
+    let total = items.len() as u64;
+    let limit = params.limit();
+
+    let start = match params.cursor::<C>()? {
+        Some(position) => items
+            .iter()
+            .position(|item| cmp(item, &position) == std::cmp::Ordering::Greater)
+            .unwrap_or(items.len()),
+        None => 0,
+    };
+
+    let mut page: Vec<T> = items.into_iter().skip(start).take(limit + 1).collect();
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|last| encode_cursor(&key(last)))
+    } else {
+        None
+    };
+
+    Ok(Paginated { items: page, next_cursor, total: Some(total) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Position {
+        name: String,
+        id: u64,
+    }
+
+    fn page(limit: Option<usize>, after: Option<String>) -> PageParams {
+        PageParams { limit, after }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let position = Position { name: "zoë & co".to_string(), id: 42 };
+        let cursor = encode_cursor(&position);
+        assert!(cursor.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(decode_cursor::<Position>(&cursor), Some(position));
+        // a client that pads it anyway is fine
+        assert!(decode_cursor::<Position>(&format!("{}==", cursor)).is_some());
+    }
+
+    #[test]
+    fn tampered_cursors_are_refused() {
+        let cursor = encode_cursor(&Position { name: "a".to_string(), id: 1 });
+        let mut flipped = cursor.clone().into_bytes();
+        flipped[3] = if flipped[3] == b'A' { b'B' } else { b'A' };
+        for raw in [
+            "not a cursor!".to_string(),
+            String::from_utf8(flipped).unwrap(),
+            cursor[..cursor.len() - 4].to_string(),
+            URL_SAFE_NO_PAD.encode("not json"),
+            encode_cursor(&serde_json::json!({ "name": 1 })),
+        ] {
+            assert_eq!(decode_cursor::<Position>(&raw), None, "{}", raw);
+            assert_eq!(page(Some(10), Some(raw)).cursor::<Position>(), Err(StatusCode::BAD_REQUEST));
+        }
+        assert_eq!(page(Some(10), Some(String::new())).cursor::<Position>(), Ok(None));
+    }
+
+    #[test]
+    fn oversized_cursors_are_refused() {
+        let big = Position { name: "x".repeat(MAX_CURSOR_LEN), id: 1 };
+        let cursor = encode_cursor(&big);
+        assert!(cursor.len() > MAX_CURSOR_LEN);
+        assert_eq!(decode_cursor::<Position>(&cursor), None);
+        assert_eq!(page(Some(10), Some(cursor)).cursor::<Position>(), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn limits_are_clamped() {
+        assert!(!page(None, None).is_paginated());
+        assert_eq!(page(None, None).limit(), DEFAULT_LIMIT);
+        assert_eq!(page(Some(0), None).limit(), DEFAULT_LIMIT);
+        assert_eq!(page(Some(7), None).limit(), 7);
+        assert_eq!(page(Some(MAX_LIMIT + 1), None).limit(), MAX_LIMIT);
+        assert_eq!(page(Some(usize::MAX), None).limit(), MAX_LIMIT);
+    }
+
+    fn by_id(items: Vec<u64>, params: &PageParams) -> Result<Paginated<u64>, StatusCode> {
+        paginate_sorted(items, params, |id| *id, |id, after: &u64| id.cmp(after))
+    }
+
+    #[test]
+    fn sorted_lists_page_from_the_cursor() {
+        let ids: Vec<u64> = (1..=5).map(|i| i * 10).collect();
+        let first = by_id(ids.clone(), &page(Some(2), None)).unwrap();
+        assert_eq!((first.items.as_slice(), first.total), ([10, 20].as_slice(), Some(5)));
+        let second = by_id(ids.clone(), &page(Some(2), first.next_cursor)).unwrap();
+        assert_eq!(second.items, [30, 40]);
+        let last = by_id(ids.clone(), &page(Some(2), second.next_cursor.clone())).unwrap();
+        assert_eq!(last.items, [50]);
+        assert_eq!(last.next_cursor, None);
+
+        // the item the cursor points at went away: carry on after where it was
+        let without_40: Vec<u64> = ids.iter().copied().filter(|id| *id != 40).collect();
+        assert_eq!(by_id(without_40, &page(Some(2), second.next_cursor)).unwrap().items, [50]);
+        // an exact fit has no next page
+        assert_eq!(by_id(ids.clone(), &page(Some(5), None)).unwrap().next_cursor, None);
+        assert_eq!(by_id(ids, &page(Some(2), Some("garbage".to_string()))).err(), Some(StatusCode::BAD_REQUEST));
+    }
+}
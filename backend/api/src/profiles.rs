@@ -0,0 +1,81 @@
+// profiles.rs — display names for lists of users
+// the sidebar shows names for everyone in every voice channel and polls every
+// few seconds, so names are cached per process instead of asking the
+// homeserver for each user on each poll. misses are fetched concurrently.
+
+use dashmap::DashMap;
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::matrix::client::MatrixClient;
+
+// default for PROFILE_CACHE_TTL_SECS
+const DEFAULT_PROFILE_TTL_SECS: u64 = 300;
+
+/// user id → display name, each refreshed every PROFILE_CACHE_TTL_SECS
+pub struct ProfileCache {
+    ttl: Duration,
+    /// the name is None for a user without one
+    entries: DashMap<String, (Option<String>, Instant)>,
+}
+
+impl Default for ProfileCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProfileCache {
+    pub fn new() -> Self {
+        let ttl_secs = std::env::var("PROFILE_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_PROFILE_TTL_SECS);
+        Self {
+            ttl: Duration::from_secs(ttl_secs),
+            entries: DashMap::new(),
+        }
+    }
+
+    fn fresh(&self, user_id: &str) -> Option<Option<String>> {
+        let entry = self.entries.get(user_id)?;
+        let (name, fetched_at) = entry.value();
+        (fetched_at.elapsed() < self.ttl).then(|| name.clone())
+    }
+
+    /// display names for `user_ids`, fetching only the ones not cached. a user
+    /// without a display name is left out of the map.
+    pub async fn display_names(&self, matrix: &MatrixClient, user_ids: &[String]) -> HashMap<String, String> {
+        let mut names = HashMap::new();
+        let mut missing = Vec::new();
+        for user_id in user_ids {
+            match self.fresh(user_id) {
+                Some(Some(name)) => {
+                    names.insert(user_id.clone(), name);
+                }
+                Some(None) => {}
+                None if !missing.contains(user_id) => missing.push(user_id.clone()),
+                None => {}
+            }
+        }
+
+        let fetched = join_all(missing.iter().map(|user_id| matrix.get_profile(user_id.clone()))).await;
+        for (user_id, profile) in missing.into_iter().zip(fetched) {
+            // a failed lookup isn't cached, so the next poll tries again — but a
+            // user without a profile (not from this homeserver, say) is an answer
+            let name = match profile {
+                Ok(profile) => profile.displayname.filter(|n| !n.is_empty()),
+                Err(e) if e.is_not_found() => None,
+                Err(e) => {
+                    tracing::debug!("failed to fetch profile of {}: {}", user_id, e);
+                    continue;
+                }
+            };
+            self.entries.insert(user_id.clone(), (name.clone(), Instant::now()));
+            if let Some(name) = name {
+                names.insert(user_id, name);
+            }
+        }
+        names
+    }
+}
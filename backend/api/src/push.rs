@@ -0,0 +1,347 @@
+// push.rs — web push for DMs and mentions, to the browsers a user subscribed
+// everything here is a no-op unless VAPID_PRIVATE_KEY / VAPID_PUBLIC_KEY are set
+// and the database is up. subscriptions live in postgres (push_subscriptions).
+//
+// pushes go out from the service account's sync, so a message sent from any
+// matrix client counts, not just the ones sent through the api. that covers
+// the rooms the account is in, which is every DM and channel the api makes
+// (service_account::bring_in). the recipient's notification levels apply.
+// delivery runs on tasks of its own, so a slow push service holds up its own
+// retries and not the sync. a subscription the push service answers 404 / 410
+// for is gone for good and gets deleted.
+
+use axum::async_trait;
+use redis::AsyncCommands;
+use serde::Serialize;
+use sqlx::Row;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use web_push::{
+    ContentEncoding, IsahcWebPushClient, SubscriptionInfo, VapidSignatureBuilder, WebPushClient,
+    WebPushError, WebPushMessageBuilder,
+};
+use crate::app_state::AppState;
+use crate::matrix::client::{MatrixClient, SyncResponse};
+use crate::notification_levels::NotificationLevels;
+use crate::service_account::SyncObserver;
+
+// trimmed so the encrypted payload stays well inside the push services' 4KB
+const SNIPPET_MAX_CHARS: usize = 100;
+const ROOM_NAME_MAX_CHARS: usize = 64;
+// how long the push service holds a push for a browser that isn't reachable
+const PUSH_TTL_SECS: u32 = 24 * 3600;
+// a push that fails for any other reason is tried this many times in all
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+// messages older than this when the sync brings them aren't pushed
+const MAX_MESSAGE_AGE_MS: i64 = 5 * 60 * 1000;
+
+/// what the service worker gets — enough to show the notification without
+/// calling back into the api
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PushPayload {
+    /// "dm" | "mention" | "test"
+    pub kind: String,
+    pub sender: String,
+    pub room_id: String,
+    pub room_name: Option<String>,
+    pub snippet: String,
+    pub event_id: Option<String>,
+}
+
+impl PushPayload {
+    pub fn new(kind: &str, sender: &str, room_id: &str, room_name: Option<String>, body: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            sender: sender.to_string(),
+            room_id: room_id.to_string(),
+            room_name: room_name.map(|name| trim(&name, ROOM_NAME_MAX_CHARS)),
+            snippet: trim(body, SNIPPET_MAX_CHARS),
+            event_id: None,
+        }
+    }
+}
+
+fn trim(text: &str, max_chars: usize) -> String {
+    let mut s: String = text.chars().take(max_chars).collect();
+    if text.chars().count() > max_chars {
+        s.push('…');
+    }
+    s
+}
+
+/// a browser's push subscription, as stored
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub id: i64,
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// the push service says the subscription no longer exists (404 / 410)
+    Gone,
+    Failed(String),
+}
+
+/// the transport pushes go out on — the push services in production, a stub in tests
+#[derive(Clone)]
+pub enum PushTransport {
+    WebPush(Arc<IsahcWebPushClient>),
+    Stub(StubPushes),
+}
+
+/// pushes recorded instead of sent, as (endpoint, payload). endpoints in
+/// `gone` answer like a push service that has dropped the subscription
+#[derive(Clone, Default)]
+pub struct StubPushes {
+    pub sent: Arc<Mutex<Vec<(String, PushPayload)>>>,
+    pub gone: Arc<Mutex<HashSet<String>>>,
+}
+
+#[derive(Clone)]
+pub struct PushConfig {
+    pub transport: PushTransport,
+    /// the application server key pair, base64url without padding. browsers
+    /// subscribe with the public half
+    pub vapid_private_key: String,
+    pub vapid_public_key: String,
+    /// who push services contact about abuse — a mailto: or https: url
+    pub subject: String,
+}
+
+impl PushConfig {
+    /// from VAPID_PRIVATE_KEY, VAPID_PUBLIC_KEY and VAPID_SUBJECT — None (push
+    /// disabled) unless both keys are set
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let vapid_private_key = var("VAPID_PRIVATE_KEY")?;
+        let Some(vapid_public_key) = var("VAPID_PUBLIC_KEY") else {
+            tracing::warn!("VAPID_PRIVATE_KEY is set without VAPID_PUBLIC_KEY. web push disabled.");
+            return None;
+        };
+        let client = match IsahcWebPushClient::new() {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("couldn't build the web push client: {}. web push disabled.", e);
+                return None;
+            }
+        };
+        Some(Self {
+            transport: PushTransport::WebPush(Arc::new(client)),
+            vapid_private_key,
+            vapid_public_key,
+            subject: var("VAPID_SUBJECT").unwrap_or_else(|| "mailto:noreply@localhost".to_string()),
+        })
+    }
+
+    /// a push config that records pushes instead of sending them
+    pub fn stub(pushes: StubPushes) -> Self {
+        Self {
+            transport: PushTransport::Stub(pushes),
+            vapid_private_key: String::new(),
+            vapid_public_key: "stub-public-key".to_string(),
+            subject: "mailto:noreply@localhost".to_string(),
+        }
+    }
+
+    pub async fn send(&self, subscription: &Subscription, payload: &PushPayload) -> Result<(), PushError> {
+        let client = match &self.transport {
+            PushTransport::WebPush(client) => client,
+            PushTransport::Stub(stub) => {
+                if stub.gone.lock().unwrap().contains(&subscription.endpoint) {
+                    return Err(PushError::Gone);
+                }
+                stub.sent.lock().unwrap().push((subscription.endpoint.clone(), payload.clone()));
+                return Ok(());
+            }
+        };
+        let failed = |e: WebPushError| PushError::Failed(e.to_string());
+
+        let body = serde_json::to_vec(payload).map_err(|e| PushError::Failed(e.to_string()))?;
+        let info = SubscriptionInfo::new(&subscription.endpoint, &subscription.p256dh, &subscription.auth);
+        let mut signature = VapidSignatureBuilder::from_base64(&self.vapid_private_key, web_push::URL_SAFE_NO_PAD, &info).map_err(failed)?;
+        signature.add_claim("sub", self.subject.as_str());
+        let mut message = WebPushMessageBuilder::new(&info);
+        message.set_payload(ContentEncoding::Aes128Gcm, &body);
+        message.set_ttl(PUSH_TTL_SECS);
+        message.set_vapid_signature(signature.build().map_err(failed)?);
+
+        client.send(message.build().map_err(failed)?).await.map_err(|e| match e {
+            WebPushError::EndpointNotValid | WebPushError::EndpointNotFound => PushError::Gone,
+            e => failed(e),
+        })
+    }
+}
+
+// ── fan-out ───────────────────────────────────────────────────────────────────
+
+/// pushes for every new message the service account's /sync brings, to each
+/// DMed or mentioned recipient who isn't using the app right now
+pub struct PushFanOut;
+
+#[async_trait]
+impl SyncObserver for PushFanOut {
+    async fn observe(&self, state: &AppState, sync: &SyncResponse) {
+        if state.push.is_none() || state.db_pool.is_none() {
+            return;
+        }
+        let Some(matrix) = state.matrix_client.read().await.clone() else { return };
+        let now = chrono::Utc::now().timestamp_millis();
+        let joined = sync.rooms.as_ref().and_then(|r| r.join.as_ref());
+        for (room_id, room) in joined.into_iter().flatten() {
+            for event in room.timeline.iter().flat_map(|t| &t.events) {
+                // a first sync replays the rooms' history, which is no news
+                let stale = event.origin_server_ts.is_some_and(|ts| now - ts > MAX_MESSAGE_AGE_MS);
+                let edit = event.content["m.relates_to"]["rel_type"] == "m.replace";
+                if event.event_type != "m.room.message" || stale || edit {
+                    continue;
+                }
+                let (Some(event_id), Some(body)) = (event.event_id.as_deref(), event.content["body"].as_str()) else {
+                    continue;
+                };
+                if let Err(e) = fan_out(state, &matrix, &event.sender, room_id, event_id, body).await {
+                    tracing::debug!("web push skipped for {}: {}", event_id, e);
+                }
+            }
+        }
+    }
+}
+
+async fn fan_out(
+    state: &AppState,
+    matrix: &MatrixClient,
+    sender: &str,
+    room_id: &str,
+    event_id: &str,
+    body: &str,
+) -> Result<(), String> {
+    let pool = state.db_pool.as_ref().ok_or("no database")?;
+    let config = state.push.as_ref().ok_or("web push is off")?;
+    let recipients = crate::email::notification_recipients(pool, matrix, room_id, sender, body).await?;
+    if recipients.is_empty() {
+        return Ok(());
+    }
+    let room_name = crate::room_summaries::joined(state, matrix, &[room_id.to_string()])
+        .await
+        .pop()
+        .flatten()
+        .and_then(|summary| summary.name);
+
+    for (recipient, kind) in recipients {
+        // a muted room sends nothing, a "mentions" one only mentions
+        let levels = NotificationLevels::load(state, &recipient).await;
+        let level = levels.resolve(state, matrix, &[room_id.to_string()]).await.remove(room_id);
+        if !level.is_none_or(|level| level.notifies(kind == "mention")) {
+            continue;
+        }
+        let exempt_only = match availability(state, &recipient).await {
+            Availability::Active => continue,
+            Availability::DoNotDisturb => true,
+            Availability::Away => false,
+        };
+        let subscriptions = subscriptions(pool, &recipient, exempt_only).await.map_err(|e| e.to_string())?;
+        if subscriptions.is_empty() {
+            continue;
+        }
+        let payload = PushPayload {
+            event_id: Some(event_id.to_string()),
+            ..PushPayload::new(kind, sender, room_id, room_name.clone(), body)
+        };
+        // off the sync worker, which search indexing shares
+        let (config, pool) = (config.clone(), pool.clone());
+        tokio::spawn(async move { deliver_with(&config, &pool, &subscriptions, &payload).await });
+    }
+    Ok(())
+}
+
+enum Availability {
+    /// has the app open — they'll see it there
+    Active,
+    /// only subscriptions marked during_dnd are pushed to
+    DoNotDisturb,
+    Away,
+}
+
+/// offline, idle and away users get pushes. without redis we can't tell, so
+/// nobody does — the same call the email capture makes
+async fn availability(state: &AppState, user_id: &str) -> Availability {
+    let Some(mut redis) = state.get_redis().await else { return Availability::Active };
+    let presence: Option<String> = redis.get(format!("presence:{}", user_id)).await.unwrap_or(None);
+    match presence.as_deref() {
+        None | Some("offline") | Some("idle") | Some("unavailable") => Availability::Away,
+        Some("dnd") => Availability::DoNotDisturb,
+        Some(_) => Availability::Active,
+    }
+}
+
+/// a user's subscriptions — with `exempt_only`, just the ones that want
+/// pushes during do not disturb
+pub async fn subscriptions(pool: &sqlx::PgPool, user_id: &str, exempt_only: bool) -> Result<Vec<Subscription>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT id, endpoint, p256dh, auth FROM push_subscriptions
+        WHERE user_id = $1 AND (during_dnd OR NOT $2)
+        ORDER BY id
+        "#,
+    )
+    .bind(user_id)
+    .bind(exempt_only)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| Subscription { id: r.get("id"), endpoint: r.get("endpoint"), p256dh: r.get("p256dh"), auth: r.get("auth") })
+        .collect())
+}
+
+/// push to each subscription, trying failed ones again after a pause and
+/// deleting the ones that are gone. returns (delivered, removed)
+pub async fn deliver(
+    state: &AppState,
+    pool: &sqlx::PgPool,
+    subscriptions: &[Subscription],
+    payload: &PushPayload,
+) -> (usize, usize) {
+    let Some(config) = state.push.as_ref() else { return (0, 0) };
+    deliver_with(config, pool, subscriptions, payload).await
+}
+
+async fn deliver_with(
+    config: &PushConfig,
+    pool: &sqlx::PgPool,
+    subscriptions: &[Subscription],
+    payload: &PushPayload,
+) -> (usize, usize) {
+    let (mut delivered, mut removed) = (0, 0);
+    for subscription in subscriptions {
+        let mut attempt = 1;
+        loop {
+            match config.send(subscription, payload).await {
+                Ok(()) => delivered += 1,
+                Err(PushError::Gone) => {
+                    let deleted = sqlx::query("DELETE FROM push_subscriptions WHERE id = $1")
+                        .bind(subscription.id)
+                        .execute(pool)
+                        .await;
+                    match deleted {
+                        Ok(_) => removed += 1,
+                        Err(e) => tracing::warn!("failed to delete expired push subscription: {}", e),
+                    }
+                }
+                Err(PushError::Failed(e)) if attempt < MAX_ATTEMPTS => {
+                    tracing::debug!("web push attempt {} failed, retrying: {}", attempt, e);
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(PushError::Failed(e)) => tracing::warn!("web push failed after {} attempts: {}", attempt, e),
+            }
+            break;
+        }
+    }
+    (delivered, removed)
+}
@@ -0,0 +1,247 @@
+// rate_limit.rs — token buckets in redis, one per (route class, caller). the
+// caller is the bearer token once it's a known session, else the client ip —
+// and always the ip for register and login, which anyone can reach with a
+// made-up token. a full bucket holds `burst` requests and refills at burst / `per` — so 5/60s lets
+// five registrations through at once, then one every twelve seconds.
+//
+// the bucket is read and written back without a lua script, so two requests
+// racing for the last token can both get it. that's fine for keeping a client
+// from hammering us. when redis is missing or failing, everything is let
+// through: a limiter that takes the api down with it is worse than none.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::app_state::AppState;
+use crate::session;
+
+/// how many requests a bucket holds, and how long it takes to fill back up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+impl Limit {
+    pub const fn new(burst: u32, per_seconds: u64) -> Self {
+        Self { burst, per: Duration::from_secs(per_seconds) }
+    }
+
+    /// "5/60" — five requests per sixty seconds
+    fn parse(value: &str) -> Option<Self> {
+        let (burst, seconds) = value.trim().split_once('/')?;
+        let limit = Self::new(burst.trim().parse().ok()?, seconds.trim().parse().ok()?);
+        (limit.burst > 0 && !limit.per.is_zero()).then_some(limit)
+    }
+}
+
+/// which bucket a request draws from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    Register,
+    Login,
+    Send,
+    Default,
+}
+
+impl RouteClass {
+    /// None for what's never limited — health checks, so monitoring can't lock itself out
+    fn of(method: &Method, path: &str) -> Option<Self> {
+        if path == "/health" || path.starts_with("/health/") {
+            return None;
+        }
+        Some(match (method, path) {
+            (&Method::POST, "/register") => Self::Register,
+            (&Method::POST, "/login") => Self::Login,
+            (&Method::POST, "/rooms/send") => Self::Send,
+            _ => Self::Default,
+        })
+    }
+
+    /// for callers without a session yet: keyed by address alone, since a
+    /// bearer header sent here is never checked
+    fn is_anonymous(self) -> bool {
+        matches!(self, Self::Register | Self::Login)
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            Self::Register => "register",
+            Self::Login => "login",
+            Self::Send => "send",
+            Self::Default => "default",
+        }
+    }
+}
+
+/// the limit for each route class. None turns a class off
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub register: Option<Limit>,
+    pub login: Option<Limit>,
+    pub send: Option<Limit>,
+    pub default: Option<Limit>,
+    /// key anonymous callers by the first X-Forwarded-For address — only
+    /// behind a proxy that sets it, since clients can send anything there
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            register: Some(Limit::new(5, 60)),
+            login: Some(Limit::new(10, 60)),
+            send: Some(Limit::new(30, 10)),
+            default: Some(Limit::new(600, 60)),
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl RateLimits {
+    /// nothing limited
+    pub fn off() -> Self {
+        Self { register: None, login: None, send: None, default: None, trust_forwarded_for: false }
+    }
+
+    /// RATE_LIMIT_REGISTER, RATE_LIMIT_LOGIN, RATE_LIMIT_SEND and RATE_LIMIT_DEFAULT
+    /// as "requests/seconds", or "off"; RATE_LIMIT_TRUST_FORWARDED=true behind a proxy
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str, default: Option<Limit>| match std::env::var(name) {
+            Ok(v) if v.trim().eq_ignore_ascii_case("off") => None,
+            Ok(v) => Limit::parse(&v).or_else(|| {
+                tracing::warn!("{}={:?} isn't requests/seconds, using the default", name, v);
+                default
+            }),
+            Err(_) => default,
+        };
+        Self {
+            register: var("RATE_LIMIT_REGISTER", defaults.register),
+            login: var("RATE_LIMIT_LOGIN", defaults.login),
+            send: var("RATE_LIMIT_SEND", defaults.send),
+            default: var("RATE_LIMIT_DEFAULT", defaults.default),
+            trust_forwarded_for: std::env::var("RATE_LIMIT_TRUST_FORWARDED").is_ok_and(|v| v == "true"),
+        }
+    }
+
+    fn for_class(&self, class: RouteClass) -> Option<Limit> {
+        match class {
+            RouteClass::Register => self.register,
+            RouteClass::Login => self.login,
+            RouteClass::Send => self.send,
+            RouteClass::Default => self.default,
+        }
+    }
+}
+
+/// the middleware: take a token from the caller's bucket or answer 429
+pub async fn limit(state: State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let Some(class) = RouteClass::of(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let Some(limit) = state.rate_limits.for_class(class) else {
+        return next.run(request).await;
+    };
+    let Some(mut redis) = state.get_redis().await else {
+        return next.run(request).await;
+    };
+
+    // taken out first: the request itself can't be held across an await
+    let bearer = bearer_token(request.headers()).filter(|_| !class.is_anonymous()).map(str::to_string);
+    let address = address(&request, state.rate_limits.trust_forwarded_for);
+    let key = format!("ratelimit:{}:{}", class.key(), caller(&state, bearer, address).await);
+    match take(&mut redis, &key, limit).await {
+        Ok(None) => next.run(request).await,
+        Ok(Some(retry_after)) => too_many_requests(retry_after),
+        Err(e) => {
+            tracing::warn!("rate limit check failed, letting the request through: {}", e);
+            state.redis.report(&e).await;
+            next.run(request).await
+        }
+    }
+}
+
+/// who the bucket belongs to: the bearer token (hashed) when the session
+/// cache already knows it, else the client's address. nothing is resolved
+/// here — an unknown token is charged to the address before the handler's
+/// whoami, so made-up tokens can't buy homeserver calls past its limit
+async fn caller(state: &AppState, bearer: Option<String>, address: String) -> String {
+    if let Some(token) = bearer {
+        if session::cached(state, &token).await.is_some() {
+            return format!("token:{:x}", Sha256::digest(token.as_bytes()));
+        }
+    }
+    format!("ip:{}", address)
+}
+
+fn address(request: &Request, trust_forwarded_for: bool) -> String {
+    let forwarded = trust_forwarded_for
+        .then(|| request.headers().get("x-forwarded-for")?.to_str().ok()?.split(',').next().map(str::trim))
+        .flatten()
+        .filter(|ip| !ip.is_empty())
+        .map(str::to_string);
+    let connected = || {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+    };
+    forwarded.or_else(connected).unwrap_or_else(|| "unknown".to_string())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let token = headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")?.trim();
+    (!token.is_empty()).then_some(token)
+}
+
+/// take one token from the bucket at `key`. Ok(None) when there was one,
+/// Ok(Some(seconds)) until there will be
+async fn take(
+    redis: &mut redis::aio::MultiplexedConnection,
+    key: &str,
+    limit: Limit,
+) -> redis::RedisResult<Option<u64>> {
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let capacity = f64::from(limit.burst);
+    let per_ms = limit.per.as_millis() as f64;
+
+    // stored as "tokens:updated_at_ms"; a missing bucket is a full one
+    let stored: Option<String> = redis.get(key).await?;
+    let (tokens, updated_ms) = stored
+        .as_deref()
+        .and_then(|v| v.split_once(':'))
+        .and_then(|(tokens, at)| Some((tokens.parse::<f64>().ok()?, at.parse::<u64>().ok()?)))
+        .unwrap_or((capacity, now_ms));
+    let refilled = now_ms.saturating_sub(updated_ms) as f64 * capacity / per_ms;
+    let tokens = (tokens + refilled).min(capacity);
+
+    if tokens < 1.0 {
+        let wait_ms = (1.0 - tokens) * per_ms / capacity;
+        return Ok(Some(((wait_ms / 1000.0).ceil() as u64).max(1)));
+    }
+    // an untouched bucket is full again after `per`, so it can go then
+    let _: () = redis.set_ex(key, format!("{}:{}", tokens - 1.0, now_ms), limit.per.as_secs().max(1)).await?;
+    Ok(None)
+}
+
+fn too_many_requests(retry_after: u64) -> Response {
+    let body = serde_json::json!({
+        "errcode": "M_LIMIT_EXCEEDED",
+        "error": "too many requests",
+        "retry_after": retry_after,
+        "retry_after_ms": retry_after * 1000,
+    });
+    let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, retry_after.into());
+    response
+}
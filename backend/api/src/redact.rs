@@ -0,0 +1,74 @@
+// secrets out of log lines. homeserver error bodies and request urls end up in
+// logs and spans; access tokens, passwords and bearer credentials in them are
+// replaced on the way.
+
+const SECRET_KEYS: [&str; 5] = ["access_token", "refresh_token", "new_password", "password", "token"];
+const REDACTED: &str = "<redacted>";
+
+/// `text` with the values of secret json fields (`"password": "..."`), query
+/// parameters (`access_token=...`) and bearer credentials replaced
+pub fn redact(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((start, end)) = next_secret(rest) {
+        redacted.push_str(&rest[..start]);
+        redacted.push_str(REDACTED);
+        rest = &rest[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// the byte range of the first secret value in `text`
+fn next_secret(text: &str) -> Option<(usize, usize)> {
+    (0..text.len())
+        .filter(|&i| text.is_char_boundary(i))
+        .find_map(|i| secret_at(text, i))
+}
+
+/// the range of a secret value whose key (or `Bearer `) starts at `i`
+fn secret_at(text: &str, i: usize) -> Option<(usize, usize)> {
+    let rest = &text[i..];
+    if let Some(credential) = rest.strip_prefix("Bearer ") {
+        let len = credential
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | ',' | '\''))
+            .unwrap_or(credential.len());
+        let start = text.len() - credential.len();
+        return (len > 0).then_some((start, start + len));
+    }
+    // whole keys only: `token` inside `access_token` is matched as the latter
+    if text[..i].chars().next_back().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let key = SECRET_KEYS.iter().find(|key| rest.starts_with(**key))?;
+    let after = &rest[key.len()..];
+
+    if let Some(value) = after.strip_prefix('=') {
+        let len = value
+            .find(|c: char| c.is_whitespace() || matches!(c, '&' | '#' | ')' | '"' | '\''))
+            .unwrap_or(value.len());
+        let start = text.len() - value.len();
+        return Some((start, start + len));
+    }
+    let value = after
+        .strip_prefix('"')?
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .strip_prefix('"')?;
+    let start = text.len() - value.len();
+    Some((start, start + quoted_len(value)))
+}
+
+/// bytes up to the closing quote of a json string, escapes skipped
+fn quoted_len(value: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return i,
+            _ => escaped = false,
+        }
+    }
+    value.len()
+}
@@ -0,0 +1,156 @@
+// redis_manager.rs — one redis connection that comes back after redis does.
+// a MultiplexedConnection whose socket died keeps failing forever, so whoever
+// sees a connection error reports it and the handle is dropped; the next
+// `get` dials again. failed dials back off (1s doubling to 30s) so a redis
+// that's down isn't hammered by every request. the watchdog pings now and
+// then, which catches a bounce even where nobody reports one, and is what
+// picks redis up when the api started before it.
+
+use redis::aio::MultiplexedConnection;
+use redis::{RedisError, RedisResult};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// a dial that hangs counts as failed after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Slot {
+    connection: Option<MultiplexedConnection>,
+    /// no dialing before this, after a failed attempt
+    next_attempt: Option<Instant>,
+    backoff: Duration,
+}
+
+pub struct RedisManager {
+    /// None when redis isn't configured at all
+    client: Option<redis::Client>,
+    slot: Mutex<Slot>,
+}
+
+impl Default for RedisManager {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+impl RedisManager {
+    /// no redis: `get` is always None
+    pub fn disabled() -> Self {
+        Self::with_client(None)
+    }
+
+    /// redis at `url`, dialed on first use (or by `connect`)
+    pub fn new(url: &str) -> Result<Self, RedisError> {
+        Ok(Self::with_client(Some(redis::Client::open(url)?)))
+    }
+
+    fn with_client(client: Option<redis::Client>) -> Self {
+        Self {
+            client,
+            slot: Mutex::new(Slot { connection: None, next_attempt: None, backoff: MIN_BACKOFF }),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// dial now, ignoring any backoff — for startup, where the error is worth reporting
+    pub async fn connect(&self) -> Result<(), RedisError> {
+        let mut slot = self.slot.lock().await;
+        slot.next_attempt = None;
+        self.dial(&mut slot).await.map(|_| ())
+    }
+
+    /// a handle on the live connection, dialing if there isn't one and the
+    /// backoff allows. None when redis isn't configured or can't be reached
+    pub async fn get(&self) -> Option<MultiplexedConnection> {
+        self.client.as_ref()?;
+        let mut slot = self.slot.lock().await;
+        if let Some(connection) = &slot.connection {
+            return Some(connection.clone());
+        }
+        if slot.next_attempt.is_some_and(|at| Instant::now() < at) {
+            return None;
+        }
+        self.dial(&mut slot).await.ok()
+    }
+
+    /// a command failed with `e` — if the connection itself is gone, drop it
+    /// so the next `get` dials a new one
+    pub async fn report(&self, e: &RedisError) {
+        if !is_connection_error(e) {
+            return;
+        }
+        let mut slot = self.slot.lock().await;
+        if slot.connection.take().is_some() {
+            tracing::warn!("redis connection lost ({}), reconnecting", e);
+        }
+    }
+
+    /// run `op` on the connection — and once more on a fresh one when the
+    /// first attempt found it gone, so a redis restart costs callers nothing.
+    /// None when there's no connection to be had
+    pub async fn retrying<T, F, Fut>(&self, op: F) -> Option<RedisResult<T>>
+    where
+        F: Fn(MultiplexedConnection) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let result = op(self.get().await?).await;
+        match result {
+            Err(e) if is_connection_error(&e) => {
+                self.report(&e).await;
+                Some(op(self.get().await?).await)
+            }
+            result => Some(result),
+        }
+    }
+
+    async fn dial(&self, slot: &mut Slot) -> Result<MultiplexedConnection, RedisError> {
+        let client = self.client.as_ref().expect("dial needs a configured client");
+        let dialed = tokio::time::timeout(CONNECT_TIMEOUT, client.get_multiplexed_tokio_connection())
+            .await
+            .unwrap_or_else(|_| Err(RedisError::from(std::io::Error::from(std::io::ErrorKind::TimedOut))));
+        match dialed {
+            Ok(connection) => {
+                tracing::info!("redis connected");
+                slot.connection = Some(connection.clone());
+                slot.next_attempt = None;
+                slot.backoff = MIN_BACKOFF;
+                Ok(connection)
+            }
+            Err(e) => {
+                tracing::warn!("redis unreachable, next attempt in {}s: {}", slot.backoff.as_secs(), e);
+                slot.next_attempt = Some(Instant::now() + slot.backoff);
+                slot.backoff = (slot.backoff * 2).min(MAX_BACKOFF);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// errors that mean the socket is gone, rather than a bad command or reply
+pub fn is_connection_error(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+/// ping redis every few seconds and report what fails — keeps the connection
+/// fresh for callers that don't report errors themselves
+pub async fn run_watchdog(state: std::sync::Arc<crate::app_state::AppState>) {
+    let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(mut redis) = state.redis.get().await else {
+            continue;
+        };
+        let pong: redis::RedisResult<String> = redis::cmd("PING").query_async(&mut redis).await;
+        if let Err(e) = pong {
+            state.redis.report(&e).await;
+        }
+    }
+}
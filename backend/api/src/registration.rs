@@ -0,0 +1,80 @@
+// registration.rs — who may register: REGISTRATION_MODE open (the default),
+// token or closed. in token mode /register needs a token from
+// registration_tokens (migrations/010_registration_tokens.sql). a use is taken
+// before the homeserver registers the account and handed back if that fails,
+// so two registrations can't both get a token's last use.
+// tokens are managed by the users in ADMIN_USERS, see routes/admin.rs.
+
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    Open,
+    /// only with a registration token
+    Token,
+    Closed,
+}
+
+impl RegistrationMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "open" => Some(Self::Open),
+            "token" => Some(Self::Token),
+            "closed" => Some(Self::Closed),
+            _ => None,
+        }
+    }
+
+    /// REGISTRATION_MODE, open when unset. a value we don't know closes
+    /// registration rather than leaving a private community open by a typo
+    pub fn from_env() -> Self {
+        match std::env::var("REGISTRATION_MODE").ok().filter(|v| !v.is_empty()) {
+            None => Self::Open,
+            Some(mode) => Self::parse(&mode.to_lowercase()).unwrap_or_else(|| {
+                tracing::warn!("unknown REGISTRATION_MODE {:?}, registration is closed", mode);
+                Self::Closed
+            }),
+        }
+    }
+}
+
+/// ADMIN_USERS: comma-separated matrix user ids
+pub fn admin_users_from_env() -> HashSet<String> {
+    std::env::var("ADMIN_USERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|user_id| !user_id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// take one use of `token` if it hasn't expired or run out — false if it has,
+/// or doesn't exist
+pub async fn take_use(pool: &sqlx::PgPool, token: &str) -> Result<bool, sqlx::Error> {
+    let taken = sqlx::query(
+        r#"
+        UPDATE registration_tokens SET uses_remaining = uses_remaining - 1
+        WHERE token = $1
+          AND (uses_remaining IS NULL OR uses_remaining > 0)
+          AND (expires_at IS NULL OR expires_at > NOW())
+        "#,
+    )
+    .bind(token)
+    .execute(pool)
+    .await?;
+    Ok(taken.rows_affected() == 1)
+}
+
+/// hand back a use taken for a registration that didn't go through
+pub async fn return_use(pool: &sqlx::PgPool, token: &str) {
+    let returned = sqlx::query(
+        "UPDATE registration_tokens SET uses_remaining = uses_remaining + 1 WHERE token = $1 AND uses_remaining IS NOT NULL",
+    )
+    .bind(token)
+    .execute(pool)
+    .await;
+    if let Err(e) = returned {
+        tracing::warn!("failed to return a use of a registration token: {}", e);
+    }
+}
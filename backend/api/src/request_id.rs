@@ -0,0 +1,60 @@
+// request ids: every request runs in a span named by one — the caller's
+// X-Request-Id when it sent a usable one, a fresh uuid otherwise — so the log
+// lines of one request, its homeserver calls included, can be picked out of
+// the interleaved rest. the id goes back in the response headers, for bug
+// reports to quote.
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::time::Instant;
+use tracing::Instrument;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// an incoming id longer than this, or with anything but letters, digits and
+// -_.: in it, is replaced rather than written into the logs
+const MAX_INCOMING_LEN: usize = 128;
+
+/// the id the request runs under, in its extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+fn incoming(request: &Request) -> Option<String> {
+    let id = request.headers().get(&REQUEST_ID)?.to_str().ok()?;
+    let usable = !id.is_empty()
+        && id.len() <= MAX_INCOMING_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    usable.then(|| id.to_string())
+}
+
+/// middleware: the request span, and the id in the response
+pub async fn trace(mut request: Request, next: Next) -> Response {
+    let id = incoming(&request).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    // the route, not the uri — queries carry access tokens, and webhook paths their secret
+    let path = match request.extensions().get::<MatchedPath>() {
+        Some(route) => route.as_str().to_string(),
+        None => request.uri().path().to_string(),
+    };
+    let span = tracing::info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %path,
+        status = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+    );
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", started.elapsed().as_millis() as u64);
+    span.in_scope(|| tracing::info!("finished"));
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
@@ -0,0 +1,239 @@
+// room_summaries.rs — cached state for the rooms listed under a space
+// listing a server's channels or a forum's threads needs every child's state,
+// which is one homeserver request per child. the state of leaf rooms (channels
+// and threads), members left out, is kept in redis by room id; sync drops a
+// room's entry when it sees a state change there, and the handlers that edit a
+// listed room drop it themselves so the editor sees the change straight away.
+// spaces aren't cached — a walk follows their m.space.child events, which
+// change as channels come and go — and neither are private channels, whose
+// state only their members may read.
+//
+// the joined rooms list needs far less than the whole state: a RoomSummary per
+// room, kept under room_info:{room_id}. those are cached for spaces and private
+// channels too, since they're only ever read back for rooms the caller has
+// joined, and are dropped along with the state above.
+//
+// the list's last-message previews are kept briefly under room_preview:{room_id},
+// dropped by sync when a room has new messages or redactions. only rooms whose
+// whole history every member can read are cached — elsewhere the last message
+// may be one a newer member isn't allowed to see.
+
+use futures_util::{stream, StreamExt};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::app_state::AppState;
+use crate::matrix::client::{Event, MatrixClient, RoomStateEvent};
+use crate::matrix::{hierarchy, message_policy};
+use crate::routes::voice;
+
+const SUMMARY_TTL_SECS: u64 = 300;
+const PREVIEW_TTL_SECS: u64 = 30;
+// a preview is a line in the sidebar, not the message
+const PREVIEW_BODY_CHARS: usize = 140;
+// state reads in flight at once when filling in the joined rooms list
+const READ_CONCURRENCY: usize = 8;
+
+fn summary_key(room_id: &str) -> String {
+    format!("room_summary:{}", room_id)
+}
+
+fn info_key(room_id: &str) -> String {
+    format!("room_info:{}", room_id)
+}
+
+fn preview_key(room_id: &str) -> String {
+    format!("room_preview:{}", room_id)
+}
+
+/// what a room listing shows of a room, members aside
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomSummary {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    /// mxc:// uri from m.room.avatar
+    pub avatar_url: Option<String>,
+    pub is_space: bool,
+    pub channel_type: String,
+    pub language: Option<String>,
+    pub slowmode_seconds: u64,
+    pub user_limit: Option<u32>,
+    /// the space it's listed under, from m.space.parent
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    #[serde(default)]
+    pub history_visibility: Option<String>,
+}
+
+impl RoomSummary {
+    pub fn from_state(state: &[RoomStateEvent]) -> Self {
+        let content = |event_type: &str| state.iter().find(|e| e.event_type == event_type).map(|e| &e.content);
+        let content_str = |event_type: &str, key: &str| {
+            content(event_type).and_then(|c| c.get(key)).and_then(|v| v.as_str()).map(String::from)
+        };
+        // "text" unless agora.room.type says otherwise
+        let channel_type = content_str("agora.room.type", "type").unwrap_or_else(|| "text".to_string());
+        Self {
+            name: content_str("m.room.name", "name"),
+            topic: content_str("m.room.topic", "topic"),
+            // an empty url is how an avatar gets removed
+            avatar_url: content_str("m.room.avatar", "url").filter(|u| !u.is_empty()),
+            is_space: hierarchy::is_space(state),
+            language: content_str("agora.room.type", "language"),
+            slowmode_seconds: message_policy::slowmode_seconds(state),
+            user_limit: content("agora.room.type").and_then(voice::user_limit).filter(|_| channel_type == "voice"),
+            channel_type,
+            parent_id: state
+                .iter()
+                .filter(|e| e.event_type == "m.space.parent")
+                .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+                .find_map(|e| e.state_key.clone())
+                .filter(|k| !k.is_empty()),
+            history_visibility: content_str("m.room.history_visibility", "history_visibility"),
+        }
+    }
+}
+
+/// the newest message in a room, as the rooms list shows it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct LastMessage {
+    pub sender: String,
+    /// cut to the first 140 characters
+    pub body: String,
+    /// ms since the epoch
+    pub ts: i64,
+}
+
+/// a room's latest messages, boiled down for the rooms list
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomPreview {
+    pub last_message: Option<LastMessage>,
+    /// when the newest message or edit was sent
+    pub last_activity_ts: Option<i64>,
+}
+
+impl RoomPreview {
+    /// from the room's last few m.room.message events, oldest first. edits
+    /// count as activity but aren't shown, and redacted messages are skipped
+    pub fn from_timeline(events: &[Event]) -> Self {
+        let last_message = events.iter().rev().find_map(|event| {
+            let body = event.content.get("body")?.as_str()?;
+            if event.content["m.relates_to"]["rel_type"] == "m.replace" {
+                return None;
+            }
+            Some(LastMessage {
+                sender: event.sender.clone(),
+                body: body.chars().take(PREVIEW_BODY_CHARS).collect(),
+                ts: event.origin_server_ts?,
+            })
+        });
+        Self { last_message, last_activity_ts: events.iter().filter_map(|e| e.origin_server_ts).max() }
+    }
+
+    /// whether every member may read the message this preview shows
+    pub fn cacheable(history_visibility: Option<&str>) -> bool {
+        matches!(history_visibility, None | Some("shared" | "world_readable"))
+    }
+}
+
+/// cached previews of these rooms, in the order given
+pub async fn cached_previews(state: &AppState, room_ids: &[String]) -> Vec<Option<RoomPreview>> {
+    let (Some(mut redis), false) = (state.get_redis().await, room_ids.is_empty()) else {
+        return vec![None; room_ids.len()];
+    };
+    let keys: Vec<String> = room_ids.iter().map(|room_id| preview_key(room_id)).collect();
+    let cached: Vec<Option<String>> = redis.mget(&keys).await.unwrap_or_default();
+    let mut previews: Vec<Option<RoomPreview>> = cached.into_iter().map(|c| c.and_then(|c| serde_json::from_str(&c).ok())).collect();
+    previews.resize(room_ids.len(), None);
+    previews
+}
+
+pub async fn cache_previews(state: &AppState, previews: &[(String, RoomPreview)]) {
+    let (Some(mut redis), false) = (state.get_redis().await, previews.is_empty()) else { return };
+    for (room_id, preview) in previews {
+        if let Ok(json) = serde_json::to_string(preview) {
+            let _: redis::RedisResult<()> = redis.set_ex(preview_key(room_id), json, PREVIEW_TTL_SECS).await;
+        }
+    }
+}
+
+/// forget the previews of rooms that had messages sent or redacted
+pub async fn invalidate_previews(state: &AppState, room_ids: &[String]) {
+    let (Some(mut redis), false) = (state.get_redis().await, room_ids.is_empty()) else { return };
+    let keys: Vec<String> = room_ids.iter().map(|room_id| preview_key(room_id)).collect();
+    let _: redis::RedisResult<()> = redis.del(keys).await;
+}
+
+/// summaries of rooms the caller has joined, in the order given — one MGET for
+/// what's cached, the rest read with the caller's token a few at a time. None
+/// for a room whose state can't be read (typically one just left)
+pub async fn joined(state: &AppState, matrix: &MatrixClient, room_ids: &[String]) -> Vec<Option<RoomSummary>> {
+    let mut redis = state.get_redis().await;
+    let mut summaries: Vec<Option<RoomSummary>> = vec![None; room_ids.len()];
+    if let (Some(redis), false) = (redis.as_mut(), room_ids.is_empty()) {
+        let keys: Vec<String> = room_ids.iter().map(|room_id| info_key(room_id)).collect();
+        let cached: Vec<Option<String>> = redis.mget(&keys).await.unwrap_or_default();
+        for (summary, cached) in summaries.iter_mut().zip(cached) {
+            *summary = cached.and_then(|c| serde_json::from_str(&c).ok());
+        }
+    }
+
+    let missing: Vec<usize> = (0..room_ids.len()).filter(|&i| summaries[i].is_none()).collect();
+    let read: Vec<(usize, Option<RoomSummary>)> = stream::iter(missing)
+        .map(|i| async move {
+            match matrix.get_room_state(room_ids[i].clone()).await {
+                Ok(room_state) => (i, Some(RoomSummary::from_state(&room_state))),
+                Err(e) => {
+                    tracing::debug!("skipping room {} — cannot read state (likely already left): {}", room_ids[i], e);
+                    (i, None)
+                }
+            }
+        })
+        .buffered(READ_CONCURRENCY)
+        .collect()
+        .await;
+
+    for (i, summary) in read {
+        if let (Some(redis), Some(json)) = (redis.as_mut(), summary.as_ref().and_then(|s| serde_json::to_string(s).ok())) {
+            let _: redis::RedisResult<()> = redis.set_ex(info_key(&room_ids[i]), json, SUMMARY_TTL_SECS).await;
+        }
+        summaries[i] = summary;
+    }
+    summaries
+}
+
+/// a room's state, from the cache when it may be shared, else read with the
+/// caller's token. empty when the room can't be read
+pub async fn load(state: &AppState, matrix: &MatrixClient, room_id: String, private: bool) -> Vec<RoomStateEvent> {
+    let key = summary_key(&room_id);
+    if !private {
+        if let Some(mut redis) = state.get_redis().await {
+            let cached: Option<String> = redis.get(&key).await.ok().flatten();
+            if let Some(room_state) = cached.and_then(|c| serde_json::from_str(&c).ok()) {
+                return room_state;
+            }
+        }
+    }
+
+    let room_state = match matrix.get_room_state(room_id.clone()).await {
+        Ok(room_state) => room_state,
+        Err(e) => {
+            tracing::debug!("cannot read state of {}: {}", room_id, e);
+            return Vec::new();
+        }
+    };
+    if !private && !hierarchy::is_space(&room_state) {
+        let summary: Vec<&RoomStateEvent> = room_state.iter().filter(|e| e.event_type != "m.room.member").collect();
+        if let (Some(mut redis), Ok(json)) = (state.get_redis().await, serde_json::to_string(&summary)) {
+            let _: redis::RedisResult<()> = redis.set_ex(&key, json, SUMMARY_TTL_SECS).await;
+        }
+    }
+    room_state
+}
+
+/// forget what's cached for these rooms
+pub async fn invalidate(state: &AppState, room_ids: &[String]) {
+    let (Some(mut redis), false) = (state.get_redis().await, room_ids.is_empty()) else { return };
+    let keys: Vec<String> = room_ids.iter().flat_map(|room_id| [summary_key(room_id), info_key(room_id)]).collect();
+    let _: redis::RedisResult<()> = redis.del(keys).await;
+}
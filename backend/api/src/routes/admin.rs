@@ -0,0 +1,212 @@
+// admin.rs — managing the api itself, for the users in ADMIN_USERS
+// for now that's the registration tokens REGISTRATION_MODE=token asks for.
+// everyone else gets 403; without a database the routes answer 503.
+
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+use sqlx::Row;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::session::{AuthJson, AuthUser};
+use super::agora_error;
+
+const MAX_TOKEN_LEN: usize = 64;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/admin/registration_tokens",
+        get(list_tokens).post(create_token).delete(delete_token),
+    )
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(list_tokens, create_token, delete_token))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateTokenRequest {
+    /// the token itself — a random one when omitted. letters, digits and `._~-`
+    pub token: Option<String>,
+    /// how many registrations it allows — unlimited when omitted
+    pub uses_allowed: Option<i32>,
+    /// unix ms it stops working at — never when omitted
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RegistrationToken {
+    pub token: String,
+    /// None when unlimited
+    pub uses_remaining: Option<i32>,
+    /// unix ms
+    pub expires_at: Option<i64>,
+    pub created_by: String,
+    /// unix ms
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TokensResponse {
+    pub tokens: Vec<RegistrationToken>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteTokenRequest {
+    pub token: String,
+}
+
+/// require a db pool or return 503
+macro_rules! require_db {
+    ($state:expr) => {
+        match $state.db_pool.as_ref() {
+            Some(pool) => pool,
+            None => {
+                tracing::error!("admin endpoints require a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+        }
+    };
+}
+
+/// 403 unless the caller is in ADMIN_USERS
+fn require_admin(state: &AppState, auth: &AuthUser) -> Result<(), Box<Response>> {
+    if state.admin_users.contains(&auth.user_id) {
+        Ok(())
+    } else {
+        Err(Box::new(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "only the api's admins can do that")))
+    }
+}
+
+fn db_error(e: sqlx::Error) -> Response {
+    tracing::error!("registration tokens query failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+const TOKEN_COLUMNS: &str = r#"
+    token, uses_remaining, created_by,
+    (EXTRACT(EPOCH FROM expires_at) * 1000)::BIGINT AS expires_at,
+    (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+"#;
+
+fn token_entry(row: &sqlx::postgres::PgRow) -> RegistrationToken {
+    RegistrationToken {
+        token: row.get("token"),
+        uses_remaining: row.get("uses_remaining"),
+        expires_at: row.get("expires_at"),
+        created_by: row.get("created_by"),
+        created_at: row.get::<Option<i64>, _>("created_at").unwrap_or_default(),
+    }
+}
+
+/// the same characters the matrix spec allows in registration tokens
+fn valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_TOKEN_LEN
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '~' | '-'))
+}
+
+/// every registration token, newest first — used up and expired ones too
+#[utoipa::path(
+    get,
+    path = "/admin/registration_tokens",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses((status = 200, body = TokensResponse), super::ErrorResponses)
+)]
+async fn list_tokens(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<TokensResponse>, Response> {
+    require_admin(&state, &auth).map_err(|e| *e)?;
+    let pool = require_db!(state);
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM registration_tokens ORDER BY created_at DESC, token",
+        TOKEN_COLUMNS
+    ))
+    .fetch_all(pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(TokensResponse { tokens: rows.iter().map(token_entry).collect() }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/registration_tokens",
+    tag = "admin",
+    request_body = CreateTokenRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = RegistrationToken), super::ErrorResponses)
+)]
+async fn create_token(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<CreateTokenRequest>,
+) -> Result<Json<RegistrationToken>, Response> {
+    require_admin(&state, &auth).map_err(|e| *e)?;
+    let pool = require_db!(state);
+
+    let token = req.token.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    if !valid_token(&token) {
+        let error = format!("tokens are 1 to {} letters, digits or ._~-", MAX_TOKEN_LEN);
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", error));
+    }
+    if req.uses_allowed.is_some_and(|uses| uses < 1) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "uses_allowed must be at least 1"));
+    }
+    if req.expires_at.is_some_and(|at| at <= chrono::Utc::now().timestamp_millis()) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "expires_at is in the past"));
+    }
+
+    let row = sqlx::query(&format!(
+        r#"
+        INSERT INTO registration_tokens (token, uses_remaining, expires_at, created_by)
+        VALUES ($1, $2, to_timestamp($3::BIGINT / 1000.0), $4)
+        ON CONFLICT (token) DO NOTHING
+        RETURNING {}
+        "#,
+        TOKEN_COLUMNS
+    ))
+    .bind(&token)
+    .bind(req.uses_allowed)
+    .bind(req.expires_at)
+    .bind(&auth.user_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(db_error)?;
+    match row {
+        Some(row) => Ok(Json(token_entry(&row))),
+        None => Err(agora_error(StatusCode::CONFLICT, "M_INVALID_PARAM", "that token already exists")),
+    }
+}
+
+/// stop a token from working — registrations it already let through stay
+#[utoipa::path(
+    delete,
+    path = "/admin/registration_tokens",
+    tag = "admin",
+    request_body = DeleteTokenRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn delete_token(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<DeleteTokenRequest>,
+) -> Result<StatusCode, Response> {
+    require_admin(&state, &auth).map_err(|e| *e)?;
+    let pool = require_db!(state);
+    let deleted = sqlx::query("DELETE FROM registration_tokens WHERE token = $1")
+        .bind(&req.token)
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+    if deleted.rows_affected() == 0 {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such token"));
+    }
+    Ok(StatusCode::OK)
+}
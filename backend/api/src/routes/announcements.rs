@@ -0,0 +1,347 @@
+// announcements.rs — announcement channels and the channels that follow them
+// an announcement channel is a Matrix room with agora.room.type = "announcement".
+// other channels follow it (manage_channels in the follower's server), which is
+// a row in announcement_follows. publishing a message from it copies the
+// content into every follower, with agora.announcement.source saying where it
+// came from. the copy is sent as whoever set up the follow, with the token they
+// followed with — they can write to the target, the publisher usually can't.
+// publishing is for the message's author or anyone with manage_messages.
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sqlx::Row;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::authz::{self, Permission};
+use crate::matrix::client::{MatrixClient, RoomStateEvent};
+use crate::session::{AuthJson, AuthUser};
+use super::{agora_error, authz_error, matrix_error};
+
+/// the field on a published copy naming the original
+pub const SOURCE_FIELD: &str = "agora.announcement.source";
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/rooms/announce", post(publish))
+        .route("/rooms/announce/follow", post(follow).delete(unfollow))
+        .route("/rooms/announce/followers", get(list_followers))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(publish, follow, unfollow, list_followers))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PublishRequest {
+    /// the announcement channel
+    pub room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PublishResponse {
+    pub event_id: String,
+    pub delivered: Vec<DeliveredCopy>,
+    /// followers the copy couldn't be sent to
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeliveredCopy {
+    pub target_room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct FollowRequest {
+    pub source_room_id: String,
+    pub target_room_id: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FollowersQuery {
+    /// the announcement channel
+    pub room_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FollowersResponse {
+    pub followers: Vec<Follower>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Follower {
+    pub target_room_id: String,
+    pub followed_by: String,
+    /// unix ms
+    pub followed_at: i64,
+}
+
+/// require a db pool or return 503
+macro_rules! require_db {
+    ($state:expr) => {
+        match $state.db_pool.as_ref() {
+            Some(pool) => pool,
+            None => {
+                tracing::error!("announcement endpoints require a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+        }
+    };
+}
+
+fn db_error(e: sqlx::Error) -> Response {
+    tracing::error!("announcement follows query failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+fn channel_type(room_state: &[RoomStateEvent]) -> Option<&str> {
+    room_state
+        .iter()
+        .find(|e| e.event_type == "agora.room.type")
+        .and_then(|e| e.content["type"].as_str())
+}
+
+fn room_name(room_state: &[RoomStateEvent]) -> Option<String> {
+    room_state
+        .iter()
+        .find(|e| e.event_type == "m.room.name")
+        .and_then(|e| e.content["name"].as_str())
+        .map(str::to_string)
+}
+
+/// the state of `room_id` when it's an announcement channel the caller can read, else 400
+async fn announcement_channel(matrix: &MatrixClient, room_id: &str) -> Result<Vec<RoomStateEvent>, Response> {
+    let room_state = matrix
+        .get_room_state(room_id.to_string())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if channel_type(&room_state) != Some("announcement") {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "not an announcement channel"));
+    }
+    Ok(room_state)
+}
+
+/// where a published copy came from: the server, the channel and the author
+async fn source_of(matrix: &MatrixClient, room_id: &str, room_state: &[RoomStateEvent], event_id: &str, sender: &str) -> serde_json::Value {
+    let server_id = authz::server_of(matrix, room_id).await.ok().flatten();
+    let server_name = match &server_id {
+        Some(server_id) => matrix.get_room_state(server_id.clone()).await.ok().and_then(|s| room_name(&s)),
+        None => None,
+    };
+    serde_json::json!({
+        "server_id": server_id,
+        "server_name": server_name,
+        "room_id": room_id,
+        "room_name": room_name(room_state),
+        "event_id": event_id,
+        "sender": sender,
+    })
+}
+
+/// copy a message from an announcement channel into every channel following it
+#[utoipa::path(
+    post,
+    path = "/rooms/announce",
+    tag = "announcements",
+    request_body = PublishRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = PublishResponse), super::ErrorResponses)
+)]
+async fn publish(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<PublishRequest>,
+) -> Result<Json<PublishResponse>, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let user_id = auth.user_id;
+    let room_state = announcement_channel(&matrix, &req.room_id).await?;
+    let event = matrix.get_event(&req.room_id, &req.event_id).await.map_err(|e| {
+        tracing::debug!("cannot load event {}: {}", req.event_id, e);
+        agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such message")
+    })?;
+    if event["type"].as_str() != Some("m.room.message") || event["content"]["body"].is_null() {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such message"));
+    }
+    let sender = event["sender"].as_str().unwrap_or_default().to_string();
+    if sender != user_id {
+        authz::require_permission_in(&matrix, &req.room_id, Permission::ManageMessages)
+            .await
+            .map_err(|e| authz_error(&e))?;
+    }
+
+    // the copy is a fresh message: no edits, replies or mentions of people who aren't there
+    let mut content = event["content"].clone();
+    if let Some(content) = content.as_object_mut() {
+        content.remove("m.relates_to");
+        content.remove("m.new_content");
+        content.remove("m.mentions");
+    }
+    content[SOURCE_FIELD] = source_of(&matrix, &req.room_id, &room_state, &req.event_id, &sender).await;
+
+    let followers = sqlx::query("SELECT target_room_id, access_token FROM announcement_follows WHERE source_room_id = $1 ORDER BY created_at")
+        .bind(&req.room_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+
+    let mut delivered = Vec::new();
+    let mut failed = Vec::new();
+    for row in followers {
+        let target_room_id: String = row.get("target_room_id");
+        let mut follower = state.matrix();
+        follower.access_token = Some(row.get("access_token"));
+        match follower.send_message_content(target_room_id.clone(), content.clone(), None).await {
+            Ok(sent) => {
+                let event_id = sent["event_id"].as_str().unwrap_or_default().to_string();
+                delivered.push(DeliveredCopy { target_room_id, event_id });
+            }
+            Err(e) => {
+                tracing::warn!("failed to publish {} to {}: {}", req.event_id, target_room_id, e);
+                failed.push(target_room_id);
+            }
+        }
+    }
+    Ok(Json(PublishResponse { event_id: req.event_id, delivered, failed }))
+}
+
+/// have `target_room_id` receive what's published in `source_room_id`.
+/// following again keeps the row and takes the caller's token
+#[utoipa::path(
+    post,
+    path = "/rooms/announce/follow",
+    tag = "announcements",
+    request_body = FollowRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn follow(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<FollowRequest>,
+) -> Result<StatusCode, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    if req.source_room_id == req.target_room_id {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "a channel can't follow itself"));
+    }
+    let user_id = auth.user_id;
+    announcement_channel(&matrix, &req.source_room_id).await?;
+    authz::require_permission_in(&matrix, &req.target_room_id, Permission::ManageChannels)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let target_state = matrix
+        .get_room_state(req.target_room_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if !matches!(channel_type(&target_state), None | Some("text" | "announcement")) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "announcements can only go into text channels"));
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO announcement_follows (source_room_id, target_room_id, followed_by, access_token)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (source_room_id, target_room_id)
+        DO UPDATE SET followed_by = EXCLUDED.followed_by, access_token = EXCLUDED.access_token
+        "#,
+    )
+    .bind(&req.source_room_id)
+    .bind(&req.target_room_id)
+    .bind(&user_id)
+    .bind(&auth.access_token)
+    .execute(pool)
+    .await
+    .map_err(db_error)?;
+    Ok(StatusCode::OK)
+}
+
+/// stop a follow — manage_channels in the target's server, like following
+#[utoipa::path(
+    delete,
+    path = "/rooms/announce/follow",
+    tag = "announcements",
+    request_body = FollowRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn unfollow(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<FollowRequest>,
+) -> Result<StatusCode, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission_in(&matrix, &req.target_room_id, Permission::ManageChannels)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let removed = sqlx::query("DELETE FROM announcement_follows WHERE source_room_id = $1 AND target_room_id = $2")
+        .bind(&req.source_room_id)
+        .bind(&req.target_room_id)
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+    if removed.rows_affected() == 0 {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "that channel doesn't follow this one"));
+    }
+    Ok(StatusCode::OK)
+}
+
+/// the channels following an announcement channel, oldest follow first —
+/// manage_channels in the announcement channel's server
+#[utoipa::path(
+    get,
+    path = "/rooms/announce/followers",
+    tag = "announcements",
+    params(FollowersQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = FollowersResponse), super::ErrorResponses)
+)]
+async fn list_followers(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<FollowersQuery>,
+) -> Result<Json<FollowersResponse>, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    announcement_channel(&matrix, &params.room_id).await?;
+    authz::require_permission_in(&matrix, &params.room_id, Permission::ManageChannels)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let rows = sqlx::query(
+        r#"
+        SELECT target_room_id, followed_by, (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS followed_at
+        FROM announcement_follows
+        WHERE source_room_id = $1
+        ORDER BY created_at
+        "#,
+    )
+    .bind(&params.room_id)
+    .fetch_all(pool)
+    .await
+    .map_err(db_error)?;
+
+    let followers = rows
+        .iter()
+        .map(|row| Follower {
+            target_room_id: row.get("target_room_id"),
+            followed_by: row.get("followed_by"),
+            followed_at: row.get("followed_at"),
+        })
+        .collect();
+    Ok(Json(FollowersResponse { followers }))
+}
@@ -1,28 +1,49 @@
 use axum::{
     extract::State,
     http::StatusCode,
+    response::{IntoResponse, Response},
     Json,
-    routing::post,
+    routing::{get, post},
     Router,
 };
+use utoipa::{OpenApi, ToSchema};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use crate::app_state::AppState;
-use crate::matrix::client::MatrixClient;
+use crate::matrix::client::WhoamiResponse;
+use crate::registration::{self, RegistrationMode};
+use crate::session::{self, AuthJson, AuthUser, SessionToken};
+use super::{agora_error, matrix_error};
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/register", post(register))
         .route("/login", post(login))
+        .route("/whoami", get(whoami))
+        .route("/logout", post(logout))
+        .route("/logout/all", post(logout_all))
+        .route("/account/password", post(change_password))
+        .route("/devices", get(list_devices).delete(delete_device))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(OpenApi)]
+#[openapi(paths(
+    register, login, whoami, logout, logout_all, change_password, list_devices,
+    delete_device,
+))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct RegisterRequest {
     pub username: String,
     pub password: String,
+    /// optional address for notification digests — a verification link is mailed to it
+    pub email: Option<String>,
+    /// required when the api only lets people in with a token (REGISTRATION_MODE=token)
+    pub registration_token: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RegisterResponse {
     pub user_id: String,
     pub access_token: String,
@@ -30,13 +51,50 @@ pub struct RegisterResponse {
     pub device_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+    /// also end every other session — this one stays logged in
+    #[serde(default)]
+    pub logout_devices: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub last_seen_ts: Option<i64>,
+    pub last_seen_ip: Option<String>,
+    /// the session this request was made with
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DevicesResponse {
+    pub devices: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteDeviceRequest {
+    pub device_id: String,
+    /// the homeserver re-checks the account password before deleting a session
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteDeviceResponse {
+    /// the deleted device was the caller's own — the token is dead, clear local state
+    pub logged_out: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct LoginResponse {
     pub user_id: String,
     pub access_token: String,
@@ -44,19 +102,39 @@ pub struct LoginResponse {
     pub device_id: Option<String>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/register",
+    tag = "auth",
+    request_body = RegisterRequest,
+    responses((status = 200, body = RegisterResponse), super::ErrorResponses)
+)]
 async fn register(
     state: State<Arc<AppState>>,
     Json(req): Json<RegisterRequest>,
-) -> Result<Json<RegisterResponse>, StatusCode> {
-    let matrix = MatrixClient::new(state.homeserver_url.clone());
+) -> Result<Json<RegisterResponse>, Response> {
+    let matrix = state.matrix();
+    let token = admit(&state, req.registration_token.as_deref()).await?;
     
-    match matrix.register(req.username, req.password).await {
+    match matrix.register(req.username.clone(), req.password.clone()).await {
         Ok(response) => {
             // extract home_server from user_id if not provided (e.g., "@user:localhost" -> "localhost")
             let home_server = response.home_server.or_else(|| {
                 response.user_id.split(':').nth(1).map(String::from)
             });
-            
+
+            // capture the email if one was given and email is enabled — never fail registration over it
+            if let (Some(address), Some(email_config), Some(pool)) =
+                (req.email.as_deref().map(str::trim), state.email.as_ref(), state.db_pool.as_ref())
+            {
+                if crate::email::is_valid_email(address) {
+                    if let Err(e) = crate::email::set_address(email_config, pool, &response.user_id, address).await {
+                        tracing::warn!("failed to store email at registration: {}", e);
+                    }
+                }
+            }
+            session::remember(&state, &response.access_token, &response.user_id).await;
+
             Ok(Json(RegisterResponse {
                 user_id: response.user_id,
                 access_token: response.access_token,
@@ -66,22 +144,70 @@ async fn register(
         }
         Err(e) => {
             tracing::error!("registration failed: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            if let (Some(token), Some(pool)) = (token, state.db_pool.as_ref()) {
+                registration::return_use(pool, &token).await;
+            }
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
 
+/// whether REGISTRATION_MODE lets this registration go ahead. in token mode
+/// that takes a use of the token, which is returned when it's Some
+async fn admit(state: &AppState, token: Option<&str>) -> Result<Option<String>, Response> {
+    match state.registration_mode {
+        RegistrationMode::Open => Ok(None),
+        RegistrationMode::Closed => Err(agora_error(
+            StatusCode::FORBIDDEN,
+            "AGORA_REGISTRATION_CLOSED",
+            "registration is closed on this server",
+        )),
+        RegistrationMode::Token => {
+            let Some(token) = token.map(str::trim).filter(|t| !t.is_empty()) else {
+                return Err(agora_error(
+                    StatusCode::FORBIDDEN,
+                    "AGORA_REGISTRATION_TOKEN_REQUIRED",
+                    "registering on this server takes a registration token",
+                ));
+            };
+            let Some(pool) = state.db_pool.as_ref() else {
+                tracing::error!("REGISTRATION_MODE=token needs a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            };
+            match registration::take_use(pool, token).await {
+                Ok(true) => Ok(Some(token.to_string())),
+                Ok(false) => Err(agora_error(
+                    StatusCode::FORBIDDEN,
+                    "AGORA_INVALID_REGISTRATION_TOKEN",
+                    "that registration token is invalid, expired or used up",
+                )),
+                Err(e) => {
+                    tracing::error!("failed to check registration token: {}", e);
+                    Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                }
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "auth",
+    request_body = LoginRequest,
+    responses((status = 200, body = LoginResponse), super::ErrorResponses)
+)]
 async fn login(
     state: State<Arc<AppState>>,
     Json(req): Json<LoginRequest>,
-) -> Result<Json<LoginResponse>, StatusCode> {
-    let matrix = MatrixClient::new(state.homeserver_url.clone());
+) -> Result<Json<LoginResponse>, Response> {
+    let matrix = state.matrix();
     
     // ensure username is in full user_id format (@user:server)
     let user = if req.username.starts_with('@') {
         req.username
     } else {
-        format!("@{}:localhost", req.username)
+        format!("@{}:{}", req.username, state.server_name)
     };
     
     match matrix.login(user, req.password).await {
@@ -90,7 +216,8 @@ async fn login(
             let home_server = response.home_server.or_else(|| {
                 response.user_id.split(':').nth(1).map(String::from)
             });
-            
+            session::remember(&state, &response.access_token, &response.user_id).await;
+
             Ok(Json(LoginResponse {
                 user_id: response.user_id,
                 access_token: response.access_token,
@@ -98,9 +225,203 @@ async fn login(
                 device_id: response.device_id,
             }))
         }
+        // wrong username or password
+        Err(e) if e.is_forbidden() => Err(agora_error(
+            StatusCode::UNAUTHORIZED,
+            "M_FORBIDDEN",
+            "invalid username or password",
+        )),
         Err(e) => {
             tracing::error!("login failed: {}", e);
-            Err(StatusCode::UNAUTHORIZED)
+            Err(matrix_error(&e, StatusCode::UNAUTHORIZED))
         }
     }
 }
+
+/// cheap token check for app start — 401 means show the login screen
+#[utoipa::path(
+    get,
+    path = "/whoami",
+    tag = "auth",
+    security(("bearer" = [])),
+    responses((status = 200, body = WhoamiResponse), super::ErrorResponses)
+)]
+async fn whoami(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<WhoamiResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    match matrix.whoami().await {
+        Ok(whoami) => Ok(Json(whoami)),
+        Err(e) => {
+            if !e.is_unknown_token() {
+                tracing::error!("whoami failed: {}", e);
+            }
+            Err(matrix_error(&e, StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    tag = "auth",
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn logout(state: State<Arc<AppState>>, SessionToken(access_token): SessionToken) -> Response {
+    end_sessions(&state, access_token, false).await
+}
+
+/// log out every device the user is signed in on, not just this one
+#[utoipa::path(
+    post,
+    path = "/logout/all",
+    tag = "auth",
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn logout_all(state: State<Arc<AppState>>, SessionToken(access_token): SessionToken) -> Response {
+    end_sessions(&state, access_token, true).await
+}
+
+async fn end_sessions(state: &AppState, access_token: String, all_devices: bool) -> Response {
+    // already logged out — the client wanted it gone and it is
+    let auth = match session::resolve(state, access_token.clone()).await {
+        Ok(auth) => auth,
+        Err(e) if e.status() == StatusCode::UNAUTHORIZED => {
+            session::forget(state, &access_token).await;
+            return StatusCode::OK.into_response();
+        }
+        Err(e) => return e,
+    };
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let result = if all_devices { matrix.logout_all().await } else { matrix.logout().await };
+    match result {
+        Ok(()) => {}
+        Err(e) if e.is_unknown_token() => {}
+        Err(e) => {
+            tracing::error!("logout failed: {}", e);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    }
+
+    session::forget(state, &auth.access_token).await;
+    super::users::clear_presence(state, &auth.user_id).await;
+    StatusCode::OK.into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/password",
+    tag = "auth",
+    request_body = ChangePasswordRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn change_password(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ChangePasswordRequest>,
+) -> Result<StatusCode, Response> {
+    if req.new_password.is_empty() {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_WEAK_PASSWORD", "new password must not be empty"));
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    match matrix
+        .change_password(&auth.user_id, &req.old_password, &req.new_password, req.logout_devices)
+        .await
+    {
+        Ok(()) => Ok(StatusCode::OK),
+        Err(e) => match e.errcode() {
+            // the old password didn't check out
+            Some("M_FORBIDDEN") => Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "old password is incorrect")),
+            Some("M_WEAK_PASSWORD") => Err(agora_error(StatusCode::BAD_REQUEST, "M_WEAK_PASSWORD", "new password is too weak")),
+            _ => {
+                tracing::error!("password change failed: {}", e);
+                Err(StatusCode::BAD_GATEWAY.into_response())
+            }
+        },
+    }
+}
+
+/// the user's sessions, most recently seen first
+#[utoipa::path(
+    get,
+    path = "/devices",
+    tag = "auth",
+    security(("bearer" = [])),
+    responses((status = 200, body = DevicesResponse), super::StatusErrors)
+)]
+async fn list_devices(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<DevicesResponse>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let current = matrix.whoami().await.map_err(|_| StatusCode::UNAUTHORIZED)?.device_id;
+    let devices = matrix.get_devices().await.map_err(|e| {
+        tracing::error!("failed to list devices: {}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    let mut devices: Vec<DeviceInfo> = devices
+        .into_iter()
+        .map(|d| DeviceInfo {
+            current: current.as_deref() == Some(d.device_id.as_str()),
+            device_id: d.device_id,
+            display_name: d.display_name,
+            last_seen_ts: d.last_seen_ts,
+            last_seen_ip: d.last_seen_ip,
+        })
+        .collect();
+    devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen_ts));
+
+    Ok(Json(DevicesResponse { devices }))
+}
+
+/// revoke one session. deleting the caller's own device is allowed — it's a logout
+#[utoipa::path(
+    delete,
+    path = "/devices",
+    tag = "auth",
+    request_body = DeleteDeviceRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = DeleteDeviceResponse), super::ErrorResponses)
+)]
+async fn delete_device(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<DeleteDeviceRequest>,
+) -> Result<Json<DeleteDeviceResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let current = matrix.whoami().await.map_err(|_| StatusCode::UNAUTHORIZED.into_response())?.device_id;
+    let user_id = auth.user_id;
+
+    if let Err(e) = matrix.delete_device(&user_id, &req.device_id, &req.password).await {
+        return Err(match e.errcode() {
+            Some("M_FORBIDDEN") => agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "password is incorrect"),
+            Some("M_NOT_FOUND") => agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such device"),
+            _ => {
+                tracing::error!("failed to delete device {}: {}", req.device_id, e);
+                StatusCode::BAD_GATEWAY.into_response()
+            }
+        });
+    }
+
+    let logged_out = current.as_deref() == Some(req.device_id.as_str());
+    if logged_out {
+        session::forget(&state, &auth.access_token).await;
+        super::users::clear_presence(&state, &user_id).await;
+    }
+    Ok(Json(DeleteDeviceResponse { logged_out }))
+}
@@ -0,0 +1,584 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+    Router,
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sqlx::Row;
+use std::collections::HashSet;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::client::{MatrixClient, MatrixError};
+use crate::matrix::encode_path_segment;
+use crate::pagination::{decode_cursor, encode_cursor};
+use crate::session::AuthUser;
+use super::sync::Message;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 50;
+// homeserver search pages fetched per request before handing back a token
+const MAX_SEARCH_PAGES: usize = 5;
+// history pages scanned per request when the homeserver can't search
+const MAX_HISTORY_PAGES: usize = 10;
+const HISTORY_PAGE_SIZE: usize = 100;
+/// the standard account data mapping user ids to their dm rooms
+pub const DIRECT_ACCOUNT_DATA_TYPE: &str = "m.direct";
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/dms/search", get(search_dm))
+        .route("/dms/recover", get(recover_dms))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(search_dm, recover_dms))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DmSearchQuery {
+    pub room_id: String,
+    pub query: String,
+    /// only messages from this matrix user id
+    pub sender: Option<String>,
+    /// only messages sent before this time (ms since epoch)
+    pub before: Option<i64>,
+    /// only messages sent after this time (ms since epoch)
+    pub after: Option<i64>,
+    pub limit: Option<usize>,
+    /// next_batch from a previous response
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DmSearchResult {
+    pub message: Message,
+    /// the message right before / after the match, for rendering a snippet
+    pub context_before: Option<Message>,
+    pub context_after: Option<Message>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DmSearchResponse {
+    pub results: Vec<DmSearchResult>,
+    /// pass back as `from` to continue; absent once there's nothing older to search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecoveredDm {
+    pub user_id: String,
+    pub room_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecoverDmsResponse {
+    /// friendships whose dm room was filled back in
+    pub recovered: Vec<RecoveredDm>,
+}
+
+/// where a search left off. the mode is part of the token so a continued search
+/// doesn't re-probe the homeserver search api after falling back.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+enum SearchPosition {
+    Search { batch: String },
+    /// `skip` events of the page starting at `from` were already looked at
+    History { from: Option<String>, skip: usize },
+}
+
+/// everything a message has to satisfy to count as a match
+struct Filters {
+    query: String,
+    sender: Option<String>,
+    before: Option<i64>,
+    after: Option<i64>,
+    blocked: HashSet<String>,
+}
+
+impl Filters {
+    /// sender, block and date rules — everything except the text match
+    fn allows(&self, message: &Message) -> bool {
+        self.sender.as_ref().is_none_or(|s| *s == message.sender)
+            && !self.blocked.contains(&message.sender)
+            && self.before.is_none_or(|b| message.timestamp.is_some_and(|ts| ts < b))
+    }
+
+    fn matches(&self, message: &Message) -> bool {
+        message.content.to_lowercase().contains(&self.query) && self.allows(message)
+    }
+
+    /// both search paths walk newest → oldest, so past `after` nothing else can match
+    fn too_old(&self, message: &Message) -> bool {
+        self.after.is_some_and(|a| message.timestamp.is_some_and(|ts| ts <= a))
+    }
+}
+
+/// the m.room.message events we show, in the same shape /sync uses
+fn to_message(room_id: &str, event: &serde_json::Value) -> Option<Message> {
+    if event["type"].as_str() != Some("m.room.message") {
+        return None;
+    }
+    let body = event["content"]["body"].as_str()?;
+    let is_reply = event["content"]["m.relates_to"]["m.in_reply_to"].is_object();
+    let content = if is_reply { crate::content::strip_reply_fallback(body) } else { body };
+    Some(Message {
+        room_id: room_id.to_string(),
+        sender: event["sender"].as_str()?.to_string(),
+        content: content.to_string(),
+        formatted_body: crate::content::html_body(&event["content"]),
+        timestamp: event["origin_server_ts"].as_i64(),
+        event_id: event["event_id"].as_str().map(str::to_string),
+        msgtype: event["content"]["msgtype"].as_str().unwrap_or("m.text").to_string(),
+        url: event["content"]["url"].as_str().map(str::to_string),
+        info: Some(event["content"]["info"].clone()).filter(|v| v.is_object()),
+        translated_body: None,
+        editable: false,
+        deletable: false,
+        mentions_me: false,
+        notify: true,
+        mentions: crate::content::Mentions::from_content(&event["content"]),
+        call: super::voice::CallSignal::from_content(&event["content"]),
+        raw_content: event["content"].clone(),
+    })
+}
+
+/// the nearest visible message in a list of context events (closest first)
+fn nearest_message(room_id: &str, events: &serde_json::Value, filters: &Filters) -> Option<Message> {
+    events
+        .as_array()?
+        .iter()
+        .filter_map(|e| to_message(room_id, e))
+        .find(|m| !filters.blocked.contains(&m.sender))
+}
+
+/// the homeserver doesn't implement /search (or not for this room) — worth falling back
+fn search_unsupported(err: &MatrixError) -> bool {
+    match err {
+        MatrixError::MatrixApiError { errcode, .. } => errcode == "M_UNRECOGNIZED",
+        // a bare 404 / 405 from a proxy or a homeserver without the route
+        MatrixError::ApiError(_) => true,
+        _ => false,
+    }
+}
+
+/// users blocked in either direction — their messages never show up in results
+async fn blocked_users(state: &AppState, user_id: &str) -> HashSet<String> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return HashSet::new();
+    };
+    let rows = sqlx::query(
+        r#"
+        SELECT requester_id, addressee_id FROM friends
+        WHERE (requester_id = $1 OR addressee_id = $1) AND status = 'blocked'
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_else(|e| {
+        tracing::warn!("failed to load blocked users: {}", e);
+        Vec::new()
+    });
+
+    rows.iter()
+        .map(|row| {
+            let requester: String = row.get("requester_id");
+            if requester == user_id { row.get("addressee_id") } else { requester }
+        })
+        .collect()
+}
+
+/// search a single conversation. uses the homeserver search api scoped to the
+/// room, and falls back to scanning history when that isn't supported.
+#[utoipa::path(
+    get,
+    path = "/dms/search",
+    tag = "dms",
+    params(DmSearchQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = DmSearchResponse), super::StatusErrors)
+)]
+async fn search_dm(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<DmSearchQuery>,
+) -> Result<Json<DmSearchResponse>, StatusCode> {
+    let query = params.query.trim().to_lowercase();
+    if query.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let position = match params.from.as_deref() {
+        Some(raw) => Some(decode_cursor::<SearchPosition>(raw).ok_or(StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let filters = Filters {
+        query,
+        sender: params.sender.clone(),
+        before: params.before,
+        after: params.after,
+        blocked: blocked_users(&state, &auth.user_id).await,
+    };
+
+    let outcome = match position {
+        Some(SearchPosition::History { from, skip }) => {
+            search_history(&matrix, &params.room_id, &filters, limit, from, skip).await
+        }
+        Some(SearchPosition::Search { batch }) => {
+            search_homeserver(&matrix, &params.room_id, &params.query, &filters, limit, Some(batch)).await
+        }
+        None => match search_homeserver(&matrix, &params.room_id, &params.query, &filters, limit, None).await {
+            Err(e) if search_unsupported(&e) => {
+                tracing::debug!("homeserver search unavailable, scanning history: {}", e);
+                search_history(&matrix, &params.room_id, &filters, limit, None, 0).await
+            }
+            other => other,
+        },
+    };
+
+    match outcome {
+        Ok((mut results, next)) => {
+            super::sync::attach_viewer_flags(&matrix, results.iter_mut().map(|r| &mut r.message)).await;
+            Ok(Json(DmSearchResponse {
+                results,
+                next_batch: next.map(|p| encode_cursor(&p)),
+            }))
+        }
+        Err(e) => {
+            tracing::error!("dm search failed: {}", e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// POST /search scoped to one room. date filters aren't part of the search api,
+/// so they're applied here; context comes back with each result.
+async fn search_homeserver(
+    matrix: &MatrixClient,
+    room_id: &str,
+    search_term: &str,
+    filters: &Filters,
+    limit: usize,
+    mut batch: Option<String>,
+) -> Result<(Vec<DmSearchResult>, Option<SearchPosition>), MatrixError> {
+    let mut results = Vec::new();
+
+    for _ in 0..MAX_SEARCH_PAGES {
+        let mut url = format!("{}/search", matrix.client_api_base().await);
+        if let Some(batch) = &batch {
+            url = format!("{}?next_batch={}", url, urlencoding::encode(batch));
+        }
+        let mut room_filter = serde_json::json!({
+            "rooms": [room_id],
+            // never ask for more than still fits, so a page is never cut short
+            "limit": limit - results.len(),
+        });
+        if let Some(sender) = &filters.sender {
+            room_filter["senders"] = serde_json::json!([sender]);
+        }
+        let body = serde_json::json!({
+            "search_categories": {
+                "room_events": {
+                    "search_term": search_term,
+                    "keys": ["content.body"],
+                    "order_by": "recent",
+                    "filter": room_filter,
+                    "event_context": { "before_limit": 1, "after_limit": 1 },
+                }
+            }
+        });
+
+        let response = matrix.post_raw(&url, &body).await?;
+        let room_events = &response["search_categories"]["room_events"];
+
+        for hit in room_events["results"].as_array().into_iter().flatten() {
+            let Some(message) = to_message(room_id, &hit["result"]) else {
+                continue;
+            };
+            if filters.too_old(&message) {
+                return Ok((results, None));
+            }
+            // the homeserver's full-text match can be looser than a substring match —
+            // keep what it found unless sender, block or date rules exclude it
+            if !filters.allows(&message) {
+                continue;
+            }
+            results.push(DmSearchResult {
+                context_before: nearest_message(room_id, &hit["context"]["events_before"], filters),
+                context_after: nearest_message(room_id, &hit["context"]["events_after"], filters),
+                message,
+            });
+        }
+
+        batch = room_events["next_batch"].as_str().map(str::to_string);
+        if batch.is_none() || results.len() >= limit {
+            break;
+        }
+    }
+
+    Ok((results, batch.map(|batch| SearchPosition::Search { batch })))
+}
+
+/// scan /messages backwards page by page and match locally. bounded to
+/// MAX_HISTORY_PAGES per request; the returned position resumes mid-page so no
+/// event is skipped or reported twice.
+async fn search_history(
+    matrix: &MatrixClient,
+    room_id: &str,
+    filters: &Filters,
+    limit: usize,
+    mut from: Option<String>,
+    mut skip: usize,
+) -> Result<(Vec<DmSearchResult>, Option<SearchPosition>), MatrixError> {
+    let mut matches = Vec::new();
+
+    for _ in 0..MAX_HISTORY_PAGES {
+        let mut url = format!(
+            "{}/rooms/{}/messages?dir=b&limit={}",
+            matrix.client_api_base().await,
+            encode_path_segment(room_id),
+            HISTORY_PAGE_SIZE
+        );
+        if let Some(from) = &from {
+            url = format!("{}&from={}", url, urlencoding::encode(from));
+        }
+        let page = matrix.get_raw(&url).await?;
+        let chunk = page["chunk"].as_array().cloned().unwrap_or_default();
+
+        for (i, event) in chunk.iter().enumerate().skip(skip) {
+            let Some(message) = to_message(room_id, event) else {
+                continue;
+            };
+            if filters.too_old(&message) {
+                return Ok((with_context(matrix, room_id, filters, matches).await, None));
+            }
+            if filters.matches(&message) {
+                matches.push(message);
+                if matches.len() >= limit {
+                    let next = SearchPosition::History { from, skip: i + 1 };
+                    return Ok((with_context(matrix, room_id, filters, matches).await, Some(next)));
+                }
+            }
+        }
+
+        // no end token (or an empty page) means the start of the room was reached
+        match page["end"].as_str() {
+            Some(end) if !chunk.is_empty() => {
+                from = Some(end.to_string());
+                skip = 0;
+            }
+            _ => return Ok((with_context(matrix, room_id, filters, matches).await, None)),
+        }
+    }
+
+    let next = SearchPosition::History { from, skip: 0 };
+    Ok((with_context(matrix, room_id, filters, matches).await, Some(next)))
+}
+
+/// attach the neighbouring messages of each match via the /context endpoint.
+/// a failed lookup just leaves the snippet without context.
+async fn with_context(
+    matrix: &MatrixClient,
+    room_id: &str,
+    filters: &Filters,
+    matches: Vec<Message>,
+) -> Vec<DmSearchResult> {
+    let mut results = Vec::with_capacity(matches.len());
+    for message in matches {
+        let context = match &message.event_id {
+            Some(event_id) => {
+                let url = format!(
+                    "{}/rooms/{}/context/{}?limit=2",
+                    matrix.client_api_base().await,
+                    encode_path_segment(room_id),
+                    encode_path_segment(event_id)
+                );
+                matrix.get_raw(&url).await.unwrap_or_else(|e| {
+                    tracing::debug!("no context for {}: {}", event_id, e);
+                    serde_json::Value::Null
+                })
+            }
+            None => serde_json::Value::Null,
+        };
+        results.push(DmSearchResult {
+            context_before: nearest_message(room_id, &context["events_before"], filters),
+            context_after: nearest_message(room_id, &context["events_after"], filters),
+            message,
+        });
+    }
+    results
+}
+
+// ── m.direct ──────────────────────────────────────────────────────────────────
+
+/// `direct` with `room_id` added to `other_user_id`'s rooms, every other entry
+/// left as it was — None when it's already listed
+fn with_direct_room(direct: serde_json::Value, other_user_id: &str, room_id: &str) -> Option<serde_json::Value> {
+    let mut map = match direct {
+        serde_json::Value::Object(map) => map,
+        _ => serde_json::Map::new(),
+    };
+    let rooms = map
+        .entry(other_user_id.to_string())
+        .or_insert_with(|| serde_json::json!([]));
+    if !rooms.is_array() {
+        *rooms = serde_json::json!([]);
+    }
+    let rooms = rooms.as_array_mut()?;
+    if rooms.iter().any(|r| r.as_str() == Some(room_id)) {
+        return None;
+    }
+    rooms.push(serde_json::json!(room_id));
+    Some(serde_json::Value::Object(map))
+}
+
+/// the session user's m.direct map — {} when they never had one
+async fn direct_rooms(matrix: &MatrixClient) -> Result<serde_json::Value, MatrixError> {
+    match matrix.get_account_data(DIRECT_ACCOUNT_DATA_TYPE).await {
+        Err(e) if e.is_not_found() => Ok(serde_json::json!({})),
+        other => other,
+    }
+}
+
+/// record `room_id` as the session user's dm with `other_user_id` in m.direct,
+/// so other matrix clients list it as one
+pub async fn remember_direct_room(matrix: &MatrixClient, other_user_id: &str, room_id: &str) -> Result<(), MatrixError> {
+    let direct = direct_rooms(matrix).await?;
+    match with_direct_room(direct, other_user_id, room_id) {
+        Some(updated) => matrix.set_account_data(DIRECT_ACCOUNT_DATA_TYPE, &updated).await,
+        None => Ok(()),
+    }
+}
+
+/// the room ids listed in an m.direct content
+pub fn direct_room_ids(direct: &serde_json::Value) -> HashSet<String> {
+    let Some(users) = direct.as_object() else {
+        return HashSet::new();
+    };
+    users
+        .values()
+        .filter_map(|rooms| rooms.as_array())
+        .flatten()
+        .filter_map(|room| room.as_str().map(str::to_string))
+        .collect()
+}
+
+/// the dm rooms of the user's friendships — these count as dms even before
+/// the user's m.direct lists them. empty without a database
+pub async fn friend_dm_rooms(state: &AppState, user_id: &str) -> HashSet<String> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return HashSet::new();
+    };
+    let rows = sqlx::query(
+        r#"
+        SELECT dm_room_id FROM friends
+        WHERE (requester_id = $1 OR addressee_id = $1)
+          AND status = 'accepted' AND dm_room_id IS NOT NULL
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await;
+    match rows {
+        Ok(rows) => rows.iter().map(|row| row.get("dm_room_id")).collect(),
+        Err(e) => {
+            tracing::warn!("failed to load dm rooms of {}: {}", user_id, e);
+            HashSet::new()
+        }
+    }
+}
+
+/// fill in friendships' missing dm rooms from m.direct. a room only counts if
+/// the caller is still joined to it; the newest such entry wins.
+#[utoipa::path(
+    get,
+    path = "/dms/recover",
+    tag = "dms",
+    security(("bearer" = [])),
+    responses((status = 200, body = RecoverDmsResponse), super::StatusErrors)
+)]
+async fn recover_dms(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<RecoverDmsResponse>, StatusCode> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        tracing::error!("dm recovery requires a database connection");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    matrix.user_id = Some(auth.user_id.clone());
+
+    let rows = sqlx::query(
+        r#"
+        SELECT requester_id, addressee_id FROM friends
+        WHERE (requester_id = $1 OR addressee_id = $1)
+          AND status = 'accepted' AND dm_room_id IS NULL
+        "#,
+    )
+    .bind(&auth.user_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to load friendships without a dm: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if rows.is_empty() {
+        return Ok(Json(RecoverDmsResponse { recovered: Vec::new() }));
+    }
+
+    let direct = direct_rooms(&matrix).await.map_err(|e| {
+        tracing::warn!("failed to read m.direct of {}: {}", auth.user_id, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    let joined: HashSet<String> = match matrix.get_joined_rooms().await {
+        Ok(joined) => joined.joined_rooms.into_iter().collect(),
+        Err(e) => {
+            tracing::warn!("failed to list joined rooms of {}: {}", auth.user_id, e);
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let mut recovered = Vec::new();
+    for row in rows {
+        let requester: String = row.get("requester_id");
+        let friend: String = if requester == auth.user_id { row.get("addressee_id") } else { requester };
+        let Some(room_id) = direct[&friend]
+            .as_array()
+            .and_then(|rooms| rooms.iter().rev().filter_map(|r| r.as_str()).find(|r| joined.contains(*r)))
+        else {
+            continue;
+        };
+
+        sqlx::query(
+            r#"
+            UPDATE friends SET dm_room_id = $1, updated_at = NOW()
+            WHERE ((requester_id = $2 AND addressee_id = $3)
+                OR (requester_id = $3 AND addressee_id = $2))
+              AND dm_room_id IS NULL
+            "#,
+        )
+        .bind(room_id)
+        .bind(&auth.user_id)
+        .bind(&friend)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to restore dm_room_id: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        recovered.push(RecoveredDm { user_id: friend, room_id: room_id.to_string() });
+    }
+
+    Ok(Json(RecoverDmsResponse { recovered }))
+}
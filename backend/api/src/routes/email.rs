@@ -0,0 +1,252 @@
+// email.rs — email address management and the no-login verify / unsubscribe links
+// all routes answer 503 when smtp or the database isn't configured
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sqlx::Row;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::email::{self, DigestFrequency};
+use crate::session::{AuthJson, AuthUser};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/account/email", get(get_email).post(set_email))
+        .route("/account/email/prefs", post(set_email_prefs))
+        .route("/account/email/verify", get(verify_email))
+        .route("/email/unsubscribe", get(unsubscribe))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(get_email, set_email, set_email_prefs, verify_email, unsubscribe))]
+pub struct ApiDoc;
+
+// ── request / response types ──────────────────────────────────────────────────
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetEmailRequest {
+    pub email: String,
+    /// "off" | "immediate" | "daily" — left unchanged when omitted
+    pub digest_frequency: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EmailPrefsRequest {
+    pub digest_frequency: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EmailStatusResponse {
+    pub email: String,
+    pub verified: bool,
+    pub digest_frequency: String,
+}
+
+// ── helpers ───────────────────────────────────────────────────────────────────
+
+/// require both smtp and a db pool or return 503
+macro_rules! require_email {
+    ($state:expr) => {
+        match ($state.email.as_ref(), $state.db_pool.as_ref()) {
+            (Some(email), Some(pool)) => (email, pool),
+            _ => {
+                tracing::debug!("email endpoints require SMTP_HOST and a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        }
+    };
+}
+
+// ── handlers ──────────────────────────────────────────────────────────────────
+
+#[utoipa::path(
+    get,
+    path = "/account/email",
+    tag = "email",
+    security(("bearer" = [])),
+    responses((status = 200, body = EmailStatusResponse), super::StatusErrors)
+)]
+async fn get_email(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<EmailStatusResponse>, StatusCode> {
+    let (_, pool) = require_email!(state);
+    let user_id = auth.user_id;
+
+    let row = sqlx::query("SELECT email, verified, digest_frequency FROM user_emails WHERE user_id = $1")
+        .bind(&user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to read user email: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(EmailStatusResponse {
+        email: row.get("email"),
+        verified: row.get("verified"),
+        digest_frequency: row.get("digest_frequency"),
+    }))
+}
+
+/// set or change the caller's email — sends a fresh verification link
+#[utoipa::path(
+    post,
+    path = "/account/email",
+    tag = "email",
+    request_body = SetEmailRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn set_email(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetEmailRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let (email_config, pool) = require_email!(state);
+
+    let address = req.email.trim().to_string();
+    if !email::is_valid_email(&address) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let frequency = match req.digest_frequency.as_deref() {
+        Some(f) => Some(DigestFrequency::parse(f).ok_or(StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+
+    let user_id = auth.user_id;
+
+    email::set_address(email_config, pool, &user_id, &address)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to store email: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if let Some(f) = frequency {
+        update_frequency(pool, &user_id, f).await?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/email/prefs",
+    tag = "email",
+    request_body = EmailPrefsRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn set_email_prefs(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<EmailPrefsRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let (_, pool) = require_email!(state);
+    let frequency = DigestFrequency::parse(&req.digest_frequency).ok_or(StatusCode::BAD_REQUEST)?;
+    let user_id = auth.user_id;
+    update_frequency(pool, &user_id, frequency).await
+}
+
+async fn update_frequency(
+    pool: &sqlx::PgPool,
+    user_id: &str,
+    frequency: DigestFrequency,
+) -> Result<StatusCode, StatusCode> {
+    let result = sqlx::query(
+        "UPDATE user_emails SET digest_frequency = $1, updated_at = NOW() WHERE user_id = $2",
+    )
+    .bind(frequency.as_str())
+    .bind(user_id)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to update digest frequency: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::OK)
+}
+
+/// target of the link in the verification email — no login required
+#[utoipa::path(
+    get,
+    path = "/account/email/verify",
+    tag = "email",
+    params(TokenQuery),
+    responses((status = 200, body = String, content_type = "text/plain"), super::StatusErrors)
+)]
+async fn verify_email(
+    state: State<Arc<AppState>>,
+    Query(params): Query<TokenQuery>,
+) -> Result<&'static str, StatusCode> {
+    let (_, pool) = require_email!(state);
+
+    let result = sqlx::query(
+        r#"
+        UPDATE user_emails SET verified = TRUE, verify_token = NULL, updated_at = NOW()
+        WHERE verify_token = $1
+        "#,
+    )
+    .bind(&params.token)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to verify email: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok("email verified — you can close this tab")
+}
+
+/// target of the link at the bottom of every digest — flips the pref to off without login
+#[utoipa::path(
+    get,
+    path = "/email/unsubscribe",
+    tag = "email",
+    params(TokenQuery),
+    responses((status = 200, body = String, content_type = "text/plain"), super::StatusErrors)
+)]
+async fn unsubscribe(
+    state: State<Arc<AppState>>,
+    Query(params): Query<TokenQuery>,
+) -> Result<&'static str, StatusCode> {
+    let (_, pool) = require_email!(state);
+
+    let result = sqlx::query(
+        r#"
+        UPDATE user_emails SET digest_frequency = 'off', updated_at = NOW()
+        WHERE unsubscribe_token = $1
+        "#,
+    )
+    .bind(&params.token)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to unsubscribe: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok("you've been unsubscribed from agora email digests")
+}
@@ -0,0 +1,300 @@
+use axum::{
+    extract::{Query, State, WebSocketUpgrade, ws::{Message, WebSocket}},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use crate::app_state::{AppState, ConnectionQueue, QueueItem};
+use crate::matrix::client::{EphemeralEvent, MatrixClient, MatrixError};
+use super::matrix_error;
+use super::sync::{build_response, SyncResponse, SyncViewer};
+
+// keeps idle sockets alive through proxies, and finds dead ones
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// a homeserver that answers an empty sync straight away (or ignores
+// `timeout`) is asked again after this, not in a tight loop
+const MIN_EMPTY_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+// after a failed sync that isn't the token's fault
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(5);
+// frames the sync loop may get ahead of the socket
+const SYNC_FRAME_BUFFER: usize = 256;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    access_token: String,
+    /// a `next_batch` from an earlier frame (or /sync) to resume from.
+    /// without it the stream starts at the moment of connecting.
+    since: Option<String>,
+    /// only send presence for these comma-separated user ids, as on /ws/presence
+    user_ids: Option<String>,
+}
+
+/// one thing that happened, as `{type, payload}` — the same shape as user
+/// events. presence and voice frames keep their /ws/presence shapes.
+#[derive(Debug, Serialize)]
+struct Frame {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    payload: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Typing {
+    room_id: String,
+    user_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Receipt {
+    room_id: String,
+    event_id: String,
+    user_id: String,
+    /// "m.read" | "m.read.private"
+    receipt_type: String,
+    timestamp: Option<i64>,
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/ws/events", get(ws_handler))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(ws_handler))]
+pub struct ApiDoc;
+
+/// the /sync loop, run server-side and pushed down one socket together with
+/// presence, voice and user events. voice comes from the rooms the caller was
+/// in when it connected. /sync stays for clients that can't hold a socket open.
+#[utoipa::path(
+    get,
+    path = "/ws/events",
+    operation_id = "events_ws",
+    tag = "events_ws",
+    params(EventsQuery),
+    responses(
+        (status = 101, description = "upgraded to a websocket"),
+        (status = 401, description = "the access_token is no good"),
+    )
+)]
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Query(params): Query<EventsQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(params.access_token.clone());
+    let user_id = match matrix.whoami().await {
+        Ok(whoami) => whoami.user_id,
+        Err(e) => return matrix_error(&e, StatusCode::BAD_GATEWAY),
+    };
+    let filter = params.user_ids.as_deref().map(|ids| {
+        ids.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(String::from)
+            .collect::<HashSet<String>>()
+    });
+    let queue = ConnectionQueue::for_user(state.ws_queue_capacity, Some(user_id.clone()))
+        .with_presence_filter(filter)
+        .with_joined_rooms(super::presence_ws::joined_rooms(&matrix).await);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, matrix, user_id, params.since, queue))
+        .into_response()
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    matrix: MatrixClient,
+    user_id: String,
+    since: Option<String>,
+    queue: ConnectionQueue,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let (connection_id, queue) = state.register_queue(queue);
+    let (frames, mut sync_frames) = mpsc::channel(SYNC_FRAME_BUFFER);
+    let sync_task = tokio::spawn(sync_loop(state.clone(), matrix, user_id, since, frames));
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+
+    loop {
+        let json = tokio::select! {
+            frame = sync_frames.recv() => match frame {
+                Some(json) => json,
+                // the sync loop gave up: the token is gone
+                None => break,
+            },
+            item = queue.pop() => {
+                let json = match item {
+                    QueueItem::Event(event) => serde_json::to_string(&event),
+                    QueueItem::Gap { dropped } => serde_json::to_string(&json!({ "type": "gap", "dropped": dropped })),
+                };
+                match json {
+                    Ok(json) => json,
+                    Err(_) => continue,
+                }
+            }
+            _ = state.shutdown.started() => {
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            }
+            _ = ping.tick() => {
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            msg = receiver.next() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(Message::Ping(data))) => {
+                        let _ = sender.send(Message::Pong(data)).await;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+        };
+        if sender.send(Message::Text(json)).await.is_err() {
+            break;
+        }
+    }
+
+    // dropping the in-flight request cancels the long-poll with it
+    sync_task.abort();
+    state.unregister_connection(connection_id);
+    let _ = sender.close().await;
+}
+
+/// long-poll /sync until the socket goes away (the send fails, or the task
+/// is aborted) or the token stops working
+async fn sync_loop(
+    state: Arc<AppState>,
+    matrix: MatrixClient,
+    user_id: String,
+    mut since: Option<String>,
+    frames: mpsc::Sender<String>,
+) {
+    // without a token to resume from, the first sync only finds the position
+    let mut catching_up = since.is_none();
+    loop {
+        let started = Instant::now();
+        let mut response = match matrix.sync(since.clone()).await {
+            Ok(response) => response,
+            Err(e) if token_is_dead(&e) => {
+                let frame = Frame { kind: "error", payload: json!({ "errcode": e.errcode(), "error": e.to_string() }) };
+                let _ = frames.send(serde_json::to_string(&frame).unwrap_or_default()).await;
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("event stream sync for {} failed: {}", user_id, e);
+                tokio::time::sleep(SYNC_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let mut ephemeral = Vec::new();
+        for (room_id, room) in response.rooms.iter_mut().flat_map(|r| r.join.iter_mut().flatten()) {
+            let events = room.ephemeral.take().map(|e| e.events).unwrap_or_default();
+            ephemeral.extend(events.into_iter().map(|event| (room_id.clone(), event)));
+        }
+        // block list and do-not-disturb can change while the socket is open
+        let viewer = SyncViewer::for_user(&state, user_id.clone()).await;
+        // nothing is sent from the first sync, so there's nothing to backfill
+        let backfill_to = if catching_up { None } else { since.as_deref() };
+        let sync = build_response(&state, &matrix, &viewer, response, backfill_to, None).await;
+        since = Some(sync.next_batch.clone());
+
+        let mut batch = if catching_up { Vec::new() } else { sync_frames(sync, ephemeral, &viewer) };
+        let quiet = batch.is_empty();
+        // the position to reconnect from, after everything it covers
+        if !quiet || catching_up {
+            let payload = json!({ "next_batch": since, "suppress_notifications": viewer.suppress_notifications });
+            batch.push(Frame { kind: "next_batch", payload });
+        }
+        catching_up = false;
+        for frame in batch {
+            let Ok(json) = serde_json::to_string(&frame) else { continue };
+            if frames.send(json).await.is_err() {
+                return;
+            }
+        }
+
+        if quiet {
+            tokio::time::sleep(MIN_EMPTY_SYNC_INTERVAL.saturating_sub(started.elapsed())).await;
+        }
+    }
+}
+
+fn token_is_dead(err: &MatrixError) -> bool {
+    matches!(err.errcode(), Some("M_UNKNOWN_TOKEN" | "M_MISSING_TOKEN" | "M_USER_DEACTIVATED"))
+}
+
+/// one frame per thing in the sync, in the order a client applies them:
+/// membership first, then timeline, then typing and receipts
+fn sync_frames(sync: SyncResponse, ephemeral: Vec<(String, EphemeralEvent)>, viewer: &SyncViewer) -> Vec<Frame> {
+    fn frames<T: Serialize>(kind: &'static str, items: Vec<T>) -> impl Iterator<Item = Frame> {
+        items.into_iter().filter_map(move |item| Some(Frame { kind, payload: serde_json::to_value(item).ok()? }))
+    }
+
+    let left_rooms: Vec<Value> = sync.left_rooms.into_iter().map(|room_id| json!({ "room_id": room_id })).collect();
+    let mut out: Vec<Frame> = frames("invite", sync.invites)
+        .chain(frames("left_room", left_rooms))
+        .chain(frames("message", sync.messages))
+        .chain(frames("call", sync.calls))
+        .chain(frames("raid", sync.raids))
+        .chain(frames("event", sync.events))
+        .chain(frames("redacted", sync.redacted))
+        .chain(frames("reaction", sync.reactions))
+        .chain(frames("timeline_gap", sync.gaps))
+        .collect();
+    for (room_id, event) in ephemeral {
+        match event.event_type.as_str() {
+            "m.typing" => out.extend(frames("typing", vec![typing(room_id, &event.content, viewer)])),
+            "m.receipt" => out.extend(frames("receipt", receipts(&room_id, &event.content))),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn typing(room_id: String, content: &Value, viewer: &SyncViewer) -> Typing {
+    let user_ids = content
+        .get("user_ids")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str())
+        .filter(|user_id| !viewer.blocked.contains(*user_id))
+        .map(String::from)
+        .collect();
+    Typing { room_id, user_ids }
+}
+
+/// m.receipt content is `{event_id: {receipt_type: {user_id: {ts}}}}`
+fn receipts(room_id: &str, content: &Value) -> Vec<Receipt> {
+    let mut out = Vec::new();
+    for (event_id, types) in content.as_object().into_iter().flatten() {
+        for (receipt_type, users) in types.as_object().into_iter().flatten() {
+            for (user_id, receipt) in users.as_object().into_iter().flatten() {
+                out.push(Receipt {
+                    room_id: room_id.to_string(),
+                    event_id: event_id.clone(),
+                    user_id: user_id.clone(),
+                    receipt_type: receipt_type.clone(),
+                    timestamp: receipt.get("ts").and_then(|v| v.as_i64()),
+                });
+            }
+        }
+    }
+    out
+}
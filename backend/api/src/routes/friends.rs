@@ -1,14 +1,21 @@
 use axum::{
     extract::{Json, Query, State},
     http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::collections::HashSet;
 use std::sync::Arc;
-use sqlx::Row;
-use crate::app_state::AppState;
-use crate::matrix::client::MatrixClient;
+use sqlx::{PgPool, Row};
+use crate::app_state::{AppState, UserEvent, WsEvent};
+use crate::matrix::client::{HistoryVisibility, MatrixClient};
+use crate::pagination::{encode_cursor, PageParams, Paginated};
+use crate::service_account;
+use crate::session::{AuthJson, AuthUser};
+use super::{agora_error, matrix_error};
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
@@ -18,33 +25,47 @@ pub fn router() -> Router<Arc<AppState>> {
         .route("/friends/reject", post(reject_friend))
         .route("/friends/remove", delete(remove_friend))
         .route("/friends/dm", post(get_or_create_dm))
+        .route("/friends/block", post(block_user))
+        .route("/friends/unblock", post(unblock_user))
+        .route("/friends/blocked", get(list_blocked))
 }
 
+#[derive(OpenApi)]
+#[openapi(paths(
+    list_friends, add_friend, accept_friend, reject_friend, remove_friend, get_or_create_dm,
+    block_user, unblock_user, list_blocked,
+))]
+pub struct ApiDoc;
+
 // ── request / response types ──────────────────────────────────────────────────
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct FriendsQuery {
-    pub access_token: String,
-    pub user_id: String,
+    /// page size — omit for the legacy un-paginated response
+    pub limit: Option<usize>,
+    /// cursor from a previous page's next_cursor
+    pub after: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct FriendActionRequest {
-    pub access_token: String,
-    /// the caller's own matrix user_id
-    pub user_id: String,
     /// the other party's matrix user_id
     pub friend_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AddFriendResponse {
+    /// the full user id the request went to
+    pub friend_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct DmRequest {
-    pub access_token: String,
-    pub user_id: String,
     pub friend_id: String,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, ToSchema)]
 pub struct FriendEntry {
     pub user_id: String,
     /// "pending_sent" | "pending_received" | "accepted"
@@ -52,12 +73,31 @@ pub struct FriendEntry {
     pub dm_room_id: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct FriendsListResponse {
     pub friends: Vec<FriendEntry>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BlockedEntry {
+    pub user_id: String,
+    /// unix ms
+    pub blocked_at: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BlockedListResponse {
+    pub blocked: Vec<BlockedEntry>,
+}
+
+/// position in the friends list: (updated_at in µs, row id), newest first
+#[derive(Debug, Serialize, Deserialize)]
+struct FriendsCursor {
+    ts: i64,
+    id: i32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct DmResponse {
     pub room_id: String,
 }
@@ -77,25 +117,79 @@ macro_rules! require_db {
     };
 }
 
+/// everyone `user_id` has blocked
+pub async fn blocked_users(pool: &PgPool, user_id: &str) -> Result<HashSet<String>, sqlx::Error> {
+    let rows = sqlx::query_scalar::<_, String>(
+        "SELECT addressee_id FROM friends WHERE requester_id = $1 AND status = 'blocked'",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().collect())
+}
+
+/// tell `target_user_id` what `user_id` did, with `user_id`'s name and avatar
+/// so the client can show it without another lookup
+async fn notify(state: &AppState, access_token: &str, kind: &'static str, user_id: &str, target_user_id: &str) {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(access_token.to_string());
+    let profile = matrix.get_profile(user_id.to_string()).await.unwrap_or_else(|e| {
+        tracing::debug!("failed to fetch profile of {}: {}", user_id, e);
+        Default::default()
+    });
+    state.publish(WsEvent::User(UserEvent {
+        target_user_id: target_user_id.to_string(),
+        kind,
+        payload: serde_json::json!({
+            "user_id": user_id,
+            "display_name": profile.displayname,
+            "avatar_url": profile.avatar_url,
+        }),
+    }));
+}
+
 // ── handlers ──────────────────────────────────────────────────────────────────
 
-/// list all friends (accepted + pending) for the calling user
+/// list all friends (accepted + pending) for the calling user.
+/// paginated (keyset on updated_at, id) when `limit` is supplied.
+#[utoipa::path(
+    get,
+    path = "/friends",
+    tag = "friends",
+    params(FriendsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = FriendsListResponse, description = "when `limit` or `after` is given the answer is a page instead: { items, next_cursor, total }"), super::StatusErrors)
+)]
 async fn list_friends(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<FriendsQuery>,
-) -> Result<Json<FriendsListResponse>, StatusCode> {
+) -> Result<Response, StatusCode> {
     let pool = require_db!(state);
 
+    let page = PageParams { limit: params.limit, after: params.after.clone() };
+    let cursor = page.cursor::<FriendsCursor>()?;
+    // fetch one extra row to know whether another page exists
+    // (LIMIT NULL means no limit for the legacy path)
+    let fetch_limit = page.is_paginated().then(|| page.limit() as i64 + 1);
+
     let rows = sqlx::query(
         r#"
-        SELECT requester_id, addressee_id, status, dm_room_id
+        SELECT id, requester_id, addressee_id, status, dm_room_id,
+               (EXTRACT(EPOCH FROM updated_at) * 1000000)::BIGINT AS updated_us
         FROM friends
         WHERE (requester_id = $1 OR addressee_id = $1)
           AND status != 'blocked'
-        ORDER BY updated_at DESC
+          AND ($2::BIGINT IS NULL
+               OR ((EXTRACT(EPOCH FROM updated_at) * 1000000)::BIGINT, id) < ($2, $3))
+        ORDER BY updated_at DESC, id DESC
+        LIMIT $4
         "#,
     )
-    .bind(&params.user_id)
+    .bind(&auth.user_id)
+    .bind(cursor.as_ref().map(|c| c.ts))
+    .bind(cursor.as_ref().map(|c| c.id).unwrap_or(0))
+    .bind(fetch_limit)
     .fetch_all(pool)
     .await
     .map_err(|e| {
@@ -103,50 +197,140 @@ async fn list_friends(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
-    let friends = rows
+    let mut positions: Vec<FriendsCursor> = Vec::with_capacity(rows.len());
+    let mut friends: Vec<FriendEntry> = rows
         .into_iter()
         .map(|row| {
-            let requester_id: String = row.get("requester_id");
-            let addressee_id: String = row.get("addressee_id");
-            let status: String = row.get("status");
-            let dm_room_id: Option<String> = row.get("dm_room_id");
-
-            let other = if requester_id == params.user_id {
-                addressee_id.clone()
-            } else {
-                requester_id.clone()
-            };
-
-            let status_label = if status == "accepted" {
-                "accepted".to_string()
-            } else if requester_id == params.user_id {
-                "pending_sent".to_string()
-            } else {
-                "pending_received".to_string()
-            };
-
-            FriendEntry {
-                user_id: other,
-                status: status_label,
-                dm_room_id,
-            }
+            positions.push(FriendsCursor { ts: row.get("updated_us"), id: row.get("id") });
+            friend_entry(&auth.user_id, &row)
         })
         .collect();
 
-    Ok(Json(FriendsListResponse { friends }))
+    if !page.is_paginated() {
+        return Ok(Json(FriendsListResponse { friends }).into_response());
+    }
+
+    let next_cursor = if friends.len() > page.limit() {
+        friends.truncate(page.limit());
+        positions.get(page.limit() - 1).map(encode_cursor)
+    } else {
+        None
+    };
+
+    let total: i64 = sqlx::query_scalar(
+        r#"
+        SELECT COUNT(*) FROM friends
+        WHERE (requester_id = $1 OR addressee_id = $1) AND status != 'blocked'
+        "#,
+    )
+    .bind(&auth.user_id)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0);
+
+    Ok(Json(Paginated { items: friends, next_cursor, total: Some(total as u64) }).into_response())
 }
 
-/// send a friend request
+/// shape a friends row from the point of view of `user_id`
+fn friend_entry(user_id: &str, row: &sqlx::postgres::PgRow) -> FriendEntry {
+    let requester_id: String = row.get("requester_id");
+    let addressee_id: String = row.get("addressee_id");
+    let status: String = row.get("status");
+    let dm_room_id: Option<String> = row.get("dm_room_id");
+
+    let other = if requester_id == user_id {
+        addressee_id.clone()
+    } else {
+        requester_id.clone()
+    };
+
+    let status_label = if status == "accepted" {
+        "accepted".to_string()
+    } else if requester_id == user_id {
+        "pending_sent".to_string()
+    } else {
+        "pending_received".to_string()
+    };
+
+    FriendEntry {
+        user_id: other,
+        status: status_label,
+        dm_room_id,
+    }
+}
+
+/// a full user id from what someone typed in the add-friend box: "alice",
+/// "@alice" or "@alice:example.org"
+pub fn normalize_user_id(input: &str, server_name: &str) -> String {
+    let localpart = input.trim().trim_start_matches('@');
+    if localpart.contains(':') {
+        format!("@{}", localpart)
+    } else {
+        format!("@{}:{}", localpart, server_name)
+    }
+}
+
+/// send a friend request — by full user id or plain username
+#[utoipa::path(
+    post,
+    path = "/friends/add",
+    tag = "friends",
+    request_body = FriendActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = AddFriendResponse), super::ErrorResponses)
+)]
 async fn add_friend(
     state: State<Arc<AppState>>,
-    Json(req): Json<FriendActionRequest>,
-) -> Result<StatusCode, StatusCode> {
+    AuthJson(auth, mut req): AuthJson<FriendActionRequest>,
+) -> Result<Json<AddFriendResponse>, Response> {
+    req.friend_id = normalize_user_id(&req.friend_id, &state.server_name);
+
+    // make sure the account exists before a row points at it
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    if let Err(e) = matrix.get_profile(req.friend_id.clone()).await {
+        if e.is_not_found() {
+            return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "user not found"));
+        }
+        tracing::warn!("failed to look up {}: {}", req.friend_id, e);
+        return Err(matrix_error(&e, StatusCode::BAD_GATEWAY));
+    }
+
+    let friend_id = req.friend_id.clone();
+    send_friend_request(&state, &auth, req).await.map_err(IntoResponse::into_response)?;
+    Ok(Json(AddFriendResponse { friend_id }))
+}
+
+async fn send_friend_request(state: &AppState, auth: &AuthUser, req: FriendActionRequest) -> Result<StatusCode, StatusCode> {
     let pool = require_db!(state);
 
-    if req.user_id == req.friend_id {
+    if auth.user_id == req.friend_id {
         return Err(StatusCode::BAD_REQUEST);
     }
 
+    // a block in either direction stops requests both ways
+    let blocked: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM friends
+            WHERE ((requester_id = $1 AND addressee_id = $2)
+                OR (requester_id = $2 AND addressee_id = $1))
+              AND status = 'blocked'
+        )
+        "#,
+    )
+    .bind(&auth.user_id)
+    .bind(&req.friend_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("db error checking blocks: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if blocked {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     // check for existing relationship in either direction
     let existing = sqlx::query(
         r#"
@@ -155,7 +339,7 @@ async fn add_friend(
            OR (requester_id = $2 AND addressee_id = $1)
         "#,
     )
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .bind(&req.friend_id)
     .fetch_optional(pool)
     .await
@@ -171,32 +355,36 @@ async fn add_friend(
         }
         // if they already sent us a request, auto-accept
         if status == "pending" {
-            sqlx::query(
+            let accepted = sqlx::query(
                 r#"
                 UPDATE friends SET status = 'accepted', updated_at = NOW()
                 WHERE requester_id = $1 AND addressee_id = $2
                 "#,
             )
             .bind(&req.friend_id)
-            .bind(&req.user_id)
+            .bind(&auth.user_id)
             .execute(pool)
             .await
             .map_err(|e| {
                 tracing::error!("failed to auto-accept friend request: {}", e);
                 StatusCode::INTERNAL_SERVER_ERROR
             })?;
+            // (a repeat of our own request matches nothing here)
+            if accepted.rows_affected() > 0 {
+                notify(state, &auth.access_token, "friend_request_accepted", &auth.user_id, &req.friend_id).await;
+            }
             return Ok(StatusCode::OK);
         }
     }
 
-    sqlx::query(
+    let inserted = sqlx::query(
         r#"
         INSERT INTO friends (requester_id, addressee_id, status)
         VALUES ($1, $2, 'pending')
         ON CONFLICT (requester_id, addressee_id) DO NOTHING
         "#,
     )
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .bind(&req.friend_id)
     .execute(pool)
     .await
@@ -205,13 +393,26 @@ async fn add_friend(
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
 
+    // a repeated request doesn't ping them again
+    if inserted.rows_affected() > 0 {
+        notify(state, &auth.access_token, "friend_request_received", &auth.user_id, &req.friend_id).await;
+    }
+
     Ok(StatusCode::OK)
 }
 
 /// accept an incoming friend request
+#[utoipa::path(
+    post,
+    path = "/friends/accept",
+    tag = "friends",
+    request_body = FriendActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
 async fn accept_friend(
     state: State<Arc<AppState>>,
-    Json(req): Json<FriendActionRequest>,
+    AuthJson(auth, req): AuthJson<FriendActionRequest>,
 ) -> Result<StatusCode, StatusCode> {
     let pool = require_db!(state);
 
@@ -222,7 +423,7 @@ async fn accept_friend(
         "#,
     )
     .bind(&req.friend_id)
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .execute(pool)
     .await
     .map_err(|e| {
@@ -234,13 +435,23 @@ async fn accept_friend(
         return Err(StatusCode::NOT_FOUND);
     }
 
+    notify(&state, &auth.access_token, "friend_request_accepted", &auth.user_id, &req.friend_id).await;
+
     Ok(StatusCode::OK)
 }
 
 /// reject / decline an incoming friend request
+#[utoipa::path(
+    post,
+    path = "/friends/reject",
+    tag = "friends",
+    request_body = FriendActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
 async fn reject_friend(
     state: State<Arc<AppState>>,
-    Json(req): Json<FriendActionRequest>,
+    AuthJson(auth, req): AuthJson<FriendActionRequest>,
 ) -> Result<StatusCode, StatusCode> {
     let pool = require_db!(state);
 
@@ -251,7 +462,7 @@ async fn reject_friend(
         "#,
     )
     .bind(&req.friend_id)
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .execute(pool)
     .await
     .map_err(|e| {
@@ -263,9 +474,17 @@ async fn reject_friend(
 }
 
 /// remove an accepted friend
+#[utoipa::path(
+    delete,
+    path = "/friends/remove",
+    tag = "friends",
+    request_body = FriendActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
 async fn remove_friend(
     state: State<Arc<AppState>>,
-    Json(req): Json<FriendActionRequest>,
+    AuthJson(auth, req): AuthJson<FriendActionRequest>,
 ) -> Result<StatusCode, StatusCode> {
     let pool = require_db!(state);
 
@@ -276,7 +495,7 @@ async fn remove_friend(
            OR (requester_id = $2 AND addressee_id = $1)
         "#,
     )
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .bind(&req.friend_id)
     .execute(pool)
     .await
@@ -288,16 +507,160 @@ async fn remove_friend(
     Ok(StatusCode::OK)
 }
 
+/// block a user. any request or friendship between the two goes away; only
+/// the blocker can lift the block, and a block the other side placed stays.
+#[utoipa::path(
+    post,
+    path = "/friends/block",
+    tag = "friends",
+    request_body = FriendActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn block_user(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<FriendActionRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let pool = require_db!(state);
+
+    if auth.user_id == req.friend_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let db_error = |e: sqlx::Error| {
+        tracing::error!("failed to block user: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    sqlx::query(
+        r#"
+        DELETE FROM friends
+        WHERE ((requester_id = $1 AND addressee_id = $2)
+            OR (requester_id = $2 AND addressee_id = $1))
+          AND status != 'blocked'
+        "#,
+    )
+    .bind(&auth.user_id)
+    .bind(&req.friend_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO friends (requester_id, addressee_id, status)
+        VALUES ($1, $2, 'blocked')
+        ON CONFLICT (requester_id, addressee_id)
+        DO UPDATE SET status = 'blocked', dm_room_id = NULL, updated_at = NOW()
+        "#,
+    )
+    .bind(&auth.user_id)
+    .bind(&req.friend_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(StatusCode::OK)
+}
+
+/// lift a block the caller placed
+#[utoipa::path(
+    post,
+    path = "/friends/unblock",
+    tag = "friends",
+    request_body = FriendActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn unblock_user(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<FriendActionRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let pool = require_db!(state);
+
+    let result = sqlx::query(
+        r#"
+        DELETE FROM friends
+        WHERE requester_id = $1 AND addressee_id = $2 AND status = 'blocked'
+        "#,
+    )
+    .bind(&auth.user_id)
+    .bind(&req.friend_id)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to unblock user: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// users the caller has blocked, most recent first
+#[utoipa::path(
+    get,
+    path = "/friends/blocked",
+    tag = "friends",
+    security(("bearer" = [])),
+    responses((status = 200, body = BlockedListResponse), super::StatusErrors)
+)]
+async fn list_blocked(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<BlockedListResponse>, StatusCode> {
+    let pool = require_db!(state);
+
+    let rows = sqlx::query(
+        r#"
+        SELECT addressee_id,
+               (EXTRACT(EPOCH FROM updated_at) * 1000)::BIGINT AS blocked_at
+        FROM friends
+        WHERE requester_id = $1 AND status = 'blocked'
+        ORDER BY updated_at DESC, id DESC
+        "#,
+    )
+    .bind(&auth.user_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to query blocked users: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let blocked = rows
+        .into_iter()
+        .map(|row| BlockedEntry { user_id: row.get("addressee_id"), blocked_at: row.get("blocked_at") })
+        .collect();
+
+    Ok(Json(BlockedListResponse { blocked }))
+}
+
 /// get the existing DM room for this friendship, or create one and cache it.
 /// always ensures the calling user is joined (handles the invite→join transition).
+#[utoipa::path(
+    post,
+    path = "/friends/dm",
+    tag = "friends",
+    request_body = DmRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = DmResponse), super::StatusErrors)
+)]
 async fn get_or_create_dm(
     state: State<Arc<AppState>>,
-    Json(req): Json<DmRequest>,
+    AuthJson(auth, req): AuthJson<DmRequest>,
 ) -> Result<Json<DmResponse>, StatusCode> {
     let pool = require_db!(state);
 
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    matrix.user_id = Some(auth.user_id.clone());
 
     // look up cached dm_room_id
     let row = sqlx::query(
@@ -307,7 +670,7 @@ async fn get_or_create_dm(
            OR (requester_id = $2 AND addressee_id = $1)
         "#,
     )
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .bind(&req.friend_id)
     .fetch_optional(pool)
     .await
@@ -324,6 +687,8 @@ async fn get_or_create_dm(
             if let Err(e) = matrix.join_room(room_id.clone()).await {
                 tracing::warn!("could not join cached dm room {} (may already be joined): {}", room_id, e);
             }
+            // the invitee's m.direct learns about the room the first time they open it
+            remember_direct_room(&matrix, &req.friend_id, &room_id).await;
             return Ok(Json(DmResponse { room_id }));
         }
     }
@@ -338,7 +703,7 @@ async fn get_or_create_dm(
         .to_string();
 
     let create_response = matrix
-        .create_dm_room(req.friend_id.clone(), friend_short)
+        .create_dm_room(req.friend_id.clone(), friend_short, HistoryVisibility::Invited)
         .await
         .map_err(|e| {
             tracing::error!("failed to create dm room: {}", e);
@@ -346,6 +711,8 @@ async fn get_or_create_dm(
         })?;
 
     let room_id = create_response.room_id.clone();
+    // DMs push through the service account's sync, so it has to be in them
+    service_account::bring_in(&state, &matrix, &room_id).await;
 
     // cache the room id in the friendship row
     sqlx::query(
@@ -356,7 +723,7 @@ async fn get_or_create_dm(
         "#,
     )
     .bind(&room_id)
-    .bind(&req.user_id)
+    .bind(&auth.user_id)
     .bind(&req.friend_id)
     .execute(pool)
     .await
@@ -364,6 +731,14 @@ async fn get_or_create_dm(
         tracing::warn!("failed to cache dm_room_id: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
+    remember_direct_room(&matrix, &req.friend_id, &room_id).await;
 
     Ok(Json(DmResponse { room_id }))
 }
+
+/// m.direct is for other clients' benefit — the friends row stays the source of truth
+async fn remember_direct_room(matrix: &MatrixClient, friend_id: &str, room_id: &str) {
+    if let Err(e) = super::dms::remember_direct_room(matrix, friend_id, room_id).await {
+        tracing::warn!("failed to record dm {} in m.direct: {}", room_id, e);
+    }
+}
@@ -1,15 +1,253 @@
 use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
     Router,
     routing::get,
 };
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sqlx::Row;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use crate::app_state::AppState;
+use crate::matrix::retry::RetryPolicy;
+
+// each dependency gets this long to answer a readiness probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+// load balancers probe every second or so; this many seconds share one answer
+const READINESS_CACHE_TTL: Duration = Duration::from_secs(2);
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/health", get(health_check))
+        .route("/health/ready", get(readiness))
+        .route("/health/migrations", get(migration_status))
+        .route("/health/features", get(features))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(health_check, readiness, migration_status, features))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FeaturesQuery {
+    /// lets the upload limit be fetched when it isn't cached yet
+    pub access_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Features {
+    /// homeserver upload limit in bytes — None when unknown or unlimited
+    pub max_upload_size: Option<u64>,
+    pub translation: bool,
+    pub email_digests: bool,
+    /// /search/messages has an index to answer from
+    pub message_search: bool,
+}
+
+/// optional capabilities of this deployment, for the frontend to gate ui on
+#[utoipa::path(
+    get,
+    path = "/health/features",
+    tag = "health",
+    params(FeaturesQuery),
+    responses((status = 200, body = Features))
+)]
+async fn features(
+    state: State<Arc<AppState>>,
+    Query(params): Query<FeaturesQuery>,
+) -> Json<Features> {
+    // the media config endpoint needs auth — without a token report what's cached
+    let max_upload_size = match params.access_token {
+        Some(token) => {
+            let mut matrix = state.matrix();
+            matrix.access_token = Some(token);
+            state.media_limit.get(&matrix).await
+        }
+        None => state.media_limit.cached(),
+    };
+
+    Json(Features {
+        max_upload_size,
+        translation: state.translate.is_some(),
+        email_digests: state.email.is_some(),
+        message_search: crate::search::enabled(&state).await,
+    })
 }
 
+/// liveness: the process is up. says nothing about its dependencies — see /health/ready
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "health",
+    responses((status = 200, body = String, content_type = "text/plain"))
+)]
 async fn health_check() -> &'static str {
     "ok"
 }
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DependencyStatus {
+    /// "ok", "down" or "not_configured"
+    #[schema(value_type = String)]
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Readiness {
+    /// false only when the homeserver is unreachable — nothing works without it
+    pub ready: bool,
+    /// "ok", "degraded" (postgres or redis missing, which the api gets by
+    /// without) or "down"
+    #[schema(value_type = String)]
+    pub status: &'static str,
+    pub homeserver: DependencyStatus,
+    pub database: DependencyStatus,
+    pub redis: DependencyStatus,
+}
+
+/// the last readiness answer and when it was worked out
+#[derive(Default)]
+pub struct ReadinessCache {
+    entry: Mutex<Option<(Readiness, Instant)>>,
+}
+
+impl ReadinessCache {
+    fn fresh(&self) -> Option<Readiness> {
+        let entry = self.entry.lock().unwrap_or_else(|e| e.into_inner());
+        entry
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() < READINESS_CACHE_TTL)
+            .map(|(readiness, _)| readiness.clone())
+    }
+
+    fn store(&self, readiness: &Readiness) {
+        *self.entry.lock().unwrap_or_else(|e| e.into_inner()) = Some((readiness.clone(), Instant::now()));
+    }
+}
+
+/// readiness: 200 while the homeserver answers, 503 when it doesn't
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "health",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, body = Readiness, description = "the homeserver is unreachable"),
+    )
+)]
+async fn readiness(state: State<Arc<AppState>>) -> Response {
+    let readiness = match state.readiness.fresh() {
+        Some(readiness) => readiness,
+        None => {
+            let readiness = check_dependencies(&state).await;
+            state.readiness.store(&readiness);
+            readiness
+        }
+    };
+    let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness)).into_response()
+}
+
+async fn check_dependencies(state: &AppState) -> Readiness {
+    // a probe reports what it finds now — retrying would only hide it
+    let homeserver = state.matrix().with_retry(RetryPolicy { max_retries: 0, ..state.matrix_retry });
+    let (homeserver, database, redis) = tokio::join!(
+        probe(Some(homeserver.get_versions())),
+        probe(state.db_pool.as_ref().map(|pool| sqlx::query("SELECT 1").execute(pool))),
+        probe(state.redis.is_configured().then_some(async {
+            let Some(mut redis) = state.get_redis().await else {
+                return Err("not connected".to_string());
+            };
+            let pong: redis::RedisResult<String> = redis::cmd("PING").query_async(&mut redis).await;
+            pong.map_err(|e| e.to_string())
+        })),
+    );
+
+    let ready = homeserver.status == "ok";
+    let status = match (ready, database.status == "ok" && redis.status == "ok") {
+        (false, _) => "down",
+        (true, false) => "degraded",
+        (true, true) => "ok",
+    };
+    Readiness { ready, status, homeserver, database, redis }
+}
+
+/// time one dependency check, giving up after PROBE_TIMEOUT. None when the
+/// dependency isn't configured
+async fn probe<T, E: std::fmt::Display>(
+    check: Option<impl std::future::Future<Output = Result<T, E>>>,
+) -> DependencyStatus {
+    let Some(check) = check else {
+        return DependencyStatus { status: "not_configured", latency_ms: None, error: None };
+    };
+    let started = Instant::now();
+    let error = match tokio::time::timeout(PROBE_TIMEOUT, check).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("no answer within {}s", PROBE_TIMEOUT.as_secs())),
+    };
+    DependencyStatus {
+        status: if error.is_some() { "down" } else { "ok" },
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        error,
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MigrationStatus {
+    /// newest migration applied to the database (None on an empty database)
+    pub applied_version: Option<i64>,
+    /// newest migration compiled into this binary
+    pub latest_version: Option<i64>,
+    /// false when a migration was started but didn't finish
+    pub clean: bool,
+    pub up_to_date: bool,
+}
+
+/// which migration the database is on — 503 when there's no database
+#[utoipa::path(
+    get,
+    path = "/health/migrations",
+    tag = "health",
+    responses((status = 200, body = MigrationStatus), super::StatusErrors)
+)]
+async fn migration_status(
+    state: State<Arc<AppState>>,
+) -> Result<Json<MigrationStatus>, StatusCode> {
+    let pool = state.db_pool.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let latest_version = sqlx::migrate!("./migrations")
+        .migrations
+        .iter()
+        .map(|m| m.version)
+        .max();
+
+    // _sqlx_migrations doesn't exist until the first migration ran
+    let row = sqlx::query("SELECT version, success FROM _sqlx_migrations ORDER BY version DESC LIMIT 1")
+        .fetch_optional(pool)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("failed to read migration table: {}", e);
+            None
+        });
+
+    let (applied_version, clean) = match row {
+        Some(row) => (Some(row.get::<i64, _>("version")), row.get::<bool, _>("success")),
+        None => (None, true),
+    };
+
+    Ok(Json(MigrationStatus {
+        applied_version,
+        latest_version,
+        clean,
+        up_to_date: clean && applied_version == latest_version,
+    }))
+}
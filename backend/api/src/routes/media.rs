@@ -0,0 +1,182 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+    Router,
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::client::{MatrixClient, MatrixError};
+use crate::session::AuthUser;
+use super::{agora_error, matrix_error};
+
+// cap on buffered upload bodies when the homeserver doesn't advertise a limit
+const FALLBACK_MAX_UPLOAD: u64 = 100 * 1024 * 1024;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/media/upload", post(upload_media))
+        .route("/media/download/:server_name/:media_id", get(download_media))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(upload_media, download_media))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadQuery {
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UploadResponse {
+    pub content_uri: String,
+}
+
+/// 413 carrying the limit so the client can tell the user what fits
+fn too_large(limit: Option<u64>) -> Response {
+    let body = serde_json::json!({
+        "errcode": "M_TOO_LARGE",
+        "error": "file exceeds the homeserver upload limit",
+        "max_upload_size": limit,
+    });
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+}
+
+/// upload a request body to the homeserver after checking it against the
+/// advertised limit. the declared Content-Length is checked before anything is
+/// read, and the body is cut off at the limit in case the header lied.
+/// shared by every endpoint that accepts a file.
+pub async fn upload_checked(
+    state: &AppState,
+    matrix: &MatrixClient,
+    headers: &HeaderMap,
+    filename: Option<&str>,
+    body: Body,
+) -> Result<String, Response> {
+    let limit = state.media_limit.get(matrix).await;
+
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let (Some(declared), Some(limit)) = (declared, limit) {
+        if declared > limit {
+            return Err(too_large(Some(limit)));
+        }
+    }
+
+    let cap = limit.unwrap_or(FALLBACK_MAX_UPLOAD);
+    let bytes = axum::body::to_bytes(body, cap as usize)
+        .await
+        .map_err(|_| too_large(limit))?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream");
+
+    upload_bytes(state, matrix, content_type, filename, bytes.to_vec(), limit).await
+}
+
+/// hand already-read bytes to the homeserver, mapping its refusals the same
+/// way for every upload. `limit` is only echoed back in a 413.
+pub async fn upload_bytes(
+    state: &AppState,
+    matrix: &MatrixClient,
+    content_type: &str,
+    filename: Option<&str>,
+    bytes: Vec<u8>,
+    limit: Option<u64>,
+) -> Result<String, Response> {
+    match matrix.upload_media(content_type, filename, bytes).await {
+        Ok(content_uri) => Ok(content_uri),
+        Err(MatrixError::MediaTooLarge(e)) => {
+            // our cached limit was wrong — pick up the new one next time
+            tracing::info!("homeserver rejected upload as too large: {}", e);
+            state.media_limit.invalidate();
+            Err(too_large(limit))
+        }
+        Err(MatrixError::MediaForbidden(e)) => {
+            tracing::warn!("homeserver refused upload: {}", e);
+            Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "the homeserver refused this upload"))
+        }
+        Err(e) => {
+            tracing::error!("upload failed: {}", e);
+            Err(StatusCode::BAD_GATEWAY.into_response())
+        }
+    }
+}
+
+/// raw file body in, mxc:// uri out — used for message attachments
+#[utoipa::path(
+    post,
+    path = "/media/upload",
+    tag = "media",
+    params(UploadQuery),
+    request_body(content = [u8], content_type = "application/octet-stream"),
+    security(("bearer" = [])),
+    responses((status = 200, body = UploadResponse), super::ErrorResponses)
+)]
+async fn upload_media(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<UploadResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let content_uri = upload_checked(&state, &matrix, &headers, params.filename.as_deref(), body).await?;
+    Ok(Json(UploadResponse { content_uri }))
+}
+
+/// where this api serves an mxc:// uri over plain http, for clients that can't
+/// reach the homeserver themselves. None if `mxc` isn't an mxc:// uri.
+pub fn download_path(mxc: &str) -> Option<String> {
+    if !crate::content::is_mxc_uri(mxc) {
+        return None;
+    }
+    let (server_name, media_id) = mxc.strip_prefix("mxc://")?.split_once('/')?;
+    Some(format!(
+        "/media/download/{}/{}",
+        urlencoding::encode(server_name),
+        urlencoding::encode(media_id)
+    ))
+}
+
+/// proxy a download from the homeserver's media repo
+#[utoipa::path(
+    get,
+    path = "/media/download/{server_name}/{media_id}",
+    tag = "media",
+    params(
+        ("server_name" = String, Path, description = "the mxc:// uri's server"),
+        ("media_id" = String, Path),
+    ),
+    responses((status = 200, body = [u8], content_type = "application/octet-stream"), super::ErrorResponses)
+)]
+async fn download_media(
+    state: State<Arc<AppState>>,
+    Path((server_name, media_id)): Path<(String, String)>,
+) -> Result<Response, Response> {
+    let (content_type, bytes) = state
+        .matrix()
+        .download_media(&server_name, &media_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_GATEWAY))?;
+    // whatever was uploaded is served from our origin, so never let it run as a page
+    let headers = [
+        (header::CONTENT_TYPE, content_type),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        (header::CONTENT_SECURITY_POLICY, "sandbox".to_string()),
+    ];
+    Ok((headers, bytes).into_response())
+}
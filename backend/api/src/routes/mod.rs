@@ -1,9 +1,132 @@
+pub mod admin;
+pub mod announcements;
 pub mod auth;
+pub mod dms;
+pub mod email;
+pub mod events_ws;
 pub mod friends;
 pub mod health;
+pub mod media;
+pub mod notifications;
 pub mod presence_ws;
+pub mod preview;
+pub mod reports;
 pub mod rooms;
+pub mod search;
+pub mod server_events;
+pub mod server_templates;
 pub mod servers;
 pub mod sync;
 pub mod users;
 pub mod voice;
+pub mod webhooks;
+
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}, Json};
+use serde::Serialize;
+use utoipa::{IntoResponses, ToSchema};
+use crate::matrix::authz::AuthzError;
+use crate::matrix::client::MatrixError;
+
+/// an error with a matrix-style `{errcode, error}` body, for failures the
+/// client needs to tell apart from a bare status code
+pub fn agora_error(status: StatusCode, errcode: &str, error: impl Into<String>) -> Response {
+    let body = serde_json::json!({ "errcode": errcode, "error": error.into() });
+    (status, Json(body)).into_response()
+}
+
+/// pass a homeserver rejection on when it means something to the client —
+/// a dead token, a permission or rate limit — keeping its errcode.
+/// everything else becomes a bare `fallback`.
+pub fn matrix_error(err: &MatrixError, fallback: StatusCode) -> Response {
+    let MatrixError::MatrixApiError { errcode, error, retry_after_ms, .. } = err else {
+        return fallback.into_response();
+    };
+    let status = match errcode.as_str() {
+        "M_UNKNOWN_TOKEN" | "M_MISSING_TOKEN" => StatusCode::UNAUTHORIZED,
+        "M_FORBIDDEN" | "M_USER_DEACTIVATED" => StatusCode::FORBIDDEN,
+        "M_NOT_FOUND" => StatusCode::NOT_FOUND,
+        "M_LIMIT_EXCEEDED" => StatusCode::TOO_MANY_REQUESTS,
+        _ => return fallback.into_response(),
+    };
+
+    let mut body = serde_json::json!({ "errcode": errcode, "error": error });
+    if let Some(ms) = retry_after_ms {
+        body["retry_after_ms"] = serde_json::json!(ms);
+    }
+    let mut response = (status, Json(body)).into_response();
+    if let (StatusCode::TOO_MANY_REQUESTS, Some(ms)) = (status, retry_after_ms) {
+        // whole seconds, rounded up so clients never retry early
+        response.headers_mut().insert(header::RETRY_AFTER, ms.div_ceil(1000).into());
+    }
+    response
+}
+
+/// a failed permission check: 401 for a dead token, the homeserver's refusal
+/// when the server can't be read, and otherwise 403 naming the permission
+/// that's missing or the role that's out of reach
+pub fn authz_error(err: &AuthzError) -> Response {
+    match err {
+        AuthzError::Unauthenticated(e) => matrix_error(e, StatusCode::UNAUTHORIZED),
+        AuthzError::Unreadable(e) => matrix_error(e, StatusCode::FORBIDDEN),
+        AuthzError::Missing(permission) => {
+            let body = serde_json::json!({
+                "errcode": "AGORA_MISSING_PERMISSION",
+                "error": format!("this needs the {} permission", permission.as_str()),
+                "permission": permission.as_str(),
+            });
+            (StatusCode::FORBIDDEN, Json(body)).into_response()
+        }
+        AuthzError::Hierarchy { role_id } => {
+            let body = serde_json::json!({
+                "errcode": "AGORA_ROLE_HIERARCHY",
+                "error": "you can only manage roles below your highest role",
+                "role_id": role_id,
+            });
+            (StatusCode::FORBIDDEN, Json(body)).into_response()
+        }
+    }
+}
+
+/// the body agora_error and matrix_error send. permission errors add
+/// `permission` or `role_id`, rate limits `retry_after_ms`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// M_FORBIDDEN, M_NOT_FOUND, AGORA_MISSING_PERMISSION, ...
+    pub errcode: String,
+    pub error: String,
+}
+
+/// what a handler returning `Result<_, Response>` can fail with, for the spec
+#[derive(IntoResponses)]
+pub enum ErrorResponses {
+    #[response(status = 400, description = "a missing or invalid parameter")]
+    BadRequest(ErrorBody),
+    #[response(status = 401, description = "no access token, or a dead one")]
+    Unauthorized(ErrorBody),
+    #[response(status = 403, description = "not allowed — errcode says why")]
+    Forbidden(ErrorBody),
+    #[response(status = 404, description = "no such room, user or server")]
+    NotFound(ErrorBody),
+    #[response(status = 429, description = "rate limited — see Retry-After")]
+    TooManyRequests(ErrorBody),
+    #[response(status = 502, description = "the homeserver failed")]
+    BadGateway(ErrorBody),
+}
+
+/// the same for handlers failing with a bare status code. the 401 and 429
+/// still carry an ErrorBody, since the session and rate limit layers send them
+#[derive(IntoResponses)]
+pub enum StatusErrors {
+    #[response(status = 400, description = "a missing or invalid parameter")]
+    BadRequest,
+    #[response(status = 401, description = "no access token, or a dead one")]
+    Unauthorized(ErrorBody),
+    #[response(status = 403, description = "not allowed")]
+    Forbidden,
+    #[response(status = 404, description = "not found")]
+    NotFound,
+    #[response(status = 429, description = "rate limited — see Retry-After")]
+    TooManyRequests(ErrorBody),
+    #[response(status = 502, description = "the homeserver failed")]
+    BadGateway,
+}
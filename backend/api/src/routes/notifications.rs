@@ -0,0 +1,347 @@
+// notifications.rs — web push subscriptions and per-room notification levels
+// for the settings page. the push routes answer 503 when VAPID keys or the
+// database aren't configured, the level routes when the database isn't
+
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::notification_levels::{self, Level, RoomLevel};
+use crate::push::{self, PushPayload};
+use crate::session::{AuthJson, AuthUser};
+use super::{agora_error, matrix_error, users};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/notifications/push-key", get(push_key))
+        .route("/notifications/subscribe", post(subscribe).delete(unsubscribe))
+        .route("/notifications/test", post(test_push))
+        .route("/notifications/settings", get(get_levels))
+        .route("/rooms/notifications", put(set_level))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(push_key, subscribe, unsubscribe, test_push, get_levels, set_level))]
+pub struct ApiDoc;
+
+// ── request / response types ──────────────────────────────────────────────────
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PushKeyResponse {
+    /// the VAPID public key, base64url — the browser's applicationServerKey
+    pub public_key: String,
+}
+
+/// the browser's PushSubscription, as PushSubscription.toJSON() gives it
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SubscribeRequest {
+    pub endpoint: String,
+    pub keys: SubscriptionKeys,
+    /// keep pushing to this browser while on do not disturb
+    #[serde(default)]
+    pub during_dnd: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UnsubscribeRequest {
+    pub endpoint: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TestPushResponse {
+    /// subscriptions the push went out to
+    pub sent: usize,
+    /// subscriptions the push service had dropped, now deleted
+    pub removed: usize,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetLevelRequest {
+    /// a room, or a space to set the level for everything under it
+    pub room_id: String,
+    /// "all" | "mentions" | "muted", or "default" to go back to inheriting
+    pub level: String,
+    /// unix ms to stop applying at — for "mute for an hour"
+    pub until: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LevelsResponse {
+    /// the levels that apply right now, by room or space id. rooms not listed
+    /// follow their space, and notify for everything outside one
+    pub rooms: HashMap<String, RoomLevel>,
+}
+
+// ── helpers ───────────────────────────────────────────────────────────────────
+
+/// require both VAPID keys and a db pool or return 503
+macro_rules! require_push {
+    ($state:expr) => {
+        match ($state.push.as_ref(), $state.db_pool.as_ref()) {
+            (Some(push), Some(pool)) => (push, pool),
+            _ => {
+                tracing::debug!("push endpoints require VAPID keys and a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        }
+    };
+}
+
+/// require a db pool or return 503
+macro_rules! require_db {
+    ($state:expr) => {
+        match $state.db_pool.as_ref() {
+            Some(pool) => pool,
+            None => {
+                tracing::debug!("notification levels require a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+        }
+    };
+}
+
+async fn levels_response(pool: &sqlx::PgPool, user_id: &str) -> Result<Json<LevelsResponse>, Response> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let rooms = notification_levels::for_user(pool, user_id, now).await.map_err(|e| {
+        tracing::error!("failed to read notification levels: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    Ok(Json(LevelsResponse { rooms }))
+}
+
+// ── handlers ──────────────────────────────────────────────────────────────────
+
+/// the key to subscribe with
+#[utoipa::path(
+    get,
+    path = "/notifications/push-key",
+    tag = "notifications",
+    security(("bearer" = [])),
+    responses((status = 200, body = PushKeyResponse), super::StatusErrors)
+)]
+async fn push_key(
+    state: State<Arc<AppState>>,
+    _auth: AuthUser,
+) -> Result<Json<PushKeyResponse>, StatusCode> {
+    let (push, _) = require_push!(state);
+    Ok(Json(PushKeyResponse { public_key: push.vapid_public_key.clone() }))
+}
+
+/// store the browser's subscription for the caller. subscribing the same
+/// browser again replaces its keys, and moves it over if someone else had it
+#[utoipa::path(
+    post,
+    path = "/notifications/subscribe",
+    tag = "notifications",
+    request_body = SubscribeRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn subscribe(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SubscribeRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let (_, pool) = require_push!(state);
+    // the api makes requests to this url, so nothing but a push service's https
+    if !req.endpoint.starts_with("https://") || req.keys.p256dh.is_empty() || req.keys.auth.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    let device_id = matrix.whoami().await.ok().and_then(|whoami| whoami.device_id);
+
+    sqlx::query(
+        r#"
+        INSERT INTO push_subscriptions (user_id, device_id, endpoint, p256dh, auth, during_dnd)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (endpoint) DO UPDATE
+            SET user_id = EXCLUDED.user_id,
+                device_id = EXCLUDED.device_id,
+                p256dh = EXCLUDED.p256dh,
+                auth = EXCLUDED.auth,
+                during_dnd = EXCLUDED.during_dnd,
+                updated_at = NOW()
+        "#,
+    )
+    .bind(&auth.user_id)
+    .bind(device_id)
+    .bind(&req.endpoint)
+    .bind(&req.keys.p256dh)
+    .bind(&req.keys.auth)
+    .bind(req.during_dnd)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to store push subscription: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::OK)
+}
+
+/// forget one of the caller's subscriptions — 404 if they don't have it
+#[utoipa::path(
+    delete,
+    path = "/notifications/subscribe",
+    tag = "notifications",
+    request_body = UnsubscribeRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn unsubscribe(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<UnsubscribeRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let (_, pool) = require_push!(state);
+    let result = sqlx::query("DELETE FROM push_subscriptions WHERE user_id = $1 AND endpoint = $2")
+        .bind(&auth.user_id)
+        .bind(&req.endpoint)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to delete push subscription: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::OK)
+}
+
+/// push a test notification to every browser the caller subscribed — 404
+/// when there are none
+#[utoipa::path(
+    post,
+    path = "/notifications/test",
+    tag = "notifications",
+    security(("bearer" = [])),
+    responses((status = 200, body = TestPushResponse), super::StatusErrors)
+)]
+async fn test_push(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<TestPushResponse>, StatusCode> {
+    let (_, pool) = require_push!(state);
+    let subscriptions = push::subscriptions(pool, &auth.user_id, false).await.map_err(|e| {
+        tracing::error!("failed to read push subscriptions: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if subscriptions.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let payload = PushPayload::new("test", &auth.user_id, "", None, "notifications are working");
+    let (sent, removed) = push::deliver(&state, pool, &subscriptions, &payload).await;
+    Ok(Json(TestPushResponse { sent, removed }))
+}
+
+/// the caller's notification levels, expired ones left out
+#[utoipa::path(
+    get,
+    path = "/notifications/settings",
+    tag = "notifications",
+    security(("bearer" = [])),
+    responses((status = 200, body = LevelsResponse), super::StatusErrors)
+)]
+async fn get_levels(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<LevelsResponse>, Response> {
+    let pool = require_db!(state);
+    levels_response(pool, &auth.user_id).await
+}
+
+/// set how much a room (or a whole space) notifies the caller — sync's badges
+/// and web push follow it. answers with all of the caller's levels
+#[utoipa::path(
+    put,
+    path = "/rooms/notifications",
+    tag = "notifications",
+    request_body = SetLevelRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = LevelsResponse), super::ErrorResponses)
+)]
+async fn set_level(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetLevelRequest>,
+) -> Result<Json<LevelsResponse>, Response> {
+    let pool = require_db!(state);
+    let level = match req.level.as_str() {
+        "default" => None,
+        level => Some(Level::parse(level).ok_or_else(|| {
+            agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "level must be all, mentions, muted or default")
+        })?),
+    };
+    if req.until.is_some_and(|until| until <= chrono::Utc::now().timestamp_millis()) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "until is in the past"));
+    }
+
+    // account data for the user's other clients; an empty event is a cleared one
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    matrix.user_id = Some(auth.user_id.clone());
+    let content = match level {
+        Some(level) => serde_json::json!({ "level": level.as_str(), "until": req.until }),
+        None => serde_json::json!({}),
+    };
+    let data_type = format!("{}{}", notification_levels::ACCOUNT_DATA_PREFIX, req.room_id);
+    match matrix.set_account_data(&data_type, &content).await {
+        Ok(()) => {}
+        // the copy below is the one the api goes by anyway
+        Err(e) if users::rejects_custom_account_data(&e) => {
+            tracing::debug!("homeserver refused {}: {}", data_type, e);
+        }
+        Err(e) => {
+            tracing::warn!("failed to write {} for {}: {}", data_type, auth.user_id, e);
+            return Err(matrix_error(&e, StatusCode::BAD_GATEWAY));
+        }
+    }
+
+    let stored = match level {
+        Some(level) => {
+            sqlx::query(
+                r#"
+                INSERT INTO room_notification_levels (user_id, room_id, level, until_ts)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (user_id, room_id) DO UPDATE
+                    SET level = EXCLUDED.level, until_ts = EXCLUDED.until_ts, updated_at = NOW()
+                "#,
+            )
+            .bind(&auth.user_id)
+            .bind(&req.room_id)
+            .bind(level.as_str())
+            .bind(req.until)
+            .execute(pool)
+            .await
+        }
+        None => {
+            sqlx::query("DELETE FROM room_notification_levels WHERE user_id = $1 AND room_id = $2")
+                .bind(&auth.user_id)
+                .bind(&req.room_id)
+                .execute(pool)
+                .await
+        }
+    };
+    stored.map_err(|e| {
+        tracing::error!("failed to store notification level: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+
+    levels_response(pool, &auth.user_id).await
+}
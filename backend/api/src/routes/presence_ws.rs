@@ -1,91 +1,350 @@
 use axum::{
     extract::{Query, State, WebSocketUpgrade, ws::{Message, WebSocket}},
-    response::IntoResponse,
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::get,
+    Json,
     Router,
 };
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use redis::AsyncCommands;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::collections::HashSet;
 use std::sync::Arc;
-use crate::app_state::{AppState, PresenceEvent};
+use crate::app_state::{AppState, ConnectionQueue, PresenceEvent, QueueItem, Subscription, TypingEvent};
+use crate::matrix::client::MatrixClient;
+use super::matrix_error;
+use super::users::public_presence;
 
-#[derive(Deserialize)]
+// bounds for a client-requested queue size
+const MIN_QUEUE_CAPACITY: usize = 16;
+const MAX_QUEUE_CAPACITY: usize = 4096;
+
+// users per snapshot frame
+const SNAPSHOT_CHUNK_SIZE: usize = 100;
+// keys asked for per SCAN call
+const SNAPSHOT_SCAN_COUNT: usize = 500;
+// cap on a client's user_ids filter
+const MAX_FILTER_USERS: usize = 5000;
+// cap on the rooms one subscribe message may name
+const MAX_SUBSCRIBED_ROOMS: usize = 1000;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct WsQuery {
     access_token: String,
+    /// optional per-connection queue size (clamped), defaults to WS_QUEUE_CAPACITY
+    queue_capacity: Option<usize>,
+    /// comma-separated user ids — only their presence is sent (snapshot and
+    /// changes). omit for every user on the instance
+    user_ids: Option<String>,
+}
+
+/// sent by the client over the socket to change what it hears about. each one
+/// replaces the last: user_ids narrows presence as the query param does
+/// (omitted = everyone), room_ids picks the rooms whose typing and voice
+/// events come through (omitted = voice from the rooms the caller is in, no
+/// typing). rooms joined since connecting count from the next one. the
+/// answer is a presence snapshot of the new user_ids, then
+/// `{"type": "subscribed", "room_ids": [...]}` naming the rooms the caller is
+/// in — the others are left out — then who's typing in them
+#[derive(Debug, Deserialize)]
+pub struct SubscribeMessage {
+    pub user_ids: Option<Vec<String>>,
+    pub room_ids: Option<Vec<String>>,
+}
+
+/// what a client may send, by its `type`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(SubscribeMessage),
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ConnectionMetrics {
+    pub connection_id: u64,
+    pub queue_depth: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WsMetricsResponse {
+    pub connections: Vec<ConnectionMetrics>,
 }
 
 pub fn router() -> Router<Arc<AppState>> {
-    Router::new().route("/ws/presence", get(ws_handler))
+    Router::new()
+        .route("/ws/presence", get(ws_handler))
+        .route("/ws/metrics", get(ws_metrics))
 }
 
+#[derive(OpenApi)]
+#[openapi(paths(ws_handler, ws_metrics))]
+pub struct ApiDoc;
+
+#[utoipa::path(
+    get,
+    path = "/ws/presence",
+    operation_id = "presence_ws",
+    tag = "presence_ws",
+    params(WsQuery),
+    responses(
+        (status = 101, description = "upgraded to a websocket"),
+        (status = 401, description = "the access_token is no good"),
+    )
+)]
 async fn ws_handler(
     ws: WebSocketUpgrade,
     Query(params): Query<WsQuery>,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    // upgrade to websocket — access_token is accepted but not deeply validated
-    // (conduit would reject any Matrix calls made with a bad token anyway)
-    let _ = params.access_token;
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+) -> Response {
+    // resolve the token before upgrading — user events are only delivered to
+    // the user they're meant for
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(params.access_token.clone());
+    let user_id = match matrix.whoami().await {
+        Ok(whoami) => whoami.user_id,
+        Err(e) => return matrix_error(&e, StatusCode::BAD_GATEWAY),
+    };
+    let capacity = params
+        .queue_capacity
+        .map(|c| c.clamp(MIN_QUEUE_CAPACITY, MAX_QUEUE_CAPACITY))
+        .unwrap_or(state.ws_queue_capacity);
+    let filter = params.user_ids.as_deref().map(|ids| {
+        ids.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .take(MAX_FILTER_USERS)
+            .map(String::from)
+            .collect::<HashSet<String>>()
+    });
+    let queue = ConnectionQueue::for_user(capacity, Some(user_id))
+        .with_presence_filter(filter.clone())
+        .with_joined_rooms(joined_rooms(&matrix).await);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, matrix, queue, filter))
+        .into_response()
 }
 
-async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
-    let (mut sender, mut receiver) = socket.split();
+/// per-connection queue depth and drop counts, for spotting stalled consumers.
+/// there's no auth in front of it, so nothing here says who is connected
+#[utoipa::path(
+    get,
+    path = "/ws/metrics",
+    tag = "presence_ws",
+    responses((status = 200, body = WsMetricsResponse))
+)]
+async fn ws_metrics(State(state): State<Arc<AppState>>) -> Json<WsMetricsResponse> {
+    let mut connections: Vec<ConnectionMetrics> = state
+        .ws_connections
+        .iter()
+        .map(|entry| ConnectionMetrics {
+            connection_id: *entry.key(),
+            queue_depth: entry.value().depth(),
+            capacity: entry.value().capacity(),
+            dropped: entry.value().dropped(),
+        })
+        .collect();
+    connections.sort_by_key(|c| c.connection_id);
+    Json(WsMetricsResponse { connections })
+}
 
-    // subscribe to the broadcast channel before sending the snapshot so we
-    // don't miss any events that arrive between the snapshot and subscribe
-    let mut rx = state.presence_tx.subscribe();
-
-    // send a snapshot of every currently-online user from redis
-    if let Some(mut redis) = state.redis.clone() {
-        // KEYS is O(N) but fine for small deployments
-        let keys: Vec<String> = redis.keys("presence:*").await.unwrap_or_default();
-        for key in keys {
-            let value: Option<String> = redis.get(&key).await.unwrap_or(None);
-            if let Some(presence) = value {
-                let user_id = key.trim_start_matches("presence:").to_string();
-                let event = PresenceEvent { user_id, presence };
-                if let Ok(json) = serde_json::to_string(&event) {
-                    if sender.send(Message::Text(json.into())).await.is_err() {
-                        return; // client disconnected during snapshot
-                    }
-                }
-            }
+/// everyone with a presence key, or just the users in `filter`. SCAN rather
+/// than KEYS so a big instance doesn't stall redis, and one MGET per page.
+pub async fn presence_snapshot(
+    redis: &mut redis::aio::MultiplexedConnection,
+    filter: Option<&HashSet<String>>,
+) -> redis::RedisResult<Vec<PresenceEvent>> {
+    let mut snapshot = Vec::new();
+
+    if let Some(filter) = filter {
+        let mut user_ids: Vec<&String> = filter.iter().collect();
+        user_ids.sort();
+        for chunk in user_ids.chunks(SNAPSHOT_SCAN_COUNT) {
+            let keys: Vec<String> = chunk.iter().map(|id| format!("presence:{}", id)).collect();
+            let values: Vec<Option<String>> = redis.mget(&keys).await?;
+            snapshot.extend(chunk.iter().zip(values).filter_map(|(user_id, presence)| {
+                Some(PresenceEvent { user_id: user_id.to_string(), presence: public_presence(&presence?).to_string() })
+            }));
         }
+        return Ok(snapshot);
     }
 
-    // forward broadcast events to this client until it disconnects
+    let mut cursor: u64 = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("presence:*")
+            .arg("COUNT")
+            .arg(SNAPSHOT_SCAN_COUNT)
+            .query_async(redis)
+            .await?;
+        if !keys.is_empty() {
+            let values: Vec<Option<String>> = redis.mget(&keys).await?;
+            snapshot.extend(keys.iter().zip(values).filter_map(|(key, presence)| {
+                Some(PresenceEvent {
+                    user_id: key.trim_start_matches("presence:").to_string(),
+                    presence: public_presence(&presence?).to_string(),
+                })
+            }));
+        }
+        if next == 0 {
+            return Ok(snapshot);
+        }
+        cursor = next;
+    }
+}
+
+type Sender = SplitSink<WebSocket, Message>;
+
+/// the snapshot goes out as arrays of presence frames, then a marker so the
+/// client knows who isn't listed is offline. false once the client is gone
+async fn send_snapshot(sender: &mut Sender, state: &AppState, filter: Option<&HashSet<String>>) -> bool {
+    let snapshot = state
+        .redis
+        .retrying(|mut redis| async move { presence_snapshot(&mut redis, filter).await })
+        .await;
+    let Some(snapshot) = snapshot else {
+        return true;
+    };
+    let snapshot = snapshot.unwrap_or_else(|e| {
+        tracing::warn!("presence snapshot failed: {}", e);
+        Vec::new()
+    });
+    let frames = snapshot
+        .chunks(SNAPSHOT_CHUNK_SIZE)
+        .filter_map(|chunk| serde_json::to_string(chunk).ok())
+        .chain(std::iter::once(serde_json::json!({ "snapshot_complete": true }).to_string()));
+    for json in frames {
+        if sender.send(Message::Text(json)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// apply a subscribe message and answer it. rooms are checked against the
+/// caller's joined rooms, so typing never reaches anyone outside a room.
+/// false once the client is gone
+async fn subscribe(
+    sender: &mut Sender,
+    state: &AppState,
+    matrix: &MatrixClient,
+    queue: &ConnectionQueue,
+    message: SubscribeMessage,
+) -> bool {
+    let user_ids = message
+        .user_ids
+        .map(|ids| ids.into_iter().filter(|id| !id.is_empty()).take(MAX_FILTER_USERS).collect::<HashSet<String>>());
+    let joined = joined_rooms(matrix).await;
+    let room_ids = message
+        .room_ids
+        .map(|ids| ids.into_iter().take(MAX_SUBSCRIBED_ROOMS).filter(|id| joined.contains(id)).collect::<HashSet<String>>());
+    queue.subscribe(Subscription { user_ids: user_ids.clone(), room_ids: room_ids.clone(), joined_rooms: joined });
+
+    if !send_snapshot(sender, state, user_ids.as_ref()).await {
+        return false;
+    }
+    let mut room_ids: Vec<String> = room_ids.into_iter().flatten().collect();
+    room_ids.sort();
+    let mut frames = vec![serde_json::json!({ "type": "subscribed", "room_ids": room_ids }).to_string()];
+    for room_id in room_ids {
+        let user_ids = state.typing.current(&room_id);
+        if !user_ids.is_empty() {
+            let typing = TypingEvent { kind: "typing", room_id, user_ids };
+            frames.extend(serde_json::to_string(&typing).ok());
+        }
+    }
+    for json in frames {
+        if sender.send(Message::Text(json)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// the rooms behind `matrix`'s token — none when the homeserver can't say,
+/// so a failed lookup hides events rather than leaking them
+pub(crate) async fn joined_rooms(matrix: &MatrixClient) -> HashSet<String> {
+    match matrix.get_joined_rooms().await {
+        Ok(joined) => joined.joined_rooms.into_iter().collect(),
+        Err(e) => {
+            tracing::warn!("couldn't look up the rooms behind a websocket: {}", e);
+            HashSet::new()
+        }
+    }
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    matrix: MatrixClient,
+    queue: ConnectionQueue,
+    filter: Option<HashSet<String>>,
+) {
+    let (mut sender, mut receiver) = socket.split();
+
+    // register before sending the snapshot so we don't miss any events that
+    // arrive between the snapshot and the forwarding loop
+    let (connection_id, queue) = state.register_queue(queue);
+
+    if !send_snapshot(&mut sender, &state, filter.as_ref()).await {
+        state.unregister_connection(connection_id);
+        return; // client disconnected during snapshot
+    }
+
+    // forward queued events to this client until it disconnects
     loop {
         tokio::select! {
-            // new presence event from the broadcast channel
-            result = rx.recv() => {
-                match result {
-                    Ok(event) => {
-                        if let Ok(json) = serde_json::to_string(&event) {
-                            if sender.send(Message::Text(json.into())).await.is_err() {
-                                break; // client disconnected
-                            }
-                        }
+            // next event (or gap marker) from this connection's queue
+            item = queue.pop() => {
+                let json = match item {
+                    QueueItem::Event(event) => serde_json::to_string(&event),
+                    QueueItem::Gap { dropped } => {
+                        tracing::debug!("presence ws {}: dropped {} events (client too slow)", connection_id, dropped);
+                        serde_json::to_string(&serde_json::json!({ "type": "gap", "dropped": dropped }))
                     }
-                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
-                        // receiver fell behind — skip dropped events, keep going
-                        tracing::warn!("presence ws: dropped {} events (receiver lagged)", n);
+                };
+                if let Ok(json) = json {
+                    if sender.send(Message::Text(json)).await.is_err() {
+                        break; // client disconnected
                     }
-                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
                 }
             }
-            // drain incoming frames — we don't expect client messages but must
-            // read to detect disconnects (close frames, pings)
+            // the api is stopping: say goodbye so the client reconnects elsewhere
+            _ = state.shutdown.started() => {
+                let _ = sender.send(Message::Close(None)).await;
+                break;
+            }
+            // subscribe messages, and close frames and pings
             msg = receiver.next() => {
                 match msg {
                     Some(Ok(Message::Close(_))) | None => break,
                     Some(Ok(Message::Ping(data))) => {
                         let _ = sender.send(Message::Pong(data)).await;
                     }
+                    Some(Ok(Message::Text(text))) => {
+                        let sent = match serde_json::from_str::<ClientMessage>(&text) {
+                            Ok(ClientMessage::Subscribe(message)) => subscribe(&mut sender, &state, &matrix, &queue, message).await,
+                            Err(e) => {
+                                let error = serde_json::json!({ "type": "error", "errcode": "M_BAD_JSON", "error": e.to_string() });
+                                sender.send(Message::Text(error.to_string())).await.is_ok()
+                            }
+                        };
+                        if !sent {
+                            break;
+                        }
+                    }
                     _ => {} // ignore other frames
                 }
             }
         }
     }
+
+    state.unregister_connection(connection_id);
 }
+
@@ -0,0 +1,62 @@
+// preview.rs — link previews for the message composer and clients rendering
+// links. see crate::link_preview for where they come from
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use utoipa::{IntoParams, OpenApi};
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::link_preview::{self, LinkPreview, PreviewError};
+use crate::session::AuthUser;
+use super::agora_error;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/preview/url", get(preview_url))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(preview_url))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PreviewQuery {
+    /// an http(s) url
+    pub url: String,
+}
+
+/// title, description and image of a web page. 400 for urls that can't be
+/// previewed (not http, or leading to a private address), 404 when the page has
+/// nothing to show
+#[utoipa::path(
+    get,
+    path = "/preview/url",
+    tag = "preview",
+    params(PreviewQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = LinkPreview), super::ErrorResponses)
+)]
+async fn preview_url(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<PreviewQuery>,
+) -> Result<Json<LinkPreview>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    match link_preview::preview(&state, &matrix, params.url.trim()).await {
+        Ok(preview) => Ok(Json(preview)),
+        Err(PreviewError::Invalid(msg)) => Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", msg)),
+        Err(PreviewError::Unauthorized) => Err(StatusCode::UNAUTHORIZED.into_response()),
+        Err(PreviewError::Unavailable(msg)) => {
+            tracing::debug!("no preview for {}: {}", params.url, msg);
+            Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "nothing to preview at that url"))
+        }
+    }
+}
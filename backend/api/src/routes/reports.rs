@@ -0,0 +1,372 @@
+// reports.rs — members reporting messages, and the queue their server's
+// moderators (kick_members) work through. a report goes to the homeserver's
+// admins through the matrix report api and into postgres (reports, see
+// migrations/006_reports.sql) under the server the room belongs to. resolving
+// one dismisses it, redacts the message or times its author out — through the
+// same checks /rooms/redact and /servers/members/timeout make — and settles
+// every open report of that message. actions are logged in moderation_actions.
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sqlx::Row;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::authz::{self, Permission};
+use crate::matrix::client::MatrixClient;
+use crate::matrix::encode_path_segment;
+use crate::session::{AuthJson, AuthUser};
+use super::{agora_error, authz_error, rooms, servers};
+
+const MAX_REASON_CHARS: usize = 1000;
+// reports listed at once, newest first
+const MAX_LISTED: i64 = 200;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/rooms/report", post(report_message))
+        .route("/servers/reports", get(list_reports))
+        .route("/servers/reports/resolve", post(resolve_report))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(report_message, list_reports, resolve_report))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReportRequest {
+    pub room_id: String,
+    pub event_id: String,
+    pub reason: Option<String>,
+    /// how offensive, from -100 (most) to 0 — as matrix scores reports
+    pub score: Option<i32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReportResponse {
+    pub report_id: i64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportsQuery {
+    pub server_id: String,
+    /// "open" (the default), "dismissed", "actioned" or "all"
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Report {
+    pub id: i64,
+    pub room_id: String,
+    pub event_id: String,
+    pub reporter_id: String,
+    pub reported_user_id: String,
+    pub reason: Option<String>,
+    pub score: Option<i32>,
+    /// "open" | "dismissed" | "actioned"
+    pub status: String,
+    pub resolved_by: Option<String>,
+    /// "dismiss" | "redact" | "timeout"
+    pub resolution: Option<String>,
+    /// unix ms
+    pub created_at: i64,
+    /// unix ms
+    pub resolved_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReportsResponse {
+    pub reports: Vec<Report>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResolveReportRequest {
+    pub server_id: String,
+    pub report_id: i64,
+    /// "dismiss", "redact" (delete the message) or "timeout" (its author)
+    pub action: String,
+    /// how long a timeout lasts — up to 28 days
+    pub duration_secs: Option<u64>,
+    /// passed on to the redaction or timeout
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ResolveReportResponse {
+    /// the report and any others of the same message, now resolved
+    pub resolved: Vec<i64>,
+    /// unix ms, for a timeout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timed_out_until: Option<i64>,
+}
+
+/// require a db pool or return 503
+macro_rules! require_db {
+    ($state:expr) => {
+        match $state.db_pool.as_ref() {
+            Some(pool) => pool,
+            None => {
+                tracing::error!("report endpoints require a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+        }
+    };
+}
+
+fn db_error(e: sqlx::Error) -> Response {
+    tracing::error!("reports query failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+const REPORT_COLUMNS: &str = r#"
+    id, room_id, event_id, reporter_id, reported_user_id, reason, score, status, resolved_by, resolution,
+    (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at,
+    (EXTRACT(EPOCH FROM resolved_at) * 1000)::BIGINT AS resolved_at
+"#;
+
+fn report_entry(row: &sqlx::postgres::PgRow) -> Report {
+    Report {
+        id: row.get("id"),
+        room_id: row.get("room_id"),
+        event_id: row.get("event_id"),
+        reporter_id: row.get("reporter_id"),
+        reported_user_id: row.get("reported_user_id"),
+        reason: row.get("reason"),
+        score: row.get("score"),
+        status: row.get("status"),
+        resolved_by: row.get("resolved_by"),
+        resolution: row.get("resolution"),
+        created_at: row.get::<Option<i64>, _>("created_at").unwrap_or_default(),
+        resolved_at: row.get("resolved_at"),
+    }
+}
+
+/// the caller's membership of a room — None when they never had one
+async fn membership(matrix: &MatrixClient, room_id: &str, user_id: &str) -> Option<String> {
+    let url = format!(
+        "{}/rooms/{}/state/m.room.member/{}",
+        matrix.client_api_base().await,
+        encode_path_segment(room_id),
+        encode_path_segment(user_id)
+    );
+    let content = matrix.get_raw(&url).await.ok()?;
+    content["membership"].as_str().map(String::from)
+}
+
+/// report a message to the moderators of its server, and the homeserver's admins.
+/// only members of the room can, once per message
+#[utoipa::path(
+    post,
+    path = "/rooms/report",
+    tag = "reports",
+    request_body = ReportRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ReportResponse), super::ErrorResponses)
+)]
+async fn report_message(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ReportRequest>,
+) -> Result<Json<ReportResponse>, Response> {
+    let pool = require_db!(state);
+    if req.score.is_some_and(|score| !(-100..=0).contains(&score)) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "score runs from -100 to 0"));
+    }
+    let reason = req.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    if reason.is_some_and(|r| r.chars().count() > MAX_REASON_CHARS) {
+        let error = format!("reasons are at most {} characters", MAX_REASON_CHARS);
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", error));
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    // a world-readable room's messages can be read from outside, but only its
+    // members get to report them
+    if membership(&matrix, &req.room_id, &auth.user_id).await.as_deref() != Some("join") {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "you can only report messages in rooms you're in"));
+    }
+    let event = matrix.get_event(&req.room_id, &req.event_id).await.map_err(|e| {
+        tracing::debug!("cannot load reported event {}: {}", req.event_id, e);
+        agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such message")
+    })?;
+    let sender = event["sender"].as_str().unwrap_or_default().to_string();
+    if event["type"].as_str() != Some("m.room.message") || sender.is_empty() {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such message"));
+    }
+    if sender == auth.user_id {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "you can't report your own message"));
+    }
+
+    let server_id = authz::server_of(&matrix, &req.room_id).await.ok().flatten();
+    let row = sqlx::query(
+        r#"
+        INSERT INTO reports (server_id, room_id, event_id, reporter_id, reported_user_id, reason, score)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (reporter_id, event_id) DO NOTHING
+        RETURNING id
+        "#,
+    )
+    .bind(&server_id)
+    .bind(&req.room_id)
+    .bind(&req.event_id)
+    .bind(&auth.user_id)
+    .bind(&sender)
+    .bind(reason)
+    .bind(req.score)
+    .fetch_optional(pool)
+    .await
+    .map_err(db_error)?;
+    let Some(row) = row else {
+        return Err(agora_error(StatusCode::CONFLICT, "AGORA_ALREADY_REPORTED", "you've already reported this message"));
+    };
+    let report_id: i64 = row.get("id");
+
+    // the queue has it either way; the homeserver's admins are a bonus
+    if let Err(e) = matrix.report_event(&req.room_id, &req.event_id, reason, req.score).await {
+        tracing::warn!("homeserver refused report of {}: {}", req.event_id, e);
+    }
+    Ok(Json(ReportResponse { report_id }))
+}
+
+/// a server's reports, newest first — open ones unless asked for others
+#[utoipa::path(
+    get,
+    path = "/servers/reports",
+    tag = "reports",
+    params(ReportsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ReportsResponse), super::ErrorResponses)
+)]
+async fn list_reports(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ReportsQuery>,
+) -> Result<Json<ReportsResponse>, Response> {
+    let status = params.status.unwrap_or_else(|| "open".to_string());
+    if !matches!(status.as_str(), "open" | "dismissed" | "actioned" | "all") {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "status must be open, dismissed, actioned or all"));
+    }
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    authz::require_permission(&matrix, &params.server_id, Permission::KickMembers)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let pool = require_db!(state);
+
+    let rows = sqlx::query(&format!(
+        r#"
+        SELECT {} FROM reports
+        WHERE server_id = $1 AND ($2 = 'all' OR status = $2)
+        ORDER BY created_at DESC, id DESC
+        LIMIT $3
+        "#,
+        REPORT_COLUMNS
+    ))
+    .bind(&params.server_id)
+    .bind(&status)
+    .bind(MAX_LISTED)
+    .fetch_all(pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(ReportsResponse { reports: rows.iter().map(report_entry).collect() }))
+}
+
+/// dismiss a report, delete the message or time its author out. the message's
+/// other open reports are resolved the same way
+#[utoipa::path(
+    post,
+    path = "/servers/reports/resolve",
+    tag = "reports",
+    request_body = ResolveReportRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ResolveReportResponse), super::ErrorResponses)
+)]
+async fn resolve_report(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ResolveReportRequest>,
+) -> Result<Json<ResolveReportResponse>, Response> {
+    if !matches!(req.action.as_str(), "dismiss" | "redact" | "timeout") {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "action must be dismiss, redact or timeout"));
+    }
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::KickMembers)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let pool = require_db!(state);
+
+    let report = sqlx::query(&format!("SELECT {} FROM reports WHERE id = $1 AND server_id = $2", REPORT_COLUMNS))
+        .bind(req.report_id)
+        .bind(&req.server_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .map(|row| report_entry(&row))
+        .ok_or_else(|| agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such report"))?;
+    if report.status != "open" {
+        return Err(agora_error(StatusCode::CONFLICT, "AGORA_REPORT_RESOLVED", "that report is already resolved"));
+    }
+
+    let mut timed_out_until = None;
+    match req.action.as_str() {
+        "redact" => rooms::redact_checked(&matrix, &report.room_id, &report.event_id, req.reason.clone()).await?,
+        "timeout" => {
+            let duration_secs = req.duration_secs.unwrap_or_default();
+            servers::check_timeout_duration(duration_secs).map_err(|e| *e)?;
+            let until = servers::time_out(
+                &state, &matrix, &access, &report.reported_user_id, duration_secs, req.reason.clone(),
+            )
+            .await?;
+            timed_out_until = Some(until);
+        }
+        _ => {}
+    }
+
+    let status = if req.action == "dismiss" { "dismissed" } else { "actioned" };
+    let resolved: Vec<i64> = sqlx::query_scalar(
+        r#"
+        UPDATE reports SET status = $1, resolution = $2, resolved_by = $3, resolved_at = NOW()
+        WHERE server_id = $4 AND event_id = $5 AND status = 'open'
+        RETURNING id
+        "#,
+    )
+    .bind(status)
+    .bind(&req.action)
+    .bind(&access.user_id)
+    .bind(&req.server_id)
+    .bind(&report.event_id)
+    .fetch_all(pool)
+    .await
+    .map_err(db_error)?;
+
+    if req.action != "dismiss" {
+        let logged = sqlx::query(
+            r#"
+            INSERT INTO moderation_actions (server_id, room_id, actor_id, target_user_id, action, reason, report_id, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, to_timestamp($8::BIGINT / 1000.0))
+            "#,
+        )
+        .bind(&req.server_id)
+        .bind(&report.room_id)
+        .bind(&access.user_id)
+        .bind(&report.reported_user_id)
+        .bind(&req.action)
+        .bind(&req.reason)
+        .bind(report.id)
+        .bind(timed_out_until)
+        .execute(pool)
+        .await;
+        if let Err(e) = logged {
+            tracing::warn!("failed to log moderation action on report {}: {}", report.id, e);
+        }
+    }
+
+    Ok(Json(ResolveReportResponse { resolved, timed_out_until }))
+}
@@ -1,257 +1,636 @@
 use axum::{
     extract::{Json, Query, State},
     http::StatusCode,
-    routing::{get, post},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Router,
 };
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use crate::app_state::AppState;
-use crate::matrix::client::MatrixClient;
+use crate::app_state::{AppState, TypingEvent, WsEvent};
+use crate::content::{self, Mentions};
+use crate::idempotency;
+use crate::link_preview;
+use crate::matrix::authz::{self, Permission};
+use crate::matrix::channel_access::{self, ChannelPermissions};
+use crate::matrix::client::{Direction, HierarchyRoom, HistoryVisibility, MatrixClient, MatrixError, SyncFilter};
+use crate::matrix::hierarchy;
+use crate::matrix::message_policy::{self, Denied, PolicyLoader};
+use crate::pagination::{paginate_sorted, PageParams};
+use crate::room_summaries::{self, LastMessage, RoomPreview};
+use crate::service_account;
+use crate::routes::{agora_error, authz_error, dms, matrix_error, servers, voice, webhooks};
+use crate::session::{AuthJson, AuthUser};
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/rooms", get(list_joined_rooms))
         .route("/rooms/create", post(create_room))
         .route("/rooms/join", post(join_room))
+        .route("/rooms/alias/check", get(check_alias))
         .route("/rooms/leave", post(leave_room))
         .route("/rooms/delete", post(delete_room))
-        .route("/rooms/delete_server", post(delete_server))
         .route("/rooms/members", get(get_room_members))
         .route("/rooms/invite", post(invite_user))
+        .route("/rooms/invite/accept", post(accept_invite))
+        .route("/rooms/invite/reject", post(reject_invite))
+        .route("/rooms/messages", get(get_messages))
         .route("/rooms/send", post(send_message))
+        .route("/rooms/edit", post(edit_message))
+        .route("/rooms/redact", post(redact_message))
+        .route("/rooms/react", post(react))
+        .route("/rooms/unreact", post(unreact))
+        .route("/rooms/reactions", get(get_reactions))
         .route("/rooms/children", get(get_space_children))
+        .route("/rooms/add_child", post(add_space_child))
         .route("/rooms/remove_child", post(remove_space_child))
+        .route("/rooms/reorder", post(reorder_children))
         .route("/rooms/state", get(get_room_state))
+        .route("/rooms/settings", post(update_room_settings))
+        .route("/rooms/slowmode", get(get_slowmode).post(set_slowmode))
         .route("/rooms/category/create", post(create_category))
         .route("/rooms/permissions", get(get_permissions).post(set_permissions))
+        .route("/rooms/permissions/overrides", get(get_overrides).post(set_overrides))
         .route("/rooms/raid", post(send_raid))
+        .route("/rooms/tag", put(set_tag).delete(remove_tag))
+        .route("/rooms/history_visibility", post(set_history_visibility))
+        .route("/rooms/typing", post(set_typing))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct RoomListQuery {
-    pub access_token: String,
-}
-
-#[derive(Debug, Serialize)]
+#[derive(OpenApi)]
+#[openapi(paths(
+    list_joined_rooms, create_room, join_room, check_alias, leave_room, delete_room, get_room_members,
+    invite_user, accept_invite, reject_invite, get_messages, send_message, edit_message,
+    redact_message, react, unreact, get_reactions, get_space_children, add_space_child,
+    remove_space_child, reorder_children, get_room_state, update_room_settings,
+    get_slowmode, set_slowmode, create_category, get_permissions, set_permissions,
+    get_overrides, set_overrides, send_raid, set_tag, remove_tag, set_history_visibility,
+    set_typing,
+), components(schemas(Direction, RoomSort)))]
+pub struct ApiDoc;
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RoomListResponse {
     pub rooms: Vec<RoomInfo>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RoomInfo {
     pub room_id: String,
     pub name: Option<String>,
     pub topic: Option<String>,
+    /// mxc:// uri from m.room.avatar
+    pub avatar_url: Option<String>,
     pub is_space: bool,
     pub member_count: Option<i32>,
-    /// "text" or "voice" — defaults to "text" if the state event is absent
+    /// "text", "voice", "forum", "stage" or "announcement" — defaults to "text" if the state event is absent
     pub channel_type: Option<String>,
+    /// BCP-47 language hint for the channel, from agora.room.type
+    pub language: Option<String>,
+    /// seconds members wait between messages — 0 when slowmode is off
+    pub slowmode_seconds: u64,
+    /// voice channels only: how many people may be in it at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_limit: Option<u32>,
+    /// voice channels with a user limit: how many are in it now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub participant_count: Option<u32>,
+    /// the space this room was listed under — only set by /rooms/children
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    /// position among its siblings, from the parent's m.space.child — only set by /rooms/children
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<String>,
+    /// "shared", "invited", "joined" or "world_readable" — absent when the room
+    /// never set one, which homeservers treat as shared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_visibility: Option<String>,
+    /// the caller's m.tag tags on the room — only set by /rooms
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, RoomTag>,
+    /// a dm rather than a channel: in the caller's m.direct, or the dm room of
+    /// one of their friendships — only set by /rooms
+    pub is_direct: bool,
+    /// only set by /rooms, and only for rooms with a message to show
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_message: Option<LastMessage>,
+    /// ms timestamp of the newest message or edit — only set by /rooms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity_ts: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RoomListQuery {
+    /// "activity" lists rooms by their newest message, latest first; without
+    /// it rooms come in the homeserver's order
+    pub sort: Option<RoomSort>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomSort {
+    Activity,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RoomTag {
+    /// in [0, 1]; lower sorts first among rooms with the same tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<f64>,
+}
+
+impl RoomInfo {
+    /// fill in participant_count for voice channels with a limit, so the sidebar can show "3/5"
+    pub async fn count_participants(state: &AppState, rooms: &mut [RoomInfo]) {
+        for room in rooms.iter_mut().filter(|r| r.user_limit.is_some()) {
+            room.participant_count = Some(voice::participant_count(state, &room.room_id).await);
+        }
+    }
+
+    /// everything but member_count, from a room's full state
+    pub fn from_state(room_id: String, state: &[crate::matrix::client::RoomStateEvent], parent_id: Option<String>) -> Self {
+        Self::from_summary(room_id, room_summaries::RoomSummary::from_state(state), parent_id)
+    }
+
+    pub fn from_summary(room_id: String, summary: room_summaries::RoomSummary, parent_id: Option<String>) -> Self {
+        RoomInfo {
+            room_id,
+            name: summary.name,
+            topic: summary.topic,
+            avatar_url: summary.avatar_url,
+            is_space: summary.is_space,
+            member_count: None,
+            channel_type: Some(summary.channel_type),
+            language: summary.language,
+            slowmode_seconds: summary.slowmode_seconds,
+            user_limit: summary.user_limit,
+            participant_count: None,
+            parent_id,
+            order: None,
+            history_visibility: summary.history_visibility,
+            tags: BTreeMap::new(),
+            is_direct: false,
+            last_message: None,
+            last_activity_ts: None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateRoomRequest {
-    pub access_token: String,
     pub name: String,
     pub topic: Option<String>,
     pub is_space: Option<bool>,
     pub parent_space_id: Option<String>,
-    /// "text" (default) or "voice"
+    /// "text" (default), "voice", "forum", "stage" or "announcement"
     pub channel_type: Option<String>,
+    /// BCP-47 language hint for the channel, e.g. "en" or "pt-BR"
+    pub language: Option<String>,
+    /// only members with one of `allowed_role_ids` (and server admins) can join
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub allowed_role_ids: Vec<String>,
+    /// voice channels: how many people may be in it at once
+    pub user_limit: Option<u32>,
+    /// "shared" (default), "invited" or "world_readable"
+    pub history_visibility: Option<HistoryVisibility>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetHistoryVisibilityRequest {
+    pub room_id: String,
+    /// "shared", "invited" or "world_readable"
+    pub history_visibility: HistoryVisibility,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TypingRequest {
+    pub room_id: String,
+    pub typing: bool,
+    /// how long the typing lasts unless sent again, in ms (default 30000, at most 120000)
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SlowmodeQuery {
+    pub room_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetSlowmodeRequest {
+    pub room_id: String,
+    /// 0 turns slowmode off
+    pub seconds: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SlowmodeResponse {
+    pub seconds: u64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetTagRequest {
+    pub room_id: String,
+    /// "m.favourite", "m.lowpriority" or a user tag starting with "u."
+    pub tag: String,
+    /// in [0, 1], for ordering rooms within the tag
+    pub order: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RemoveTagRequest {
+    pub room_id: String,
+    pub tag: String,
+}
+
+/// fields left out are unchanged; an empty topic or avatar_url clears it
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RoomSettingsRequest {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    /// mxc:// uri, as returned by /media/upload
+    pub avatar_url: Option<String>,
+    /// voice channels only — 0 removes the limit
+    pub user_limit: Option<u32>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct CreateRoomResponse {
     pub room_id: String,
+    /// the alias the channel got from its name — suffixed when the plain one
+    /// was taken. None for spaces, private channels and names with nothing
+    /// alias-worthy in them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AliasCheckQuery {
+    /// a full alias (#general:example.org), or a channel name to make one from
+    pub alias: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AliasCheckResponse {
+    /// the alias that was checked, after normalizing
+    pub alias: String,
+    pub available: bool,
+    /// the room it points at when taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct JoinRoomRequest {
-    pub access_token: String,
     pub room_id_or_alias: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct RoomMembersQuery {
-    pub access_token: String,
     pub room_id: String,
+    /// page size — omit for the legacy un-paginated response
+    pub limit: Option<usize>,
+    /// cursor from a previous page's next_cursor
+    pub after: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RoomMembersResponse {
     pub members: Vec<MemberInfo>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct MemberInfo {
     pub user_id: String,
     pub display_name: Option<String>,
     pub avatar_url: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct InviteRequest {
-    pub access_token: String,
     pub room_id: String,
     pub user_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct InviteResponseRequest {
+    /// a room from /sync's invites
+    pub room_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SendMessageRequest {
-    pub access_token: String,
     pub room_id: String,
     pub content: String,
+    /// client-rendered html for the message — sanitized before sending
+    pub formatted_body: Option<String>,
+    /// render `content` as markdown on the server (ignored when formatted_body is set)
+    #[serde(default, alias = "formatted")]
+    pub markdown: bool,
+    /// m.image, m.file, m.video or m.audio to send an attachment, with `content` as its caption
+    pub msgtype: Option<String>,
+    /// the attachment's mxc:// uri, as returned by /media/upload
+    pub url: Option<String>,
+    pub info: Option<MediaInfo>,
+    /// picked by the client and sent again with each retry of this message —
+    /// a retry of one that already went out answers with its event_id
+    pub txn_id: Option<String>,
+}
+
+/// the attachment msgtypes /rooms/send accepts besides m.text
+const MEDIA_MSGTYPES: &[&str] = &["m.image", "m.file", "m.video", "m.audio"];
+
+/// the `info` block of an attachment, as the matrix spec names it
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct MediaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+    /// bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// width and height in pixels, for images and video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub w: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub h: Option<u32>,
+    /// ms, for audio and video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MessageHistoryQuery {
+    pub room_id: String,
+    /// `end` of the previous page — omit to start from the newest message
+    pub from: Option<String>,
+    /// page size, default 50, at most 100
+    pub limit: Option<u32>,
+    /// "b" (default) pages back in time, "f" forward
+    #[serde(default)]
+    pub dir: Direction,
+    /// BCP-47 tag — attach translated_body to messages when translation is configured
+    pub translate_to: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MessageHistoryResponse {
+    pub messages: Vec<HistoryMessage>,
+    pub start: String,
+    /// pass as `from` for the next page — absent once the start of the room is reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HistoryMessage {
+    pub event_id: Option<String>,
+    pub sender: String,
+    pub body: String,
+    /// sanitized html, for messages sent with formatting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
+    pub msgtype: String,
+    pub origin_server_ts: Option<i64>,
+    /// mxc:// uri and info of an attachment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub info: Option<serde_json::Value>,
+    /// the full event content for agora.* msgtypes (raids, calls) and webhook posts, which carry more than a body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub content: Option<serde_json::Value>,
+    /// machine translation of body, only when translate_to was requested and it succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translated_body: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EditMessageRequest {
+    pub room_id: String,
+    pub event_id: String,
+    /// the new plain-text body
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RedactMessageRequest {
+    pub room_id: String,
+    pub event_id: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReactionRequest {
+    pub room_id: String,
+    /// the message being reacted to
+    pub event_id: String,
+    /// the emoji
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReactionsQuery {
+    pub room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReactionsResponse {
+    /// most used first
+    pub reactions: Vec<ReactionCount>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReactionCount {
+    pub key: String,
+    pub count: usize,
+    /// whether the caller is one of the reactors
+    pub reacted: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct RoomStateQuery {
-    pub access_token: String,
     pub room_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RoomStateResponse {
     pub events: Vec<RoomStateEvent>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RoomStateEvent {
     #[serde(rename = "type")]
     pub event_type: String,
     pub sender: String,
+    #[schema(value_type = Object)]
     pub content: serde_json::Value,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct SendMessageResponse {
     pub event_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SpaceChildrenQuery {
-    pub access_token: String,
     pub space_id: String,
+    /// how many levels below the space to list (default 1 = direct children only)
+    pub max_depth: Option<usize>,
+    /// direct children per page, each with everything below it — omit for all of them
+    pub limit: Option<usize>,
+    /// cursor from a previous page's next_cursor
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OverridesQuery {
+    pub room_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetOverridesRequest {
+    pub room_id: String,
+    pub private: bool,
+    #[serde(default)]
+    pub allowed_role_ids: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct RemoveChildRequest {
-    pub access_token: String,
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SpaceChildRequest {
     pub space_id: String,
     pub child_room_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReorderRequest {
+    pub space_id: String,
+    /// the space's children in the order they should be shown
+    pub child_room_ids: Vec<String>,
+    /// when the list takes in a room from another space (dragging a channel into a
+    /// different category), the space it's moving out of
+    pub from_space_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct SpaceChildrenResponse {
     pub children: Vec<RoomInfo>,
+    /// pass back as `after` for the next page; absent on the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// position among a space's direct children, in their display order
+#[derive(Debug, Serialize, Deserialize)]
+struct ChildCursor {
+    order: Option<String>,
+    room_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct LeaveRoomRequest {
-    pub access_token: String,
     pub room_id: String,
-    /// only needed for delete_server — the owner's matrix user id
-    pub user_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct DeleteRoomRequest {
-    pub access_token: String,
     pub room_id: String,
+    /// the space the channel is listed in — found from its m.space.parent when omitted
+    pub parent_space_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteRoomResponse {
+    /// false when the room isn't a server's channel and was only left
+    pub deleted: bool,
+    pub members_removed: usize,
+    pub aliases_removed: Vec<String>,
+    /// false when some member couldn't be removed, or the homeserver refused a step
+    pub complete: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateCategoryRequest {
-    pub access_token: String,
     pub name: String,
     pub parent_space_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct CreateCategoryResponse {
     pub room_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct PermissionsQuery {
-    pub access_token: String,
     pub room_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct PermissionsResponse {
-    pub users: std::collections::HashMap<String, i64>,
+    pub users: HashMap<String, i64>,
     pub users_default: i64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SetPermissionsRequest {
-    pub access_token: String,
     pub room_id: String,
     pub user_id: String,
     pub power_level: i64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/rooms",
+    tag = "rooms",
+    params(RoomListQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomListResponse), super::StatusErrors)
+)]
 async fn list_joined_rooms(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<RoomListQuery>,
 ) -> Result<Json<RoomListResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
     match matrix.get_joined_rooms().await {
         Ok(response) => {
-            let mut rooms = Vec::new();
-            
-            for room_id in response.joined_rooms {
-                // fetch state once — if this fails (403, user already left) skip the room entirely
-                // this prevents ghost rooms from appearing in the list after a partial leave
-                let state_events = match matrix.get_room_state(room_id.clone()).await {
-                    Ok(events) => events,
-                    Err(e) => {
-                        tracing::debug!("skipping room {} — cannot read state (likely already left): {}", room_id, e);
-                        continue;
-                    }
-                };
+            // a room whose state can't be read (403, user already left) is skipped entirely,
+            // so ghost rooms don't appear in the list after a partial leave
+            let summaries = room_summaries::joined(&state, &matrix, &response.joined_rooms).await;
+            let mut rooms: Vec<RoomInfo> = response
+                .joined_rooms
+                .into_iter()
+                .zip(summaries)
+                .filter_map(|(room_id, summary)| Some(RoomInfo::from_summary(room_id, summary?, None)))
+                .collect();
 
-                let name = state_events
-                    .iter()
-                    .find(|e| e.event_type == "m.room.name")
-                    .and_then(|e| e.content.get("name"))
-                    .and_then(|v| v.as_str())
-                    .map(String::from);
-
-                let topic = state_events
-                    .iter()
-                    .find(|e| e.event_type == "m.room.topic")
-                    .and_then(|e| e.content.get("topic"))
-                    .and_then(|v| v.as_str())
-                    .map(String::from);
-
-                let is_space = state_events
-                    .iter()
-                    .find(|e| e.event_type == "m.room.create")
-                    .and_then(|e| e.content.get("type"))
-                    .map(|v| v.as_str() == Some("m.space"))
-                    .unwrap_or(false);
-
-                let channel_type = state_events
-                    .iter()
-                    .find(|e| e.event_type == "agora.room.type")
-                    .and_then(|e| e.content.get("type"))
-                    .and_then(|v| v.as_str())
-                    .map(String::from)
-                    .unwrap_or_else(|| "text".to_string());
-
-                rooms.push(RoomInfo {
-                    room_id,
-                    name,
-                    topic,
-                    is_space,
-                    member_count: None,
-                    channel_type: Some(channel_type),
-                });
+            RoomInfo::count_participants(&state, &mut rooms).await;
+            let dm_rooms = dms::friend_dm_rooms(&state, &auth.user_id).await;
+            attach_account_data_and_previews(&state, &matrix, &mut rooms, dm_rooms).await;
+            if params.sort == Some(RoomSort::Activity) {
+                // rooms without messages go last, in the order they came
+                rooms.sort_by_key(|r| std::cmp::Reverse(r.last_activity_ts));
             }
-
             Ok(Json(RoomListResponse { rooms }))
         }
         Err(e) => {
@@ -261,26 +640,168 @@ async fn list_joined_rooms(
     }
 }
 
+/// messages read per room for its preview — a few, since edits and
+/// redacted messages among the newest don't count
+const PREVIEW_MESSAGES: u32 = 5;
+
+/// fill in each room's m.tag, is_direct and last message from one sync
+/// filtered down to just those. previews that may be shared come from redis
+/// while fresh, so the sync only reads the timelines of the rest. all of it
+/// is decoration — the list goes out without what the sync couldn't get
+async fn attach_account_data_and_previews(
+    state: &AppState,
+    matrix: &MatrixClient,
+    rooms: &mut [RoomInfo],
+    dm_rooms: HashSet<String>,
+) {
+    if rooms.is_empty() {
+        return;
+    }
+    let shareable: Vec<String> = rooms
+        .iter()
+        .filter(|r| RoomPreview::cacheable(r.history_visibility.as_deref()))
+        .map(|r| r.room_id.clone())
+        .collect();
+    let mut previews: HashMap<String, RoomPreview> = shareable
+        .iter()
+        .cloned()
+        .zip(room_summaries::cached_previews(state, &shareable).await)
+        .filter_map(|(room_id, preview)| Some((room_id, preview?)))
+        .collect();
+
+    let room_ids: Vec<String> = rooms.iter().map(|r| r.room_id.clone()).collect();
+    let unread: Vec<String> = room_ids.iter().filter(|id| !previews.contains_key(*id)).cloned().collect();
+    let filter = SyncFilter::room_list(&room_ids, &unread, PREVIEW_MESSAGES);
+    let (joined, direct) = match matrix.sync_with_filter(None, Some(&filter), 0).await {
+        Ok(sync) => {
+            let direct = sync
+                .account_data
+                .and_then(|data| data.events.into_iter().rev().find(|e| e.event_type == dms::DIRECT_ACCOUNT_DATA_TYPE))
+                .map(|event| dms::direct_room_ids(&event.content))
+                .unwrap_or_default();
+            (Some(sync.rooms.and_then(|r| r.join).unwrap_or_default()), direct)
+        }
+        Err(e) => {
+            tracing::warn!("failed to sync room tags and previews: {}", e);
+            (None, HashSet::new())
+        }
+    };
+
+    if let Some(joined) = &joined {
+        let mut fresh: Vec<(String, RoomPreview)> = Vec::new();
+        for room_id in unread {
+            // a room the sync says nothing about has no messages
+            let preview = joined
+                .get(&room_id)
+                .and_then(|r| r.timeline.as_ref())
+                .map_or_else(RoomPreview::default, |t| RoomPreview::from_timeline(&t.events));
+            if shareable.contains(&room_id) {
+                fresh.push((room_id.clone(), preview.clone()));
+            }
+            previews.insert(room_id, preview);
+        }
+        room_summaries::cache_previews(state, &fresh).await;
+    }
+
+    for room in rooms.iter_mut() {
+        let joined_room = joined.as_ref().and_then(|j| j.get(&room.room_id));
+        let tags = joined_room
+            .and_then(|r| r.account_data.as_ref())
+            .and_then(|data| data.events.iter().rev().find(|e| e.event_type == "m.tag"))
+            .and_then(|event| serde_json::from_value(event.content["tags"].clone()).ok());
+        room.tags = tags.unwrap_or_default();
+        room.is_direct = direct.contains(&room.room_id) || dm_rooms.contains(&room.room_id);
+        if let Some(preview) = previews.remove(&room.room_id) {
+            room.last_message = preview.last_message;
+            room.last_activity_ts = preview.last_activity_ts;
+        }
+    }
+}
+
+/// the choices channels get. "joined" would hide a channel's history even from
+/// members who were invited to it, which nothing in agora expects
+fn check_history_visibility(visibility: HistoryVisibility) -> Result<(), Box<Response>> {
+    if visibility == HistoryVisibility::Joined {
+        return Err(Box::new(agora_error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_PARAM",
+            "history_visibility must be shared, invited or world_readable",
+        )));
+    }
+    Ok(())
+}
+
+/// tags the spec defines for clients to set, plus the user namespace
+fn valid_tag(tag: &str) -> bool {
+    match tag.strip_prefix("u.") {
+        Some(name) => !name.is_empty() && tag.len() <= 255,
+        None => tag == "m.favourite" || tag == "m.lowpriority",
+    }
+}
+
+/// channel types clients know how to show
+pub const CHANNEL_TYPES: [&str; 5] = ["text", "voice", "forum", "stage", "announcement"];
+
+#[utoipa::path(
+    post,
+    path = "/rooms/create",
+    tag = "rooms",
+    request_body = CreateRoomRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = CreateRoomResponse), super::ErrorResponses)
+)]
 async fn create_room(
     state: State<Arc<AppState>>,
-    Json(req): Json<CreateRoomRequest>,
-) -> Result<Json<CreateRoomResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    AuthJson(auth, req): AuthJson<CreateRoomRequest>,
+) -> Result<Json<CreateRoomResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
 
     let parent_space_id = req.parent_space_id.clone();
     let is_space = req.is_space.unwrap_or(false);
     let channel_type = req.channel_type.clone().unwrap_or_else(|| "text".to_string());
+    if !is_space && !CHANNEL_TYPES.contains(&channel_type.as_str()) {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    if let Some(language) = req.language.as_deref() {
+        if !crate::translate::is_valid_language_tag(language) {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+    }
+    let history_visibility = req.history_visibility.unwrap_or(HistoryVisibility::Shared);
+    check_history_visibility(history_visibility).map_err(|e| *e)?;
+
+    // a channel in a server is the server's to add to
+    if let Some(space_id) = parent_space_id.as_deref() {
+        authz::require_permission_in(&matrix, space_id, Permission::ManageChannels)
+            .await
+            .map_err(|e| authz_error(&e))?;
+    }
 
-    match matrix.create_room(req.name.clone(), req.topic.clone(), is_space).await {
+    let access = ChannelPermissions { private: req.private && !is_space, allowed_role_ids: req.allowed_role_ids.clone() };
+    let created = if access.private {
+        let join_rules = access.join_rules(parent_space_id.as_deref());
+        matrix.create_private_room(req.name.clone(), req.topic.clone(), join_rules, history_visibility).await
+    } else {
+        matrix.create_room(req.name.clone(), req.topic.clone(), is_space, history_visibility).await
+    };
+
+    match created {
         Ok(response) => {
             let room_id = response.room_id.clone();
 
             // store the channel type as a Matrix state event so all clients can read it
-            // store for all non-space channels (text, voice, forum) so the frontend
+            // store for all non-space channels (text, voice, forum, stage) so the frontend
             // can reliably distinguish them without falling back to defaults
             if !is_space {
-                let content = serde_json::json!({ "type": channel_type });
+                let mut content = serde_json::json!({ "type": channel_type });
+                if let Some(language) = &req.language {
+                    content["language"] = serde_json::Value::String(language.clone());
+                }
+                if let Some(limit) = req.user_limit.filter(|l| *l > 0 && channel_type == "voice") {
+                    content["user_limit"] = serde_json::json!(limit);
+                }
                 if let Err(e) = matrix.send_state_event(
                     room_id.clone(),
                     "agora.room.type".to_string(),
@@ -289,57 +810,93 @@ async fn create_room(
                 ).await {
                     tracing::warn!("failed to set channel type state event: {}", e);
                 }
+                if channel_type == "stage" {
+                    if let Err(e) = voice::open_stage_requests(&matrix, &room_id).await {
+                        tracing::warn!("failed to open stage requests to members: {}", e);
+                    }
+                }
+                // the service account follows the channel for push and search
+                service_account::bring_in(&state, &matrix, &room_id).await;
             }
 
-            // note: we do NOT create a room alias here.
-            // channels are discovered via the space hierarchy (m.space.child), not by alias.
-            // aliases are only set for servers via the vanity slug in /servers/meta.
-            // creating aliases by name (e.g. #general:localhost) causes collisions when
-            // multiple servers have channels with the same name.
+            // channels are found through the space hierarchy (m.space.child), the alias
+            // is for joining and linking by name. servers get theirs from the vanity
+            // slug in /servers/meta, private channels none at all
+            let alias = if is_space || access.private {
+                None
+            } else {
+                claim_name_alias(&matrix, &room_id, &req.name, parent_space_id.as_deref(), &state.server_name).await
+            };
 
             // if this room has a parent space, add it as a space child
             if let Some(space_id) = parent_space_id.clone() {
-                if let Err(e) = matrix.add_space_child(space_id, room_id.clone()).await {
-                    tracing::warn!("failed to add space child relationship: {}", e);
+                if let Err(e) = link_to_parent(&matrix, &room_id, &space_id, &state.server_name).await {
+                    tracing::warn!("failed to link channel to its space: {}", e);
                     // don't fail the whole request — room was created, just the hierarchy link failed
                 }
+                if access.private {
+                    let content = serde_json::to_value(&access).unwrap_or_default();
+                    if let Err(e) = matrix.send_state_event(space_id, channel_access::EVENT_TYPE.to_string(), room_id.clone(), content).await {
+                        tracing::warn!("failed to record private channel roles: {}", e);
+                    }
+                }
             }
 
             // note: we do NOT auto-create a "general" channel here.
-            // the wizard (CreateServerWizard.svelte) creates all channels based on the
-            // chosen template, so auto-creating one here would produce duplicates.
+            // a new server gets its channels from POST /servers/create_from_template,
+            // so auto-creating one here would produce duplicates.
 
             Ok(Json(CreateRoomResponse {
                 room_id,
+                alias,
             }))
         }
         Err(e) => {
             tracing::error!("failed to create room: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
 
+/// hang `room_id` under `parent_id`: the parent lists it as an m.space.child, and
+/// the channel points back with m.space.parent so server settings can be found from it
+pub async fn link_to_parent(matrix: &MatrixClient, room_id: &str, parent_id: &str, via: &str) -> Result<(), MatrixError> {
+    matrix.add_space_child(parent_id.to_string(), room_id.to_string(), via).await?;
+    let parent = serde_json::json!({ "via": [via], "canonical": true });
+    matrix
+        .send_state_event(room_id.to_string(), "m.space.parent".to_string(), parent_id.to_string(), parent)
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/join",
+    tag = "rooms",
+    request_body = JoinRoomRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = CreateRoomResponse), super::StatusErrors)
+)]
 async fn join_room(
     state: State<Arc<AppState>>,
-    Json(req): Json<JoinRoomRequest>,
+    AuthJson(auth, req): AuthJson<JoinRoomRequest>,
 ) -> Result<Json<CreateRoomResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
     // normalize the input — matrix requires ! for room ids or # for aliases
     let room_id_or_alias = {
         let input = req.room_id_or_alias.trim().to_string();
         if input.starts_with('!') || input.starts_with('#') {
-            // already has a sigil — if no server part, append :localhost
+            // already has a sigil — if no server part, append our server name
             if input.contains(':') {
                 input
             } else {
-                format!("{}:localhost", input)
+                format!("{}:{}", input, state.server_name)
             }
         } else {
             // bare name — treat as alias
-            format!("#{}:localhost", input)
+            format!("#{}:{}", input, state.server_name)
         }
     };
     tracing::info!("joining room: {}", room_id_or_alias);
@@ -348,35 +905,61 @@ async fn join_room(
         Ok(response) => {
             let room_id = response.room_id.clone();
 
-            // if the joined room is a space, also join all child channels
-            // so members can immediately read and write in the channels
-            if let Ok(state_events) = matrix.get_room_state(room_id.clone()).await {
-                // check if it's a space
-                let is_space = state_events.iter().any(|e| {
-                    e.event_type == "m.room.create"
-                        && e.content.get("type").and_then(|v| v.as_str()) == Some("m.space")
-                });
-
-                if is_space {
-                    // get all child room ids from m.space.child events
-                    let child_ids: Vec<String> = state_events
-                        .iter()
-                        .filter(|e| e.event_type == "m.space.child")
-                        .filter_map(|e| e.state_key.clone())
-                        .filter(|k| !k.is_empty())
-                        .collect();
-
-                    for child_id in child_ids {
-                        if let Err(e) = matrix.join_room(child_id.clone()).await {
-                            tracing::warn!("failed to auto-join child channel {}: {}", child_id, e);
-                        } else {
-                            tracing::info!("auto-joined child channel: {}", child_id);
+            // if the joined room is a space, also join everything below it (categories
+            // and their channels) so members can immediately read and write in the channels.
+            // breadth-first: a child's state is only readable once we've joined it, so
+            // each level is discovered by joining the one above.
+            // private channels are skipped unless the member's roles on the joined
+            // server allow them in.
+            let max_depth = hierarchy::max_depth();
+            let mut visited = std::collections::HashSet::from([room_id.clone()]);
+            let mut queue = std::collections::VecDeque::from([(room_id.clone(), 0usize)]);
+            let mut server_state = Vec::new();
+            let mut user_id: Option<String> = None;
+
+            while let Some((space_id, depth)) = queue.pop_front() {
+                let Ok(state_events) = matrix.get_room_state(space_id.clone()).await else {
+                    continue;
+                };
+                if !hierarchy::is_space(&state_events) || depth >= max_depth {
+                    continue;
+                }
+                if depth == 0 {
+                    server_state = state_events.clone();
+                    // the default role goes out before the channels are walked, so
+                    // private channels it opens are joined too
+                    user_id = matrix.whoami().await.ok().map(|w| w.user_id);
+                    if let Some(uid) = user_id.as_deref() {
+                        if servers::assign_default_role(&state, &room_id, &server_state, uid).await.is_some() {
+                            server_state = matrix.get_room_state(room_id.clone()).await.unwrap_or(server_state);
+                        }
+                    }
+                }
+
+                for child_id in hierarchy::child_ids(&state_events) {
+                    if !visited.insert(child_id.clone()) {
+                        continue; // cycle or shared child — already handled
+                    }
+                    let access = ChannelPermissions::of_child(&state_events, &child_id);
+                    if access.private {
+                        if user_id.is_none() {
+                            user_id = matrix.whoami().await.ok().map(|w| w.user_id);
                         }
+                        if !user_id.as_deref().is_some_and(|u| access.allows(&server_state, u)) {
+                            tracing::info!("not auto-joining private channel {}", child_id);
+                            continue;
+                        }
+                    }
+                    if let Err(e) = matrix.join_room(child_id.clone()).await {
+                        tracing::warn!("failed to auto-join child channel {}: {}", child_id, e);
+                    } else {
+                        tracing::info!("auto-joined child channel: {}", child_id);
+                        queue.push_back((child_id, depth + 1));
                     }
                 }
             }
 
-            Ok(Json(CreateRoomResponse { room_id }))
+            Ok(Json(CreateRoomResponse { room_id, alias: None }))
         }
         Err(e) => {
             tracing::error!("failed to join room: {}", e);
@@ -385,17 +968,70 @@ async fn join_room(
     }
 }
 
+/// whether an alias is free, for the create-channel dialog. takes a full alias or
+/// a channel name, which is turned into the alias create_room would try first
+#[utoipa::path(
+    get,
+    path = "/rooms/alias/check",
+    tag = "rooms",
+    params(AliasCheckQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = AliasCheckResponse), super::ErrorResponses)
+)]
+async fn check_alias(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<AliasCheckQuery>,
+) -> Result<Json<AliasCheckResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let input = params.alias.trim();
+    let alias = match input.strip_prefix('#') {
+        Some(rest) => {
+            let (localpart, server) = rest.split_once(':').unwrap_or((rest, state.server_name.as_str()));
+            let valid = !localpart.is_empty()
+                && !server.is_empty()
+                && !localpart.chars().any(|c| c.is_whitespace() || c == ':' || c == '#');
+            valid.then(|| format!("#{}:{}", localpart, server))
+        }
+        None => name_alias(input, &state.server_name),
+    };
+    let Some(alias) = alias else {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "that doesn't make a room alias"));
+    };
+
+    match matrix.resolve_alias(&alias).await {
+        Ok(resolved) => Ok(Json(AliasCheckResponse { alias, available: false, room_id: Some(resolved.room_id) })),
+        Err(e) if e.is_not_found() => Ok(Json(AliasCheckResponse { alias, available: true, room_id: None })),
+        Err(e) => {
+            tracing::warn!("failed to resolve {}: {}", alias, e);
+            Err(matrix_error(&e, StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/rooms/members",
+    tag = "rooms",
+    params(RoomMembersQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomMembersResponse, description = "when `limit` or `after` is given the answer is a page instead: { items, next_cursor, total }"), super::StatusErrors)
+)]
 async fn get_room_members(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<RoomMembersQuery>,
-) -> Result<Json<RoomMembersResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+) -> Result<Response, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    let page = PageParams { limit: params.limit, after: params.after };
 
     match matrix.get_room_members(params.room_id).await {
         Ok(response) => {
             // filter for actual joined members, extract info from state events
-            let members = response
+            let mut members: Vec<MemberInfo> = response
                 .members
                 .into_iter()
                 .filter(|m| {
@@ -409,7 +1045,19 @@ async fn get_room_members(
                 })
                 .collect();
 
-            Ok(Json(RoomMembersResponse { members }))
+            if !page.is_paginated() {
+                return Ok(Json(RoomMembersResponse { members }).into_response());
+            }
+
+            // pages are ordered by user id; the cursor is the last user id served
+            members.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+            let paginated = paginate_sorted(
+                members,
+                &page,
+                |m| m.user_id.clone(),
+                |m, after: &String| m.user_id.cmp(after),
+            )?;
+            Ok(Json(paginated).into_response())
         }
         Err(e) => {
             tracing::error!("failed to get room members: {}", e);
@@ -418,12 +1066,20 @@ async fn get_room_members(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/rooms/invite",
+    tag = "rooms",
+    request_body = InviteRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
 async fn invite_user(
     state: State<Arc<AppState>>,
-    Json(req): Json<InviteRequest>,
+    AuthJson(auth, req): AuthJson<InviteRequest>,
 ) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
     match matrix.invite_user(req.room_id, req.user_id).await {
         Ok(_) => Ok(StatusCode::OK),
@@ -434,324 +1090,1379 @@ async fn invite_user(
     }
 }
 
+/// join a room the user was invited to. the same as /rooms/join, so an
+/// invite to a server brings its channels along.
+#[utoipa::path(
+    post,
+    path = "/rooms/invite/accept",
+    tag = "rooms",
+    request_body = InviteResponseRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = CreateRoomResponse), super::StatusErrors)
+)]
+async fn accept_invite(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<InviteResponseRequest>,
+) -> Result<Json<CreateRoomResponse>, StatusCode> {
+    let join = JoinRoomRequest { room_id_or_alias: req.room_id };
+    join_room(state, AuthJson(auth, join)).await
+}
+
+/// turn an invite down: leave the room and forget it, so it's gone from sync
+#[utoipa::path(
+    post,
+    path = "/rooms/invite/reject",
+    tag = "rooms",
+    request_body = InviteResponseRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn reject_invite(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<InviteResponseRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    matrix.leave_room(req.room_id.clone()).await.map_err(|e| {
+        tracing::warn!("failed to reject invite to {}: {}", req.room_id, e);
+        matrix_error(&e, StatusCode::BAD_REQUEST)
+    })?;
+    if let Err(e) = matrix.forget_room(req.room_id).await {
+        tracing::warn!("failed to forget room after rejecting invite: {}", e);
+    }
+    Ok(StatusCode::OK)
+}
+
+/// the content of an attachment message, or why it isn't a valid one
+fn media_content(req: &SendMessageRequest, msgtype: &str) -> Result<serde_json::Value, String> {
+    if !MEDIA_MSGTYPES.contains(&msgtype) {
+        return Err(format!("unsupported msgtype {}", msgtype));
+    }
+    let url = req.url.as_deref().unwrap_or_default();
+    if !content::is_mxc_uri(url) {
+        return Err("url must be an mxc:// uri".to_string());
+    }
+    let mut content = serde_json::json!({ "msgtype": msgtype, "body": req.content, "url": url });
+    if let Some(info) = &req.info {
+        content["info"] = serde_json::json!(info);
+    }
+    Ok(content)
+}
+
+/// the content of an m.text message: formatted when asked to, with the room
+/// members it mentions in m.mentions and as pills in the html
+async fn text_content(matrix: &MatrixClient, req: &SendMessageRequest) -> Result<serde_json::Value, Response> {
+    let mut formatted_body = match req.formatted_body.as_deref() {
+        Some(html) => Some(content::sanitize_html(html)),
+        None if req.markdown => Some(content::render_markdown(&req.content)),
+        None => None,
+    };
+
+    let mentions = resolve_mentions(matrix, &req.room_id, &req.content).await?;
+    if !mentions.user_ids.is_empty() {
+        let html = formatted_body.unwrap_or_else(|| content::text_to_html(&req.content));
+        formatted_body = Some(content::pill_mentions(&html, &mentions.user_ids));
+    }
+
+    let mut message = serde_json::json!({ "msgtype": "m.text", "body": req.content });
+    if let Some(formatted_body) = formatted_body {
+        message["format"] = "org.matrix.custom.html".into();
+        message["formatted_body"] = formatted_body.into();
+    }
+    if !mentions.is_empty() {
+        message["m.mentions"] = mentions.to_json();
+    }
+    Ok(message)
+}
+
+/// the joined members `body` names by @localpart or mxid, and @everyone —
+/// which is 403 for senders without the mention_everyone permission
+async fn resolve_mentions(matrix: &MatrixClient, room_id: &str, body: &str) -> Result<Mentions, Response> {
+    let mut mentions = Mentions::default();
+    if !body.contains('@') {
+        return Ok(mentions);
+    }
+
+    if content::mentions_everyone(body) {
+        let Some(mut loader) = PolicyLoader::new(matrix).await else {
+            return Err(StatusCode::UNAUTHORIZED.into_response());
+        };
+        if !loader.for_room(room_id).await.can_mention_everyone {
+            return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "you can't mention everyone here"));
+        }
+        mentions.room = true;
+    }
+
+    // without the member list the message still goes out, just without pings
+    match matrix.get_room_members(room_id.to_string()).await {
+        Ok(members) => {
+            mentions.user_ids = members
+                .members
+                .into_iter()
+                .filter(|m| m.content.membership.as_deref() == Some("join"))
+                .map(|m| m.state_key)
+                .filter(|user_id| content::mentions_user(body, user_id))
+                .collect();
+        }
+        Err(e) => tracing::debug!("cannot resolve mentions in {}: {}", room_id, e),
+    }
+    Ok(mentions)
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/send",
+    tag = "rooms",
+    request_body = SendMessageRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = SendMessageResponse), super::ErrorResponses)
+)]
 async fn send_message(
     state: State<Arc<AppState>>,
-    Json(req): Json<SendMessageRequest>,
-) -> Result<Json<SendMessageResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    AuthJson(auth, req): AuthJson<SendMessageRequest>,
+) -> Result<Json<SendMessageResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let txn_id = checked_txn_id(req.txn_id.as_deref()).map_err(|e| *e)?;
+    if let Some(txn_id) = txn_id {
+        if let Some(event_id) = idempotency::sent(&state, &auth.access_token, idempotency::Kind::Message, txn_id).await {
+            return Ok(Json(SendMessageResponse { event_id }));
+        }
+    }
+    if let Some(mut loader) = PolicyLoader::new(&matrix).await {
+        check_timeout(&state, &mut loader, &req.room_id).await?;
+        check_thread_lock(&matrix, &mut loader, &req.room_id).await?;
+    }
+    let content = match req.msgtype.as_deref() {
+        None | Some("m.text") => {
+            let mut content = text_content(&matrix, &req).await?;
+            // receivers render the link without each fetching it
+            if let Some(preview) = link_preview::for_message(&state, &req.content).await {
+                content[link_preview::CONTENT_KEY] = serde_json::to_value(preview).unwrap_or_default();
+            }
+            content
+        }
+        Some(msgtype) => media_content(&req, msgtype)
+            .map_err(|msg| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", msg))?,
+    };
+    let slowmode_key = take_slowmode_slot(&state, &matrix, &req.room_id).await?;
+    let homeserver_txn_id = txn_id.map(|t| idempotency::homeserver_txn_id(idempotency::Kind::Message, t));
+    let result = matrix.send_message_content(req.room_id.clone(), content, homeserver_txn_id.as_deref()).await;
+
+    // a message that didn't go out doesn't start the wait
+    if let (Err(_), Some(key), Some(mut redis)) = (&result, &slowmode_key, state.get_redis().await) {
+        let _: redis::RedisResult<()> = redis.del(key).await;
+    }
 
-    match matrix.send_message(req.room_id, req.content).await {
+    match result {
         Ok(result) => {
             let event_id = result
                 .get("event_id")
                 .and_then(|v| v.as_str())
                 .unwrap_or("")
                 .to_string();
+            if let Some(txn_id) = txn_id {
+                idempotency::remember(&state, &auth.access_token, idempotency::Kind::Message, txn_id, &event_id).await;
+            }
+            // the sender's rooms list shows it straight away
+            room_summaries::invalidate_previews(&state, std::slice::from_ref(&req.room_id)).await;
+
+            // queue email digests for away DM recipients / mentioned users
+            crate::email::record_message(
+                state.0.clone(), matrix, req.room_id, event_id.clone(), req.content,
+            );
+
             Ok(Json(SendMessageResponse { event_id }))
         }
         Err(e) => {
             tracing::error!("failed to send message: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
 
-async fn get_space_children(
-    state: State<Arc<AppState>>,
-    Query(params): Query<SpaceChildrenQuery>,
-) -> Result<Json<SpaceChildrenResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token.clone());
-
-    // get space state events to find m.space.child entries
-    let state_events = matrix.get_room_state(params.space_id.clone()).await
-        .map_err(|e| {
-            tracing::error!("failed to get space state: {}", e);
-            StatusCode::BAD_REQUEST
-        })?;
+/// a client's txn_id, if it sent one it can be
+pub fn checked_txn_id(txn_id: Option<&str>) -> Result<Option<&str>, Box<Response>> {
+    match txn_id {
+        Some(txn_id) if !idempotency::is_valid(txn_id) => Err(Box::new(agora_error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_PARAM",
+            "txn_id may only use letters, digits and -_.~, up to 64 of them",
+        ))),
+        txn_id => Ok(txn_id),
+    }
+}
 
-    let child_room_ids: Vec<String> = state_events
-        .iter()
-        .filter(|e| e.event_type == "m.space.child")
-        .filter_map(|e| e.state_key.clone())
-        .filter(|key| !key.is_empty())
-        .collect();
+/// start the sender's slowmode wait in a room, refusing with 429 while the last one is
+/// still running. returns the redis key holding the wait. without redis there's
+/// nowhere to keep the timers, so messages go through unchecked.
+/// 403 with `timed_out_until` while the caller is timed out in the room's server
+async fn check_timeout(state: &AppState, loader: &mut PolicyLoader<'_>, room_id: &str) -> Result<(), Response> {
+    let now = chrono::Utc::now().timestamp_millis();
+    if !servers::may_be_timed_out(state, loader.user_id(), now).await {
+        return Ok(());
+    }
+    match loader.timed_out_until(room_id, now).await {
+        Some(until) => {
+            let body = serde_json::json!({
+                "errcode": "AGORA_TIMED_OUT",
+                "error": "you're timed out in this server",
+                "timed_out_until": until,
+            });
+            Err((StatusCode::FORBIDDEN, Json(body)).into_response())
+        }
+        None => Ok(()),
+    }
+}
 
-    let mut children = Vec::new();
+/// 403 in a locked forum thread, unless the caller moderates its server
+async fn check_thread_lock(matrix: &MatrixClient, loader: &mut PolicyLoader<'_>, room_id: &str) -> Result<(), Response> {
+    if !loader.is_locked_thread(room_id).await {
+        return Ok(());
+    }
+    if let Ok(Some(_)) = authz::require_permission_in(matrix, room_id, Permission::ManageMessages).await {
+        return Ok(());
+    }
+    Err(agora_error(StatusCode::FORBIDDEN, "AGORA_THREAD_LOCKED", "this thread is locked"))
+}
 
-    for room_id in child_room_ids {
-        // single state fetch per child — extract all fields in one pass
-        let (name, topic, is_space, channel_type) =
-            if let Ok(room_state) = matrix.get_room_state(room_id.clone()).await {
-                let name = room_state
-                    .iter()
-                    .find(|e| e.event_type == "m.room.name")
-                    .and_then(|e| e.content.get("name"))
-                    .and_then(|v| v.as_str())
-                    .map(String::from);
-
-                let topic = room_state
-                    .iter()
-                    .find(|e| e.event_type == "m.room.topic")
-                    .and_then(|e| e.content.get("topic"))
-                    .and_then(|v| v.as_str())
-                    .map(String::from);
-
-                let is_space = room_state
-                    .iter()
-                    .find(|e| e.event_type == "m.room.create")
-                    .and_then(|e| e.content.get("type"))
-                    .and_then(|v| v.as_str())
-                    .map(|t| t == "m.space")
-                    .unwrap_or(false);
-
-                let channel_type = room_state
-                    .iter()
-                    .find(|e| e.event_type == "agora.room.type")
-                    .and_then(|e| e.content.get("type"))
-                    .and_then(|v| v.as_str())
-                    .map(String::from)
-                    .unwrap_or_else(|| "text".to_string());
-
-                (name, topic, is_space, channel_type)
-            } else {
-                (None, None, false, "text".to_string())
-            };
+async fn take_slowmode_slot(
+    state: &AppState,
+    matrix: &MatrixClient,
+    room_id: &str,
+) -> Result<Option<String>, Response> {
+    let Some(mut redis) = state.get_redis().await else {
+        return Ok(None);
+    };
+    let Some(mut loader) = PolicyLoader::new(matrix).await else {
+        return Ok(None);
+    };
+    let policy = loader.for_room(room_id).await;
+    if policy.slowmode_seconds == 0 {
+        return Ok(None);
+    }
 
-        children.push(RoomInfo {
-            room_id,
-            name,
-            topic,
-            is_space,
-            member_count: None,
-            channel_type: Some(channel_type),
-        });
+    let key = format!("slowmode:{}:{}", room_id, policy.user_id);
+    let claimed: redis::RedisResult<Option<String>> = redis::cmd("SET")
+        .arg(&key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(policy.slowmode_seconds)
+        .query_async(&mut redis)
+        .await;
+    match claimed {
+        Ok(Some(_)) => Ok(Some(key)),
+        Ok(None) => {
+            let ttl: i64 = redis.ttl(&key).await.unwrap_or(-1);
+            let retry_after = u64::try_from(ttl).ok().filter(|t| *t > 0).unwrap_or(policy.slowmode_seconds);
+            let body = serde_json::json!({
+                "errcode": "M_LIMIT_EXCEEDED",
+                "error": "this channel is in slowmode",
+                "retry_after": retry_after,
+                "retry_after_ms": retry_after * 1000,
+            });
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+            response.headers_mut().insert(axum::http::header::RETRY_AFTER, retry_after.into());
+            Err(response)
+        }
+        Err(e) => {
+            tracing::warn!("slowmode check failed, letting the message through: {}", e);
+            Ok(None)
+        }
     }
+}
 
-    Ok(Json(SpaceChildrenResponse { children }))
+#[utoipa::path(
+    get,
+    path = "/rooms/slowmode",
+    tag = "rooms",
+    params(SlowmodeQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = SlowmodeResponse), super::ErrorResponses)
+)]
+async fn get_slowmode(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<SlowmodeQuery>,
+) -> Result<Json<SlowmodeResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let room_state = matrix.get_room_state(params.room_id).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    Ok(Json(SlowmodeResponse { seconds: message_policy::slowmode_seconds(&room_state) }))
 }
 
-async fn get_room_state(
+#[utoipa::path(
+    post,
+    path = "/rooms/slowmode",
+    tag = "rooms",
+    request_body = SetSlowmodeRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = SlowmodeResponse), super::ErrorResponses)
+)]
+async fn set_slowmode(
     state: State<Arc<AppState>>,
-    Query(params): Query<RoomStateQuery>,
-) -> Result<Json<RoomStateResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    AuthJson(auth, req): AuthJson<SetSlowmodeRequest>,
+) -> Result<Json<SlowmodeResponse>, Response> {
+    if req.seconds > message_policy::MAX_SLOWMODE_SECONDS {
+        return Err(agora_error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_PARAM",
+            format!("slowmode can be at most {} seconds", message_policy::MAX_SLOWMODE_SECONDS),
+        ));
+    }
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let content = serde_json::json!({ "seconds": req.seconds });
+    matrix
+        .send_state_event(req.room_id.clone(), message_policy::SLOWMODE_EVENT_TYPE.to_string(), "".to_string(), content)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    room_summaries::invalidate(&state, &[req.room_id]).await;
+    Ok(Json(SlowmodeResponse { seconds: req.seconds }))
+}
 
-    match matrix.get_room_state(params.room_id).await {
-        Ok(state_events) => {
-            let events = state_events
-                .into_iter()
-                .map(|e| RoomStateEvent {
-                    event_type: e.event_type,
-                    sender: e.sender,
-                    content: e.content,
-                })
-                .collect();
-            Ok(Json(RoomStateResponse { events }))
+/// who can read a channel's history: its members (from when they were invited,
+/// or all of it) or anyone, which the public server preview needs
+#[utoipa::path(
+    post,
+    path = "/rooms/history_visibility",
+    tag = "rooms",
+    request_body = SetHistoryVisibilityRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn set_history_visibility(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetHistoryVisibilityRequest>,
+) -> Result<StatusCode, Response> {
+    check_history_visibility(req.history_visibility).map_err(|e| *e)?;
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission_in(&matrix, &req.room_id, Permission::ManageChannels)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    matrix
+        .send_state_event(
+            req.room_id.clone(),
+            "m.room.history_visibility".to_string(),
+            "".to_string(),
+            req.history_visibility.content(),
+        )
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    room_summaries::invalidate(&state, &[req.room_id]).await;
+    Ok(StatusCode::OK)
+}
+
+const DEFAULT_TYPING_TIMEOUT_MS: u64 = 30_000;
+const MAX_TYPING_TIMEOUT_MS: u64 = 120_000;
+
+/// start or stop typing in a room. the homeserver tells other matrix clients;
+/// websocket clients subscribed to the room get who's typing there now
+#[utoipa::path(
+    post,
+    path = "/rooms/typing",
+    tag = "rooms",
+    request_body = TypingRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn set_typing(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<TypingRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let timeout_ms = req.timeout_ms.unwrap_or(DEFAULT_TYPING_TIMEOUT_MS).min(MAX_TYPING_TIMEOUT_MS);
+    matrix
+        .set_typing(&auth.user_id, &req.room_id, req.typing, timeout_ms)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    let until = req.typing.then(|| std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms));
+    let user_ids = state.typing.set(&req.room_id, &auth.user_id, until);
+    state.publish(WsEvent::Typing(TypingEvent { kind: "typing", room_id: req.room_id, user_ids }));
+    Ok(StatusCode::OK)
+}
+
+/// tag one of the caller's rooms, or move it within the tag by setting a new order
+#[utoipa::path(
+    put,
+    path = "/rooms/tag",
+    tag = "rooms",
+    request_body = SetTagRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn set_tag(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetTagRequest>,
+) -> Result<StatusCode, Response> {
+    if !valid_tag(&req.tag) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "unknown tag"));
+    }
+    if req.order.is_some_and(|order| !(0.0..=1.0).contains(&order)) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "order must be between 0 and 1"));
+    }
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    matrix
+        .set_room_tag(&auth.user_id, &req.room_id, &req.tag, req.order)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/rooms/tag",
+    tag = "rooms",
+    request_body = RemoveTagRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn remove_tag(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<RemoveTagRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    matrix
+        .delete_room_tag(&auth.user_id, &req.room_id, &req.tag)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    Ok(StatusCode::OK)
+}
+
+/// a page of a room's message history, for scrolling back past what /sync delivered
+#[utoipa::path(
+    get,
+    path = "/rooms/messages",
+    tag = "rooms",
+    params(MessageHistoryQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = MessageHistoryResponse), super::ErrorResponses)
+)]
+async fn get_messages(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<MessageHistoryQuery>,
+) -> Result<Json<MessageHistoryResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    let limit = params.limit.unwrap_or(50).clamp(1, 100);
+
+    let page = matrix
+        .get_messages(&params.room_id, params.from.as_deref(), params.dir, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to get messages for {}: {}", params.room_id, e);
+            matrix_error(&e, StatusCode::BAD_GATEWAY)
+        })?;
+
+    let mut messages: Vec<HistoryMessage> = page
+        .chunk
+        .into_iter()
+        .filter(|e| e.event_type == "m.room.message")
+        .filter_map(|e| {
+            // redacted messages have no msgtype left
+            let msgtype = e.content.get("msgtype")?.as_str()?.to_string();
+            let body = e.content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+            let body = if e.content.pointer("/m.relates_to/m.in_reply_to").is_some() {
+                content::strip_reply_fallback(body)
+            } else {
+                body
+            }
+            .to_string();
+            let content = (msgtype.starts_with("agora.") || e.content.get(webhooks::CONTENT_FIELD).is_some())
+                .then(|| e.content.clone());
+            Some(HistoryMessage {
+                url: e.content.get("url").and_then(|v| v.as_str()).map(String::from),
+                info: e.content.get("info").filter(|v| v.is_object()).cloned(),
+                formatted_body: content::html_body(&e.content),
+                event_id: e.event_id,
+                sender: e.sender,
+                body,
+                msgtype,
+                origin_server_ts: e.origin_server_ts,
+                content,
+                translated_body: None,
+            })
+        })
+        .collect();
+
+    if let Some(lang) = params.translate_to.as_deref() {
+        let items: Vec<(String, String)> = messages
+            .iter()
+            .filter_map(|m| m.event_id.clone().map(|id| (id, m.body.clone())))
+            .collect();
+        let translated = crate::translate::translate_bodies(&state, &auth.access_token, lang, &items).await;
+        for message in messages.iter_mut() {
+            message.translated_body = message.event_id.as_ref().and_then(|id| translated.get(id).cloned());
         }
-        Err(e) => {
-            tracing::error!("failed to get room state: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+    }
+
+    Ok(Json(MessageHistoryResponse { messages, start: page.start, end: page.end }))
+}
+
+/// 403 for an edit or delete the message policy refuses. an expired window
+/// carries the cutoff so the client can explain when it closed.
+fn message_denied(denied: Denied) -> Response {
+    match denied {
+        Denied::WindowExpired { cutoff } => {
+            let body = serde_json::json!({
+                "errcode": "AGORA_WINDOW_EXPIRED",
+                "error": "this message can no longer be changed",
+                "cutoff": cutoff,
+            });
+            (StatusCode::FORBIDDEN, Json(body)).into_response()
         }
+        Denied::NotAllowed => agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "not your message"),
     }
 }
 
-async fn leave_room(
+/// the sender and send time of a message, or 404 if it isn't one
+async fn message_origin(matrix: &MatrixClient, room_id: &str, event_id: &str) -> Result<(String, Option<i64>), Response> {
+    let event = matrix.get_event(room_id, event_id).await.map_err(|e| {
+        tracing::debug!("cannot load event {}: {}", event_id, e);
+        StatusCode::NOT_FOUND.into_response()
+    })?;
+    if event["type"].as_str() != Some("m.room.message") {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+    let sender = event["sender"].as_str().unwrap_or_default().to_string();
+    Ok((sender, event["origin_server_ts"].as_i64()))
+}
+
+/// replace the body of one of the caller's messages (an m.replace relation),
+/// subject to the server's edit window
+#[utoipa::path(
+    post,
+    path = "/rooms/edit",
+    tag = "rooms",
+    request_body = EditMessageRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = SendMessageResponse), super::ErrorResponses)
+)]
+async fn edit_message(
     state: State<Arc<AppState>>,
-    Json(req): Json<LeaveRoomRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
-
-    // if this is a space, recursively leave all children (categories and their channels)
-    // so nothing lingers in joined_rooms after the server is left.
-    // categories are sub-spaces with their own m.space.child entries — we must
-    // recurse into them or channels inside categories will never be left.
-    if let Ok(state_events) = matrix.get_room_state(req.room_id.clone()).await {
-        let is_space = state_events.iter().any(|e| {
-            e.event_type == "m.room.create"
-                && e.content.get("type").and_then(|v| v.as_str()) == Some("m.space")
-        });
-
-        if is_space {
-            let child_ids: Vec<String> = state_events
-                .iter()
-                .filter(|e| e.event_type == "m.space.child")
-                .filter_map(|e| e.state_key.clone())
-                .filter(|k| !k.is_empty())
-                .collect();
+    AuthJson(auth, req): AuthJson<EditMessageRequest>,
+) -> Result<Json<SendMessageResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
-            for child_id in child_ids {
-                // check if this child is itself a sub-space (category) and recurse
-                if let Ok(child_state) = matrix.get_room_state(child_id.clone()).await {
-                    let child_is_space = child_state.iter().any(|e| {
-                        e.event_type == "m.room.create"
-                            && e.content.get("type").and_then(|v| v.as_str()) == Some("m.space")
-                    });
-
-                    if child_is_space {
-                        // leave and forget all grandchildren (channels inside this category)
-                        let grandchild_ids: Vec<String> = child_state
-                            .iter()
-                            .filter(|e| e.event_type == "m.space.child")
-                            .filter_map(|e| e.state_key.clone())
-                            .filter(|k| !k.is_empty())
-                            .collect();
-
-                        for gc_id in grandchild_ids {
-                            let _ = matrix.leave_room(gc_id.clone()).await;
-                            let _ = matrix.forget_room(gc_id).await;
-                        }
-                    }
-                }
+    let Some(mut loader) = PolicyLoader::new(&matrix).await else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let (sender, sent_at) = message_origin(&matrix, &req.room_id, &req.event_id).await?;
+    let policy = loader.for_room(&req.room_id).await;
+    policy
+        .can_edit(&sender, sent_at, chrono::Utc::now().timestamp_millis())
+        .map_err(message_denied)?;
 
-                // leave and forget the child (channel or category) itself
-                let _ = matrix.leave_room(child_id.clone()).await;
-                let _ = matrix.forget_room(child_id).await;
-            }
+    let content = serde_json::json!({
+        "msgtype": "m.text",
+        "body": format!("* {}", req.content),
+        "m.new_content": { "msgtype": "m.text", "body": req.content },
+        "m.relates_to": { "rel_type": "m.replace", "event_id": req.event_id },
+    });
+    match matrix.send_message_content(req.room_id.clone(), content, None).await {
+        Ok(result) => {
+            let event_id = result["event_id"].as_str().unwrap_or("").to_string();
+            room_summaries::invalidate_previews(&state, &[req.room_id]).await;
+            Ok(Json(SendMessageResponse { event_id }))
+        }
+        Err(e) => {
+            tracing::error!("failed to edit message: {}", e);
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
+}
 
-    // leave the space itself — treat "not a member" as success
-    match matrix.leave_room(req.room_id.clone()).await {
-        Ok(_) => {
-            let _ = matrix.forget_room(req.room_id).await;
-            Ok(StatusCode::OK)
+/// delete a message: the sender inside the server's delete window, moderators always
+#[utoipa::path(
+    post,
+    path = "/rooms/redact",
+    tag = "rooms",
+    request_body = RedactMessageRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn redact_message(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<RedactMessageRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    redact_checked(&matrix, &req.room_id, &req.event_id, req.reason).await?;
+    room_summaries::invalidate_previews(&state, &[req.room_id]).await;
+    Ok(StatusCode::OK)
+}
+
+/// redact a message if the caller may delete it — shared with the report queue
+pub async fn redact_checked(
+    matrix: &MatrixClient,
+    room_id: &str,
+    event_id: &str,
+    reason: Option<String>,
+) -> Result<(), Response> {
+    let Some(mut loader) = PolicyLoader::new(matrix).await else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+    let (sender, sent_at) = message_origin(matrix, room_id, event_id).await?;
+    let policy = loader.for_room(room_id).await;
+    policy
+        .can_delete(&sender, sent_at, chrono::Utc::now().timestamp_millis())
+        .map_err(message_denied)?;
+
+    match matrix.redact_event(room_id, event_id, reason).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::error!("failed to redact message: {}", e);
+            Err(StatusCode::BAD_REQUEST.into_response())
+        }
+    }
+}
+
+/// the emoji of an m.annotation — None once the reaction is redacted
+fn annotation_key(event: &crate::matrix::client::Event) -> Option<&str> {
+    event.content.pointer("/m.relates_to/key")?.as_str()
+}
+
+/// the reactions on a message and the caller's user id
+async fn load_reactions(
+    matrix: &MatrixClient,
+    room_id: &str,
+    event_id: &str,
+) -> Result<(Vec<crate::matrix::client::Event>, String), Response> {
+    let user_id = matrix
+        .whoami()
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::UNAUTHORIZED))?
+        .user_id;
+    let reactions = matrix
+        .get_relations(room_id, event_id, "m.annotation", "m.reaction")
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to load reactions on {}: {}", event_id, e);
+            matrix_error(&e, StatusCode::BAD_GATEWAY)
+        })?;
+    Ok((reactions, user_id))
+}
+
+/// react to a message with an emoji. reacting twice with the same key returns
+/// the existing reaction instead of sending another.
+#[utoipa::path(
+    post,
+    path = "/rooms/react",
+    tag = "rooms",
+    request_body = ReactionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = SendMessageResponse), super::ErrorResponses)
+)]
+async fn react(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ReactionRequest>,
+) -> Result<Json<SendMessageResponse>, Response> {
+    if req.key.is_empty() {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "reaction key must not be empty"));
+    }
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    if let Some(mut loader) = PolicyLoader::new(&matrix).await {
+        check_timeout(&state, &mut loader, &req.room_id).await?;
+    }
+    let (reactions, user_id) = load_reactions(&matrix, &req.room_id, &req.event_id).await?;
+    let existing = reactions
+        .into_iter()
+        .find(|r| r.sender == user_id && annotation_key(r) == Some(req.key.as_str()));
+    if let Some(event_id) = existing.and_then(|r| r.event_id) {
+        return Ok(Json(SendMessageResponse { event_id }));
+    }
+
+    let content = serde_json::json!({
+        "m.relates_to": { "rel_type": "m.annotation", "event_id": req.event_id, "key": req.key },
+    });
+    match matrix.send_event(&req.room_id, "m.reaction", content).await {
+        Ok(result) => {
+            let event_id = result["event_id"].as_str().unwrap_or("").to_string();
+            Ok(Json(SendMessageResponse { event_id }))
         }
         Err(e) => {
-            let err_str = e.to_string();
-            if err_str.contains("M_FORBIDDEN") || err_str.contains("not a member") || err_str.contains("not invited or joined") {
-                tracing::info!("user already not a member of room, treating leave as success");
-                let _ = matrix.forget_room(req.room_id).await;
-                Ok(StatusCode::OK)
-            } else {
-                tracing::error!("failed to leave room: {}", e);
-                Err(StatusCode::BAD_REQUEST)
-            }
+            tracing::error!("failed to react to {}: {}", req.event_id, e);
+            Err(matrix_error(&e, StatusCode::BAD_REQUEST))
         }
     }
 }
 
-async fn delete_room(
+/// take back the caller's reaction — a no-op if they hadn't reacted with that key
+#[utoipa::path(
+    post,
+    path = "/rooms/unreact",
+    tag = "rooms",
+    request_body = ReactionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn unreact(
     state: State<Arc<AppState>>,
-    Json(req): Json<DeleteRoomRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
-
-    // for channels/categories, we should remove them from parent space first
-    // then leave and forget
-    // Note: in matrix, you can't truly "delete" a room, only leave it
-    // for a proper delete, we'd need to kick all members and purge from db
-    
-    match matrix.leave_room(req.room_id.clone()).await {
-        Ok(_) => {
-            // try to forget, but don't fail if it doesn't work
-            if let Err(e) = matrix.forget_room(req.room_id).await {
-                tracing::warn!("failed to forget room after leaving: {}", e);
+    AuthJson(auth, req): AuthJson<ReactionRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let (reactions, user_id) = load_reactions(&matrix, &req.room_id, &req.event_id).await?;
+    let own = reactions
+        .iter()
+        .filter(|r| r.sender == user_id && annotation_key(r) == Some(req.key.as_str()))
+        .filter_map(|r| r.event_id.as_deref());
+    for reaction_id in own {
+        if let Err(e) = matrix.redact_event(&req.room_id, reaction_id, None).await {
+            tracing::error!("failed to remove reaction {}: {}", reaction_id, e);
+            return Err(matrix_error(&e, StatusCode::BAD_REQUEST));
+        }
+    }
+    Ok(StatusCode::OK)
+}
+
+/// reaction counts per emoji on one message
+#[utoipa::path(
+    get,
+    path = "/rooms/reactions",
+    tag = "rooms",
+    params(ReactionsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ReactionsResponse), super::ErrorResponses)
+)]
+async fn get_reactions(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ReactionsQuery>,
+) -> Result<Json<ReactionsResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let (events, user_id) = load_reactions(&matrix, &params.room_id, &params.event_id).await?;
+    // keys in the order they were first used, so ties keep a stable order
+    let mut reactions: Vec<ReactionCount> = Vec::new();
+    let mut counted = std::collections::HashSet::new();
+    for event in &events {
+        let Some(key) = annotation_key(event) else { continue };
+        // one reaction per user and key, even if the homeserver let a duplicate through
+        if !counted.insert((event.sender.as_str(), key)) {
+            continue;
+        }
+        let reacted = event.sender == user_id;
+        match reactions.iter_mut().find(|r| r.key == key) {
+            Some(r) => {
+                r.count += 1;
+                r.reacted |= reacted;
             }
-            Ok(StatusCode::OK)
+            None => reactions.push(ReactionCount { key: key.to_string(), count: 1, reacted }),
+        }
+    }
+    reactions.sort_by_key(|r| std::cmp::Reverse(r.count));
+    Ok(Json(ReactionsResponse { reactions }))
+}
+
+// rooms read per /hierarchy page, and pages followed at most
+const HIERARCHY_PAGE_SIZE: u32 = 100;
+const MAX_HIERARCHY_PAGES: usize = 10;
+
+#[utoipa::path(
+    get,
+    path = "/rooms/children",
+    tag = "rooms",
+    params(SpaceChildrenQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = SpaceChildrenResponse), super::StatusErrors)
+)]
+async fn get_space_children(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<SpaceChildrenQuery>,
+) -> Result<Json<SpaceChildrenResponse>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let max_depth = params.max_depth.unwrap_or(1).clamp(1, hierarchy::max_depth());
+    let page = PageParams { limit: params.limit, after: params.after.clone() };
+    page.cursor::<ChildCursor>()?;
+
+    // the space itself is always read with the caller's token — it's what
+    // says they may see the rest, and which of its channels are private
+    let root_state = match matrix.get_room_state(params.space_id.clone()).await {
+        Ok(root_state) if !root_state.is_empty() => root_state,
+        _ => {
+            tracing::error!("failed to get space state for {}", params.space_id);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let (first, next_cursor) = first_page(&root_state, &page)?;
+    let root = hierarchy::SpaceNode::root(&params.space_id, root_state);
+
+    // one /hierarchy walk names everything below; homeservers without it get
+    // walked room by room
+    let (nodes, member_counts) = match space_hierarchy(&matrix, &params.space_id, max_depth).await {
+        Ok(Some(rooms)) => {
+            // categories are read live for their channels' permissions; a channel
+            // is its summary, or what the hierarchy says when it can't be read
+            let nodes = hierarchy::walk_below(root, first, max_depth, |room_id, private| {
+                let room = rooms.get(&room_id);
+                let (state, matrix) = (&state, &matrix);
+                async move {
+                    let Some(room) = room else { return Vec::new() };
+                    let room_state = if room.room_type.as_deref() == Some("m.space") {
+                        matrix.get_room_state(room_id).await.unwrap_or_default()
+                    } else {
+                        room_summaries::load(state, matrix, room_id, private).await
+                    };
+                    if room_state.is_empty() { hierarchy_state(room) } else { room_state }
+                }
+            })
+            .await;
+            // children the caller can't see aren't in the hierarchy, and aren't listed
+            let nodes: Vec<hierarchy::SpaceNode> = nodes
+                .into_iter()
+                .filter(|n| n.parent_id.is_none() || rooms.contains_key(&n.room_id))
+                .collect();
+            let member_counts: HashMap<String, i32> = rooms
+                .iter()
+                .map(|(room_id, room)| (room_id.clone(), room.num_joined_members.clamp(0, i32::MAX as i64) as i32))
+                .collect();
+            (nodes, member_counts)
+        }
+        // single state fetch per room during the walk — extract all fields from it
+        Ok(None) => {
+            let nodes = hierarchy::walk_below(root, first, max_depth, |room_id, private| {
+                room_summaries::load(&state, &matrix, room_id, private)
+            })
+            .await;
+            (nodes, HashMap::new())
         }
         Err(e) => {
-            tracing::error!("failed to leave room: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            tracing::error!("failed to get space hierarchy for {}: {}", params.space_id, e);
+            return Err(StatusCode::BAD_REQUEST);
         }
+    };
+
+    let mut children = Vec::new();
+
+    for node in nodes.into_iter().skip(1) {
+        let mut info = RoomInfo::from_state(node.room_id, &node.state, node.parent_id);
+        info.order = node.order;
+        info.member_count = member_counts.get(&info.room_id).copied();
+        children.push(info);
     }
+
+    RoomInfo::count_participants(&state, &mut children).await;
+    Ok(Json(SpaceChildrenResponse { children, next_cursor }))
 }
 
-/// delete_server — owner-only: kick all members from every room in the server,
-/// then leave and forget everything. makes the server effectively disappear for everyone.
-/// matrix has no true room deletion, but kicking all members achieves the same result
-/// on a single-homeserver deployment.
-async fn delete_server(
-    state: State<Arc<AppState>>,
-    Json(req): Json<LeaveRoomRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+/// a page of a space's direct children as (room id, order), and the cursor past it
+type ChildPage = (Option<Vec<(String, Option<String>)>>, Option<String>);
 
-    // collect all room ids in the server: the space itself + all children + grandchildren
-    let mut all_room_ids: Vec<String> = vec![req.room_id.clone()];
+/// the root's direct children on the requested page — None for all of them
+fn first_page(root_state: &[crate::matrix::client::RoomStateEvent], page: &PageParams) -> Result<ChildPage, StatusCode> {
+    if !page.is_paginated() {
+        return Ok((None, None));
+    }
+    let paginated = paginate_sorted(
+        hierarchy::children(root_state),
+        page,
+        |(room_id, order)| ChildCursor { order: order.clone(), room_id: room_id.clone() },
+        |(room_id, order), c| hierarchy::child_order((room_id, order.as_deref()), (&c.room_id, c.order.as_deref())),
+    )?;
+    Ok((Some(paginated.items), paginated.next_cursor))
+}
 
-    if let Ok(space_state) = matrix.get_room_state(req.room_id.clone()).await {
-        let child_ids: Vec<String> = space_state
-            .iter()
-            .filter(|e| e.event_type == "m.space.child")
-            .filter_map(|e| e.state_key.clone())
-            .filter(|k| !k.is_empty())
-            .collect();
+/// every room /hierarchy shows below `space_id`, by id. None when the
+/// homeserver doesn't have /hierarchy
+async fn space_hierarchy(
+    matrix: &MatrixClient,
+    space_id: &str,
+    max_depth: usize,
+) -> Result<Option<HashMap<String, HierarchyRoom>>, MatrixError> {
+    let mut rooms = HashMap::new();
+    let mut from: Option<String> = None;
+    for _ in 0..MAX_HIERARCHY_PAGES {
+        let page = match matrix.get_space_hierarchy(space_id, HIERARCHY_PAGE_SIZE, from.as_deref(), max_depth).await {
+            Ok(page) => page,
+            Err(e) if hierarchy_unsupported(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        rooms.extend(page.rooms.into_iter().map(|room| (room.room_id.clone(), room)));
+        match page.next_batch {
+            Some(next) => from = Some(next),
+            None => break,
+        }
+    }
+    Ok(Some(rooms))
+}
 
-        for child_id in &child_ids {
-            // recurse into sub-spaces (categories)
-            if let Ok(child_state) = matrix.get_room_state(child_id.clone()).await {
-                let is_sub_space = child_state.iter().any(|e| {
-                    e.event_type == "m.room.create"
-                        && e.content.get("type").and_then(|v| v.as_str()) == Some("m.space")
-                });
-                if is_sub_space {
-                    let grandchild_ids: Vec<String> = child_state
-                        .iter()
-                        .filter(|e| e.event_type == "m.space.child")
-                        .filter_map(|e| e.state_key.clone())
-                        .filter(|k| !k.is_empty())
-                        .collect();
-                    all_room_ids.extend(grandchild_ids);
+/// the homeserver predates /hierarchy — worth walking the space instead
+fn hierarchy_unsupported(err: &MatrixError) -> bool {
+    match err {
+        MatrixError::MatrixApiError { errcode, .. } => errcode == "M_UNRECOGNIZED",
+        // a bare 404 / 405 from a proxy or a homeserver without the route
+        MatrixError::ApiError(_) => true,
+        _ => false,
+    }
+}
+
+/// what /hierarchy says about a room, as the state events RoomInfo and the
+/// walk read
+fn hierarchy_state(room: &HierarchyRoom) -> Vec<crate::matrix::client::RoomStateEvent> {
+    use crate::matrix::client::RoomStateEvent;
+    let event = |event_type: &str, content: serde_json::Value| RoomStateEvent {
+        event_type: event_type.to_string(),
+        state_key: Some(String::new()),
+        content,
+        sender: String::new(),
+    };
+    let mut room_state = vec![event("m.room.create", serde_json::json!({ "type": room.room_type }))];
+    if let Some(name) = &room.name {
+        room_state.push(event("m.room.name", serde_json::json!({ "name": name })));
+    }
+    if let Some(topic) = &room.topic {
+        room_state.push(event("m.room.topic", serde_json::json!({ "topic": topic })));
+    }
+    if let Some(url) = &room.avatar_url {
+        room_state.push(event("m.room.avatar", serde_json::json!({ "url": url })));
+    }
+    room_state.extend(room.children_state.iter().map(|child| RoomStateEvent {
+        event_type: child.event_type.clone(),
+        state_key: Some(child.state_key.clone()),
+        content: child.content.clone(),
+        sender: child.sender.clone(),
+    }));
+    room_state
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/settings",
+    tag = "rooms",
+    request_body = RoomSettingsRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomInfo), super::ErrorResponses)
+)]
+async fn update_room_settings(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<RoomSettingsRequest>,
+) -> Result<Json<RoomInfo>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let name = req.name.map(|n| n.trim().to_string());
+    if name.as_deref() == Some("") {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "name can't be empty"));
+    }
+    if let Some(url) = req.avatar_url.as_deref().filter(|u| !u.is_empty()) {
+        if !content::is_mxc_uri(url) {
+            return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "avatar_url must be an mxc:// uri"));
+        }
+    }
+
+    let before = matrix.get_room_state(req.room_id.clone()).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let old_name = RoomInfo::from_state(req.room_id.clone(), &before, None).name;
+
+    let room_type = match req.user_limit {
+        None => None,
+        Some(limit) => {
+            let mut content = before
+                .iter()
+                .find(|e| e.event_type == "agora.room.type")
+                .map(|e| e.content.clone())
+                .unwrap_or_default();
+            if content["type"] != "voice" {
+                return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "only voice channels have a user limit"));
+            }
+            match content.as_object_mut() {
+                Some(fields) if limit == 0 => {
+                    fields.remove("user_limit");
                 }
+                _ => content["user_limit"] = serde_json::json!(limit),
             }
-            all_room_ids.push(child_id.clone());
+            Some(content)
+        }
+    };
+
+    let updates = [
+        ("m.room.name", name.as_ref().map(|n| serde_json::json!({ "name": n }))),
+        ("m.room.topic", req.topic.map(|t| serde_json::json!({ "topic": t }))),
+        ("m.room.avatar", req.avatar_url.map(|u| serde_json::json!({ "url": u }))),
+        ("agora.room.type", room_type),
+    ];
+    for (event_type, content) in updates {
+        let Some(content) = content else { continue };
+        matrix
+            .send_state_event(req.room_id.clone(), event_type.to_string(), "".to_string(), content)
+            .await
+            .map_err(|e| {
+                tracing::error!("failed to set {} on {}: {}", event_type, req.room_id, e);
+                matrix_error(&e, StatusCode::BAD_REQUEST)
+            })?;
+    }
+    room_summaries::invalidate(&state, std::slice::from_ref(&req.room_id)).await;
+
+    if let (Some(old_name), Some(new_name)) = (old_name, name.as_deref()) {
+        if old_name != new_name {
+            rename_alias(&matrix, &req.room_id, &before, &old_name, new_name, &state.server_name).await;
         }
     }
 
-    // for each room, kick all members except the requester, then leave + forget
-    for room_id in &all_room_ids {
-        if let Ok(members) = matrix.get_room_members(room_id.clone()).await {
-            let my_user_id = req.user_id.clone().unwrap_or_default();
-            for member in members.members {
-                if member.event_type == "m.room.member"
-                    && member.content.membership.as_deref() == Some("join")
-                    && member.state_key != my_user_id
+    let after = matrix.get_room_state(req.room_id.clone()).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let mut info = RoomInfo::from_state(req.room_id, &after, None);
+    RoomInfo::count_participants(&state, std::slice::from_mut(&mut info)).await;
+    Ok(Json(info))
+}
+
+/// the alias create_room derives from a channel's name, before any suffix
+fn name_alias(name: &str, server_name: &str) -> Option<String> {
+    let localpart: String = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_whitespace() { '-' } else { c })
+        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    (!localpart.is_empty()).then(|| format!("#{}:{}", localpart, server_name))
+}
+
+/// give a new channel its name alias and make it the canonical one. when another
+/// room has the plain alias (every server has a #general) it's suffixed with the
+/// server's vanity slug, then with a few hex digits of the room id:
+/// #general-3f2a:server. None when the name makes no alias, or none could be set
+async fn claim_name_alias(
+    matrix: &MatrixClient,
+    room_id: &str,
+    name: &str,
+    parent_space_id: Option<&str>,
+    server_name: &str,
+) -> Option<String> {
+    let plain = name_alias(name, server_name)?;
+    let localpart = plain.strip_prefix('#')?.strip_suffix(&format!(":{}", server_name))?;
+
+    for attempt in 0..3 {
+        let alias = match attempt {
+            0 => plain.clone(),
+            1 => match server_slug(matrix, parent_space_id).await {
+                Some(slug) => format!("#{}-{}:{}", localpart, slug, server_name),
+                None => continue,
+            },
+            _ => {
+                let hash = format!("{:x}", Sha256::digest(room_id.as_bytes()));
+                format!("#{}-{}:{}", localpart, &hash[..4], server_name)
+            }
+        };
+        match matrix.create_room_alias(alias.clone(), room_id.to_string()).await {
+            Ok(()) => {
+                let content = serde_json::json!({ "alias": alias });
+                if let Err(e) = matrix
+                    .send_state_event(room_id.to_string(), "m.room.canonical_alias".to_string(), "".to_string(), content)
+                    .await
                 {
-                    let _ = matrix.kick_user(
-                        room_id.clone(),
-                        member.state_key,
-                        Some("server deleted".to_string()),
-                    ).await;
+                    tracing::warn!("failed to set canonical alias of {}: {}", room_id, e);
                 }
+                return Some(alias);
+            }
+            Err(e) if e.is_alias_taken() => tracing::debug!("{} is taken, trying another", alias),
+            Err(e) => {
+                tracing::warn!("failed to create alias {} for {}: {}", alias, room_id, e);
+                return None;
             }
         }
-        let _ = matrix.leave_room(room_id.clone()).await;
-        let _ = matrix.forget_room(room_id.clone()).await;
     }
+    tracing::warn!("no free alias for {} from {}", room_id, plain);
+    None
+}
 
-    Ok(StatusCode::OK)
+/// the vanity slug of the server a new channel is going into
+async fn server_slug(matrix: &MatrixClient, parent_space_id: Option<&str>) -> Option<String> {
+    let server_id = authz::server_of(matrix, parent_space_id?).await.ok().flatten()?;
+    servers::read_meta(matrix, &server_id).await.vanity_slug
 }
 
+/// a room whose canonical alias was generated from its old name gets one for the new
+/// name. the old alias keeps resolving and is listed in alt_aliases, so links shared
+/// before the rename still work. hand-picked aliases (vanity slugs) are left alone.
+async fn rename_alias(
+    matrix: &MatrixClient,
+    room_id: &str,
+    room_state: &[crate::matrix::client::RoomStateEvent],
+    old_name: &str,
+    new_name: &str,
+    server_name: &str,
+) {
+    let canonical = room_state.iter().find(|e| e.event_type == "m.room.canonical_alias").map(|e| &e.content);
+    let Some(old_alias) = canonical.and_then(|c| c.get("alias")).and_then(|a| a.as_str()) else {
+        return;
+    };
+    if name_alias(old_name, server_name).as_deref() != Some(old_alias) {
+        return;
+    }
+    let Some(new_alias) = name_alias(new_name, server_name).filter(|a| a != old_alias) else {
+        return;
+    };
+
+    if let Err(e) = matrix.create_room_alias(new_alias.clone(), room_id.to_string()).await {
+        // most likely taken by another room — keep the old alias as canonical
+        tracing::info!("not moving {} to {}: {}", room_id, new_alias, e);
+        return;
+    }
+    let mut alt_aliases: Vec<String> = canonical
+        .and_then(|c| c.get("alt_aliases"))
+        .and_then(|a| serde_json::from_value(a.clone()).ok())
+        .unwrap_or_default();
+    alt_aliases.retain(|a| a != &new_alias && a != old_alias);
+    alt_aliases.insert(0, old_alias.to_string());
+    let content = serde_json::json!({ "alias": new_alias, "alt_aliases": alt_aliases });
+    if let Err(e) = matrix
+        .send_state_event(room_id.to_string(), "m.room.canonical_alias".to_string(), "".to_string(), content)
+        .await
+    {
+        tracing::warn!("failed to update canonical alias of {}: {}", room_id, e);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/rooms/state",
+    tag = "rooms",
+    params(RoomStateQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomStateResponse), super::StatusErrors)
+)]
+async fn get_room_state(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<RoomStateQuery>,
+) -> Result<Json<RoomStateResponse>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    match matrix.get_room_state(params.room_id).await {
+        Ok(state_events) => {
+            let events = state_events
+                .into_iter()
+                .map(|e| RoomStateEvent {
+                    event_type: e.event_type,
+                    sender: e.sender,
+                    content: e.content,
+                })
+                .collect();
+            Ok(Json(RoomStateResponse { events }))
+        }
+        Err(e) => {
+            tracing::error!("failed to get room state: {}", e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/leave",
+    tag = "rooms",
+    request_body = LeaveRoomRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn leave_room(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<LeaveRoomRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    // if this is a space, leave everything below it (categories and their channels)
+    // so nothing lingers in joined_rooms after the server is left. the walk is
+    // breadth-first with a visited set, so nested categories are covered and a
+    // cycle in the hierarchy can't loop forever. deepest rooms are left first.
+    let nodes = hierarchy::walk_space(&matrix, &req.room_id, hierarchy::max_depth()).await;
+    for node in nodes.iter().skip(1).rev() {
+        let _ = matrix.leave_room(node.room_id.clone()).await;
+        let _ = matrix.forget_room(node.room_id.clone()).await;
+    }
+
+    // leave the space itself — treat "not a member" as success
+    match matrix.leave_room(req.room_id.clone()).await {
+        Ok(_) => {
+            let _ = matrix.forget_room(req.room_id).await;
+            Ok(StatusCode::OK)
+        }
+        // the homeserver refuses leaving a room you're not in with M_FORBIDDEN
+        Err(e) if e.is_forbidden() => {
+            tracing::info!("user already not a member of room, treating leave as success");
+            let _ = matrix.forget_room(req.room_id).await;
+            Ok(StatusCode::OK)
+        }
+        Err(e) => {
+            tracing::error!("failed to leave room: {}", e);
+            Err(matrix_error(&e, StatusCode::BAD_REQUEST))
+        }
+    }
+}
+
+/// sent into the parent space when one of its channels is deleted, so members'
+/// sync (and the event stream) drop it straight away
+pub const ROOM_DELETED_EVENT_TYPE: &str = "agora.room.deleted";
+
+/// delete a server's channel: unlist it from its space, remove everyone in it
+/// and its aliases, then leave and forget it — there's no true room deletion in
+/// matrix, but nobody can get back in. rooms outside any server (group chats,
+/// dms) are just left, like before.
+#[utoipa::path(
+    post,
+    path = "/rooms/delete",
+    tag = "rooms",
+    request_body = DeleteRoomRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = DeleteRoomResponse), super::ErrorResponses)
+)]
+async fn delete_room(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<DeleteRoomRequest>,
+) -> Result<Json<DeleteRoomResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let parent_id = match req.parent_space_id {
+        Some(parent_id) => {
+            let parent_state = matrix
+                .get_room_state(parent_id.clone())
+                .await
+                .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+            let listed = parent_state.iter().any(|e| {
+                e.event_type == "m.space.child"
+                    && e.state_key.as_deref() == Some(req.room_id.as_str())
+                    && e.content.as_object().is_some_and(|c| !c.is_empty())
+            });
+            if !listed {
+                return Err(agora_error(StatusCode::BAD_REQUEST, "AGORA_NOT_A_CHILD", "the room isn't listed in that space"));
+            }
+            Some(parent_id)
+        }
+        None => parent_space(&matrix, &req.room_id).await.ok().flatten(),
+    };
+    // top-level rooms aren't anyone's channel — a server is deleted with /servers/delete
+    let Some(parent_id) = parent_id else {
+        return leave_only(&matrix, req.room_id).await;
+    };
+    let access = match authz::require_permission_in(&matrix, &parent_id, Permission::ManageChannels).await {
+        Ok(Some(access)) => access,
+        Ok(None) | Err(authz::AuthzError::Unreadable(_)) => return leave_only(&matrix, req.room_id).await,
+        Err(e) => return Err(authz_error(&e)),
+    };
+    let room_state = matrix
+        .get_room_state(req.room_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    if let Err(e) = matrix.remove_space_child(parent_id.clone(), req.room_id.clone()).await {
+        return Err(matrix_error(&e, StatusCode::BAD_REQUEST));
+    }
+    let deleted = serde_json::json!({ "room_id": req.room_id, "deleted_by": access.user_id });
+    if let Err(e) = matrix.send_event(&parent_id, ROOM_DELETED_EVENT_TYPE, deleted).await {
+        tracing::warn!("failed to announce deletion of {} in {}: {}", req.room_id, parent_id, e);
+    }
+
+    let aliases = servers::room_aliases(&room_state);
+    let (outcome, aliases_removed) =
+        servers::empty_room(&matrix, &access.user_id, &req.room_id, &room_state, aliases, "channel deleted").await;
+
+    let room_ids = [req.room_id];
+    servers::forget_room_rows(&state, &room_ids).await;
+    room_summaries::invalidate(&state, &room_ids).await;
+    Ok(Json(DeleteRoomResponse {
+        deleted: true,
+        members_removed: outcome.members_removed,
+        aliases_removed,
+        complete: outcome.ok,
+    }))
+}
+
+/// the old behaviour of /rooms/delete: leave and forget, nothing else
+async fn leave_only(matrix: &MatrixClient, room_id: String) -> Result<Json<DeleteRoomResponse>, Response> {
+    if let Err(e) = matrix.leave_room(room_id.clone()).await {
+        tracing::error!("failed to leave room: {}", e);
+        return Err(matrix_error(&e, StatusCode::BAD_REQUEST));
+    }
+    // try to forget, but don't fail if it doesn't work
+    if let Err(e) = matrix.forget_room(room_id).await {
+        tracing::warn!("failed to forget room after leaving: {}", e);
+    }
+    Ok(Json(DeleteRoomResponse { deleted: false, members_removed: 0, aliases_removed: Vec::new(), complete: true }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/category/create",
+    tag = "rooms",
+    request_body = CreateCategoryRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = CreateCategoryResponse), super::ErrorResponses)
+)]
 async fn create_category(
     state: State<Arc<AppState>>,
-    Json(req): Json<CreateCategoryRequest>,
-) -> Result<Json<CreateCategoryResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    AuthJson(auth, req): AuthJson<CreateCategoryRequest>,
+) -> Result<Json<CreateCategoryResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission_in(&matrix, &req.parent_space_id, Permission::ManageChannels)
+        .await
+        .map_err(|e| authz_error(&e))?;
+
+    // categories can't contain categories — the ui only renders server → category → channel
+    if hierarchy::space_nesting(&matrix, &req.parent_space_id).await >= hierarchy::MAX_SPACE_NESTING {
+        return Err(agora_error(
+            StatusCode::BAD_REQUEST,
+            "AGORA_HIERARCHY_TOO_DEEP",
+            format!("spaces can only be nested {} levels deep", hierarchy::MAX_SPACE_NESTING),
+        ));
+    }
 
-    match matrix.create_category(req.name, req.parent_space_id).await {
+    match matrix.create_category(req.name, req.parent_space_id, &state.server_name).await {
         Ok(response) => Ok(Json(CreateCategoryResponse {
             room_id: response.room_id,
         })),
         Err(e) => {
             tracing::error!("failed to create category: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/rooms/permissions",
+    tag = "rooms",
+    params(PermissionsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = PermissionsResponse), super::StatusErrors)
+)]
 async fn get_permissions(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<PermissionsQuery>,
 ) -> Result<Json<PermissionsResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
     match matrix.get_power_levels(params.room_id).await {
         Ok(power_levels) => Ok(Json(PermissionsResponse {
@@ -765,19 +2476,31 @@ async fn get_permissions(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/rooms/permissions",
+    tag = "rooms",
+    request_body = SetPermissionsRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
 async fn set_permissions(
     state: State<Arc<AppState>>,
-    Json(req): Json<SetPermissionsRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    AuthJson(auth, req): AuthJson<SetPermissionsRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    authz::require_permission_in(&matrix, &req.room_id, Permission::ManageRoles)
+        .await
+        .map_err(|e| authz_error(&e))?;
 
     // first get current power levels
     let current = match matrix.get_power_levels(req.room_id.clone()).await {
         Ok(pl) => pl,
         Err(e) => {
             tracing::error!("failed to get current power levels: {}", e);
-            return Err(StatusCode::BAD_REQUEST);
+            return Err(StatusCode::BAD_REQUEST.into_response());
         }
     };
 
@@ -801,17 +2524,128 @@ async fn set_permissions(
         Ok(_) => Ok(StatusCode::OK),
         Err(e) => {
             tracing::error!("failed to set permissions: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
+        }
+    }
+}
+
+/// the space a channel sits in, from its m.space.parent state
+async fn parent_space(matrix: &MatrixClient, room_id: &str) -> Result<Option<String>, MatrixError> {
+    let room_state = matrix.get_room_state(room_id.to_string()).await?;
+    Ok(room_state
+        .iter()
+        .filter(|e| e.event_type == "m.space.parent")
+        .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+        .find_map(|e| e.state_key.clone())
+        .filter(|k| !k.is_empty()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/rooms/permissions/overrides",
+    tag = "rooms",
+    params(OverridesQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ChannelPermissions), super::ErrorResponses)
+)]
+async fn get_overrides(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<OverridesQuery>,
+) -> Result<Json<ChannelPermissions>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let Some(space_id) = parent_space(&matrix, &params.room_id).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?
+    else {
+        // outside any server there are no roles to restrict by
+        return Ok(Json(ChannelPermissions::default()));
+    };
+    let space_state = matrix.get_room_state(space_id).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    Ok(Json(ChannelPermissions::of_child(&space_state, &params.room_id)))
+}
+
+/// make a channel private (or public again). members already inside stay — kick
+/// them to take access away.
+#[utoipa::path(
+    post,
+    path = "/rooms/permissions/overrides",
+    tag = "rooms",
+    request_body = SetOverridesRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn set_overrides(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetOverridesRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let Some(space_id) = parent_space(&matrix, &req.room_id).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?
+    else {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "the channel isn't in a server"));
+    };
+
+    let access = ChannelPermissions { private: req.private, allowed_role_ids: req.allowed_role_ids };
+    let content = serde_json::to_value(&access).unwrap_or_default();
+    matrix
+        .send_state_event(space_id.clone(), channel_access::EVENT_TYPE.to_string(), req.room_id.clone(), content)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    matrix
+        .send_state_event(req.room_id, "m.room.join_rules".to_string(), "".to_string(), access.join_rules(Some(&space_id)))
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/add_child",
+    tag = "rooms",
+    request_body = SpaceChildRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn add_space_child(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SpaceChildRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    match matrix.add_space_child(req.space_id, req.child_room_id, &state.server_name).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(MatrixError::HierarchyCycle) => Err(agora_error(
+            StatusCode::CONFLICT,
+            "AGORA_HIERARCHY_CYCLE",
+            "the space is already inside this room's hierarchy",
+        )),
+        Err(e) => {
+            tracing::error!("failed to add space child: {}", e);
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/rooms/remove_child",
+    tag = "rooms",
+    request_body = SpaceChildRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
 async fn remove_space_child(
     state: State<Arc<AppState>>,
-    Json(req): Json<RemoveChildRequest>,
+    AuthJson(auth, req): AuthJson<SpaceChildRequest>,
 ) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
     match matrix.remove_space_child(req.space_id, req.child_room_id).await {
         Ok(_) => Ok(StatusCode::OK),
@@ -822,13 +2656,100 @@ async fn remove_space_child(
     }
 }
 
+/// `order` for the child at `index` — fixed width, so lexicographic order is list order
+fn child_order(index: usize) -> String {
+    format!("{:06}", index)
+}
+
+#[utoipa::path(
+    post,
+    path = "/rooms/reorder",
+    tag = "rooms",
+    request_body = ReorderRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn reorder_children(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ReorderRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let space_state = matrix.get_room_state(req.space_id.clone()).await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if !hierarchy::is_space(&space_state) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "not a space"));
+    }
+    let current: Vec<String> = hierarchy::child_ids(&space_state);
+    let moved: Vec<&String> = req.child_room_ids.iter().filter(|id| !current.contains(id)).collect();
+    if !moved.is_empty() && req.from_space_id.is_none() {
+        return Err(agora_error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_PARAM",
+            "rooms that aren't children of the space need from_space_id",
+        ));
+    }
+
+    // link moved rooms under the new parent before unlinking them from the old one,
+    // so a failure halfway never leaves a channel in no category at all
+    for room_id in &moved {
+        match matrix.add_space_child(req.space_id.clone(), room_id.to_string(), &state.server_name).await {
+            Ok(()) => {}
+            Err(MatrixError::HierarchyCycle) => {
+                return Err(agora_error(
+                    StatusCode::CONFLICT,
+                    "AGORA_HIERARCHY_CYCLE",
+                    "the space is already inside this room's hierarchy",
+                ))
+            }
+            Err(e) => return Err(matrix_error(&e, StatusCode::BAD_REQUEST)),
+        }
+    }
+
+    for (index, room_id) in req.child_room_ids.iter().enumerate() {
+        let existing = space_state
+            .iter()
+            .find(|e| e.event_type == "m.space.child" && e.state_key.as_deref() == Some(room_id.as_str()))
+            .map(|e| e.content.clone())
+            .filter(|c| c.as_object().is_some_and(|c| !c.is_empty()));
+        let order = child_order(index);
+        if existing.as_ref().and_then(|c| c.get("order")).and_then(|o| o.as_str()) == Some(order.as_str()) {
+            continue;
+        }
+        let mut content = existing.unwrap_or_else(|| serde_json::json!({ "via": [state.server_name] }));
+        content["order"] = serde_json::Value::String(order);
+        matrix
+            .send_state_event(req.space_id.clone(), "m.space.child".to_string(), room_id.clone(), content)
+            .await
+            .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    }
+
+    if let Some(from_space_id) = req.from_space_id.filter(|from| *from != req.space_id) {
+        for room_id in moved {
+            if let Err(e) = matrix.remove_space_child(from_space_id.clone(), room_id.clone()).await {
+                tracing::warn!("moved {} but couldn't unlink it from {}: {}", room_id, from_space_id, e);
+            }
+            // the channel points back at its new parent, as create_room sets it up
+            let parent = serde_json::json!({ "via": [state.server_name], "canonical": true });
+            let _ = matrix
+                .send_state_event(room_id.clone(), "m.space.parent".to_string(), req.space_id.clone(), parent)
+                .await;
+            let _ = matrix
+                .send_state_event(room_id.clone(), "m.space.parent".to_string(), from_space_id.clone(), serde_json::json!({}))
+                .await;
+        }
+    }
+
+    Ok(StatusCode::OK)
+}
+
 // ── raid alert ────────────────────────────────────────────────────────────────
 // a raid message (agora.raid) sent into the server's channel triggers a
 // full-screen alert overlay on every member's client via the sync loop.
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct RaidRequest {
-    pub access_token: String,
     /// the channel room to broadcast the raid into
     pub room_id: String,
     pub raider_id: String,
@@ -837,14 +2758,56 @@ pub struct RaidRequest {
     pub message: Option<String>,
     /// countdown seconds before the raid begins (default 5)
     pub countdown: Option<u32>,
+    /// the same for each retry of this raid, like /rooms/send's
+    pub txn_id: Option<String>,
+}
+
+/// an agora.raid event's fields, as /sync surfaces them
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RaidSignal {
+    pub raider_id: String,
+    pub raider_name: String,
+    #[serde(default)]
+    pub message: String,
+    /// seconds until the raid begins
+    #[serde(default)]
+    pub countdown: u32,
+}
+
+impl RaidSignal {
+    pub fn from_content(content: &serde_json::Value) -> Option<Self> {
+        if content["msgtype"] != "agora.raid" {
+            return None;
+        }
+        serde_json::from_value(content.clone()).ok()
+    }
 }
 
+#[utoipa::path(
+    post,
+    path = "/rooms/raid",
+    tag = "rooms",
+    request_body = RaidRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
 async fn send_raid(
     state: State<Arc<AppState>>,
-    Json(req): Json<RaidRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    AuthJson(auth, req): AuthJson<RaidRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let txn_id = checked_txn_id(req.txn_id.as_deref()).map_err(|e| *e)?;
+    if let Some(txn_id) = txn_id {
+        if idempotency::sent(&state, &auth.access_token, idempotency::Kind::Raid, txn_id).await.is_some() {
+            return Ok(StatusCode::OK);
+        }
+    }
+    // a raid puts an overlay in front of everyone in the channel
+    authz::require_permission_in(&matrix, &req.room_id, Permission::MentionEveryone)
+        .await
+        .map_err(|e| authz_error(&e))?;
 
     let countdown = req.countdown.unwrap_or(5).min(30); // cap at 30 seconds
     let message = req.message.unwrap_or_else(|| "RAID!".to_string());
@@ -858,11 +2821,17 @@ async fn send_raid(
         "countdown": countdown,
     });
 
-    match matrix.send_message_content(req.room_id, content).await {
-        Ok(_) => Ok(StatusCode::OK),
+    let homeserver_txn_id = txn_id.map(|t| idempotency::homeserver_txn_id(idempotency::Kind::Raid, t));
+    match matrix.send_message_content(req.room_id, content, homeserver_txn_id.as_deref()).await {
+        Ok(result) => {
+            if let (Some(txn_id), Some(event_id)) = (txn_id, result["event_id"].as_str()) {
+                idempotency::remember(&state, &auth.access_token, idempotency::Kind::Raid, txn_id, event_id).await;
+            }
+            Ok(StatusCode::OK)
+        }
         Err(e) => {
             tracing::error!("failed to send raid event: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
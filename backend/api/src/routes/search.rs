@@ -0,0 +1,121 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+    Router,
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::search;
+use crate::session::AuthUser;
+use super::{agora_error, friends, matrix_error};
+
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 50;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/search/messages", get(search_messages))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(search_messages))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MessageSearchQuery {
+    /// words, "quoted phrases", `or` and `-excluded` words, as in a web search
+    pub query: String,
+    /// only this room — the caller has to be in it
+    pub room_id: Option<String>,
+    /// only messages from this matrix user id
+    pub sender: Option<String>,
+    /// default 20, max 50
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MessageSearchResponse {
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchResult {
+    pub room_id: String,
+    pub event_id: String,
+    pub sender: String,
+    pub body: String,
+    pub timestamp: i64,
+    /// higher is a better match; only meaningful within one response
+    pub rank: f32,
+}
+
+/// every room the caller is in (or just `room_id`), best match first. only
+/// rooms the search indexer has joined are covered — see crate::search.
+#[utoipa::path(
+    get,
+    path = "/search/messages",
+    tag = "search",
+    params(MessageSearchQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = MessageSearchResponse), super::ErrorResponses)
+)]
+async fn search_messages(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<MessageSearchQuery>,
+) -> Result<Json<MessageSearchResponse>, Response> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+    };
+    if !search::enabled(&state).await {
+        return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+    }
+    let query = params.query.trim();
+    if query.is_empty() {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "query is empty"));
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    let user_id = auth.user_id;
+    let joined = state
+        .joined_rooms
+        .get(&user_id, &matrix)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_GATEWAY))?;
+    let room_ids = match params.room_id {
+        Some(room_id) if !joined.contains(&room_id) => {
+            return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "you aren't in that room"));
+        }
+        Some(room_id) => vec![room_id],
+        None => joined.to_vec(),
+    };
+
+    let blocked = friends::blocked_users(pool, &user_id).await.unwrap_or_default();
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let hits = search::search_messages(pool, query, &room_ids, params.sender.as_deref(), &blocked, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!("message search failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    let results = hits
+        .into_iter()
+        .map(|hit| SearchResult {
+            room_id: hit.room_id,
+            event_id: hit.event_id,
+            sender: hit.sender,
+            body: hit.body,
+            timestamp: hit.ts,
+            rank: hit.rank,
+        })
+        .collect();
+    Ok(Json(MessageSearchResponse { results }))
+}
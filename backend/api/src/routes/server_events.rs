@@ -0,0 +1,435 @@
+// server_events.rs — scheduled community events members can RSVP to
+// an event is an agora.server.event state event on the space, keyed by its id;
+// cancelling one empties it to `{}`. members mark themselves interested in the
+// event's own content, so the type is opened to every member the first time
+// someone allowed to edit power levels creates an event (like stage hands).
+// when an event has a channel, its start is announced there: the start time and
+// the creator's token wait in redis until the announcement worker sends it.
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::authz::{self, Permission};
+use crate::matrix::client::{MatrixClient, MatrixError};
+use crate::matrix::encode_path_segment;
+use crate::matrix::revision;
+use crate::session::{AuthJson, AuthUser};
+use super::servers::is_voice_channel_of;
+use super::{agora_error, authz_error, matrix_error};
+
+pub const SERVER_EVENT_TYPE: &str = "agora.server.event";
+/// msgtype of the message posted in an event's channel when it starts
+pub const EVENT_STARTING_MSGTYPE: &str = "agora.event.starting";
+
+const MAX_EVENT_NAME_LEN: usize = 100;
+const MAX_EVENT_DESCRIPTION_LEN: usize = 1000;
+const MAX_EVENT_LOCATION_LEN: usize = 200;
+// a year ahead is as far as anyone plans a community event
+const MAX_EVENT_LEAD_MS: i64 = 365 * 24 * 60 * 60 * 1000;
+
+// {server_id}|{event_id} → EventAnnouncement, for events with a channel
+const EVENT_ANNOUNCEMENTS_KEY: &str = "server_event_announcements";
+const ANNOUNCE_SWEEP_INTERVAL_SECS: u64 = 30;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/servers/events", get(list_events).delete(cancel_event))
+        .route("/servers/events/create", post(create_event))
+        .route("/servers/events/rsvp", post(rsvp_event))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(list_events, cancel_event, create_event, rsvp_event))]
+pub struct ApiDoc;
+
+/// the content of an agora.server.event
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ServerEvent {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// unix ms
+    pub starts_at: i64,
+    /// unix ms
+    pub ends_at: i64,
+    /// a voice channel in the server, where the start is announced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    /// somewhere outside the server, free text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub created_by: String,
+    /// user ids, in the order they said so
+    #[serde(default)]
+    pub interested: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    pub server_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EventsResponse {
+    /// events that haven't ended, soonest first
+    pub events: Vec<ServerEvent>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateEventRequest {
+    pub server_id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub starts_at: i64,
+    pub ends_at: i64,
+    /// a voice channel, or
+    pub channel_id: Option<String>,
+    /// a place outside the server — not both
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EventActionRequest {
+    pub server_id: String,
+    pub event_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RsvpResponse {
+    pub event_id: String,
+    /// whether the caller is interested now
+    pub interested: bool,
+    pub interested_count: usize,
+}
+
+/// what the announcement worker needs once the event starts
+#[derive(Debug, Serialize, Deserialize)]
+struct EventAnnouncement {
+    server_id: String,
+    event_id: String,
+    channel_id: String,
+    starts_at: i64,
+    /// the creator's, to post with
+    access_token: String,
+}
+
+fn announcement_field(server_id: &str, event_id: &str) -> String {
+    format!("{}|{}", server_id, event_id)
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// trimmed fields, checked against each other and against `now`
+fn check_event(req: &CreateEventRequest, now: i64) -> Result<(), String> {
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_EVENT_NAME_LEN {
+        return Err(format!("event names are 1 to {} characters", MAX_EVENT_NAME_LEN));
+    }
+    if req.description.as_deref().is_some_and(|d| d.chars().count() > MAX_EVENT_DESCRIPTION_LEN) {
+        return Err(format!("descriptions are at most {} characters", MAX_EVENT_DESCRIPTION_LEN));
+    }
+    if req.location.as_deref().is_some_and(|l| l.trim().chars().count() > MAX_EVENT_LOCATION_LEN) {
+        return Err(format!("locations are at most {} characters", MAX_EVENT_LOCATION_LEN));
+    }
+    if req.channel_id.is_some() && req.location.is_some() {
+        return Err("an event is in a channel or at a location, not both".to_string());
+    }
+    if req.starts_at <= now {
+        return Err("events have to start in the future".to_string());
+    }
+    if req.starts_at > now + MAX_EVENT_LEAD_MS {
+        return Err("events can be scheduled up to a year ahead".to_string());
+    }
+    if req.ends_at <= req.starts_at {
+        return Err("events have to end after they start".to_string());
+    }
+    Ok(())
+}
+
+/// the event's current content — None once it's cancelled or if it never was
+async fn read_event(matrix: &MatrixClient, server_id: &str, event_id: &str) -> Result<Option<ServerEvent>, MatrixError> {
+    let (content, _) = revision::read(matrix, server_id, SERVER_EVENT_TYPE, event_id).await?;
+    Ok(serde_json::from_value::<ServerEvent>(content).ok().map(|mut event| {
+        event.id = event_id.to_string();
+        event
+    }))
+}
+
+fn event_not_found() -> Response {
+    agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such event")
+}
+
+/// let every member write agora.server.event, so they can mark themselves
+/// interested. left alone when the caller can't change power levels — the
+/// write that follows then says why
+async fn open_event_writes(matrix: &MatrixClient, server_id: &str) -> Result<(), MatrixError> {
+    let url = format!(
+        "{}/rooms/{}/state/m.room.power_levels/",
+        matrix.client_api_base().await,
+        encode_path_segment(server_id)
+    );
+    let mut power = matrix.get_raw(&url).await?;
+    if power["events"][SERVER_EVENT_TYPE].as_i64() == Some(0) {
+        return Ok(());
+    }
+    power["events"][SERVER_EVENT_TYPE] = serde_json::json!(0);
+    matrix
+        .send_state_event(server_id.to_string(), "m.room.power_levels".to_string(), "".to_string(), power)
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/events",
+    tag = "server_events",
+    params(EventsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = EventsResponse), super::ErrorResponses)
+)]
+async fn list_events(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<EventsQuery>,
+) -> Result<Json<EventsResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let server_state = matrix
+        .get_room_state(params.server_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let now = now_ms();
+    let mut events: Vec<ServerEvent> = server_state
+        .into_iter()
+        .filter(|e| e.event_type == SERVER_EVENT_TYPE)
+        .filter_map(|e| {
+            let mut event: ServerEvent = serde_json::from_value(e.content).ok()?;
+            event.id = e.state_key?;
+            Some(event)
+        })
+        .filter(|event| event.ends_at > now)
+        .collect();
+    events.sort_by(|a, b| a.starts_at.cmp(&b.starts_at).then_with(|| a.id.cmp(&b.id)));
+    Ok(Json(EventsResponse { events }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/events/create",
+    tag = "server_events",
+    request_body = CreateEventRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ServerEvent), super::ErrorResponses)
+)]
+async fn create_event(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<CreateEventRequest>,
+) -> Result<Json<ServerEvent>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    check_event(&req, now_ms()).map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?;
+    let created_by = auth.user_id;
+    let channel_id = req.channel_id.clone().filter(|c| !c.is_empty());
+    if let Some(channel_id) = &channel_id {
+        if !is_voice_channel_of(&matrix, &req.server_id, channel_id).await {
+            return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "not a voice channel in this server"));
+        }
+    }
+
+    if let Err(e) = open_event_writes(&matrix, &req.server_id).await {
+        tracing::debug!("event writes stay closed in {}: {}", req.server_id, e);
+    }
+
+    let event = ServerEvent {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        name: req.name.trim().to_string(),
+        description: req.description.as_deref().map(str::trim).filter(|d| !d.is_empty()).map(str::to_string),
+        starts_at: req.starts_at,
+        ends_at: req.ends_at,
+        channel_id,
+        location: req.location.as_deref().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string),
+        created_by,
+        interested: Vec::new(),
+    };
+    let content = serde_json::to_value(&event).unwrap_or_default();
+    matrix
+        .send_state_event(req.server_id.clone(), SERVER_EVENT_TYPE.to_string(), event.id.clone(), content)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    if let (Some(channel_id), Some(mut redis)) = (&event.channel_id, state.get_redis().await) {
+        let announcement = EventAnnouncement {
+            server_id: req.server_id.clone(),
+            event_id: event.id.clone(),
+            channel_id: channel_id.clone(),
+            starts_at: event.starts_at,
+            access_token: auth.access_token.clone(),
+        };
+        if let Ok(json) = serde_json::to_string(&announcement) {
+            let field = announcement_field(&req.server_id, &event.id);
+            let _: redis::RedisResult<()> = redis.hset(EVENT_ANNOUNCEMENTS_KEY, field, json).await;
+        }
+    }
+    Ok(Json(event))
+}
+
+/// mark the caller interested, or not any more
+#[utoipa::path(
+    post,
+    path = "/servers/events/rsvp",
+    tag = "server_events",
+    request_body = EventActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = RsvpResponse), super::ErrorResponses)
+)]
+async fn rsvp_event(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<EventActionRequest>,
+) -> Result<Json<RsvpResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let user_id = auth.user_id;
+    let event = read_event(&matrix, &req.server_id, &req.event_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if event.is_none_or(|event| event.ends_at <= now_ms()) {
+        return Err(event_not_found());
+    }
+
+    let mut outcome = None;
+    revision::update(&matrix, &req.server_id, SERVER_EVENT_TYPE, &req.event_id, |content| {
+        let mut interested: Vec<String> = serde_json::from_value(content["interested"].clone()).unwrap_or_default();
+        let was = interested.contains(&user_id);
+        if was {
+            interested.retain(|u| *u != user_id);
+        } else {
+            interested.push(user_id.clone());
+        }
+        outcome = Some((!was, interested.len()));
+        content["interested"] = serde_json::json!(interested);
+    })
+    .await
+    .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    let (interested, interested_count) = outcome.unwrap_or_default();
+    Ok(Json(RsvpResponse { event_id: req.event_id, interested, interested_count }))
+}
+
+/// the creator, or anyone with manage_server
+#[utoipa::path(
+    delete,
+    path = "/servers/events",
+    tag = "server_events",
+    request_body = EventActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn cancel_event(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<EventActionRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let user_id = auth.user_id;
+    let Some(event) = read_event(&matrix, &req.server_id, &req.event_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?
+    else {
+        return Err(event_not_found());
+    };
+    if event.created_by != user_id {
+        authz::require_permission(&matrix, &req.server_id, Permission::ManageServer)
+            .await
+            .map_err(|e| authz_error(&e))?;
+    }
+
+    matrix
+        .send_state_event(req.server_id.clone(), SERVER_EVENT_TYPE.to_string(), req.event_id.clone(), serde_json::json!({}))
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if let Some(mut redis) = state.get_redis().await {
+        let field = announcement_field(&req.server_id, &req.event_id);
+        let _: redis::RedisResult<()> = redis.hdel(EVENT_ANNOUNCEMENTS_KEY, field).await;
+    }
+    Ok(StatusCode::OK)
+}
+
+/// background loop that announces events as they start — spawned from main.rs when redis is up
+pub async fn run_announcement_worker(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(ANNOUNCE_SWEEP_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let announced = announce_started_events(&state, now_ms()).await;
+        if announced > 0 {
+            tracing::info!("announced {} starting server events", announced);
+        }
+    }
+}
+
+/// post in the channel of every event that started by `now` (unix ms); returns how many
+pub async fn announce_started_events(state: &AppState, now: i64) -> usize {
+    let Some(mut redis) = state.get_redis().await else {
+        return 0;
+    };
+    let pending: Vec<(String, String)> = redis.hgetall(EVENT_ANNOUNCEMENTS_KEY).await.unwrap_or_default();
+    let mut announced = 0;
+
+    for (field, json) in pending {
+        let Ok(announcement) = serde_json::from_str::<EventAnnouncement>(&json) else {
+            let _: redis::RedisResult<()> = redis.hdel(EVENT_ANNOUNCEMENTS_KEY, &field).await;
+            continue;
+        };
+        if announcement.starts_at > now {
+            continue;
+        }
+        // whoever removes the entry posts, so it goes out once
+        let removed: u32 = redis.hdel(EVENT_ANNOUNCEMENTS_KEY, &field).await.unwrap_or(0);
+        if removed == 0 {
+            continue;
+        }
+
+        let mut matrix = state.matrix();
+        matrix.access_token = Some(announcement.access_token);
+        // cancelled, or moved to another channel since
+        let event = match read_event(&matrix, &announcement.server_id, &announcement.event_id).await {
+            Ok(Some(event)) if event.channel_id.as_deref() == Some(announcement.channel_id.as_str()) => event,
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::warn!("failed to read server event {}: {}", announcement.event_id, e);
+                continue;
+            }
+        };
+        let content = serde_json::json!({
+            "msgtype": EVENT_STARTING_MSGTYPE,
+            "body": format!("{} is starting", event.name),
+            "server_id": announcement.server_id,
+            "server_event_id": event.id,
+            "name": event.name,
+            "starts_at": event.starts_at,
+            "ends_at": event.ends_at,
+        });
+        match matrix.send_message_content(announcement.channel_id, content, None).await {
+            Ok(_) => announced += 1,
+            Err(e) => tracing::warn!("failed to announce server event {}: {}", event.id, e),
+        }
+    }
+    announced
+}
@@ -0,0 +1,469 @@
+// server_templates.rs — building a whole server in one request
+// a template is the channels a new server starts with, top-level or grouped
+// into categories, plus the roles it starts with. POST /servers/create_from_template
+// builds all of it here instead of in the client, so a server is never left
+// half made: when a step fails, everything created so far is left and forgotten
+// again, and the error says what had been made.
+
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{OpenApi, ToSchema};
+use std::collections::HashSet;
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::matrix::client::{HistoryVisibility, MatrixClient, MatrixError};
+use crate::matrix::revision::{self, CasError};
+use crate::service_account;
+use crate::session::AuthJson;
+use super::rooms::{link_to_parent, CHANNEL_TYPES};
+use super::servers::{clean_vanity_slug, Role, RolePermissions, ServerMeta};
+use super::{agora_error, matrix_error, voice};
+
+// categories and channels together
+const MAX_TEMPLATE_ROOMS: usize = 50;
+const MAX_NAME_LEN: usize = 100;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/servers/templates", get(list_templates))
+        .route("/servers/create_from_template", post(create_from_template))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(list_templates, create_from_template))]
+pub struct ApiDoc;
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ServerTemplate {
+    /// set on the built-ins; recorded as the server's `template` in agora.server.meta
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub icon: String,
+    /// channels above every category
+    #[serde(default)]
+    pub channels: Vec<TemplateChannel>,
+    #[serde(default)]
+    pub categories: Vec<TemplateCategory>,
+    /// the server's roles to begin with — a moderator role when left out
+    #[serde(default = "default_roles")]
+    pub roles: Vec<Role>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct TemplateCategory {
+    pub name: String,
+    #[serde(default)]
+    pub channels: Vec<TemplateChannel>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct TemplateChannel {
+    pub name: String,
+    /// one of rooms::CHANNEL_TYPES
+    #[serde(rename = "type", default = "text_type")]
+    pub channel_type: String,
+}
+
+fn text_type() -> String {
+    "text".to_string()
+}
+
+fn default_roles() -> Vec<Role> {
+    vec![Role {
+        id: "moderator".to_string(),
+        name: "moderator".to_string(),
+        color: "#3ba55c".to_string(),
+        hoist: true,
+        mentionable: true,
+        permissions: RolePermissions {
+            kick_members: true,
+            ban_members: true,
+            mention_everyone: true,
+            manage_messages: true,
+            ..RolePermissions::default()
+        },
+        power_level: 50,
+        position: 1,
+        is_default: false,
+    }]
+}
+
+fn channel(name: &str, channel_type: &str) -> TemplateChannel {
+    TemplateChannel { name: name.to_string(), channel_type: channel_type.to_string() }
+}
+
+fn category(name: &str, channels: Vec<TemplateChannel>) -> TemplateCategory {
+    TemplateCategory { name: name.to_string(), channels }
+}
+
+fn builtin(id: &str, label: &str, description: &str, icon: &str) -> ServerTemplate {
+    ServerTemplate {
+        id: Some(id.to_string()),
+        label: label.to_string(),
+        description: description.to_string(),
+        icon: icon.to_string(),
+        channels: Vec::new(),
+        categories: Vec::new(),
+        roles: default_roles(),
+    }
+}
+
+/// the templates the create server wizard offers
+pub fn builtin_templates() -> Vec<ServerTemplate> {
+    vec![
+        ServerTemplate {
+            categories: vec![
+                category("text channels", vec![channel("general", "text"), channel("looking-for-group", "text")]),
+                category("voice channels", vec![channel("game-night", "voice"), channel("chill", "voice")]),
+            ],
+            ..builtin("gaming", "gaming", "a place for your gaming crew", "🎮")
+        },
+        ServerTemplate {
+            channels: vec![channel("general", "text"), channel("vibes", "text"), channel("hang", "voice")],
+            ..builtin("friends", "friends & family", "hang out with the people you care about", "👥")
+        },
+        ServerTemplate {
+            categories: vec![
+                category("info", vec![channel("announcements", "text"), channel("resources", "forum")]),
+                category("study", vec![channel("general-study", "text"), channel("pomodoro", "voice")]),
+            ],
+            ..builtin("study", "study group", "focused space for studying together", "📚")
+        },
+        ServerTemplate {
+            categories: vec![
+                category("info", vec![channel("announcements", "text")]),
+                category(
+                    "members",
+                    vec![channel("general", "text"), channel("events", "forum"), channel("meeting-room", "voice")],
+                ),
+            ],
+            ..builtin("club", "school club", "organize your club activities", "🎓")
+        },
+        ServerTemplate {
+            channels: vec![
+                channel("welcome", "text"),
+                channel("announcements", "text"),
+                channel("events-board", "forum"),
+                channel("town-hall", "voice"),
+            ],
+            ..builtin("community", "local community", "connect with people around you", "🏘️")
+        },
+        ServerTemplate {
+            channels: vec![channel("general", "text")],
+            ..builtin("custom", "custom", "start from scratch", "✨")
+        },
+    ]
+}
+
+impl ServerTemplate {
+    /// why the template can't be built, if it can't
+    fn validate(&self) -> Result<(), String> {
+        let channels = self.channels.iter().chain(self.categories.iter().flat_map(|c| &c.channels));
+        let mut rooms = 0;
+        for ch in channels {
+            rooms += 1;
+            check_name(&ch.name)?;
+            if !CHANNEL_TYPES.contains(&ch.channel_type.as_str()) {
+                return Err(format!("{} isn't a channel type", ch.channel_type));
+            }
+        }
+        for cat in &self.categories {
+            rooms += 1;
+            check_name(&cat.name)?;
+        }
+        if rooms > MAX_TEMPLATE_ROOMS {
+            return Err(format!("a template can have at most {} categories and channels", MAX_TEMPLATE_ROOMS));
+        }
+        let mut role_ids = HashSet::new();
+        if let Some(role) = self.roles.iter().find(|r| r.id.is_empty() || !role_ids.insert(r.id.as_str())) {
+            return Err(format!("role id {:?} is empty or used twice", role.id));
+        }
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    let len = name.trim().chars().count();
+    if len == 0 || len > MAX_NAME_LEN {
+        return Err(format!("names have to be 1-{} characters", MAX_NAME_LEN));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TemplatesResponse {
+    pub templates: Vec<ServerTemplate>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum TemplateChoice {
+    /// a built-in's id, from GET /servers/templates
+    Builtin(String),
+    Inline(ServerTemplate),
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateFromTemplateRequest {
+    /// the server's name
+    pub name: String,
+    pub template: TemplateChoice,
+    /// claim #slug:{server_name} for the server
+    pub vanity_slug: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedChannel {
+    pub room_id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub channel_type: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedCategory {
+    pub room_id: String,
+    pub name: String,
+    pub channels: Vec<CreatedChannel>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedServer {
+    pub server_id: String,
+    pub name: String,
+    pub template: Option<String>,
+    pub vanity_slug: Option<String>,
+    pub roles: Vec<Role>,
+    pub channels: Vec<CreatedChannel>,
+    pub categories: Vec<CreatedCategory>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/templates",
+    tag = "server_templates",
+    responses((status = 200, body = TemplatesResponse))
+)]
+async fn list_templates() -> Json<TemplatesResponse> {
+    Json(TemplatesResponse { templates: builtin_templates() })
+}
+
+/// a room made so far, for cleaning up and for the error
+#[derive(Debug, Serialize)]
+struct MadeRoom {
+    room_id: String,
+    name: String,
+}
+
+/// what stopped the build
+struct Failure {
+    step: &'static str,
+    error: MatrixError,
+}
+
+fn failed(step: &'static str) -> impl FnOnce(MatrixError) -> Failure {
+    move |error| Failure { step, error }
+}
+
+/// build the server the template describes, in order: the space, its vanity
+/// alias, meta and roles, then each category and channel. a failure after the
+/// space exists undoes everything — see `undo`.
+#[utoipa::path(
+    post,
+    path = "/servers/create_from_template",
+    tag = "server_templates",
+    request_body = CreateFromTemplateRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = CreatedServer), super::ErrorResponses)
+)]
+async fn create_from_template(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<CreateFromTemplateRequest>,
+) -> Result<Json<CreatedServer>, Response> {
+    let template = match req.template {
+        TemplateChoice::Builtin(id) => match builtin_templates().into_iter().find(|t| t.id.as_deref() == Some(&id)) {
+            Some(template) => template,
+            None => return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", format!("no template called {}", id))),
+        },
+        TemplateChoice::Inline(template) => template,
+    };
+    let name = req.name.trim().to_string();
+    if let Err(e) = check_name(&name).and_then(|_| template.validate()) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e));
+    }
+    let vanity_slug = match req.vanity_slug.as_deref().map(clean_vanity_slug) {
+        Some(None) => {
+            return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "vanity slugs are 3-32 letters, digits, - or _"));
+        }
+        Some(Some(slug)) => Some(slug),
+        None => None,
+    };
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let server_id = match matrix.create_room(name.clone(), None, true, HistoryVisibility::Shared).await {
+        Ok(response) => response.room_id,
+        Err(e) => {
+            tracing::error!("failed to create server from template: {}", e);
+            return Err(matrix_error(&e, StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let mut made = vec![MadeRoom { room_id: server_id.clone(), name: name.clone() }];
+    let mut alias = None;
+    let built = build(&state, &matrix, &server_id, &template, vanity_slug.as_deref(), &mut made, &mut alias).await;
+    let (channels, categories) = match built {
+        Ok(tree) => tree,
+        Err(failure) => return Err(undo(&matrix, made, alias, failure).await),
+    };
+
+    Ok(Json(CreatedServer {
+        server_id,
+        name,
+        template: template.id,
+        vanity_slug,
+        roles: template.roles,
+        channels,
+        categories,
+    }))
+}
+
+async fn build(
+    state: &AppState,
+    matrix: &MatrixClient,
+    server_id: &str,
+    template: &ServerTemplate,
+    vanity_slug: Option<&str>,
+    made: &mut Vec<MadeRoom>,
+    alias: &mut Option<String>,
+) -> Result<(Vec<CreatedChannel>, Vec<CreatedCategory>), Failure> {
+    if let Some(slug) = vanity_slug {
+        let room_alias = format!("#{}:{}", slug, state.server_name);
+        matrix.create_room_alias(room_alias.clone(), server_id.to_string()).await.map_err(failed("vanity_slug"))?;
+        *alias = Some(room_alias);
+    }
+
+    let meta = ServerMeta {
+        name: Some(made[0].name.clone()),
+        vanity_slug: vanity_slug.map(String::from),
+        template: template.id.clone(),
+        ..ServerMeta::default()
+    };
+    let content = serde_json::to_value(&meta).unwrap_or_default();
+    matrix
+        .send_state_event(server_id.to_string(), "agora.server.meta".to_string(), "".to_string(), content)
+        .await
+        .map_err(failed("meta"))?;
+
+    if !template.roles.is_empty() {
+        // a brand new space has no roles yet, so this is always revision 0
+        let content = serde_json::json!({ "roles": template.roles });
+        revision::write(matrix, server_id, "agora.roles", "", Some(0), false, content)
+            .await
+            .map_err(|e| match e {
+                CasError::Matrix(error) => Failure { step: "roles", error },
+                CasError::Conflict { .. } => {
+                    Failure { step: "roles", error: MatrixError::ApiError("roles were already set".to_string()) }
+                }
+            })?;
+    }
+
+    let mut channels = Vec::new();
+    for ch in &template.channels {
+        channels.push(create_channel(state, matrix, server_id, ch, made).await?);
+    }
+    let mut categories = Vec::new();
+    for cat in &template.categories {
+        let room_id = matrix
+            .create_category(cat.name.trim().to_string(), server_id.to_string(), &state.server_name)
+            .await
+            .map_err(failed("category"))?
+            .room_id;
+        made.push(MadeRoom { room_id: room_id.clone(), name: cat.name.trim().to_string() });
+        let mut created = CreatedCategory { room_id: room_id.clone(), name: cat.name.trim().to_string(), channels: Vec::new() };
+        for ch in &cat.channels {
+            created.channels.push(create_channel(state, matrix, &room_id, ch, made).await?);
+        }
+        categories.push(created);
+    }
+    Ok((channels, categories))
+}
+
+/// a channel under `parent_id`, with its type set and linked both ways
+async fn create_channel(
+    state: &AppState,
+    matrix: &MatrixClient,
+    parent_id: &str,
+    ch: &TemplateChannel,
+    made: &mut Vec<MadeRoom>,
+) -> Result<CreatedChannel, Failure> {
+    let name = ch.name.trim().to_string();
+    let room_id = matrix.create_room(name.clone(), None, false, HistoryVisibility::Shared).await.map_err(failed("channel"))?.room_id;
+    made.push(MadeRoom { room_id: room_id.clone(), name: name.clone() });
+
+    let content = serde_json::json!({ "type": ch.channel_type });
+    matrix
+        .send_state_event(room_id.clone(), "agora.room.type".to_string(), "".to_string(), content)
+        .await
+        .map_err(failed("channel"))?;
+    link_to_parent(matrix, &room_id, parent_id, &state.server_name).await.map_err(failed("channel"))?;
+    service_account::bring_in(state, matrix, &room_id).await;
+    if ch.channel_type == "stage" {
+        voice::open_stage_requests(matrix, &room_id).await.map_err(failed("channel"))?;
+    }
+    Ok(CreatedChannel { room_id, name, channel_type: ch.channel_type.clone() })
+}
+
+/// leave and forget every room made so far, newest first, and give the alias
+/// back. the response names what had been made, whether it's all gone, and
+/// the step that failed.
+async fn undo(matrix: &MatrixClient, made: Vec<MadeRoom>, alias: Option<String>, failure: Failure) -> Response {
+    tracing::warn!("building a server from a template failed at {}: {}", failure.step, failure.error);
+
+    let mut cleaned_up = true;
+    if let Some(alias) = alias {
+        if let Err(e) = matrix.delete_room_alias(alias.clone()).await {
+            tracing::warn!("couldn't remove {} while cleaning up: {}", alias, e);
+            cleaned_up = false;
+        }
+    }
+    for room in made.iter().rev() {
+        let left = matrix.leave_room(room.room_id.clone()).await;
+        let forgotten = match left {
+            Ok(()) => matrix.forget_room(room.room_id.clone()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = forgotten {
+            tracing::warn!("couldn't leave {} while cleaning up: {}", room.room_id, e);
+            cleaned_up = false;
+        }
+    }
+
+    // the homeserver's own status where it means something (a taken alias, a rate limit)
+    let status = match &failure.error {
+        MatrixError::MatrixApiError { status, .. } => {
+            StatusCode::from_u16(*status).ok().filter(|s| s.is_client_error()).unwrap_or(StatusCode::BAD_GATEWAY)
+        }
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    let body = serde_json::json!({
+        "errcode": "AGORA_TEMPLATE_FAILED",
+        "error": failure.error.to_string(),
+        "step": failure.step,
+        "created": made,
+        "cleaned_up": cleaned_up,
+    });
+    (status, Json(body)).into_response()
+}
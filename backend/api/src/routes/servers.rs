@@ -1,55 +1,108 @@
 // servers.rs — server-level management endpoints
-// covers: metadata, vanity aliases, roles, member management, forum threads
+// covers: metadata, settings, vanity aliases, roles, member management, moderation, forum threads,
+// deletion, public previews
 // all server state is stored as Matrix state events on the server (space) room
 
 use axum::{
     extract::{Json, Query, State},
     http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
+use futures_util::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use crate::app_state::AppState;
-use crate::matrix::client::MatrixClient;
+use crate::matrix::authz::{self, Permission};
+use crate::matrix::client::{Direction, HistoryVisibility, MatrixClient, MatrixError, RoomStateEvent};
+use crate::matrix::hierarchy;
+use crate::matrix::encode_path_segment;
+use crate::matrix::message_policy::{ServerSettings, TIMEOUT_EVENT_TYPE};
+use crate::matrix::revision::{self, CasError};
+use crate::pagination::{paginate_sorted, PageParams};
+use crate::room_summaries;
+use crate::service_account;
+use crate::session::{AuthJson, AuthUser};
+use super::presence_ws::presence_snapshot;
+use super::rooms::link_to_parent;
+use super::voice::{self, Vibe};
+use super::{agora_error, authz_error, matrix_error};
+use redis::AsyncCommands;
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         // server metadata
         .route("/servers/meta", get(get_server_meta).post(set_server_meta))
+        .route("/servers/welcome", get(get_welcome).post(set_welcome))
+        .route("/servers/settings", get(get_server_settings).post(set_server_settings))
+        .route("/servers/vibes", get(get_vibes).post(set_vibes))
         // roles
-        .route("/servers/roles", get(get_roles).post(set_roles))
+        .route("/servers/roles", get(get_roles).post(set_roles).delete(delete_role))
+        .route("/servers/roles/reorder", post(reorder_roles))
+        .route("/servers/members", get(list_members))
         .route("/servers/members/roles", get(get_member_roles).post(set_member_roles))
+        .route("/servers/members/joined", post(member_joined))
+        // moderation
+        .route("/servers/members/kick", post(kick_member))
+        .route("/servers/members/ban", post(ban_member))
+        .route("/servers/members/unban", post(unban_member))
+        .route("/servers/bans", get(list_bans))
+        .route("/servers/members/timeout", post(timeout_member).delete(remove_timeout))
         // forum threads
         .route("/servers/forum/threads", get(list_threads))
         .route("/servers/forum/thread", post(create_thread))
+        .route("/servers/forum/thread/update", post(update_thread))
+        .route("/servers/forum/thread/tags", post(set_thread_tags))
+        .route("/servers/forum/tags", get(get_forum_tags).post(set_forum_tags))
+        .route("/servers/delete", post(delete_server))
         // invite / vanity
         .route("/servers/invite", get(get_invite_info))
+        .route("/servers/preview", get(preview_server))
 }
 
+#[derive(OpenApi)]
+#[openapi(paths(
+    get_server_meta, set_server_meta, get_welcome, set_welcome, get_server_settings,
+    set_server_settings, get_vibes, set_vibes, get_roles, set_roles, delete_role,
+    reorder_roles, list_members, get_member_roles, set_member_roles, member_joined,
+    kick_member, ban_member, unban_member, list_bans, timeout_member, remove_timeout,
+    list_threads, create_thread, update_thread, set_thread_tags, get_forum_tags,
+    set_forum_tags, delete_server, get_invite_info, preview_server,
+), components(schemas(ArchivedFilter, ThreadSort)))]
+pub struct ApiDoc;
+
 // ── server metadata ───────────────────────────────────────────────────────────
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct ServerMetaQuery {
-    pub access_token: String,
     pub server_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
 pub struct ServerMeta {
     pub name: Option<String>,
     pub description: Option<String>,
     pub icon_url: Option<String>,
     pub banner_url: Option<String>,
-    /// the vanity slug used as the room alias: #slug:localhost
+    /// the vanity slug used as the room alias: #slug:{server_name}
     pub vanity_slug: Option<String>,
     /// template id used to initially populate the server
     pub template: Option<String>,
+    /// voice channel idle members are moved to — None when the server has none
+    pub afk_channel_id: Option<String>,
+    /// how long someone can sit in voice without an unmuted mic before being moved
+    pub afk_timeout_secs: Option<u64>,
+    /// role every member gets when they join — see assign_default_role
+    #[serde(default)]
+    pub default_role_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SetServerMetaRequest {
-    pub access_token: String,
     pub server_id: String,
     pub description: Option<String>,
     pub icon_url: Option<String>,
@@ -57,56 +110,73 @@ pub struct SetServerMetaRequest {
     /// setting a new vanity slug creates a new room alias and updates agora.server.meta
     pub vanity_slug: Option<String>,
     pub name: Option<String>,
+    /// a voice channel in this server; "" turns the afk channel off
+    pub afk_channel_id: Option<String>,
+    pub afk_timeout_secs: Option<u64>,
+    /// "" stops handing a role out
+    pub default_role_id: Option<String>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/servers/meta",
+    tag = "servers",
+    params(ServerMetaQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ServerMeta), super::StatusErrors)
+)]
 async fn get_server_meta(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<ServerMetaQuery>,
 ) -> Result<Json<ServerMeta>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    Ok(Json(read_meta(&matrix, &params.server_id).await))
+}
 
-    // read agora.server.meta state event
+/// the server's agora.server.meta — empty when it has none or it can't be read
+pub async fn read_meta(matrix: &MatrixClient, server_id: &str) -> ServerMeta {
     let url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.server.meta/",
-        state.homeserver_url,
-        url_encode(&params.server_id)
+        "{}/rooms/{}/state/agora.server.meta/",
+        matrix.client_api_base().await,
+        encode_path_segment(server_id)
     );
     match matrix.get_raw(&url).await {
-        Ok(body) => {
-            let meta: ServerMeta = serde_json::from_value(body).unwrap_or(ServerMeta {
-                name: None, description: None, icon_url: None, banner_url: None,
-                vanity_slug: None, template: None,
-            });
-            Ok(Json(meta))
-        }
-        Err(_) => Ok(Json(ServerMeta {
-            name: None, description: None, icon_url: None, banner_url: None,
-            vanity_slug: None, template: None,
-        }))
+        Ok(body) => serde_json::from_value(body).unwrap_or_default(),
+        Err(_) => ServerMeta::default(),
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/servers/meta",
+    tag = "servers",
+    request_body = SetServerMetaRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
 async fn set_server_meta(
     state: State<Arc<AppState>>,
-    Json(req): Json<SetServerMetaRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    AuthJson(auth, req): AuthJson<SetServerMetaRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::ManageServer)
+        .await
+        .map_err(|e| authz_error(&e))?;
 
     // read current meta first so we only overwrite provided fields
     let url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.server.meta/",
-        state.homeserver_url,
-        url_encode(&req.server_id)
+        "{}/rooms/{}/state/agora.server.meta/",
+        matrix.client_api_base().await,
+        encode_path_segment(&req.server_id)
     );
     let mut current: ServerMeta = matrix.get_raw(&url).await
         .ok()
         .and_then(|v| serde_json::from_value(v).ok())
-        .unwrap_or(ServerMeta {
-            name: None, description: None, icon_url: None, banner_url: None,
-            vanity_slug: None, template: None,
-        });
+        .unwrap_or_default();
 
     if let Some(d) = req.description { current.description = Some(d); }
     if let Some(i) = req.icon_url    { current.icon_url = Some(i); }
@@ -121,26 +191,174 @@ async fn set_server_meta(
     }
 
     if let Some(slug) = req.vanity_slug {
-        // validate slug: alphanumeric and hyphens only, 3-32 chars
-        let clean: String = slug.chars()
-            .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
-            .collect::<String>()
-            .to_lowercase();
-        if clean.len() < 3 || clean.len() > 32 {
-            return Err(StatusCode::BAD_REQUEST);
-        }
+        let Some(clean) = clean_vanity_slug(&slug) else {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        };
         // create the new alias (will fail silently if already taken by someone else)
         let _ = matrix.create_room_alias(
-            format!("#{clean}:localhost"), req.server_id.clone()
+            format!("#{}:{}", clean, state.server_name), req.server_id.clone()
         ).await;
         current.vanity_slug = Some(clean);
     }
 
+    let afk_changed = req.afk_channel_id.is_some() || req.afk_timeout_secs.is_some();
+    if let Some(channel_id) = req.afk_channel_id {
+        if channel_id.is_empty() {
+            current.afk_channel_id = None;
+        } else if is_voice_channel_of(&matrix, &req.server_id, &channel_id).await {
+            current.afk_channel_id = Some(channel_id);
+        } else {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+    }
+    if let Some(timeout) = req.afk_timeout_secs {
+        if !voice::AFK_TIMEOUT_RANGE.contains(&timeout) {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+        current.afk_timeout_secs = Some(timeout);
+    }
+
+    // handing a role to everyone who joins is handing it out, so it takes the
+    // same standing as assigning it to one member
+    let default_role_changed = req.default_role_id.is_some();
+    if let Some(role_id) = req.default_role_id.filter(|id| !id.is_empty()) {
+        access.require(Permission::ManageRoles).map_err(|e| authz_error(&e))?;
+        let (content, _) = revision::read(&matrix, &req.server_id, "agora.roles", "")
+            .await
+            .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+        let Some(role) = roles_from_content(&content).into_iter().find(|r| r.id == role_id) else {
+            return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "no such role"));
+        };
+        access.require_outranks(&role.id, role.position).map_err(|e| authz_error(&e))?;
+        current.default_role_id = Some(role_id);
+    } else if default_role_changed {
+        current.default_role_id = None;
+    }
+
     let content = serde_json::to_value(&current).unwrap_or_default();
-    match matrix.send_state_event(req.server_id, "agora.server.meta".to_string(), "".to_string(), content).await {
-        Ok(_) => Ok(StatusCode::OK),
+    match matrix.send_state_event(req.server_id.clone(), "agora.server.meta".to_string(), "".to_string(), content).await {
+        Ok(_) => {
+            if default_role_changed {
+                let token = current.default_role_id.as_ref().map(|_| auth.access_token.as_str());
+                register_default_role(&state, &req.server_id, token).await;
+            }
+            if afk_changed {
+                let config = current.afk_channel_id.map(|afk_channel_id| voice::AfkConfig {
+                    afk_channel_id,
+                    afk_timeout_secs: current.afk_timeout_secs.unwrap_or(voice::DEFAULT_AFK_TIMEOUT_SECS),
+                    access_token: auth.access_token,
+                });
+                voice::register_afk(&state, &req.server_id, config.as_ref()).await;
+            }
+            Ok(StatusCode::OK)
+        }
         Err(e) => {
             tracing::error!("failed to set server meta: {}", e);
+            Err(StatusCode::BAD_REQUEST.into_response())
+        }
+    }
+}
+
+/// a vanity slug as it goes into the alias: alphanumerics, hyphens and
+/// underscores, lowercased, 3-32 chars. None when too little is left.
+pub fn clean_vanity_slug(slug: &str) -> Option<String> {
+    let clean: String = slug.chars()
+        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+        .collect::<String>()
+        .to_lowercase();
+    (3..=32).contains(&clean.len()).then_some(clean)
+}
+
+/// true when `channel_id` is a voice channel somewhere under the server
+pub async fn is_voice_channel_of(matrix: &MatrixClient, server_id: &str, channel_id: &str) -> bool {
+    hierarchy::walk_space(matrix, server_id, hierarchy::max_depth())
+        .await
+        .iter()
+        .any(|node| {
+            node.room_id == channel_id
+                && node.state.iter().any(|e| e.event_type == "agora.room.type" && e.content["type"] == "voice")
+        })
+}
+
+// ── server settings ───────────────────────────────────────────────────────────
+// behaviour knobs enforced by the api (agora.server.settings on the server room).
+// the message windows are applied by the edit / redact handlers.
+
+// a year — longer windows are as good as unlimited
+const MAX_WINDOW_MINUTES: u64 = 365 * 24 * 60;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetServerSettingsRequest {
+    pub server_id: String,
+    pub edit_window_minutes: Option<u64>,
+    pub delete_window_minutes: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/settings",
+    tag = "servers",
+    params(ServerMetaQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ServerSettings), super::StatusErrors)
+)]
+async fn get_server_settings(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ServerMetaQuery>,
+) -> Result<Json<ServerSettings>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let url = format!(
+        "{}/rooms/{}/state/agora.server.settings/",
+        matrix.client_api_base().await,
+        encode_path_segment(&params.server_id)
+    );
+    // no settings event yet — everything unlimited
+    let settings = matrix.get_raw(&url).await.ok()
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default();
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/settings",
+    tag = "servers",
+    request_body = SetServerSettingsRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ServerSettings), super::StatusErrors)
+)]
+async fn set_server_settings(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetServerSettingsRequest>,
+) -> Result<Json<ServerSettings>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let too_long = |w: Option<u64>| w.is_some_and(|m| m > MAX_WINDOW_MINUTES);
+    if too_long(req.edit_window_minutes) || too_long(req.delete_window_minutes) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // only overwrite the fields that were provided
+    let url = format!(
+        "{}/rooms/{}/state/agora.server.settings/",
+        matrix.client_api_base().await,
+        encode_path_segment(&req.server_id)
+    );
+    let mut settings: ServerSettings = matrix.get_raw(&url).await.ok()
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default();
+    if let Some(m) = req.edit_window_minutes { settings.edit_window_minutes = m; }
+    if let Some(m) = req.delete_window_minutes { settings.delete_window_minutes = m; }
+
+    let content = serde_json::to_value(&settings).unwrap_or_default();
+    match matrix.send_state_event(req.server_id, "agora.server.settings".to_string(), "".to_string(), content).await {
+        Ok(_) => Ok(Json(settings)),
+        Err(e) => {
+            tracing::error!("failed to set server settings: {}", e);
             Err(StatusCode::BAD_REQUEST)
         }
     }
@@ -150,8 +368,10 @@ async fn set_server_meta(
 // roles are stored as a single agora.roles state event (list of role objects).
 // member role assignments are stored as agora.member.roles state events (one per user).
 // permissions are a flat flags object — which actions are allowed for the role.
+// roles are ranked by position (highest first); below administrator, members can
+// only manage roles under their own highest one — see matrix::authz.
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct RolePermissions {
     pub send_messages: bool,
     pub manage_channels: bool,
@@ -160,6 +380,9 @@ pub struct RolePermissions {
     pub ban_members: bool,
     pub mention_everyone: bool,
     pub manage_server: bool,
+    /// edit / delete windows don't apply, can delete anyone's messages
+    #[serde(default)]
+    pub manage_messages: bool,
     pub administrator: bool, // overrides all others
 }
 
@@ -173,12 +396,13 @@ impl Default for RolePermissions {
             ban_members: false,
             mention_everyone: false,
             manage_server: false,
+            manage_messages: false,
             administrator: false,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Role {
     pub id: String,         // uuid4 or short string
     pub name: String,
@@ -188,52 +412,150 @@ pub struct Role {
     pub permissions: RolePermissions,
     /// power level this role maps to in Matrix (for enforcement)
     pub power_level: i64,
+    /// rank in the hierarchy — higher outranks lower, and hoisted roles list in this order
+    #[serde(default)]
+    pub position: i64,
+    /// whether joiners get this role (default_role_id in agora.server.meta).
+    /// only ever set on the way out — the meta is what counts.
+    #[serde(default, skip_deserializing)]
+    pub is_default: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RolesResponse {
     pub roles: Vec<Role>,
+    /// echo this back in SetRolesRequest — bumped on every save
+    pub revision: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct RolesQuery {
-    pub access_token: String,
     pub server_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SetRolesRequest {
-    pub access_token: String,
     pub server_id: String,
     pub roles: Vec<Role>,
+    /// the revision the edit is based on (from GET /servers/roles)
+    pub revision: Option<u64>,
+    /// skip the revision check — server admins (power level 100) only
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReorderRolesRequest {
+    pub server_id: String,
+    /// every role id, highest first
+    pub role_ids: Vec<String>,
+    /// the revision the order is based on (from GET /servers/roles)
+    pub revision: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteRoleRequest {
+    pub server_id: String,
+    pub role_id: String,
 }
 
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteRoleResponse {
+    /// the roles revision after the delete
+    pub revision: u64,
+    /// members the role was taken from
+    pub members_updated: usize,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/roles",
+    tag = "servers",
+    params(RolesQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = RolesResponse), super::StatusErrors)
+)]
 async fn get_roles(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<RolesQuery>,
 ) -> Result<Json<RolesResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let (content, revision) = revision::read(&matrix, &params.server_id, "agora.roles", "")
+        .await
+        .unwrap_or((serde_json::Value::Null, 0));
+    let mut roles = roles_from_content(&content);
+    mark_default(&matrix, &params.server_id, &mut roles).await;
+    Ok(Json(RolesResponse { roles, revision }))
+}
 
-    let url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.roles/",
-        state.homeserver_url, url_encode(&params.server_id)
-    );
-    let roles = match matrix.get_raw(&url).await {
-        Ok(body) => body["roles"].as_array()
-            .and_then(|arr| serde_json::from_value::<Vec<Role>>(serde_json::Value::Array(arr.clone())).ok())
-            .unwrap_or_default(),
-        Err(_) => vec![],
-    };
-    Ok(Json(RolesResponse { roles }))
+/// flag the server's default role, if it has one
+async fn mark_default(matrix: &MatrixClient, server_id: &str, roles: &mut [Role]) {
+    let default_role_id = read_meta(matrix, server_id).await.default_role_id;
+    for role in roles {
+        role.is_default = default_role_id.as_deref() == Some(role.id.as_str());
+    }
+}
+
+/// the stored roles, highest position first
+fn roles_from_content(content: &serde_json::Value) -> Vec<Role> {
+    let mut roles = content["roles"].as_array()
+        .and_then(|arr| serde_json::from_value::<Vec<Role>>(serde_json::Value::Array(arr.clone())).ok())
+        .unwrap_or_default();
+    roles.sort_by_key(|r| std::cmp::Reverse(r.position));
+    roles
 }
 
+/// every role added, edited or removed between `before` and `after` has to
+/// be below the caller, where it was and where it ends up
+fn check_role_edits(access: &authz::ServerAccess, before: &[Role], after: &[Role]) -> Result<(), authz::AuthzError> {
+    for role in after {
+        let old = before.iter().find(|r| r.id == role.id);
+        let unchanged = old.is_some_and(|old| serde_json::to_value(old).ok() == serde_json::to_value(role).ok());
+        if unchanged {
+            continue;
+        }
+        access.require_outranks(&role.id, role.position)?;
+        if let Some(old) = old {
+            access.require_outranks(&old.id, old.position)?;
+        }
+    }
+    for role in before.iter().filter(|old| !after.iter().any(|r| r.id == old.id)) {
+        access.require_outranks(&role.id, role.position)?;
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/roles",
+    tag = "servers",
+    request_body = SetRolesRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = RolesResponse), super::ErrorResponses)
+)]
 async fn set_roles(
     state: State<Arc<AppState>>,
-    Json(req): Json<SetRolesRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    AuthJson(auth, req): AuthJson<SetRolesRequest>,
+) -> Result<Json<RolesResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::ManageRoles)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    if req.force && access.level < 100 {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+    if !access.is_admin() {
+        let (current, _) = revision::read(&matrix, &req.server_id, "agora.roles", "")
+            .await
+            .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+        check_role_edits(&access, &roles_from_content(&current), &req.roles).map_err(|e| authz_error(&e))?;
+    }
 
     // also sync power levels for each role so Matrix enforcement works
     // fetch current power levels first
@@ -253,50 +575,579 @@ async fn set_roles(
         let _ = matrix.send_state_event(req.server_id.clone(), "m.room.power_levels".to_string(), "".to_string(), content).await;
     }
 
-    let content = serde_json::json!({ "roles": req.roles });
-    match matrix.send_state_event(req.server_id, "agora.roles".to_string(), "".to_string(), content).await {
-        Ok(_) => Ok(StatusCode::OK),
-        Err(e) => {
+    let mut roles = req.roles;
+    let content = serde_json::json!({ "roles": roles });
+    match revision::write(&matrix, &req.server_id, "agora.roles", "", req.revision, req.force, content).await {
+        Ok(revision) => {
+            roles.sort_by_key(|r| std::cmp::Reverse(r.position));
+            mark_default(&matrix, &req.server_id, &mut roles).await;
+            Ok(Json(RolesResponse { roles, revision }))
+        }
+        Err(CasError::Conflict { revision, current }) => Err(revision_conflict(
+            revision,
+            serde_json::json!({ "roles": roles_from_content(&current) }),
+        )),
+        Err(CasError::Matrix(e)) => {
             tracing::error!("failed to set roles: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
+        }
+    }
+}
+
+/// rank the server's roles: `role_ids` lists all of them, highest first, and
+/// positions are renumbered from it. roles at or above the caller's own have
+/// to stay where they are.
+#[utoipa::path(
+    post,
+    path = "/servers/roles/reorder",
+    tag = "servers",
+    request_body = ReorderRolesRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = RolesResponse), super::ErrorResponses)
+)]
+async fn reorder_roles(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ReorderRolesRequest>,
+) -> Result<Json<RolesResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::ManageRoles)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let (content, _) = revision::read(&matrix, &req.server_id, "agora.roles", "")
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let before = roles_from_content(&content);
+
+    let mut ids = req.role_ids.clone();
+    ids.sort();
+    ids.dedup();
+    let mut known: Vec<&String> = before.iter().map(|r| &r.id).collect();
+    known.sort();
+    if ids.len() != req.role_ids.len() || ids.iter().ne(known) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "role_ids must list every role once"));
+    }
+
+    let top = req.role_ids.len() as i64 - 1;
+    let after: Vec<Role> = req.role_ids.iter().enumerate()
+        .filter_map(|(i, id)| {
+            let mut role = before.iter().find(|r| &r.id == id)?.clone();
+            role.position = top - i as i64;
+            Some(role)
+        })
+        .collect();
+    if !access.is_admin() {
+        check_role_edits(&access, &before, &after).map_err(|e| authz_error(&e))?;
+    }
+
+    let mut after = after;
+    let content = serde_json::json!({ "roles": after });
+    match revision::write(&matrix, &req.server_id, "agora.roles", "", req.revision, false, content).await {
+        Ok(revision) => {
+            mark_default(&matrix, &req.server_id, &mut after).await;
+            Ok(Json(RolesResponse { roles: after, revision }))
+        }
+        Err(CasError::Conflict { revision, current }) => Err(revision_conflict(
+            revision,
+            serde_json::json!({ "roles": roles_from_content(&current) }),
+        )),
+        Err(CasError::Matrix(e)) => Err(matrix_error(&e, StatusCode::BAD_REQUEST)),
+    }
+}
+
+/// remove a role from the server and from every member holding it. each of
+/// them gets the power level of the roles they have left.
+#[utoipa::path(
+    delete,
+    path = "/servers/roles",
+    tag = "servers",
+    request_body = DeleteRoleRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = DeleteRoleResponse), super::ErrorResponses)
+)]
+async fn delete_role(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<DeleteRoleRequest>,
+) -> Result<Json<DeleteRoleResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::ManageRoles)
+        .await
+        .map_err(|e| authz_error(&e))?;
+
+    let (content, _) = revision::read(&matrix, &req.server_id, "agora.roles", "")
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let Some(role) = roles_from_content(&content).into_iter().find(|r| r.id == req.role_id) else {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such role"));
+    };
+    access.require_outranks(&role.id, role.position).map_err(|e| authz_error(&e))?;
+
+    let mut remaining = Vec::new();
+    let revision = revision::update(&matrix, &req.server_id, "agora.roles", "", |content| {
+        let mut roles = roles_from_content(content);
+        roles.retain(|r| r.id != req.role_id);
+        content["roles"] = serde_json::to_value(&roles).unwrap_or_default();
+        remaining = roles;
+    })
+    .await
+    .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    // every assignment is a state event on the server room, keyed by user id
+    let server_state = matrix.get_room_state(req.server_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let mut powers = Vec::new();
+    for event in server_state.iter().filter(|e| e.event_type == "agora.member.roles") {
+        let Some(user_id) = event.state_key.clone() else { continue };
+        let mut role_ids: Vec<String> = serde_json::from_value(event.content["role_ids"].clone()).unwrap_or_default();
+        if !role_ids.contains(&req.role_id) {
+            continue;
+        }
+        role_ids.retain(|id| *id != req.role_id);
+        let content = serde_json::json!({ "role_ids": role_ids });
+        match matrix.send_state_event(req.server_id.clone(), "agora.member.roles".to_string(), user_id.clone(), content).await {
+            Ok(_) => powers.push((user_id, role_power(&remaining, &role_ids))),
+            Err(e) => tracing::warn!("failed to take role {} from {}: {}", req.role_id, user_id, e),
+        }
+    }
+    set_member_powers(&matrix, &req.server_id, &powers).await;
+
+    // joiners stop getting a role that's gone
+    let meta = server_state.iter().find(|e| e.event_type == "agora.server.meta" && e.state_key.as_deref() == Some(""));
+    if let Some(mut meta) = meta.and_then(|e| serde_json::from_value::<ServerMeta>(e.content.clone()).ok()) {
+        if meta.default_role_id.as_deref() == Some(req.role_id.as_str()) {
+            meta.default_role_id = None;
+            let content = serde_json::to_value(&meta).unwrap_or_default();
+            if let Err(e) = matrix.send_state_event(req.server_id.clone(), "agora.server.meta".to_string(), "".to_string(), content).await {
+                tracing::warn!("failed to clear the default role of {}: {}", req.server_id, e);
+            }
+            register_default_role(&state, &req.server_id, None).await;
+        }
+    }
+
+    Ok(Json(DeleteRoleResponse { revision, members_updated: powers.len() }))
+}
+
+// ── welcome screen ────────────────────────────────────────────────────────────
+// shown to new members: a short blurb plus a few highlighted channels.
+// stored as a revisioned agora.server.welcome state event on the server room.
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct WelcomeScreen {
+    pub description: Option<String>,
+    #[serde(default)]
+    pub welcome_channels: Vec<WelcomeChannel>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct WelcomeChannel {
+    pub room_id: String,
+    pub description: String,
+    pub emoji: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WelcomeResponse {
+    pub welcome: WelcomeScreen,
+    pub revision: u64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetWelcomeRequest {
+    pub server_id: String,
+    pub welcome: WelcomeScreen,
+    /// the revision the edit is based on (from GET /servers/welcome)
+    pub revision: Option<u64>,
+    /// skip the revision check — server admins (power level 100) only
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/welcome",
+    tag = "servers",
+    params(ServerMetaQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = WelcomeResponse), super::StatusErrors)
+)]
+async fn get_welcome(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ServerMetaQuery>,
+) -> Result<Json<WelcomeResponse>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let (content, revision) = revision::read(&matrix, &params.server_id, "agora.server.welcome", "")
+        .await
+        .unwrap_or((serde_json::Value::Null, 0));
+    let welcome = serde_json::from_value(content).unwrap_or_default();
+    Ok(Json(WelcomeResponse { welcome, revision }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/welcome",
+    tag = "servers",
+    request_body = SetWelcomeRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = WelcomeResponse), super::ErrorResponses)
+)]
+async fn set_welcome(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetWelcomeRequest>,
+) -> Result<Json<WelcomeResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    if req.force && !is_server_admin(&matrix, &req.server_id).await {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let content = serde_json::to_value(&req.welcome).unwrap_or_default();
+    match revision::write(&matrix, &req.server_id, "agora.server.welcome", "", req.revision, req.force, content).await {
+        Ok(revision) => Ok(Json(WelcomeResponse { welcome: req.welcome, revision })),
+        Err(CasError::Conflict { revision, current }) => {
+            let current: WelcomeScreen = serde_json::from_value(current).unwrap_or_default();
+            Err(revision_conflict(revision, serde_json::json!({ "welcome": current })))
+        }
+        Err(CasError::Matrix(e)) => {
+            tracing::error!("failed to set welcome screen: {}", e);
+            Err(StatusCode::BAD_REQUEST.into_response())
+        }
+    }
+}
+
+// ── vibes ─────────────────────────────────────────────────────────────────────
+// a server's own ambiences for its voice channels, on top of the built-ins.
+// stored as a revisioned agora.vibes event on the server room; moderators
+// manage the list.
+
+/// server power needed to edit the server's vibes
+const VIBE_MANAGER_POWER: i64 = 50;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct VibesResponse {
+    /// available everywhere, not editable
+    pub builtin: Vec<Vibe>,
+    /// this server's own
+    pub vibes: Vec<Vibe>,
+    pub revision: u64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetVibesRequest {
+    pub server_id: String,
+    /// the complete custom list — replaces what's there
+    pub vibes: Vec<Vibe>,
+    /// the revision the edit is based on (from GET /servers/vibes)
+    pub revision: Option<u64>,
+    /// skip the revision check — server admins (power level 100) only
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/vibes",
+    tag = "servers",
+    params(ServerMetaQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = VibesResponse), super::ErrorResponses)
+)]
+async fn get_vibes(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ServerMetaQuery>,
+) -> Result<Json<VibesResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let (content, revision) = revision::read(&matrix, &params.server_id, voice::VIBES_EVENT_TYPE, "")
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+    Ok(Json(VibesResponse { builtin: voice::builtin_vibes(), vibes: voice::custom_vibes(&content), revision }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/vibes",
+    tag = "servers",
+    request_body = SetVibesRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = VibesResponse), super::ErrorResponses)
+)]
+async fn set_vibes(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetVibesRequest>,
+) -> Result<Json<VibesResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let level = server_power(&matrix, &req.server_id).await.unwrap_or(0);
+    if level < VIBE_MANAGER_POWER || (req.force && level < 100) {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "only moderators can manage the server's vibes"));
+    }
+    if let Some(reason) = voice::invalid_custom_vibes(&req.vibes) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", reason));
+    }
+
+    let content = serde_json::json!({ "vibes": req.vibes });
+    match revision::write(&matrix, &req.server_id, voice::VIBES_EVENT_TYPE, "", req.revision, req.force, content).await {
+        Ok(revision) => Ok(Json(VibesResponse { builtin: voice::builtin_vibes(), vibes: req.vibes, revision })),
+        Err(CasError::Conflict { revision, current }) => Err(revision_conflict(
+            revision,
+            serde_json::json!({ "vibes": voice::custom_vibes(&current) }),
+        )),
+        Err(CasError::Matrix(e)) => Err(matrix_error(&e, StatusCode::BAD_REQUEST)),
+    }
+}
+
+// ── member list ───────────────────────────────────────────────────────────────
+// the member sidebar: everyone joined to the server, each under their highest
+// hoisted role and coloured by their highest coloured one. memberships, role
+// assignments and role definitions all come from one read of the space's state;
+// presence is one MGET per page of user ids. online members come first, then
+// by hoisted role (highest first, unhoisted last), then by name.
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ServerMembersQuery {
+    pub server_id: String,
+    /// only members whose display name (or user id) contains this, any case
+    pub query: Option<String>,
+    pub limit: Option<usize>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ServerMember {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    /// as everyone else sees it — invisible members are offline
+    pub presence: String,
+    /// the roles they hold, highest first
+    pub role_ids: Vec<String>,
+    /// the highest hoisted role they hold — the group they list under
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hoisted_role_id: Option<String>,
+    /// their highest role's colour
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip)]
+    hoisted_position: Option<i64>,
+}
+
+impl ServerMember {
+    fn online(&self) -> bool {
+        self.presence != "offline"
+    }
+
+    /// what the list sorts on, lowest first
+    fn sort_key(&self) -> MemberCursor {
+        MemberCursor {
+            offline: !self.online(),
+            hoisted_position: self.hoisted_position,
+            name: self.display_name.as_deref().unwrap_or(&self.user_id).to_lowercase(),
+            user_id: self.user_id.clone(),
         }
     }
 }
 
+/// a hoisted role members are grouped under
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MemberGroup {
+    pub role_id: String,
+    pub name: String,
+    pub color: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ServerMembersResponse {
+    /// every hoisted role, highest first — members without one list after them
+    pub groups: Vec<MemberGroup>,
+    pub members: Vec<ServerMember>,
+    /// members matching the query, across all pages
+    pub total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct MemberCursor {
+    offline: bool,
+    hoisted_position: Option<i64>,
+    name: String,
+    user_id: String,
+}
+
+impl MemberCursor {
+    // the highest hoisted role first, members without one last
+    fn key(&self) -> (bool, bool, std::cmp::Reverse<Option<i64>>, &str, &str) {
+        let position = self.hoisted_position;
+        (self.offline, position.is_none(), std::cmp::Reverse(position), &self.name, &self.user_id)
+    }
+}
+
+impl Ord for MemberCursor {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for MemberCursor {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/members",
+    tag = "servers",
+    params(ServerMembersQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ServerMembersResponse), super::ErrorResponses)
+)]
+async fn list_members(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ServerMembersQuery>,
+) -> Result<Json<ServerMembersResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    let page = PageParams { limit: params.limit, after: params.after.clone() };
+
+    let server_state = matrix
+        .get_room_state(params.server_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let roles = roles_from_content(
+        &server_state
+            .iter()
+            .find(|e| e.event_type == "agora.roles" && e.state_key.as_deref() == Some(""))
+            .map(|e| e.content.clone())
+            .unwrap_or_default(),
+    );
+    let held: HashMap<&str, Vec<String>> = server_state
+        .iter()
+        .filter(|e| e.event_type == "agora.member.roles")
+        .filter_map(|e| {
+            let role_ids = serde_json::from_value(e.content["role_ids"].clone()).ok()?;
+            Some((e.state_key.as_deref()?, role_ids))
+        })
+        .collect();
+
+    let query = params.query.as_deref().map(str::to_lowercase).filter(|q| !q.is_empty());
+    let mut members: Vec<ServerMember> = server_state
+        .iter()
+        .filter(|e| e.event_type == "m.room.member" && e.content["membership"] == "join")
+        .filter_map(|e| {
+            let user_id = e.state_key.clone()?;
+            let display_name = e.content["displayname"].as_str().map(str::to_string);
+            if let Some(query) = &query {
+                let name = display_name.as_deref().unwrap_or(&user_id).to_lowercase();
+                if !name.contains(query.as_str()) && !user_id.to_lowercase().contains(query.as_str()) {
+                    return None;
+                }
+            }
+            // roles are highest first, so the first match is the one that counts
+            let member_roles: Vec<&Role> = held
+                .get(user_id.as_str())
+                .map(|ids| roles.iter().filter(|r| ids.contains(&r.id)).collect())
+                .unwrap_or_default();
+            let hoisted = member_roles.iter().find(|r| r.hoist);
+            Some(ServerMember {
+                display_name,
+                avatar_url: e.content["avatar_url"].as_str().map(str::to_string),
+                presence: "offline".to_string(),
+                role_ids: member_roles.iter().map(|r| r.id.clone()).collect(),
+                hoisted_role_id: hoisted.map(|r| r.id.clone()),
+                color: member_roles.iter().find(|r| !r.color.is_empty()).map(|r| r.color.clone()),
+                hoisted_position: hoisted.map(|r| r.position),
+                user_id,
+            })
+        })
+        .collect();
+
+    if let Some(mut redis) = state.get_redis().await {
+        let user_ids: HashSet<String> = members.iter().map(|m| m.user_id.clone()).collect();
+        match presence_snapshot(&mut redis, Some(&user_ids)).await {
+            Ok(snapshot) => {
+                let presence: HashMap<String, String> = snapshot.into_iter().map(|p| (p.user_id, p.presence)).collect();
+                for member in &mut members {
+                    if let Some(p) = presence.get(&member.user_id) {
+                        member.presence = p.clone();
+                    }
+                }
+            }
+            Err(e) => tracing::warn!("no presence for the member list of {}: {}", params.server_id, e),
+        }
+    }
+
+    members.sort_by_cached_key(ServerMember::sort_key);
+    let groups = roles
+        .iter()
+        .filter(|r| r.hoist)
+        .map(|r| MemberGroup { role_id: r.id.clone(), name: r.name.clone(), color: r.color.clone() })
+        .collect();
+    let paginated = paginate_sorted(members, &page, ServerMember::sort_key, |m, c| m.sort_key().cmp(c))
+        .map_err(|status| agora_error(status, "M_INVALID_PARAM", "invalid cursor"))?;
+    Ok(Json(ServerMembersResponse {
+        groups,
+        members: paginated.items,
+        total: paginated.total.unwrap_or_default(),
+        next_cursor: paginated.next_cursor,
+    }))
+}
+
 // ── member role assignments ───────────────────────────────────────────────────
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct MemberRoles {
     pub user_id: String,
     pub role_ids: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct MemberRolesQuery {
-    pub access_token: String,
     pub server_id: String,
     pub user_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SetMemberRolesRequest {
-    pub access_token: String,
     pub server_id: String,
     pub user_id: String,
     pub role_ids: Vec<String>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/servers/members/roles",
+    tag = "servers",
+    params(MemberRolesQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = MemberRoles), super::StatusErrors)
+)]
 async fn get_member_roles(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<MemberRolesQuery>,
 ) -> Result<Json<MemberRoles>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
 
-    let encoded_uid = url_encode(&params.user_id);
+    let encoded_uid = encode_path_segment(&params.user_id);
     let url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.member.roles/{encoded_uid}",
-        state.homeserver_url, url_encode(&params.server_id)
+        "{}/rooms/{}/state/agora.member.roles/{encoded_uid}",
+        matrix.client_api_base().await, encode_path_segment(&params.server_id)
     );
     let role_ids = match matrix.get_raw(&url).await {
         Ok(body) => body["role_ids"].as_array()
@@ -307,104 +1158,775 @@ async fn get_member_roles(
     Ok(Json(MemberRoles { user_id: params.user_id, role_ids }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/servers/members/roles",
+    tag = "servers",
+    request_body = SetMemberRolesRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
 async fn set_member_roles(
     state: State<Arc<AppState>>,
-    Json(req): Json<SetMemberRolesRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    AuthJson(auth, req): AuthJson<SetMemberRolesRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::ManageRoles)
+        .await
+        .map_err(|e| authz_error(&e))?;
 
     // also update the member's Matrix power level to match the highest-power role they have
     // first fetch the current roles list so we know the power levels
     let roles_url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.roles/",
-        state.homeserver_url, url_encode(&req.server_id)
+        "{}/rooms/{}/state/agora.roles/",
+        matrix.client_api_base().await, encode_path_segment(&req.server_id)
     );
     let roles: Vec<Role> = matrix.get_raw(&roles_url).await.ok()
         .and_then(|v| v["roles"].as_array().and_then(|a| serde_json::from_value::<Vec<Role>>(serde_json::Value::Array(a.clone())).ok()))
         .unwrap_or_default();
 
-    // compute the highest power level this member gets from their roles
-    let max_power = req.role_ids.iter()
-        .filter_map(|rid| roles.iter().find(|r| &r.id == rid))
-        .map(|r| r.power_level)
-        .max()
-        .unwrap_or(0);
-
-    // update Matrix power levels for this member
-    if let Ok(mut power) = matrix.get_power_levels(req.server_id.clone()).await {
-        power.users.get_or_insert_with(Default::default).insert(req.user_id.clone(), max_power);
-        let content = serde_json::to_value(&power).unwrap_or_default();
-        let _ = matrix.send_state_event(req.server_id.clone(), "m.room.power_levels".to_string(), "".to_string(), content).await;
+    // whatever is handed out or taken away has to be below the caller
+    if !access.is_admin() {
+        let member_url = format!(
+            "{}/rooms/{}/state/agora.member.roles/{}",
+            matrix.client_api_base().await, encode_path_segment(&req.server_id), encode_path_segment(&req.user_id)
+        );
+        let current: Vec<String> = matrix.get_raw(&member_url).await.ok()
+            .and_then(|v| serde_json::from_value(v["role_ids"].clone()).ok())
+            .unwrap_or_default();
+        let changed = req.role_ids.iter().filter(|id| !current.contains(id))
+            .chain(current.iter().filter(|id| !req.role_ids.contains(id)));
+        for role in changed.filter_map(|id| roles.iter().find(|r| &r.id == id)) {
+            access.require_outranks(&role.id, role.position).map_err(|e| authz_error(&e))?;
+        }
     }
+    let max_power = role_power(&roles, &req.role_ids);
+    set_member_powers(&matrix, &req.server_id, &[(req.user_id.clone(), max_power)]).await;
 
     let content = serde_json::json!({ "role_ids": req.role_ids });
     match matrix.send_state_event(req.server_id.clone(), "agora.member.roles".to_string(), req.user_id.clone(), content).await {
         Ok(_) => Ok(StatusCode::OK),
         Err(e) => {
             tracing::error!("failed to set member roles: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            Err(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
 
-// ── forum threads ─────────────────────────────────────────────────────────────
-// a forum channel is a Matrix room with agora.room.type = "forum".
-// threads are Matrix rooms with agora.room.type = "thread" linked as
-// m.space.child state events on the forum channel room.
+/// the highest power level any of `role_ids` maps to — 0 with none
+fn role_power(roles: &[Role], role_ids: &[String]) -> i64 {
+    role_ids.iter()
+        .filter_map(|rid| roles.iter().find(|r| &r.id == rid))
+        .map(|r| r.power_level)
+        .max()
+        .unwrap_or(0)
+}
 
-#[derive(Debug, Deserialize)]
-pub struct ThreadsQuery {
-    pub access_token: String,
-    pub forum_channel_id: String,
+/// write members' power levels in one m.room.power_levels update
+async fn set_member_powers(matrix: &MatrixClient, server_id: &str, powers: &[(String, i64)]) {
+    if powers.is_empty() {
+        return;
+    }
+    if let Ok(mut power) = matrix.get_power_levels(server_id.to_string()).await {
+        let users = power.users.get_or_insert_with(Default::default);
+        for (user_id, level) in powers {
+            users.insert(user_id.clone(), *level);
+        }
+        let content = serde_json::to_value(&power).unwrap_or_default();
+        let _ = matrix.send_state_event(server_id.to_string(), "m.room.power_levels".to_string(), "".to_string(), content).await;
+    }
 }
 
-#[derive(Debug, Serialize)]
-pub struct ThreadInfo {
-    pub room_id: String,
-    pub title: String,
-    pub author: String,
-    pub created_at: Option<u64>,
-    pub reply_count: Option<u64>,
-    pub pinned: bool,
+// ── default role ──────────────────────────────────────────────────────────────
+// a server can name a role every member gets on joining (default_role_id in
+// agora.server.meta). a joiner can't write the server's state, so whoever sets
+// the default registers their token in the default_role_servers redis hash —
+// as with afk channels — and the role is handed out with it when someone joins
+// through /rooms/join, or reports having joined through /servers/members/joined.
+// members who already hold roles are left alone.
+
+const DEFAULT_ROLE_SERVERS_KEY: &str = "default_role_servers";
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MemberJoinedRequest {
+    pub server_id: String,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ThreadsResponse {
-    pub threads: Vec<ThreadInfo>,
+/// start or stop handing out the server's default role with `access_token`
+async fn register_default_role(state: &AppState, server_id: &str, access_token: Option<&str>) {
+    let Some(mut redis) = state.get_redis().await else {
+        return;
+    };
+    let stored: redis::RedisResult<()> = match access_token {
+        Some(token) => redis.hset(DEFAULT_ROLE_SERVERS_KEY, server_id, token).await,
+        None => redis.hdel(DEFAULT_ROLE_SERVERS_KEY, server_id).await,
+    };
+    if let Err(e) = stored {
+        tracing::warn!("failed to register the default role of {}: {}", server_id, e);
+    }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct CreateThreadRequest {
-    pub access_token: String,
-    pub forum_channel_id: String,
-    pub title: String,
-    pub author: String,
-    /// initial message body for the thread (sent as first message)
-    pub body: String,
+/// give `user_id` the server's default role, unless they already hold a role
+/// or there's none to give. their power level is only ever raised by it.
+/// returns the role handed out.
+pub async fn assign_default_role(
+    state: &AppState,
+    server_id: &str,
+    server_state: &[RoomStateEvent],
+    user_id: &str,
+) -> Option<String> {
+    let find = |event_type: &str, state_key: &str| {
+        server_state
+            .iter()
+            .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(state_key))
+            .map(|e| &e.content)
+    };
+    let role_id = find("agora.server.meta", "")?["default_role_id"].as_str()?.to_string();
+    let holds_roles = find("agora.member.roles", user_id)
+        .and_then(|c| c["role_ids"].as_array())
+        .is_some_and(|ids| !ids.is_empty());
+    if holds_roles {
+        return None;
+    }
+    let role = roles_from_content(find("agora.roles", "")?).into_iter().find(|r| r.id == role_id)?;
+
+    let mut redis = state.get_redis().await?;
+    let token: Option<String> = redis.hget(DEFAULT_ROLE_SERVERS_KEY, server_id).await.ok()?;
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(token?);
+
+    let content = serde_json::json!({ "role_ids": [role_id] });
+    if let Err(e) = matrix.send_state_event(server_id.to_string(), "agora.member.roles".to_string(), user_id.to_string(), content).await {
+        tracing::warn!("failed to give {} the default role of {}: {}", user_id, server_id, e);
+        return None;
+    }
+    let level = authz::ServerAccess::from_state(server_state, user_id.to_string(), server_id).level;
+    if role.power_level > level {
+        set_member_powers(&matrix, server_id, &[(user_id.to_string(), role.power_level)]).await;
+    }
+    Some(role.id)
 }
 
-async fn list_threads(
+/// for members who joined some other way than /rooms/join (straight through
+/// the homeserver, say): the default role, if they're due it
+#[utoipa::path(
+    post,
+    path = "/servers/members/joined",
+    tag = "servers",
+    request_body = MemberJoinedRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = MemberRoles), super::ErrorResponses)
+)]
+async fn member_joined(
     state: State<Arc<AppState>>,
-    Query(params): Query<ThreadsQuery>,
-) -> Result<Json<ThreadsResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token.clone());
+    AuthJson(auth, req): AuthJson<MemberJoinedRequest>,
+) -> Result<Json<MemberRoles>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let user_id = auth.user_id;
+    let server_state = matrix.get_room_state(req.server_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+    let joined = server_state.iter().any(|e| {
+        e.event_type == "m.room.member" && e.state_key.as_deref() == Some(&user_id) && e.content["membership"] == "join"
+    });
+    if !joined {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "you aren't in that server"));
+    }
 
-    // get all m.space.child events from the forum channel room
-    let room_state = matrix.get_room_state(params.forum_channel_id.clone()).await
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let role_ids = match assign_default_role(&state, &req.server_id, &server_state, &user_id).await {
+        Some(role_id) => vec![role_id],
+        None => server_state.iter()
+            .find(|e| e.event_type == "agora.member.roles" && e.state_key.as_deref() == Some(&user_id))
+            .and_then(|e| serde_json::from_value(e.content["role_ids"].clone()).ok())
+            .unwrap_or_default(),
+    };
+    Ok(Json(MemberRoles { user_id, role_ids }))
+}
 
-    let child_ids: Vec<String> = room_state.iter()
-        .filter(|e| e.event_type == "m.space.child")
-        .filter_map(|e| e.state_key.clone())
-        .filter(|k| !k.is_empty())
-        .collect();
+// ── moderation ────────────────────────────────────────────────────────────────
+// a kick or ban from a server applies to the space and every room below it —
+// otherwise the member would be gone from the sidebar but still sitting in (or
+// able to rejoin) the channels. the homeserver checks kick/ban power per room,
+// so a partial failure is reported per room instead of failing the whole call.
+// bans are also listed in an agora.server.bans state event on the space, since
+// the ban memberships alone don't say who banned, when, or why.
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ServerMemberActionRequest {
+    pub server_id: String,
+    pub user_id: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ServerBansQuery {
+    pub server_id: String,
+    pub limit: Option<usize>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerBan {
+    pub user_id: String,
+    pub banned_by: String,
+    /// unix ms
+    pub banned_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RoomOutcome {
+    pub room_id: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errcode: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ModerationResponse {
+    pub rooms: Vec<RoomOutcome>,
+    /// false when some room refused — the member may still be in (or able to join) it
+    pub complete: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+}
+
+impl ModerationAction {
+    /// whether the action applies to a member currently in this state.
+    /// None means the room's state couldn't be read — try anyway.
+    fn applies_to(self, membership: Option<&str>) -> bool {
+        match (self, membership) {
+            (_, None) => true,
+            (ModerationAction::Kick, Some(m)) => matches!(m, "join" | "invite" | "knock"),
+            (ModerationAction::Ban, Some(m)) => m != "ban",
+            (ModerationAction::Unban, Some(m)) => m == "ban",
+        }
+    }
+}
+
+/// apply `action` to `user_id` in every room under the server, deepest first and
+/// the space itself last. rooms where the user's membership makes it a no-op are skipped.
+async fn moderate_server(
+    matrix: &MatrixClient,
+    server_id: &str,
+    user_id: &str,
+    action: ModerationAction,
+    reason: Option<String>,
+) -> Result<ModerationResponse, MatrixError> {
+    let nodes = hierarchy::walk_space(matrix, server_id, hierarchy::max_depth()).await;
+    let mut rooms = Vec::new();
+    let mut first_error = None;
+
+    let order = nodes.iter().skip(1).rev().chain(nodes.first());
+    for node in order {
+        let membership = (!node.state.is_empty()).then(|| {
+            node.state
+                .iter()
+                .find(|e| e.event_type == "m.room.member" && e.state_key.as_deref() == Some(user_id))
+                .and_then(|e| e.content.get("membership").and_then(|m| m.as_str()))
+                .unwrap_or("leave")
+        });
+        if !action.applies_to(membership) {
+            continue;
+        }
+
+        let result = match action {
+            ModerationAction::Kick => matrix.kick_user(node.room_id.clone(), user_id.to_string(), reason.clone()).await,
+            ModerationAction::Ban => matrix.ban_user(&node.room_id, user_id, reason.clone()).await,
+            ModerationAction::Unban => matrix.unban_user(&node.room_id, user_id).await,
+        };
+        let errcode = match result {
+            Ok(()) => None,
+            Err(e) => {
+                tracing::warn!("moderation in {} failed: {}", node.room_id, e);
+                let errcode = e.errcode().unwrap_or("M_UNKNOWN").to_string();
+                first_error.get_or_insert(e);
+                Some(errcode)
+            }
+        };
+        rooms.push(RoomOutcome { room_id: node.room_id.clone(), ok: errcode.is_none(), errcode });
+    }
+
+    // nothing went through at all — that's a failure of the call, not a partial result
+    if let Some(e) = first_error {
+        if rooms.iter().all(|r| !r.ok) {
+            return Err(e);
+        }
+    }
+    let complete = rooms.iter().all(|r| r.ok);
+    Ok(ModerationResponse { rooms, complete })
+}
+
+/// where a page of bans ends: newest first, then by user id
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct BanCursor {
+    banned_at: std::cmp::Reverse<u64>,
+    user_id: String,
+}
+
+impl ServerBan {
+    fn sort_key(&self) -> BanCursor {
+        BanCursor { banned_at: std::cmp::Reverse(self.banned_at), user_id: self.user_id.clone() }
+    }
+}
+
+fn bans_of(content: &serde_json::Value) -> Vec<ServerBan> {
+    content
+        .get("bans")
+        .and_then(|b| serde_json::from_value(b.clone()).ok())
+        .unwrap_or_default()
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/members/kick",
+    tag = "servers",
+    request_body = ServerMemberActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ModerationResponse), super::ErrorResponses)
+)]
+async fn kick_member(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ServerMemberActionRequest>,
+) -> Result<Json<ModerationResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    moderate_server(&matrix, &req.server_id, &req.user_id, ModerationAction::Kick, req.reason)
+        .await
+        .map(Json)
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/members/ban",
+    tag = "servers",
+    request_body = ServerMemberActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ModerationResponse), super::ErrorResponses)
+)]
+async fn ban_member(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ServerMemberActionRequest>,
+) -> Result<Json<ModerationResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let banned_by = auth.user_id;
+    let response = moderate_server(&matrix, &req.server_id, &req.user_id, ModerationAction::Ban, req.reason.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    let ban = ServerBan {
+        user_id: req.user_id.clone(),
+        banned_by,
+        banned_at: chrono::Utc::now().timestamp_millis() as u64,
+        reason: req.reason,
+    };
+    let recorded = revision::update(&matrix, &req.server_id, "agora.server.bans", "", |content| {
+        let mut bans = bans_of(content);
+        bans.retain(|b| b.user_id != ban.user_id);
+        bans.push(ban);
+        content["bans"] = serde_json::to_value(bans).unwrap_or_default();
+    })
+    .await;
+    if let Err(e) = recorded {
+        // the memberships are what enforce the ban; the list is bookkeeping
+        tracing::warn!("failed to record server ban: {}", e);
+    }
+    Ok(Json(response))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/members/unban",
+    tag = "servers",
+    request_body = ServerMemberActionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ModerationResponse), super::ErrorResponses)
+)]
+async fn unban_member(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<ServerMemberActionRequest>,
+) -> Result<Json<ModerationResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let response = moderate_server(&matrix, &req.server_id, &req.user_id, ModerationAction::Unban, None)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    let user_id = req.user_id;
+    let recorded = revision::update(&matrix, &req.server_id, "agora.server.bans", "", |content| {
+        let mut bans = bans_of(content);
+        bans.retain(|b| b.user_id != user_id);
+        content["bans"] = serde_json::to_value(bans).unwrap_or_default();
+    })
+    .await;
+    if let Err(e) = recorded {
+        tracing::warn!("failed to update server ban list: {}", e);
+    }
+    Ok(Json(response))
+}
+
+/// the server's bans, newest first. paginated when `limit` is supplied
+#[utoipa::path(
+    get,
+    path = "/servers/bans",
+    tag = "servers",
+    params(ServerBansQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = Object, description = "{ bans }, or with `limit` a page of them: { items, next_cursor, total }"), super::ErrorResponses)
+)]
+async fn list_bans(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ServerBansQuery>,
+) -> Result<Response, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    let page = PageParams { limit: params.limit, after: params.after.clone() };
+
+    // who was banned, by whom and why is for the people who ban
+    authz::require_permission(&matrix, &params.server_id, Permission::BanMembers)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let (content, _) = revision::read(&matrix, &params.server_id, "agora.server.bans", "")
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let mut bans = bans_of(&content);
+    bans.sort_by_cached_key(ServerBan::sort_key);
+    if !page.is_paginated() {
+        return Ok(Json(serde_json::json!({ "bans": bans })).into_response());
+    }
+    let paginated = paginate_sorted(bans, &page, ServerBan::sort_key, |b, c| b.sort_key().cmp(c))
+        .map_err(|status| agora_error(status, "M_INVALID_PARAM", "invalid cursor"))?;
+    Ok(Json(paginated).into_response())
+}
+
+// ── timeouts ──────────────────────────────────────────────────────────────────
+// a timeout keeps a member from posting or reacting anywhere in the server
+// until it runs out. agora.member.timeout on the space (keyed by user) is the
+// record; a timeouts:{user} redis hash (server → until) lets send and react
+// skip reading the server's state for the many members who have none.
+// expiry is checked wherever it's enforced, so nothing has to clear it.
+
+pub const MAX_TIMEOUT_SECS: u64 = 28 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TimeoutRequest {
+    pub server_id: String,
+    pub user_id: String,
+    /// up to 28 days
+    pub duration_secs: u64,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RemoveTimeoutRequest {
+    pub server_id: String,
+    pub user_id: String,
+}
+
+/// the content of agora.member.timeout — `{}` once lifted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemberTimeout {
+    /// unix ms
+    pub until: i64,
+    pub timed_out_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TimeoutResponse {
+    pub user_id: String,
+    /// unix ms
+    pub timed_out_until: i64,
+}
+
+fn timeouts_key(user_id: &str) -> String {
+    format!("timeouts:{}", user_id)
+}
+
+/// mirror a timeout (or its removal) into redis. the hash lives as long as
+/// the user's longest timeout.
+async fn cache_timeout(state: &AppState, server_id: &str, user_id: &str, until: Option<i64>) {
+    let Some(mut redis) = state.get_redis().await else {
+        return;
+    };
+    let key = timeouts_key(user_id);
+    let stored: redis::RedisResult<()> = match until {
+        Some(until) => redis.hset(&key, server_id, until).await,
+        None => redis.hdel(&key, server_id).await,
+    };
+    if let Err(e) = stored {
+        tracing::warn!("failed to cache the timeout of {} in {}: {}", user_id, server_id, e);
+        return;
+    }
+    let all: HashMap<String, i64> = redis.hgetall(&key).await.unwrap_or_default();
+    let now = chrono::Utc::now().timestamp_millis();
+    if let Some(last) = all.values().max().filter(|last| **last > now) {
+        let secs = (last - now + 999) / 1000;
+        let _: redis::RedisResult<()> = redis.expire(&key, secs).await;
+    }
+}
+
+/// false only when redis says the user has no timeout running anywhere at
+/// `now` — anything else has to be checked against the server's state
+pub async fn may_be_timed_out(state: &AppState, user_id: &str, now: i64) -> bool {
+    let Some(mut redis) = state.get_redis().await else {
+        return true;
+    };
+    match redis.hgetall::<_, HashMap<String, i64>>(timeouts_key(user_id)).await {
+        Ok(timeouts) => timeouts.values().any(|until| *until > now),
+        Err(_) => true,
+    }
+}
+
+/// the caller may time `user_id` out: not themselves, not an admin, and not
+/// anyone holding a role at or above the caller's highest
+async fn require_can_time_out(matrix: &MatrixClient, access: &authz::ServerAccess, user_id: &str) -> Result<(), Response> {
+    if user_id == access.user_id {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "you can't time yourself out"));
+    }
+    let server_state = matrix.get_room_state(access.server_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let target = authz::ServerAccess::from_state(&server_state, user_id.to_string(), &access.server_id);
+    let out_of_reach = !access.is_admin() && target.highest_position().is_some_and(|p| !access.outranks(p));
+    if target.is_admin() || out_of_reach {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "you can't time out someone at or above your rank"));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/members/timeout",
+    tag = "servers",
+    request_body = TimeoutRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = TimeoutResponse), super::ErrorResponses)
+)]
+async fn timeout_member(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<TimeoutRequest>,
+) -> Result<Json<TimeoutResponse>, Response> {
+    check_timeout_duration(req.duration_secs).map_err(|e| *e)?;
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::KickMembers)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let until = time_out(&state, &matrix, &access, &req.user_id, req.duration_secs, req.reason).await?;
+    Ok(Json(TimeoutResponse { user_id: req.user_id, timed_out_until: until }))
+}
+
+pub fn check_timeout_duration(duration_secs: u64) -> Result<(), Box<Response>> {
+    if !(1..=MAX_TIMEOUT_SECS).contains(&duration_secs) {
+        return Err(Box::new(agora_error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_PARAM",
+            "timeouts last from a second up to 28 days",
+        )));
+    }
+    Ok(())
+}
+
+/// time `user_id` out of the caller's server, `access` having been checked for
+/// kick_members. returns when it ends, unix ms. shared with the report queue
+pub async fn time_out(
+    state: &AppState,
+    matrix: &MatrixClient,
+    access: &authz::ServerAccess,
+    user_id: &str,
+    duration_secs: u64,
+    reason: Option<String>,
+) -> Result<i64, Response> {
+    require_can_time_out(matrix, access, user_id).await?;
+
+    let until = chrono::Utc::now().timestamp_millis() + duration_secs as i64 * 1000;
+    let timeout = MemberTimeout { until, timed_out_by: access.user_id.clone(), reason };
+    let content = serde_json::to_value(&timeout).unwrap_or_default();
+    matrix.send_state_event(access.server_id.clone(), TIMEOUT_EVENT_TYPE.to_string(), user_id.to_string(), content)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    cache_timeout(state, &access.server_id, user_id, Some(until)).await;
+    Ok(until)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/servers/members/timeout",
+    tag = "servers",
+    request_body = RemoveTimeoutRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn remove_timeout(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<RemoveTimeoutRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission(&matrix, &req.server_id, Permission::KickMembers)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    matrix.send_state_event(req.server_id.clone(), TIMEOUT_EVENT_TYPE.to_string(), req.user_id.clone(), serde_json::json!({}))
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    cache_timeout(&state, &req.server_id, &req.user_id, None).await;
+    Ok(StatusCode::OK)
+}
+
+// ── forum threads ─────────────────────────────────────────────────────────────
+// a forum channel is a Matrix room with agora.room.type = "forum".
+// threads are Matrix rooms with agora.room.type = "thread" linked as
+// m.space.child state events on the forum channel room.
+// agora.thread.meta on the thread carries its flags: pinned threads list
+// first, locked ones take no new messages (moderators excepted), archived
+// ones drop out of the list unless asked for. pinning and locking are for
+// moderators (manage_messages); the author can archive or delete their own.
+// reply counts and last activity are read off the thread's timeline when
+// listed, and kept in redis for a minute — sync drops a thread's entry when
+// it sees a new message there.
+// a forum's tags live in agora.forum.meta on the forum channel (manage_channels
+// to change them); a thread carries the ids of up to five in its meta, which
+// the author or a moderator can change later.
+// thread states come through the room summary cache (room_summaries.rs).
+
+const THREAD_STATS_TTL_SECS: u64 = 60;
+// timeline pages read per thread; a thread longer than this reports a lower bound
+const THREAD_STATS_MAX_PAGES: usize = 20;
+const THREAD_STATS_PAGE_SIZE: u32 = 100;
+// thread rooms read at once while listing
+const THREAD_READ_CONCURRENCY: usize = 8;
+const MAX_FORUM_TAGS: usize = 20;
+const MAX_THREAD_TAGS: usize = 5;
+const MAX_TAG_NAME_LEN: usize = 30;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ThreadsQuery {
+    pub forum_channel_id: String,
+    /// page size — omit for the legacy un-paginated response
+    pub limit: Option<usize>,
+    /// cursor from a previous page's next_cursor
+    pub after: Option<String>,
+    /// archived threads: left out (default), included, or the only ones listed
+    #[serde(default)]
+    pub archived: ArchivedFilter,
+    /// newest first by creation (default), last message, or reply count;
+    /// pinned threads come first either way
+    #[serde(default)]
+    pub sort: ThreadSort,
+    /// only threads carrying this tag id
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadSort {
+    Activity,
+    #[default]
+    Created,
+    Replies,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchivedFilter {
+    #[default]
+    Exclude,
+    Include,
+    Only,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ThreadInfo {
+    pub room_id: String,
+    pub title: String,
+    pub author: String,
+    pub created_at: Option<u64>,
+    /// messages after the opening post; null when the timeline couldn't be read
+    pub reply_count: Option<u64>,
+    /// when the last message was sent, else when the thread was created
+    pub last_activity_ts: Option<u64>,
+    pub pinned: bool,
+    pub locked: bool,
+    pub archived: bool,
+    /// tag ids, leaving out any the forum no longer has
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ThreadsResponse {
+    pub threads: Vec<ThreadInfo>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateThreadRequest {
+    pub forum_channel_id: String,
+    pub title: String,
+    pub author: String,
+    /// initial message body for the thread (sent as first message)
+    pub body: String,
+    /// ids from the forum's tags
+    #[serde(default)]
+    pub tag_ids: Vec<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/forum/threads",
+    tag = "servers",
+    params(ThreadsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ThreadsResponse, description = "when `limit` or `after` is given the answer is a page instead: { items, next_cursor, total }"), super::StatusErrors)
+)]
+async fn list_threads(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ThreadsQuery>,
+) -> Result<Response, StatusCode> {
+    let page = PageParams { limit: params.limit, after: params.after.clone() };
+    // reject a bad cursor before doing any matrix work
+    page.cursor::<ThreadCursor>()?;
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    // get all m.space.child events from the forum channel room
+    let room_state = matrix.get_room_state(params.forum_channel_id.clone()).await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let child_ids = hierarchy::child_ids(&room_state);
+    let forum_tags = tags_of(&room_state);
+
+    // read thread states a few at a time, most of them from the summary cache
+    let thread_states: Vec<Vec<RoomStateEvent>> = stream::iter(child_ids.clone())
+        .map(|child_id| room_summaries::load(&state, &matrix, child_id, false))
+        .buffered(THREAD_READ_CONCURRENCY)
+        .collect()
+        .await;
 
     let mut threads = Vec::new();
-    for child_id in child_ids {
-        // read thread state
-        let thread_state = matrix.get_room_state(child_id.clone()).await.unwrap_or_default();
+    for (child_id, thread_state) in child_ids.into_iter().zip(thread_states) {
         let title = thread_state.iter()
             .find(|e| e.event_type == "m.room.name")
             .and_then(|e| e.content["name"].as_str().map(String::from))
@@ -419,37 +1941,176 @@ async fn list_threads(
             .find(|e| e.event_type == "agora.thread.meta")
             .and_then(|e| e.content["created_at"].as_u64());
 
-        let reply_count = thread_state.iter()
+        let flag = |name: &str| thread_state.iter()
             .find(|e| e.event_type == "agora.thread.meta")
-            .and_then(|e| e.content["reply_count"].as_u64());
+            .and_then(|e| e.content[name].as_bool())
+            .unwrap_or(false);
+        let (pinned, locked, archived) = (flag("pinned"), flag("locked"), flag("archived"));
 
-        let pinned = thread_state.iter()
+        let tags: Vec<String> = thread_state.iter()
             .find(|e| e.event_type == "agora.thread.meta")
-            .and_then(|e| e.content["pinned"].as_bool())
-            .unwrap_or(false);
+            .and_then(|e| e.content["tags"].as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|t| t.as_str())
+            .filter(|t| forum_tags.iter().any(|known| known.id == *t))
+            .map(String::from)
+            .collect();
+
+        let listed = match params.archived {
+            ArchivedFilter::Exclude => !archived,
+            ArchivedFilter::Include => true,
+            ArchivedFilter::Only => archived,
+        } && params.tag.as_ref().is_none_or(|tag| tags.contains(tag));
+        if listed {
+            threads.push(ThreadInfo {
+                room_id: child_id, title, author, created_at, reply_count: None, last_activity_ts: None, pinned, locked, archived, tags,
+            });
+        }
+    }
 
-        threads.push(ThreadInfo { room_id: child_id, title, author, created_at, reply_count, pinned });
+    let listed: Vec<String> = threads.iter().map(|t| t.room_id.clone()).collect();
+    let stats: Vec<Option<ThreadStats>> = stream::iter(listed)
+        .map(|room_id| thread_stats(&state, &matrix, room_id))
+        .buffered(THREAD_READ_CONCURRENCY)
+        .collect()
+        .await;
+    for (thread, stats) in threads.iter_mut().zip(stats) {
+        thread.reply_count = stats.as_ref().map(|s| s.messages.saturating_sub(1));
+        thread.last_activity_ts = stats.and_then(|s| s.last_message_ts).or(thread.created_at);
     }
 
-    // sort: pinned first, then by created_at descending
-    threads.sort_by(|a, b| {
-        b.pinned.cmp(&a.pinned)
-            .then(b.created_at.cmp(&a.created_at))
-    });
+    // sort: pinned first, then by the chosen key descending (room id breaks ties for stable pages)
+    let sort = params.sort;
+    threads.sort_by(|a, b| thread_order(a, &ThreadCursor::of(b, sort), sort));
 
-    Ok(Json(ThreadsResponse { threads }))
+    if !page.is_paginated() {
+        return Ok(Json(ThreadsResponse { threads }).into_response());
+    }
+    let paginated = paginate_sorted(threads, &page, |t| ThreadCursor::of(t, sort), |t, c| thread_order(t, c, sort))?;
+    Ok(Json(paginated).into_response())
+}
+
+/// position in the thread list — the full sort key so pages survive new threads
+#[derive(Debug, Serialize, Deserialize)]
+struct ThreadCursor {
+    pinned: bool,
+    /// created_at, last_activity_ts or reply_count, whichever the list is sorted by
+    key: Option<u64>,
+    room_id: String,
 }
 
+impl ThreadCursor {
+    fn of(t: &ThreadInfo, sort: ThreadSort) -> Self {
+        let key = match sort {
+            ThreadSort::Activity => t.last_activity_ts,
+            ThreadSort::Created => t.created_at,
+            ThreadSort::Replies => t.reply_count,
+        };
+        Self { pinned: t.pinned, key, room_id: t.room_id.clone() }
+    }
+}
+
+fn thread_order(t: &ThreadInfo, c: &ThreadCursor, sort: ThreadSort) -> std::cmp::Ordering {
+    let t = ThreadCursor::of(t, sort);
+    c.pinned.cmp(&t.pinned)
+        .then(c.key.cmp(&t.key))
+        .then(t.room_id.cmp(&c.room_id))
+}
+
+/// what a thread's timeline says about it
+#[derive(Debug, Serialize, Deserialize)]
+struct ThreadStats {
+    /// messages, the opening post included — edits and deleted ones aren't counted
+    messages: u64,
+    last_message_ts: Option<u64>,
+}
+
+fn thread_stats_key(room_id: &str) -> String {
+    format!("thread_stats:{}", room_id)
+}
+
+/// a thread's message count and latest message, from redis when fresh,
+/// else by paging back through its timeline. None when that fails
+async fn thread_stats(state: &AppState, matrix: &MatrixClient, room_id: String) -> Option<ThreadStats> {
+    let key = thread_stats_key(&room_id);
+    if let Some(mut redis) = state.get_redis().await {
+        let cached: Option<String> = redis.get(&key).await.ok().flatten();
+        if let Some(stats) = cached.and_then(|c| serde_json::from_str(&c).ok()) {
+            return Some(stats);
+        }
+    }
+
+    let mut stats = ThreadStats { messages: 0, last_message_ts: None };
+    let mut from: Option<String> = None;
+    for _ in 0..THREAD_STATS_MAX_PAGES {
+        let page = match matrix.get_messages(&room_id, from.as_deref(), Direction::Backward, THREAD_STATS_PAGE_SIZE).await {
+            Ok(page) => page,
+            Err(e) => {
+                tracing::debug!("couldn't read thread {}: {}", room_id, e);
+                return None;
+            }
+        };
+        for event in &page.chunk {
+            let counted = event.event_type == "m.room.message"
+                && event.content.get("body").is_some()
+                && event.content["m.relates_to"]["rel_type"] != "m.replace";
+            if counted {
+                stats.messages += 1;
+                // newest first, so the first one counted is the latest
+                if stats.last_message_ts.is_none() {
+                    stats.last_message_ts = event.origin_server_ts.map(|ts| ts.max(0) as u64);
+                }
+            }
+        }
+        match page.end {
+            Some(end) if !page.chunk.is_empty() => from = Some(end),
+            _ => break,
+        }
+    }
+
+    if let (Some(mut redis), Ok(json)) = (state.get_redis().await, serde_json::to_string(&stats)) {
+        let _: redis::RedisResult<()> = redis.set_ex(&key, json, THREAD_STATS_TTL_SECS).await;
+    }
+    Some(stats)
+}
+
+/// drop the cached stats for rooms that just had messages — sync calls this,
+/// and most of them won't be threads, which costs nothing
+pub async fn invalidate_thread_stats(state: &AppState, room_ids: &[String]) {
+    let (Some(mut redis), false) = (state.get_redis().await, room_ids.is_empty()) else { return };
+    let keys: Vec<String> = room_ids.iter().map(|room_id| thread_stats_key(room_id)).collect();
+    let _: redis::RedisResult<()> = redis.del(keys).await;
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/forum/thread",
+    tag = "servers",
+    request_body = CreateThreadRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = Object), super::ErrorResponses)
+)]
 async fn create_thread(
     state: State<Arc<AppState>>,
-    Json(req): Json<CreateThreadRequest>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token.clone());
+    AuthJson(auth, req): AuthJson<CreateThreadRequest>,
+) -> Result<Json<serde_json::Value>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    // check the tags before anything is created
+    let tag_ids = if req.tag_ids.is_empty() {
+        Vec::new()
+    } else {
+        let forum_state = matrix.get_room_state(req.forum_channel_id.clone())
+            .await
+            .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+        check_thread_tags(&tags_of(&forum_state), &req.tag_ids).map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?
+    };
 
     // create a new Matrix room for this thread
-    let thread_room = matrix.create_room(req.title.clone(), None, false).await
-        .map_err(|e| { tracing::error!("failed to create thread room: {}", e); StatusCode::INTERNAL_SERVER_ERROR })?;
+    let thread_room = matrix.create_room(req.title.clone(), None, false, HistoryVisibility::Shared).await
+        .map_err(|e| { tracing::error!("failed to create thread room: {}", e); StatusCode::INTERNAL_SERVER_ERROR.into_response() })?;
 
     let now_ms = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -460,30 +2121,544 @@ async fn create_thread(
     let meta = serde_json::json!({
         "author": req.author,
         "created_at": now_ms,
-        "reply_count": 0,
         "pinned": false,
+        "tags": tag_ids,
     });
     let _ = matrix.send_state_event(thread_room.room_id.clone(), "agora.room.type".to_string(), "".to_string(), serde_json::json!({ "type": "thread" })).await;
     let _ = matrix.send_state_event(thread_room.room_id.clone(), "agora.thread.meta".to_string(), "".to_string(), meta).await;
 
-    // link thread room to forum channel
-    let _ = matrix.add_space_child(req.forum_channel_id.clone(), thread_room.room_id.clone()).await;
+    // link thread room to forum channel, both ways so the thread can find its server
+    if let Err(e) = link_to_parent(&matrix, &thread_room.room_id, &req.forum_channel_id, &state.server_name).await {
+        tracing::warn!("failed to link thread to its forum: {}", e);
+    }
+    service_account::bring_in(&state, &matrix, &thread_room.room_id).await;
 
     // send the opening message
-    let _ = matrix.send_message(thread_room.room_id.clone(), req.body).await;
+    let _ = matrix.send_message(thread_room.room_id.clone(), req.body, None).await;
 
     Ok(Json(serde_json::json!({ "room_id": thread_room.room_id })))
 }
 
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateThreadRequest {
+    pub thread_room_id: String,
+    pub pinned: Option<bool>,
+    pub locked: Option<bool>,
+    pub archived: Option<bool>,
+    /// unlink the thread from its forum and leave it
+    #[serde(default)]
+    pub delete: bool,
+    /// only needed to delete threads made before they recorded their forum
+    pub forum_channel_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UpdateThreadResponse {
+    pub room_id: String,
+    pub pinned: bool,
+    pub locked: bool,
+    pub archived: bool,
+    pub deleted: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/forum/thread/update",
+    tag = "servers",
+    request_body = UpdateThreadRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = UpdateThreadResponse), super::ErrorResponses)
+)]
+async fn update_thread(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<UpdateThreadRequest>,
+) -> Result<Json<UpdateThreadResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let user_id = auth.user_id;
+    let thread_state = matrix.get_room_state(req.thread_room_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let find = |event_type: &str| {
+        thread_state.iter()
+            .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+            .map(|e| e.content.clone())
+    };
+    if find("agora.room.type").is_none_or(|t| t["type"] != "thread") {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "that room isn't a forum thread"));
+    }
+    let mut meta = find("agora.thread.meta").unwrap_or_else(|| serde_json::json!({}));
+
+    // the author looks after their own thread; pins and locks are the forum's
+    let is_author = meta["author"].as_str() == Some(user_id.as_str());
+    if req.pinned.is_some() || req.locked.is_some() || !is_author {
+        authz::require_permission_in(&matrix, &req.thread_room_id, Permission::ManageMessages)
+            .await
+            .map_err(|e| authz_error(&e))?;
+    }
+
+    for (flag, value) in [("pinned", req.pinned), ("locked", req.locked), ("archived", req.archived)] {
+        if let Some(value) = value {
+            meta[flag] = serde_json::json!(value);
+        }
+    }
+    matrix.send_state_event(req.thread_room_id.clone(), "agora.thread.meta".to_string(), "".to_string(), meta.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    room_summaries::invalidate(&state, std::slice::from_ref(&req.thread_room_id)).await;
+
+    if req.delete {
+        if let Some(forum_id) = forum_of(&thread_state).or(req.forum_channel_id) {
+            matrix.remove_space_child(forum_id, req.thread_room_id.clone())
+                .await
+                .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+        }
+        if let Err(e) = matrix.leave_room(req.thread_room_id.clone()).await {
+            tracing::warn!("failed to leave deleted thread {}: {}", req.thread_room_id, e);
+        } else if let Err(e) = matrix.forget_room(req.thread_room_id.clone()).await {
+            tracing::warn!("failed to forget deleted thread {}: {}", req.thread_room_id, e);
+        }
+    }
+
+    let flag = |name: &str| meta[name].as_bool().unwrap_or(false);
+    Ok(Json(UpdateThreadResponse {
+        room_id: req.thread_room_id,
+        pinned: flag("pinned"),
+        locked: flag("locked"),
+        archived: flag("archived"),
+        deleted: req.delete,
+    }))
+}
+
+/// the forum a thread was linked from, by its m.space.parent
+fn forum_of(thread_state: &[RoomStateEvent]) -> Option<String> {
+    thread_state.iter()
+        .filter(|e| e.event_type == "m.space.parent")
+        .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+        .find_map(|e| e.state_key.clone())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ForumTag {
+    pub id: String,
+    pub name: String,
+    /// hex colour e.g. "#5865f2"
+    #[serde(default)]
+    pub color: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ForumTagsQuery {
+    pub forum_channel_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetForumTagsRequest {
+    pub forum_channel_id: String,
+    /// the whole set, replacing what was there
+    pub tags: Vec<ForumTag>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ForumTagsResponse {
+    pub forum_channel_id: String,
+    pub tags: Vec<ForumTag>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetThreadTagsRequest {
+    pub thread_room_id: String,
+    /// the thread's whole set — empty clears it
+    pub tag_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ThreadTagsResponse {
+    pub room_id: String,
+    pub tags: Vec<String>,
+}
+
+fn forum_meta(room_state: &[RoomStateEvent]) -> Option<&serde_json::Value> {
+    room_state.iter()
+        .find(|e| e.event_type == "agora.forum.meta" && e.state_key.as_deref() == Some(""))
+        .map(|e| &e.content)
+}
+
+fn tags_of(room_state: &[RoomStateEvent]) -> Vec<ForumTag> {
+    forum_meta(room_state)
+        .and_then(|meta| serde_json::from_value(meta["tags"].clone()).ok())
+        .unwrap_or_default()
+}
+
+fn is_forum(room_state: &[RoomStateEvent]) -> bool {
+    room_state.iter().any(|e| e.event_type == "agora.room.type" && e.content["type"] == "forum")
+}
+
+/// the tag ids, deduplicated, if the forum has them all and there aren't too many
+fn check_thread_tags(forum_tags: &[ForumTag], tag_ids: &[String]) -> Result<Vec<String>, String> {
+    let mut checked: Vec<String> = Vec::new();
+    for id in tag_ids {
+        if !forum_tags.iter().any(|t| t.id == *id) {
+            return Err(format!("this forum has no tag {}", id));
+        }
+        if !checked.contains(id) {
+            checked.push(id.clone());
+        }
+    }
+    if checked.len() > MAX_THREAD_TAGS {
+        return Err(format!("a thread can have at most {} tags", MAX_THREAD_TAGS));
+    }
+    Ok(checked)
+}
+
+fn check_forum_tags(tags: &[ForumTag]) -> Result<(), String> {
+    if tags.len() > MAX_FORUM_TAGS {
+        return Err(format!("a forum can have at most {} tags", MAX_FORUM_TAGS));
+    }
+    for (i, tag) in tags.iter().enumerate() {
+        if tag.id.trim().is_empty() {
+            return Err("every tag needs an id".to_string());
+        }
+        let name_len = tag.name.trim().chars().count();
+        if name_len == 0 || name_len > MAX_TAG_NAME_LEN {
+            return Err(format!("tag names are 1 to {} characters", MAX_TAG_NAME_LEN));
+        }
+        if tags[..i].iter().any(|t| t.id == tag.id) {
+            return Err(format!("tag id {} is used twice", tag.id));
+        }
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/forum/tags",
+    tag = "servers",
+    params(ForumTagsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ForumTagsResponse), super::ErrorResponses)
+)]
+async fn get_forum_tags(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<ForumTagsQuery>,
+) -> Result<Json<ForumTagsResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    let room_state = matrix.get_room_state(params.forum_channel_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    Ok(Json(ForumTagsResponse { tags: tags_of(&room_state), forum_channel_id: params.forum_channel_id }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/forum/tags",
+    tag = "servers",
+    request_body = SetForumTagsRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ForumTagsResponse), super::ErrorResponses)
+)]
+async fn set_forum_tags(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetForumTagsRequest>,
+) -> Result<Json<ForumTagsResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission_in(&matrix, &req.forum_channel_id, Permission::ManageChannels)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let tags: Vec<ForumTag> = req.tags.into_iter()
+        .map(|t| ForumTag { id: t.id.trim().to_string(), name: t.name.trim().to_string(), color: t.color })
+        .collect();
+    check_forum_tags(&tags).map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?;
+
+    let room_state = matrix.get_room_state(req.forum_channel_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if !is_forum(&room_state) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "that room isn't a forum channel"));
+    }
+    // anything else in the meta stays as it was
+    let mut meta = forum_meta(&room_state).cloned().unwrap_or_else(|| serde_json::json!({}));
+    meta["tags"] = serde_json::json!(tags);
+    matrix.send_state_event(req.forum_channel_id.clone(), "agora.forum.meta".to_string(), "".to_string(), meta)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    Ok(Json(ForumTagsResponse { forum_channel_id: req.forum_channel_id, tags }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/forum/thread/tags",
+    tag = "servers",
+    request_body = SetThreadTagsRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ThreadTagsResponse), super::ErrorResponses)
+)]
+async fn set_thread_tags(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetThreadTagsRequest>,
+) -> Result<Json<ThreadTagsResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let user_id = auth.user_id;
+    let thread_state = matrix.get_room_state(req.thread_room_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let forum_id = forum_of(&thread_state)
+        .ok_or_else(|| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "that room isn't a forum thread"))?;
+    let mut meta = thread_state.iter()
+        .find(|e| e.event_type == "agora.thread.meta" && e.state_key.as_deref() == Some(""))
+        .map(|e| e.content.clone())
+        .unwrap_or_else(|| serde_json::json!({}));
+
+    if meta["author"].as_str() != Some(user_id.as_str()) {
+        authz::require_permission_in(&matrix, &req.thread_room_id, Permission::ManageMessages)
+            .await
+            .map_err(|e| authz_error(&e))?;
+    }
+
+    let forum_state = matrix.get_room_state(forum_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    let tags = check_thread_tags(&tags_of(&forum_state), &req.tag_ids).map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?;
+    meta["tags"] = serde_json::json!(tags);
+    matrix.send_state_event(req.thread_room_id.clone(), "agora.thread.meta".to_string(), "".to_string(), meta)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    room_summaries::invalidate(&state, std::slice::from_ref(&req.thread_room_id)).await;
+
+    Ok(Json(ThreadTagsResponse { room_id: req.thread_room_id, tags }))
+}
+
+// ── deleting a server ─────────────────────────────────────────────────────────
+// matrix has no room deletion, so a server is deleted by emptying it: the space
+// is marked with agora.server.deleted, then every room under it is closed to
+// joins, cleared of its members and aliases, and left by the owner. on a
+// single-homeserver deployment nobody can get back in, and the homeserver
+// purges rooms once the last member has left. invite codes and indexed messages
+// go with it; the moderation audit log is kept, and ages out under its own
+// retention like every other server's.
+
+const SERVER_DELETED_EVENT_TYPE: &str = "agora.server.deleted";
+// kicks in flight at once while emptying a room
+const DELETE_KICK_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteServerRequest {
+    pub server_id: String,
+    /// the server's name, typed out by the owner — a guard against deleting the wrong one
+    pub confirm_name: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeletedRoom {
+    pub room_id: String,
+    pub members_removed: usize,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errcode: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeleteServerReport {
+    /// every room under the server, deepest first and the space last
+    pub rooms: Vec<DeletedRoom>,
+    pub members_removed: usize,
+    pub aliases_removed: Vec<String>,
+    /// false when some room couldn't be emptied — the owner isn't in it, or the homeserver refused
+    pub complete: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/delete",
+    tag = "servers",
+    request_body = DeleteServerRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = DeleteServerReport), super::ErrorResponses)
+)]
+async fn delete_server(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<DeleteServerRequest>,
+) -> Result<Json<DeleteServerReport>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let owner = auth.user_id;
+    let server_state = matrix
+        .get_room_state(req.server_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    // the owner, not an administrator — only power 100 can undo what the others do
+    if authz::ServerAccess::from_state(&server_state, owner.clone(), &req.server_id).level < 100 {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "only the server's owner can delete it"));
+    }
+    let name = state_content(&server_state, "m.room.name")["name"].as_str().unwrap_or_default().to_string();
+    if req.confirm_name.trim() != name.trim() {
+        return Err(agora_error(
+            StatusCode::BAD_REQUEST,
+            "AGORA_CONFIRMATION_MISMATCH",
+            "type the server's name to delete it",
+        ));
+    }
+
+    let deleted = serde_json::json!({ "deleted_by": owner, "deleted_at": chrono::Utc::now().timestamp_millis() });
+    matrix
+        .send_state_event(req.server_id.clone(), SERVER_DELETED_EVENT_TYPE.to_string(), String::new(), deleted)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    let vanity_alias = serde_json::from_value::<ServerMeta>(state_content(&server_state, "agora.server.meta"))
+        .ok()
+        .and_then(|meta| meta.vanity_slug)
+        .map(|slug| format!("#{}:{}", slug, state.server_name));
+    let nodes = hierarchy::walk_space(&matrix, &req.server_id, hierarchy::max_depth()).await;
+    let mut rooms = Vec::new();
+    let mut aliases_removed = Vec::new();
+
+    let order = nodes.iter().skip(1).rev().chain(nodes.first());
+    for node in order {
+        let mut aliases = room_aliases(&node.state);
+        if node.parent_id.is_none() {
+            aliases.extend(vanity_alias.clone());
+        }
+        let (outcome, removed) = empty_room(&matrix, &owner, &node.room_id, &node.state, aliases, "server deleted").await;
+        aliases_removed.extend(removed);
+        rooms.push(outcome);
+    }
+
+    let room_ids: Vec<String> = rooms.iter().map(|r| r.room_id.clone()).collect();
+    forget_server_rows(&state, &req.server_id, &room_ids).await;
+    room_summaries::invalidate(&state, &room_ids).await;
+    invalidate_thread_stats(&state, &room_ids).await;
+
+    let members_removed = rooms.iter().map(|r| r.members_removed).sum();
+    let complete = rooms.iter().all(|r| r.ok);
+    Ok(Json(DeleteServerReport { rooms, members_removed, aliases_removed, complete }))
+}
+
+/// the content of a room's `event_type` state (empty state key), or null
+fn state_content(room_state: &[RoomStateEvent], event_type: &str) -> serde_json::Value {
+    room_state
+        .iter()
+        .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+        .map(|e| e.content.clone())
+        .unwrap_or_default()
+}
+
+/// the aliases a room publishes in m.room.canonical_alias
+pub(crate) fn room_aliases(room_state: &[RoomStateEvent]) -> Vec<String> {
+    let canonical = state_content(room_state, "m.room.canonical_alias");
+    let alt = canonical["alt_aliases"].as_array().into_iter().flatten();
+    std::iter::once(&canonical["alias"]).chain(alt).filter_map(|a| a.as_str()).map(str::to_string).collect()
+}
+
+/// close a room to joins, remove everyone else and its aliases, then leave and
+/// forget it. returns how it went and the aliases that were removed
+pub(crate) async fn empty_room(
+    matrix: &MatrixClient,
+    owner: &str,
+    room_id: &str,
+    room_state: &[RoomStateEvent],
+    aliases: Vec<String>,
+    reason: &str,
+) -> (DeletedRoom, Vec<String>) {
+    let mut first_error: Option<MatrixError> = None;
+
+    let closed = serde_json::json!({ "join_rule": "invite" });
+    if let Err(e) = matrix.send_state_event(room_id.to_string(), "m.room.join_rules".to_string(), String::new(), closed).await {
+        first_error.get_or_insert(e);
+    }
+
+    let members: Vec<String> = room_state
+        .iter()
+        .filter(|e| e.event_type == "m.room.member" && matches!(e.content["membership"].as_str(), Some("join" | "invite")))
+        .filter_map(|e| e.state_key.clone())
+        .filter(|user_id| user_id != owner)
+        .collect();
+    let kicks: Vec<Result<(), MatrixError>> = stream::iter(members)
+        .map(|user_id| matrix.kick_user(room_id.to_string(), user_id, Some(reason.to_string())))
+        .buffered(DELETE_KICK_CONCURRENCY)
+        .collect()
+        .await;
+    let members_removed = kicks.iter().filter(|k| k.is_ok()).count();
+    if let Some(e) = kicks.into_iter().find_map(Result::err) {
+        first_error.get_or_insert(e);
+    }
+
+    let mut removed = Vec::new();
+    for alias in aliases {
+        match matrix.delete_room_alias(alias.clone()).await {
+            Ok(()) => removed.push(alias),
+            // gone already, or another room's by now
+            Err(e) => tracing::warn!("failed to remove alias {} of {}: {}", alias, room_id, e),
+        }
+    }
+
+    if let Err(e) = matrix.leave_room(room_id.to_string()).await {
+        first_error.get_or_insert(e);
+    } else if let Err(e) = matrix.forget_room(room_id.to_string()).await {
+        tracing::warn!("failed to forget {} after leaving: {}", room_id, e);
+    }
+
+    if let Some(e) = &first_error {
+        tracing::warn!("deleting {} was incomplete: {}", room_id, e);
+    }
+    let errcode = first_error.map(|e| e.errcode().unwrap_or("M_UNKNOWN").to_string());
+    (DeletedRoom { room_id: room_id.to_string(), members_removed, ok: errcode.is_none(), errcode }, removed)
+}
+
+/// drop the database rows that only mean something while the server exists
+async fn forget_server_rows(state: &AppState, server_id: &str, room_ids: &[String]) {
+    let Some(pool) = state.db_pool.as_ref() else { return };
+    let invites = sqlx::query("DELETE FROM server_invites WHERE server_id = $1").bind(server_id).execute(pool).await;
+    if let Err(e) = invites {
+        tracing::error!("failed to delete invites of {}: {}", server_id, e);
+    }
+    let webhooks = sqlx::query("DELETE FROM webhooks WHERE server_id = $1").bind(server_id).execute(pool).await;
+    if let Err(e) = webhooks {
+        tracing::error!("failed to delete webhooks of {}: {}", server_id, e);
+    }
+    forget_room_rows(state, room_ids).await;
+}
+
+/// the same for rooms deleted on their own: webhooks, indexed messages and
+/// announcement follows
+pub(crate) async fn forget_room_rows(state: &AppState, room_ids: &[String]) {
+    let Some(pool) = state.db_pool.as_ref() else { return };
+    let webhooks = sqlx::query("DELETE FROM webhooks WHERE room_id = ANY($1)").bind(room_ids).execute(pool).await;
+    if let Err(e) = webhooks {
+        tracing::error!("failed to delete webhooks of {:?}: {}", room_ids, e);
+    }
+    let messages = sqlx::query("DELETE FROM messages WHERE room_id = ANY($1)").bind(room_ids).execute(pool).await;
+    if let Err(e) = messages {
+        tracing::error!("failed to delete indexed messages of {:?}: {}", room_ids, e);
+    }
+    let follows = sqlx::query("DELETE FROM announcement_follows WHERE source_room_id = ANY($1) OR target_room_id = ANY($1)")
+        .bind(room_ids)
+        .execute(pool)
+        .await;
+    if let Err(e) = follows {
+        tracing::error!("failed to delete announcement follows of {:?}: {}", room_ids, e);
+    }
+}
+
 // ── invite info ───────────────────────────────────────────────────────────────
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct InviteQuery {
-    pub access_token: String,
     pub server_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct InviteInfo {
     /// the Matrix room alias that can be shared
     pub alias: String,
@@ -493,12 +2668,21 @@ pub struct InviteInfo {
     pub member_count: u64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/servers/invite",
+    tag = "servers",
+    params(InviteQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = InviteInfo), super::StatusErrors)
+)]
 async fn get_invite_info(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<InviteQuery>,
 ) -> Result<Json<InviteInfo>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token.clone());
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
 
     let room_state = matrix.get_room_state(params.server_id.clone()).await
         .map_err(|_| StatusCode::BAD_REQUEST)?;
@@ -520,8 +2704,8 @@ async fn get_invite_info(
 
     // read vanity slug from agora meta
     let meta_url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.server.meta/",
-        state.homeserver_url, url_encode(&params.server_id)
+        "{}/rooms/{}/state/agora.server.meta/",
+        matrix.client_api_base().await, encode_path_segment(&params.server_id)
     );
     let vanity_slug = matrix.get_raw(&meta_url).await.ok()
         .and_then(|v| v["vanity_slug"].as_str().map(String::from));
@@ -529,15 +2713,268 @@ async fn get_invite_info(
     Ok(Json(InviteInfo { alias, vanity_slug, server_name, member_count }))
 }
 
+// ── public preview ────────────────────────────────────────────────────────────
+// what an invite link shows before joining. the link's code, alias or vanity
+// slug resolves to the server; /hierarchy gives its name, icon and member
+// count to anyone it's public to. servers whose history is world_readable also
+// show their channels, welcome screen, banner and the last few messages of the
+// first welcome channel (or the first channel). the caller's own token is used
+// when there is one, otherwise a guest session.
+
+const PREVIEW_MESSAGES: u32 = 10;
+const PREVIEW_MAX_CHANNELS: u32 = 100;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PreviewQuery {
+    /// an invite code, a vanity slug, or a #alias:server
+    pub code_or_alias: String,
+    /// optional — previews work logged out
+    pub access_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Default, ToSchema)]
+pub struct ServerPreview {
+    pub server_id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub icon_url: Option<String>,
+    pub member_count: i64,
+    /// whether the fields below were readable — false leaves them empty
+    pub world_readable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub welcome: Option<WelcomeScreen>,
+    pub channels: Vec<PreviewChannel>,
+    /// the channel `messages` are from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_channel_id: Option<String>,
+    /// oldest first
+    pub messages: Vec<PreviewMessage>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PreviewChannel {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub is_category: bool,
+    /// the category it's under, or the server
+    pub parent_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PreviewMessage {
+    pub event_id: Option<String>,
+    pub sender: String,
+    pub body: String,
+    pub msgtype: String,
+    pub origin_server_ts: Option<i64>,
+}
+
+/// the server an invite code, vanity slug, alias or room id points at — None when nothing does
+async fn resolve_preview_target(state: &AppState, matrix: &MatrixClient, code_or_alias: &str) -> Option<String> {
+    let code_or_alias = code_or_alias.trim();
+    if code_or_alias.starts_with('!') {
+        return Some(code_or_alias.to_string());
+    }
+    if !code_or_alias.starts_with('#') {
+        if let Some(pool) = state.db_pool.as_ref() {
+            let server_id = sqlx::query_scalar::<_, String>(
+                r#"
+                SELECT server_id FROM server_invites
+                WHERE code = $1 AND revoked_at IS NULL
+                  AND (expires_at IS NULL OR expires_at > NOW())
+                  AND (max_uses IS NULL OR uses < max_uses)
+                "#,
+            )
+            .bind(code_or_alias)
+            .fetch_optional(pool)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("failed to look up invite code: {}", e);
+                None
+            });
+            if server_id.is_some() {
+                return server_id;
+            }
+        }
+    }
+    let alias = match code_or_alias.strip_prefix('#') {
+        Some(_) => code_or_alias.to_string(),
+        None => format!("#{}:{}", clean_vanity_slug(code_or_alias)?, state.server_name),
+    };
+    match matrix.resolve_alias(&alias).await {
+        Ok(resolved) => Some(resolved.room_id),
+        Err(e) => {
+            tracing::debug!("cannot resolve {}: {}", alias, e);
+            None
+        }
+    }
+}
+
+/// the caller's session, or the shared guest one — None when the homeserver has no guests
+async fn preview_client(state: &AppState, access_token: Option<String>) -> Option<MatrixClient> {
+    let mut matrix = state.matrix();
+    if let Some(token) = access_token.filter(|t| !t.is_empty()) {
+        matrix.access_token = Some(token);
+        return Some(matrix);
+    }
+    if let Some(guest) = state.guest_client.read().await.clone() {
+        return Some(guest);
+    }
+    let mut guest_client = state.guest_client.write().await;
+    if guest_client.is_none() {
+        match matrix.register_guest().await {
+            Ok(guest) => {
+                matrix.access_token = Some(guest.access_token);
+                *guest_client = Some(matrix);
+            }
+            Err(e) => tracing::debug!("no guest session for previews: {}", e),
+        }
+    }
+    guest_client.clone()
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/preview",
+    tag = "servers",
+    params(PreviewQuery),
+    responses((status = 200, body = ServerPreview), super::ErrorResponses)
+)]
+async fn preview_server(
+    state: State<Arc<AppState>>,
+    Query(params): Query<PreviewQuery>,
+) -> Result<Json<ServerPreview>, Response> {
+    let not_found = || agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "this invite is invalid or has expired");
+    let caller_token = params.access_token.is_some();
+    let Some(matrix) = preview_client(&state, params.access_token).await else {
+        return Err(agora_error(StatusCode::SERVICE_UNAVAILABLE, "M_GUEST_ACCESS_FORBIDDEN", "log in to preview servers"));
+    };
+    let Some(server_id) = resolve_preview_target(&state, &matrix, &params.code_or_alias).await else {
+        return Err(not_found());
+    };
+
+    // the room summary is there for anyone the server is public to
+    let hierarchy = match matrix.get_space_hierarchy(&server_id, PREVIEW_MAX_CHANNELS, None, hierarchy::MAX_SPACE_NESTING).await {
+        Ok(page) => page.rooms,
+        Err(e) => {
+            if !caller_token && e.errcode() == Some("M_UNKNOWN_TOKEN") {
+                // the guest session expired — the next preview gets a new one
+                *state.guest_client.write().await = None;
+            }
+            tracing::debug!("no summary of {}: {}", server_id, e);
+            return Err(not_found());
+        }
+    };
+    let Some(root) = hierarchy.iter().find(|room| room.room_id == server_id) else {
+        return Err(not_found());
+    };
+    let mut preview = ServerPreview {
+        server_id: server_id.clone(),
+        name: root.name.clone(),
+        topic: root.topic.clone(),
+        icon_url: root.avatar_url.clone(),
+        member_count: root.num_joined_members,
+        ..Default::default()
+    };
+
+    let server_state = matrix.get_room_state(server_id.clone()).await.unwrap_or_default();
+    let world_readable = server_state.iter().any(|e| {
+        e.event_type == "m.room.history_visibility" && e.content["history_visibility"] == "world_readable"
+    });
+    if !world_readable {
+        return Ok(Json(preview));
+    }
+    preview.world_readable = true;
+
+    let find = |event_type: &str| {
+        server_state
+            .iter()
+            .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+            .map(|e| e.content.clone())
+    };
+    let meta: ServerMeta = find("agora.server.meta").and_then(|c| serde_json::from_value(c).ok()).unwrap_or_default();
+    preview.icon_url = meta.icon_url.or(preview.icon_url);
+    preview.banner_url = meta.banner_url;
+    preview.description = meta.description;
+    preview.welcome = find("agora.server.welcome").and_then(|c| serde_json::from_value(c).ok());
+
+    for room in hierarchy.iter().filter(|room| room.room_id != server_id) {
+        let parent_id = hierarchy
+            .iter()
+            .find(|parent| parent.children_state.iter().any(|child| child.state_key == room.room_id))
+            .map(|parent| parent.room_id.clone())
+            .unwrap_or_else(|| server_id.clone());
+        preview.channels.push(PreviewChannel {
+            room_id: room.room_id.clone(),
+            name: room.name.clone(),
+            topic: room.topic.clone(),
+            is_category: room.room_type.as_deref() == Some("m.space"),
+            parent_id,
+        });
+    }
+
+    let welcome_channel = preview
+        .welcome
+        .as_ref()
+        .and_then(|w| w.welcome_channels.iter().map(|c| c.room_id.clone()).find(|id| preview.channels.iter().any(|c| c.room_id == *id)));
+    let default_channel_id = welcome_channel.or_else(|| preview.channels.iter().find(|c| !c.is_category).map(|c| c.room_id.clone()));
+    if let Some(channel_id) = &default_channel_id {
+        match matrix.get_messages(channel_id, None, Direction::Backward, PREVIEW_MESSAGES).await {
+            Ok(page) => {
+                preview.messages = page
+                    .chunk
+                    .into_iter()
+                    .rev()
+                    .filter(|e| e.event_type == "m.room.message")
+                    .filter_map(|e| {
+                        let msgtype = e.content.get("msgtype")?.as_str()?.to_string();
+                        let body = e.content.get("body")?.as_str()?.to_string();
+                        Some(PreviewMessage { event_id: e.event_id, sender: e.sender, body, msgtype, origin_server_ts: e.origin_server_ts })
+                    })
+                    .collect();
+            }
+            Err(e) => tracing::debug!("no preview messages from {}: {}", channel_id, e),
+        }
+    }
+    preview.default_channel_id = default_channel_id;
+    Ok(Json(preview))
+}
+
 // ── helpers ───────────────────────────────────────────────────────────────────
 
-fn url_encode(s: &str) -> String {
-    s.chars().map(|c| match c {
-        '!' => "%21".to_string(),
-        ':' => "%3A".to_string(),
-        '.' => "%2E".to_string(),
-        '#' => "%23".to_string(),
-        '@' => "%40".to_string(),
-        _ => c.to_string(),
-    }).collect()
+/// 409 for a stale revision — `current` holds the up-to-date document so the
+/// client can merge its edit and retry with the returned revision
+fn revision_conflict(revision: u64, current: serde_json::Value) -> Response {
+    let mut body = serde_json::json!({
+        "errcode": "AGORA_REVISION_CONFLICT",
+        "error": "this was changed by someone else since you loaded it",
+        "revision": revision,
+    });
+    if let (Some(body), serde_json::Value::Object(current)) = (body.as_object_mut(), current) {
+        body.extend(current);
+    }
+    (StatusCode::CONFLICT, Json(body)).into_response()
 }
+
+/// the caller's power level in the server room — None when it can't be read
+async fn server_power(matrix: &MatrixClient, server_id: &str) -> Option<i64> {
+    let user_id = matrix.whoami().await.ok()?.user_id;
+    let power = matrix.get_power_levels(server_id.to_string()).await.ok()?;
+    let level = power.users.as_ref()
+        .and_then(|users| users.get(&user_id).copied())
+        .or(power.users_default)
+        .unwrap_or(0);
+    Some(level)
+}
+
+/// true when the caller has power level 100 in the server room
+async fn is_server_admin(matrix: &MatrixClient, server_id: &str) -> bool {
+    server_power(matrix, server_id).await.is_some_and(|level| level >= 100)
+}
+
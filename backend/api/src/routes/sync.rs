@@ -1,85 +1,659 @@
 use axum::{
     extract::{Query, State},
     http::StatusCode,
+    response::Response,
     Json,
     routing::get,
     Router,
 };
+use futures_util::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use crate::app_state::AppState;
-use crate::matrix::client::MatrixClient;
+use crate::content::{self, Mentions};
+use crate::notification_levels::NotificationLevels;
+use crate::room_summaries;
+use super::{friends, servers, users};
+use super::rooms::RaidSignal;
+use super::voice::CallSignal;
+use crate::matrix::client::{Direction, Event, InvitedRoom, MatrixClient, SyncFilter};
+use crate::matrix::message_policy::{MessagePolicy, PolicyLoader};
+use crate::session::AuthUser;
+use super::matrix_error;
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/sync", get(sync))
+        .route("/sync/room", get(sync_room))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(OpenApi)]
+#[openapi(paths(sync, sync_room))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SyncQuery {
-    pub access_token: String,
     pub since: Option<String>,
+    /// BCP-47 tag — attach translated_body to messages when translation is configured
+    pub translate_to: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RoomSyncQuery {
+    pub room_id: String,
+    pub since: Option<String>,
+    /// most recent events to return when there are more (default 50, max 100)
+    pub limit: Option<u32>,
+    pub translate_to: Option<String>,
+}
+
+/// one room's new events, in the same shapes as /sync
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RoomSyncResponse {
+    pub room_id: String,
+    pub next_batch: String,
+    /// /rooms/messages token for what came before these events; null when
+    /// the room had nothing new
+    pub prev_batch: Option<String>,
+    /// events between `since` and these were skipped — load them through
+    /// /rooms/messages from prev_batch
+    pub limited: bool,
+    pub messages: Vec<Message>,
+    pub calls: Vec<CallEvent>,
+    pub raids: Vec<RaidEvent>,
+    pub events: Vec<CustomEvent>,
+    pub redacted: Vec<Redacted>,
+    pub reactions: Vec<Reaction>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct SyncResponse {
     pub next_batch: String,
+    /// everything shown in the message list: text, formatted text, attachments
     pub messages: Vec<Message>,
+    /// call signaling (msgtype agora.call) — rings, accepts, hangups
+    pub calls: Vec<CallEvent>,
+    /// raid alerts (msgtype agora.raid)
+    pub raids: Vec<RaidEvent>,
+    /// other agora.* timeline events (voice moderation, moves, ...), as sent
+    pub events: Vec<CustomEvent>,
+    /// rooms the user has been invited to since the last sync — accept or
+    /// reject with /rooms/invite/accept and /rooms/invite/reject
+    pub invites: Vec<Invite>,
+    /// rooms the user left, was kicked or banned from, or declined since the last sync
+    pub left_rooms: Vec<String>,
+    /// messages deleted since the last sync — drop them from the view
+    pub redacted: Vec<Redacted>,
+    /// reactions added since the last sync; a removed one shows up in `redacted`
+    pub reactions: Vec<Reaction>,
+    /// rooms where more was skipped than sync backfills — page
+    /// /rooms/messages back from prev_batch for the rest
+    pub gaps: Vec<Gap>,
+    /// the user is on do-not-disturb: messages still arrive, but clients
+    /// should skip sounds and badge flashes
+    pub suppress_notifications: bool,
+}
+
+/// a room whose timeline came back limited, with events still missing
+/// before the ones in this sync
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Gap {
+    pub room_id: String,
+    /// /rooms/messages `from` token, going backwards
+    pub prev_batch: String,
+    /// always true, as in the matrix timeline
+    pub limited: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Reaction {
+    pub room_id: String,
+    /// the reaction event itself
+    pub event_id: Option<String>,
+    /// the message reacted to
+    pub target_event_id: String,
+    pub key: String,
+    pub sender: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CallEvent {
+    pub room_id: String,
+    pub event_id: Option<String>,
+    pub sender: String,
+    pub timestamp: Option<i64>,
+    #[serde(flatten)]
+    pub call: CallSignal,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RaidEvent {
+    pub room_id: String,
+    pub event_id: Option<String>,
+    pub sender: String,
+    pub timestamp: Option<i64>,
+    #[serde(flatten)]
+    pub raid: RaidSignal,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CustomEvent {
+    pub room_id: String,
+    pub event_id: Option<String>,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub sender: String,
+    pub timestamp: Option<i64>,
+    #[schema(value_type = Object)]
+    pub content: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Invite {
+    pub room_id: String,
+    pub inviter: Option<String>,
+    pub name: Option<String>,
+    /// mxc:// uri from m.room.avatar
+    pub avatar_url: Option<String>,
+    /// the inviter marked it as a direct message
+    pub is_direct: bool,
+    /// an invite to a whole server rather than a single room
+    pub is_space: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Redacted {
+    pub room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct Message {
     pub room_id: String,
     pub sender: String,
     pub content: String,
+    /// sanitized html for formatted (markdown / rich text) messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_body: Option<String>,
     pub timestamp: Option<i64>,
     pub event_id: Option<String>,
+    /// m.text, or m.image / m.file / m.video / m.audio for attachments (or an agora.* type)
+    pub msgtype: String,
+    /// the attachment's mxc:// uri and its info block (mimetype, size, w, h, duration)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub info: Option<serde_json::Value>,
+    /// machine translation of content, only when translate_to was requested and it succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translated_body: Option<String>,
+    /// whether the requesting user may edit / delete this message right now,
+    /// so the ui can hide the buttons instead of failing on click
+    pub editable: bool,
+    pub deletable: bool,
+    /// the requesting user is pinged, by name or @everyone — for highlights and unread counts
+    pub mentions_me: bool,
+    /// counts towards unread badges and sounds, by the viewer's notification
+    /// level for the room: never in a muted one, only mentions in a "mentions" one
+    pub notify: bool,
+    /// the message's m.mentions, when its sender's client set one
+    #[serde(skip)]
+    pub mentions: Option<Mentions>,
+    /// call signaling (msgtype agora.call): call_id, action, from, participants, ...
+    #[serde(flatten)]
+    pub call: Option<CallSignal>,
+    /// the event's content as sent, for anything the fields above leave out
+    #[schema(value_type = Object)]
+    pub raw_content: serde_json::Value,
 }
 
+#[utoipa::path(
+    get,
+    path = "/sync",
+    tag = "sync",
+    params(SyncQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = SyncResponse), super::ErrorResponses)
+)]
 async fn sync(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<SyncQuery>,
-) -> Result<Json<SyncResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+) -> Result<Json<SyncResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
     
-    match matrix.sync(params.since).await {
+    let viewer = SyncViewer::for_user(&state, auth.user_id).await;
+    match matrix.sync(params.since.clone()).await {
         Ok(response) => {
-            let mut messages = Vec::new();
-            
-            if let Some(rooms) = response.rooms {
-                if let Some(join) = rooms.join {
-                    for (room_id, room) in join {
-                        if let Some(timeline) = room.timeline {
-                            for event in timeline.events {
-                                if event.event_type == "m.room.message" {
-                                    let content = event.content.get("body")
-                                        .and_then(|v| v.as_str())
-                                        .unwrap_or("")
-                                        .to_string();
-                                    
-                                    messages.push(Message {
-                                        room_id: room_id.clone(),
-                                        sender: event.sender,
-                                        content,
-                                        timestamp: event.origin_server_ts,
-                                        event_id: event.event_id.clone(),
-                                    });
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            
-            Ok(Json(SyncResponse {
-                next_batch: response.next_batch,
-                messages,
-            }))
+            let (since, translate_to) = (params.since.as_deref(), params.translate_to.as_deref());
+            Ok(Json(build_response(&state, &matrix, &viewer, response, since, translate_to).await))
         }
         Err(e) => {
             tracing::error!("sync failed: {}", e);
-            Err(StatusCode::UNAUTHORIZED)
+            Err(matrix_error(&e, StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+// events fetched back per limited room — past that, a gap is reported
+const BACKFILL_LIMIT: u32 = 100;
+// limited rooms backfilled per sync; the others are reported as gaps
+const MAX_BACKFILL_ROOMS: usize = 10;
+const BACKFILL_CONCURRENCY: usize = 4;
+
+/// who a sync is for — block lists, do-not-disturb and notification levels
+/// live outside matrix
+#[derive(Debug, Default)]
+pub struct SyncViewer {
+    pub user_id: Option<String>,
+    /// senders whose messages, events and invites are left out
+    pub blocked: HashSet<String>,
+    pub suppress_notifications: bool,
+    pub notification_levels: NotificationLevels,
+}
+
+impl SyncViewer {
+    pub async fn for_user(state: &AppState, user_id: String) -> Self {
+        SyncViewer {
+            blocked: blocked_senders(state, &user_id).await,
+            suppress_notifications: users::stored_presence(state, &user_id).await.as_deref() == Some("dnd"),
+            notification_levels: NotificationLevels::load(state, &user_id).await,
+            user_id: Some(user_id),
+        }
+    }
+}
+
+/// the homeserver's sync, sorted into ours. the event socket sends the same
+/// thing frame by frame. on an incremental sync, limited rooms get the events
+/// skipped since `since` filled in from /messages, as far as the caps allow —
+/// a first sync is limited everywhere, and history is /rooms/messages' job.
+pub async fn build_response(
+    state: &AppState,
+    matrix: &MatrixClient,
+    viewer: &SyncViewer,
+    response: crate::matrix::client::SyncResponse,
+    since: Option<&str>,
+    translate_to: Option<&str>,
+) -> SyncResponse {
+    let rooms = response.rooms.unwrap_or_default();
+    let mut joined: Vec<(String, Vec<Event>)> = Vec::new();
+    let mut limited: Vec<(usize, String)> = Vec::new();
+    let mut gaps: Vec<Gap> = Vec::new();
+    for (room_id, room) in rooms.join.unwrap_or_default() {
+        let Some(room_timeline) = room.timeline else { continue };
+        if let (Some(_), true, Some(prev_batch)) = (since, room_timeline.limited, room_timeline.prev_batch) {
+            if limited.len() < MAX_BACKFILL_ROOMS {
+                limited.push((joined.len(), prev_batch));
+            } else {
+                gaps.push(Gap { room_id: room_id.clone(), prev_batch, limited: true });
+            }
+        }
+        joined.push((room_id, room_timeline.events));
+    }
+    let room_ids: Vec<String> = joined.iter().map(|(room_id, _)| room_id.clone()).collect();
+    let pages: Vec<_> = stream::iter(limited)
+        .map(|(index, prev_batch)| {
+            let room_id = &room_ids[index];
+            async move {
+                let page = matrix
+                    .get_messages_until(room_id, Some(&prev_batch), since, Direction::Backward, BACKFILL_LIMIT)
+                    .await;
+                (index, prev_batch, page)
+            }
+        })
+        .buffer_unordered(BACKFILL_CONCURRENCY)
+        .collect()
+        .await;
+    for (index, prev_batch, page) in pages {
+        let (room_id, events) = &mut joined[index];
+        match page {
+            Ok(page) => {
+                // the page is newest first; it goes in front, oldest first
+                let mut backfilled = page.chunk;
+                backfilled.reverse();
+                backfilled.append(events);
+                *events = backfilled;
+                if let Some(end) = page.end {
+                    gaps.push(Gap { room_id: room_id.clone(), prev_batch: end, limited: true });
+                }
+            }
+            Err(e) => {
+                tracing::warn!("backfill for {} failed: {}", room_id, e);
+                gaps.push(Gap { room_id: room_id.clone(), prev_batch, limited: true });
+            }
+        }
+    }
+    gaps.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+
+    // forum threads count their replies off the timeline; new ones make that stale
+    let messaged: Vec<String> = joined
+        .iter()
+        .filter(|(_, events)| events.iter().any(|e| e.event_type == "m.room.message"))
+        .map(|(room_id, _)| room_id.clone())
+        .collect();
+    servers::invalidate_thread_stats(state, &messaged).await;
+    // rooms list previews show the newest message that's still there
+    let previewed: Vec<String> = joined
+        .iter()
+        .filter(|(_, events)| events.iter().any(|e| e.event_type == "m.room.message" || e.event_type == "m.room.redaction"))
+        .map(|(room_id, _)| room_id.clone())
+        .collect();
+    room_summaries::invalidate_previews(state, &previewed).await;
+    // and listings cache channel and thread state
+    let restated: Vec<String> = joined
+        .iter()
+        .filter(|(_, events)| events.iter().any(|e| e.state_key.is_some() && e.event_type != "m.room.member"))
+        .map(|(room_id, _)| room_id.clone())
+        .collect();
+    room_summaries::invalidate(state, &restated).await;
+
+    let mut timeline = Timeline { blocked: viewer.blocked.clone(), ..Timeline::default() };
+    for (room_id, events) in joined {
+        for event in events {
+            timeline.push(&room_id, event);
+        }
+    }
+    let mut invites: Vec<Invite> = rooms
+        .invite
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(room_id, room)| invite(room_id, room, viewer.user_id.as_deref()))
+        // an invite from someone blocked stays pending, unseen
+        .filter(|invite| !invite.inviter.as_ref().is_some_and(|i| viewer.blocked.contains(i)))
+        .collect();
+    invites.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+    let mut left_rooms: Vec<String> = rooms.leave.unwrap_or_default().into_keys().collect();
+    left_rooms.sort();
+    let Timeline { mut messages, calls, raids, events, redacted, reactions, .. } = timeline;
+
+    attach_viewer_flags(matrix, messages.iter_mut()).await;
+    let mut messaged_rooms: Vec<String> = messages.iter().map(|m| m.room_id.clone()).collect();
+    messaged_rooms.sort();
+    messaged_rooms.dedup();
+    let levels = viewer.notification_levels.resolve(state, matrix, &messaged_rooms).await;
+    for message in messages.iter_mut() {
+        message.notify = levels.get(&message.room_id).is_none_or(|level| level.notifies(message.mentions_me));
+    }
+
+    if let (Some(lang), Some(access_token)) = (translate_to, matrix.access_token.as_deref()) {
+        attach_translations(state, access_token, lang, &mut messages).await;
+    }
+
+    SyncResponse {
+        next_batch: response.next_batch,
+        messages,
+        calls,
+        raids,
+        events,
+        invites,
+        left_rooms,
+        redacted,
+        reactions,
+        gaps,
+        suppress_notifications: viewer.suppress_notifications,
+    }
+}
+
+const DEFAULT_ROOM_LIMIT: u32 = 50;
+const MAX_ROOM_LIMIT: u32 = 100;
+
+/// new events for the channel that's open. the filter keeps other rooms,
+/// presence and account data out of the homeserver's answer, so this stays
+/// quick however many servers the user is in; it also doesn't long-poll.
+#[utoipa::path(
+    get,
+    path = "/sync/room",
+    tag = "sync",
+    params(RoomSyncQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomSyncResponse), super::ErrorResponses)
+)]
+async fn sync_room(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<RoomSyncQuery>,
+) -> Result<Json<RoomSyncResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let blocked = SyncViewer::for_user(&state, auth.user_id).await.blocked;
+    let limit = params.limit.unwrap_or(DEFAULT_ROOM_LIMIT).clamp(1, MAX_ROOM_LIMIT);
+    let filter = SyncFilter::room_timeline(&params.room_id, limit);
+    let response = matrix
+        .sync_with_filter(params.since, Some(&filter), 0)
+        .await
+        .map_err(|e| {
+            tracing::error!("room sync failed: {}", e);
+            matrix_error(&e, StatusCode::BAD_GATEWAY)
+        })?;
+
+    let room = response
+        .rooms
+        .and_then(|rooms| rooms.join)
+        .and_then(|mut join| join.remove(&params.room_id))
+        .and_then(|room| room.timeline);
+    let mut timeline = Timeline { blocked, ..Timeline::default() };
+    let (prev_batch, limited) = match room {
+        Some(room) => {
+            for event in room.events {
+                timeline.push(&params.room_id, event);
+            }
+            (room.prev_batch, room.limited)
+        }
+        None => (None, false),
+    };
+    let Timeline { mut messages, calls, raids, events, redacted, reactions, .. } = timeline;
+
+    attach_viewer_flags(&matrix, messages.iter_mut()).await;
+    if let Some(lang) = params.translate_to.as_deref() {
+        attach_translations(&state, &auth.access_token, lang, &mut messages).await;
+    }
+
+    Ok(Json(RoomSyncResponse {
+        room_id: params.room_id,
+        next_batch: response.next_batch,
+        prev_batch,
+        limited,
+        messages,
+        calls,
+        raids,
+        events,
+        redacted,
+        reactions,
+    }))
+}
+
+/// an invite as the invitee sees it, from the room's stripped state. the
+/// inviter is the sender of the viewer's own invite membership — when the
+/// viewer isn't known, of any invite in there.
+fn invite(room_id: String, room: InvitedRoom, viewer: Option<&str>) -> Invite {
+    let events = room.invite_state.map(|s| s.events).unwrap_or_default();
+    let find = |event_type: &str| events.iter().find(|e| e.event_type == event_type).map(|e| &e.content);
+    let text = |event_type: &str, field: &str| {
+        find(event_type)
+            .and_then(|c| c.get(field))
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+    };
+    let membership = events.iter().find(|e| {
+        e.event_type == "m.room.member"
+            && e.content.get("membership").and_then(|v| v.as_str()) == Some("invite")
+            && viewer.is_none_or(|viewer| e.state_key == viewer)
+    });
+
+    Invite {
+        inviter: membership.map(|m| m.sender.clone()),
+        name: text("m.room.name", "name"),
+        avatar_url: text("m.room.avatar", "url"),
+        is_direct: membership.and_then(|m| m.content.get("is_direct")).and_then(|v| v.as_bool()).unwrap_or(false),
+        is_space: text("m.room.create", "type").as_deref() == Some("m.space"),
+        room_id,
+    }
+}
+
+/// a sync's timeline events, sorted into the response's arrays
+#[derive(Default)]
+struct Timeline {
+    /// senders whose messages and events are left out
+    blocked: HashSet<String>,
+    messages: Vec<Message>,
+    calls: Vec<CallEvent>,
+    raids: Vec<RaidEvent>,
+    events: Vec<CustomEvent>,
+    redacted: Vec<Redacted>,
+    reactions: Vec<Reaction>,
+}
+
+impl Timeline {
+    fn push(&mut self, room_id: &str, event: Event) {
+        if let Some(event_id) = event.redacted_event_id() {
+            self.redacted.push(Redacted {
+                room_id: room_id.to_string(),
+                event_id: event_id.to_string(),
+            });
+        } else if event.event_type == "m.reaction" {
+            let relation = event.content.get("m.relates_to");
+            let target = relation.and_then(|r| r.get("event_id")).and_then(|v| v.as_str());
+            let key = relation.and_then(|r| r.get("key")).and_then(|v| v.as_str());
+            if let (Some(target), Some(key)) = (target, key) {
+                self.reactions.push(Reaction {
+                    room_id: room_id.to_string(),
+                    event_id: event.event_id.clone(),
+                    target_event_id: target.to_string(),
+                    key: key.to_string(),
+                    sender: event.sender.clone(),
+                });
+            }
+        } else if self.blocked.contains(&event.sender) {
+            // redactions and reactions still apply; anything they said doesn't
+        } else if event.event_type == "m.room.message" {
+            if let Some(call) = CallSignal::from_content(&event.content) {
+                self.calls.push(CallEvent {
+                    room_id: room_id.to_string(),
+                    event_id: event.event_id,
+                    sender: event.sender,
+                    timestamp: event.origin_server_ts,
+                    call,
+                });
+            } else if let Some(raid) = RaidSignal::from_content(&event.content) {
+                self.raids.push(RaidEvent {
+                    room_id: room_id.to_string(),
+                    event_id: event.event_id,
+                    sender: event.sender,
+                    timestamp: event.origin_server_ts,
+                    raid,
+                });
+            } else {
+                self.messages.push(message(room_id, event));
+            }
+        } else if event.event_type.starts_with("agora.") && event.state_key.is_none() {
+            self.events.push(CustomEvent {
+                room_id: room_id.to_string(),
+                event_id: event.event_id,
+                event_type: event.event_type,
+                sender: event.sender,
+                timestamp: event.origin_server_ts,
+                content: event.content,
+            });
+        }
+    }
+}
+
+fn message(room_id: &str, event: Event) -> Message {
+    let body = event.content.get("body").and_then(|v| v.as_str()).unwrap_or("");
+    // replies carry a quoted fallback — drop it, the relation is enough
+    let is_reply = event.content.pointer("/m.relates_to/m.in_reply_to").is_some();
+    let content = if is_reply {
+        content::strip_reply_fallback(body).to_string()
+    } else {
+        body.to_string()
+    };
+
+    Message {
+        room_id: room_id.to_string(),
+        sender: event.sender,
+        content,
+        formatted_body: content::html_body(&event.content),
+        timestamp: event.origin_server_ts,
+        event_id: event.event_id,
+        msgtype: event.content.get("msgtype")
+            .and_then(|v| v.as_str())
+            .unwrap_or("m.text")
+            .to_string(),
+        url: event.content.get("url")
+            .and_then(|v| v.as_str())
+            .map(String::from),
+        info: event.content.get("info")
+            .filter(|v| v.is_object())
+            .cloned(),
+        translated_body: None,
+        editable: false,
+        deletable: false,
+        mentions_me: false,
+        notify: true,
+        mentions: Mentions::from_content(&event.content),
+        call: None,
+        raw_content: event.content,
+    }
+}
+
+/// the users `user_id` has blocked, whose messages sync leaves out.
+/// empty without a database, or when the lookup fails.
+async fn blocked_senders(state: &AppState, user_id: &str) -> HashSet<String> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return HashSet::new();
+    };
+    friends::blocked_users(pool, user_id).await.unwrap_or_else(|e| {
+        tracing::warn!("failed to load block list for {}: {}", user_id, e);
+        HashSet::new()
+    })
+}
+
+/// fill in editable / deletable / mentions_me for the token's user, one policy per room
+pub async fn attach_viewer_flags<'m>(matrix: &MatrixClient, messages: impl IntoIterator<Item = &'m mut Message>) {
+    let messages: Vec<&mut Message> = messages.into_iter().collect();
+    if messages.is_empty() {
+        return;
+    }
+    let Some(mut loader) = PolicyLoader::new(matrix).await else {
+        return;
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut policies: HashMap<String, MessagePolicy> = HashMap::new();
+    for message in messages {
+        if !policies.contains_key(&message.room_id) {
+            let policy = loader.for_room(&message.room_id).await;
+            policies.insert(message.room_id.clone(), policy);
+        }
+        let policy = &policies[&message.room_id];
+        message.editable = policy.can_edit(&message.sender, message.timestamp, now).is_ok();
+        message.deletable = policy.can_delete(&message.sender, message.timestamp, now).is_ok();
+        // clients that don't send m.mentions only have the body to go on
+        message.mentions_me = message.sender != policy.user_id
+            && match &message.mentions {
+                Some(mentions) => mentions.room || mentions.user_ids.contains(&policy.user_id),
+                None => content::mentions_user(&message.content, &policy.user_id),
+            };
+    }
+}
+
+/// fill in translated_body where the translation backend (or its cache) has one
+async fn attach_translations(state: &AppState, access_token: &str, lang: &str, messages: &mut [Message]) {
+    let items: Vec<(String, String)> = messages
+        .iter()
+        .filter_map(|m| m.event_id.clone().map(|id| (id, m.content.clone())))
+        .collect();
+    let translated = crate::translate::translate_bodies(state, access_token, lang, &items).await;
+    for message in messages.iter_mut() {
+        if let Some(id) = &message.event_id {
+            message.translated_body = translated.get(id).cloned();
         }
     }
 }
@@ -1,47 +1,91 @@
 use axum::{
-    extract::{Json, Query, State},
+    extract::{
+        multipart::{Field, MultipartError},
+        DefaultBodyLimit, Json, Multipart, Query, State,
+    },
     http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{get, post, put},
     Router,
 };
+use futures_util::{stream, StreamExt, TryStreamExt};
 use redis::AsyncCommands;
+use serde::de::IgnoredAny;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::HashMap;
 use std::sync::Arc;
-use crate::app_state::{AppState, PresenceEvent};
-use crate::matrix::client::MatrixClient;
+use crate::app_state::{AppState, PresenceEvent, WsEvent};
+use crate::avatar::{self, AvatarError};
+use crate::matrix::client::RoomStateEvent;
+use crate::matrix::hierarchy;
+use crate::matrix::client::{MatrixClient, MatrixError, ProfileData};
+use crate::session::{AuthJson, AuthUser};
+use super::{agora_error, matrix_error, media};
 
 // how many seconds before a presence key expires automatically.
 // if a client crashes without logging out it will go offline after this time.
 const PRESENCE_TTL_SECS: u64 = 300; // 5 minutes
 
+// hash of user_id → unix time of their last heartbeat (or presence change).
+// the sweeper walks it to spot idle users and keys that expired.
+const LAST_ACTIVE_KEY: &str = "presence_last_active";
+
+// default for PRESENCE_IDLE_AFTER_SECS: an online user with no heartbeat for
+// this long shows as idle, well before the key expires and they go offline
+const DEFAULT_IDLE_AFTER_SECS: u64 = 150;
+
+// how often the sweeper runs
+const PRESENCE_SWEEP_INTERVAL_SECS: u64 = 30;
+
+// what a client may set; "idle" is only ever set by the sweeper
+const SETTABLE_PRESENCE: [&str; 5] = ["online", "unavailable", "dnd", "invisible", "offline"];
+
+// the avatar itself plus room for the multipart framing around it
+const AVATAR_BODY_LIMIT: usize = avatar::MAX_AVATAR_BYTES + 64 * 1024;
+
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         // presence
         .route("/presence/set", post(set_presence))
         .route("/presence/get", get(get_presence))
+        .route("/presence/heartbeat", post(heartbeat))
         // profile
         .route("/profile/get", get(get_profile))
         .route("/profile/set", put(set_profile))
+        .route("/profile/batch", get(batch_profiles))
+        .route("/profile/avatar", put(upload_avatar).layer(DefaultBodyLimit::max(AVATAR_BODY_LIMIT)))
+        // settings
+        .route("/settings", get(get_settings).put(update_settings))
+        // directory
+        .route("/users/search", get(search_users))
+        .route("/users/mutual", get(mutual))
 }
 
+#[derive(OpenApi)]
+#[openapi(paths(
+    set_presence, get_presence, heartbeat, get_profile, set_profile, batch_profiles,
+    upload_avatar, get_settings, update_settings, search_users, mutual,
+))]
+pub struct ApiDoc;
+
 // ── types ─────────────────────────────────────────────────────────────────────
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SetPresenceRequest {
-    pub access_token: String,
-    pub user_id: String,
-    /// "online" | "offline" | "unavailable"
+    /// "online" | "unavailable" | "dnd" | "invisible" | "offline"
     pub presence: String,
     pub status_msg: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct GetPresenceQuery {
-    pub access_token: String,
     pub user_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct PresenceResponse {
     pub presence: String,
     pub last_active_ago: Option<i64>,
@@ -49,51 +93,190 @@ pub struct PresenceResponse {
     pub currently_active: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct GetProfileQuery {
-    pub access_token: String,
     pub user_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BatchProfileQuery {
+    /// comma-separated user ids
+    pub user_ids: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SetProfileRequest {
-    pub access_token: String,
-    pub user_id: String,
     pub displayname: Option<String>,
     pub avatar_url: Option<String>,
+    // agora's own fields: left out to keep, "" to clear
+    pub bio: Option<String>,
+    /// mxc:// uri
+    pub banner_url: Option<String>,
+    /// "#rrggbb"
+    pub accent_color: Option<String>,
+    pub pronouns: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AvatarResponse {
+    /// the new avatar's mxc:// uri
+    pub avatar_url: String,
+    /// the same image over http, served by /media/download
+    pub http_url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ProfileResponse {
     pub user_id: String,
     pub displayname: Option<String>,
     pub avatar_url: Option<String>,
+    #[serde(flatten)]
+    pub extended: ExtendedProfile,
+}
+
+/// the profile card fields matrix has no place for, kept in agora_profiles
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ExtendedProfile {
+    pub bio: Option<String>,
+    pub banner_url: Option<String>,
+    pub accent_color: Option<String>,
+    pub pronouns: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchProfileResponse {
+    pub profiles: Vec<ProfileResponse>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateSettingsRequest {
+    /// a json merge patch (rfc 7396) against the stored settings: keys set to
+    /// null are removed, objects merge, anything else replaces
+    #[schema(value_type = Object)]
+    pub settings: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SettingsResponse {
+    #[schema(value_type = Object)]
+    pub settings: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchUsersQuery {
+    pub query: String,
+    /// at most this many results, default 10
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserSearchResult {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchUsersResponse {
+    pub results: Vec<UserSearchResult>,
+    /// more users matched than were returned
+    pub limited: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MutualQuery {
+    pub other_user_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MutualServer {
+    pub room_id: String,
+    pub name: Option<String>,
+    /// mxc:// uri of the server icon
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MutualFriend {
+    pub user_id: String,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MutualResponse {
+    pub servers: Vec<MutualServer>,
+    pub friends: Vec<MutualFriend>,
 }
 
+// how many room states /users/mutual reads at once
+const MUTUAL_STATE_CONCURRENCY: usize = 8;
+
+// limits on the agora profile fields, in characters
+const MAX_BIO_LEN: usize = 190;
+const MAX_PRONOUNS_LEN: usize = 40;
+
+// cap on user ids per /profile/batch, and how many profiles it fetches at once
+const MAX_BATCH_PROFILES: usize = 100;
+const BATCH_PROFILE_CONCURRENCY: usize = 8;
+
+// the account data type the settings blob lives under
+const SETTINGS_ACCOUNT_DATA_TYPE: &str = "agora.settings";
+// cap on the stored settings, serialized
+const MAX_SETTINGS_BYTES: usize = 64 * 1024;
+
+// default and cap for SearchUsersQuery::limit
+const DEFAULT_SEARCH_LIMIT: u32 = 10;
+const MAX_SEARCH_LIMIT: u32 = 50;
+
 // ── handlers ──────────────────────────────────────────────────────────────────
 
 /// set the calling user's presence state — stored in redis with a TTL so
 /// clients that crash without logging out eventually go offline automatically.
+#[utoipa::path(
+    post,
+    path = "/presence/set",
+    tag = "users",
+    request_body = SetPresenceRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
 async fn set_presence(
     state: State<Arc<AppState>>,
-    Json(req): Json<SetPresenceRequest>,
+    AuthJson(auth, req): AuthJson<SetPresenceRequest>,
 ) -> StatusCode {
-    let Some(mut redis) = state.redis.clone() else {
-        tracing::warn!("set_presence: redis unavailable");
-        return StatusCode::SERVICE_UNAVAILABLE;
-    };
-
+    if !SETTABLE_PRESENCE.contains(&req.presence.as_str()) {
+        return StatusCode::BAD_REQUEST;
+    }
     // key format: presence:{user_id}
-    // value: "online" | "offline" | "unavailable"
-    let key = format!("presence:{}", req.user_id);
+    // value: "online" | "unavailable" | "dnd" | "invisible" | "idle"
+    let key = format!("presence:{}", auth.user_id);
     let value = req.presence.as_str();
 
-    let result: redis::RedisResult<()> = if value == "offline" {
-        // delete immediately so the key doesn't linger
-        redis.del(&key).await
-    } else {
-        // set with TTL so a crash/disconnect eventually expires
-        redis.set_ex(&key, value, PRESENCE_TTL_SECS).await
+    let result = state
+        .redis
+        .retrying(|mut redis| {
+            let (key, user_id) = (&key, &auth.user_id);
+            async move {
+                let result: redis::RedisResult<()> = if value == "offline" {
+                    // delete immediately so the key doesn't linger
+                    let _: redis::RedisResult<()> = redis.hdel(LAST_ACTIVE_KEY, user_id).await;
+                    redis.del(key).await
+                } else {
+                    // set with TTL so a crash/disconnect eventually expires
+                    let _: redis::RedisResult<()> = redis.hset(LAST_ACTIVE_KEY, user_id, unix_now()).await;
+                    redis.set_ex(key, value, PRESENCE_TTL_SECS).await
+                };
+                result
+            }
+        })
+        .await;
+    let Some(result) = result else {
+        tracing::warn!("set_presence: redis unavailable");
+        return StatusCode::SERVICE_UNAVAILABLE;
     };
 
     if let Err(e) = result {
@@ -103,21 +286,198 @@ async fn set_presence(
 
     // broadcast the change to all connected websocket clients instantly
     let event = PresenceEvent {
-        user_id: req.user_id.clone(),
-        presence: req.presence.clone(),
+        user_id: auth.user_id.clone(),
+        presence: public_presence(&req.presence).to_string(),
     };
-    // send() only errors if there are no receivers — that's fine, just ignore
-    let _ = state.presence_tx.send(event);
+    state.publish(WsEvent::Presence(event));
 
     StatusCode::OK
 }
 
+/// what everyone but the user themselves sees: invisible users look offline
+pub fn public_presence(presence: &str) -> &str {
+    if presence == "invisible" {
+        "offline"
+    } else {
+        presence
+    }
+}
+
+/// the user's own presence as stored — None when redis is unavailable or
+/// they're offline
+pub async fn stored_presence(state: &AppState, user_id: &str) -> Option<String> {
+    let mut redis = state.get_redis().await?;
+    redis.get(format!("presence:{}", user_id)).await.unwrap_or(None)
+}
+
+/// drop a user's presence key and tell everyone they're offline — for when a
+/// session ends on purpose, instead of waiting out the TTL
+pub async fn clear_presence(state: &AppState, user_id: &str) {
+    if let Some(mut redis) = state.get_redis().await {
+        let _: redis::RedisResult<()> = redis.hdel(LAST_ACTIVE_KEY, user_id).await;
+        let result: redis::RedisResult<()> = redis.del(format!("presence:{}", user_id)).await;
+        if let Err(e) = result {
+            tracing::warn!("redis clear_presence error: {}", e);
+        }
+    }
+
+    let event = PresenceEvent {
+        user_id: user_id.to_string(),
+        presence: "offline".to_string(),
+    };
+    state.publish(WsEvent::Presence(event));
+}
+
+/// the client is still there: push the presence key's expiry back and count
+/// it as activity. an idle (or already expired) user comes back online.
+#[utoipa::path(
+    post,
+    path = "/presence/heartbeat",
+    tag = "users",
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn heartbeat(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, _): AuthJson<IgnoredAny>,
+) -> StatusCode {
+    let Some(mut redis) = state.get_redis().await else {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    };
+
+    let key = format!("presence:{}", auth.user_id);
+    let current: Option<String> = match redis.get(&key).await {
+        Ok(current) => current,
+        Err(e) => {
+            tracing::warn!("redis heartbeat error: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    let _: redis::RedisResult<()> = redis.hset(LAST_ACTIVE_KEY, &auth.user_id, unix_now()).await;
+
+    // anything the user chose themselves (away, ...) is kept as it is
+    let back = matches!(current.as_deref(), None | Some("idle"));
+    let presence = if back { "online" } else { current.as_deref().unwrap_or("online") };
+    let result: redis::RedisResult<()> = redis.set_ex(&key, presence, PRESENCE_TTL_SECS).await;
+    if let Err(e) = result {
+        tracing::warn!("redis heartbeat error: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    if back {
+        state.publish(WsEvent::Presence(PresenceEvent {
+            user_id: auth.user_id,
+            presence: presence.to_string(),
+        }));
+    }
+
+    StatusCode::OK
+}
+
+/// PRESENCE_IDLE_AFTER_SECS, or the default
+fn idle_after_secs() -> u64 {
+    std::env::var("PRESENCE_IDLE_AFTER_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_IDLE_AFTER_SECS)
+}
+
+/// background task: flips quiet users to idle and announces expired ones as
+/// offline — spawned from main.rs when redis is available
+pub async fn run_presence_sweeper(state: Arc<AppState>) {
+    let idle_after = idle_after_secs();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(PRESENCE_SWEEP_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        sweep_presence(&state, unix_now(), idle_after).await;
+    }
+}
+
+/// one pass over everyone with a heartbeat on record: online users quiet for
+/// `idle_after` seconds become idle, and users whose presence key has expired
+/// are broadcast as offline (once) and forgotten. returns how many changed.
+pub async fn sweep_presence(state: &AppState, now: u64, idle_after: u64) -> usize {
+    let Some(mut redis) = state.get_redis().await else {
+        return 0;
+    };
+    let last_active: Vec<(String, u64)> = match redis.hgetall(LAST_ACTIVE_KEY).await {
+        Ok(last_active) => last_active,
+        Err(e) => {
+            tracing::warn!("redis presence sweep error: {}", e);
+            return 0;
+        }
+    };
+    if last_active.is_empty() {
+        return 0;
+    }
+
+    let keys: Vec<String> = last_active.iter().map(|(user_id, _)| format!("presence:{}", user_id)).collect();
+    let presences: Vec<Option<String>> = redis.mget(&keys).await.unwrap_or_default();
+
+    let mut changed = 0;
+    for ((user_id, active_at), (key, presence)) in last_active.into_iter().zip(keys.iter().zip(presences)) {
+        let next = match presence.as_deref() {
+            None => {
+                let _: redis::RedisResult<()> = redis.hdel(LAST_ACTIVE_KEY, &user_id).await;
+                "offline"
+            }
+            Some("online") if now.saturating_sub(active_at) >= idle_after => {
+                // keep the key's expiry: idle users still go offline on time.
+                // XX so a key that expired meanwhile isn't brought back
+                let set: Option<String> = redis::cmd("SET")
+                    .arg(key)
+                    .arg("idle")
+                    .arg("XX")
+                    .arg("KEEPTTL")
+                    .query_async(&mut redis)
+                    .await
+                    .unwrap_or(None);
+                if set.is_none() {
+                    continue;
+                }
+                "idle"
+            }
+            _ => continue,
+        };
+        state.publish(WsEvent::Presence(PresenceEvent { user_id, presence: next.to_string() }));
+        changed += 1;
+    }
+    changed
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 /// fetch any user's presence state from redis
+#[utoipa::path(
+    get,
+    path = "/presence/get",
+    tag = "users",
+    params(GetPresenceQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = PresenceResponse))
+)]
 async fn get_presence(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<GetPresenceQuery>,
 ) -> Json<PresenceResponse> {
-    let Some(mut redis) = state.redis.clone() else {
+    let key = format!("presence:{}", params.user_id);
+    let value = state
+        .redis
+        .retrying(|mut redis| {
+            let key = &key;
+            async move {
+                let value: redis::RedisResult<Option<String>> = redis.get(key).await;
+                value
+            }
+        })
+        .await;
+    let (Some(value), Some(mut redis)) = (value, state.get_redis().await) else {
         tracing::warn!("get_presence: redis unavailable");
         return Json(PresenceResponse {
             presence: "offline".to_string(),
@@ -126,34 +486,56 @@ async fn get_presence(
             currently_active: Some(false),
         });
     };
+    let value = value.unwrap_or(None);
 
-    let key = format!("presence:{}", params.user_id);
-    let value: Option<String> = redis.get(&key).await.unwrap_or(None);
-
-    let presence = value.unwrap_or_else(|| "offline".to_string());
+    let mut presence = value.unwrap_or_else(|| "offline".to_string());
+    // only the owner can see through invisible
+    if presence == "invisible" && auth.user_id != params.user_id {
+        presence = public_presence(&presence).to_string();
+    }
     let currently_active = presence == "online";
+    // only meaningful while they're around
+    let last_active_ago = if presence == "offline" {
+        None
+    } else {
+        let active_at: Option<u64> = redis.hget(LAST_ACTIVE_KEY, &params.user_id).await.unwrap_or(None);
+        active_at.map(|t| unix_now().saturating_sub(t) as i64 * 1000)
+    };
 
     Json(PresenceResponse {
         presence,
-        last_active_ago: None,
+        last_active_ago,
         status_msg: None,
         currently_active: Some(currently_active),
     })
 }
 
 /// fetch a user's profile (displayname + avatar)
+#[utoipa::path(
+    get,
+    path = "/profile/get",
+    tag = "users",
+    params(GetProfileQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = ProfileResponse), super::StatusErrors)
+)]
 async fn get_profile(
     state: State<Arc<AppState>>,
+    auth: AuthUser,
     Query(params): Query<GetProfileQuery>,
 ) -> Result<Json<ProfileResponse>, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let mut extended = extended_profiles(&state, std::slice::from_ref(&params.user_id)).await?;
+    let extended = extended.remove(&params.user_id).unwrap_or_default();
 
     match matrix.get_profile(params.user_id.clone()).await {
         Ok(p) => Ok(Json(ProfileResponse {
             user_id: params.user_id,
             displayname: p.displayname,
             avatar_url: p.avatar_url,
+            extended,
         })),
         Err(e) => {
             tracing::warn!("failed to get profile for {}: {}", params.user_id, e);
@@ -162,28 +544,580 @@ async fn get_profile(
                 user_id: params.user_id,
                 displayname: None,
                 avatar_url: None,
+                extended,
             }))
         }
     }
 }
 
-/// update the calling user's own profile
+/// profiles for several users at once, for member lists. users whose matrix
+/// profile can't be read come back with just their agora fields.
+#[utoipa::path(
+    get,
+    path = "/profile/batch",
+    tag = "users",
+    params(BatchProfileQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = BatchProfileResponse), super::StatusErrors)
+)]
+async fn batch_profiles(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<BatchProfileQuery>,
+) -> Result<Json<BatchProfileResponse>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let mut user_ids: Vec<String> = Vec::new();
+    for id in params.user_ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        if !user_ids.iter().any(|seen| seen == id) {
+            user_ids.push(id.to_string());
+        }
+    }
+    user_ids.truncate(MAX_BATCH_PROFILES);
+
+    let mut extended = extended_profiles(&state, &user_ids).await?;
+    let matrix_ref = &matrix;
+    let mut fetched: HashMap<String, ProfileData> = stream::iter(user_ids.clone())
+        .map(|user_id| async move {
+            let profile = matrix_ref.get_profile(user_id.clone()).await;
+            if let Err(e) = &profile {
+                if e.status() == Some(401) {
+                    return Err(StatusCode::UNAUTHORIZED);
+                }
+                tracing::debug!("failed to get profile for {}: {}", user_id, e);
+            }
+            Ok((user_id, profile.unwrap_or_default()))
+        })
+        .buffer_unordered(BATCH_PROFILE_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let profiles = user_ids
+        .into_iter()
+        .map(|user_id| {
+            let profile = fetched.remove(&user_id).unwrap_or_default();
+            ProfileResponse {
+                displayname: profile.displayname,
+                avatar_url: profile.avatar_url,
+                extended: extended.remove(&user_id).unwrap_or_default(),
+                user_id,
+            }
+        })
+        .collect();
+    Ok(Json(BatchProfileResponse { profiles }))
+}
+
+/// agora_profiles rows for `user_ids`; users without one are left out, and
+/// without a database there are none
+async fn extended_profiles(state: &AppState, user_ids: &[String]) -> Result<HashMap<String, ExtendedProfile>, StatusCode> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(HashMap::new());
+    };
+    let rows = sqlx::query(
+        "SELECT user_id, bio, banner_url, accent_color, pronouns FROM agora_profiles WHERE user_id = ANY($1)",
+    )
+    .bind(user_ids)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("failed to load agora profiles: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let profile = ExtendedProfile {
+                bio: row.get("bio"),
+                banner_url: row.get("banner_url"),
+                accent_color: row.get("accent_color"),
+                pronouns: row.get("pronouns"),
+            };
+            (row.get("user_id"), profile)
+        })
+        .collect())
+}
+
+/// update the calling user's own profile. matrix fields go to the
+/// homeserver, the rest to agora_profiles; everything is checked before
+/// either is written.
+#[utoipa::path(
+    put,
+    path = "/profile/set",
+    tag = "users",
+    request_body = SetProfileRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
 async fn set_profile(
     state: State<Arc<AppState>>,
-    Json(req): Json<SetProfileRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    AuthJson(auth, req): AuthJson<SetProfileRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    // an empty string clears the avatar, as in matrix
+    if let Some(url) = &req.avatar_url {
+        if !url.is_empty() && !crate::content::is_mxc_uri(url) {
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+    }
+    let extended = validate_extended(&req).map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?;
+    let pool = match (&extended, state.db_pool.as_ref()) {
+        (None, _) => None,
+        (Some(_), Some(pool)) => Some(pool),
+        (Some(_), None) => return Err(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
 
     if let Some(name) = req.displayname {
         matrix
-            .set_displayname(req.user_id.clone(), name)
+            .set_displayname(auth.user_id.clone(), name)
             .await
             .map_err(|e| {
                 tracing::warn!("failed to set displayname: {}", e);
-                StatusCode::BAD_REQUEST
+                StatusCode::BAD_REQUEST.into_response()
+            })?;
+    }
+
+    if let Some(url) = req.avatar_url {
+        matrix
+            .set_avatar_url(auth.user_id.clone(), url)
+            .await
+            .map_err(|e| {
+                tracing::warn!("failed to set avatar: {}", e);
+                StatusCode::BAD_REQUEST.into_response()
             })?;
     }
 
+    if let (Some(fields), Some(pool)) = (extended, pool) {
+        // None keeps a column, "" clears it
+        sqlx::query(
+            r#"
+            INSERT INTO agora_profiles (user_id, bio, banner_url, accent_color, pronouns)
+            VALUES ($1, NULLIF($2, ''), NULLIF($3, ''), NULLIF($4, ''), NULLIF($5, ''))
+            ON CONFLICT (user_id) DO UPDATE SET
+                bio = NULLIF(COALESCE($2, agora_profiles.bio), ''),
+                banner_url = NULLIF(COALESCE($3, agora_profiles.banner_url), ''),
+                accent_color = NULLIF(COALESCE($4, agora_profiles.accent_color), ''),
+                pronouns = NULLIF(COALESCE($5, agora_profiles.pronouns), ''),
+                updated_at = NOW()
+            "#,
+        )
+        .bind(&auth.user_id)
+        .bind(fields.bio)
+        .bind(fields.banner_url)
+        .bind(fields.accent_color)
+        .bind(fields.pronouns)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to save agora profile for {}: {}", auth.user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    }
+
     Ok(StatusCode::OK)
 }
+
+/// the agora fields of a profile update, cleaned up, or None if it has none.
+/// each field is None to keep, "" to clear, or the new value.
+fn validate_extended(req: &SetProfileRequest) -> Result<Option<ExtendedProfile>, String> {
+    if req.bio.is_none() && req.banner_url.is_none() && req.accent_color.is_none() && req.pronouns.is_none() {
+        return Ok(None);
+    }
+
+    let bio = req.bio.as_deref().map(|bio| clean_profile_text(bio, true));
+    if bio.as_ref().is_some_and(|bio| bio.chars().count() > MAX_BIO_LEN) {
+        return Err(format!("bio must be at most {} characters", MAX_BIO_LEN));
+    }
+    let pronouns = req.pronouns.as_deref().map(|p| clean_profile_text(p, false));
+    if pronouns.as_ref().is_some_and(|p| p.chars().count() > MAX_PRONOUNS_LEN) {
+        return Err(format!("pronouns must be at most {} characters", MAX_PRONOUNS_LEN));
+    }
+    let banner_url = req.banner_url.as_deref().map(str::trim);
+    if banner_url.is_some_and(|url| !url.is_empty() && !crate::content::is_mxc_uri(url)) {
+        return Err("banner_url must be an mxc:// uri".to_string());
+    }
+    let accent_color = req.accent_color.as_deref().map(|c| c.trim().to_ascii_lowercase());
+    let is_hex_color = |c: &str| c.len() == 7 && c.starts_with('#') && c[1..].chars().all(|ch| ch.is_ascii_hexdigit());
+    if accent_color.as_deref().is_some_and(|c| !c.is_empty() && !is_hex_color(c)) {
+        return Err("accent_color must look like #rrggbb".to_string());
+    }
+
+    Ok(Some(ExtendedProfile {
+        bio,
+        banner_url: banner_url.map(String::from),
+        accent_color,
+        pronouns,
+    }))
+}
+
+/// trim, and drop control characters — newlines too unless `multiline`.
+/// these are shown as plain text, so nothing else needs escaping here.
+fn clean_profile_text(text: &str, multiline: bool) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control() || (multiline && *c == '\n'))
+        .collect();
+    text.trim().to_string()
+}
+
+/// upload an image and make it the caller's avatar. takes multipart with the
+/// image in a `file` field; it's checked and scaled down by avatar::prepare
+/// before it goes to the media repo.
+#[utoipa::path(
+    put,
+    path = "/profile/avatar",
+    tag = "users",
+    request_body(content_type = "multipart/form-data", description = "an image in a `file` field, up to the upload limit"),
+    security(("bearer" = [])),
+    responses((status = 200, body = AvatarResponse), super::ErrorResponses)
+)]
+async fn upload_avatar(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    mut multipart: Multipart,
+) -> Result<Json<AvatarResponse>, Response> {
+    let matrix = session(&state, auth);
+
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        if field.name() == Some("file") {
+            let is_image = field.content_type().is_some_and(|t| t.starts_with("image/"));
+            if !is_image {
+                return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "avatar must be an image"));
+            }
+            upload = Some(read_field(field).await?);
+            break;
+        }
+    }
+    let Some(bytes) = upload else {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_MISSING_PARAM", "missing file field"));
+    };
+
+    let image = avatar::prepare(bytes).map_err(|e| match e {
+        AvatarError::TooLarge => avatar_too_large(),
+        AvatarError::NotAnImage => agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e.to_string()),
+    })?;
+    let limit = state.media_limit.get(&matrix).await;
+    let avatar_url = media::upload_bytes(&state, &matrix, image.content_type, Some("avatar"), image.bytes, limit).await?;
+
+    let user_id = matrix.user_id.clone().unwrap_or_default();
+    matrix
+        .set_avatar_url(user_id, avatar_url.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_GATEWAY))?;
+
+    let http_url = media::download_path(&avatar_url).unwrap_or_default();
+    Ok(Json(AvatarResponse { avatar_url, http_url }))
+}
+
+fn avatar_too_large() -> Response {
+    let message = format!("avatar exceeds {} bytes", avatar::MAX_AVATAR_BYTES);
+    agora_error(StatusCode::PAYLOAD_TOO_LARGE, "M_TOO_LARGE", &message)
+}
+
+fn multipart_error(e: MultipartError) -> Response {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return avatar_too_large();
+    }
+    agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e.body_text())
+}
+
+/// a field's bytes, stopping as soon as it's over the avatar limit
+async fn read_field(mut field: Field<'_>) -> Result<Vec<u8>, Response> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        if bytes.len() + chunk.len() > avatar::MAX_AVATAR_BYTES {
+            return Err(avatar_too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// a client for the caller, with user_id filled in for account data
+fn session(state: &AppState, auth: AuthUser) -> MatrixClient {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    matrix.user_id = Some(auth.user_id);
+    matrix
+}
+
+/// the homeserver won't store our custom account data type — as opposed to
+/// a bad token or a rate limit, which are passed on
+pub fn rejects_custom_account_data(e: &MatrixError) -> bool {
+    matches!(e.status(), Some(400 | 403 | 404 | 405))
+        && !matches!(e.errcode(), Some("M_NOT_FOUND" | "M_UNKNOWN_TOKEN" | "M_MISSING_TOKEN" | "M_LIMIT_EXCEEDED"))
+}
+
+/// settings kept in postgres for homeservers that refuse them
+async fn stored_settings(state: &AppState, user_id: &str) -> Result<Option<serde_json::Value>, Response> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(None);
+    };
+    let settings: Option<String> = sqlx::query_scalar("SELECT settings::TEXT FROM user_settings WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("failed to load settings for {}: {}", user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+    Ok(settings.and_then(|s| serde_json::from_str(&s).ok()))
+}
+
+/// the current settings, wherever they are — {} when none were saved yet
+async fn load_settings(state: &AppState, matrix: &MatrixClient) -> Result<serde_json::Value, Response> {
+    let user_id = matrix.user_id.as_deref().unwrap_or_default();
+    match matrix.get_account_data(SETTINGS_ACCOUNT_DATA_TYPE).await {
+        Ok(settings) => Ok(settings),
+        Err(e) if e.is_not_found() || rejects_custom_account_data(&e) => {
+            Ok(stored_settings(state, user_id).await?.unwrap_or_else(|| serde_json::json!({})))
+        }
+        Err(e) => {
+            tracing::warn!("failed to read settings of {}: {}", user_id, e);
+            Err(matrix_error(&e, StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+/// apply a json merge patch (rfc 7396) to `target`
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::json!({});
+    }
+    let target = target.as_object_mut().expect("made an object above");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// the caller's client settings (theme, notification prefs, keybinds, ...)
+#[utoipa::path(
+    get,
+    path = "/settings",
+    tag = "users",
+    security(("bearer" = [])),
+    responses((status = 200, body = SettingsResponse), super::ErrorResponses)
+)]
+async fn get_settings(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+) -> Result<Json<SettingsResponse>, Response> {
+    let matrix = session(&state, auth);
+    let settings = load_settings(&state, &matrix).await?;
+    Ok(Json(SettingsResponse { settings }))
+}
+
+/// merge a patch into the caller's settings, so a device only sends what it
+/// changed and doesn't undo another device's changes. stored in matrix account
+/// data, or postgres when the homeserver refuses custom types.
+#[utoipa::path(
+    put,
+    path = "/settings",
+    tag = "users",
+    request_body = UpdateSettingsRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = SettingsResponse), super::ErrorResponses)
+)]
+async fn update_settings(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<UpdateSettingsRequest>,
+) -> Result<Json<SettingsResponse>, Response> {
+    if !req.settings.is_object() {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_BAD_JSON", "settings must be a json object"));
+    }
+    let matrix = session(&state, auth);
+    let user_id = matrix.user_id.clone().unwrap_or_default();
+
+    let mut settings = load_settings(&state, &matrix).await?;
+    merge_patch(&mut settings, &req.settings);
+    if settings.to_string().len() > MAX_SETTINGS_BYTES {
+        let error = format!("settings are limited to {} bytes", MAX_SETTINGS_BYTES);
+        return Err(agora_error(StatusCode::PAYLOAD_TOO_LARGE, "M_TOO_LARGE", error));
+    }
+
+    match matrix.set_account_data(SETTINGS_ACCOUNT_DATA_TYPE, &settings).await {
+        Ok(()) => {}
+        Err(e) if rejects_custom_account_data(&e) => {
+            let Some(pool) = state.db_pool.as_ref() else {
+                tracing::error!("homeserver refused settings for {} and there is no database to fall back to: {}", user_id, e);
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            };
+            sqlx::query(
+                r#"
+                INSERT INTO user_settings (user_id, settings, updated_at)
+                VALUES ($1, $2::JSONB, NOW())
+                ON CONFLICT (user_id) DO UPDATE SET settings = $2::JSONB, updated_at = NOW()
+                "#,
+            )
+            .bind(&user_id)
+            .bind(settings.to_string())
+            .execute(pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("failed to store settings for {}: {}", user_id, e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })?;
+        }
+        Err(e) => {
+            tracing::warn!("failed to write settings of {}: {}", user_id, e);
+            return Err(matrix_error(&e, StatusCode::BAD_GATEWAY));
+        }
+    }
+
+    Ok(Json(SettingsResponse { settings }))
+}
+
+/// autocomplete for the add-friend box, from the homeserver's user directory
+#[utoipa::path(
+    get,
+    path = "/users/search",
+    tag = "users",
+    params(SearchUsersQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = SearchUsersResponse), super::ErrorResponses)
+)]
+async fn search_users(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<SearchUsersQuery>,
+) -> Result<Json<SearchUsersResponse>, Response> {
+    let query = params.query.trim();
+    if query.is_empty() {
+        return Ok(Json(SearchUsersResponse { results: Vec::new(), limited: false }));
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    let found = matrix.search_users(query, limit).await.map_err(|e| {
+        tracing::warn!("user directory search failed: {}", e);
+        matrix_error(&e, StatusCode::BAD_GATEWAY)
+    })?;
+
+    let results = found
+        .results
+        .into_iter()
+        .map(|user| UserSearchResult {
+            user_id: user.user_id,
+            display_name: user.display_name,
+            avatar_url: user.avatar_url,
+        })
+        .collect();
+    Ok(Json(SearchUsersResponse { results, limited: found.limited }))
+}
+
+/// what the profile popout shows under "mutual servers" / "mutual friends"
+#[utoipa::path(
+    get,
+    path = "/users/mutual",
+    tag = "users",
+    params(MutualQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = MutualResponse), super::ErrorResponses)
+)]
+async fn mutual(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<MutualQuery>,
+) -> Result<Json<MutualResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let joined = matrix.get_joined_rooms().await.map_err(|e| {
+        tracing::warn!("failed to list joined rooms: {}", e);
+        matrix_error(&e, StatusCode::BAD_GATEWAY)
+    })?;
+
+    // every joined room's state tells whether it's a space and whether the
+    // other user is in it — read them a few at a time
+    let matrix_ref = &matrix;
+    let other = params.other_user_id.as_str();
+    let mut servers: Vec<MutualServer> = stream::iter(joined.joined_rooms)
+        .map(|room_id| async move {
+            let room_state = matrix_ref.get_room_state(room_id.clone()).await.ok()?;
+            (hierarchy::is_space(&room_state) && is_joined(&room_state, other))
+                .then(|| mutual_server(room_id, &room_state))
+        })
+        .buffer_unordered(MUTUAL_STATE_CONCURRENCY)
+        .filter_map(|server| async move { server })
+        .collect()
+        .await;
+    servers.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.room_id.cmp(&b.room_id)));
+
+    let friend_ids = match state.db_pool.as_ref() {
+        Some(pool) => mutual_friend_ids(pool, &auth.user_id, other).await.map_err(|e| {
+            tracing::error!("failed to query mutual friends: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?,
+        // friends live in the database — without one there are none to share
+        None => Vec::new(),
+    };
+    let names = state.profiles.display_names(&matrix, &friend_ids).await;
+    let friends = friend_ids
+        .into_iter()
+        .map(|user_id| MutualFriend { display_name: names.get(&user_id).cloned(), user_id })
+        .collect();
+
+    Ok(Json(MutualResponse { servers, friends }))
+}
+
+fn is_joined(room_state: &[RoomStateEvent], user_id: &str) -> bool {
+    room_state.iter().any(|e| {
+        e.event_type == "m.room.member"
+            && e.state_key.as_deref() == Some(user_id)
+            && e.content.get("membership").and_then(|v| v.as_str()) == Some("join")
+    })
+}
+
+fn mutual_server(room_id: String, room_state: &[RoomStateEvent]) -> MutualServer {
+    let content = |event_type: &str, field: &str| {
+        room_state
+            .iter()
+            .find(|e| e.event_type == event_type)
+            .and_then(|e| e.content.get(field))
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+    };
+    MutualServer {
+        name: content("m.room.name", "name"),
+        avatar_url: content("m.room.avatar", "url"),
+        room_id,
+    }
+}
+
+/// users with an accepted friendship with both `user_id` and `other_user_id`
+async fn mutual_friend_ids(pool: &sqlx::PgPool, user_id: &str, other_user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        WITH mine AS (
+            SELECT CASE WHEN requester_id = $1 THEN addressee_id ELSE requester_id END AS friend_id
+            FROM friends
+            WHERE (requester_id = $1 OR addressee_id = $1) AND status = 'accepted'
+        ), theirs AS (
+            SELECT CASE WHEN requester_id = $2 THEN addressee_id ELSE requester_id END AS friend_id
+            FROM friends
+            WHERE (requester_id = $2 OR addressee_id = $2) AND status = 'accepted'
+        )
+        SELECT friend_id FROM mine JOIN theirs USING (friend_id)
+        ORDER BY friend_id
+        "#,
+    )
+    .bind(user_id)
+    .bind(other_user_id)
+    .fetch_all(pool)
+    .await
+}
@@ -1,44 +1,155 @@
 use axum::{
     extract::{Json, Query, State},
-    http::StatusCode,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::sync::Arc;
-use crate::app_state::AppState;
+use futures_util::future::join_all;
+use crate::app_state::{AppState, VoiceEvent, WsEvent};
+use crate::idempotency;
+use crate::matrix::client::{MatrixClient, MatrixError, RoomStateEvent};
+use crate::matrix::hierarchy::{max_depth, walk_space, MAX_SPACE_NESTING};
+use crate::matrix::{encode_path_segment, revision};
+use crate::routes::{agora_error, matrix_error};
+use crate::session::{AuthJson, AuthUser};
 
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/voice/token", post(get_voice_token))
         .route("/voice/participants", get(get_voice_participants))
+        .route("/voice/states", get(get_voice_states))
+        .route("/voice/webhook", post(livekit_webhook))
+        .route("/voice/moderate", post(moderate_voice))
+        .route("/voice/stage", get(get_stage))
+        .route("/voice/stage/speakers", post(set_stage_speaker))
+        .route("/voice/stage/request", post(raise_hand))
+        .route("/voice/settings", get(get_voice_settings).post(set_voice_settings))
         .route("/voice/call", post(send_call_event))
+        .route("/voice/call/history", get(get_call_history))
+        .route("/voice/call/participants", get(get_call_participants))
         .route("/voice/vibe", get(get_vibe))
         .route("/voice/vibe", post(set_vibe))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(OpenApi)]
+#[openapi(paths(
+    get_voice_token, get_voice_participants, get_voice_states, livekit_webhook,
+    moderate_voice, get_stage, set_stage_speaker, raise_hand, get_voice_settings,
+    set_voice_settings, send_call_event, get_call_history, get_call_participants, get_vibe,
+    set_vibe,
+))]
+pub struct ApiDoc;
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct VoiceTokenRequest {
-    pub access_token: String,
     pub room_id: String,
-    pub user_id: String,
     pub display_name: Option<String>,
+    /// joining a call in a dm rather than the room's own voice channel — each
+    /// call gets its own livekit room so back-to-back calls don't collide
+    pub call_id: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct VoiceTokenResponse {
     pub token: String,
     pub livekit_url: String,
+    /// publisher hints for this channel — clients apply them to their livekit publish options
+    pub settings: VoiceSettings,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct VoiceParticipantsQuery {
     pub room_name: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct VoiceParticipantsResponse {
-    pub participants: Vec<String>,
+    pub participants: Vec<ParticipantInfo>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ParticipantInfo {
+    pub user_id: String,
+    /// the name their voice token was issued with, else the user id
+    pub display_name: String,
+    /// microphone muted
+    pub is_muted: bool,
+    pub is_screen_sharing: bool,
+    pub is_camera_on: bool,
+    /// unix seconds — absent when livekit didn't say
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub joined_at: Option<u64>,
+}
+
+/// a RoomService ListParticipants answer. livekit leaves fields at their zero
+/// value out (older versions don't know some at all), so everything defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ListParticipantsResponse {
+    participants: Vec<LiveKitParticipant>,
+}
+
+/// livekit's ParticipantInfo — only the fields we use
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LiveKitParticipant {
+    identity: String,
+    /// the name their token was issued with
+    name: String,
+    /// unix seconds; protojson sends int64 as a string
+    #[serde(alias = "joinedAt")]
+    joined_at: serde_json::Value,
+    tracks: Vec<LiveKitTrack>,
+}
+
+/// livekit's TrackInfo, in ListParticipants and in track webhooks
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LiveKitTrack {
+    /// "AUDIO", "VIDEO" or "DATA" — left out for AUDIO, the zero value
+    #[serde(rename = "type")]
+    kind: String,
+    /// "MICROPHONE", "CAMERA", "SCREEN_SHARE" or "SCREEN_SHARE_AUDIO"; left
+    /// out by livekit versions from before track sources
+    source: String,
+    muted: bool,
+}
+
+impl LiveKitTrack {
+    fn is_microphone(&self) -> bool {
+        match self.source.as_str() {
+            "" | "UNKNOWN" => matches!(self.kind.as_str(), "" | "AUDIO"),
+            source => source == "MICROPHONE",
+        }
+    }
+
+    fn is_camera(&self) -> bool {
+        match self.source.as_str() {
+            "" | "UNKNOWN" => self.kind == "VIDEO",
+            source => source == "CAMERA",
+        }
+    }
+
+    fn is_screen_share(&self) -> bool {
+        self.source == "SCREEN_SHARE"
+    }
+}
+
+/// an int64 from livekit: a string in protojson, a number from older versions
+fn proto_u64(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::String(s) => s.parse().ok(),
+        value => value.as_u64(),
+    }
 }
 
 // livekit jwt claims — matches the livekit server spec exactly
@@ -67,17 +178,30 @@ struct VideoGrant {
     can_subscribe: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "canPublishData")]
     can_publish_data: Option<bool>,
+    /// when set, only these track sources may be published
+    #[serde(skip_serializing_if = "Option::is_none", rename = "canPublishSources")]
+    can_publish_sources: Option<Vec<String>>,
     // admin grants
     #[serde(skip_serializing_if = "Option::is_none", rename = "roomAdmin")]
     room_admin: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none", rename = "roomList")]
     room_list: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "roomCreate")]
+    room_create: Option<bool>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/voice/token",
+    tag = "voice",
+    request_body = VoiceTokenRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = VoiceTokenResponse), super::ErrorResponses)
+)]
 async fn get_voice_token(
-    _state: State<Arc<AppState>>,
-    Json(req): Json<VoiceTokenRequest>,
-) -> Result<Json<VoiceTokenResponse>, StatusCode> {
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<VoiceTokenRequest>,
+) -> Result<Json<VoiceTokenResponse>, Response> {
     let api_key = std::env::var("LIVEKIT_API_KEY").unwrap_or_else(|_| "devkey".to_string());
     let api_secret = std::env::var("LIVEKIT_API_SECRET")
         .unwrap_or_else(|_| "devsecret_agora_local_development_key_32chars".to_string());
@@ -85,7 +209,33 @@ async fn get_voice_token(
 
     // use the matrix room id as the livekit room name (sanitized)
     // strip leading ! and replace : with _ for livekit compatibility
-    let room_name = sanitize_room_name(&req.room_id);
+    let room_name = match req.call_id.as_deref() {
+        Some(call_id) => call_room_name(call_id).ok_or_else(|| {
+            agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "call_id may only use letters, digits, - and _")
+        })?,
+        None => sanitize_room_name(&req.room_id),
+    };
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    // the livekit identity
+    let user_id = auth.user_id;
+    let room_state = matrix
+        .get_room_state(req.room_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+    let access = VoiceAccess::from_state(&room_state, &user_id);
+    if !access.joined {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "join the channel before connecting to voice"));
+    }
+    if access.muted {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "you are muted in this channel"));
+    }
+
+    // channel voice settings double as publisher hints and livekit room limits
+    let settings = read_voice_settings(&matrix, &req.room_id).await;
+    check_user_limit(&state, &room_state, &room_name, &user_id, access.level).await?;
+    ensure_livekit_room(&state, &room_name, &req.room_id, &settings).await;
 
     // token valid for 6 hours
     let exp = (std::time::SystemTime::now()
@@ -96,15 +246,17 @@ async fn get_voice_token(
     let claims = LiveKitClaims {
         exp,
         iss: api_key.clone(),
-        sub: req.user_id.clone(), // participant identity
+        sub: user_id, // participant identity
         video: VideoGrant {
             room_join: Some(true),
             room: Some(room_name.clone()),
-            can_publish: Some(true),
+            can_publish: Some(access.can_publish),
             can_subscribe: Some(true),
             can_publish_data: Some(true),
+            can_publish_sources: access.publish_sources(),
             room_admin: None,
             room_list: None,
+            room_create: None,
         },
         name: req.display_name,
     };
@@ -113,77 +265,77 @@ async fn get_voice_token(
     let key = jsonwebtoken::EncodingKey::from_secret(api_secret.as_bytes());
 
     match jsonwebtoken::encode(&header, &claims, &key) {
-        Ok(token) => Ok(Json(VoiceTokenResponse { token, livekit_url })),
+        Ok(token) => Ok(Json(VoiceTokenResponse { token, livekit_url, settings })),
         Err(e) => {
             tracing::error!("failed to generate livekit token: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
         }
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/voice/participants",
+    tag = "voice",
+    params(VoiceParticipantsQuery),
+    responses((status = 200, body = VoiceParticipantsResponse), super::StatusErrors)
+)]
 async fn get_voice_participants(
-    _state: State<Arc<AppState>>,
+    state: State<Arc<AppState>>,
     Query(params): Query<VoiceParticipantsQuery>,
 ) -> Result<Json<VoiceParticipantsResponse>, StatusCode> {
-    let api_key = std::env::var("LIVEKIT_API_KEY").unwrap_or_else(|_| "devkey".to_string());
-    let api_secret = std::env::var("LIVEKIT_API_SECRET")
-        .unwrap_or_else(|_| "devsecret_agora_local_development_key_32chars".to_string());
-    let livekit_http = std::env::var("LIVEKIT_HTTP_URL")
-        .unwrap_or_else(|_| "http://localhost:7880".to_string());
-
-    let room_name = sanitize_room_name(&params.room_name);
+    let participants = list_participants(&state, &sanitize_room_name(&params.room_name)).await;
+    Ok(Json(VoiceParticipantsResponse { participants: participants.into_iter().map(Connected::into_info).collect() }))
+}
 
-    // generate an admin token to call the livekit rest api
-    let admin_token = match make_admin_token(&api_key, &api_secret, &room_name) {
-        Ok(t) => t,
-        Err(e) => {
-            tracing::error!("failed to make admin token: {}", e);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-    let url = format!("{}/twirp/livekit.RoomService/ListParticipants", livekit_http);
+/// who is connected to a livekit room: what the webhooks have told us, or
+/// livekit itself when we aren't tracking the room (no redis, nobody joined
+/// since startup, or the last person left)
+async fn list_participants(state: &AppState, room_name: &str) -> Vec<Connected> {
+    match tracked_connected(state, room_name).await {
+        Some(connected) => connected,
+        None => poll_connected(state, room_name).await,
+    }
+}
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", admin_token))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({ "room": room_name }))
-        .send()
-        .await;
+async fn poll_connected(state: &AppState, room_name: &str) -> Vec<Connected> {
+    poll_livekit(state, room_name).await.into_iter().filter_map(Connected::from_livekit).collect()
+}
 
-    match resp {
-        Ok(r) if r.status().is_success() => {
-            let body: serde_json::Value = r.json().await.unwrap_or_default();
-            let participants = body["participants"]
-                .as_array()
-                .unwrap_or(&vec![])
-                .iter()
-                .filter_map(|p| p["identity"].as_str().map(String::from))
-                .collect();
-            Ok(Json(VoiceParticipantsResponse { participants }))
-        }
+/// ask livekit who is in a room. a room livekit doesn't have (nobody joined
+/// yet) or livekit being unreachable reads as empty rather than an error.
+async fn poll_livekit(state: &AppState, room_name: &str) -> Vec<LiveKitParticipant> {
+    match room_service(state, "ListParticipants", room_name, serde_json::json!({ "room": room_name })).await {
+        Ok(r) if r.status().is_success() => match r.json::<ListParticipantsResponse>().await {
+            Ok(body) => body.participants,
+            Err(e) => {
+                tracing::warn!("unreadable livekit participants for {}: {}", room_name, e);
+                vec![]
+            }
+        },
         Ok(r) => {
             let status = r.status().as_u16();
-            // 404 = room doesn't exist yet (no one joined) — normal, return empty
+            // 404 = room doesn't exist yet (no one joined) — normal
             // 401 = bad jwt or livekit just restarted — log at debug, not warn
-            if status == 404 || status == 401 {
-                if status == 401 {
-                    tracing::debug!("livekit participants 401 — jwt may be stale or livekit restarted");
-                }
-                return Ok(Json(VoiceParticipantsResponse { participants: vec![] }));
+            match status {
+                404 => {}
+                401 => tracing::debug!("livekit participants 401 — jwt may be stale or livekit restarted"),
+                _ => tracing::warn!("livekit list participants returned unexpected {}", status),
             }
-            tracing::warn!("livekit list participants returned unexpected {}", status);
-            Ok(Json(VoiceParticipantsResponse { participants: vec![] }))
+            vec![]
         }
         Err(e) => {
-            // livekit unreachable — return empty list rather than 500
             tracing::warn!("livekit unreachable for participants: {}", e);
-            Ok(Json(VoiceParticipantsResponse { participants: vec![] }))
+            vec![]
         }
     }
 }
 
+/// how many people are in a matrix room's voice channel right now
+pub async fn participant_count(state: &AppState, matrix_room_id: &str) -> u32 {
+    list_participants(state, &sanitize_room_name(matrix_room_id)).await.len() as u32
+}
+
 /// generate a short-lived admin jwt for livekit rest api calls.
 /// livekit requires: iss = api_key, sub = identity, video grant with roomAdmin + room name.
 fn make_admin_token(api_key: &str, api_secret: &str, room_name: &str) -> Result<String, jsonwebtoken::errors::Error> {
@@ -202,8 +354,11 @@ fn make_admin_token(api_key: &str, api_secret: &str, room_name: &str) -> Result<
             can_publish: None,
             can_subscribe: None,
             can_publish_data: None,
+            can_publish_sources: None,
             room_admin: Some(true),
-            room_list: None, // not needed when room is specified
+            // list + create let us provision the room before anyone joins
+            room_list: Some(true),
+            room_create: Some(true),
         },
         name: None,
     };
@@ -213,151 +368,1839 @@ fn make_admin_token(api_key: &str, api_secret: &str, room_name: &str) -> Result<
     jsonwebtoken::encode(&header, &claims, &key)
 }
 
-// ── call signaling ────────────────────────────────────────────────────────────
-// calls are signaled via special Matrix messages (msgtype: agora.call)
-// the sync loop on each client detects these and triggers the incoming call ui
+// ── user limit ────────────────────────────────────────────────────────────────
+// a voice channel can cap how many people are in it at once (user_limit in its
+// agora.room.type event, next to type: "voice"). unlike max_participants in the
+// voice settings, which livekit holds everyone to, moderators can still join a
+// full channel.
 
-#[derive(Debug, Deserialize)]
-pub struct CallEventRequest {
-    pub access_token: String,
-    /// the matrix dm room id to send the event into
-    pub room_id: String,
-    /// "ring" | "accept" | "cancel"
-    pub action: String,
-    /// unique id for this call session — matches ring/accept/cancel together
-    pub call_id: String,
-    pub from_user_id: String,
-    pub display_name: Option<String>,
+/// room power needed to join a voice channel past its user limit
+const USER_LIMIT_EXEMPT_POWER: i64 = 50;
+
+/// the user limit in an agora.room.type event — None when unlimited
+pub fn user_limit(room_type: &serde_json::Value) -> Option<u32> {
+    room_type
+        .get("user_limit")
+        .and_then(|v| v.as_u64())
+        .filter(|limit| *limit > 0)
+        .map(|limit| u32::try_from(limit).unwrap_or(u32::MAX))
 }
 
-async fn send_call_event(
-    state: State<Arc<AppState>>,
-    Json(req): Json<CallEventRequest>,
-) -> Result<StatusCode, StatusCode> {
-    use crate::matrix::client::MatrixClient;
+/// refuse a token for a full channel. someone already connected (rejoining,
+/// a second device) doesn't count against themselves.
+async fn check_user_limit(
+    state: &AppState,
+    room_state: &[RoomStateEvent],
+    room_name: &str,
+    identity: &str,
+    power: i64,
+) -> Result<(), Response> {
+    let limit = room_state
+        .iter()
+        .find(|e| e.event_type == "agora.room.type")
+        .and_then(|e| user_limit(&e.content));
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    if power >= USER_LIMIT_EXEMPT_POWER {
+        return Ok(());
+    }
 
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    let participants = list_participants(state, room_name).await;
+    if participants.iter().any(|p| p.identity == identity) || (participants.len() as u32) < limit {
+        return Ok(());
+    }
 
-    let content = serde_json::json!({
-        "msgtype": "agora.call",
-        "body": format!("[call {}]", req.action),
-        "call_id": req.call_id,
-        "action": req.action,
-        "from": req.from_user_id,
-        "display_name": req.display_name.unwrap_or_default(),
+    let body = serde_json::json!({
+        "errcode": "AGORA_ROOM_FULL",
+        "error": "room_full",
+        "user_limit": limit,
+        "participant_count": participants.len(),
     });
+    Err((StatusCode::FORBIDDEN, Json(body)).into_response())
+}
 
-    match matrix.send_message_content(req.room_id, content).await {
-        Ok(_) => Ok(StatusCode::OK),
-        Err(e) => {
-            tracing::error!("failed to send call event: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+// ── publish grants ────────────────────────────────────────────────────────────
+// a voice token's grants follow the room's power levels: anyone who can post
+// in the channel can talk (on a stage, only its speakers), everyone else listens. screen sharing can be held
+// to a higher level through its own power-levels entry.
+
+/// power-levels `events` entry gating screen sharing — defaults to events_default
+pub const SCREEN_SHARE_EVENT_TYPE: &str = "agora.voice.screen_share";
+
+/// track sources a token limited to camera and microphone may publish
+const NON_SCREEN_SOURCES: [&str; 2] = ["camera", "microphone"];
+
+/// what the caller may do in a voice channel
+#[derive(Debug)]
+struct VoiceAccess {
+    joined: bool,
+    level: i64,
+    /// a negative power level — below any default, so only ever given on purpose
+    muted: bool,
+    can_publish: bool,
+    can_screen_share: bool,
+}
+
+impl VoiceAccess {
+    fn from_state(room_state: &[RoomStateEvent], user_id: &str) -> Self {
+        let content = |event_type: &str, state_key: &str| {
+            room_state
+                .iter()
+                .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(state_key))
+                .map(|e| e.content.clone())
+                .unwrap_or_default()
+        };
+        let joined = content("m.room.member", user_id)["membership"] == "join";
+        let power = content("m.room.power_levels", "");
+        let level = power["users"][user_id]
+            .as_i64()
+            .or_else(|| power["users_default"].as_i64())
+            .unwrap_or(0);
+        let events_default = power["events_default"].as_i64().unwrap_or(0);
+        let screen_share = power["events"][SCREEN_SHARE_EVENT_TYPE].as_i64().unwrap_or(events_default);
+        // on a stage only the chosen speakers talk
+        let on_stage = !is_stage(room_state) || StageInfo::from_state(room_state).speakers.iter().any(|s| s == user_id);
+        Self {
+            joined,
+            level,
+            muted: level < 0,
+            can_publish: level >= events_default && on_stage,
+            can_screen_share: level >= screen_share,
+        }
+    }
+
+    /// None lets a publisher use every source
+    fn publish_sources(&self) -> Option<Vec<String>> {
+        if !self.can_publish || self.can_screen_share {
+            return None;
         }
+        Some(NON_SCREEN_SOURCES.iter().map(|s| s.to_string()).collect())
     }
 }
 
-// ── vibe rooms ────────────────────────────────────────────────────────────────
-// vibe is stored as a matrix state event (agora.vibe) on the voice channel room.
-// any participant can set it; everyone polling /voice/vibe sees the change.
+// ── moderation ────────────────────────────────────────────────────────────────
+// moderators can server-mute someone's microphone or drop them from a voice
+// channel. livekit does the work; the room gets an agora.voice.moderation
+// event so other clients can show "X was muted by Y".
 
-#[derive(Debug, Deserialize)]
-pub struct VibeQuery {
-    pub access_token: String,
+/// room power needed to mute or disconnect someone
+const VOICE_MODERATOR_POWER: i64 = 50;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct VoiceModerateRequest {
     pub room_id: String,
+    pub target_user_id: String,
+    pub action: VoiceModerationAction,
 }
 
-#[derive(Debug, Serialize)]
-pub struct VibeResponse {
-    pub vibe: String, // "none" | "rain" | "lofi" | "campfire" | "space"
-    pub set_by: Option<String>,
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum VoiceModerationAction {
+    Mute,
+    Unmute,
+    Disconnect,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct SetVibeRequest {
-    pub access_token: String,
+/// the caller's user id and power level in a room
+async fn caller_power(matrix: &MatrixClient, room_id: &str) -> Result<(String, i64), MatrixError> {
+    let user_id = matrix.whoami().await?.user_id;
+    let power = matrix.get_power_levels(room_id.to_string()).await?;
+    let level = power.users.as_ref()
+        .and_then(|users| users.get(&user_id).copied())
+        .or(power.users_default)
+        .unwrap_or(0);
+    Ok((user_id, level))
+}
+
+fn not_in_voice() -> Response {
+    agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "that user isn't in this voice channel")
+}
+
+fn livekit_unavailable(detail: impl std::fmt::Display) -> Response {
+    tracing::warn!("livekit moderation call failed: {}", detail);
+    agora_error(StatusCode::BAD_GATEWAY, "M_UNKNOWN", "the voice server couldn't be reached")
+}
+
+/// mute or unmute every audio track a participant publishes
+async fn set_audio_muted(state: &AppState, room_name: &str, identity: &str, muted: bool) -> Result<(), Response> {
+    let participant = room_service(state, "GetParticipant", room_name, serde_json::json!({ "room": room_name, "identity": identity }))
+        .await
+        .map_err(livekit_unavailable)?;
+    if participant.status() == StatusCode::NOT_FOUND {
+        return Err(not_in_voice());
+    }
+    if !participant.status().is_success() {
+        return Err(livekit_unavailable(participant.status()));
+    }
+    let participant: serde_json::Value = participant.json().await.map_err(livekit_unavailable)?;
+
+    // AUDIO is the zero value of the track type, so it may be left out
+    let audio_tracks = participant["tracks"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|t| t.get("type").is_none_or(|ty| ty == "AUDIO" || ty == 0))
+        .filter_map(|t| t["sid"].as_str());
+    for track_sid in audio_tracks {
+        let body = serde_json::json!({ "room": room_name, "identity": identity, "track_sid": track_sid, "muted": muted });
+        let response = room_service(state, "MutePublishedTrack", room_name, body).await.map_err(livekit_unavailable)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(not_in_voice());
+        }
+        if !response.status().is_success() {
+            return Err(livekit_unavailable(response.status()));
+        }
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/voice/moderate",
+    tag = "voice",
+    request_body = VoiceModerateRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn moderate_voice(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<VoiceModerateRequest>,
+) -> Result<StatusCode, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let (_, power) = caller_power(&matrix, &req.room_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+    if power < VOICE_MODERATOR_POWER {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "only moderators can do that"));
+    }
+
+    let room_name = sanitize_room_name(&req.room_id);
+    match req.action {
+        VoiceModerationAction::Mute => set_audio_muted(&state, &room_name, &req.target_user_id, true).await?,
+        VoiceModerationAction::Unmute => set_audio_muted(&state, &room_name, &req.target_user_id, false).await?,
+        VoiceModerationAction::Disconnect => {
+            let body = serde_json::json!({ "room": room_name, "identity": req.target_user_id });
+            let response = room_service(&state, "RemoveParticipant", &room_name, body).await.map_err(livekit_unavailable)?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Err(not_in_voice());
+            }
+            if !response.status().is_success() {
+                return Err(livekit_unavailable(response.status()));
+            }
+        }
+    }
+
+    // the action already happened, so a failed record is only logged
+    let content = serde_json::json!({ "action": req.action, "user_id": req.target_user_id });
+    if let Err(e) = matrix.send_event(&req.room_id, "agora.voice.moderation", content).await {
+        tracing::warn!("failed to record voice moderation in {}: {}", req.room_id, e);
+    }
+    Ok(StatusCode::OK)
+}
+
+// ── stage channels ────────────────────────────────────────────────────────────
+// a stage is a voice channel where only chosen speakers talk and everyone else
+// listens. moderators keep the speaker list in agora.stage.speakers; members
+// raise their hand in agora.stage.requests. the two are separate events
+// because members can't write moderator-only state — stage rooms are created
+// with agora.stage.requests open to everyone instead.
+
+pub const STAGE_SPEAKERS_EVENT_TYPE: &str = "agora.stage.speakers";
+pub const STAGE_REQUESTS_EVENT_TYPE: &str = "agora.stage.requests";
+
+/// speakers and raised hands, as read from the room state
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct StageInfo {
+    pub speakers: Vec<String>,
+    pub requests: Vec<String>,
+    /// people connected who aren't speakers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_count: Option<u32>,
+}
+
+impl StageInfo {
+    fn from_state(room_state: &[RoomStateEvent]) -> Self {
+        let list = |event_type: &str, field: &str| -> Vec<String> {
+            room_state
+                .iter()
+                .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+                .and_then(|e| serde_json::from_value(e.content[field].clone()).ok())
+                .unwrap_or_default()
+        };
+        let speakers = list(STAGE_SPEAKERS_EVENT_TYPE, "speakers");
+        let requests = list(STAGE_REQUESTS_EVENT_TYPE, "requests")
+            .into_iter()
+            .filter(|user_id| !speakers.contains(user_id))
+            .collect();
+        Self { speakers, requests, audience_count: None }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StageQuery {
+    pub room_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct StageSpeakersRequest {
     pub room_id: String,
-    /// the vibe id to set — must be one of: none, rain, lofi, campfire, space
-    pub vibe: String,
     pub user_id: String,
+    pub action: StageSpeakerAction,
 }
 
-async fn get_vibe(
-    state: State<Arc<AppState>>,
-    Query(params): Query<VibeQuery>,
-) -> Result<Json<VibeResponse>, StatusCode> {
-    use crate::matrix::client::MatrixClient;
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StageSpeakerAction {
+    Add,
+    Remove,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct StageHandRequest {
+    pub room_id: String,
+}
 
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(params.access_token);
+fn is_stage(room_state: &[RoomStateEvent]) -> bool {
+    room_state
+        .iter()
+        .any(|e| e.event_type == "agora.room.type" && e.content["type"] == "stage")
+}
 
-    // read the agora.vibe state event from the room
+/// let every member raise their hand in a new stage channel
+pub async fn open_stage_requests(matrix: &MatrixClient, room_id: &str) -> Result<(), MatrixError> {
     let url = format!(
-        "{}/_matrix/client/v3/rooms/{}/state/agora.vibe/",
-        state.homeserver_url,
-        urlencoding_encode(&params.room_id)
+        "{}/rooms/{}/state/m.room.power_levels/",
+        matrix.client_api_base().await,
+        encode_path_segment(room_id)
     );
+    let mut power = matrix.get_raw(&url).await?;
+    power["events"][STAGE_REQUESTS_EVENT_TYPE] = serde_json::json!(0);
+    matrix
+        .send_state_event(room_id.to_string(), "m.room.power_levels".to_string(), "".to_string(), power)
+        .await?;
+    Ok(())
+}
 
-    let resp = matrix.get_raw(&url).await;
-    match resp {
-        Ok(body) => {
-            let vibe = body["vibe"].as_str().unwrap_or("none").to_string();
-            let set_by = body["set_by"].as_str().map(String::from);
-            Ok(Json(VibeResponse { vibe, set_by }))
+/// the stage as it is now, for a joined member
+async fn stage_info(state: &AppState, matrix: &MatrixClient, room_id: &str) -> Result<StageInfo, Response> {
+    let room_state = matrix
+        .get_room_state(room_id.to_string())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+    if !is_stage(&room_state) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "not a stage channel"));
+    }
+    let mut info = StageInfo::from_state(&room_state);
+    let participants = list_participants(state, &sanitize_room_name(room_id)).await;
+    info.audience_count = Some(participants.iter().filter(|p| !info.speakers.contains(&p.identity)).count() as u32);
+    Ok(info)
+}
+
+#[utoipa::path(
+    get,
+    path = "/voice/stage",
+    tag = "voice",
+    params(StageQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = StageInfo), super::ErrorResponses)
+)]
+async fn get_stage(
+    State(state): State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<StageQuery>,
+) -> Result<Json<StageInfo>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    Ok(Json(stage_info(&state, &matrix, &params.room_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/voice/stage/speakers",
+    tag = "voice",
+    request_body = StageSpeakersRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = StageInfo), super::ErrorResponses)
+)]
+async fn set_stage_speaker(
+    State(state): State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<StageSpeakersRequest>,
+) -> Result<Json<StageInfo>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let (_, power) = caller_power(&matrix, &req.room_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+    if power < VOICE_MODERATOR_POWER {
+        return Err(agora_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "only moderators can choose speakers"));
+    }
+    stage_info(&state, &matrix, &req.room_id).await?;
+
+    let user_id = req.user_id.clone();
+    let action = req.action;
+    revision::update(&matrix, &req.room_id, STAGE_SPEAKERS_EVENT_TYPE, "", move |content| {
+        let mut speakers: Vec<String> = serde_json::from_value(content["speakers"].clone()).unwrap_or_default();
+        speakers.retain(|s| *s != user_id);
+        if let StageSpeakerAction::Add = action {
+            speakers.push(user_id);
         }
-        Err(_) => {
-            // no vibe set yet (404 from conduit) — return none
-            Ok(Json(VibeResponse { vibe: "none".to_string(), set_by: None }))
+        content["speakers"] = serde_json::json!(speakers);
+    })
+    .await
+    .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+
+    // a granted request is answered — take the hand down
+    if let StageSpeakerAction::Add = req.action {
+        let user_id = req.user_id.clone();
+        let lowered = revision::update(&matrix, &req.room_id, STAGE_REQUESTS_EVENT_TYPE, "", move |content| {
+            let mut requests: Vec<String> = serde_json::from_value(content["requests"].clone()).unwrap_or_default();
+            requests.retain(|r| *r != user_id);
+            content["requests"] = serde_json::json!(requests);
+        })
+        .await;
+        if let Err(e) = lowered {
+            tracing::warn!("failed to clear stage request in {}: {}", req.room_id, e);
         }
     }
+
+    Ok(Json(stage_info(&state, &matrix, &req.room_id).await?))
 }
 
-async fn set_vibe(
-    state: State<Arc<AppState>>,
-    Json(req): Json<SetVibeRequest>,
-) -> Result<StatusCode, StatusCode> {
-    use crate::matrix::client::MatrixClient;
+#[utoipa::path(
+    post,
+    path = "/voice/stage/request",
+    tag = "voice",
+    request_body = StageHandRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = StageInfo), super::ErrorResponses)
+)]
+async fn raise_hand(
+    State(state): State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<StageHandRequest>,
+) -> Result<Json<StageInfo>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+    let user_id = auth.user_id;
 
-    // validate vibe value server-side
-    let allowed = ["none", "rain", "lofi", "campfire", "space"];
-    if !allowed.contains(&req.vibe.as_str()) {
-        return Err(StatusCode::BAD_REQUEST);
+    let info = stage_info(&state, &matrix, &req.room_id).await?;
+    if info.speakers.contains(&user_id) || info.requests.contains(&user_id) {
+        return Ok(Json(info));
     }
 
-    let mut matrix = MatrixClient::new(state.homeserver_url.clone());
-    matrix.access_token = Some(req.access_token);
+    revision::update(&matrix, &req.room_id, STAGE_REQUESTS_EVENT_TYPE, "", move |content| {
+        let mut requests: Vec<String> = serde_json::from_value(content["requests"].clone()).unwrap_or_default();
+        requests.push(user_id);
+        content["requests"] = serde_json::json!(requests);
+    })
+    .await
+    .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
 
-    let content = serde_json::json!({
-        "vibe": req.vibe,
-        "set_by": req.user_id,
-    });
+    Ok(Json(stage_info(&state, &matrix, &req.room_id).await?))
+}
 
-    match matrix.send_state_event(req.room_id, "agora.vibe".to_string(), "".to_string(), content).await {
-        Ok(_) => Ok(StatusCode::OK),
+// ── livekit webhooks ──────────────────────────────────────────────────────────
+// livekit posts participant_joined / participant_left / room_finished to
+// /voice/webhook. we keep who is in each room in a redis hash
+// (voice:{room} → identity → join metadata) so listing participants doesn't
+// poll livekit, and push joins/leaves to websocket clients for the sidebar.
+
+/// the livekit webhook payload — only the fields we use
+#[derive(Debug, Deserialize)]
+struct WebhookEvent {
+    event: String,
+    room: Option<WebhookRoom>,
+    participant: Option<WebhookParticipant>,
+    track: Option<LiveKitTrack>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookRoom {
+    name: String,
+    /// what ensure_livekit_room set — json naming the matrix room
+    #[serde(default)]
+    metadata: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookParticipant {
+    identity: String,
+    #[serde(default)]
+    name: String,
+    /// unix seconds; protojson sends int64 as a string
+    #[serde(default, alias = "joinedAt")]
+    joined_at: serde_json::Value,
+}
+
+/// claims livekit signs each webhook with
+#[derive(Debug, Deserialize)]
+struct WebhookClaims {
+    /// base64 sha256 of the request body
+    sha256: String,
+}
+
+fn voice_key(room_name: &str) -> String {
+    format!("voice:{}", room_name)
+}
+
+/// the join metadata flag a track event changes, and what it changes it to
+fn track_flag(event: &str, track: &LiveKitTrack) -> Option<(&'static str, bool)> {
+    match event {
+        "track_muted" | "track_unmuted" if track.is_microphone() => Some(("muted", event == "track_muted")),
+        "track_muted" | "track_unmuted" if track.is_camera() => Some(("camera", event == "track_unmuted")),
+        "track_published" | "track_unpublished" if track.is_camera() => Some(("camera", event == "track_published")),
+        "track_published" | "track_unpublished" if track.is_screen_share() => {
+            Some(("screen_sharing", event == "track_published"))
+        }
+        _ => None,
+    }
+}
+
+/// check the webhook's Authorization jwt: signed with our api secret, issued
+/// for our api key, and carrying the hash of this exact body
+fn verify_webhook(headers: &HeaderMap, body: &str) -> bool {
+    let api_key = std::env::var("LIVEKIT_API_KEY").unwrap_or_else(|_| "devkey".to_string());
+    let api_secret = std::env::var("LIVEKIT_API_SECRET")
+        .unwrap_or_else(|_| "devsecret_agora_local_development_key_32chars".to_string());
+
+    let Some(token) = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+
+    let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+    validation.set_issuer(&[api_key]);
+    validation.set_required_spec_claims(&["exp", "iss"]);
+    let key = jsonwebtoken::DecodingKey::from_secret(api_secret.as_bytes());
+    let claims = match jsonwebtoken::decode::<WebhookClaims>(token, &key, &validation) {
+        Ok(data) => data.claims,
         Err(e) => {
-            tracing::error!("failed to set vibe: {}", e);
-            Err(StatusCode::BAD_REQUEST)
+            tracing::debug!("rejected livekit webhook token: {}", e);
+            return false;
+        }
+    };
+    claims.sha256 == STANDARD.encode(Sha256::digest(body.as_bytes()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/voice/webhook",
+    tag = "voice",
+    request_body(content = String, description = "a livekit webhook event, signed in the Authorization header"),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn livekit_webhook(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    if !verify_webhook(&headers, &body) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    let event: WebhookEvent = match serde_json::from_str(&body) {
+        Ok(event) => event,
+        Err(e) => {
+            tracing::warn!("unreadable livekit webhook: {}", e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    // events without a room (egress, ingress, ...) aren't tracked
+    let Some(room) = event.room else {
+        return StatusCode::OK;
+    };
+    let key = voice_key(&room.name);
+    let room_id = serde_json::from_str::<serde_json::Value>(&room.metadata)
+        .ok()
+        .and_then(|m| m["matrix_room_id"].as_str().map(String::from));
+    let publish = |action: &'static str, user_id: String| {
+        state.publish(WsEvent::Voice(VoiceEvent {
+            kind: "voice",
+            action,
+            room_id: room_id.clone(),
+            room_name: room.name.clone(),
+            user_id,
+        }));
+    };
+
+    let flag_change = event.track.as_ref().and_then(|t| track_flag(&event.event, t));
+    match (event.event.as_str(), event.participant, flag_change) {
+        ("participant_joined", Some(participant), _) => {
+            let joined_at = proto_u64(&participant.joined_at).unwrap_or_else(|| chrono::Utc::now().timestamp() as u64);
+            let metadata = serde_json::json!({ "name": participant.name, "joined_at": joined_at, "muted": false });
+            if let Some(mut redis) = state.get_redis().await {
+                let stored: redis::RedisResult<()> =
+                    redis.hset(&key, &participant.identity, metadata.to_string()).await;
+                if let Err(e) = stored {
+                    tracing::warn!("failed to record voice join in {}: {}", key, e);
+                }
+            }
+            publish("joined", participant.identity);
+        }
+        ("participant_left", Some(participant), _) => {
+            if let Some(mut redis) = state.get_redis().await {
+                let removed: redis::RedisResult<()> = redis.hdel(&key, &participant.identity).await;
+                if let Err(e) = removed {
+                    tracing::warn!("failed to record voice leave in {}: {}", key, e);
+                }
+            }
+            publish("left", participant.identity);
+        }
+        (_, Some(participant), Some((flag, value))) => {
+            // only the sidebar's icons care, so nothing is published
+            if let Some(mut redis) = state.get_redis().await {
+                let stored: Option<String> = redis.hget(&key, &participant.identity).await.ok().flatten();
+                if let Some(mut metadata) = stored.and_then(|m| serde_json::from_str::<serde_json::Value>(&m).ok()) {
+                    metadata[flag] = serde_json::json!(value);
+                    let updated: redis::RedisResult<()> =
+                        redis.hset(&key, &participant.identity, metadata.to_string()).await;
+                    if let Err(e) = updated {
+                        tracing::warn!("failed to record voice {} in {}: {}", flag, key, e);
+                    }
+                }
+            }
         }
+        ("room_finished", _, _) => {
+            // livekit doesn't send participant_left for whoever was still in
+            // the room, so say goodbye for them
+            if let Some(mut redis) = state.get_redis().await {
+                let identities: Vec<String> = redis.hkeys(&key).await.unwrap_or_default();
+                let _: redis::RedisResult<()> = redis.del(&key).await;
+                for identity in identities {
+                    publish("left", identity);
+                }
+            }
+        }
+        _ => {}
     }
+    StatusCode::OK
 }
 
-/// url-encode a matrix room id for use in a path segment
-fn urlencoding_encode(s: &str) -> String {
-    s.chars().map(|c| match c {
-        '!' => "%21".to_string(),
-        ':' => "%3A".to_string(),
-        '.' => "%2E".to_string(),
-        '#' => "%23".to_string(),
-        _ => c.to_string(),
-    }).collect()
+// ── voice states ──────────────────────────────────────────────────────────────
+// the sidebar shows who is in each voice channel of a server without joining
+// any of them. /voice/states answers for every voice channel in a space at
+// once: from the webhook hashes where we have them, otherwise one ListRooms
+// and a ListParticipants per active room. the answer is cached in redis for a
+// few seconds so every open sidebar can poll it.
+
+const VOICE_STATES_TTL_SECS: u64 = 5;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VoiceStatesQuery {
+    pub space_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VoiceStatesResponse {
+    pub space_id: String,
+    /// every voice and stage channel in the space, in walk order
+    pub channels: Vec<VoiceChannelState>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VoiceChannelState {
+    pub room_id: String,
+    pub participants: Vec<VoiceParticipantState>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VoiceParticipantState {
+    pub user_id: String,
+    pub display_name: String,
+    /// microphone muted
+    pub muted: bool,
+}
+
+/// someone connected to a livekit room, as the webhooks or livekit describe them
+struct Connected {
+    identity: String,
+    /// the name their token was issued with
+    name: String,
+    muted: bool,
+    screen_sharing: bool,
+    camera_on: bool,
+    /// unix seconds
+    joined_at: Option<u64>,
 }
 
+impl Connected {
+    /// muted when their microphone track is; sharing or on camera when such a
+    /// track is published and not muted
+    fn from_livekit(participant: LiveKitParticipant) -> Option<Self> {
+        if participant.identity.is_empty() {
+            return None;
+        }
+        let tracks = &participant.tracks;
+        Some(Connected {
+            muted: tracks.iter().any(|t| t.is_microphone() && t.muted),
+            screen_sharing: tracks.iter().any(|t| t.is_screen_share() && !t.muted),
+            camera_on: tracks.iter().any(|t| t.is_camera() && !t.muted),
+            joined_at: proto_u64(&participant.joined_at),
+            identity: participant.identity,
+            name: participant.name,
+        })
+    }
+
+    fn into_info(self) -> ParticipantInfo {
+        ParticipantInfo {
+            display_name: if self.name.is_empty() { self.identity.clone() } else { self.name },
+            user_id: self.identity,
+            is_muted: self.muted,
+            is_screen_sharing: self.screen_sharing,
+            is_camera_on: self.camera_on,
+            joined_at: self.joined_at,
+        }
+    }
+}
+
+fn voice_states_key(space_id: &str) -> String {
+    format!("voice_states:{}", space_id)
+}
+
+/// what the webhooks have recorded for a room — None when it isn't tracked
+async fn tracked_connected(state: &AppState, room_name: &str) -> Option<Vec<Connected>> {
+    let mut redis = state.get_redis().await?;
+    let tracked: Vec<(String, String)> = redis.hgetall(voice_key(room_name)).await.ok()?;
+    if tracked.is_empty() {
+        return None;
+    }
+    let mut connected: Vec<Connected> = tracked
+        .into_iter()
+        .map(|(identity, metadata)| {
+            let metadata: serde_json::Value = serde_json::from_str(&metadata).unwrap_or_default();
+            Connected {
+                identity,
+                name: metadata["name"].as_str().unwrap_or_default().to_string(),
+                muted: metadata["muted"] == true,
+                screen_sharing: metadata["screen_sharing"] == true,
+                camera_on: metadata["camera"] == true,
+                joined_at: metadata["joined_at"].as_u64(),
+            }
+        })
+        .collect();
+    connected.sort_by(|a, b| a.identity.cmp(&b.identity));
+    Some(connected)
+}
+
+/// the livekit rooms among `room_names` that exist right now
+async fn active_rooms(state: &AppState, room_names: &[String]) -> Vec<String> {
+    match room_service(state, "ListRooms", "", serde_json::json!({ "names": room_names })).await {
+        Ok(r) if r.status().is_success() => {
+            let body: serde_json::Value = r.json().await.unwrap_or_default();
+            body["rooms"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|room| room["name"].as_str().map(String::from))
+                .collect()
+        }
+        Ok(r) => {
+            tracing::debug!("livekit list rooms returned {}", r.status());
+            vec![]
+        }
+        Err(e) => {
+            tracing::warn!("livekit unreachable for rooms: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn is_voice_channel(room_state: &[RoomStateEvent]) -> bool {
+    room_state
+        .iter()
+        .any(|e| e.event_type == "agora.room.type" && matches!(e.content["type"].as_str(), Some("voice" | "stage")))
+}
+
+#[utoipa::path(
+    get,
+    path = "/voice/states",
+    tag = "voice",
+    params(VoiceStatesQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = VoiceStatesResponse), super::ErrorResponses)
+)]
+async fn get_voice_states(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<VoiceStatesQuery>,
+) -> Result<Json<VoiceStatesResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    // only members of the space see who is talking in it
+    matrix
+        .get_room_state(params.space_id.clone())
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::FORBIDDEN))?;
+
+    let cache_key = voice_states_key(&params.space_id);
+    if let Some(mut redis) = state.get_redis().await {
+        let cached: Option<String> = redis.get(&cache_key).await.ok().flatten();
+        if let Some(states) = cached.and_then(|c| serde_json::from_str(&c).ok()) {
+            return Ok(Json(states));
+        }
+    }
+
+    let channels: Vec<String> = walk_space(&matrix, &params.space_id, max_depth())
+        .await
+        .into_iter()
+        .filter(|node| is_voice_channel(&node.state))
+        .map(|node| node.room_id)
+        .collect();
+
+    let mut connected: Vec<Option<Vec<Connected>>> = Vec::with_capacity(channels.len());
+    for room_id in &channels {
+        connected.push(tracked_connected(&state, &sanitize_room_name(room_id)).await);
+    }
+    // rooms the webhooks don't know about: ask livekit, but only about the
+    // ones it has open
+    let untracked: Vec<String> = channels
+        .iter()
+        .zip(&connected)
+        .filter(|(_, c)| c.is_none())
+        .map(|(room_id, _)| sanitize_room_name(room_id))
+        .collect();
+    let mut polled: HashMap<String, Vec<Connected>> = HashMap::new();
+    if !untracked.is_empty() {
+        let active = active_rooms(&state, &untracked).await;
+        let participants = join_all(active.iter().map(|room_name| poll_connected(&state, room_name))).await;
+        polled.extend(active.into_iter().zip(participants));
+    }
+    let connected: Vec<Vec<Connected>> = channels
+        .iter()
+        .zip(connected)
+        .map(|(room_id, c)| c.or_else(|| polled.remove(&sanitize_room_name(room_id))).unwrap_or_default())
+        .collect();
+
+    let user_ids: Vec<String> = connected.iter().flatten().map(|c| c.identity.clone()).collect();
+    let names = state.profiles.display_names(&matrix, &user_ids).await;
+    let channels = channels
+        .into_iter()
+        .zip(connected)
+        .map(|(room_id, connected)| VoiceChannelState {
+            room_id,
+            participants: connected
+                .into_iter()
+                .map(|c| {
+                    // the matrix display name, else the name livekit was given
+                    let display_name = match names.get(&c.identity) {
+                        Some(name) => name.clone(),
+                        None if !c.name.is_empty() => c.name,
+                        None => c.identity.clone(),
+                    };
+                    VoiceParticipantState { user_id: c.identity, display_name, muted: c.muted }
+                })
+                .collect(),
+        })
+        .collect();
+    let states = VoiceStatesResponse { space_id: params.space_id, channels };
+
+    if let Some(mut redis) = state.get_redis().await {
+        if let Ok(json) = serde_json::to_string(&states) {
+            let _: redis::RedisResult<()> = redis.set_ex(&cache_key, json, VOICE_STATES_TTL_SECS).await;
+        }
+    }
+    Ok(Json(states))
+}
+
+// ── afk channel ───────────────────────────────────────────────────────────────
+// a server can name an afk voice channel and a timeout in agora.server.meta.
+// whoever configures it registers the server in the afk_servers redis hash
+// along with their token, which the afk worker uses to walk the server and
+// post agora.voice.moved events. someone with no unmuted microphone for
+// longer than the timeout is moved to the afk channel by livekit (or dropped,
+// on livekit versions without MoveParticipant); the event tells their client
+// to reconnect there.
+
+const AFK_SERVERS_KEY: &str = "afk_servers";
+pub const DEFAULT_AFK_TIMEOUT_SECS: u64 = 300;
+/// the shortest and longest timeouts a server can pick
+pub const AFK_TIMEOUT_RANGE: std::ops::RangeInclusive<u64> = 60..=3600;
+const AFK_SWEEP_INTERVAL_SECS: u64 = 30;
+pub const VOICE_MOVED_EVENT_TYPE: &str = "agora.voice.moved";
+
+/// what the afk worker needs to know about a server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AfkConfig {
+    pub afk_channel_id: String,
+    pub afk_timeout_secs: u64,
+    /// the token of whoever configured it — the worker acts as them
+    pub access_token: String,
+}
+
+/// start or stop watching a server for idle voice participants
+pub async fn register_afk(state: &AppState, server_id: &str, config: Option<&AfkConfig>) {
+    let Some(mut redis) = state.get_redis().await else {
+        return;
+    };
+    let stored: redis::RedisResult<()> = match config {
+        Some(config) => match serde_json::to_string(config) {
+            Ok(json) => redis.hset(AFK_SERVERS_KEY, server_id, json).await,
+            Err(_) => return,
+        },
+        None => redis.hdel(AFK_SERVERS_KEY, server_id).await,
+    };
+    if let Err(e) = stored {
+        tracing::warn!("failed to register afk channel of {}: {}", server_id, e);
+    }
+}
+
+fn afk_idle_key(room_name: &str) -> String {
+    format!("afk_idle:{}", room_name)
+}
+
+/// someone in a livekit room, as ListParticipants describes them
+struct VoicePresence {
+    identity: String,
+    joined_at: u64,
+    /// publishing an unmuted microphone
+    audible: bool,
+    /// has published a microphone at all
+    published_audio: bool,
+}
+
+impl VoicePresence {
+    fn from_livekit(participant: LiveKitParticipant) -> Option<Self> {
+        if participant.identity.is_empty() {
+            return None;
+        }
+        let microphones: Vec<&LiveKitTrack> = participant.tracks.iter().filter(|t| t.is_microphone()).collect();
+        Some(VoicePresence {
+            joined_at: proto_u64(&participant.joined_at).unwrap_or(0),
+            audible: microphones.iter().any(|t| !t.muted),
+            published_audio: !microphones.is_empty(),
+            identity: participant.identity,
+        })
+    }
+}
+
+async fn list_presence(state: &AppState, room_name: &str) -> Vec<VoicePresence> {
+    poll_livekit(state, room_name).await.into_iter().filter_map(VoicePresence::from_livekit).collect()
+}
+
+/// move someone to another livekit room, or drop them where livekit can't move
+async fn move_participant(state: &AppState, room_name: &str, identity: &str, destination: &str) -> bool {
+    let body = serde_json::json!({ "room": room_name, "identity": identity, "destination_room": destination });
+    match room_service(state, "MoveParticipant", room_name, body).await {
+        Ok(r) if r.status().is_success() => return true,
+        Ok(r) => tracing::debug!("livekit MoveParticipant returned {} — disconnecting instead", r.status()),
+        Err(e) => {
+            tracing::warn!("livekit unreachable for afk move: {}", e);
+            return false;
+        }
+    }
+    let body = serde_json::json!({ "room": room_name, "identity": identity });
+    matches!(room_service(state, "RemoveParticipant", room_name, body).await, Ok(r) if r.status().is_success())
+}
+
+/// background loop that moves idle participants to their server's afk channel
+pub async fn run_afk_worker(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(AFK_SWEEP_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let moved = move_idle_participants(&state, unix_now()).await;
+        if moved > 0 {
+            tracing::info!("moved {} idle voice participants to afk channels", moved);
+        }
+    }
+}
+
+/// one sweep over every registered server; returns how many people were moved
+pub async fn move_idle_participants(state: &AppState, now: u64) -> usize {
+    let Some(mut redis) = state.get_redis().await else {
+        return 0;
+    };
+    let servers: Vec<(String, String)> = redis.hgetall(AFK_SERVERS_KEY).await.unwrap_or_default();
+    let mut moved = 0;
+
+    for (server_id, config) in servers {
+        let Ok(config) = serde_json::from_str::<AfkConfig>(&config) else {
+            continue;
+        };
+        let mut matrix = state.matrix();
+        matrix.access_token = Some(config.access_token.clone());
+        let afk_room = sanitize_room_name(&config.afk_channel_id);
+
+        let channels = walk_space(&matrix, &server_id, max_depth()).await;
+        for channel in channels.iter().filter(|n| is_voice_channel(&n.state) && n.room_id != config.afk_channel_id) {
+            let room_name = sanitize_room_name(&channel.room_id);
+            let idle_key = afk_idle_key(&room_name);
+            let present = list_presence(state, &room_name).await;
+            let mut idle_since: HashMap<String, u64> = redis
+                .hgetall::<_, Vec<(String, String)>>(&idle_key)
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(identity, since)| Some((identity, since.parse().ok()?)))
+                .collect();
+
+            let mut still_idle: Vec<(String, u64)> = Vec::new();
+            for person in present {
+                if person.audible {
+                    continue;
+                }
+                // never having turned the mic on counts from when they joined
+                let since = idle_since.remove(&person.identity).unwrap_or_else(|| {
+                    if person.published_audio || person.joined_at == 0 { now } else { person.joined_at.min(now) }
+                });
+                if now.saturating_sub(since) < config.afk_timeout_secs {
+                    still_idle.push((person.identity, since));
+                    continue;
+                }
+                if !move_participant(state, &room_name, &person.identity, &afk_room).await {
+                    still_idle.push((person.identity, since));
+                    continue;
+                }
+                moved += 1;
+                let content = serde_json::json!({
+                    "user_id": person.identity,
+                    "room_id": channel.room_id,
+                    "afk_channel_id": config.afk_channel_id,
+                });
+                if let Err(e) = matrix.send_event(&channel.room_id, VOICE_MOVED_EVENT_TYPE, content).await {
+                    tracing::warn!("failed to announce afk move in {}: {}", channel.room_id, e);
+                }
+            }
+
+            // whoever left or spoke up is forgotten
+            let _: redis::RedisResult<()> = redis.del(&idle_key).await;
+            for (identity, since) in still_idle {
+                let _: redis::RedisResult<()> = redis.hset(&idle_key, identity, since).await;
+            }
+        }
+    }
+    moved
+}
+
+// ── voice settings ────────────────────────────────────────────────────────────
+// per-channel audio hints stored as an agora.voice.settings state event on the
+// voice channel room. clients get them with their token and configure their
+// livekit publisher; max_participants is also pushed to livekit itself — when
+// the room is created, and to a room already running when it changes — so the
+// limit holds even for clients that ignore the hints.
+
+const ALLOWED_CODECS: [&str; 3] = ["opus", "pcmu", "pcma"];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct VoiceSettings {
+    /// audio publish bitrate cap (opus supports 6–510 kbps)
+    pub max_bitrate_kbps: Option<u32>,
+    /// discontinuous transmission — saves bandwidth during silence
+    pub dtx: Option<bool>,
+    /// redundant audio encoding — helps on lossy connections
+    pub red: Option<bool>,
+    pub preferred_codec: Option<String>,
+    /// enforced by livekit; None or 0 = unlimited
+    pub max_participants: Option<u32>,
+}
+
+impl VoiceSettings {
+    fn is_valid(&self) -> bool {
+        self.max_bitrate_kbps.is_none_or(|b| (6..=510).contains(&b))
+            && self.preferred_codec.as_deref().is_none_or(|c| ALLOWED_CODECS.contains(&c))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetVoiceSettingsRequest {
+    pub room_id: String,
+    pub settings: VoiceSettings,
+}
+
+async fn read_voice_settings(matrix: &crate::matrix::client::MatrixClient, room_id: &str) -> VoiceSettings {
+    let url = format!(
+        "{}/rooms/{}/state/agora.voice.settings/",
+        matrix.client_api_base().await,
+        encode_path_segment(room_id)
+    );
+    // no settings event yet (404) — defaults, i.e. no hints
+    matrix.get_raw(&url).await.ok()
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+#[utoipa::path(
+    get,
+    path = "/voice/settings",
+    tag = "voice",
+    params(VibeQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = VoiceSettings), super::StatusErrors)
+)]
+async fn get_voice_settings(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<VibeQuery>,
+) -> Result<Json<VoiceSettings>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    Ok(Json(read_voice_settings(&matrix, &params.room_id).await))
+}
+
+#[utoipa::path(
+    post,
+    path = "/voice/settings",
+    tag = "voice",
+    request_body = SetVoiceSettingsRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn set_voice_settings(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetVoiceSettingsRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if !req.settings.is_valid() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let content = serde_json::to_value(&req.settings).unwrap_or_default();
+    if let Err(e) = matrix.send_state_event(req.room_id.clone(), "agora.voice.settings".to_string(), "".to_string(), content).await {
+        tracing::error!("failed to set voice settings: {}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // push the change to a running livekit room — 404 just means nobody is connected,
+    // and the next token creates the room with the new settings
+    let room_name = sanitize_room_name(&req.room_id);
+    let metadata = livekit_room_metadata(&req.room_id, &req.settings);
+    let body = serde_json::json!({ "room": room_name, "metadata": metadata });
+    let running = match room_service(&state, "UpdateRoomMetadata", &room_name, body).await {
+        Ok(r) if r.status().is_success() => true,
+        Ok(r) if r.status().as_u16() == 404 => false,
+        Ok(r) => {
+            tracing::warn!("livekit UpdateRoomMetadata returned {}", r.status());
+            false
+        }
+        Err(e) => {
+            tracing::debug!("livekit unreachable for metadata update: {}", e);
+            false
+        }
+    };
+    // CreateRoom on a room that exists updates its limit. livekit skips a 0 there,
+    // so a lifted limit only lifts once the room has emptied out
+    if let Some(limit) = req.settings.max_participants.filter(|l| running && *l > 0) {
+        let body = serde_json::json!({ "name": room_name, "max_participants": limit, "metadata": metadata });
+        match room_service(&state, "CreateRoom", &room_name, body).await {
+            Ok(r) if r.status().is_success() => {}
+            Ok(r) => tracing::warn!("livekit CreateRoom returned {} for a limit update", r.status()),
+            Err(e) => tracing::debug!("livekit unreachable for limit update: {}", e),
+        }
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// room metadata every participant can read — links the livekit room back to matrix
+fn livekit_room_metadata(matrix_room_id: &str, settings: &VoiceSettings) -> String {
+    serde_json::json!({
+        "matrix_room_id": matrix_room_id,
+        "voice_settings": settings,
+    })
+    .to_string()
+}
+
+/// create the livekit room with our limits and metadata if it doesn't exist yet.
+/// best effort: livekit creates rooms on first join anyway, just without the limits.
+async fn ensure_livekit_room(state: &AppState, room_name: &str, matrix_room_id: &str, settings: &VoiceSettings) {
+    let exists = match room_service(state, "ListRooms", room_name, serde_json::json!({ "names": [room_name] })).await {
+        Ok(r) if r.status().is_success() => {
+            let body: serde_json::Value = r.json().await.unwrap_or_default();
+            body["rooms"].as_array().is_some_and(|rooms| !rooms.is_empty())
+        }
+        Ok(r) => {
+            tracing::debug!("livekit ListRooms returned {}", r.status());
+            return;
+        }
+        Err(e) => {
+            tracing::debug!("livekit unreachable, skipping room provisioning: {}", e);
+            return;
+        }
+    };
+    if exists {
+        return;
+    }
+
+    let body = serde_json::json!({
+        "name": room_name,
+        "max_participants": settings.max_participants.unwrap_or(0),
+        "metadata": livekit_room_metadata(matrix_room_id, settings),
+    });
+    match room_service(state, "CreateRoom", room_name, body).await {
+        Ok(r) if r.status().is_success() => {}
+        Ok(r) => tracing::warn!("livekit CreateRoom returned {}", r.status()),
+        Err(e) => tracing::debug!("livekit CreateRoom failed: {}", e),
+    }
+}
+
+/// call a livekit RoomService twirp method with an admin token scoped to `room_name`
+async fn room_service(
+    state: &AppState,
+    method: &str,
+    room_name: &str,
+    body: serde_json::Value,
+) -> Result<reqwest::Response, String> {
+    let api_key = std::env::var("LIVEKIT_API_KEY").unwrap_or_else(|_| "devkey".to_string());
+    let api_secret = std::env::var("LIVEKIT_API_SECRET")
+        .unwrap_or_else(|_| "devsecret_agora_local_development_key_32chars".to_string());
+    let livekit_http = std::env::var("LIVEKIT_HTTP_URL")
+        .unwrap_or_else(|_| "http://localhost:7880".to_string());
+
+    let admin_token = make_admin_token(&api_key, &api_secret, room_name).map_err(|e| e.to_string())?;
+    state
+        .http
+        .post(format!("{}/twirp/livekit.RoomService/{}", livekit_http, method))
+        .header("Authorization", format!("Bearer {}", admin_token))
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// ── call signaling ────────────────────────────────────────────────────────────
+// calls are signaled via special Matrix messages (msgtype: agora.call)
+// the sync loop on each client detects these and triggers the incoming call ui.
+// a ring is also tracked in redis until it's answered or cancelled; one left
+// ringing past CALL_RING_TIMEOUT_SECS gets a "timeout" event, sent with the
+// caller's token, which clients show as a missed call.
+// group dms can have several people in one call: who is in it is a redis set
+// per call_id, and every event carries the list so ringing ui can say
+// "Alice and 2 others are in a call".
+
+// default for CALL_RING_TIMEOUT_SECS
+const DEFAULT_RING_TIMEOUT_SECS: u64 = 45;
+// how often the timeout worker looks for expired rings
+const RING_SWEEP_INTERVAL_SECS: u64 = 5;
+// a call's participant set is dropped this long after its last change — as
+// long as a voice token lasts
+const CALL_MEMBERS_TTL_SECS: i64 = 6 * 3600;
+// most calls /voice/call/history returns, and how far back it looks for them
+const MAX_CALL_HISTORY: usize = 50;
+const CALL_HISTORY_PAGES: usize = 5;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CallEventRequest {
+    /// the matrix dm room id to send the event into
+    pub room_id: String,
+    /// "ring" | "accept" | "cancel", or "join" | "leave" for a group call already going
+    pub action: String,
+    /// unique id for this call session — matches ring/accept/cancel together
+    pub call_id: String,
+    pub display_name: Option<String>,
+    /// group calls: who the ring is for
+    #[serde(default)]
+    pub invited_user_ids: Vec<String>,
+    /// the same for each retry of this event, like /rooms/send's
+    pub txn_id: Option<String>,
+}
+
+/// an agora.call event's fields, as /sync and dm search surface them
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CallSignal {
+    pub call_id: String,
+    pub action: String,
+    pub from: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invited_user_ids: Vec<String>,
+    /// who is in the call after this event
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub participants: Vec<String>,
+}
+
+impl CallSignal {
+    pub fn from_content(content: &serde_json::Value) -> Option<Self> {
+        if content["msgtype"] != "agora.call" {
+            return None;
+        }
+        serde_json::from_value(content.clone()).ok()
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallParticipantsQuery {
+    pub call_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CallParticipantsResponse {
+    pub call_id: String,
+    pub participants: Vec<String>,
+}
+
+/// a ring waiting for an answer
+#[derive(Debug, Serialize, Deserialize)]
+struct RingSession {
+    room_id: String,
+    call_id: String,
+    from: String,
+    display_name: String,
+    /// the caller's token — the timeout event is sent as them
+    access_token: String,
+    /// unix seconds
+    expires_at: u64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallHistoryQuery {
+    pub room_id: String,
+    /// how many calls, newest first — default 20
+    pub limit: Option<usize>,
+}
+
+/// how a call ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CallOutcome {
+    /// still ringing
+    Ringing,
+    Completed,
+    /// the callee turned it down
+    Declined,
+    /// the caller hung up before an answer
+    Cancelled,
+    /// nobody answered before the ring timed out
+    Missed,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CallRecord {
+    pub call_id: String,
+    pub caller: String,
+    pub outcome: CallOutcome,
+    /// when the ring was sent, in ms
+    pub started_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CallHistoryResponse {
+    pub calls: Vec<CallRecord>,
+}
+
+fn ring_timeout_secs() -> u64 {
+    std::env::var("CALL_RING_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RING_TIMEOUT_SECS)
+}
+
+fn ring_key(room_id: &str, call_id: &str) -> String {
+    format!("call_ring:{}:{}", room_id, call_id)
+}
+
+fn call_members_key(call_id: &str) -> String {
+    format!("call_members:{}", call_id)
+}
+
+/// the livekit room for a call — None when the call id isn't safe to use in one
+fn call_room_name(call_id: &str) -> Option<String> {
+    let valid = !call_id.is_empty()
+        && call_id.len() <= 64
+        && call_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| format!("call_{}", call_id))
+}
+
+/// whether an action puts its sender in the call (Some(true)) or takes them out
+fn joins_call(action: &str) -> Option<bool> {
+    match action {
+        "ring" | "accept" | "join" => Some(true),
+        "cancel" | "leave" => Some(false),
+        _ => None,
+    }
+}
+
+async fn call_participants(redis: &mut redis::aio::MultiplexedConnection, call_id: &str) -> Vec<String> {
+    let mut participants: Vec<String> = redis.smembers(call_members_key(call_id)).await.unwrap_or_default();
+    participants.sort();
+    participants
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn call_content(action: &str, call_id: &str, from: &str, display_name: &str) -> serde_json::Value {
+    serde_json::json!({
+        "msgtype": "agora.call",
+        "body": format!("[call {}]", action),
+        "call_id": call_id,
+        "action": action,
+        "from": from,
+        "display_name": display_name,
+    })
+}
+
+/// start or stop tracking a ring after its event went out
+async fn track_ring(state: &AppState, auth: &AuthUser, req: &CallEventRequest, display_name: &str) {
+    let Some(mut redis) = state.get_redis().await else {
+        return;
+    };
+    let key = ring_key(&req.room_id, &req.call_id);
+    let tracked: redis::RedisResult<()> = match req.action.as_str() {
+        "ring" => {
+            let timeout = ring_timeout_secs();
+            let session = RingSession {
+                room_id: req.room_id.clone(),
+                call_id: req.call_id.clone(),
+                from: auth.user_id.clone(),
+                display_name: display_name.to_string(),
+                access_token: auth.access_token.clone(),
+                expires_at: unix_now() + timeout,
+            };
+            let value = serde_json::to_string(&session).unwrap_or_default();
+            // the key outlives the ring so a slow sweep still finds it
+            redis.set_ex(&key, value, timeout + 60).await
+        }
+        "accept" | "cancel" => redis.del(&key).await,
+        _ => Ok(()),
+    };
+    if let Err(e) = tracked {
+        tracing::warn!("failed to track call {}: {}", req.call_id, e);
+    }
+}
+
+/// record the sender joining or leaving the call after its event went out
+async fn track_participant(state: &AppState, auth: &AuthUser, req: &CallEventRequest) {
+    let (Some(mut redis), Some(joins)) = (state.get_redis().await, joins_call(&req.action)) else {
+        return;
+    };
+    let key = call_members_key(&req.call_id);
+    let tracked: redis::RedisResult<()> = if joins {
+        match redis.sadd::<_, _, ()>(&key, &auth.user_id).await {
+            Ok(()) => redis.expire(&key, CALL_MEMBERS_TTL_SECS).await,
+            Err(e) => Err(e),
+        }
+    } else {
+        redis.srem(&key, &auth.user_id).await
+    };
+    if let Err(e) = tracked {
+        tracing::warn!("failed to track participants of call {}: {}", req.call_id, e);
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/voice/call",
+    tag = "voice",
+    request_body = CallEventRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn send_call_event(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<CallEventRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token.clone());
+
+    let txn_id = req.txn_id.as_deref();
+    if let Some(txn_id) = txn_id {
+        if !idempotency::is_valid(txn_id) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        // a retry of an event that went out doesn't ring or join anyone twice
+        if idempotency::sent(&state, &auth.access_token, idempotency::Kind::Call, txn_id).await.is_some() {
+            return Ok(StatusCode::OK);
+        }
+    }
+    let display_name = req.display_name.clone().unwrap_or_default();
+    let mut content = call_content(&req.action, &req.call_id, &auth.user_id, &display_name);
+    if !req.invited_user_ids.is_empty() {
+        content["invited_user_ids"] = serde_json::json!(req.invited_user_ids);
+    }
+    // who will be in the call once this event lands
+    if let (Some(mut redis), Some(joins)) = (state.get_redis().await, joins_call(&req.action)) {
+        let mut participants = call_participants(&mut redis, &req.call_id).await;
+        participants.retain(|p| *p != auth.user_id);
+        if joins {
+            participants.push(auth.user_id.clone());
+            participants.sort();
+        }
+        content["participants"] = serde_json::json!(participants);
+    }
+
+    let homeserver_txn_id = txn_id.map(|t| idempotency::homeserver_txn_id(idempotency::Kind::Call, t));
+    match matrix.send_message_content(req.room_id.clone(), content, homeserver_txn_id.as_deref()).await {
+        Ok(result) => {
+            if let (Some(txn_id), Some(event_id)) = (txn_id, result["event_id"].as_str()) {
+                idempotency::remember(&state, &auth.access_token, idempotency::Kind::Call, txn_id, event_id).await;
+            }
+            track_ring(&state, &auth, &req, &display_name).await;
+            track_participant(&state, &auth, &req).await;
+            Ok(StatusCode::OK)
+        }
+        Err(e) => {
+            tracing::error!("failed to send call event: {}", e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// who is in a call right now
+#[utoipa::path(
+    get,
+    path = "/voice/call/participants",
+    tag = "voice",
+    params(CallParticipantsQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = CallParticipantsResponse))
+)]
+async fn get_call_participants(
+    state: State<Arc<AppState>>,
+    // anyone signed in — the call id is the secret
+    _auth: AuthUser,
+    Query(params): Query<CallParticipantsQuery>,
+) -> Json<CallParticipantsResponse> {
+    let participants = match state.get_redis().await {
+        Some(mut redis) => call_participants(&mut redis, &params.call_id).await,
+        None => Vec::new(),
+    };
+    Json(CallParticipantsResponse { call_id: params.call_id, participants })
+}
+
+/// background loop that times out unanswered rings — spawned from main.rs when redis is up
+pub async fn run_ring_timeout_worker(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(RING_SWEEP_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        expire_rings(&state, unix_now()).await;
+    }
+}
+
+/// send "timeout" for every ring that expired by `now` (unix seconds); returns how many
+pub async fn expire_rings(state: &AppState, now: u64) -> usize {
+    let Some(mut redis) = state.get_redis().await else {
+        return 0;
+    };
+    // KEYS is O(N) but only rings in flight match
+    let keys: Vec<String> = redis.keys("call_ring:*").await.unwrap_or_default();
+    let mut expired = 0;
+    for key in keys {
+        let value: Option<String> = redis.get(&key).await.unwrap_or(None);
+        let Some(session) = value.and_then(|v| serde_json::from_str::<RingSession>(&v).ok()) else {
+            continue;
+        };
+        if session.expires_at > now {
+            continue;
+        }
+        // whoever deletes the key sends the timeout, so it goes out once
+        let removed: u32 = redis.del(&key).await.unwrap_or(0);
+        if removed == 0 {
+            continue;
+        }
+        let mut matrix = state.matrix();
+        matrix.access_token = Some(session.access_token);
+        let content = call_content("timeout", &session.call_id, &session.from, &session.display_name);
+        match matrix.send_message_content(session.room_id, content, None).await {
+            Ok(_) => expired += 1,
+            Err(e) => tracing::warn!("failed to send call timeout for {}: {}", session.call_id, e),
+        }
+    }
+    expired
+}
+
+/// the room's recent calls and how each ended, newest first
+#[utoipa::path(
+    get,
+    path = "/voice/call/history",
+    tag = "voice",
+    params(CallHistoryQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = CallHistoryResponse), super::ErrorResponses)
+)]
+async fn get_call_history(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<CallHistoryQuery>,
+) -> Result<Json<CallHistoryResponse>, Response> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+    let limit = params.limit.unwrap_or(20).clamp(1, MAX_CALL_HISTORY);
+
+    // call_id → its signaling events, newest first
+    let mut calls: Vec<(String, Vec<crate::matrix::client::Event>)> = Vec::new();
+    let mut from: Option<String> = None;
+    for _ in 0..CALL_HISTORY_PAGES {
+        let page = matrix
+            .get_messages(&params.room_id, from.as_deref(), crate::matrix::client::Direction::Backward, 100)
+            .await
+            .map_err(|e| matrix_error(&e, StatusCode::BAD_GATEWAY))?;
+        for event in page.chunk {
+            if event.event_type != "m.room.message" || event.content["msgtype"] != "agora.call" {
+                continue;
+            }
+            let Some(call_id) = event.content["call_id"].as_str().map(String::from) else {
+                continue;
+            };
+            match calls.iter_mut().find(|(id, _)| *id == call_id) {
+                Some((_, events)) => events.push(event),
+                None => calls.push((call_id, vec![event])),
+            }
+        }
+        // stop once the oldest call we need has its ring
+        let rung = |events: &[crate::matrix::client::Event]| events.iter().any(|e| e.content["action"] == "ring");
+        if calls.len() >= limit && calls[..limit].iter().all(|(_, events)| rung(events)) {
+            break;
+        }
+        match page.end {
+            Some(end) => from = Some(end),
+            None => break,
+        }
+    }
+
+    let calls = calls
+        .into_iter()
+        .filter_map(|(call_id, events)| {
+            let ring = events.iter().find(|e| e.content["action"] == "ring")?;
+            let caller = ring.sender.clone();
+            let has = |action: &str| events.iter().any(|e| e.content["action"] == action);
+            let outcome = if has("accept") {
+                CallOutcome::Completed
+            } else if has("timeout") {
+                CallOutcome::Missed
+            } else if let Some(cancel) = events.iter().find(|e| e.content["action"] == "cancel") {
+                if cancel.sender == caller { CallOutcome::Cancelled } else { CallOutcome::Declined }
+            } else {
+                CallOutcome::Ringing
+            };
+            Some(CallRecord { call_id, caller, outcome, started_at: ring.origin_server_ts })
+        })
+        .take(limit)
+        .collect();
+    Ok(Json(CallHistoryResponse { calls }))
+}
+
+// ── vibe rooms ────────────────────────────────────────────────────────────────
+// vibe is stored as a matrix state event (agora.vibe) on the voice channel room.
+// any participant can set it; everyone polling /voice/vibe sees the change.
+// besides the built-in ambiences (synthesized by the client), a server can
+// define its own in an agora.vibes event on the server room — each with an
+// uploaded audio file. a channel finds them by walking up its m.space.parent
+// chain to the first space that has some.
+
+pub const VIBES_EVENT_TYPE: &str = "agora.vibes";
+/// most custom vibes a server can define
+pub const MAX_CUSTOM_VIBES: usize = 25;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Vibe {
+    pub id: String,
+    pub name: String,
+    /// an emoji, or an mxc:// image
+    #[serde(default)]
+    pub icon: String,
+    /// the ambience to loop — None for built-ins, which clients synthesize
+    #[serde(default)]
+    pub audio_mxc_url: Option<String>,
+}
+
+impl Vibe {
+    fn builtin(id: &str, name: &str, icon: &str) -> Self {
+        Vibe { id: id.to_string(), name: name.to_string(), icon: icon.to_string(), audio_mxc_url: None }
+    }
+
+    fn none() -> Self {
+        Vibe::builtin("none", "off", "🔇")
+    }
+}
+
+/// the ambiences every server has
+pub fn builtin_vibes() -> Vec<Vibe> {
+    vec![
+        Vibe::none(),
+        Vibe::builtin("rain", "rain", "🌧️"),
+        Vibe::builtin("lofi", "lo-fi", "🎵"),
+        Vibe::builtin("campfire", "campfire", "🔥"),
+        Vibe::builtin("space", "space", "🌌"),
+    ]
+}
+
+/// the custom vibes in an agora.vibes event's content
+pub fn custom_vibes(content: &serde_json::Value) -> Vec<Vibe> {
+    content
+        .get("vibes")
+        .and_then(|v| serde_json::from_value(v.clone()).ok())
+        .unwrap_or_default()
+}
+
+/// why a server's custom vibe list can't be saved, if it can't
+pub fn invalid_custom_vibes(vibes: &[Vibe]) -> Option<String> {
+    if vibes.len() > MAX_CUSTOM_VIBES {
+        return Some(format!("a server can have at most {} custom vibes", MAX_CUSTOM_VIBES));
+    }
+    let builtin = builtin_vibes();
+    for (i, vibe) in vibes.iter().enumerate() {
+        let valid_id = !vibe.id.is_empty()
+            && vibe.id.len() <= 32
+            && vibe.id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid_id {
+            return Some(format!("vibe id '{}' may only use a-z, 0-9, - and _ (at most 32)", vibe.id));
+        }
+        if builtin.iter().any(|b| b.id == vibe.id) || vibes[..i].iter().any(|v| v.id == vibe.id) {
+            return Some(format!("vibe id '{}' is already taken", vibe.id));
+        }
+        if vibe.name.trim().is_empty() || vibe.name.chars().count() > 32 {
+            return Some(format!("vibe '{}' needs a name of at most 32 characters", vibe.id));
+        }
+        if !vibe.audio_mxc_url.as_deref().is_some_and(|url| url.starts_with("mxc://")) {
+            return Some(format!("vibe '{}' needs an mxc:// audio_mxc_url", vibe.id));
+        }
+    }
+    None
+}
+
+/// the custom vibes available in a channel: those of the nearest space above it
+/// that defines any
+async fn inherited_vibes(matrix: &MatrixClient, room_id: &str) -> Vec<Vibe> {
+    let mut visited = vec![room_id.to_string()];
+    let mut current = room_id.to_string();
+    // server → category → channel is at most MAX_SPACE_NESTING hops up
+    for _ in 0..=MAX_SPACE_NESTING {
+        let Ok(state) = matrix.get_room_state(current.clone()).await else {
+            break;
+        };
+        if let Some(event) = state.iter().find(|e| e.event_type == VIBES_EVENT_TYPE) {
+            let vibes = custom_vibes(&event.content);
+            if !vibes.is_empty() {
+                return vibes;
+            }
+        }
+        let parent = state
+            .iter()
+            .filter(|e| e.event_type == "m.space.parent")
+            .filter(|e| e.content.as_object().is_some_and(|c| !c.is_empty()))
+            .find_map(|e| e.state_key.clone())
+            .filter(|k| !k.is_empty() && !visited.contains(k));
+        match parent {
+            Some(parent) => {
+                visited.push(parent.clone());
+                current = parent;
+            }
+            None => break,
+        }
+    }
+    Vec::new()
+}
+
+/// a vibe id as this channel knows it — None when it's neither built in nor
+/// defined by the channel's server
+async fn resolve_vibe(matrix: &MatrixClient, room_id: &str, id: &str) -> Option<Vibe> {
+    if let Some(vibe) = builtin_vibes().into_iter().find(|v| v.id == id) {
+        return Some(vibe);
+    }
+    inherited_vibes(matrix, room_id).await.into_iter().find(|v| v.id == id)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VibeQuery {
+    pub room_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct VibeResponse {
+    /// the full definition, so clients can play a custom vibe without looking it up
+    pub vibe: Vibe,
+    pub set_by: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetVibeRequest {
+    pub room_id: String,
+    /// a built-in vibe id (none, rain, lofi, campfire, space) or one of the server's own
+    pub vibe: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/voice/vibe",
+    tag = "voice",
+    params(VibeQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = VibeResponse), super::StatusErrors)
+)]
+async fn get_vibe(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<VibeQuery>,
+) -> Result<Json<VibeResponse>, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    // read the agora.vibe state event from the room
+    let url = format!(
+        "{}/rooms/{}/state/agora.vibe/",
+        matrix.client_api_base().await,
+        encode_path_segment(&params.room_id)
+    );
+
+    let resp = matrix.get_raw(&url).await;
+    match resp {
+        Ok(body) => {
+            let id = body["vibe"].as_str().unwrap_or("none");
+            // a custom vibe the server has since deleted plays nothing
+            let vibe = resolve_vibe(&matrix, &params.room_id, id).await.unwrap_or_else(Vibe::none);
+            let set_by = body["set_by"].as_str().map(String::from);
+            Ok(Json(VibeResponse { vibe, set_by }))
+        }
+        Err(_) => {
+            // no vibe set yet (404 from conduit) — return none
+            Ok(Json(VibeResponse { vibe: Vibe::none(), set_by: None }))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/voice/vibe",
+    tag = "voice",
+    request_body = SetVibeRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::StatusErrors)
+)]
+async fn set_vibe(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<SetVibeRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    // validate vibe value server-side
+    if resolve_vibe(&matrix, &req.room_id, &req.vibe).await.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let content = serde_json::json!({
+        "vibe": req.vibe,
+        "set_by": auth.user_id,
+    });
+
+    match matrix.send_state_event(req.room_id, "agora.vibe".to_string(), "".to_string(), content).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) => {
+            tracing::error!("failed to set vibe: {}", e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// url-encode a matrix room id for use in a path segment
 /// sanitize a matrix room id into a livekit-compatible room name
 /// matrix room ids look like: !abc123:localhost
 /// livekit room names must not contain special chars the jwt can't handle
 fn sanitize_room_name(room_id: &str) -> String {
     room_id
         .trim_start_matches('!')
-        .replace(':', "_")
-        .replace('.', "_")
+        .replace([':', '.'], "_")
 }
@@ -0,0 +1,492 @@
+// webhooks.rs — incoming webhooks: external services posting into a channel
+// a webhook belongs to a server and posts into one of its channels. managing
+// them takes manage_server; posting only takes the url, /webhooks/{id}/{token}.
+// only a sha256 of the token is kept (migrations/005_webhooks.sql), so the
+// token is shown once, when the webhook is created.
+// messages go out as a service account (WEBHOOK_ACCESS_TOKEN), which is
+// invited into the channel when a webhook is created there. they're m.text
+// with an agora.webhook field naming the webhook, so clients can show the bot
+// as the author, and any discord-style embeds in agora.embeds — cut down to
+// the fields clients show, text sanitized like messages and links http(s) only.
+// each webhook is limited to WEBHOOK_RATE_LIMIT posts per window, counted in
+// redis like translations are.
+
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use crate::app_state::AppState;
+use crate::content;
+use crate::matrix::authz::{self, Permission};
+use crate::matrix::client::MatrixClient;
+use crate::session::{AuthJson, AuthUser};
+use super::{agora_error, authz_error, matrix_error};
+
+/// the field on a webhook's message naming the webhook
+pub const CONTENT_FIELD: &str = "agora.webhook";
+pub const EMBEDS_FIELD: &str = "agora.embeds";
+
+const MAX_WEBHOOK_NAME_LEN: usize = 80;
+const MAX_CONTENT_LEN: usize = 2000;
+const MAX_EMBEDS: usize = 10;
+const MAX_EMBED_FIELDS: usize = 25;
+// five posts every two seconds, like discord's
+const WEBHOOK_RATE_LIMIT: u64 = 5;
+const RATE_WINDOW_SECS: i64 = 2;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/servers/webhooks", get(list_webhooks).delete(delete_webhook))
+        .route("/servers/webhooks/create", post(create_webhook))
+        .route("/webhooks/:webhook_id/:token", post(execute_webhook))
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(list_webhooks, delete_webhook, create_webhook, execute_webhook))]
+pub struct ApiDoc;
+
+/// WEBHOOK_ACCESS_TOKEN, when set
+pub fn service_token_from_env() -> Option<String> {
+    std::env::var("WEBHOOK_ACCESS_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateWebhookRequest {
+    pub server_id: String,
+    /// the channel it posts into
+    pub room_id: String,
+    pub name: String,
+    /// mxc:// uri
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    /// only ever returned here
+    pub token: String,
+    /// path to post to, relative to the api
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: String,
+    pub server_id: String,
+    pub room_id: String,
+    pub name: String,
+    pub avatar_url: Option<String>,
+    pub created_by: String,
+    /// unix ms
+    pub created_at: i64,
+    /// unix ms — None until it first posts
+    pub last_used_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WebhooksQuery {
+    pub server_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhooksResponse {
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteWebhookRequest {
+    pub server_id: String,
+    pub webhook_id: String,
+}
+
+/// what an external service posts: `{content}`, or discord's
+/// `{username, avatar_url, content, embeds}`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct WebhookMessage {
+    #[serde(default)]
+    pub content: String,
+    /// shown instead of the webhook's name for this message
+    pub username: Option<String>,
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Vec<Object>)]
+    pub embeds: Vec<serde_json::Value>,
+}
+
+/// require a db pool or return 503
+macro_rules! require_db {
+    ($state:expr) => {
+        match $state.db_pool.as_ref() {
+            Some(pool) => pool,
+            None => {
+                tracing::error!("webhook endpoints require a database connection");
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+        }
+    };
+}
+
+fn db_error(e: sqlx::Error) -> Response {
+    tracing::error!("webhooks query failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// two uuids' worth of randomness, url-safe
+fn new_token() -> String {
+    format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+/// the service account webhooks post as — None when webhooks aren't set up
+pub async fn poster(state: &AppState) -> Option<MatrixClient> {
+    state.db_pool.as_ref()?;
+    state.webhook_client.read().await.clone()
+}
+
+const WEBHOOK_COLUMNS: &str = r#"
+    id, server_id, room_id, name, avatar_url, created_by,
+    (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at,
+    (EXTRACT(EPOCH FROM last_used_at) * 1000)::BIGINT AS last_used_at
+"#;
+
+fn webhook_entry(row: &sqlx::postgres::PgRow) -> Webhook {
+    Webhook {
+        id: row.get("id"),
+        server_id: row.get("server_id"),
+        room_id: row.get("room_id"),
+        name: row.get("name"),
+        avatar_url: row.get("avatar_url"),
+        created_by: row.get("created_by"),
+        created_at: row.get::<Option<i64>, _>("created_at").unwrap_or_default(),
+        last_used_at: row.get("last_used_at"),
+    }
+}
+
+/// a trimmed name, or why it isn't one
+fn clean_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_WEBHOOK_NAME_LEN {
+        return Err(format!("webhook names are 1 to {} characters", MAX_WEBHOOK_NAME_LEN));
+    }
+    Ok(name.to_string())
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/webhooks/create",
+    tag = "webhooks",
+    request_body = CreateWebhookRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = CreatedWebhook), super::ErrorResponses)
+)]
+async fn create_webhook(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<CreateWebhookRequest>,
+) -> Result<Json<CreatedWebhook>, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    let name = clean_name(&req.name).map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?;
+    let avatar_url = req.avatar_url.filter(|u| !u.is_empty());
+    if avatar_url.as_deref().is_some_and(|u| !content::is_mxc_uri(u)) {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "avatar_url must be an mxc:// uri"));
+    }
+    let access = authz::require_permission(&matrix, &req.server_id, Permission::ManageServer)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let in_server = authz::server_of(&matrix, &req.room_id)
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_REQUEST))?;
+    if in_server.as_deref() != Some(req.server_id.as_str()) || req.room_id == req.server_id {
+        return Err(agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", "not a channel in this server"));
+    }
+
+    // the service account has to be in the channel to post there
+    let Some(poster) = poster(&state).await else {
+        return Err(agora_error(StatusCode::SERVICE_UNAVAILABLE, "AGORA_WEBHOOKS_DISABLED", "webhooks aren't set up on this api"));
+    };
+    let poster_id = poster.whoami().await.map_err(|e| {
+        tracing::error!("the webhook account's token doesn't work: {}", e);
+        StatusCode::SERVICE_UNAVAILABLE.into_response()
+    })?.user_id;
+    if let Err(e) = matrix.invite_user(req.room_id.clone(), poster_id).await {
+        // already in the room from an earlier webhook
+        tracing::debug!("webhook account not invited to {}: {}", req.room_id, e);
+    }
+    poster.join_room(req.room_id.clone()).await.map_err(|e| {
+        tracing::warn!("webhook account cannot join {}: {}", req.room_id, e);
+        matrix_error(&e, StatusCode::BAD_GATEWAY)
+    })?;
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let token = new_token();
+    let row = sqlx::query(&format!(
+        r#"
+        INSERT INTO webhooks (id, server_id, room_id, name, avatar_url, token_hash, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING {}
+        "#,
+        WEBHOOK_COLUMNS
+    ))
+    .bind(&id)
+    .bind(&req.server_id)
+    .bind(&req.room_id)
+    .bind(&name)
+    .bind(&avatar_url)
+    .bind(hash_token(&token))
+    .bind(&access.user_id)
+    .fetch_one(pool)
+    .await
+    .map_err(db_error)?;
+
+    let url = format!("/webhooks/{}/{}", id, token);
+    Ok(Json(CreatedWebhook { webhook: webhook_entry(&row), token, url }))
+}
+
+/// a server's webhooks, oldest first — never with their tokens
+#[utoipa::path(
+    get,
+    path = "/servers/webhooks",
+    tag = "webhooks",
+    params(WebhooksQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = WebhooksResponse), super::ErrorResponses)
+)]
+async fn list_webhooks(
+    state: State<Arc<AppState>>,
+    auth: AuthUser,
+    Query(params): Query<WebhooksQuery>,
+) -> Result<Json<WebhooksResponse>, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission(&matrix, &params.server_id, Permission::ManageServer)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM webhooks WHERE server_id = $1 ORDER BY created_at, id",
+        WEBHOOK_COLUMNS
+    ))
+    .bind(&params.server_id)
+    .fetch_all(pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(WebhooksResponse { webhooks: rows.iter().map(webhook_entry).collect() }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/servers/webhooks",
+    tag = "webhooks",
+    request_body = DeleteWebhookRequest,
+    security(("bearer" = [])),
+    responses((status = 200), super::ErrorResponses)
+)]
+async fn delete_webhook(
+    state: State<Arc<AppState>>,
+    AuthJson(auth, req): AuthJson<DeleteWebhookRequest>,
+) -> Result<StatusCode, Response> {
+    let pool = require_db!(state);
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(auth.access_token);
+
+    authz::require_permission(&matrix, &req.server_id, Permission::ManageServer)
+        .await
+        .map_err(|e| authz_error(&e))?;
+    let deleted = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND server_id = $2")
+        .bind(&req.webhook_id)
+        .bind(&req.server_id)
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+    if deleted.rows_affected() == 0 {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "no such webhook"));
+    }
+    Ok(StatusCode::OK)
+}
+
+/// the message content a webhook post turns into, or why it can't be sent
+fn message_content(webhook_id: &str, name: &str, avatar_url: Option<String>, message: WebhookMessage) -> Result<serde_json::Value, String> {
+    if message.content.chars().count() > MAX_CONTENT_LEN {
+        return Err(format!("content is at most {} characters", MAX_CONTENT_LEN));
+    }
+    if message.embeds.len() > MAX_EMBEDS {
+        return Err(format!("at most {} embeds", MAX_EMBEDS));
+    }
+    if message.embeds.iter().any(|e| !e.is_object()) {
+        return Err("embeds are objects".to_string());
+    }
+    // clients that don't know embeds still get something to show
+    let body = if message.content.trim().is_empty() {
+        message
+            .embeds
+            .iter()
+            .find_map(|e| e["title"].as_str().or(e["description"].as_str()))
+            .unwrap_or_default()
+            .to_string()
+    } else {
+        message.content
+    };
+    if body.trim().is_empty() {
+        return Err("a message needs content or an embed with a title or description".to_string());
+    }
+
+    let name = message.username.as_deref().and_then(|u| clean_name(u).ok()).unwrap_or_else(|| name.to_string());
+    let avatar_url = message.avatar_url.filter(|u| content::is_mxc_uri(u)).or(avatar_url);
+    let mut content = serde_json::json!({
+        "msgtype": "m.text",
+        "body": body,
+    });
+    content[CONTENT_FIELD] = serde_json::json!({ "id": webhook_id, "name": name, "avatar_url": avatar_url });
+    if !message.embeds.is_empty() {
+        content[EMBEDS_FIELD] = message.embeds.iter().map(clean_embed).collect();
+    }
+    Ok(content)
+}
+
+/// the parts of a discord embed clients show; anything else is dropped
+fn clean_embed(embed: &serde_json::Value) -> serde_json::Value {
+    let mut clean = pick(embed, &["title", "description"], &["url"]);
+    if let Some(color) = embed["color"].as_u64().filter(|c| *c <= 0xFF_FFFF) {
+        clean.insert("color".to_string(), color.into());
+    }
+    let parts: [(&str, &[&str], &[&str]); 4] = [
+        ("author", &["name"], &["url", "icon_url"]),
+        ("footer", &["text"], &["icon_url"]),
+        ("image", &[], &["url"]),
+        ("thumbnail", &[], &["url"]),
+    ];
+    for (key, texts, links) in parts {
+        let part = pick(&embed[key], texts, links);
+        if !part.is_empty() {
+            clean.insert(key.to_string(), part.into());
+        }
+    }
+    let fields: Vec<serde_json::Value> = embed["fields"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .take(MAX_EMBED_FIELDS)
+        .map(|field| {
+            let mut clean = pick(field, &["name", "value"], &[]);
+            if let Some(inline) = field["inline"].as_bool() {
+                clean.insert("inline".to_string(), inline.into());
+            }
+            clean.into()
+        })
+        .collect();
+    if !fields.is_empty() {
+        clean.insert("fields".to_string(), fields.into());
+    }
+    clean.into()
+}
+
+/// the `texts` of an embed object through the message sanitizer, and the
+/// `links` that are http or https urls
+fn pick(value: &serde_json::Value, texts: &[&str], links: &[&str]) -> serde_json::Map<String, serde_json::Value> {
+    let mut picked = serde_json::Map::new();
+    for key in texts {
+        if let Some(text) = value[*key].as_str() {
+            picked.insert(key.to_string(), content::sanitize_html(text).into());
+        }
+    }
+    for key in links {
+        if let Some(url) = value[*key].as_str().filter(|u| content::is_web_url(u)) {
+            picked.insert(key.to_string(), url.into());
+        }
+    }
+    picked
+}
+
+/// fixed-window limiter per webhook — without redis there's no shared counter, so allow the post
+async fn take_rate_token(state: &AppState, webhook_id: &str) -> bool {
+    let Some(mut redis) = state.get_redis().await else {
+        return true;
+    };
+    let key = format!("webhook_rate:{}", webhook_id);
+    let count: u64 = match redis.incr(&key, 1).await {
+        Ok(c) => c,
+        Err(_) => return true,
+    };
+    if count == 1 {
+        let _: redis::RedisResult<()> = redis.expire(&key, RATE_WINDOW_SECS).await;
+    }
+    count <= WEBHOOK_RATE_LIMIT
+}
+
+/// post as a webhook. no session: the token in the url is the credential
+#[utoipa::path(
+    post,
+    path = "/webhooks/{webhook_id}/{token}",
+    tag = "webhooks",
+    params(
+        ("webhook_id" = String, Path),
+        ("token" = String, Path),
+    ),
+    request_body = WebhookMessage,
+    responses((status = 204), super::ErrorResponses)
+)]
+async fn execute_webhook(
+    state: State<Arc<AppState>>,
+    Path((webhook_id, token)): Path<(String, String)>,
+    Json(message): Json<WebhookMessage>,
+) -> Result<StatusCode, Response> {
+    let pool = require_db!(state);
+    let row = sqlx::query("SELECT room_id, name, avatar_url, token_hash FROM webhooks WHERE id = $1")
+        .bind(&webhook_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+    // an unknown id and a wrong token look the same, and the comparison
+    // takes as long however much of the hash matches
+    let hash = hash_token(&token);
+    let Some(row) = row.filter(|row| bool::from(row.get::<String, _>("token_hash").as_bytes().ct_eq(hash.as_bytes()))) else {
+        return Err(agora_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "unknown webhook"));
+    };
+    let room_id: String = row.get("room_id");
+    let name: String = row.get("name");
+
+    let content = message_content(&webhook_id, &name, row.get("avatar_url"), message)
+        .map_err(|e| agora_error(StatusCode::BAD_REQUEST, "M_INVALID_PARAM", e))?;
+    if !take_rate_token(&state, &webhook_id).await {
+        let body = serde_json::json!({
+            "errcode": "M_LIMIT_EXCEEDED",
+            "error": "this webhook is posting too fast",
+            "retry_after_ms": RATE_WINDOW_SECS * 1000,
+        });
+        return Err((StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response());
+    }
+    let Some(poster) = poster(&state).await else {
+        return Err(agora_error(StatusCode::SERVICE_UNAVAILABLE, "AGORA_WEBHOOKS_DISABLED", "webhooks aren't set up on this api"));
+    };
+
+    poster.send_message_content(room_id.clone(), content, None).await.map_err(|e| {
+        tracing::warn!("webhook {} failed to post into {}: {}", webhook_id, room_id, e);
+        matrix_error(&e, StatusCode::BAD_GATEWAY)
+    })?;
+    let touched = sqlx::query("UPDATE webhooks SET last_used_at = NOW() WHERE id = $1")
+        .bind(&webhook_id)
+        .execute(pool)
+        .await;
+    if let Err(e) = touched {
+        tracing::warn!("failed to record webhook {} use: {}", webhook_id, e);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,217 @@
+// search.rs — the full-text message index
+// a no-op without a service account (see service_account.rs — or just its
+// token, SEARCH_INDEX_TOKEN) and a database. the indexer follows the account's
+// /sync and writes every text message it sees into postgres, where
+// /search/messages looks them up. the account has to be in a room for the
+// room to be indexed: it's brought into every channel and DM the api makes,
+// and accepts any other invite on its next pass. edits replace the indexed
+// body, redactions remove it.
+
+use dashmap::DashMap;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::app_state::AppState;
+use crate::content;
+use crate::matrix::client::{Event, MatrixClient, MatrixError};
+
+// message types with a body worth searching
+const INDEXED_MSGTYPES: [&str; 3] = ["m.text", "m.notice", "m.emote"];
+// after a failed pass (homeserver down, database hiccup)
+const INDEX_RETRY_DELAY: Duration = Duration::from_secs(10);
+// a homeserver that answers an empty sync straight away isn't asked again
+// before this
+const MIN_EMPTY_PASS_INTERVAL: Duration = Duration::from_secs(1);
+// how long a user's joined rooms are trusted for search scoping
+const JOINED_ROOMS_TTL: Duration = Duration::from_secs(30);
+
+/// SEARCH_INDEX_TOKEN, when set
+pub fn index_token_from_env() -> Option<String> {
+    std::env::var("SEARCH_INDEX_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+/// the indexer's client, when search is set up
+pub async fn indexer(state: &AppState) -> Option<MatrixClient> {
+    state.db_pool.as_ref()?;
+    state.matrix_client.read().await.clone()
+}
+
+/// whether /search/messages has an index to answer from
+pub async fn enabled(state: &AppState) -> bool {
+    state.db_pool.is_some() && state.matrix_client.read().await.is_some()
+}
+
+pub async fn run_indexer(state: Arc<AppState>) {
+    loop {
+        let started = Instant::now();
+        match index_once(&state).await {
+            Ok(0) => tokio::time::sleep(MIN_EMPTY_PASS_INTERVAL.saturating_sub(started.elapsed())).await,
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("search indexing failed: {:#}", e);
+                tokio::time::sleep(INDEX_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// one /sync pass: accept invites, apply the timeline to the index and move
+/// the stored position on. returns how many index rows changed.
+pub async fn index_once(state: &AppState) -> anyhow::Result<u64> {
+    let (Some(pool), Some(matrix)) = (state.db_pool.as_ref(), indexer(state).await) else {
+        return Ok(0);
+    };
+    let since: Option<String> = sqlx::query_scalar("SELECT next_batch FROM search_index_state")
+        .fetch_optional(pool)
+        .await?;
+    let response = matrix.sync(since).await?;
+    let rooms = response.rooms.unwrap_or_default();
+
+    for room_id in rooms.invite.unwrap_or_default().into_keys() {
+        if let Err(e) = matrix.join_room(room_id.clone()).await {
+            tracing::warn!("search indexer couldn't join {}: {}", room_id, e);
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    let mut changed = 0;
+    for (room_id, room) in rooms.join.unwrap_or_default() {
+        for event in room.timeline.map(|t| t.events).unwrap_or_default() {
+            changed += apply(&mut tx, &room_id, &event).await?;
+        }
+    }
+    sqlx::query(
+        r#"
+        INSERT INTO search_index_state (id, next_batch) VALUES (TRUE, $1)
+        ON CONFLICT (id) DO UPDATE SET next_batch = EXCLUDED.next_batch, updated_at = NOW()
+        "#,
+    )
+    .bind(&response.next_batch)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(changed)
+}
+
+/// what one timeline event does to the index
+async fn apply(tx: &mut Transaction<'_, Postgres>, room_id: &str, event: &Event) -> Result<u64, sqlx::Error> {
+    if let Some(redacted) = event.redacted_event_id() {
+        let result = sqlx::query("DELETE FROM messages WHERE event_id = $1").bind(redacted).execute(&mut **tx).await?;
+        return Ok(result.rows_affected());
+    }
+    if event.event_type != "m.room.message" {
+        return Ok(0);
+    }
+    let content = &event.content;
+    let relation = content.get("m.relates_to");
+    if relation.and_then(|r| r.get("rel_type")).and_then(|v| v.as_str()) == Some("m.replace") {
+        let target = relation.and_then(|r| r.get("event_id")).and_then(|v| v.as_str());
+        let body = content.pointer("/m.new_content/body").and_then(|v| v.as_str());
+        let (Some(target), Some(body)) = (target, body) else {
+            return Ok(0);
+        };
+        let result = sqlx::query("UPDATE messages SET body = $1 WHERE event_id = $2")
+            .bind(body)
+            .bind(target)
+            .execute(&mut **tx)
+            .await?;
+        return Ok(result.rows_affected());
+    }
+
+    let msgtype = content.get("msgtype").and_then(|v| v.as_str()).unwrap_or_default();
+    let body = content.get("body").and_then(|v| v.as_str()).unwrap_or_default();
+    let body = content::strip_reply_fallback(body).trim();
+    let Some(event_id) = event.event_id.as_deref() else {
+        return Ok(0);
+    };
+    if !INDEXED_MSGTYPES.contains(&msgtype) || body.is_empty() {
+        return Ok(0);
+    }
+    let result = sqlx::query(
+        r#"
+        INSERT INTO messages (event_id, room_id, sender, body, ts)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (event_id) DO NOTHING
+        "#,
+    )
+    .bind(event_id)
+    .bind(room_id)
+    .bind(&event.sender)
+    .bind(body)
+    .bind(event.origin_server_ts.unwrap_or_default())
+    .execute(&mut **tx)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// the ranked matches for `query` in `room_ids`, best first
+pub async fn search_messages(
+    pool: &PgPool,
+    query: &str,
+    room_ids: &[String],
+    sender: Option<&str>,
+    excluded_senders: &HashSet<String>,
+    limit: u32,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let excluded: Vec<&str> = excluded_senders.iter().map(String::as_str).collect();
+    sqlx::query_as::<_, SearchHit>(
+        r#"
+        SELECT event_id, room_id, sender, body, ts, ts_rank(body_tsv, q) AS rank
+        FROM messages, websearch_to_tsquery('simple', $1) q
+        WHERE body_tsv @@ q
+          AND room_id = ANY($2)
+          AND ($3::TEXT IS NULL OR sender = $3)
+          AND sender <> ALL($4)
+        ORDER BY rank DESC, ts DESC
+        LIMIT $5
+        "#,
+    )
+    .bind(query)
+    .bind(room_ids)
+    .bind(sender)
+    .bind(excluded)
+    .bind(i64::from(limit))
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct SearchHit {
+    pub event_id: String,
+    pub room_id: String,
+    pub sender: String,
+    pub body: String,
+    pub ts: i64,
+    pub rank: f32,
+}
+
+/// user id → the rooms they're joined to, so a burst of searches (search as
+/// you type) doesn't ask the homeserver every keystroke
+pub struct JoinedRoomsCache {
+    entries: DashMap<String, (Arc<Vec<String>>, Instant)>,
+}
+
+impl Default for JoinedRoomsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JoinedRoomsCache {
+    pub fn new() -> Self {
+        Self { entries: DashMap::new() }
+    }
+
+    pub async fn get(&self, user_id: &str, matrix: &MatrixClient) -> Result<Arc<Vec<String>>, MatrixError> {
+        if let Some(entry) = self.entries.get(user_id) {
+            let (rooms, fetched_at) = entry.value();
+            if fetched_at.elapsed() < JOINED_ROOMS_TTL {
+                return Ok(rooms.clone());
+            }
+        }
+        let rooms = Arc::new(matrix.get_joined_rooms().await?.joined_rooms);
+        self.entries.insert(user_id.to_string(), (rooms.clone(), Instant::now()));
+        Ok(rooms)
+    }
+}
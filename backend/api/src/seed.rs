@@ -0,0 +1,130 @@
+// seed.rs — `agora-api --seed-dev`: fill a local dev stack with demo data
+// creates two users (alice / bob), a demo server with a few channels that both
+// have joined, and an accepted friendship between them. safe to run repeatedly:
+// existing users are logged in instead of registered, the server is reused if
+// alice already has one with the demo name, and the friendship is upserted.
+
+use anyhow::{anyhow, Context};
+use crate::app_state::AppState;
+use crate::matrix::client::{HistoryVisibility, MatrixClient};
+use crate::matrix::hierarchy;
+
+const DEMO_PASSWORD: &str = "agora-dev-password";
+const DEMO_USERS: [&str; 2] = ["alice", "bob"];
+const DEMO_SERVER_NAME: &str = "Agora Dev";
+// (name, channel type)
+const DEMO_CHANNELS: &[(&str, &str)] = &[("general", "text"), ("random", "text"), ("hangout", "voice")];
+
+pub async fn seed_dev(state: &AppState) -> anyhow::Result<()> {
+    let pool = state.db_pool.as_ref().context("--seed-dev needs a database connection")?;
+
+    let alice = login_or_register(state, DEMO_USERS[0]).await?;
+    let bob = login_or_register(state, DEMO_USERS[1]).await?;
+
+    let space_id = match find_demo_server(&alice).await {
+        Some(space_id) => {
+            tracing::info!("seed: reusing demo server {}", space_id);
+            space_id
+        }
+        None => create_demo_server(&alice, &state.server_name).await?,
+    };
+
+    // bob joins the server and every channel in it (public rooms, no invite needed)
+    for node in hierarchy::walk_space(&alice, &space_id, hierarchy::max_depth()).await {
+        if let Err(e) = bob.join_room(node.room_id.clone()).await {
+            tracing::warn!("seed: bob failed to join {}: {}", node.room_id, e);
+        }
+    }
+
+    let alice_id = alice.user_id.clone().unwrap_or_default();
+    let bob_id = bob.user_id.clone().unwrap_or_default();
+    sqlx::query(
+        r#"
+        INSERT INTO friends (requester_id, addressee_id, status)
+        VALUES ($1, $2, 'accepted')
+        ON CONFLICT (requester_id, addressee_id)
+        DO UPDATE SET status = 'accepted', updated_at = NOW()
+        "#,
+    )
+    .bind(&alice_id)
+    .bind(&bob_id)
+    .execute(pool)
+    .await
+    .context("failed to seed friendship")?;
+
+    tracing::info!(
+        "seed: done — log in as {} or {} with password \"{}\"",
+        DEMO_USERS[0], DEMO_USERS[1], DEMO_PASSWORD
+    );
+    Ok(())
+}
+
+/// log in as a demo user, registering them first if they don't exist yet
+async fn login_or_register(state: &AppState, username: &str) -> anyhow::Result<MatrixClient> {
+    let matrix = state.matrix();
+
+    let full_id = format!("@{}:{}", username, state.server_name);
+    let (user_id, access_token) = match matrix.login(full_id, DEMO_PASSWORD.to_string()).await {
+        Ok(response) => (response.user_id, response.access_token),
+        Err(_) => {
+            let response = matrix
+                .register(username.to_string(), DEMO_PASSWORD.to_string())
+                .await
+                .map_err(|e| anyhow!("failed to register {}: {}", username, e))?;
+            tracing::info!("seed: registered {}", response.user_id);
+            (response.user_id, response.access_token)
+        }
+    };
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(access_token);
+    matrix.user_id = Some(user_id);
+    Ok(matrix)
+}
+
+/// the first joined space named DEMO_SERVER_NAME, if any
+async fn find_demo_server(matrix: &MatrixClient) -> Option<String> {
+    let joined = matrix.get_joined_rooms().await.ok()?;
+    for room_id in joined.joined_rooms {
+        let Ok(state) = matrix.get_room_state(room_id.clone()).await else {
+            continue;
+        };
+        let name = state
+            .iter()
+            .find(|e| e.event_type == "m.room.name")
+            .and_then(|e| e.content.get("name"))
+            .and_then(|v| v.as_str());
+        if hierarchy::is_space(&state) && name == Some(DEMO_SERVER_NAME) {
+            return Some(room_id);
+        }
+    }
+    None
+}
+
+async fn create_demo_server(matrix: &MatrixClient, server_name: &str) -> anyhow::Result<String> {
+    let space = matrix
+        .create_room(DEMO_SERVER_NAME.to_string(), Some("seeded by --seed-dev".to_string()), true, HistoryVisibility::Shared)
+        .await
+        .map_err(|e| anyhow!("failed to create demo server: {}", e))?;
+
+    for (name, channel_type) in DEMO_CHANNELS {
+        let room = matrix
+            .create_room(name.to_string(), None, false, HistoryVisibility::Shared)
+            .await
+            .map_err(|e| anyhow!("failed to create #{}: {}", name, e))?;
+        let content = serde_json::json!({ "type": channel_type });
+        if let Err(e) = matrix
+            .send_state_event(room.room_id.clone(), "agora.room.type".to_string(), "".to_string(), content)
+            .await
+        {
+            tracing::warn!("seed: failed to set channel type for #{}: {}", name, e);
+        }
+        matrix
+            .add_space_child(space.room_id.clone(), room.room_id, server_name)
+            .await
+            .map_err(|e| anyhow!("failed to add #{} to the demo server: {}", name, e))?;
+    }
+
+    tracing::info!("seed: created demo server {}", space.room_id);
+    Ok(space.room_id)
+}
@@ -0,0 +1,203 @@
+// service_account.rs — the backend's own matrix account, and a /sync loop on it
+// handlers only ever act with the caller's token, so nothing here sees events
+// nobody asked about. with SERVICE_ACCOUNT_USERNAME / _PASSWORD set, startup
+// logs that account in (registering it the first time) and puts the session
+// in AppState::matrix_client. the sync worker then follows its /sync and hands
+// every response to the registered SyncObservers. the account only sees rooms
+// it's in: rooms the api makes bring it in as they're created (bring_in), and
+// it accepts any other invite on the pass after it arrives.
+//
+// the sync position lives in redis, so a restart carries on where it stopped
+// rather than replaying the account's whole history to every observer.
+// without redis it's kept in memory for as long as the process runs.
+
+use axum::async_trait;
+use redis::AsyncCommands;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::app_state::AppState;
+use crate::matrix::client::{MatrixClient, MatrixError, SyncResponse};
+
+const SINCE_KEY: &str = "service_sync:next_batch";
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// how often to look again while there's no service account session
+const IDLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// SERVICE_ACCOUNT_USERNAME and SERVICE_ACCOUNT_PASSWORD
+#[derive(Debug, Clone)]
+pub struct ServiceAccount {
+    /// the localpart, on our own homeserver
+    pub username: String,
+    pub password: String,
+}
+
+impl ServiceAccount {
+    /// None unless both are set
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Some(Self { username: var("SERVICE_ACCOUNT_USERNAME")?, password: var("SERVICE_ACCOUNT_PASSWORD")? })
+    }
+
+    /// log in, registering the account when the homeserver doesn't know it yet,
+    /// and make the session AppState::matrix_client
+    pub async fn sign_in(&self, state: &AppState) -> Result<MatrixClient, MatrixError> {
+        let mut client = state.matrix();
+        let user_id = format!("@{}:{}", self.username, state.server_name);
+        let access_token = match client.login(user_id.clone(), self.password.clone()).await {
+            Ok(session) => session.access_token,
+            Err(MatrixError::MatrixApiError { errcode, .. }) if errcode == "M_FORBIDDEN" => {
+                tracing::info!("service account {} can't log in, registering it", self.username);
+                client.register(self.username.clone(), self.password.clone()).await?.access_token
+            }
+            Err(e) => return Err(e),
+        };
+        client.access_token = Some(access_token);
+        client.user_id = Some(user_id);
+        *state.matrix_client.write().await = Some(client.clone());
+        Ok(client)
+    }
+}
+
+/// invite the service account into a room the api just made and join it
+/// straight away, so its sync — and the push and search that follow it —
+/// covers the room from the first message. `inviter` is the room's creator.
+/// a no-op without a service account; failing is logged, the room works without it
+pub async fn bring_in(state: &AppState, inviter: &MatrixClient, room_id: &str) {
+    let Some(account) = state.matrix_client.read().await.clone() else { return };
+    // a session handed over as a bare token (SEARCH_INDEX_TOKEN) doesn't know who it is
+    let user_id = match account.user_id.clone() {
+        Some(user_id) => user_id,
+        None => match account.whoami().await {
+            Ok(whoami) => whoami.user_id,
+            Err(e) => {
+                tracing::warn!("couldn't tell who the service account is: {}", e);
+                return;
+            }
+        },
+    };
+    if let Err(e) = inviter.invite_user(room_id.to_string(), user_id).await {
+        tracing::warn!("couldn't invite the service account into {}: {}", room_id, e);
+        return;
+    }
+    if let Err(e) = account.join_room(room_id.to_string()).await {
+        tracing::warn!("service account couldn't join {}: {}", room_id, e);
+    }
+}
+
+/// something that wants to see what the service account's /sync brings. an
+/// observer deals with its own failures — one that errors mustn't hold up the rest
+#[async_trait]
+pub trait SyncObserver: Send + Sync {
+    async fn observe(&self, state: &AppState, sync: &SyncResponse);
+}
+
+/// the observers the sync worker calls, in the order they were registered
+#[derive(Default)]
+pub struct SyncObservers {
+    observers: RwLock<Vec<Arc<dyn SyncObserver>>>,
+}
+
+impl SyncObservers {
+    pub fn register(&self, observer: impl SyncObserver + 'static) {
+        self.observers.write().unwrap().push(Arc::new(observer));
+    }
+
+    fn all(&self) -> Vec<Arc<dyn SyncObserver>> {
+        self.observers.read().unwrap().clone()
+    }
+}
+
+/// follow the service account's /sync for good. failed passes back off (1s
+/// doubling to a minute); a dead session is signed in again when there are
+/// credentials to do it with
+pub async fn run_sync_worker(state: Arc<AppState>, account: Option<ServiceAccount>) {
+    let mut since = stored_since(&state).await;
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match sync_once(&state, since.clone()).await {
+            Ok(Some(next_batch)) => {
+                store_since(&state, &next_batch).await;
+                since = Some(next_batch);
+                backoff = MIN_BACKOFF;
+            }
+            // no session — signing in at startup failed, or there's nothing to sign in with
+            Ok(None) => {
+                let signed_in = match &account {
+                    Some(account) => account
+                        .sign_in(&state)
+                        .await
+                        .map_err(|e| tracing::warn!("service account sign-in failed: {}", e))
+                        .is_ok(),
+                    None => false,
+                };
+                if !signed_in {
+                    tokio::time::sleep(IDLE_INTERVAL).await;
+                }
+            }
+            Err(e) => {
+                tracing::warn!("service account sync failed, next attempt in {}s: {}", backoff.as_secs(), e);
+                if let (MatrixError::MatrixApiError { errcode, .. }, Some(account)) = (&e, &account) {
+                    if errcode == "M_UNKNOWN_TOKEN" {
+                        if let Err(e) = account.sign_in(&state).await {
+                            tracing::warn!("service account sign-in failed: {}", e);
+                        }
+                    }
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// one /sync pass from `since`: accept invites and show the response to every
+/// observer. the position to carry on from, or None without a service account
+pub async fn sync_once(state: &AppState, since: Option<String>) -> Result<Option<String>, MatrixError> {
+    let Some(matrix) = state.matrix_client.read().await.clone() else {
+        return Ok(None);
+    };
+    let response = matrix.sync(since).await?;
+
+    let invited = response.rooms.as_ref().and_then(|r| r.invite.as_ref());
+    for room_id in invited.into_iter().flat_map(|rooms| rooms.keys()) {
+        if let Err(e) = matrix.join_room(room_id.clone()).await {
+            tracing::warn!("service account couldn't join {}: {}", room_id, e);
+        }
+    }
+    for observer in state.sync_observers.all() {
+        observer.observe(state, &response).await;
+    }
+    Ok(Some(response.next_batch))
+}
+
+async fn stored_since(state: &AppState) -> Option<String> {
+    let mut redis = state.get_redis().await?;
+    redis.get(SINCE_KEY).await.ok().flatten()
+}
+
+async fn store_since(state: &AppState, next_batch: &str) {
+    let Some(mut redis) = state.get_redis().await else { return };
+    let stored: redis::RedisResult<()> = redis.set(SINCE_KEY, next_batch).await;
+    if let Err(e) = stored {
+        tracing::warn!("couldn't store the service account's sync position: {}", e);
+        state.redis.report(&e).await;
+    }
+}
+
+/// proof of life: logs how many messages each room got in a pass
+pub struct MessageCounts;
+
+#[async_trait]
+impl SyncObserver for MessageCounts {
+    async fn observe(&self, _state: &AppState, sync: &SyncResponse) {
+        let joined = sync.rooms.as_ref().and_then(|r| r.join.as_ref());
+        for (room_id, room) in joined.into_iter().flatten() {
+            let events = room.timeline.as_ref().map(|t| t.events.as_slice()).unwrap_or_default();
+            let messages = events.iter().filter(|e| e.event_type == "m.room.message").count();
+            if messages > 0 {
+                tracing::info!("service account saw {} new messages in {}", messages, room_id);
+            }
+        }
+    }
+}
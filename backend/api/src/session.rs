@@ -0,0 +1,193 @@
+// who's calling. the token comes in an `Authorization: Bearer` header, is
+// checked once against the homeserver's whoami, and the answer is cached in
+// redis for a minute so a burst of requests costs one round trip.
+//
+// clients used to send `access_token` in the query string or the json body.
+// that still works while they move over, with a warning — it puts the token in
+// access logs. the websockets keep it in the query: browsers can't set headers there.
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Query, Request},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use redis::AsyncCommands;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use crate::app_state::AppState;
+use crate::routes::{agora_error, matrix_error};
+
+// how long a token → user id answer is trusted. logging out drops it at once;
+// tokens ended from another device (logout_all) can outlive it by this much
+const TOKEN_CACHE_SECONDS: u64 = 60;
+
+// the deprecated field, in the query string or the json body
+const LEGACY_FIELD: &str = "access_token";
+
+/// the caller, from a verified access token
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub access_token: String,
+    pub user_id: String,
+}
+
+/// an authenticated json body: the caller and the request. the legacy
+/// `access_token` field is taken out of the body before it's deserialized
+pub struct AuthJson<T>(pub AuthUser, pub T);
+
+#[derive(Debug, Deserialize)]
+struct LegacyToken {
+    access_token: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let legacy = || {
+            Query::<LegacyToken>::try_from_uri(&parts.uri)
+                .ok()
+                .and_then(|Query(q)| q.access_token)
+        };
+        let access_token = token_or_legacy(&parts.headers, legacy, parts.uri.path()).map_err(|e| *e)?;
+        resolve(state, access_token).await
+    }
+}
+
+#[async_trait]
+impl<T: DeserializeOwned> FromRequest<Arc<AppState>> for AuthJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let (access_token, body) = token_and_body(req, state).await.map_err(|e| *e)?;
+        let user = resolve(state, access_token).await?;
+
+        // the same rejection axum's Json gives for a body that doesn't fit
+        let body = serde_json::from_value(body).map_err(|e| {
+            let msg = format!("Failed to deserialize the JSON body into the target type: {}", e);
+            (StatusCode::UNPROCESSABLE_ENTITY, msg).into_response()
+        })?;
+        Ok(AuthJson(user, body))
+    }
+}
+
+/// the access token a request carries, not checked against the homeserver —
+/// for logging out, where a token that's already dead is no error
+pub struct SessionToken(pub String);
+
+#[async_trait]
+impl FromRequest<Arc<AppState>> for SessionToken {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let (access_token, _) = token_and_body(req, state).await.map_err(|e| *e)?;
+        Ok(SessionToken(access_token))
+    }
+}
+
+/// the token, and the json body with the legacy `access_token` field taken out
+async fn token_and_body(req: Request, state: &Arc<AppState>) -> Result<(String, serde_json::Value), Box<Response>> {
+    let headers = req.headers().clone();
+    let path = req.uri().path().to_string();
+    let bytes = Bytes::from_request(req, state).await.map_err(|e| Box::new(e.into_response()))?;
+    // a request with nothing to say but who it's from may leave the body out
+    let mut body = if bytes.is_empty() {
+        serde_json::json!({})
+    } else {
+        let Json(body) = json_body(&headers, &bytes)?;
+        body
+    };
+
+    let legacy = body
+        .as_object_mut()
+        .and_then(|fields| fields.remove(LEGACY_FIELD))
+        .and_then(|token| token.as_str().map(str::to_string));
+    let access_token = token_or_legacy(&headers, || legacy, &path)?;
+    Ok((access_token, body))
+}
+
+/// what axum's Json extractor checks: a json content type, then the syntax
+fn json_body(headers: &HeaderMap, bytes: &[u8]) -> Result<Json<serde_json::Value>, Box<Response>> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !is_json {
+        let msg = "Expected request with `Content-Type: application/json`";
+        return Err(Box::new((StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response()));
+    }
+    Json::from_bytes(bytes).map_err(|e| Box::new(e.into_response()))
+}
+
+/// the bearer token, or else the legacy one — warned about, since it ends up in logs
+fn token_or_legacy(
+    headers: &HeaderMap,
+    legacy: impl FnOnce() -> Option<String>,
+    path: &str,
+) -> Result<String, Box<Response>> {
+    if let Some(token) = bearer_token(headers) {
+        return Ok(token);
+    }
+    match legacy() {
+        Some(token) => {
+            tracing::warn!("{}: access_token in the request is deprecated, send Authorization: Bearer", path);
+            Ok(token)
+        }
+        None => Err(Box::new(agora_error(StatusCode::UNAUTHORIZED, "M_MISSING_TOKEN", "missing access token"))),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+/// the user behind `access_token` — from the cache, or the homeserver
+pub async fn resolve(state: &AppState, access_token: String) -> Result<AuthUser, Response> {
+    if let Some(user_id) = cached(state, &access_token).await {
+        return Ok(AuthUser { access_token, user_id });
+    }
+
+    let mut matrix = state.matrix();
+    matrix.access_token = Some(access_token.clone());
+    // only a token the homeserver doesn't know is a 401 — an outage or a
+    // timeout mustn't log clients out
+    let user_id = matrix
+        .whoami()
+        .await
+        .map_err(|e| matrix_error(&e, StatusCode::BAD_GATEWAY))?
+        .user_id;
+    remember(state, &access_token, &user_id).await;
+    Ok(AuthUser { access_token, user_id })
+}
+
+/// the user behind `access_token` if the cache knows it, without asking the homeserver
+pub async fn cached(state: &AppState, access_token: &str) -> Option<String> {
+    let mut redis = state.get_redis().await?;
+    redis.get::<_, Option<String>>(cache_key(access_token)).await.ok().flatten()
+}
+
+/// trust `access_token` as `user_id` for a while — after whoami, or straight
+/// from login and registration, which hand the token out
+pub async fn remember(state: &AppState, access_token: &str, user_id: &str) {
+    let Some(mut redis) = state.get_redis().await else { return };
+    let _: redis::RedisResult<()> = redis.set_ex(cache_key(access_token), user_id, TOKEN_CACHE_SECONDS).await;
+}
+
+/// stop trusting a token straight away — after logging it out
+pub async fn forget(state: &AppState, access_token: &str) {
+    let Some(mut redis) = state.get_redis().await else { return };
+    let _: redis::RedisResult<()> = redis.del(cache_key(access_token)).await;
+}
+
+// hashed, so the cache doesn't hold working tokens
+fn cache_key(access_token: &str) -> String {
+    format!("session:{:x}", Sha256::digest(access_token.as_bytes()))
+}
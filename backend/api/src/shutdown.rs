@@ -0,0 +1,170 @@
+// shutdown.rs — stopping without dropping requests. on SIGTERM / SIGINT the
+// listener stops accepting, requests already running get a grace period to
+// finish, and websocket loops are told to close (they'd otherwise hold their
+// connections open forever). main aborts the background workers once the
+// server is down.
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use futures_util::FutureExt;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tower::Service;
+
+// how long in-flight requests get after the signal — override with SHUTDOWN_GRACE_SECS
+const DEFAULT_GRACE_SECS: u64 = 30;
+
+/// a one-way switch: once begun, everyone waiting on `started` wakes up
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self { tx: Arc::new(watch::channel(false).0) }
+    }
+
+    pub fn begin(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// resolves once shutdown has begun — straight away if it already has
+    pub async fn started(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|started| *started).await;
+    }
+}
+
+/// SHUTDOWN_GRACE_SECS, else thirty seconds
+pub fn grace_from_env() -> Duration {
+    let secs = std::env::var("SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GRACE_SECS);
+    Duration::from_secs(secs)
+}
+
+/// begin `shutdown` on SIGTERM (docker stop) or SIGINT (ctrl-c)
+pub async fn on_signal(shutdown: Shutdown) {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("can't listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("can't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => tracing::info!("interrupted, shutting down"),
+        _ = terminate => tracing::info!("terminated, shutting down"),
+    }
+    shutdown.begin();
+}
+
+/// serve `app` until `shutdown` begins, then give in-flight requests `grace`
+/// to finish. returns once every connection is closed or the grace ran out
+pub async fn serve(listener: TcpListener, app: Router, shutdown: Shutdown, grace: Duration) -> std::io::Result<()> {
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // out of file descriptors and the like: give it a moment rather than spin
+                    tracing::error!("failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.started() => break,
+        };
+        connections.spawn(connection(stream, remote, app.clone(), shutdown.clone()));
+    }
+    // connections the kernel had already set up are as good as accepted
+    while let Some(Ok((stream, remote))) = listener.accept().now_or_never() {
+        connections.spawn(connection(stream, remote, app.clone(), shutdown.clone()));
+    }
+    drop(listener);
+    tracing::info!("no longer accepting connections, waiting up to {}s for requests in flight", grace.as_secs());
+
+    let drained = async { while connections.join_next().await.is_some() {} };
+    match tokio::time::timeout(grace, drained).await {
+        Ok(()) => tracing::info!("all connections closed"),
+        Err(_) => {
+            // the process exits right after, which is what ends them
+            tracing::warn!("requests still running after {}s, giving up on them", grace.as_secs());
+            connections.abort_all();
+        }
+    }
+    Ok(())
+}
+
+/// one connection, until the client closes it or shutdown does. an accepted
+/// connection gets its request answered even if shutdown begins before the
+/// request has been read — only then is it closed, after the response
+async fn connection(stream: TcpStream, remote: SocketAddr, app: Router, shutdown: Shutdown) {
+    let (served, mut first_request) = watch::channel(false);
+    let service = service_fn(move |mut request: hyper::Request<Incoming>| {
+        served.send_replace(true);
+        request.extensions_mut().insert(ConnectInfo(remote));
+        app.clone().call(request.map(Body::new))
+    });
+    let builder = Builder::new(TokioExecutor::new());
+    // upgrades for the websockets, which close on their own when shutdown begins
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+
+    let closing = async {
+        shutdown.started().await;
+        let _ = first_request.wait_for(|served| *served).await;
+    };
+    tokio::select! {
+        result = conn.as_mut() => {
+            log_connection_end(result);
+            return;
+        }
+        _ = closing => {}
+    }
+    conn.as_mut().graceful_shutdown();
+    log_connection_end(conn.await);
+}
+
+fn log_connection_end<E: std::fmt::Display>(result: Result<(), E>) {
+    // clients hanging up without a request, mostly
+    if let Err(e) = result {
+        tracing::debug!("connection ended: {}", e);
+    }
+}
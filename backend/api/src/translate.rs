@@ -0,0 +1,205 @@
+// translate.rs — optional machine translation of message bodies
+// a no-op unless TRANSLATE_API_URL is configured. the backend speaks the
+// libretranslate api (POST /translate with a batch of strings). results are
+// cached in redis per event + target language so each message is translated
+// at most once, and backend calls are rate limited per session. any failure
+// just means the caller gets no translated_body — originals are never touched.
+
+use redis::AsyncCommands;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use crate::app_state::AppState;
+
+// translations of an event don't change — keep them for a week
+const CACHE_TTL_SECS: u64 = 7 * 24 * 3600;
+// strings per backend request
+const BATCH_SIZE: usize = 25;
+// default backend calls allowed per session per minute (TRANSLATE_RATE_LIMIT)
+const DEFAULT_RATE_LIMIT: u64 = 30;
+const RATE_WINDOW_SECS: i64 = 60;
+// very long bodies aren't worth a translation round trip
+const MAX_BODY_CHARS: usize = 4000;
+
+#[derive(Debug, Clone)]
+pub struct TranslateConfig {
+    /// base url of the translation backend, e.g. http://libretranslate:5000
+    pub api_url: String,
+    pub api_key: Option<String>,
+    /// backend calls per session per minute
+    pub rate_limit: u64,
+}
+
+impl TranslateConfig {
+    /// returns None (translation disabled) when TRANSLATE_API_URL is unset
+    pub fn from_env() -> Option<Self> {
+        let api_url = std::env::var("TRANSLATE_API_URL").ok().filter(|u| !u.is_empty())?;
+        Some(Self {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            api_key: std::env::var("TRANSLATE_API_KEY").ok().filter(|k| !k.is_empty()),
+            rate_limit: std::env::var("TRANSLATE_RATE_LIMIT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_RATE_LIMIT),
+        })
+    }
+}
+
+/// loose BCP-47 check: a 2–8 letter primary language subtag
+/// followed by 1–8 character alphanumeric subtags, e.g. "en", "pt-BR", "zh-Hant-TW"
+pub fn is_valid_language_tag(tag: &str) -> bool {
+    if tag.is_empty() || tag.len() > 35 {
+        return false;
+    }
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    let primary_ok = (2..=8).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic());
+    primary_ok
+        && subtags.all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn cache_key(event_id: &str, lang: &str) -> String {
+    format!("translate:{}:{}", lang, event_id)
+}
+
+/// sync only has the access token, so limits are per session rather than per account.
+/// the token is hashed so it never ends up in redis.
+fn rate_key(access_token: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    access_token.hash(&mut hasher);
+    format!("translate_rate:{:x}", hasher.finish())
+}
+
+/// translate message bodies into `lang`. `items` are (event_id, body) pairs;
+/// returns translations keyed by event id — missing entries mean "not available"
+pub async fn translate_bodies(
+    state: &AppState,
+    access_token: &str,
+    lang: &str,
+    items: &[(String, String)],
+) -> HashMap<String, String> {
+    let mut translated = HashMap::new();
+    let Some(config) = state.translate.as_ref() else {
+        return translated;
+    };
+    if items.is_empty() || !is_valid_language_tag(lang) {
+        return translated;
+    }
+
+    // serve what we can from the cache first
+    let mut redis = state.get_redis().await;
+    let mut misses: Vec<&(String, String)> = Vec::new();
+    if let Some(conn) = redis.as_mut() {
+        let keys: Vec<String> = items.iter().map(|(id, _)| cache_key(id, lang)).collect();
+        let cached: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(conn)
+            .await
+            .unwrap_or_else(|_| vec![None; items.len()]);
+        for (item, hit) in items.iter().zip(cached) {
+            match hit {
+                Some(text) => {
+                    translated.insert(item.0.clone(), text);
+                }
+                None => misses.push(item),
+            }
+        }
+    } else {
+        misses.extend(items.iter());
+    }
+
+    misses.retain(|(_, body)| !body.trim().is_empty() && body.chars().count() <= MAX_BODY_CHARS);
+
+    for batch in misses.chunks(BATCH_SIZE) {
+        if !take_rate_token(redis.as_mut(), config, access_token).await {
+            tracing::debug!("translation rate limit reached — skipping remaining batches");
+            break;
+        }
+
+        let bodies: Vec<&str> = batch.iter().map(|(_, body)| body.as_str()).collect();
+        let results = match request_batch(config, lang, &bodies).await {
+            Ok(results) if results.len() == batch.len() => results,
+            Ok(results) => {
+                tracing::warn!("translation backend returned {} results for {} inputs", results.len(), batch.len());
+                continue;
+            }
+            Err(e) => {
+                // one failed batch doesn't take the rest down with it
+                tracing::warn!("translation batch failed: {}", e);
+                continue;
+            }
+        };
+
+        for ((event_id, _), text) in batch.iter().zip(results) {
+            if text.is_empty() {
+                continue;
+            }
+            if let Some(conn) = redis.as_mut() {
+                let _: redis::RedisResult<()> =
+                    conn.set_ex(cache_key(event_id, lang), &text, CACHE_TTL_SECS).await;
+            }
+            translated.insert(event_id.clone(), text);
+        }
+    }
+
+    translated
+}
+
+/// fixed-window limiter — without redis there's no shared counter, so allow the call
+async fn take_rate_token(
+    redis: Option<&mut redis::aio::MultiplexedConnection>,
+    config: &TranslateConfig,
+    access_token: &str,
+) -> bool {
+    let Some(conn) = redis else {
+        return true;
+    };
+    let key = rate_key(access_token);
+    let count: u64 = match conn.incr(&key, 1).await {
+        Ok(c) => c,
+        Err(_) => return true,
+    };
+    if count == 1 {
+        let _: redis::RedisResult<()> = conn.expire(&key, RATE_WINDOW_SECS).await;
+    }
+    count <= config.rate_limit
+}
+
+async fn request_batch(
+    config: &TranslateConfig,
+    lang: &str,
+    bodies: &[&str],
+) -> Result<Vec<String>, reqwest::Error> {
+    let client = reqwest::Client::new();
+    let mut body = serde_json::json!({
+        "q": bodies,
+        "source": "auto",
+        "target": lang,
+        "format": "text",
+    });
+    if let Some(key) = &config.api_key {
+        body["api_key"] = serde_json::Value::String(key.clone());
+    }
+
+    let response: serde_json::Value = client
+        .post(format!("{}/translate", config.api_url))
+        .timeout(std::time::Duration::from_secs(10))
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // a batched request answers with an array; a single string means one input
+    let results = match &response["translatedText"] {
+        serde_json::Value::Array(items) => items
+            .iter()
+            .map(|v| v.as_str().unwrap_or_default().to_string())
+            .collect(),
+        serde_json::Value::String(text) => vec![text.clone()],
+        _ => Vec::new(),
+    };
+    Ok(results)
+}
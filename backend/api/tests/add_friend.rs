@@ -0,0 +1,95 @@
+// adding friends by username, and the user directory search behind the add-friend box
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp};
+use serde_json::json;
+use sqlx::PgPool;
+
+#[sqlx::test]
+async fn a_plain_username_is_enough(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    let add = |friend_id: &str| {
+        app.post("/friends/add", json!({ "access_token": alice.access_token, "user_id": alice.user_id, "friend_id": friend_id }))
+    };
+    let (status, body) = add(" bob ").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["friend_id"], bob.user_id.as_str());
+    let (status, body) = add("@bob").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["friend_id"], bob.user_id.as_str());
+
+    // no dangling row for someone who doesn't exist
+    let (status, body) = add("nobody").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["errcode"], "M_NOT_FOUND");
+    assert_eq!(body["error"], "user not found");
+
+    let (_, friends) = app
+        .get(&format!("/friends?access_token={}&user_id={}", alice.access_token, enc(&alice.user_id)))
+        .await;
+    assert_eq!(friends["friends"], json!([{ "user_id": bob.user_id, "status": "pending_sent", "dm_room_id": null }]));
+}
+
+#[sqlx::test]
+async fn the_friends_list_pages_by_cursor(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    for name in ["bob", "carol", "dave", "erin"] {
+        app.register(name).await;
+        let body = json!({ "access_token": alice.access_token, "user_id": alice.user_id, "friend_id": name });
+        assert_eq!(app.post("/friends/add", body).await.0, StatusCode::OK);
+    }
+    let url = |extra: &str| format!("/friends?access_token={}{}", alice.access_token, extra);
+    let ids = |page: &serde_json::Value| -> Vec<String> {
+        page["items"].as_array().unwrap().iter().map(|f| f["user_id"].as_str().unwrap().to_string()).collect()
+    };
+
+    let (status, first) = app.get(&url("&limit=3")).await;
+    assert_eq!(status, StatusCode::OK, "{}", first);
+    assert_eq!(first["total"], 4);
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let (_, second) = app.get(&url(&format!("&limit=3&after={}", enc(cursor)))).await;
+    assert!(second.get("next_cursor").is_none());
+    // most recently changed first, each friend exactly once
+    let paged = [ids(&first), ids(&second)].concat();
+    assert_eq!(paged, ["erin", "dave", "carol", "bob"].map(|name| format!("@{}:localhost", name)));
+
+    // without a limit it's the old shape, all of it
+    let (_, everything) = app.get(&url("")).await;
+    assert_eq!(everything["friends"].as_array().unwrap().len(), 4);
+    assert_eq!(app.get(&url("&limit=3&after=garbage")).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn search_finds_users_by_name() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    app.register("bob").await;
+    app.register("bobby").await;
+
+    let search = |query: &str| format!("/users/search?access_token={}&query={}", alice.access_token, query);
+    let (status, body) = app.get(&search("bob")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({
+        "results": [
+            { "user_id": "@bob:localhost", "display_name": "bob", "avatar_url": null },
+            { "user_id": "@bobby:localhost", "display_name": "bobby", "avatar_url": null },
+        ],
+        "limited": false,
+    }));
+
+    let (_, body) = app.get(&search("bob&limit=1")).await;
+    assert_eq!(body["results"].as_array().unwrap().len(), 1);
+    assert_eq!(body["limited"], true);
+
+    let (_, body) = app.get(&search("%20")).await;
+    assert_eq!(body, json!({ "results": [], "limited": false }));
+
+    let (status, _) = app.get("/users/search?access_token=nope&query=bob").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
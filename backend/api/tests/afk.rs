@@ -0,0 +1,123 @@
+// afk channels: idle voice participants are moved there after the server's timeout
+
+mod common;
+
+use agora_api::routes::voice::move_idle_participants;
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use wiremock::matchers::{body_partial_json, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+/// the livekit room name the api uses for a matrix room
+fn room_name(room_id: &str) -> String {
+    room_id.trim_start_matches('!').replace([':', '.'], "_")
+}
+
+async fn set_meta(app: &TestApp, user: &TestUser, server_id: &str, fields: Value) -> StatusCode {
+    let mut body = json!({ "access_token": user.access_token, "server_id": server_id });
+    body.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+    app.post("/servers/meta", body).await.0
+}
+
+fn participants(list: Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({ "participants": list }))
+}
+
+/// the calls livekit received for one twirp method
+async fn calls(livekit: &MockServer, method: &str) -> Vec<Value> {
+    livekit
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.url.path() == format!("/twirp/livekit.RoomService/{}", method))
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect()
+}
+
+// livekit is configured through the environment, so everything that needs it
+// runs in this one test
+#[tokio::test]
+async fn idle_participants_are_moved_to_the_afk_channel() {
+    let livekit = MockServer::start().await;
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let lounge = create(&app, &alice, json!({ "name": "lounge", "channel_type": "voice", "parent_space_id": server_id })).await;
+    let afk = create(&app, &alice, json!({ "name": "afk", "channel_type": "voice", "parent_space_id": server_id })).await;
+    let now = chrono::Utc::now().timestamp() as u64;
+
+    // only a voice channel of this server, and a sensible timeout
+    assert_eq!(set_meta(&app, &alice, &server_id, json!({ "afk_channel_id": general })).await, StatusCode::BAD_REQUEST);
+    assert_eq!(set_meta(&app, &alice, &server_id, json!({ "afk_timeout_secs": 5 })).await, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        set_meta(&app, &alice, &server_id, json!({ "afk_channel_id": afk, "afk_timeout_secs": 300 })).await,
+        StatusCode::OK
+    );
+    let (_, meta) = app
+        .get(&format!("/servers/meta?access_token={}&server_id={}", alice.access_token, enc(&server_id)))
+        .await;
+    assert_eq!(meta["afk_channel_id"], afk.as_str());
+    assert_eq!(meta["afk_timeout_secs"], 300);
+
+    let lounge_room = json!({ "room": room_name(&lounge) });
+    Mock::given(path("/twirp/livekit.RoomService/ListParticipants"))
+        .and(body_partial_json(lounge_room.clone()))
+        .respond_with(participants(json!([
+            // joined long ago and never turned the mic on
+            { "identity": "@bob:localhost", "joined_at": (now - 1000).to_string(), "tracks": [] },
+            { "identity": "@carol:localhost", "joined_at": (now - 1000).to_string(), "tracks": [{ "type": "AUDIO", "muted": false }] },
+            { "identity": "@dave:localhost", "joined_at": (now - 100).to_string(), "tracks": [] },
+        ])))
+        .up_to_n_times(1)
+        .mount(&livekit)
+        .await;
+    Mock::given(path("/twirp/livekit.RoomService/ListParticipants"))
+        .and(body_partial_json(lounge_room))
+        .respond_with(participants(json!([
+            { "identity": "@carol:localhost", "joined_at": (now - 1000).to_string(), "tracks": [{ "type": "AUDIO", "muted": false }] },
+            { "identity": "@dave:localhost", "joined_at": (now - 100).to_string(), "tracks": [{ "type": "AUDIO", "muted": true }] },
+        ])))
+        .mount(&livekit)
+        .await;
+    Mock::given(path("/twirp/livekit.RoomService/MoveParticipant"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .mount(&livekit)
+        .await;
+    std::env::set_var("LIVEKIT_HTTP_URL", livekit.uri());
+
+    assert_eq!(move_idle_participants(&app.state, now).await, 1);
+    let moves = calls(&livekit, "MoveParticipant").await;
+    assert_eq!(moves, [json!({
+        "room": room_name(&lounge),
+        "identity": "@bob:localhost",
+        "destination_room": room_name(&afk),
+    })]);
+    // the afk channel itself is never swept
+    assert!(calls(&livekit, "ListParticipants").await.iter().all(|c| c["room"] == room_name(&lounge).as_str()));
+
+    let moved = app.homeserver.inspect(|hs| hs.timeline.last().unwrap().clone());
+    assert_eq!(moved.0, lounge);
+    assert_eq!(moved.1["type"], "agora.voice.moved");
+    assert_eq!(moved.1["content"], json!({ "user_id": "@bob:localhost", "room_id": lounge, "afk_channel_id": afk }));
+
+    // dave has been idle since he joined; muting doesn't reset that
+    assert_eq!(move_idle_participants(&app.state, now + 199).await, 0);
+    assert_eq!(move_idle_participants(&app.state, now + 200).await, 1);
+    assert_eq!(calls(&livekit, "MoveParticipant").await[1]["identity"], "@dave:localhost");
+
+    // turning the afk channel off stops the sweeps
+    assert_eq!(set_meta(&app, &alice, &server_id, json!({ "afk_channel_id": "" })).await, StatusCode::OK);
+    assert!(!app.redis.lock().unwrap().contains_key("afk_servers"));
+    assert_eq!(move_idle_participants(&app.state, now + 10_000).await, 0);
+}
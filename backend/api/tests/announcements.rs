@@ -0,0 +1,110 @@
+// announcement channels: following one from another server, publishing into
+// the followers, and unfollowing. these need postgres, like flows.rs.
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn join(app: &TestApp, user: &TestUser, room_id: &str) {
+    let (status, body) = app.post("/rooms/join", json!({ "access_token": user.access_token, "room_id_or_alias": room_id })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, content: &str) -> String {
+    let body = json!({ "access_token": user.access_token, "room_id": room_id, "content": content });
+    let (status, sent) = app.post("/rooms/send", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", sent);
+    sent["event_id"].as_str().unwrap().to_string()
+}
+
+fn follow_body(user: &TestUser, source: &str, target: &str) -> Value {
+    json!({ "access_token": user.access_token, "source_room_id": source, "target_room_id": target })
+}
+
+async fn publish(app: &TestApp, user: &TestUser, room_id: &str, event_id: &str) -> (StatusCode, Value) {
+    app.post("/rooms/announce", json!({ "access_token": user.access_token, "room_id": room_id, "event_id": event_id })).await
+}
+
+/// message contents in `room_id` that came from an announcement channel
+fn copies(app: &TestApp, room_id: &str) -> Vec<Value> {
+    app.homeserver.inspect(|hs| {
+        hs.timeline
+            .iter()
+            .filter(|(id, e)| id == room_id && !e["content"]["agora.announcement.source"].is_null())
+            .map(|(_, e)| e["content"].clone())
+            .collect()
+    })
+}
+
+#[sqlx::test]
+async fn followers_receive_published_messages(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+
+    let news_server = create(&app, &alice, json!({ "name": "News", "is_space": true })).await;
+    let news = create(&app, &alice, json!({ "name": "news", "channel_type": "announcement", "parent_space_id": news_server })).await;
+    let chat = create(&app, &alice, json!({ "name": "chat", "parent_space_id": news_server })).await;
+    let fans = create(&app, &bob, json!({ "name": "Fans", "is_space": true })).await;
+    let feed = create(&app, &bob, json!({ "name": "feed", "parent_space_id": fans })).await;
+    for (user, room_id) in [(&bob, &news_server), (&bob, &news), (&carol, &fans), (&carol, &news_server), (&carol, &news)] {
+        join(&app, user, room_id).await;
+    }
+
+    // only announcement channels can be followed, and only by whoever manages the target
+    assert_eq!(app.post("/rooms/announce/follow", follow_body(&bob, &chat, &feed)).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(app.post("/rooms/announce/follow", follow_body(&carol, &news, &feed)).await.0, StatusCode::FORBIDDEN);
+    let (status, body) = app.post("/rooms/announce/follow", follow_body(&bob, &news, &feed)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let followers_uri = format!("/rooms/announce/followers?access_token={}&room_id={}", alice.access_token, enc(&news));
+    let (status, listed) = app.get(&followers_uri).await;
+    assert_eq!(status, StatusCode::OK, "{}", listed);
+    assert_eq!(listed["followers"].as_array().unwrap().len(), 1);
+    assert_eq!(listed["followers"][0]["target_room_id"], feed.as_str());
+    assert_eq!(listed["followers"][0]["followed_by"], bob.user_id.as_str());
+
+    // the author publishes; someone else without manage_messages can't
+    let event_id = send(&app, &alice, &news, "v2 is out").await;
+    assert_eq!(publish(&app, &carol, &news, &event_id).await.0, StatusCode::FORBIDDEN);
+    let (status, report) = publish(&app, &alice, &news, &event_id).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["delivered"][0]["target_room_id"], feed.as_str());
+    assert_eq!(report["failed"], json!([]));
+
+    let delivered = copies(&app, &feed);
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0]["body"], "v2 is out");
+    let source = &delivered[0]["agora.announcement.source"];
+    assert_eq!(source["server_id"], news_server.as_str());
+    assert_eq!(source["server_name"], "News");
+    assert_eq!(source["room_id"], news.as_str());
+    assert_eq!(source["room_name"], "news");
+    assert_eq!(source["event_id"], event_id.as_str());
+    assert_eq!(source["sender"], alice.user_id.as_str());
+
+    // messages outside announcement channels can't be published
+    let chatter = send(&app, &alice, &chat, "hi").await;
+    assert_eq!(publish(&app, &alice, &chat, &chatter).await.0, StatusCode::BAD_REQUEST);
+
+    // once unfollowed, nothing more arrives
+    let unfollow = |user: &TestUser| app.request(Method::DELETE, "/rooms/announce/follow", Some(follow_body(user, &news, &feed)));
+    assert_eq!(unfollow(&carol).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(unfollow(&bob).await.0, StatusCode::OK);
+    assert_eq!(unfollow(&bob).await.0, StatusCode::NOT_FOUND);
+    let (_, report) = publish(&app, &alice, &news, &send(&app, &alice, &news, "v3 is out").await).await;
+    assert_eq!(report["delivered"], json!([]));
+    assert_eq!(copies(&app, &feed).len(), 1);
+}
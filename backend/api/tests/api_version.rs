@@ -0,0 +1,123 @@
+// the client-server api version is probed from /versions once and used for
+// every request after it: v3 on matrix 1.x homeservers, r0 on older ones
+
+mod common;
+
+use agora_api::matrix::client::{ApiVersion, ApiVersionCache, MatrixClient};
+use agora_api::matrix::retry::RetryPolicy;
+use serde_json::json;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// a homeserver advertising `versions` that answers whoami under any version
+async fn homeserver_advertising(versions: &[&str]) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "versions": versions })))
+        .mount(&server)
+        .await;
+    for version in ["v3", "r0"] {
+        Mock::given(method("GET"))
+            .and(path(format!("/_matrix/client/{}/account/whoami", version)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "user_id": "@alice:localhost" })))
+            .mount(&server)
+            .await;
+    }
+    server
+}
+
+fn client(server: &MockServer, api_version: ApiVersionCache) -> MatrixClient {
+    let mut matrix = MatrixClient::new(server.uri())
+        .with_retry(RetryPolicy { max_retries: 0, ..RetryPolicy::default() })
+        .with_api_version(api_version);
+    matrix.access_token = Some("token".to_string());
+    matrix
+}
+
+/// the paths the server saw, in order
+async fn paths(server: &MockServer) -> Vec<String> {
+    server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| r.url.path().to_string())
+        .collect()
+}
+
+#[test]
+fn versions_pick_the_newest_supported_api() {
+    let versions = |v: &[&str]| ApiVersion::from_versions(&v.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+    assert_eq!(versions(&["r0.5.0", "r0.6.1"]), ApiVersion::R0);
+    assert_eq!(versions(&["r0.6.1", "v1.1", "v1.2"]), ApiVersion::V3);
+    assert_eq!(versions(&["v1.8"]), ApiVersion::V3);
+    // nothing recognisable — assume current
+    assert_eq!(versions(&[]), ApiVersion::V3);
+}
+
+#[tokio::test]
+async fn modern_homeservers_get_v3_paths() {
+    let server = homeserver_advertising(&["r0.6.1", "v1.1", "v1.2"]).await;
+    let matrix = client(&server, ApiVersionCache::default());
+
+    matrix.whoami().await.unwrap();
+    assert_eq!(matrix.client_api_base().await, format!("{}/_matrix/client/v3", server.uri()));
+    assert_eq!(paths(&server).await, ["/_matrix/client/versions", "/_matrix/client/v3/account/whoami"]);
+}
+
+#[tokio::test]
+async fn r0_only_homeservers_get_r0_paths() {
+    let server = homeserver_advertising(&["r0.5.0", "r0.6.1"]).await;
+    let matrix = client(&server, ApiVersionCache::default());
+
+    matrix.whoami().await.unwrap();
+    assert_eq!(matrix.media_api_base().await, format!("{}/_matrix/media/r0", server.uri()));
+    assert_eq!(paths(&server).await, ["/_matrix/client/versions", "/_matrix/client/r0/account/whoami"]);
+}
+
+#[tokio::test]
+async fn versions_are_probed_once_per_shared_cache() {
+    let server = homeserver_advertising(&["r0.6.1"]).await;
+    let shared = ApiVersionCache::default();
+
+    for _ in 0..3 {
+        client(&server, shared.clone()).whoami().await.unwrap();
+    }
+    let probes = paths(&server).await.iter().filter(|p| p.ends_with("/versions")).count();
+    assert_eq!(probes, 1);
+}
+
+#[tokio::test]
+async fn a_failed_probe_falls_back_to_v3_and_is_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    let matrix = client(&server, ApiVersionCache::default());
+
+    assert_eq!(matrix.client_api_base().await, format!("{}/_matrix/client/v3", server.uri()));
+    matrix.client_api_base().await;
+    assert_eq!(paths(&server).await.len(), 2);
+}
+
+#[tokio::test]
+async fn the_api_probes_the_homeserver_once() {
+    let app = common::TestApp::new().await;
+    app.register("alice").await;
+    app.register("bob").await;
+
+    assert!(app.state.matrix_api.get().is_some());
+    let probes = app
+        .homeserver
+        .server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|r| r.url.path() == "/_matrix/client/versions")
+        .count();
+    assert_eq!(probes, 1);
+}
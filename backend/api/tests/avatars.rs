@@ -0,0 +1,136 @@
+// avatars: set through /profile/set, or uploaded and set in one go through
+// /profile/avatar, which checks and scales the image first
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use std::io::Cursor;
+use tower::ServiceExt;
+
+const BOUNDARY: &str = "avatar-boundary";
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let image = image::RgbImage::from_fn(width, height, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 128]));
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, image::ImageFormat::Png).unwrap();
+    out.into_inner()
+}
+
+async fn upload(app: &TestApp, user: &TestUser, content_type: &str, bytes: &[u8]) -> (StatusCode, Value) {
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"me\"\r\nContent-Type: {content_type}\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(bytes);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(format!("/profile/avatar?access_token={}", user.access_token))
+        .header("content-type", format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(body))
+        .unwrap();
+    let response = app.router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+async fn avatar_url(app: &TestApp, user: &TestUser) -> Value {
+    let (_, profile) = app
+        .get(&format!("/profile/get?access_token={}&user_id={}", user.access_token, enc(&user.user_id)))
+        .await;
+    profile["avatar_url"].clone()
+}
+
+#[tokio::test]
+async fn an_upload_is_scaled_down_and_becomes_the_avatar() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let (status, body) = upload(&app, &alice, "image/png", &png(1024, 600)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let mxc = body["avatar_url"].as_str().unwrap();
+    let media_id = mxc.strip_prefix("mxc://localhost/").unwrap();
+    assert_eq!(body["http_url"], format!("/media/download/localhost/{}", media_id));
+    assert_eq!(avatar_url(&app, &alice).await, mxc);
+
+    let request = Request::builder().uri(body["http_url"].as_str().unwrap()).body(Body::empty()).unwrap();
+    let response = app.router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let stored = image::load_from_memory(&bytes).unwrap();
+    assert_eq!((stored.width(), stored.height()), (512, 300));
+}
+
+#[tokio::test]
+async fn small_images_are_stored_as_they_are() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let original = png(64, 64);
+
+    // the declared type is only a gate; the stored type comes from the bytes
+    let (status, body) = upload(&app, &alice, "image/jpeg", &original).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let media_id = body["avatar_url"].as_str().unwrap().rsplit('/').next().unwrap().to_string();
+    let stored = app.homeserver.inspect(|hs| hs.media[&media_id].clone());
+    assert_eq!(stored, ("image/png".to_string(), original));
+}
+
+#[tokio::test]
+async fn anything_but_a_reasonable_image_is_refused() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let (status, body) = upload(&app, &alice, "text/plain", b"hello").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+
+    // claims to be an image, isn't one
+    let (status, _) = upload(&app, &alice, "image/png", b"<svg onload=alert(1)>").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = upload(&app, &alice, "image/png", &vec![0; 8 * 1024 * 1024 + 1]).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["errcode"], "M_TOO_LARGE");
+
+    assert_eq!(avatar_url(&app, &alice).await, Value::Null);
+    assert!(app.homeserver.inspect(|hs| hs.media.is_empty()));
+
+    let stranger = TestUser { user_id: alice.user_id.clone(), access_token: "nope".to_string() };
+    let (status, _) = upload(&app, &stranger, "image/png", &png(8, 8)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn set_profile_applies_both_fields() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let set = |body: Value| {
+        let mut body = body;
+        body["access_token"] = json!(alice.access_token);
+        body["user_id"] = json!(alice.user_id);
+        app.request(Method::PUT, "/profile/set", Some(body))
+    };
+
+    let (status, _) = set(json!({ "displayname": "Alice", "avatar_url": "mxc://localhost/abc" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, profile) = app
+        .get(&format!("/profile/get?access_token={}&user_id={}", alice.access_token, enc(&alice.user_id)))
+        .await;
+    assert_eq!(profile["displayname"], "Alice");
+    assert_eq!(profile["avatar_url"], "mxc://localhost/abc");
+
+    let (status, _) = set(json!({ "avatar_url": "https://example.com/me.png" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(avatar_url(&app, &alice).await, "mxc://localhost/abc");
+
+    // an empty string clears it
+    let (status, _) = set(json!({ "avatar_url": "" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(avatar_url(&app, &alice).await, Value::Null);
+}
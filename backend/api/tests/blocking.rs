@@ -0,0 +1,94 @@
+// blocking users: no friend requests either way, and no messages in sync.
+// these need postgres, like flows.rs.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn friends_action(app: &TestApp, path: &str, user: &TestUser, other: &TestUser) -> StatusCode {
+    let body = json!({ "access_token": user.access_token, "user_id": user.user_id, "friend_id": other.user_id });
+    app.post(path, body).await.0
+}
+
+async fn friends(app: &TestApp, user: &TestUser) -> Value {
+    let (status, body) = app
+        .get(&format!("/friends?access_token={}&user_id={}", user.access_token, enc(&user.user_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    body["friends"].clone()
+}
+
+async fn blocked(app: &TestApp, user: &TestUser) -> Vec<String> {
+    let (status, body) = app
+        .get(&format!("/friends/blocked?access_token={}&user_id={}", user.access_token, enc(&user.user_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    body["blocked"].as_array().unwrap().iter().map(|b| b["user_id"].as_str().unwrap().to_string()).collect()
+}
+
+#[sqlx::test]
+async fn a_block_stops_requests_both_ways(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    // the pending request is dropped by the block
+    assert_eq!(friends_action(&app, "/friends/add", &bob, &alice).await, StatusCode::OK);
+    assert_eq!(friends_action(&app, "/friends/block", &alice, &bob).await, StatusCode::OK);
+    assert_eq!(friends(&app, &alice).await, json!([]));
+    assert_eq!(friends(&app, &bob).await, json!([]));
+    assert_eq!(blocked(&app, &alice).await, [bob.user_id.as_str()]);
+    assert!(blocked(&app, &bob).await.is_empty());
+
+    assert_eq!(friends_action(&app, "/friends/add", &bob, &alice).await, StatusCode::FORBIDDEN);
+    assert_eq!(friends_action(&app, "/friends/add", &alice, &bob).await, StatusCode::FORBIDDEN);
+
+    // only the blocker can lift it
+    assert_eq!(friends_action(&app, "/friends/unblock", &bob, &alice).await, StatusCode::NOT_FOUND);
+    assert_eq!(friends_action(&app, "/friends/unblock", &alice, &bob).await, StatusCode::OK);
+    assert!(blocked(&app, &alice).await.is_empty());
+    assert_eq!(friends_action(&app, "/friends/add", &bob, &alice).await, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn blocking_a_friend_ends_the_friendship(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    friends_action(&app, "/friends/add", &alice, &bob).await;
+    friends_action(&app, "/friends/accept", &bob, &alice).await;
+
+    assert_eq!(friends_action(&app, "/friends/block", &bob, &alice).await, StatusCode::OK);
+    assert_eq!(friends(&app, &alice).await, json!([]));
+    assert_eq!(blocked(&app, &bob).await, [alice.user_id.as_str()]);
+    assert_eq!(friends_action(&app, "/friends/block", &bob, &bob).await, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn sync_leaves_out_blocked_senders(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    for user in [&bob, &carol] {
+        app.post("/rooms/join", json!({ "access_token": user.access_token, "room_id_or_alias": room_id })).await;
+        app.post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": "hi" })).await;
+    }
+    friends_action(&app, "/friends/block", &alice, &bob).await;
+
+    let senders = |sync: &Value| -> Vec<String> {
+        sync["messages"].as_array().unwrap().iter().map(|m| m["sender"].as_str().unwrap().to_string()).collect()
+    };
+    let (status, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(senders(&sync), [carol.user_id.as_str()]);
+
+    // the block is alice's alone
+    let (_, sync) = app.get(&format!("/sync?access_token={}", carol.access_token)).await;
+    assert!(senders(&sync).contains(&bob.user_id));
+}
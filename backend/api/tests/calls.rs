@@ -0,0 +1,120 @@
+// call signaling: unanswered rings time out, and the room keeps a call history
+
+mod common;
+
+use agora_api::routes::voice::expire_rings;
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a room alice and bob are both in
+async fn dm(app: &TestApp) -> (TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "dm" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    (alice, bob, room_id)
+}
+
+async fn call(app: &TestApp, user: &TestUser, room_id: &str, call_id: &str, action: &str) {
+    let (status, _) = app
+        .post("/voice/call", json!({
+            "access_token": user.access_token,
+            "room_id": room_id,
+            "action": action,
+            "call_id": call_id,
+            "from_user_id": user.user_id,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+fn ringing(app: &TestApp) -> usize {
+    app.redis.lock().unwrap().keys().filter(|k| k.starts_with("call_ring:")).count()
+}
+
+fn now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+#[tokio::test]
+async fn unanswered_rings_time_out_once() {
+    let app = TestApp::new().await;
+    let (alice, _, room_id) = dm(&app).await;
+
+    call(&app, &alice, &room_id, "c1", "ring").await;
+    assert_eq!(ringing(&app), 1);
+
+    // not yet
+    assert_eq!(expire_rings(&app.state, now()).await, 0);
+    assert_eq!(ringing(&app), 1);
+
+    assert_eq!(expire_rings(&app.state, now() + 46).await, 1);
+    assert_eq!(ringing(&app), 0);
+    let timeout = app.homeserver.inspect(|hs| hs.timeline.last().unwrap().1.clone());
+    assert_eq!(timeout["sender"], alice.user_id.as_str());
+    assert_eq!(timeout["content"]["msgtype"], "agora.call");
+    assert_eq!(timeout["content"]["action"], "timeout");
+    assert_eq!(timeout["content"]["call_id"], "c1");
+    assert_eq!(timeout["content"]["from"], alice.user_id.as_str());
+
+    assert_eq!(expire_rings(&app.state, now() + 46).await, 0);
+}
+
+#[tokio::test]
+async fn answered_and_cancelled_rings_stop_ringing() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = dm(&app).await;
+
+    call(&app, &alice, &room_id, "c1", "ring").await;
+    call(&app, &bob, &room_id, "c1", "accept").await;
+    call(&app, &alice, &room_id, "c2", "ring").await;
+    call(&app, &alice, &room_id, "c2", "cancel").await;
+    assert_eq!(ringing(&app), 0);
+    assert_eq!(expire_rings(&app.state, now() + 46).await, 0);
+}
+
+#[tokio::test]
+async fn history_says_how_each_call_ended() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = dm(&app).await;
+
+    call(&app, &alice, &room_id, "answered", "ring").await;
+    call(&app, &bob, &room_id, "answered", "accept").await;
+    call(&app, &alice, &room_id, "declined", "ring").await;
+    call(&app, &bob, &room_id, "declined", "cancel").await;
+    call(&app, &bob, &room_id, "hung-up", "ring").await;
+    call(&app, &bob, &room_id, "hung-up", "cancel").await;
+    call(&app, &alice, &room_id, "missed", "ring").await;
+    expire_rings(&app.state, now() + 46).await;
+    call(&app, &alice, &room_id, "ringing", "ring").await;
+    // plain messages in between are skipped
+    app.post("/rooms/send", json!({ "access_token": bob.access_token, "room_id": room_id, "content": "hi" })).await;
+
+    let url = format!("/voice/call/history?access_token={}&room_id={}", bob.access_token, enc(&room_id));
+    let (status, body) = app.get(&url).await;
+    assert_eq!(status, StatusCode::OK);
+    let calls: Vec<(&str, &str)> = body["calls"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["call_id"].as_str().unwrap(), c["outcome"].as_str().unwrap()))
+        .collect();
+    assert_eq!(calls, [
+        ("ringing", "ringing"),
+        ("missed", "missed"),
+        ("hung-up", "cancelled"),
+        ("declined", "declined"),
+        ("answered", "completed"),
+    ]);
+    assert_eq!(body["calls"][2]["caller"], bob.user_id.as_str());
+    assert!(body["calls"][0]["started_at"].is_number());
+
+    let (_, body) = app.get(&format!("{}&limit=2", url)).await;
+    assert_eq!(body["calls"].as_array().unwrap().len(), 2);
+    assert_eq!(body["calls"][1]["outcome"], Value::from("missed"));
+}
@@ -0,0 +1,93 @@
+// deleting a channel: whoever manages channels empties it and unlists it from
+// its space; rooms outside any server are only left, as before.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+fn membership(app: &TestApp, room_id: &str, user: &TestUser) -> Option<String> {
+    app.homeserver.inspect(|hs| hs.rooms[room_id].membership(&user.user_id).map(String::from))
+}
+
+async fn delete(app: &TestApp, user: &TestUser, body: Value) -> (StatusCode, Value) {
+    let mut body = body;
+    body["access_token"] = json!(user.access_token);
+    app.post("/rooms/delete", body).await
+}
+
+#[tokio::test]
+async fn deleting_a_channel_empties_and_unlists_it() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let random = create(&app, &alice, json!({ "name": "random", "parent_space_id": server_id })).await;
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": server_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    assert_eq!(membership(&app, &general, &bob).as_deref(), Some("join"));
+
+    // a member without manage_channels can't, and stays in it
+    let (status, body) = delete(&app, &bob, json!({ "room_id": general })).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(membership(&app, &general, &bob).as_deref(), Some("join"));
+
+    // the parent has to actually list the channel
+    let (status, body) = delete(&app, &alice, json!({ "room_id": general, "parent_space_id": random })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "AGORA_NOT_A_CHILD");
+
+    let (status, report) = delete(&app, &alice, json!({ "room_id": general })).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["deleted"], true);
+    assert_eq!(report["members_removed"], 1);
+    assert_eq!(report["complete"], true);
+    assert_eq!(membership(&app, &general, &bob).as_deref(), Some("leave"));
+    assert_eq!(membership(&app, &general, &alice).as_deref(), Some("leave"));
+    assert_eq!(membership(&app, &random, &bob).as_deref(), Some("join"));
+
+    app.homeserver.inspect(|hs| {
+        let space = &hs.rooms[&server_id];
+        assert_eq!(space.state[&("m.space.child".into(), general.clone())]["content"], json!({}));
+        let announced = hs
+            .timeline
+            .iter()
+            .find(|(room_id, e)| *room_id == server_id && e["type"] == "agora.room.deleted")
+            .map(|(_, e)| e["content"].clone())
+            .unwrap();
+        assert_eq!(announced["room_id"], general.as_str());
+        assert_eq!(announced["deleted_by"], alice.user_id.as_str());
+    });
+
+    // the server itself isn't deleted this way — only left
+    let (status, report) = delete(&app, &bob, json!({ "room_id": server_id })).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["deleted"], false);
+    assert_eq!(membership(&app, &server_id, &alice).as_deref(), Some("join"));
+}
+
+#[tokio::test]
+async fn rooms_outside_a_server_are_only_left() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = create(&app, &alice, json!({ "name": "hangout" })).await;
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": room_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+
+    let (status, report) = delete(&app, &bob, json!({ "room_id": room_id })).await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["deleted"], false);
+    assert_eq!(membership(&app, &room_id, &bob).as_deref(), Some("leave"));
+    assert_eq!(membership(&app, &room_id, &alice).as_deref(), Some("join"));
+}
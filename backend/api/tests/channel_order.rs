@@ -0,0 +1,159 @@
+// channel order within a space and moving channels between categories
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let path = if body["category"] == true { "/rooms/category/create" } else { "/rooms/create" };
+    let (status, room) = app.post(path, body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn children(app: &TestApp, user: &TestUser, space_id: &str) -> Vec<(String, Value)> {
+    let (status, body) = app
+        .get(&format!("/rooms/children?access_token={}&space_id={}", user.access_token, enc(space_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    body["children"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["room_id"].as_str().unwrap().to_string(), c["order"].clone()))
+        .collect()
+}
+
+fn ids(children: &[(String, Value)]) -> Vec<&str> {
+    children.iter().map(|(id, _)| id.as_str()).collect()
+}
+
+#[tokio::test]
+async fn children_follow_the_saved_order() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let a = create(&app, &alice, json!({ "name": "a", "parent_space_id": server_id })).await;
+    let b = create(&app, &alice, json!({ "name": "b", "parent_space_id": server_id })).await;
+    let c = create(&app, &alice, json!({ "name": "c", "parent_space_id": server_id })).await;
+
+    // unordered children come back by room id, without an order
+    let listed = children(&app, &alice, &server_id).await;
+    let mut by_id = vec![a.as_str(), b.as_str(), c.as_str()];
+    by_id.sort();
+    assert_eq!(ids(&listed), by_id);
+    assert!(listed.iter().all(|(_, order)| order.is_null()));
+
+    let (status, _) = app
+        .post("/rooms/reorder", json!({
+            "access_token": alice.access_token,
+            "space_id": server_id,
+            "child_room_ids": [c, a, b],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let listed = children(&app, &alice, &server_id).await;
+    assert_eq!(ids(&listed), [c.as_str(), a.as_str(), b.as_str()]);
+    assert!(listed.iter().all(|(_, order)| order.is_string()));
+
+    // the via list written at creation survives the reorder
+    app.homeserver.inspect(|hs| {
+        let child = &hs.rooms[&server_id].state[&("m.space.child".to_string(), a.clone())];
+        assert_eq!(child["content"]["via"], json!(["localhost"]));
+    });
+}
+
+#[tokio::test]
+async fn a_channel_can_move_into_another_category() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let category_id = create(&app, &alice, json!({ "name": "voice", "category": true, "parent_space_id": server_id })).await;
+    let lobby = create(&app, &alice, json!({ "name": "lobby", "parent_space_id": category_id })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+
+    let (status, _) = app
+        .post("/rooms/reorder", json!({
+            "access_token": alice.access_token,
+            "space_id": category_id,
+            "child_room_ids": [general, lobby],
+            "from_space_id": server_id,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    assert_eq!(ids(&children(&app, &alice, &category_id).await), [general.as_str(), lobby.as_str()]);
+    assert_eq!(ids(&children(&app, &alice, &server_id).await), [category_id.as_str()]);
+
+    // the channel's own parent pointer moved with it
+    app.homeserver.inspect(|hs| {
+        let room = &hs.rooms[&general];
+        assert_eq!(room.state[&("m.space.parent".to_string(), category_id.clone())]["content"]["canonical"], true);
+        assert_eq!(room.state[&("m.space.parent".to_string(), server_id.clone())]["content"], json!({}));
+    });
+}
+
+#[tokio::test]
+async fn reordering_checks_its_input() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let stray = create(&app, &alice, json!({ "name": "stray" })).await;
+
+    // a room from elsewhere without saying where from
+    let (status, body) = app
+        .post("/rooms/reorder", json!({
+            "access_token": alice.access_token,
+            "space_id": server_id,
+            "child_room_ids": [stray, general],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+
+    // a channel isn't a space
+    let (status, _) = app
+        .post("/rooms/reorder", json!({
+            "access_token": alice.access_token,
+            "space_id": general,
+            "child_room_ids": [],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .post("/rooms/reorder", json!({
+            "access_token": bob.access_token,
+            "space_id": server_id,
+            "child_room_ids": [general],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn removed_children_are_no_longer_listed() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+
+    let (status, _) = app
+        .post("/rooms/remove_child", json!({
+            "access_token": alice.access_token,
+            "space_id": server_id,
+            "child_room_id": general,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(children(&app, &alice, &server_id).await.is_empty());
+}
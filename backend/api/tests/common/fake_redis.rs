@@ -0,0 +1,223 @@
+// a tiny in-process redis: speaks enough RESP2 for the commands the api uses
+// (GET / SET [NX] / SETEX / DEL / MGET / INCR / EXPIRE / TTL / KEYS / SCAN, HSET /
+// HGET / HGETALL / HDEL / HKEYS on hashes, and SADD / SREM / SMEMBERS on sets).
+// expiry is accepted but never enforced or tracked — no test runs long enough
+// to care. a hash is kept as a json object under its key, a set as a json
+// array, so tests can read them like any other value. `bounce` drops every
+// open connection, the way a redis restart looks to the api.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+pub type Store = Arc<Mutex<HashMap<String, String>>>;
+
+pub struct FakeRedis {
+    pub url: String,
+    pub store: Store,
+    connections: Arc<Mutex<Vec<tokio::task::AbortHandle>>>,
+}
+
+impl FakeRedis {
+    /// close every connection; the keyspace and the listener stay
+    pub fn bounce(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            connection.abort();
+        }
+    }
+}
+
+/// start the server
+pub async fn start() -> FakeRedis {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let store: Store = Arc::default();
+    let connections: Arc<Mutex<Vec<tokio::task::AbortHandle>>> = Arc::default();
+
+    let (accept_store, accepted) = (store.clone(), connections.clone());
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let connection = tokio::spawn(serve(socket, accept_store.clone()));
+            accepted.lock().unwrap().push(connection.abort_handle());
+        }
+    });
+
+    FakeRedis { url: format!("redis://{}", addr), store, connections }
+}
+
+async fn serve(socket: tokio::net::TcpStream, store: Store) {
+    let (read, mut write) = socket.into_split();
+    let mut reader = BufReader::new(read);
+    while let Some(args) = read_command(&mut reader).await {
+        let reply = execute(&store, &args);
+        if write.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// one command as an array of bulk strings
+async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<Vec<String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok().filter(|n| *n > 0)?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut buf = vec![0u8; len + 2];
+        reader.read_exact(&mut buf).await.ok()?;
+        buf.truncate(len);
+        args.push(String::from_utf8(buf).ok()?);
+    }
+    Some(args)
+}
+
+fn bulk(value: Option<&String>) -> String {
+    match value {
+        Some(v) => format!("${}\r\n{}\r\n", v.len(), v),
+        None => "$-1\r\n".to_string(),
+    }
+}
+
+/// the hash at `key` — empty when missing, like redis
+fn hash(data: &HashMap<String, String>, key: &str) -> serde_json::Map<String, serde_json::Value> {
+    data.get(key).and_then(|v| serde_json::from_str(v).ok()).unwrap_or_default()
+}
+
+/// write a hash back; an emptied hash disappears, like redis
+fn store_hash(data: &mut HashMap<String, String>, key: &str, fields: serde_json::Map<String, serde_json::Value>) {
+    if fields.is_empty() {
+        data.remove(key);
+    } else {
+        data.insert(key.to_string(), serde_json::Value::Object(fields).to_string());
+    }
+}
+
+/// the set at `key`, kept as a json array
+fn set(data: &HashMap<String, String>, key: &str) -> Vec<String> {
+    data.get(key).and_then(|v| serde_json::from_str(v).ok()).unwrap_or_default()
+}
+
+fn store_set(data: &mut HashMap<String, String>, key: &str, members: Vec<String>) {
+    if members.is_empty() {
+        data.remove(key);
+    } else {
+        data.insert(key.to_string(), serde_json::json!(members).to_string());
+    }
+}
+
+fn execute(store: &Store, args: &[String]) -> String {
+    let mut data = store.lock().unwrap();
+    let command = args.first().map(|c| c.to_ascii_uppercase()).unwrap_or_default();
+    match (command.as_str(), &args[1..]) {
+        ("PING", _) => "+PONG\r\n".to_string(),
+        ("GET", [key]) => bulk(data.get(key)),
+        ("SET", [key, _, options @ ..])
+            if options.iter().any(|o| o.eq_ignore_ascii_case("NX")) && data.contains_key(key) =>
+        {
+            "$-1\r\n".to_string()
+        }
+        ("SET", [key, value, ..]) | ("SETEX", [key, _, value]) => {
+            data.insert(key.clone(), value.clone());
+            "+OK\r\n".to_string()
+        }
+        ("DEL", keys) => {
+            let removed = keys.iter().filter(|k| data.remove(*k).is_some()).count();
+            format!(":{}\r\n", removed)
+        }
+        ("MGET", keys) => {
+            let values: String = keys.iter().map(|k| bulk(data.get(k))).collect();
+            format!("*{}\r\n{}", keys.len(), values)
+        }
+        ("INCR", [key]) | ("INCRBY", [key, _]) => {
+            let by = args.get(2).and_then(|b| b.parse::<i64>().ok()).unwrap_or(1);
+            let next = data.get(key).and_then(|v| v.parse::<i64>().ok()).unwrap_or(0) + by;
+            data.insert(key.clone(), next.to_string());
+            format!(":{}\r\n", next)
+        }
+        ("EXPIRE", [key, ..]) => format!(":{}\r\n", data.contains_key(key) as i32),
+        // -1: exists without a (tracked) expiry, -2: missing
+        ("TTL", [key]) => format!(":{}\r\n", if data.contains_key(key) { -1 } else { -2 }),
+        ("KEYS", [pattern]) => {
+            let prefix = pattern.trim_end_matches('*');
+            let keys: Vec<&String> = data.keys().filter(|k| k.starts_with(prefix)).collect();
+            let values: String = keys.iter().map(|k| bulk(Some(k))).collect();
+            format!("*{}\r\n{}", keys.len(), values)
+        }
+        // the cursor is an offset into the sorted matching keys
+        ("SCAN", [cursor, options @ ..]) => {
+            let option = |name: &str| {
+                options.chunks(2).find(|o| o[0].eq_ignore_ascii_case(name)).and_then(|o| o.get(1))
+            };
+            let prefix = option("MATCH").map(|p| p.trim_end_matches('*')).unwrap_or("");
+            let count: usize = option("COUNT").and_then(|c| c.parse().ok()).unwrap_or(10);
+            let start: usize = cursor.parse().unwrap_or(0);
+            let mut keys: Vec<&String> = data.keys().filter(|k| k.starts_with(prefix)).collect();
+            keys.sort();
+            let page: String = keys.iter().skip(start).take(count).map(|k| bulk(Some(k))).collect();
+            let returned = keys.len().saturating_sub(start).min(count);
+            let next = if start + count < keys.len() { start + count } else { 0 };
+            let next = next.to_string();
+            format!("*2\r\n{}*{}\r\n{}", bulk(Some(&next)), returned, page)
+        }
+        ("HSET", [key, pairs @ ..]) => {
+            let mut fields = hash(&data, key);
+            let added = pairs
+                .chunks(2)
+                .filter(|pair| fields.insert(pair[0].clone(), pair[1].clone().into()).is_none())
+                .count();
+            store_hash(&mut data, key, fields);
+            format!(":{}\r\n", added)
+        }
+        ("HDEL", [key, names @ ..]) => {
+            let mut fields = hash(&data, key);
+            let removed = names.iter().filter(|name| fields.remove(*name).is_some()).count();
+            store_hash(&mut data, key, fields);
+            format!(":{}\r\n", removed)
+        }
+        ("HGET", [key, name]) => bulk(hash(&data, key).get(name).and_then(|v| v.as_str()).map(String::from).as_ref()),
+        ("HGETALL", [key]) => {
+            let fields = hash(&data, key);
+            let pairs: String = fields
+                .iter()
+                .map(|(k, v)| bulk(Some(k)) + &bulk(v.as_str().map(String::from).as_ref()))
+                .collect();
+            format!("*{}\r\n{}", fields.len() * 2, pairs)
+        }
+        ("HKEYS", [key]) => {
+            let fields = hash(&data, key);
+            let names: String = fields.keys().map(|k| bulk(Some(k))).collect();
+            format!("*{}\r\n{}", fields.len(), names)
+        }
+        ("SADD", [key, new @ ..]) => {
+            let mut members = set(&data, key);
+            let mut added = 0;
+            for member in new {
+                if !members.contains(member) {
+                    members.push(member.clone());
+                    added += 1;
+                }
+            }
+            store_set(&mut data, key, members);
+            format!(":{}\r\n", added)
+        }
+        ("SREM", [key, gone @ ..]) => {
+            let mut members = set(&data, key);
+            let before = members.len();
+            members.retain(|m| !gone.contains(m));
+            let removed = before - members.len();
+            store_set(&mut data, key, members);
+            format!(":{}\r\n", removed)
+        }
+        ("SMEMBERS", [key]) => {
+            let members = set(&data, key);
+            let values: String = members.iter().map(|m| bulk(Some(m))).collect();
+            format!("*{}\r\n{}", members.len(), values)
+        }
+        // connection setup (CLIENT SETINFO, SELECT, ...) — accept and move on
+        _ => "+OK\r\n".to_string(),
+    }
+}
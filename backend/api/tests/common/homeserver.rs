@@ -0,0 +1,939 @@
+// an in-memory stand-in for conduit, served by wiremock. it keeps just enough
+// state (users, tokens, rooms, state events, timelines) for the api's flows to
+// behave the way they do against a real homeserver. anything it doesn't know
+// answers 404 M_UNRECOGNIZED, like an unimplemented endpoint would.
+
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use wiremock::matchers::any;
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// the server name unless a test asks for another
+pub const SERVER_NAME: &str = "localhost";
+/// advertised as m.upload.size
+pub const UPLOAD_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Default)]
+pub struct Room {
+    /// (event type, state key) → full state event
+    pub state: BTreeMap<(String, String), Value>,
+}
+
+impl Room {
+    fn content(&self, event_type: &str, state_key: &str) -> Option<&Value> {
+        self.state
+            .get(&(event_type.to_string(), state_key.to_string()))
+            .map(|e| &e["content"])
+    }
+
+    pub fn membership(&self, user_id: &str) -> Option<&str> {
+        self.content("m.room.member", user_id)?["membership"].as_str()
+    }
+
+    fn power(&self, user_id: &str) -> i64 {
+        let power = self.content("m.room.power_levels", "").cloned().unwrap_or_default();
+        power["users"][user_id].as_i64().or(power["users_default"].as_i64()).unwrap_or(0)
+    }
+
+    /// power needed to set a state event of this type
+    fn state_level(&self, event_type: &str) -> i64 {
+        let power = self.content("m.room.power_levels", "").cloned().unwrap_or_default();
+        power["events"][event_type].as_i64().or(power["state_default"].as_i64()).unwrap_or(50)
+    }
+
+    /// give a user a power level directly, bypassing the power-level checks
+    pub fn set_power(&mut self, user_id: &str, level: i64) {
+        let key = ("m.room.power_levels".to_string(), String::new());
+        if let Some(event) = self.state.get_mut(&key) {
+            event["content"]["users"][user_id] = json!(level);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HomeserverState {
+    pub server_name: String,
+    /// user id → password
+    pub users: HashMap<String, String>,
+    /// access token → user id
+    pub tokens: HashMap<String, String>,
+    /// access token → the device it was issued to
+    pub devices: HashMap<String, String>,
+    pub rooms: HashMap<String, Room>,
+    /// room alias → room id
+    pub aliases: HashMap<String, String>,
+    /// user id → display name; registering sets it to the localpart, like conduit
+    pub displaynames: HashMap<String, String>,
+    /// user id → mxc:// avatar uri
+    pub avatar_urls: HashMap<String, String>,
+    /// media id → (content type, bytes) of everything uploaded
+    pub media: HashMap<String, (String, Vec<u8>)>,
+    /// how many profile lookups have been answered
+    pub profile_lookups: usize,
+    /// how many whole-room state reads have been answered
+    pub state_reads: usize,
+    /// every timeline event in send order, as (room id, event) — sync tokens index into it
+    pub timeline: Vec<(String, Value)>,
+    /// typing and receipt events as (timeline length when sent, room id,
+    /// event) — synced to joined members whose token is at or before that point
+    pub ephemeral: Vec<(usize, String, Value)>,
+    /// a sync's timeline limit when the filter doesn't set one, like a real
+    /// homeserver's default — None sends everything
+    pub sync_timeline_limit: Option<usize>,
+    /// (user id, type) → global account data content
+    pub account_data: HashMap<(String, String), Value>,
+    /// refuse account data types outside the m.* namespace, like some homeservers
+    pub reject_custom_account_data: bool,
+    /// (user id, room id) → that user's m.tag tags on the room
+    pub room_tags: HashMap<(String, String), serde_json::Map<String, Value>>,
+    /// answer /hierarchy as unrecognized, like homeservers from before it
+    pub hierarchy_unsupported: bool,
+    /// url → the og:* answer /preview_url gives for it. urls not listed answer
+    /// like a homeserver with previews turned off
+    pub url_previews: HashMap<String, Value>,
+    /// how many /preview_url requests have been answered
+    pub preview_requests: usize,
+    /// every /report as (reporter, room id, event id, body)
+    pub event_reports: Vec<(String, String, String, Value)>,
+    /// (sender, room id, txn id) → the event a /send made, so a repeated txn
+    /// id answers with it. real homeservers scope these to the device
+    pub txns: HashMap<(String, String, String), Value>,
+    /// room id → who is typing there. timeouts aren't kept
+    pub typing: HashMap<String, std::collections::BTreeSet<String>>,
+    next_id: u64,
+}
+
+impl HomeserverState {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// a new session: token and device id
+    fn session(&mut self, user_id: &str) -> (String, String) {
+        let n = self.next_id();
+        let (token, device) = (format!("token_{}", n), format!("DEVICE{}", n));
+        self.tokens.insert(token.clone(), user_id.to_string());
+        self.devices.insert(token.clone(), device.clone());
+        (token, device)
+    }
+
+    fn event(&mut self, event_type: &str, sender: &str, content: Value, state_key: Option<&str>) -> Value {
+        let mut event = json!({
+            "type": event_type,
+            "sender": sender,
+            "content": content,
+            "event_id": format!("$ev{}", self.next_id()),
+            "origin_server_ts": chrono::Utc::now().timestamp_millis(),
+        });
+        if let Some(key) = state_key {
+            event["state_key"] = json!(key);
+        }
+        event
+    }
+
+    fn put_state(&mut self, room_id: &str, sender: &str, event_type: &str, state_key: &str, content: Value) -> Value {
+        let event = self.event(event_type, sender, content, Some(state_key));
+        if let Some(room) = self.rooms.get_mut(room_id) {
+            room.state.insert((event_type.to_string(), state_key.to_string()), event.clone());
+        }
+        event
+    }
+
+    /// membership changes also go into the timeline, which is how sync
+    /// spots invites and leaves
+    fn set_membership(&mut self, room_id: &str, sender: &str, user_id: &str, membership: &str) {
+        self.put_member(room_id, sender, user_id, json!({ "membership": membership }));
+    }
+
+    fn put_member(&mut self, room_id: &str, sender: &str, user_id: &str, content: Value) {
+        let event = self.put_state(room_id, sender, "m.room.member", user_id, content);
+        self.timeline.push((room_id.to_string(), event));
+    }
+}
+
+pub struct FakeHomeserver {
+    pub server: MockServer,
+    pub state: Arc<Mutex<HomeserverState>>,
+}
+
+impl FakeHomeserver {
+    pub async fn start() -> Self {
+        Self::start_named(SERVER_NAME).await
+    }
+
+    /// a homeserver whose user ids, room ids and aliases end in `:server_name`
+    pub async fn start_named(server_name: &str) -> Self {
+        let server = MockServer::start().await;
+        let state = Arc::new(Mutex::new(HomeserverState {
+            server_name: server_name.to_string(),
+            ..Default::default()
+        }));
+        Mock::given(any())
+            .respond_with(Handler(state.clone()))
+            .mount(&server)
+            .await;
+        Self { server, state }
+    }
+
+    pub fn uri(&self) -> String {
+        self.server.uri()
+    }
+
+    /// run `f` against the homeserver's state, e.g. to assert on it
+    pub fn inspect<T>(&self, f: impl FnOnce(&HomeserverState) -> T) -> T {
+        f(&self.state.lock().unwrap())
+    }
+}
+
+struct Handler(Arc<Mutex<HomeserverState>>);
+
+fn error(status: u16, errcode: &str, message: &str) -> ResponseTemplate {
+    ResponseTemplate::new(status).set_body_json(json!({ "errcode": errcode, "error": message }))
+}
+
+fn ok(body: Value) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(body)
+}
+
+impl Respond for Handler {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let mut hs = self.0.lock().unwrap();
+        let path = request.url.path();
+        let segments: Vec<String> = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| urlencoding::decode(s).map(|d| d.into_owned()).unwrap_or_default())
+            .collect();
+        let query: HashMap<String, String> = request.url.query_pairs().into_owned().collect();
+        let body: Value = serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+        let method = request.method.as_str();
+
+        if method == "GET" && path == "/_matrix/client/versions" {
+            return ok(json!({ "versions": ["r0.6.1", "v1.1", "v1.2"] }));
+        }
+
+        // /_matrix/{client|media}/{version}/...
+        let (api, rest) = match segments.as_slice() {
+            [m, api, _version, rest @ ..] if m == "_matrix" => (api.as_str(), rest),
+            _ => return error(404, "M_UNRECOGNIZED", "unrecognized request"),
+        };
+        let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+
+        // unauthenticated endpoints
+        match (method, api, rest.as_slice()) {
+            ("POST", "client", ["register"]) if query.get("kind").map(String::as_str) == Some("guest") => {
+                return register_guest(&mut hs);
+            }
+            ("POST", "client", ["register"]) => return register(&mut hs, &body),
+            ("GET", "client", ["directory", "room", alias]) => {
+                return match hs.aliases.get(*alias) {
+                    Some(room_id) => ok(json!({ "room_id": room_id, "servers": [hs.server_name] })),
+                    None => error(404, "M_NOT_FOUND", "room alias not found"),
+                };
+            }
+            ("POST", "client", ["login"]) => return login(&mut hs, &body),
+            ("GET", "media", ["download", server_name, media_id]) => {
+                return match hs.media.get(*media_id) {
+                    Some((content_type, bytes)) if *server_name == hs.server_name => {
+                        ResponseTemplate::new(200).set_body_raw(bytes.clone(), content_type)
+                    }
+                    _ => error(404, "M_NOT_FOUND", "media not found"),
+                };
+            }
+            _ => {}
+        }
+
+        let token = request
+            .headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        let Some(user) = hs.tokens.get(token).cloned() else {
+            return error(401, "M_UNKNOWN_TOKEN", "unknown access token");
+        };
+
+        match (method, api, rest.as_slice()) {
+            ("GET", "media", ["config"]) => ok(json!({ "m.upload.size": UPLOAD_LIMIT })),
+            ("GET", "media", ["preview_url"]) => {
+                hs.preview_requests += 1;
+                let url = query.get("url").cloned().unwrap_or_default();
+                match hs.url_previews.get(&url) {
+                    Some(og) => ok(og.clone()),
+                    None => error(404, "M_UNRECOGNIZED", "url previews are disabled"),
+                }
+            }
+            ("POST", "media", ["upload"]) => {
+                if request.body.len() as u64 > UPLOAD_LIMIT {
+                    return error(413, "M_TOO_LARGE", "upload is too large");
+                }
+                let content_type = request
+                    .headers
+                    .get("content-type")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("application/octet-stream")
+                    .to_string();
+                let media_id = format!("media{}", hs.next_id());
+                hs.media.insert(media_id.clone(), (content_type, request.body.clone()));
+                ok(json!({ "content_uri": format!("mxc://{}/{}", hs.server_name, media_id) }))
+            }
+            ("POST", "client", ["logout"]) => {
+                hs.tokens.remove(token);
+                hs.devices.remove(token);
+                ok(json!({}))
+            }
+            ("POST", "client", ["logout", "all"]) => {
+                hs.tokens.retain(|_, owner| *owner != user);
+                ok(json!({}))
+            }
+            ("POST", "client", ["account", "password"]) => change_password(&mut hs, &user, token, &body),
+            ("GET", "client", ["account", "whoami"]) => ok(json!({ "user_id": user, "device_id": hs.devices.get(token) })),
+            ("GET", "client", ["devices"]) => {
+                let devices: Vec<Value> = hs
+                    .tokens
+                    .iter()
+                    .filter(|(_, owner)| **owner == user)
+                    .filter_map(|(t, _)| hs.devices.get(t))
+                    .map(|device| json!({ "device_id": device, "display_name": null, "last_seen_ts": null, "last_seen_ip": null }))
+                    .collect();
+                ok(json!({ "devices": devices }))
+            }
+            ("DELETE", "client", ["devices", device_id]) => delete_device(&mut hs, &user, device_id, &body),
+            ("POST", "client", ["createRoom"]) => create_room(&mut hs, &user, &body),
+            ("POST", "client", ["join", room_id]) => {
+                let room_id = hs.aliases.get(*room_id).cloned().unwrap_or(room_id.to_string());
+                join(&mut hs, &user, &room_id)
+            }
+            ("PUT", "client", ["directory", "room", alias]) => {
+                if hs.aliases.contains_key(*alias) {
+                    return error(409, "M_UNKNOWN", "room alias already exists");
+                }
+                let room_id = body["room_id"].as_str().unwrap_or_default().to_string();
+                hs.aliases.insert(alias.to_string(), room_id);
+                ok(json!({}))
+            }
+            ("DELETE", "client", ["directory", "room", alias]) => match hs.aliases.remove(*alias) {
+                Some(_) => ok(json!({})),
+                None => error(404, "M_NOT_FOUND", "room alias not found"),
+            },
+            ("GET", "client", ["joined_rooms"]) => {
+                let mut joined: Vec<&String> = hs
+                    .rooms
+                    .iter()
+                    .filter(|(_, room)| room.membership(&user) == Some("join"))
+                    .map(|(id, _)| id)
+                    .collect();
+                joined.sort();
+                ok(json!({ "joined_rooms": joined }))
+            }
+            ("GET", "client", ["profile", user_id]) => {
+                hs.profile_lookups += 1;
+                if !hs.users.contains_key(*user_id) {
+                    return error(404, "M_NOT_FOUND", "profile was not found");
+                }
+                let mut profile = json!({});
+                if let Some(name) = hs.displaynames.get(*user_id) {
+                    profile["displayname"] = json!(name);
+                }
+                if let Some(url) = hs.avatar_urls.get(*user_id) {
+                    profile["avatar_url"] = json!(url);
+                }
+                ok(profile)
+            }
+            ("PUT", "client", ["profile", user_id, field @ ("displayname" | "avatar_url")]) => {
+                if *user_id != user {
+                    return error(403, "M_FORBIDDEN", "cannot set another user's profile");
+                }
+                let value = body[*field].as_str().unwrap_or_default().to_string();
+                let profile = if *field == "displayname" { &mut hs.displaynames } else { &mut hs.avatar_urls };
+                if value.is_empty() {
+                    profile.remove(*user_id);
+                } else {
+                    profile.insert(user_id.to_string(), value);
+                }
+                ok(json!({}))
+            }
+            ("POST", "client", ["user_directory", "search"]) => {
+                let term = body["search_term"].as_str().unwrap_or_default().to_lowercase();
+                let limit = body["limit"].as_u64().unwrap_or(10) as usize;
+                let mut matches: Vec<&String> = hs
+                    .users
+                    .keys()
+                    .filter(|id| {
+                        let name = hs.displaynames.get(*id).map(|n| n.to_lowercase()).unwrap_or_default();
+                        id.to_lowercase().contains(&term) || name.contains(&term)
+                    })
+                    .collect();
+                matches.sort();
+                let results: Vec<Value> = matches
+                    .iter()
+                    .take(limit)
+                    .map(|id| json!({ "user_id": id, "display_name": hs.displaynames.get(*id), "avatar_url": null }))
+                    .collect();
+                ok(json!({ "results": results, "limited": matches.len() > limit }))
+            }
+            ("GET" | "PUT", "client", ["user", owner, "account_data", data_type]) => {
+                if *owner != user {
+                    return error(403, "M_FORBIDDEN", "cannot access another user's account data");
+                }
+                if hs.reject_custom_account_data && !data_type.starts_with("m.") {
+                    return error(400, "M_UNRECOGNIZED", "unsupported account data type");
+                }
+                let key = (user.clone(), data_type.to_string());
+                if method == "PUT" {
+                    hs.account_data.insert(key, body);
+                    return ok(json!({}));
+                }
+                match hs.account_data.get(&key) {
+                    Some(content) => ok(content.clone()),
+                    None => error(404, "M_NOT_FOUND", "account data not found"),
+                }
+            }
+            ("PUT" | "DELETE", "client", ["user", owner, "rooms", room_id, "tags", tag]) => {
+                if *owner != user {
+                    return error(403, "M_FORBIDDEN", "cannot add tags for another user");
+                }
+                let tags = hs.room_tags.entry((user.clone(), room_id.to_string())).or_default();
+                if method == "PUT" {
+                    tags.insert(tag.to_string(), body);
+                } else {
+                    tags.remove(*tag);
+                }
+                ok(json!({}))
+            }
+            ("GET", "client", ["sync"]) => sync(&hs, &user, query.get("since"), query.get("filter")),
+            // search isn't implemented, like on conduit builds without it
+            ("POST", "client", ["search"]) => error(404, "M_UNRECOGNIZED", "unrecognized request"),
+            ("GET", "client", ["rooms", room_id, "hierarchy"]) => hierarchy(&hs, &user, room_id, &query),
+            ("GET" | "PUT" | "POST", "client", ["rooms", room_id, rest @ ..]) => {
+                room_request(&mut hs, &user, method, room_id, rest, &body, &query)
+            }
+            _ => error(404, "M_UNRECOGNIZED", "unrecognized request"),
+        }
+    }
+}
+
+fn register(hs: &mut HomeserverState, body: &Value) -> ResponseTemplate {
+    // first call starts a user-interactive auth session
+    if body.get("auth").is_none() {
+        return ResponseTemplate::new(401).set_body_json(json!({
+            "flows": [{ "stages": ["m.login.dummy"] }],
+            "params": {},
+            "session": "test-session",
+        }));
+    }
+    let username = body["username"].as_str().unwrap_or_default();
+    let user_id = format!("@{}:{}", username, hs.server_name);
+    if hs.users.contains_key(&user_id) {
+        return error(400, "M_USER_IN_USE", "user id already taken");
+    }
+    hs.users.insert(user_id.clone(), body["password"].as_str().unwrap_or_default().to_string());
+    hs.displaynames.insert(user_id.clone(), username.to_string());
+    let (token, device_id) = hs.session(&user_id);
+    ok(json!({ "user_id": user_id, "access_token": token, "device_id": device_id }))
+}
+
+/// guests skip user-interactive auth and get a numbered user id
+fn register_guest(hs: &mut HomeserverState) -> ResponseTemplate {
+    let user_id = format!("@guest{}:{}", hs.next_id(), hs.server_name);
+    hs.users.insert(user_id.clone(), String::new());
+    let (token, device_id) = hs.session(&user_id);
+    ok(json!({ "user_id": user_id, "access_token": token, "device_id": device_id }))
+}
+
+fn login(hs: &mut HomeserverState, body: &Value) -> ResponseTemplate {
+    let user_id = body["user"].as_str().unwrap_or_default().to_string();
+    let password = body["password"].as_str().unwrap_or_default();
+    if hs.users.get(&user_id).map(String::as_str) != Some(password) {
+        return error(403, "M_FORBIDDEN", "invalid username or password");
+    }
+    let (token, device_id) = hs.session(&user_id);
+    ok(json!({ "user_id": user_id, "access_token": token, "device_id": device_id, "home_server": hs.server_name }))
+}
+
+/// the m.login.password stage of user-interactive auth: None once it's satisfied
+fn password_auth(hs: &HomeserverState, user: &str, body: &Value) -> Option<ResponseTemplate> {
+    let challenge = json!({
+        "flows": [{ "stages": ["m.login.password"] }],
+        "params": {},
+        "session": "test-session",
+    });
+    let auth = &body["auth"];
+    if auth["session"] != "test-session" {
+        return Some(ResponseTemplate::new(401).set_body_json(challenge));
+    }
+    if hs.users.get(user).map(String::as_str) != auth["password"].as_str() {
+        let mut body = challenge;
+        body["errcode"] = json!("M_FORBIDDEN");
+        body["error"] = json!("invalid password");
+        return Some(ResponseTemplate::new(401).set_body_json(body));
+    }
+    None
+}
+
+fn change_password(hs: &mut HomeserverState, user: &str, token: &str, body: &Value) -> ResponseTemplate {
+    if let Some(challenge) = password_auth(hs, user, body) {
+        return challenge;
+    }
+    let new_password = body["new_password"].as_str().unwrap_or_default().to_string();
+    hs.users.insert(user.to_string(), new_password);
+    // the spec defaults logout_devices to true
+    if body["logout_devices"].as_bool().unwrap_or(true) {
+        hs.tokens.retain(|t, owner| owner != user || t == token);
+    }
+    ok(json!({}))
+}
+
+fn delete_device(hs: &mut HomeserverState, user: &str, device_id: &str, body: &Value) -> ResponseTemplate {
+    let owned = hs.tokens.iter().any(|(t, owner)| owner == user && hs.devices.get(t).map(String::as_str) == Some(device_id));
+    if !owned {
+        return error(404, "M_NOT_FOUND", "device not found");
+    }
+    if let Some(challenge) = password_auth(hs, user, body) {
+        return challenge;
+    }
+    let tokens: Vec<String> = hs
+        .devices
+        .iter()
+        .filter(|(_, d)| *d == device_id)
+        .map(|(t, _)| t.clone())
+        .collect();
+    for token in tokens {
+        hs.tokens.remove(&token);
+        hs.devices.remove(&token);
+    }
+    ok(json!({}))
+}
+
+fn create_room(hs: &mut HomeserverState, user: &str, body: &Value) -> ResponseTemplate {
+    let room_id = format!("!room{}:{}", hs.next_id(), hs.server_name);
+    hs.rooms.insert(room_id.clone(), Room::default());
+
+    let mut create = json!({ "creator": user, "room_version": "9" });
+    if let Some(room_type) = body["creation_content"]["type"].as_str() {
+        create["type"] = json!(room_type);
+    }
+    hs.put_state(&room_id, user, "m.room.create", "", create);
+    // the creator's join stays out of the timeline, so history starts at the first message
+    hs.put_state(&room_id, user, "m.room.member", user, json!({ "membership": "join" }));
+    hs.put_state(&room_id, user, "m.room.power_levels", "", json!({
+        "users": { user: 100 },
+        "users_default": 0,
+        "events_default": 0,
+        "state_default": 50,
+        "ban": 50, "kick": 50, "redact": 50, "invite": 0,
+    }));
+    let join_rule = if body["preset"].as_str() == Some("public_chat") { "public" } else { "invite" };
+    hs.put_state(&room_id, user, "m.room.join_rules", "", json!({ "join_rule": join_rule }));
+    if let Some(name) = body["name"].as_str() {
+        hs.put_state(&room_id, user, "m.room.name", "", json!({ "name": name }));
+    }
+    if let Some(topic) = body["topic"].as_str() {
+        hs.put_state(&room_id, user, "m.room.topic", "", json!({ "topic": topic }));
+    }
+    for event in body["initial_state"].as_array().into_iter().flatten() {
+        let event_type = event["type"].as_str().unwrap_or_default();
+        let state_key = event["state_key"].as_str().unwrap_or_default();
+        hs.put_state(&room_id, user, event_type, state_key, event["content"].clone());
+    }
+    let is_direct = body["is_direct"].as_bool().unwrap_or(false);
+    for invitee in body["invite"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+        hs.put_member(&room_id, user, invitee, json!({ "membership": "invite", "is_direct": is_direct }));
+    }
+    ok(json!({ "room_id": room_id }))
+}
+
+fn join(hs: &mut HomeserverState, user: &str, room_id: &str) -> ResponseTemplate {
+    let Some(room) = hs.rooms.get(room_id) else {
+        return error(404, "M_NOT_FOUND", "room not found");
+    };
+    if room.membership(user) == Some("ban") {
+        return error(403, "M_FORBIDDEN", "you are banned from this room");
+    }
+    if !may_join(hs, room, user) {
+        return error(403, "M_FORBIDDEN", "you are not invited to this room");
+    }
+    hs.set_membership(room_id, user, user, "join");
+    ok(json!({ "room_id": room_id }))
+}
+
+/// public, restricted to a room the user is in, or invited to / already in
+fn may_join(hs: &HomeserverState, room: &Room, user: &str) -> bool {
+    let join_rules = room.content("m.room.join_rules", "").cloned().unwrap_or_default();
+    let public = join_rules["join_rule"] == "public";
+    // restricted: open to members of any of the allowed rooms
+    let allowed = join_rules["join_rule"] == "restricted"
+        && join_rules["allow"].as_array().into_iter().flatten().any(|allow| {
+            allow["room_id"]
+                .as_str()
+                .and_then(|id| hs.rooms.get(id))
+                .is_some_and(|r| r.membership(user) == Some("join"))
+        });
+    public || allowed || matches!(room.membership(user), Some("invite" | "join"))
+}
+
+/// breadth first from the space, skipping rooms the user can't join; `from`
+/// is how many rooms earlier pages held
+fn hierarchy(hs: &HomeserverState, user: &str, space_id: &str, query: &HashMap<String, String>) -> ResponseTemplate {
+    if hs.hierarchy_unsupported {
+        return error(404, "M_UNRECOGNIZED", "unrecognized request");
+    }
+    let visible = |room_id: &str| {
+        hs.rooms.get(room_id).filter(|room| room.membership(user) != Some("ban") && may_join(hs, room, user))
+    };
+    if visible(space_id).is_none() {
+        return error(403, "M_FORBIDDEN", "you cannot see this space");
+    }
+    let max_depth: usize = query.get("max_depth").and_then(|d| d.parse().ok()).unwrap_or(usize::MAX);
+    let limit: usize = query.get("limit").and_then(|l| l.parse().ok()).unwrap_or(50);
+    let from: usize = query.get("from").and_then(|f| f.parse().ok()).unwrap_or(0);
+
+    let mut rooms = Vec::new();
+    let mut seen = vec![space_id.to_string()];
+    let mut queue = std::collections::VecDeque::from([(space_id.to_string(), 0)]);
+    while let Some((room_id, depth)) = queue.pop_front() {
+        let Some(room) = visible(&room_id) else { continue };
+        let children_state: Vec<Value> = room
+            .state
+            .iter()
+            .filter(|((event_type, _), event)| {
+                event_type == "m.space.child" && event["content"].as_object().is_some_and(|c| !c.is_empty())
+            })
+            .map(|((event_type, state_key), event)| {
+                json!({ "type": event_type, "state_key": state_key, "sender": event["sender"], "content": event["content"] })
+            })
+            .collect();
+        if depth < max_depth {
+            for child in &children_state {
+                let child_id = child["state_key"].as_str().unwrap_or_default().to_string();
+                if !seen.contains(&child_id) {
+                    seen.push(child_id.clone());
+                    queue.push_back((child_id, depth + 1));
+                }
+            }
+        }
+        let members = room.state.iter().filter(|((t, _), e)| t == "m.room.member" && e["content"]["membership"] == "join").count();
+        rooms.push(json!({
+            "room_id": room_id,
+            "name": room.content("m.room.name", "").and_then(|c| c["name"].as_str()),
+            "topic": room.content("m.room.topic", "").and_then(|c| c["topic"].as_str()),
+            "avatar_url": room.content("m.room.avatar", "").and_then(|c| c["url"].as_str()),
+            "room_type": room.content("m.room.create", "").and_then(|c| c["type"].as_str()),
+            "num_joined_members": members,
+            "children_state": children_state,
+        }));
+    }
+    let page: Vec<Value> = rooms.iter().skip(from).take(limit).cloned().collect();
+    let mut body = json!({ "rooms": page });
+    if from + limit < rooms.len() {
+        body["next_batch"] = json!((from + limit).to_string());
+    }
+    ok(body)
+}
+
+/// only the inline-filter fields the api sends: `room.rooms` and
+/// `room.timeline.limit`
+fn sync(hs: &HomeserverState, user: &str, since: Option<&String>, filter: Option<&String>) -> ResponseTemplate {
+    let since: usize = since.and_then(|s| s.parse().ok()).unwrap_or(0);
+    let filter: Value = filter.and_then(|f| serde_json::from_str(f).ok()).unwrap_or_default();
+    let rooms_filter: Option<Vec<&str>> = filter["room"]["rooms"].as_array().map(|r| r.iter().filter_map(Value::as_str).collect());
+    let limit = filter["room"]["timeline"]["limit"].as_u64().map(|l| l as usize).or(hs.sync_timeline_limit);
+    let strings = |list: &Value| list.as_array().map(|l| l.iter().filter_map(Value::as_str).map(str::to_string).collect::<Vec<_>>());
+    let timeline_rooms = strings(&filter["room"]["timeline"]["rooms"]);
+    let timeline_types = strings(&filter["room"]["timeline"]["types"]);
+    // an empty types list, as in room-free filters, means none
+    let in_timeline = |room_id: &str, event: &Value| {
+        timeline_rooms.as_ref().is_none_or(|rooms| rooms.iter().any(|r| r == room_id))
+            && timeline_types.as_ref().is_none_or(|types| types.iter().any(|t| event["type"] == t.as_str()))
+    };
+    let mut join: serde_json::Map<String, Value> = serde_json::Map::new();
+    let mut invite: serde_json::Map<String, Value> = serde_json::Map::new();
+    let mut leave: serde_json::Map<String, Value> = serde_json::Map::new();
+    // each event's position in its room, which is what /messages tokens count
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (index, (room_id, event)) in hs.timeline.iter().enumerate() {
+        let position = positions.entry(room_id.as_str()).or_insert(0);
+        *position += 1;
+        if index < since || rooms_filter.as_ref().is_some_and(|rooms| !rooms.contains(&room_id.as_str())) {
+            continue;
+        }
+        let Some(room) = hs.rooms.get(room_id) else { continue };
+        match room.membership(user) {
+            Some("join") => {
+                let entry = join
+                    .entry(room_id.clone())
+                    .or_insert_with(|| json!({ "timeline": { "events": [], "positions": [] } }));
+                if in_timeline(room_id, event) {
+                    entry["timeline"]["events"].as_array_mut().unwrap().push(event.clone());
+                    entry["timeline"]["positions"].as_array_mut().unwrap().push(json!(*position - 1));
+                }
+            }
+            // only rooms whose membership changed since the last sync
+            _ if event["type"] != "m.room.member" || event["state_key"] != user => {}
+            Some("invite") => {
+                // stripped state: just enough to show the invite
+                let events: Vec<Value> = room
+                    .state
+                    .iter()
+                    .filter(|((event_type, key), _)| {
+                        ["m.room.create", "m.room.name", "m.room.avatar", "m.room.join_rules"].contains(&event_type.as_str())
+                            || (event_type == "m.room.member" && key == user)
+                    })
+                    .map(|(_, e)| json!({ "type": e["type"], "state_key": e["state_key"], "sender": e["sender"], "content": e["content"] }))
+                    .collect();
+                invite.insert(room_id.clone(), json!({ "invite_state": { "events": events } }));
+            }
+            Some("leave" | "ban") => {
+                leave.insert(room_id.clone(), json!({ "timeline": { "events": [] } }));
+            }
+            _ => {}
+        }
+    }
+    for entry in join.values_mut() {
+        let timeline = entry["timeline"].as_object_mut().unwrap();
+        let mut events = timeline.remove("events").unwrap().as_array().unwrap().clone();
+        let positions = timeline.remove("positions").unwrap();
+        let skip = limit.map_or(0, |limit| events.len().saturating_sub(limit));
+        events.drain(..skip);
+        timeline.insert("limited".into(), json!(skip > 0));
+        if let Some(position) = positions[skip].as_u64() {
+            timeline.insert("prev_batch".into(), json!(position.to_string()));
+        }
+        timeline.insert("events".into(), json!(events));
+    }
+    for (position, room_id, event) in &hs.ephemeral {
+        let joined = hs.rooms.get(room_id).and_then(|r| r.membership(user)) == Some("join");
+        if *position < since || !joined || rooms_filter.as_ref().is_some_and(|rooms| !rooms.contains(&room_id.as_str())) {
+            continue;
+        }
+        let entry = join.entry(room_id.clone()).or_insert_with(|| json!({ "timeline": { "events": [] } }));
+        if entry["ephemeral"].is_null() {
+            entry["ephemeral"] = json!({ "events": [] });
+        }
+        entry["ephemeral"]["events"].as_array_mut().unwrap().push(event.clone());
+    }
+    // tags go out in the initial sync only, for quiet rooms as well: account
+    // data is enough for a room to be in the sync
+    for ((owner, room_id), tags) in &hs.room_tags {
+        let joined = hs.rooms.get(room_id).and_then(|r| r.membership(user)) == Some("join");
+        if owner != user || since != 0 || !joined || rooms_filter.as_ref().is_some_and(|rooms| !rooms.contains(&room_id.as_str())) {
+            continue;
+        }
+        let entry = join.entry(room_id.clone()).or_insert_with(|| json!({ "timeline": { "events": [] } }));
+        entry["account_data"] = json!({ "events": [{ "type": "m.tag", "content": { "tags": tags } }] });
+    }
+    // a room that was left and re-joined (or re-invited) is only in its latest section
+    for room_id in join.keys().chain(invite.keys()) {
+        leave.remove(room_id);
+    }
+    let rooms = json!({ "join": join, "invite": invite, "leave": leave });
+    // global account data goes out in the initial sync only, like tags
+    let account_data_types = strings(&filter["account_data"]["types"]);
+    let account_data: Vec<Value> = hs
+        .account_data
+        .iter()
+        .filter(|((owner, event_type), _)| {
+            owner == user && since == 0 && account_data_types.as_ref().is_none_or(|types| types.contains(event_type))
+        })
+        .map(|((_, event_type), content)| json!({ "type": event_type, "content": content }))
+        .collect();
+    ok(json!({
+        "next_batch": hs.timeline.len().to_string(),
+        "rooms": rooms,
+        "account_data": { "events": account_data },
+    }))
+}
+
+fn room_request(
+    hs: &mut HomeserverState,
+    user: &str,
+    method: &str,
+    room_id: &str,
+    rest: &[&str],
+    body: &Value,
+    query: &HashMap<String, String>,
+) -> ResponseTemplate {
+    let Some(room) = hs.rooms.get(room_id) else {
+        return error(404, "M_NOT_FOUND", "room not found");
+    };
+    let membership = room.membership(user).map(str::to_string);
+    let joined = membership.as_deref() == Some("join");
+    // world_readable rooms can be read, but not written, by anyone
+    let world_readable = room
+        .content("m.room.history_visibility", "")
+        .is_some_and(|c| c["history_visibility"] == "world_readable");
+
+    match (method, rest) {
+        ("POST", ["leave"]) => {
+            if !matches!(membership.as_deref(), Some("join" | "invite")) {
+                return error(403, "M_FORBIDDEN", "user is not invited or joined");
+            }
+            hs.set_membership(room_id, user, user, "leave");
+            ok(json!({}))
+        }
+        ("POST", ["forget"]) => ok(json!({})),
+        _ if !joined && (!world_readable || method != "GET") => error(403, "M_FORBIDDEN", "you are not joined to this room"),
+        ("POST", ["invite"]) => {
+            let invitee = body["user_id"].as_str().unwrap_or_default().to_string();
+            hs.set_membership(room_id, user, &invitee, "invite");
+            ok(json!({}))
+        }
+        ("POST", [action @ ("kick" | "ban" | "unban")]) => {
+            if room.power(user) < 50 {
+                return error(403, "M_FORBIDDEN", "insufficient power level");
+            }
+            let target = body["user_id"].as_str().unwrap_or_default().to_string();
+            let current = room.membership(&target);
+            let membership = match *action {
+                "kick" if matches!(current, Some("join" | "invite")) => "leave",
+                "kick" => return error(403, "M_FORBIDDEN", "the target is not in the room"),
+                "ban" => "ban",
+                _ if current == Some("ban") => "leave",
+                _ => return error(403, "M_FORBIDDEN", "the target is not banned"),
+            };
+            hs.set_membership(room_id, user, &target, membership);
+            ok(json!({}))
+        }
+        ("GET", ["members"]) => {
+            let chunk: Vec<&Value> = room
+                .state
+                .iter()
+                .filter(|((t, _), _)| t == "m.room.member")
+                .map(|(_, e)| e)
+                .collect();
+            ok(json!({ "chunk": chunk }))
+        }
+        ("GET", ["state"]) => {
+            let events = json!(room.state.values().collect::<Vec<_>>());
+            hs.state_reads += 1;
+            ok(events)
+        }
+        ("GET", ["state", event_type, key @ ..]) => {
+            let state_key = key.first().copied().unwrap_or_default();
+            match room.content(event_type, state_key) {
+                Some(content) => ok(content.clone()),
+                None => error(404, "M_NOT_FOUND", "event not found"),
+            }
+        }
+        ("PUT", ["state", event_type, key @ ..]) => {
+            if !joined || room.power(user) < room.state_level(event_type) {
+                return error(403, "M_FORBIDDEN", "insufficient power level");
+            }
+            let state_key = key.first().copied().unwrap_or_default().to_string();
+            let event = hs.put_state(room_id, user, event_type, &state_key, body.clone());
+            ok(json!({ "event_id": event["event_id"] }))
+        }
+        ("PUT", ["typing", target]) => {
+            if *target != user {
+                return error(403, "M_FORBIDDEN", "cannot set another user's typing state");
+            }
+            let typing = hs.typing.entry(room_id.to_string()).or_default();
+            if body["typing"] == true {
+                typing.insert(user.to_string());
+            } else {
+                typing.remove(user);
+            }
+            let event = json!({ "type": "m.typing", "content": { "user_ids": typing.iter().collect::<Vec<_>>() } });
+            let position = hs.timeline.len();
+            hs.ephemeral.push((position, room_id.to_string(), event));
+            ok(json!({}))
+        }
+        ("PUT", ["send", event_type, txn]) => {
+            let txn_key = (user.to_string(), room_id.to_string(), txn.to_string());
+            if let Some(event_id) = hs.txns.get(&txn_key) {
+                return ok(json!({ "event_id": event_id }));
+            }
+            let event = hs.event(event_type, user, body.clone(), None);
+            hs.timeline.push((room_id.to_string(), event.clone()));
+            hs.txns.insert(txn_key, event["event_id"].clone());
+            ok(json!({ "event_id": event["event_id"] }))
+        }
+        ("GET", ["event", event_id]) => {
+            match hs.timeline.iter().find(|(r, e)| r == room_id && e["event_id"] == *event_id) {
+                Some((_, event)) => ok(event.clone()),
+                None => error(404, "M_NOT_FOUND", "event not found"),
+            }
+        }
+        ("POST", ["report", event_id]) => {
+            if !hs.timeline.iter().any(|(r, e)| r == room_id && e["event_id"] == *event_id) {
+                return error(404, "M_NOT_FOUND", "event not found");
+            }
+            hs.event_reports.push((user.to_string(), room_id.to_string(), event_id.to_string(), body.clone()));
+            ok(json!({}))
+        }
+        ("PUT", ["redact", event_id, _txn]) => {
+            let sender = hs
+                .timeline
+                .iter()
+                .find(|(r, e)| r == room_id && e["event_id"] == *event_id)
+                .and_then(|(_, e)| e["sender"].as_str().map(str::to_string));
+            let Some(sender) = sender else {
+                return error(404, "M_NOT_FOUND", "event not found");
+            };
+            if sender != user && room.power(user) < 50 {
+                return error(403, "M_FORBIDDEN", "insufficient power level to redact");
+            }
+            for (_, event) in hs.timeline.iter_mut().filter(|(r, e)| r == room_id && e["event_id"] == *event_id) {
+                event["content"] = json!({});
+            }
+            let mut redaction = hs.event("m.room.redaction", user, json!({}), None);
+            redaction["redacts"] = json!(event_id);
+            hs.timeline.push((room_id.to_string(), redaction.clone()));
+            ok(json!({ "event_id": redaction["event_id"] }))
+        }
+        ("GET", ["messages"]) => {
+            // tokens are positions in the room's timeline; dir=b pages newest first
+            let events: Vec<&Value> = hs.timeline.iter().filter(|(r, _)| r == room_id).map(|(_, e)| e).collect();
+            let limit: usize = query.get("limit").and_then(|l| l.parse().ok()).unwrap_or(10);
+            let from = query.get("from").and_then(|f| f.parse::<usize>().ok());
+            if query.get("dir").map(String::as_str) == Some("f") {
+                let from = from.unwrap_or(0).min(events.len());
+                let end = (from + limit).min(events.len());
+                let mut response = json!({ "start": from.to_string(), "chunk": &events[from..end] });
+                if end < events.len() {
+                    response["end"] = json!(end.to_string());
+                }
+                return ok(response);
+            }
+            // `to` only ever comes from a sync here, so it's a global timeline
+            // index: stop at the room's first event at or after it
+            let floor = query.get("to").and_then(|t| t.parse::<usize>().ok()).map_or(0, |to| {
+                hs.timeline.iter().take(to).filter(|(r, _)| r == room_id).count()
+            });
+            let from = from.unwrap_or(events.len()).min(events.len());
+            let start = from.saturating_sub(limit).max(floor.min(from));
+            let chunk: Vec<&Value> = events[start..from].iter().rev().copied().collect();
+            let mut response = json!({ "start": from.to_string(), "chunk": chunk });
+            if start > floor {
+                response["end"] = json!(start.to_string());
+            }
+            ok(response)
+        }
+        ("GET", ["relations", event_id, rel_type, event_type]) => {
+            let chunk: Vec<&Value> = hs
+                .timeline
+                .iter()
+                .filter(|(r, e)| {
+                    r == room_id
+                        && e["type"] == *event_type
+                        && e["content"]["m.relates_to"]["event_id"] == *event_id
+                        && e["content"]["m.relates_to"]["rel_type"] == *rel_type
+                })
+                .map(|(_, e)| e)
+                .collect();
+            ok(json!({ "chunk": chunk }))
+        }
+        ("GET", ["context", event_id]) => {
+            let events: Vec<&Value> = hs.timeline.iter().filter(|(r, _)| r == room_id).map(|(_, e)| e).collect();
+            let Some(i) = events.iter().position(|e| e["event_id"] == *event_id) else {
+                return error(404, "M_NOT_FOUND", "event not found");
+            };
+            let before: Vec<&Value> = events[..i].iter().rev().take(1).copied().collect();
+            let after: Vec<&Value> = events[i + 1..].iter().take(1).copied().collect();
+            ok(json!({ "event": events[i], "events_before": before, "events_after": after }))
+        }
+        _ => error(404, "M_UNRECOGNIZED", "unrecognized request"),
+    }
+}
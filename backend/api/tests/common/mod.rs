@@ -0,0 +1,146 @@
+// shared harness for the integration tests: the full router with an AppState
+// pointed at a fake homeserver, a fake redis, and (for #[sqlx::test]s) a
+// throwaway postgres database with all migrations applied. requests go through
+// tower's oneshot, so no port is bound for the api itself.
+
+#![allow(dead_code)] // each test binary uses a different slice of the harness
+
+pub mod homeserver;
+pub mod fake_redis;
+
+use agora_api::app_state::AppState;
+use agora_api::matrix::retry::RetryPolicy;
+use agora_api::rate_limit::RateLimits;
+use agora_api::redis_manager::RedisManager;
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+pub use homeserver::FakeHomeserver;
+
+pub struct TestApp {
+    pub router: Router,
+    pub state: Arc<AppState>,
+    pub homeserver: FakeHomeserver,
+    pub redis: fake_redis::Store,
+    pub redis_server: fake_redis::FakeRedis,
+}
+
+/// a logged-in user of the fake homeserver
+pub struct TestUser {
+    pub user_id: String,
+    pub access_token: String,
+}
+
+impl TestApp {
+    /// without a database — endpoints that need one answer 503
+    pub async fn new() -> Self {
+        Self::build(None, homeserver::SERVER_NAME).await
+    }
+
+    pub async fn with_db(pool: sqlx::PgPool) -> Self {
+        Self::build(Some(pool), homeserver::SERVER_NAME).await
+    }
+
+    /// against a homeserver on another domain than localhost
+    pub async fn with_server_name(server_name: &str) -> Self {
+        Self::build(None, server_name).await
+    }
+
+    /// with the state adjusted before the router is built
+    pub async fn with_config(configure: impl FnOnce(&mut AppState)) -> Self {
+        Self::build_with(None, homeserver::SERVER_NAME, configure).await
+    }
+
+    async fn build(db_pool: Option<sqlx::PgPool>, server_name: &str) -> Self {
+        Self::build_with(db_pool, server_name, |_| {}).await
+    }
+
+    async fn build_with(
+        db_pool: Option<sqlx::PgPool>,
+        server_name: &str,
+        configure: impl FnOnce(&mut AppState),
+    ) -> Self {
+        let homeserver = FakeHomeserver::start_named(server_name).await;
+        let redis_server = fake_redis::start().await;
+
+        let mut state = AppState::new();
+        state.homeserver_url = homeserver.uri();
+        state.server_name = server_name.to_string();
+        state.db_pool = db_pool;
+        state.redis = RedisManager::new(&redis_server.url).unwrap();
+        state.redis.connect().await.unwrap();
+        // same retry behaviour, without the real waits
+        state.matrix_retry = RetryPolicy {
+            base_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+        // tests register and post far faster than any person — rate_limits.rs turns them back on
+        state.rate_limits = RateLimits::off();
+        configure(&mut state);
+        let state = Arc::new(state);
+
+        let router = agora_api::app(state.clone());
+        let redis = redis_server.store.clone();
+        Self { router, state, homeserver, redis, redis_server }
+    }
+
+    pub async fn request(&self, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        self.request_as(None, method, uri, body).await
+    }
+
+    /// the same, with the token in an `Authorization: Bearer` header
+    pub async fn authed(&self, user: &TestUser, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        self.request_as(Some(&user.access_token), method, uri, body).await
+    }
+
+    async fn request_as(&self, token: Option<&str>, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        let request = match body {
+            Some(body) => builder
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        }
+        .unwrap();
+
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, json)
+    }
+
+    pub async fn get(&self, uri: &str) -> (StatusCode, Value) {
+        self.request(Method::GET, uri, None).await
+    }
+
+    pub async fn post(&self, uri: &str, body: Value) -> (StatusCode, Value) {
+        self.request(Method::POST, uri, Some(body)).await
+    }
+
+    /// register through the api and return the new session
+    pub async fn register(&self, username: &str) -> TestUser {
+        let (status, body) = self
+            .post("/register", serde_json::json!({ "username": username, "password": "hunter2" }))
+            .await;
+        assert_eq!(status, StatusCode::OK, "register {}: {}", username, body);
+        TestUser {
+            user_id: body["user_id"].as_str().unwrap().to_string(),
+            access_token: body["access_token"].as_str().unwrap().to_string(),
+        }
+    }
+}
+
+/// percent-encode a query parameter value (room and user ids contain ! : @)
+pub fn enc(value: &str) -> String {
+    urlencoding::encode(value).into_owned()
+}
@@ -0,0 +1,138 @@
+// rich text: markdown rendering, the html sanitizer every formatted body goes
+// through, and mentions
+
+use agora_api::content::{
+    html_body, is_mxc_uri, mentions_everyone, mentions_user, pill_mentions, render_markdown, sanitize_html,
+    strip_reply_fallback, text_to_html, MAX_HTML_LEN, MAX_MARKDOWN_LEN, MAX_NESTING_DEPTH,
+};
+use serde_json::json;
+
+#[test]
+fn script_and_event_handlers_are_stripped() {
+    for payload in [
+        "<script>alert(1)</script>hi",
+        "<img src=x onerror=alert(1)>hi",
+        "<a href=\"javascript:alert(1)\">hi</a>",
+        "<p onclick=\"alert(1)\">hi</p>",
+        "<iframe src=\"https://evil.example\"></iframe>hi",
+        "<svg><script>alert(1)</script></svg>hi",
+        "<style>body{display:none}</style>hi",
+    ] {
+        let clean = sanitize_html(payload);
+        for bad in ["<script", "onerror", "onclick", "javascript:", "<iframe", "<svg", "<style", "alert"] {
+            assert!(!clean.contains(bad), "{:?} survived in {:?}", bad, clean);
+        }
+        assert!(clean.contains("hi"), "text of {:?} was lost: {:?}", payload, clean);
+    }
+}
+
+#[test]
+fn markdown_is_sanitized_too() {
+    let html = render_markdown("**bold** <script>alert(1)</script> [link](javascript:alert(1))");
+    assert!(html.contains("<strong>bold</strong>"));
+    assert!(!html.contains("<script"));
+    assert!(!html.contains("javascript:"));
+}
+
+#[test]
+fn fenced_code_blocks_survive() {
+    let html = render_markdown("```rust\nfn main() { println!(\"<hi>\"); }\n```");
+    assert!(html.contains("<pre><code class=\"language-rust\">"), "{}", html);
+    // the code is escaped, not interpreted
+    assert!(html.contains("&lt;hi&gt;"), "{}", html);
+    // and a second pass through the sanitizer leaves it alone
+    assert_eq!(sanitize_html(&html), html);
+}
+
+#[test]
+fn only_html_formatted_bodies_are_read() {
+    let formatted = json!({ "body": "hi", "format": "org.matrix.custom.html", "formatted_body": "<b>hi</b><script>x</script>" });
+    assert_eq!(html_body(&formatted).as_deref(), Some("<b>hi</b>"));
+    assert_eq!(html_body(&json!({ "body": "hi", "formatted_body": "<b>hi</b>" })), None);
+    assert_eq!(html_body(&json!({ "body": "hi" })), None);
+}
+
+#[test]
+fn mxc_uris_need_a_server_and_a_media_id() {
+    assert!(is_mxc_uri("mxc://localhost/abc123"));
+    assert!(!is_mxc_uri("mxc://localhost"));
+    assert!(!is_mxc_uri("mxc:///abc123"));
+    assert!(!is_mxc_uri("mxc://localhost/a/b"));
+    assert!(!is_mxc_uri("https://localhost/abc123"));
+}
+
+#[test]
+fn mentions_need_a_word_boundary() {
+    assert!(mentions_user("hey @alice!", "@alice:localhost"));
+    assert!(mentions_user("hey @alice:localhost", "@alice:localhost"));
+    assert!(!mentions_user("hey @alicia", "@alice:localhost"));
+    assert!(!mentions_user("mail alice@alice.org", "@alice:localhost"));
+    assert!(mentions_everyone("@everyone look"));
+    assert!(!mentions_everyone("@everyones"));
+}
+
+#[test]
+fn mentions_become_pills_outside_code_and_links() {
+    let users = vec!["@alice:localhost".to_string(), "@al:localhost".to_string()];
+    assert_eq!(
+        pill_mentions(&text_to_html("hi @alice & @al"), &users),
+        "hi <a href=\"https://matrix.to/#/@alice:localhost\">@alice</a> &amp; <a href=\"https://matrix.to/#/@al:localhost\">@al</a>"
+    );
+    let html = "<p><code>@alice</code> <a href=\"https://x.org\">@alice</a> @alice:localhost</p>";
+    assert_eq!(
+        pill_mentions(html, &users),
+        "<p><code>@alice</code> <a href=\"https://x.org\">@alice</a> <a href=\"https://matrix.to/#/@alice:localhost\">@alice:localhost</a></p>"
+    );
+}
+
+#[test]
+fn deep_nesting_is_unwrapped_not_kept() {
+    let html = "<b>".repeat(5_000) + "deep" + &"</b>".repeat(5_000);
+    let clean = sanitize_html(&html);
+    assert_eq!(clean.matches("<b>").count(), MAX_NESTING_DEPTH);
+    assert_eq!(clean.matches("</b>").count(), MAX_NESTING_DEPTH);
+    assert!(clean.contains("deep"));
+    // void elements past the limit are dropped instead of nested
+    let clean = sanitize_html(&("<i>".repeat(MAX_NESTING_DEPTH) + "<br><hr>x"));
+    assert!(!clean.contains("<br>") && !clean.contains("<hr>"), "{}", clean);
+}
+
+#[test]
+fn huge_inputs_stay_bounded() {
+    let emphasis = "*_".repeat(5 * 1024 * 1024);
+    let html = render_markdown(&emphasis);
+    assert!(html.len() <= MAX_MARKDOWN_LEN * 4, "{} bytes", html.len());
+
+    let links = "<a href=\"https://x.org\">x</a>".repeat(MAX_HTML_LEN);
+    assert!(sanitize_html(&links).len() <= MAX_HTML_LEN * 2);
+    // a multi-byte character across the cut doesn't panic
+    let long = "é".repeat(MAX_HTML_LEN);
+    assert!(sanitize_html(&long).len() <= MAX_HTML_LEN);
+    for broken in ["<", "<a href=\"", "<<<>>>", "</b></b></i>", "<b><i>x</b></i>", "&#x0;&#xD800;"] {
+        sanitize_html(broken);
+        render_markdown(broken);
+    }
+}
+
+#[test]
+fn spoilers_and_colours_are_kept_and_schemes_filtered() {
+    let html = "<span data-mx-spoiler=\"plot\">dies</span><font data-mx-color=\"#ff0000\" color=\"red\">hot</font>";
+    assert_eq!(
+        sanitize_html(html),
+        "<span data-mx-spoiler=\"plot\">dies</span><font data-mx-color=\"#ff0000\">hot</font>"
+    );
+    assert_eq!(sanitize_html("<img src=\"https://evil.example/x.png\" alt=\"x\">"), "<img alt=\"x\">");
+    assert_eq!(sanitize_html("<img src=\"mxc://localhost/abc\">"), "<img src=\"mxc://localhost/abc\">");
+    assert!(!sanitize_html("<a href=\"mxc://localhost/abc\">x</a>").contains("mxc"));
+    assert!(!sanitize_html("<a href=\"data:text/html,hi\">x</a>").contains("data:"));
+    assert!(!sanitize_html("<a href=\"/relative\">x</a>").contains("relative"));
+}
+
+#[test]
+fn reply_fallbacks_are_dropped() {
+    let html = "<mx-reply><blockquote><a href=\"https://matrix.to/#/!r:localhost/$e\">In reply to</a> hi</blockquote></mx-reply>hello";
+    assert_eq!(sanitize_html(html), "hello");
+    assert_eq!(strip_reply_fallback("> <@bob:localhost> hi\n> there\n\nhello"), "hello");
+    assert_eq!(strip_reply_fallback("> just a quote"), "");
+    assert_eq!(strip_reply_fallback("no quote"), "no quote");
+}
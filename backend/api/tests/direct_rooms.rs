@@ -0,0 +1,89 @@
+// dms in m.direct, so other matrix clients see them as dms, and rebuilding
+// lost dm rooms of friendships from it
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn befriend(app: &TestApp, user: &TestUser, friend: &TestUser) {
+    let add = json!({ "access_token": user.access_token, "friend_id": friend.user_id });
+    assert_eq!(app.post("/friends/add", add).await.0, StatusCode::OK);
+    let accept = json!({ "access_token": friend.access_token, "friend_id": user.user_id });
+    assert_eq!(app.post("/friends/accept", accept).await.0, StatusCode::OK);
+}
+
+async fn open_dm(app: &TestApp, user: &TestUser, friend: &TestUser) -> String {
+    let (status, body) = app
+        .post("/friends/dm", json!({ "access_token": user.access_token, "friend_id": friend.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["room_id"].as_str().unwrap().to_string()
+}
+
+fn direct(app: &TestApp, user: &TestUser) -> Option<Value> {
+    let key = (user.user_id.clone(), "m.direct".to_string());
+    app.homeserver.inspect(|hs| hs.account_data.get(&key).cloned())
+}
+
+#[sqlx::test]
+async fn dms_are_merged_into_both_sides_m_direct(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    befriend(&app, &alice, &bob).await;
+    // a dm some other client made earlier
+    app.homeserver.state.lock().unwrap().account_data.insert(
+        (alice.user_id.clone(), "m.direct".to_string()),
+        json!({ "@carol:localhost": ["!elsewhere:localhost"], bob.user_id.clone(): ["!old:localhost"] }),
+    );
+
+    let dm_id = open_dm(&app, &alice, &bob).await;
+    assert_eq!(
+        direct(&app, &alice),
+        Some(json!({ "@carol:localhost": ["!elsewhere:localhost"], bob.user_id.clone(): ["!old:localhost", dm_id] })),
+    );
+    // the invitee's side is written once they open it
+    assert_eq!(direct(&app, &bob), None);
+    assert_eq!(open_dm(&app, &bob, &alice).await, dm_id);
+    assert_eq!(direct(&app, &bob), Some(json!({ alice.user_id.clone(): [dm_id] })));
+
+    // opening it again doesn't list it twice
+    open_dm(&app, &alice, &bob).await;
+    assert_eq!(direct(&app, &alice).unwrap()[&bob.user_id], json!(["!old:localhost", dm_id]));
+}
+
+#[sqlx::test]
+async fn lost_dm_rooms_are_recovered_from_m_direct(pool: PgPool) {
+    let app = TestApp::with_db(pool.clone()).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    befriend(&app, &alice, &bob).await;
+    befriend(&app, &carol, &alice).await;
+    let dm_id = open_dm(&app, &alice, &bob).await;
+
+    sqlx::query("UPDATE friends SET dm_room_id = NULL").execute(&pool).await.unwrap();
+    // a room alice isn't in doesn't count, wherever it is in the list
+    {
+        let mut hs = app.homeserver.state.lock().unwrap();
+        let direct = hs.account_data.get_mut(&(alice.user_id.clone(), "m.direct".to_string())).unwrap();
+        direct[&bob.user_id].as_array_mut().unwrap().push(json!("!gone:localhost"));
+        direct[&carol.user_id] = json!(["!gone:localhost"]);
+    }
+
+    let (status, body) = app.authed(&alice, Method::GET, "/dms/recover", None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["recovered"], json!([{ "user_id": bob.user_id, "room_id": dm_id }]));
+
+    let (_, friends) = app
+        .get(&format!("/friends?access_token={}&user_id={}", bob.access_token, enc(&bob.user_id)))
+        .await;
+    assert_eq!(friends["friends"][0]["dm_room_id"], dm_id.as_str());
+
+    // nothing left to do the second time
+    let (_, body) = app.authed(&alice, Method::GET, "/dms/recover", None).await;
+    assert_eq!(body["recovered"], json!([]));
+}
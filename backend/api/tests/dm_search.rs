@@ -0,0 +1,181 @@
+// /dms/search: one conversation searched with sender and date filters, over
+// the homeserver's search api where it has one and a history scan where it
+// doesn't (the fake homeserver has none, like some conduit builds)
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::time::Duration;
+use wiremock::matchers::{method, path_regex, query_param};
+use wiremock::{Mock, ResponseTemplate};
+
+/// a conversation between alice and bob, one message per (sender, body),
+/// a few ms apart so every message has its own timestamp
+async fn conversation(app: &TestApp, alice: &TestUser, bob: &TestUser, lines: &[(&TestUser, &str)]) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "dm" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    app.post("/rooms/invite", json!({ "access_token": alice.access_token, "room_id": room_id, "user_id": bob.user_id })).await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id })).await;
+    for (sender, body) in lines {
+        let send = json!({ "access_token": sender.access_token, "room_id": room_id, "content": body });
+        assert_eq!(app.post("/rooms/send", send).await.0, StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(3)).await;
+    }
+    room_id
+}
+
+async fn search(app: &TestApp, user: &TestUser, room_id: &str, query: &str, extra: &str) -> Value {
+    let url = format!("/dms/search?access_token={}&room_id={}&query={}{}", user.access_token, enc(room_id), enc(query), extra);
+    let (status, body) = app.get(&url).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body
+}
+
+fn found(body: &Value) -> Vec<&str> {
+    body["results"].as_array().unwrap().iter().map(|r| r["message"]["content"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn history_is_scanned_with_sender_and_date_filters() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = conversation(
+        &app,
+        &alice,
+        &bob,
+        &[(&alice, "Lunch at noon?"), (&bob, "lunch sounds good"), (&bob, "see you there"), (&alice, "no lunch today after all")],
+    )
+    .await;
+
+    let all = search(&app, &alice, &room_id, "LUNCH", "").await;
+    assert_eq!(found(&all), ["no lunch today after all", "lunch sounds good", "Lunch at noon?"]);
+    assert!(all.get("next_batch").is_none());
+    // each match comes with its neighbours for the snippet
+    let middle = &all["results"][1];
+    assert_eq!(middle["context_before"]["content"], "Lunch at noon?");
+    assert_eq!(middle["context_after"]["content"], "see you there");
+    assert!(all["results"][0]["context_after"].is_null());
+    let sent: HashMap<&str, i64> = all["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| (r["message"]["content"].as_str().unwrap(), r["message"]["timestamp"].as_i64().unwrap()))
+        .collect();
+
+    let from_bob = search(&app, &alice, &room_id, "lunch", &format!("&sender={}", enc(&bob.user_id))).await;
+    assert_eq!(found(&from_bob), ["lunch sounds good"]);
+    let before = search(&app, &alice, &room_id, "lunch", &format!("&before={}", sent["no lunch today after all"])).await;
+    assert_eq!(found(&before), ["lunch sounds good", "Lunch at noon?"]);
+    let after = search(&app, &alice, &room_id, "lunch", &format!("&after={}", sent["Lunch at noon?"])).await;
+    assert_eq!(found(&after), ["no lunch today after all", "lunch sounds good"]);
+    let between = format!("&after={}&before={}", sent["Lunch at noon?"], sent["no lunch today after all"]);
+    assert_eq!(found(&search(&app, &alice, &room_id, "lunch", &between).await), ["lunch sounds good"]);
+    let from_alice_after = format!("&sender={}&after={}", enc(&alice.user_id), sent["Lunch at noon?"]);
+    assert_eq!(found(&search(&app, &alice, &room_id, "lunch", &from_alice_after).await), ["no lunch today after all"]);
+    assert!(found(&search(&app, &alice, &room_id, "dinner", "").await).is_empty());
+}
+
+#[tokio::test]
+async fn a_scan_continues_from_its_token() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = conversation(&app, &alice, &bob, &[(&alice, "one ping"), (&bob, "pong"), (&bob, "two ping"), (&alice, "three ping")]).await;
+
+    let mut pages = Vec::new();
+    let mut from = String::new();
+    loop {
+        let page = search(&app, &alice, &room_id, "ping", &format!("&limit=2{}", from)).await;
+        pages.push(found(&page).into_iter().map(String::from).collect::<Vec<_>>());
+        match page["next_batch"].as_str() {
+            Some(next) => from = format!("&from={}", enc(next)),
+            None => break,
+        }
+    }
+    assert_eq!(pages.concat(), ["three ping", "two ping", "one ping"]);
+    assert_eq!(pages[0].len(), 2);
+
+    let bad = format!("/dms/search?access_token={}&room_id={}&query=ping&from=nonsense", alice.access_token, enc(&room_id));
+    assert_eq!(app.get(&bad).await.0, StatusCode::BAD_REQUEST);
+    let blank = format!("/dms/search?access_token={}&room_id={}&query=%20", alice.access_token, enc(&room_id));
+    assert_eq!(app.get(&blank).await.0, StatusCode::BAD_REQUEST);
+}
+
+fn hit(room_id: &str, id: &str, sender: &str, body: &str, ts: i64) -> Value {
+    json!({ "type": "m.room.message", "room_id": room_id, "event_id": id, "sender": sender, "origin_server_ts": ts,
+            "content": { "msgtype": "m.text", "body": body } })
+}
+
+#[tokio::test]
+async fn the_homeserver_search_api_is_used_when_there_is_one() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = conversation(&app, &alice, &bob, &[]).await;
+    let results = json!({ "search_categories": { "room_events": { "next_batch": "page-2", "results": [
+        { "result": hit(&room_id, "$3", &bob.user_id, "Deploy done", 3_000),
+          "context": { "events_before": [hit(&room_id, "$2", &alice.user_id, "how is it going", 2_000)], "events_after": [] } },
+        { "result": hit(&room_id, "$1", &alice.user_id, "deploying now", 1_000), "context": {} },
+    ] } } });
+    let last = json!({ "search_categories": { "room_events": { "results": [
+        { "result": hit(&room_id, "$0", &bob.user_id, "deploy planned", 500), "context": {} },
+    ] } } });
+    Mock::given(method("POST"))
+        .and(path_regex("/search$"))
+        .and(query_param("next_batch", "page-2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(last))
+        .with_priority(1)
+        .mount(&app.homeserver.server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex("/search$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(results))
+        .with_priority(2)
+        .mount(&app.homeserver.server)
+        .await;
+
+    let body = search(&app, &alice, &room_id, "deploy", "&limit=2").await;
+    assert_eq!(found(&body), ["Deploy done", "deploying now"]);
+    assert_eq!(body["results"][0]["context_before"]["content"], "how is it going");
+    assert!(body["next_batch"].is_string());
+    // dates aren't part of the search api, so they're applied to what comes back
+    assert_eq!(found(&search(&app, &alice, &room_id, "deploy", "&before=2000").await), ["deploying now", "deploy planned"]);
+    // and past `after` the next page isn't even asked for
+    assert_eq!(found(&search(&app, &alice, &room_id, "deploy", "&after=2000").await), ["Deploy done"]);
+
+    let requests = app.homeserver.server.received_requests().await.unwrap();
+    assert_eq!(requests.iter().filter(|r| r.url.path().ends_with("/messages")).count(), 0);
+    let searches: Vec<Value> = requests
+        .iter()
+        .filter(|r| r.url.path().ends_with("/search"))
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect();
+    assert_eq!(searches[0]["search_categories"]["room_events"]["filter"]["rooms"], json!([room_id]));
+
+    // the token carries on with the search api rather than a scan
+    let next = body["next_batch"].as_str().unwrap();
+    let rest = search(&app, &alice, &room_id, "deploy", &format!("&limit=2&from={}", enc(next))).await;
+    assert_eq!(found(&rest), ["deploy planned"]);
+    assert!(rest.get("next_batch").is_none());
+}
+
+#[sqlx::test]
+async fn blocked_users_are_left_out(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = conversation(&app, &alice, &bob, &[(&alice, "hello?"), (&bob, "hello back"), (&alice, "hello again")]).await;
+    let block = json!({ "access_token": alice.access_token, "friend_id": bob.user_id });
+    assert_eq!(app.post("/friends/block", block).await.0, StatusCode::OK);
+
+    let body = search(&app, &alice, &room_id, "hello", "").await;
+    assert_eq!(found(&body), ["hello again", "hello?"]);
+    // nor do they show up as context
+    assert_eq!(body["results"][0]["context_before"], Value::Null);
+    assert_eq!(body["results"][1]["context_after"], Value::Null);
+}
@@ -0,0 +1,139 @@
+// email: the address captured at registration or set later, the emailed
+// verify link, and the unsubscribe link at the bottom of a digest — with a
+// stub transport standing in for smtp
+
+mod common;
+
+use agora_api::email::{self, EmailConfig};
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use lettre::transport::stub::AsyncStubTransport;
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::Duration;
+
+async fn email_app(pool: PgPool) -> (TestApp, AsyncStubTransport) {
+    let mailer = AsyncStubTransport::new_ok();
+    let stub = mailer.clone();
+    let app = TestApp::with_config(|state| {
+        state.db_pool = Some(pool);
+        state.email = Some(EmailConfig::stub(stub));
+    })
+    .await;
+    (app, mailer)
+}
+
+/// the `path?token=...` link in the newest mail to `to`, as a path to request
+async fn link(mailer: &AsyncStubTransport, to: &str, path: &str) -> String {
+    let messages = mailer.messages().await;
+    let (_, raw) = messages
+        .iter()
+        .rev()
+        .find(|(envelope, _)| envelope.to().iter().any(|a| a.to_string() == to))
+        .unwrap_or_else(|| panic!("no mail to {} in {:?}", to, messages));
+    // undo quoted-printable's soft line breaks and escaped `=`
+    let text = raw.replace("=\r\n", "").replace("=3D", "=");
+    let start = text.find(&format!("{}?token=", path)).unwrap_or_else(|| panic!("no {} link in {}", path, text));
+    let token: String = text[start + path.len() + "?token=".len()..].chars().take_while(char::is_ascii_alphanumeric).collect();
+    format!("{}?token={}", path, token)
+}
+
+async fn status(app: &TestApp, user: &TestUser) -> serde_json::Value {
+    let (status, body) = app.authed(user, Method::GET, "/account/email", None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body
+}
+
+#[sqlx::test]
+async fn the_emailed_link_verifies_the_address(pool: PgPool) {
+    let (app, mailer) = email_app(pool).await;
+    let alice = app.register("alice").await;
+    assert_eq!(app.authed(&alice, Method::GET, "/account/email", None).await.0, StatusCode::NOT_FOUND);
+
+    let set = json!({ "email": "alice@example.org", "digest_frequency": "daily" });
+    assert_eq!(app.authed(&alice, Method::POST, "/account/email", Some(set)).await.0, StatusCode::OK);
+    assert_eq!(status(&app, &alice).await, json!({ "email": "alice@example.org", "verified": false, "digest_frequency": "daily" }));
+
+    let verify = link(&mailer, "alice@example.org", "/account/email/verify").await;
+    assert_eq!(app.get("/account/email/verify?token=not-the-token").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(app.get(&verify).await.0, StatusCode::OK);
+    assert_eq!(status(&app, &alice).await["verified"], true);
+    // the token is spent
+    assert_eq!(app.get(&verify).await.0, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn a_new_address_starts_unverified_again(pool: PgPool) {
+    let (app, mailer) = email_app(pool).await;
+    let (status_code, body) = app
+        .post("/register", json!({ "username": "alice", "password": "hunter2", "email": "alice@example.org" }))
+        .await;
+    assert_eq!(status_code, StatusCode::OK, "{}", body);
+    let alice = TestUser {
+        user_id: body["user_id"].as_str().unwrap().to_string(),
+        access_token: body["access_token"].as_str().unwrap().to_string(),
+    };
+    let first = link(&mailer, "alice@example.org", "/account/email/verify").await;
+    assert_eq!(app.get(&first).await.0, StatusCode::OK);
+    assert_eq!(status(&app, &alice).await["verified"], true);
+
+    let set = json!({ "email": "alice@example.net" });
+    assert_eq!(app.authed(&alice, Method::POST, "/account/email", Some(set)).await.0, StatusCode::OK);
+    assert_eq!(status(&app, &alice).await["verified"], false);
+    let second = link(&mailer, "alice@example.net", "/account/email/verify").await;
+    assert_ne!(first, second);
+    assert_eq!(app.get(&first).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(app.get(&second).await.0, StatusCode::OK);
+    assert_eq!(status(&app, &alice).await["email"], "alice@example.net");
+
+    let bad = json!({ "email": "not an address" });
+    assert_eq!(app.authed(&alice, Method::POST, "/account/email", Some(bad)).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn the_digest_unsubscribe_link_turns_digests_off(pool: PgPool) {
+    let (app, mailer) = email_app(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let set = json!({ "email": "bob@example.org", "digest_frequency": "immediate" });
+    app.authed(&bob, Method::POST, "/account/email", Some(set)).await;
+    let verify = link(&mailer, "bob@example.org", "/account/email/verify").await;
+    assert_eq!(app.get(&verify).await.0, StatusCode::OK);
+
+    // bob has no presence, so he's offline and a mention waits for his digest
+    let (_, room) = app.authed(&alice, Method::POST, "/rooms/create", Some(json!({ "name": "general" }))).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let invite = json!({ "room_id": room_id, "user_id": bob.user_id });
+    app.authed(&alice, Method::POST, "/rooms/invite", Some(invite)).await;
+    app.authed(&bob, Method::POST, "/rooms/join", Some(json!({ "room_id_or_alias": room_id }))).await;
+    let message = json!({ "room_id": room_id, "content": format!("{} lunch?", bob.user_id) });
+    assert_eq!(app.authed(&alice, Method::POST, "/rooms/send", Some(message)).await.0, StatusCode::OK);
+
+    // the capture runs after the send returns
+    let mut sent = 0;
+    for _ in 0..50 {
+        sent = email::send_due_digests(&app.state).await.unwrap();
+        if sent > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(sent, 1);
+    // recorded, so the next run has nothing to send
+    assert_eq!(email::send_due_digests(&app.state).await.unwrap(), 0);
+
+    let unsubscribe = link(&mailer, "bob@example.org", "/email/unsubscribe").await;
+    assert_eq!(app.get("/email/unsubscribe?token=nope").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(app.get(&unsubscribe).await.0, StatusCode::OK);
+    assert_eq!(status(&app, &bob).await["digest_frequency"], "off");
+}
+
+#[tokio::test]
+async fn without_smtp_the_routes_are_unavailable() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let set = json!({ "email": "alice@example.org" });
+    assert_eq!(app.authed(&alice, Method::POST, "/account/email", Some(set)).await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(app.get("/account/email/verify?token=abc").await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(app.get("/email/unsubscribe?token=abc").await.0, StatusCode::SERVICE_UNAVAILABLE);
+}
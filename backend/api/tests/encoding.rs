@@ -0,0 +1,54 @@
+// matrix ids go into homeserver urls as single path segments — whatever
+// characters the id holds
+
+mod common;
+
+use agora_api::matrix::encode_path_segment;
+use axum::http::StatusCode;
+use common::TestApp;
+use serde_json::json;
+
+#[test]
+fn sigils_and_separators_are_encoded() {
+    assert_eq!(encode_path_segment("!abc123:localhost"), "%21abc123%3Alocalhost");
+    assert_eq!(encode_path_segment("@alice:example.org"), "%40alice%3Aexample.org");
+    assert_eq!(encode_path_segment("#general:localhost"), "%23general%3Alocalhost");
+    assert_eq!(encode_path_segment("#a/b?c&d+e:localhost"), "%23a%2Fb%3Fc%26d%2Be%3Alocalhost");
+}
+
+#[test]
+fn spaces_and_unicode_are_encoded() {
+    assert_eq!(encode_path_segment("#game night:localhost"), "%23game%20night%3Alocalhost");
+    assert_eq!(encode_path_segment("#café:localhost"), "%23caf%C3%A9%3Alocalhost");
+    assert_eq!(encode_path_segment("#☕:localhost"), "%23%E2%98%95%3Alocalhost");
+}
+
+#[test]
+fn already_encoded_input_is_not_double_encoded() {
+    let once = encode_path_segment("#game night ☕:localhost");
+    assert_eq!(encode_path_segment(&once), once);
+    assert_eq!(encode_path_segment("%23general%3Alocalhost"), "%23general%3Alocalhost");
+}
+
+#[tokio::test]
+async fn aliases_with_unicode_names_can_be_joined() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "café ☕" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let alias = "#café ☕/lounge:localhost";
+    app.homeserver.state.lock().unwrap().aliases.insert(alias.to_string(), room_id.clone());
+
+    let (status, body) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": alias }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(
+        app.homeserver.inspect(|hs| hs.rooms[&room_id].membership(&bob.user_id).map(str::to_string)),
+        Some("join".to_string())
+    );
+}
@@ -0,0 +1,157 @@
+// /ws/events: the sync loop pushed over a real socket, next to presence
+
+mod common;
+
+use agora_api::app_state::{PresenceEvent, WsEvent};
+use common::{TestApp, TestUser};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// the router on a real port, since a websocket upgrade needs a connection
+async fn serve(app: &TestApp) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = app.router.clone();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("ws://{}", addr)
+}
+
+async fn connect(base: &str, user: &TestUser, since: Option<&str>) -> Socket {
+    let mut url = format!("{}/ws/events?access_token={}", base, user.access_token);
+    if let Some(since) = since {
+        url.push_str(&format!("&since={}", since));
+    }
+    connect_async(url).await.expect("socket opens").0
+}
+
+async fn next_frame(socket: &mut Socket) -> Value {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(10), socket.next())
+            .await
+            .expect("a frame within 10s")
+            .expect("socket still open")
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+async fn syncs_for(app: &TestApp, user: &TestUser) -> usize {
+    let bearer = format!("Bearer {}", user.access_token);
+    let requests = app.homeserver.server.received_requests().await.unwrap();
+    requests
+        .iter()
+        .filter(|r| r.url.path().ends_with("/sync") && r.headers.get("authorization").is_some_and(|h| h == bearer.as_str()))
+        .count()
+}
+
+#[tokio::test]
+async fn timeline_membership_ephemeral_and_presence_share_one_socket() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id })).await;
+    app.post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": room_id, "content": "before" })).await;
+
+    let base = serve(&app).await;
+    let mut socket = connect(&base, &bob, None).await;
+    // no since: just the position, not the backlog
+    let first = next_frame(&mut socket).await;
+    assert_eq!(first["type"], "next_batch");
+    assert!(first["payload"]["next_batch"].is_string());
+
+    app.post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": room_id, "content": "hi bob" })).await;
+    let message = next_frame(&mut socket).await;
+    assert_eq!(message["type"], "message");
+    assert_eq!(message["payload"]["content"], "hi bob");
+    assert_eq!(message["payload"]["sender"], alice.user_id.as_str());
+    assert_eq!(next_frame(&mut socket).await["type"], "next_batch");
+
+    let (_, other) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "secret" })).await;
+    let body = json!({ "access_token": alice.access_token, "room_id": other["room_id"], "user_id": bob.user_id });
+    app.post("/rooms/invite", body).await;
+    let invite = next_frame(&mut socket).await;
+    assert_eq!(invite["type"], "invite");
+    assert_eq!(invite["payload"]["room_id"], other["room_id"]);
+    assert_eq!(invite["payload"]["inviter"], alice.user_id.as_str());
+    assert_eq!(next_frame(&mut socket).await["type"], "next_batch");
+
+    app.state.publish(WsEvent::Presence(PresenceEvent { user_id: alice.user_id.clone(), presence: "online".into() }));
+    assert_eq!(next_frame(&mut socket).await, json!({ "user_id": alice.user_id, "presence": "online" }));
+
+    {
+        let mut hs = app.homeserver.state.lock().unwrap();
+        let position = hs.timeline.len();
+        let typing = json!({ "type": "m.typing", "content": { "user_ids": [alice.user_id] } });
+        let receipt = json!({
+            "type": "m.receipt",
+            "content": { "$read": { "m.read": { alice.user_id.clone(): { "ts": 1234 } } } },
+        });
+        hs.ephemeral.push((position, room_id.clone(), typing));
+        hs.ephemeral.push((position, room_id.clone(), receipt));
+    }
+    assert_eq!(next_frame(&mut socket).await, json!({
+        "type": "typing",
+        "payload": { "room_id": room_id, "user_ids": [alice.user_id] },
+    }));
+    assert_eq!(next_frame(&mut socket).await, json!({
+        "type": "receipt",
+        "payload": {
+            "room_id": room_id,
+            "event_id": "$read",
+            "user_id": alice.user_id,
+            "receipt_type": "m.read",
+            "timestamp": 1234,
+        },
+    }));
+}
+
+#[tokio::test]
+async fn a_reconnect_resumes_and_a_close_stops_the_long_poll() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+
+    let base = serve(&app).await;
+    let mut socket = connect(&base, &alice, None).await;
+    let since = next_frame(&mut socket).await["payload"]["next_batch"].as_str().unwrap().to_string();
+    socket.close(None).await.unwrap();
+
+    for _ in 0..50 {
+        if app.state.ws_connections.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(app.state.ws_connections.is_empty());
+    let polls = syncs_for(&app, &alice).await;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(syncs_for(&app, &alice).await, polls, "still syncing after the socket closed");
+
+    app.post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": room_id, "content": "while away" })).await;
+    let mut socket = connect(&base, &alice, Some(&since)).await;
+    let message = next_frame(&mut socket).await;
+    assert_eq!((message["type"].clone(), message["payload"]["content"].clone()), (json!("message"), json!("while away")));
+}
+
+#[tokio::test]
+async fn a_bad_token_is_refused_before_the_upgrade() {
+    let app = TestApp::new().await;
+    let base = serve(&app).await;
+    let stranger = TestUser { user_id: "@nobody:localhost".into(), access_token: "nope".into() };
+    let url = format!("{}/ws/events?access_token={}", base, stranger.access_token);
+    match connect_async(url).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expected a 401, got {:?}", other.map(|(_, r)| r.status())),
+    }
+}
@@ -0,0 +1,135 @@
+// end-to-end user flows through the full router. these need postgres:
+// #[sqlx::test] creates a fresh database per test from DATABASE_URL and runs
+// the migrations into it.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp};
+use serde_json::json;
+use sqlx::PgPool;
+
+#[sqlx::test]
+async fn server_chat_friends_and_dm(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    // a fresh login works with the short username
+    let (status, login) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(login["user_id"], alice.user_id.as_str());
+
+    // alice creates a server with a channel in it, the way the create-server wizard does
+    let (status, server) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Test Server", "is_space": true }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+
+    let (status, channel) = app
+        .post("/rooms/create", json!({
+            "access_token": alice.access_token,
+            "name": "general",
+            "parent_space_id": server_id,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let channel_id = channel["room_id"].as_str().unwrap().to_string();
+
+    let (status, children) = app
+        .get(&format!("/rooms/children?access_token={}&space_id={}", alice.access_token, enc(&server_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(children["children"][0]["room_id"], channel_id.as_str());
+    assert_eq!(children["children"][0]["channel_type"], "text");
+
+    // joining the server joins its channels too
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        app.homeserver.inspect(|hs| hs.rooms[&channel_id].membership(&bob.user_id).map(str::to_string)),
+        Some("join".to_string())
+    );
+
+    // alice talks, bob's sync sees it
+    let (status, sent) = app
+        .post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": channel_id, "content": "hello bob" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let event_id = sent["event_id"].as_str().unwrap().to_string();
+
+    let (status, sync) = app.get(&format!("/sync?access_token={}", bob.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let message = sync["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["event_id"] == event_id.as_str())
+        .expect("bob's sync has alice's message");
+    assert_eq!(message["content"], "hello bob");
+    assert_eq!(message["sender"], alice.user_id.as_str());
+    assert_eq!(message["room_id"], channel_id.as_str());
+    // bob can neither edit nor delete alice's message
+    assert_eq!(message["editable"], false);
+    assert_eq!(message["deletable"], false);
+
+    // the next sync from the returned token is empty
+    let since = sync["next_batch"].as_str().unwrap();
+    let (_, sync) = app.get(&format!("/sync?access_token={}&since={}", bob.access_token, since)).await;
+    assert_eq!(sync["messages"].as_array().unwrap().len(), 0);
+
+    // friend request, accepted by bob
+    let (status, _) = app
+        .post("/friends/add", json!({ "access_token": alice.access_token, "user_id": alice.user_id, "friend_id": bob.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, friends) = app
+        .get(&format!("/friends?access_token={}&user_id={}", bob.access_token, enc(&bob.user_id)))
+        .await;
+    assert_eq!(friends["friends"][0]["user_id"], alice.user_id.as_str());
+    assert_eq!(friends["friends"][0]["status"], "pending_received");
+
+    let (status, _) = app
+        .post("/friends/accept", json!({ "access_token": bob.access_token, "user_id": bob.user_id, "friend_id": alice.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the dm is created once and shared by both sides
+    let (status, dm) = app
+        .post("/friends/dm", json!({ "access_token": alice.access_token, "user_id": alice.user_id, "friend_id": bob.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let dm_id = dm["room_id"].as_str().unwrap().to_string();
+
+    let (status, dm) = app
+        .post("/friends/dm", json!({ "access_token": bob.access_token, "user_id": bob.user_id, "friend_id": alice.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(dm["room_id"], dm_id.as_str());
+
+    let (_, friends) = app
+        .get(&format!("/friends?access_token={}&user_id={}", alice.access_token, enc(&alice.user_id)))
+        .await;
+    assert_eq!(friends["friends"][0]["status"], "accepted");
+    assert_eq!(friends["friends"][0]["dm_room_id"], dm_id.as_str());
+
+    // bob leaves the server — its channels go with it, the dm stays
+    let (status, _) = app
+        .post("/rooms/leave", json!({ "access_token": bob.access_token, "room_id": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, rooms) = app.get(&format!("/rooms?access_token={}", bob.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let room_ids: Vec<&str> = rooms["rooms"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|r| r["room_id"].as_str())
+        .collect();
+    assert_eq!(room_ids, vec![dm_id.as_str()]);
+}
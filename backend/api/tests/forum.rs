@@ -0,0 +1,262 @@
+// forum threads: pinning, locking, archiving, deleting, counting and tagging
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// a server with a forum channel, owned by `owner` and joined by `member`
+async fn forum(app: &TestApp, owner: &TestUser, member: &TestUser) -> String {
+    let (_, server) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": "Club", "is_space": true }))
+        .await;
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+    let (_, forum) = app
+        .post("/rooms/create", json!({
+            "access_token": owner.access_token,
+            "name": "help",
+            "parent_space_id": server_id,
+            "channel_type": "forum",
+        }))
+        .await;
+    app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": server_id })).await;
+    forum["room_id"].as_str().unwrap().to_string()
+}
+
+async fn thread(app: &TestApp, author: &TestUser, forum_id: &str, title: &str) -> String {
+    let body = json!({
+        "access_token": author.access_token,
+        "forum_channel_id": forum_id,
+        "title": title,
+        "author": author.user_id,
+        "body": "first post",
+    });
+    let (status, created) = app.post("/servers/forum/thread", body).await;
+    assert_eq!(status, StatusCode::OK);
+    created["room_id"].as_str().unwrap().to_string()
+}
+
+async fn update(app: &TestApp, user: &TestUser, thread_id: &str, changes: Value) -> (StatusCode, Value) {
+    let mut body = json!({ "access_token": user.access_token, "thread_room_id": thread_id });
+    body.as_object_mut().unwrap().extend(changes.as_object().unwrap().clone());
+    app.post("/servers/forum/thread/update", body).await
+}
+
+async fn titles(app: &TestApp, viewer: &TestUser, forum_id: &str, archived: &str) -> Vec<String> {
+    let (_, body) = app
+        .get(&format!(
+            "/servers/forum/threads?access_token={}&forum_channel_id={}&archived={}",
+            viewer.access_token,
+            enc(forum_id),
+            archived
+        ))
+        .await;
+    body["threads"].as_array().unwrap().iter().map(|t| t["title"].as_str().unwrap().to_string()).collect()
+}
+
+async fn sorted(app: &TestApp, viewer: &TestUser, forum_id: &str, sort: &str) -> Vec<Value> {
+    let (_, body) = app
+        .get(&format!(
+            "/servers/forum/threads?access_token={}&forum_channel_id={}&sort={}",
+            viewer.access_token,
+            enc(forum_id),
+            sort
+        ))
+        .await;
+    body["threads"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn pinned_first_and_archived_on_request() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let forum_id = forum(&app, &alice, &bob).await;
+    let older = thread(&app, &alice, &forum_id, "older").await;
+    let newer = thread(&app, &alice, &forum_id, "newer").await;
+
+    let (status, body) = update(&app, &alice, &older, json!({ "pinned": true })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["pinned"], true);
+    assert_eq!(titles(&app, &alice, &forum_id, "exclude").await, ["older", "newer"]);
+
+    assert_eq!(update(&app, &alice, &newer, json!({ "archived": true })).await.0, StatusCode::OK);
+    assert_eq!(titles(&app, &alice, &forum_id, "exclude").await, ["older"]);
+    assert_eq!(titles(&app, &alice, &forum_id, "include").await, ["older", "newer"]);
+    assert_eq!(titles(&app, &alice, &forum_id, "only").await, ["newer"]);
+
+    // pinning is for moderators
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": newer })).await;
+    let (status, body) = update(&app, &bob, &newer, json!({ "pinned": true })).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["permission"], "manage_messages");
+}
+
+#[tokio::test]
+async fn threads_page_by_cursor() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let forum_id = forum(&app, &alice, &bob).await;
+    let mut ids = Vec::new();
+    for title in ["one", "two", "three", "four", "five"] {
+        ids.push(thread(&app, &alice, &forum_id, title).await);
+        tokio::time::sleep(Duration::from_millis(3)).await;
+    }
+    assert_eq!(update(&app, &alice, &ids[1], json!({ "pinned": true })).await.0, StatusCode::OK);
+    let url = |extra: &str| format!("/servers/forum/threads?access_token={}&forum_channel_id={}{}", alice.access_token, enc(&forum_id), extra);
+
+    let mut pages = Vec::new();
+    let mut after = String::new();
+    loop {
+        let (status, page) = app.get(&url(&format!("&limit=2{}", after))).await;
+        assert_eq!(status, StatusCode::OK, "{}", page);
+        assert_eq!(page["total"], 5);
+        pages.push(page["items"].as_array().unwrap().iter().map(|t| t["title"].as_str().unwrap().to_string()).collect::<Vec<_>>());
+        match page["next_cursor"].as_str() {
+            Some(cursor) => after = format!("&after={}", enc(cursor)),
+            None => break,
+        }
+    }
+    assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+    // the same order as the whole list: pinned first, then newest
+    assert_eq!(pages.concat(), titles(&app, &alice, &forum_id, "exclude").await);
+    assert_eq!(pages.concat(), ["two", "five", "four", "three", "one"]);
+    assert_eq!(app.get(&url("&limit=2&after=garbage")).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn a_locked_thread_takes_no_new_messages() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let forum_id = forum(&app, &alice, &bob).await;
+    let thread_id = thread(&app, &alice, &forum_id, "rules").await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": thread_id })).await;
+    let send = |user: &TestUser| json!({ "access_token": user.access_token, "room_id": thread_id, "content": "hi" });
+
+    assert_eq!(update(&app, &alice, &thread_id, json!({ "locked": true })).await.0, StatusCode::OK);
+    let (status, body) = app.post("/rooms/send", send(&bob)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "AGORA_THREAD_LOCKED");
+    // the server's owner can still post
+    assert_eq!(app.post("/rooms/send", send(&alice)).await.0, StatusCode::OK);
+
+    assert_eq!(update(&app, &alice, &thread_id, json!({ "locked": false })).await.0, StatusCode::OK);
+    assert_eq!(app.post("/rooms/send", send(&bob)).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn deleting_a_thread_unlinks_and_leaves_it() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let forum_id = forum(&app, &alice, &bob).await;
+    let thread_id = thread(&app, &alice, &forum_id, "oops").await;
+
+    let (status, body) = update(&app, &alice, &thread_id, json!({ "delete": true })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["deleted"], true);
+    let (link, membership) = app.homeserver.inspect(|hs| {
+        let link = hs.rooms[&forum_id].state[&("m.space.child".to_string(), thread_id.clone())]["content"].clone();
+        (link, hs.rooms[&thread_id].membership(&alice.user_id).map(String::from))
+    });
+    assert_eq!(link, json!({}));
+    assert_eq!(membership.as_deref(), Some("leave"));
+    assert!(titles(&app, &alice, &forum_id, "include").await.is_empty());
+
+    let (status, _) = update(&app, &alice, &forum_id, json!({ "archived": true })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn replies_are_counted_and_sortable() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let forum_id = forum(&app, &alice, &bob).await;
+    thread(&app, &alice, &forum_id, "quiet").await;
+    let busy = thread(&app, &alice, &forum_id, "busy").await;
+    let revived = thread(&app, &alice, &forum_id, "revived").await;
+    let reply = |room_id: &str| json!({ "access_token": alice.access_token, "room_id": room_id, "content": "me too" });
+    for _ in 0..2 {
+        app.post("/rooms/send", reply(&busy)).await;
+    }
+
+    let by_title = |threads: &[Value], title: &str| threads.iter().find(|t| t["title"] == title).unwrap().clone();
+    let order = |threads: &[Value]| threads.iter().map(|t| t["title"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+    let threads = sorted(&app, &alice, &forum_id, "replies").await;
+    assert_eq!(by_title(&threads, "busy")["reply_count"], 2);
+    assert_eq!(by_title(&threads, "quiet")["reply_count"], 0);
+    assert_eq!(order(&threads)[0], "busy");
+    assert!(by_title(&threads, "quiet")["last_activity_ts"].is_u64());
+
+    // the counts are cached until a sync sees the thread move
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    app.post("/rooms/send", reply(&revived)).await;
+    assert_eq!(by_title(&sorted(&app, &alice, &forum_id, "replies").await, "revived")["reply_count"], 0);
+    app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    let threads = sorted(&app, &alice, &forum_id, "activity").await;
+    assert_eq!(by_title(&threads, "revived")["reply_count"], 1);
+    assert_eq!(order(&threads)[0], "revived");
+}
+
+#[tokio::test]
+async fn threads_are_tagged_from_the_forums_set() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let forum_id = forum(&app, &alice, &bob).await;
+
+    let tags = json!([
+        { "id": "help", "name": "help", "color": "#5865f2" },
+        { "id": "bug", "name": "bug", "color": "#ed4245" },
+    ]);
+    let set = |user: &TestUser, tags: Value| json!({ "access_token": user.access_token, "forum_channel_id": forum_id, "tags": tags });
+    let (status, body) = app.post("/servers/forum/tags", set(&bob, tags.clone())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["permission"], "manage_channels");
+    assert_eq!(app.post("/servers/forum/tags", set(&alice, tags)).await.0, StatusCode::OK);
+    let too_many: Vec<Value> = (0..21).map(|i| json!({ "id": format!("t{}", i), "name": "tag" })).collect();
+    assert_eq!(app.post("/servers/forum/tags", set(&alice, json!(too_many))).await.0, StatusCode::BAD_REQUEST);
+    let path = format!("/servers/forum/tags?access_token={}&forum_channel_id={}", bob.access_token, enc(&forum_id));
+    assert_eq!(app.get(&path).await.1["tags"][1]["color"], "#ed4245");
+
+    // a post is tagged as it's made, and only with tags the forum has
+    let post = |tag_ids: Value| json!({
+        "access_token": alice.access_token,
+        "forum_channel_id": forum_id,
+        "title": "crash on start",
+        "author": alice.user_id,
+        "body": "it crashes",
+        "tag_ids": tag_ids,
+    });
+    let (status, body) = app.post("/servers/forum/thread", post(json!(["showcase"]))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let (_, created) = app.post("/servers/forum/thread", post(json!(["bug"]))).await;
+    let thread_id = created["room_id"].as_str().unwrap().to_string();
+    thread(&app, &alice, &forum_id, "welcome").await;
+
+    let tagged = |tag: &str| format!(
+        "/servers/forum/threads?access_token={}&forum_channel_id={}&tag={}",
+        alice.access_token,
+        enc(&forum_id),
+        tag
+    );
+    let (_, body) = app.get(&tagged("bug")).await;
+    assert_eq!(body["threads"].as_array().unwrap().len(), 1);
+    assert_eq!(body["threads"][0]["tags"], json!(["bug"]));
+
+    // re-tagging is for the author and moderators
+    let retag = |user: &TestUser| json!({ "access_token": user.access_token, "thread_room_id": thread_id, "tag_ids": ["help", "help"] });
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": thread_id })).await;
+    assert_eq!(app.post("/servers/forum/thread/tags", retag(&bob)).await.0, StatusCode::FORBIDDEN);
+    let (status, body) = app.post("/servers/forum/thread/tags", retag(&alice)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["tags"], json!(["help"]));
+    assert!(app.get(&tagged("bug")).await.1["threads"].as_array().unwrap().is_empty());
+    assert_eq!(app.get(&tagged("help")).await.1["threads"][0]["title"], "crash on start");
+}
@@ -0,0 +1,79 @@
+// friend request notifications: pushed to the websocket of the user they're for.
+// these need postgres, like flows.rs.
+
+mod common;
+
+use agora_api::app_state::{ConnectionQueue, QueueItem, WsEvent};
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn friends_action(app: &TestApp, path: &str, user: &TestUser, other: &TestUser) -> StatusCode {
+    let body = json!({ "access_token": user.access_token, "user_id": user.user_id, "friend_id": other.user_id });
+    app.post(path, body).await.0
+}
+
+async fn next_event(queue: &ConnectionQueue) -> Value {
+    match queue.pop().await {
+        QueueItem::Event(event @ WsEvent::User(_)) => serde_json::to_value(event).unwrap(),
+        _ => panic!("expected a user event"),
+    }
+}
+
+#[sqlx::test]
+async fn requests_and_acceptances_reach_only_their_target(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, alice_queue) = app.state.register_user_connection(16, Some(alice.user_id.clone()));
+    let (_, bob_queue) = app.state.register_user_connection(16, Some(bob.user_id.clone()));
+    let (_, anonymous) = app.state.register_connection(16);
+
+    assert_eq!(friends_action(&app, "/friends/add", &alice, &bob).await, StatusCode::OK);
+    assert_eq!(next_event(&bob_queue).await, json!({
+        "type": "friend_request_received",
+        "payload": { "user_id": alice.user_id, "display_name": "alice", "avatar_url": null },
+    }));
+    // asking again doesn't ping bob twice
+    assert_eq!(friends_action(&app, "/friends/add", &alice, &bob).await, StatusCode::OK);
+    assert_eq!(bob_queue.depth(), 0);
+
+    assert_eq!(friends_action(&app, "/friends/accept", &bob, &alice).await, StatusCode::OK);
+    let accepted = next_event(&alice_queue).await;
+    assert_eq!(accepted["type"], "friend_request_accepted");
+    assert_eq!(accepted["payload"]["user_id"], bob.user_id.as_str());
+    assert_eq!(accepted["payload"]["display_name"], "bob");
+
+    assert_eq!(alice_queue.depth(), 0);
+    assert_eq!(bob_queue.depth(), 0);
+    assert_eq!(anonymous.depth(), 0);
+}
+
+#[sqlx::test]
+async fn ws_metrics_dont_say_who_is_connected(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let (id, _queue) = app.state.register_user_connection(16, Some(alice.user_id.clone()));
+
+    let (status, metrics) = app.get("/ws/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    let connection = metrics["connections"].as_array().unwrap().iter().find(|c| c["connection_id"] == id).unwrap();
+    assert_eq!(connection["capacity"], 16);
+    assert!(connection.get("user_id").is_none(), "{}", connection);
+    assert!(!metrics.to_string().contains(&alice.user_id));
+}
+
+#[sqlx::test]
+async fn crossing_requests_accept_each_other(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, alice_queue) = app.state.register_user_connection(16, Some(alice.user_id.clone()));
+
+    friends_action(&app, "/friends/add", &alice, &bob).await;
+    friends_action(&app, "/friends/add", &bob, &alice).await;
+    let accepted = next_event(&alice_queue).await;
+    assert_eq!(accepted["type"], "friend_request_accepted");
+    assert_eq!(accepted["payload"]["user_id"], bob.user_id.as_str());
+}
@@ -0,0 +1,130 @@
+// group calls in group dms: several people in one call, tracked per call id
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a room alice, bob and carol are all in
+async fn group(app: &TestApp) -> (TestUser, TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "group" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    for user in [&bob, &carol] {
+        let (status, _) = app
+            .post("/rooms/join", json!({ "access_token": user.access_token, "room_id_or_alias": room_id }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    (alice, bob, carol, room_id)
+}
+
+async fn call(app: &TestApp, user: &TestUser, room_id: &str, action: &str, extra: Value) {
+    let mut body = json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "action": action,
+        "call_id": "g1",
+        "from_user_id": user.user_id,
+    });
+    body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+    let (status, _) = app.post("/voice/call", body).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn participants(app: &TestApp, user: &TestUser) -> Value {
+    let (status, body) = app
+        .get(&format!("/voice/call/participants?access_token={}&call_id=g1", user.access_token))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["call_id"], "g1");
+    body["participants"].clone()
+}
+
+fn last_content(app: &TestApp) -> Value {
+    app.homeserver.inspect(|hs| hs.timeline.last().unwrap().1["content"].clone())
+}
+
+#[tokio::test]
+async fn members_join_and_leave_a_group_call() {
+    let app = TestApp::new().await;
+    let (alice, bob, carol, room_id) = group(&app).await;
+
+    call(&app, &alice, &room_id, "ring", json!({ "invited_user_ids": [bob.user_id, carol.user_id] })).await;
+    let ring = last_content(&app);
+    assert_eq!(ring["invited_user_ids"], json!([bob.user_id, carol.user_id]));
+    assert_eq!(ring["participants"], json!([alice.user_id]));
+
+    call(&app, &bob, &room_id, "accept", json!({})).await;
+    call(&app, &carol, &room_id, "join", json!({})).await;
+    assert_eq!(last_content(&app)["participants"], json!([alice.user_id, bob.user_id, carol.user_id]));
+    assert_eq!(participants(&app, &carol).await, json!([alice.user_id, bob.user_id, carol.user_id]));
+
+    // the call carries on without alice
+    call(&app, &alice, &room_id, "leave", json!({})).await;
+    assert_eq!(last_content(&app)["participants"], json!([bob.user_id, carol.user_id]));
+    assert_eq!(participants(&app, &bob).await, json!([bob.user_id, carol.user_id]));
+
+    call(&app, &bob, &room_id, "leave", json!({})).await;
+    call(&app, &carol, &room_id, "leave", json!({})).await;
+    assert_eq!(participants(&app, &bob).await, json!([]));
+    assert!(!app.redis.lock().unwrap().contains_key("call_members:g1"));
+
+    let (status, _) = app.get("/voice/call/participants?access_token=nope&call_id=g1").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn sync_surfaces_call_fields() {
+    let app = TestApp::new().await;
+    let (alice, bob, carol, room_id) = group(&app).await;
+    call(&app, &alice, &room_id, "ring", json!({ "invited_user_ids": [bob.user_id, carol.user_id], "display_name": "Alice" })).await;
+
+    let (status, sync) = app.get(&format!("/sync?access_token={}", bob.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let ring = sync["calls"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["call_id"] == "g1")
+        .expect("bob's sync has the ring");
+    assert_eq!(ring["action"], "ring");
+    assert_eq!(ring["from"], alice.user_id.as_str());
+    assert_eq!(ring["display_name"], "Alice");
+    assert_eq!(ring["participants"], json!([alice.user_id]));
+    assert_eq!(ring["invited_user_ids"], json!([bob.user_id, carol.user_id]));
+
+    // plain messages don't grow call fields
+    app.post("/rooms/send", json!({ "access_token": bob.access_token, "room_id": room_id, "content": "hi" })).await;
+    let (_, sync) = app.get(&format!("/sync?access_token={}", carol.access_token)).await;
+    let hi = sync["messages"].as_array().unwrap().iter().find(|m| m["content"] == "hi").unwrap();
+    assert!(hi.get("call_id").is_none());
+}
+
+#[tokio::test]
+async fn each_call_gets_its_own_livekit_room() {
+    let app = TestApp::new().await;
+    let (alice, _, _, room_id) = group(&app).await;
+    let token = |call_id: &str| {
+        app.post("/voice/token", json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "user_id": alice.user_id,
+            "call_id": call_id,
+        }))
+    };
+
+    let (status, body) = token("g1").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let key = jsonwebtoken::DecodingKey::from_secret(b"devsecret_agora_local_development_key_32chars");
+    let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+    let claims = jsonwebtoken::decode::<Value>(body["token"].as_str().unwrap(), &key, &validation).unwrap().claims;
+    assert_eq!(claims["video"]["room"], "call_g1");
+
+    let (status, body) = token("../other").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+}
@@ -0,0 +1,78 @@
+// m.room.history_visibility: set explicitly when channels and dms are made,
+// switched by whoever manages a server's channels
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+fn visibility(app: &TestApp, room_id: &str) -> Value {
+    let key = ("m.room.history_visibility".to_string(), String::new());
+    app.homeserver.inspect(|hs| hs.rooms[room_id].state[&key]["content"]["history_visibility"].clone())
+}
+
+async fn create(app: &TestApp, user: &TestUser, body: Value) -> (StatusCode, Value) {
+    let mut body = body;
+    body["access_token"] = json!(user.access_token);
+    app.post("/rooms/create", body).await
+}
+
+async fn set(app: &TestApp, user: &TestUser, room_id: &str, history_visibility: &str) -> (StatusCode, Value) {
+    app.post(
+        "/rooms/history_visibility",
+        json!({ "access_token": user.access_token, "room_id": room_id, "history_visibility": history_visibility }),
+    )
+    .await
+}
+
+#[sqlx::test]
+async fn channels_and_dms_are_created_with_it(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    let (_, channel) = create(&app, &alice, json!({ "name": "general" })).await;
+    assert_eq!(visibility(&app, channel["room_id"].as_str().unwrap()), "shared");
+    let (_, private) = create(&app, &alice, json!({ "name": "staff", "private": true, "history_visibility": "invited" })).await;
+    assert_eq!(visibility(&app, private["room_id"].as_str().unwrap()), "invited");
+    let (status, _) = create(&app, &alice, json!({ "name": "odd", "history_visibility": "joined" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let add = json!({ "access_token": alice.access_token, "friend_id": bob.user_id });
+    assert_eq!(app.post("/friends/add", add).await.0, StatusCode::OK);
+    let accept = json!({ "access_token": bob.access_token, "friend_id": alice.user_id });
+    assert_eq!(app.post("/friends/accept", accept).await.0, StatusCode::OK);
+    let (status, dm) = app
+        .post("/friends/dm", json!({ "access_token": alice.access_token, "friend_id": bob.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", dm);
+    assert_eq!(visibility(&app, dm["room_id"].as_str().unwrap()), "invited");
+}
+
+#[tokio::test]
+async fn channel_managers_switch_it() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, server) = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let server_id = server["room_id"].as_str().unwrap();
+    let (_, channel) = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let channel_id = channel["room_id"].as_str().unwrap();
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": server_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+
+    assert_eq!(set(&app, &bob, channel_id, "world_readable").await.0, StatusCode::FORBIDDEN);
+    let (status, body) = set(&app, &alice, channel_id, "world_readable").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(visibility(&app, channel_id), "world_readable");
+
+    let (status, body) = set(&app, &alice, channel_id, "joined").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+
+    let (_, rooms) = app.get(&format!("/rooms?access_token={}", alice.access_token)).await;
+    let listed = rooms["rooms"].as_array().unwrap().iter().find(|r| r["room_id"] == channel_id).unwrap().clone();
+    assert_eq!(listed["history_visibility"], "world_readable");
+}
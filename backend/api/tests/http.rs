@@ -0,0 +1,59 @@
+// the shared homeserver http client: pooled across handlers, with timeouts
+
+mod common;
+
+use agora_api::matrix::client::{http_client, HttpTimeouts};
+use agora_api::matrix::retry::RetryPolicy;
+use axum::http::StatusCode;
+use common::TestApp;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use wiremock::matchers::any;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn room_list_fans_out_over_the_shared_client() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+
+    const ROOMS: usize = 25;
+    for i in 0..ROOMS {
+        let (status, _) = app
+            .post("/rooms/create", json!({ "access_token": user.access_token, "name": format!("room {}", i) }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    // /rooms does a state fetch per joined room
+    let (status, body) = app.get(&format!("/rooms?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["rooms"].as_array().unwrap().len(), ROOMS);
+}
+
+#[tokio::test]
+async fn a_stalled_homeserver_times_out() {
+    let stalled = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "user_id": "@alice:localhost" }))
+                .set_delay(Duration::from_secs(5)),
+        )
+        .mount(&stalled)
+        .await;
+
+    let app = TestApp::with_config(|state| {
+        state.homeserver_url = stalled.uri();
+        state.http = http_client(HttpTimeouts {
+            connect: Duration::from_secs(1),
+            read: Duration::from_millis(200),
+        });
+        state.matrix_retry = RetryPolicy { max_retries: 0, ..RetryPolicy::default() };
+    })
+    .await;
+
+    let started = Instant::now();
+    let (status, _) = app.get("/whoami?access_token=anything").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+}
@@ -0,0 +1,119 @@
+// invites and leaves in /sync, and acting on an invite. the dm case needs
+// postgres, like flows.rs.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn sync(app: &TestApp, user: &TestUser, since: Option<&str>) -> Value {
+    let mut uri = format!("/sync?access_token={}", user.access_token);
+    if let Some(since) = since {
+        uri.push_str(&format!("&since={}", since));
+    }
+    let (status, sync) = app.get(&uri).await;
+    assert_eq!(status, StatusCode::OK);
+    sync
+}
+
+async fn invite(app: &TestApp, from: &TestUser, to: &TestUser, room_id: &str) {
+    let body = json!({ "access_token": from.access_token, "room_id": room_id, "user_id": to.user_id });
+    assert_eq!(app.post("/rooms/invite", body).await.0, StatusCode::OK);
+}
+
+fn membership(app: &TestApp, room_id: &str, user: &TestUser) -> Option<String> {
+    app.homeserver.inspect(|hs| hs.rooms[room_id].membership(&user.user_id).map(String::from))
+}
+
+#[tokio::test]
+async fn an_invite_shows_up_once_and_can_be_accepted() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, server) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Alice's Server", "is_space": true }))
+        .await;
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+
+    let before = sync(&app, &bob, None).await;
+    assert_eq!(before["invites"], json!([]));
+
+    invite(&app, &alice, &bob, &server_id).await;
+    let since = before["next_batch"].as_str().unwrap();
+    let sync_with_invite = sync(&app, &bob, Some(since)).await;
+    assert_eq!(sync_with_invite["invites"], json!([{
+        "room_id": server_id,
+        "inviter": alice.user_id,
+        "name": "Alice's Server",
+        "avatar_url": null,
+        "is_direct": false,
+        "is_space": true,
+    }]));
+    assert_eq!(sync_with_invite["left_rooms"], json!([]));
+
+    // already told
+    let since = sync_with_invite["next_batch"].as_str().unwrap();
+    assert_eq!(sync(&app, &bob, Some(since)).await["invites"], json!([]));
+
+    let body = json!({ "access_token": bob.access_token, "room_id": server_id });
+    let (status, joined) = app.post("/rooms/invite/accept", body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(joined["room_id"], server_id.as_str());
+    assert_eq!(membership(&app, &server_id, &bob).as_deref(), Some("join"));
+}
+
+#[tokio::test]
+async fn rejecting_or_being_kicked_is_a_left_room() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let create = |name: &str| app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": name }));
+    let (_, declined) = create("declined").await;
+    let declined = declined["room_id"].as_str().unwrap().to_string();
+    let (_, kicked) = create("kicked").await;
+    let kicked = kicked["room_id"].as_str().unwrap().to_string();
+
+    invite(&app, &alice, &bob, &declined).await;
+    invite(&app, &alice, &bob, &kicked).await;
+    app.post("/rooms/invite/accept", json!({ "access_token": bob.access_token, "room_id": kicked })).await;
+    let since = sync(&app, &bob, None).await["next_batch"].as_str().unwrap().to_string();
+
+    let body = json!({ "access_token": bob.access_token, "room_id": declined });
+    assert_eq!(app.post("/rooms/invite/reject", body).await.0, StatusCode::OK);
+    assert_eq!(membership(&app, &declined, &bob).as_deref(), Some("leave"));
+    let body = json!({ "access_token": alice.access_token, "server_id": kicked, "user_id": bob.user_id });
+    assert_eq!(app.post("/servers/members/kick", body).await.0, StatusCode::OK);
+
+    let after = sync(&app, &bob, Some(&since)).await;
+    let mut expected = vec![declined.clone(), kicked.clone()];
+    expected.sort();
+    assert_eq!(after["left_rooms"], json!(expected));
+    assert_eq!(after["invites"], json!([]));
+
+    // nothing to reject any more
+    let body = json!({ "access_token": bob.access_token, "room_id": declined });
+    assert_eq!(app.post("/rooms/invite/reject", body).await.0, StatusCode::FORBIDDEN);
+}
+
+#[sqlx::test]
+async fn a_new_dm_arrives_as_a_direct_invite(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let friends = |path: &'static str, user: &TestUser, other: &TestUser| {
+        app.post(path, json!({ "access_token": user.access_token, "user_id": user.user_id, "friend_id": other.user_id }))
+    };
+    friends("/friends/add", &alice, &bob).await;
+    friends("/friends/accept", &bob, &alice).await;
+    let since = sync(&app, &bob, None).await["next_batch"].as_str().unwrap().to_string();
+
+    let (status, dm) = friends("/friends/dm", &alice, &bob).await;
+    assert_eq!(status, StatusCode::OK, "{}", dm);
+    let invites = sync(&app, &bob, Some(&since)).await["invites"].clone();
+    assert_eq!(invites[0]["room_id"], dm["room_id"]);
+    assert_eq!(invites[0]["inviter"], alice.user_id.as_str());
+    assert_eq!(invites[0]["is_direct"], true);
+    assert_eq!(invites[0]["is_space"], false);
+}
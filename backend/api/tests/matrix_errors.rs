@@ -0,0 +1,115 @@
+// homeserver errors are parsed into errcodes and mapped to statuses the
+// client can act on
+
+mod common;
+
+use agora_api::matrix::client::{MatrixClient, MatrixError};
+use agora_api::matrix::retry::RetryPolicy;
+use axum::http::StatusCode;
+use common::TestApp;
+use serde_json::json;
+use wiremock::matchers::any;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// a client that reports the first failure instead of retrying it
+fn client(server: &MockServer) -> MatrixClient {
+    MatrixClient::new(server.uri()).with_retry(RetryPolicy { max_retries: 0, ..RetryPolicy::default() })
+}
+
+/// a homeserver that answers everything with `response`
+async fn homeserver_answering(response: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(any()).respond_with(response).mount(&server).await;
+    server
+}
+
+#[tokio::test]
+async fn error_bodies_are_parsed() {
+    let server = homeserver_answering(ResponseTemplate::new(429).set_body_json(json!({
+        "errcode": "M_LIMIT_EXCEEDED",
+        "error": "too many requests",
+        "retry_after_ms": 1500,
+    })))
+    .await;
+    let mut matrix = client(&server);
+    matrix.access_token = Some("token".to_string());
+
+    let err = matrix.whoami().await.unwrap_err();
+    assert!(err.is_rate_limited());
+    assert_eq!(err.errcode(), Some("M_LIMIT_EXCEEDED"));
+    assert_eq!(err.status(), Some(429));
+    assert_eq!(err.retry_after_ms(), Some(1500));
+    assert!(!err.is_forbidden());
+}
+
+#[tokio::test]
+async fn retry_after_header_is_used_without_a_body_hint() {
+    let server = homeserver_answering(
+        ResponseTemplate::new(429)
+            .insert_header("Retry-After", "3")
+            .set_body_json(json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "slow down" })),
+    )
+    .await;
+    let mut matrix = client(&server);
+    matrix.access_token = Some("token".to_string());
+
+    assert_eq!(matrix.whoami().await.unwrap_err().retry_after_ms(), Some(3000));
+}
+
+#[tokio::test]
+async fn non_matrix_bodies_keep_the_raw_text() {
+    let server = homeserver_answering(ResponseTemplate::new(502).set_body_string("<html>bad gateway</html>")).await;
+    let mut matrix = client(&server);
+    matrix.access_token = Some("token".to_string());
+
+    match matrix.whoami().await.unwrap_err() {
+        MatrixError::ApiError(body) => assert!(body.contains("bad gateway")),
+        other => panic!("expected the raw body, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn rate_limits_are_passed_on_with_retry_after() {
+    let server = homeserver_answering(ResponseTemplate::new(429).set_body_json(json!({
+        "errcode": "M_LIMIT_EXCEEDED",
+        "error": "too many login attempts",
+        "retry_after_ms": 2500,
+    })))
+    .await;
+    let app = TestApp::with_config(|state| state.homeserver_url = server.uri()).await;
+
+    let request = axum::http::Request::post("/login")
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from(json!({ "username": "alice", "password": "x" }).to_string()))
+        .unwrap();
+    let response = tower::ServiceExt::oneshot(app.router.clone(), request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "3");
+}
+
+#[tokio::test]
+async fn a_dead_token_is_unauthorized_on_sync() {
+    let app = TestApp::new().await;
+    let (status, body) = app.get("/sync?access_token=revoked").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["errcode"], "M_UNKNOWN_TOKEN");
+}
+
+#[tokio::test]
+async fn leaving_a_room_twice_succeeds() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": user.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap();
+
+    for _ in 0..2 {
+        let (status, _) = app.post("/rooms/leave", json!({ "access_token": user.access_token, "room_id": room_id })).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    // but a room that doesn't exist is still an error
+    let (status, _) = app
+        .post("/rooms/leave", json!({ "access_token": user.access_token, "room_id": "!nope:localhost" }))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,122 @@
+// /media/upload against the homeserver's limit: the declared length checked
+// before anything is sent, the homeserver's own refusals passed through, and
+// the cached limit picked up again once it's stale
+
+mod common;
+
+use agora_api::media::MediaLimitCache;
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use common::homeserver::UPLOAD_LIMIT;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tower::ServiceExt;
+use wiremock::matchers::{method, path_regex};
+use wiremock::{Mock, ResponseTemplate};
+
+/// upload `bytes`, declaring `content_length` when given
+async fn upload(app: &TestApp, user: &TestUser, bytes: Vec<u8>, content_length: Option<u64>) -> (StatusCode, Value) {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/media/upload?access_token={}&filename=notes.txt", user.access_token))
+        .header("content-type", "text/plain");
+    if let Some(length) = content_length {
+        request = request.header("content-length", length);
+    }
+    let response = app.router.clone().oneshot(request.body(Body::from(bytes)).unwrap()).await.unwrap();
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+/// how many requests the homeserver got whose path ends in `suffix`
+async fn requests_to(app: &TestApp, suffix: &str) -> usize {
+    let requests = app.homeserver.server.received_requests().await.unwrap();
+    requests.iter().filter(|r| r.url.path().ends_with(suffix)).count()
+}
+
+fn refusal(status: u16, errcode: &str) -> ResponseTemplate {
+    ResponseTemplate::new(status).set_body_json(json!({ "errcode": errcode, "error": "no" }))
+}
+
+#[tokio::test]
+async fn too_large_uploads_never_reach_the_homeserver() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let (status, body) = upload(&app, &alice, b"hello".to_vec(), Some(5)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body["content_uri"].as_str().unwrap().starts_with("mxc://localhost/"));
+    assert_eq!(requests_to(&app, "/upload").await, 1);
+
+    // a declared length over the limit is refused before the body is read
+    let (status, body) = upload(&app, &alice, b"small really".to_vec(), Some(UPLOAD_LIMIT + 1)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body, json!({ "errcode": "M_TOO_LARGE", "error": "file exceeds the homeserver upload limit", "max_upload_size": UPLOAD_LIMIT }));
+    // and a body that turns out bigger than it said is cut off at the limit
+    let (status, _) = upload(&app, &alice, vec![b'x'; UPLOAD_LIMIT as usize + 1], None).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(requests_to(&app, "/upload").await, 1);
+}
+
+#[tokio::test]
+async fn the_homeservers_refusals_pass_through() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    Mock::given(method("POST"))
+        .and(path_regex("/upload$"))
+        .respond_with(refusal(413, "M_TOO_LARGE"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&app.homeserver.server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex("/upload$"))
+        .respond_with(refusal(403, "M_FORBIDDEN"))
+        .up_to_n_times(1)
+        .with_priority(2)
+        .mount(&app.homeserver.server)
+        .await;
+
+    let (status, body) = upload(&app, &alice, b"hello".to_vec(), None).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!((&body["errcode"], &body["max_upload_size"]), (&json!("M_TOO_LARGE"), &json!(UPLOAD_LIMIT)));
+    assert_eq!(requests_to(&app, "/config").await, 1);
+
+    let (status, body) = upload(&app, &alice, b"hello".to_vec(), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+    // the 413 meant our idea of the limit was off, so it was asked for again
+    assert_eq!(requests_to(&app, "/config").await, 2);
+
+    assert_eq!(upload(&app, &alice, b"hello".to_vec(), None).await.0, StatusCode::OK);
+    assert_eq!(requests_to(&app, "/config").await, 2);
+}
+
+#[tokio::test]
+async fn the_limit_is_fetched_again_once_it_expires() {
+    let app = TestApp::with_config(|state| state.media_limit = MediaLimitCache::with_ttl(Duration::from_millis(200))).await;
+    let alice = app.register("alice").await;
+    let two_kb = vec![b'x'; 2048];
+
+    assert_eq!(upload(&app, &alice, two_kb.clone(), None).await.0, StatusCode::OK);
+    assert_eq!(upload(&app, &alice, two_kb.clone(), None).await.0, StatusCode::OK);
+    assert_eq!(requests_to(&app, "/config").await, 1);
+
+    // the homeserver lowers its limit; the cached one holds until it expires
+    Mock::given(method("GET"))
+        .and(path_regex("/config$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "m.upload.size": 1024 })))
+        .with_priority(1)
+        .mount(&app.homeserver.server)
+        .await;
+    assert_eq!(upload(&app, &alice, two_kb.clone(), Some(2048)).await.0, StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    let (status, body) = upload(&app, &alice, two_kb, Some(2048)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["max_upload_size"], 1024);
+    assert_eq!(requests_to(&app, "/config").await, 2);
+    assert_eq!(requests_to(&app, "/upload").await, 3);
+}
@@ -0,0 +1,165 @@
+// edit and delete windows from agora.server.settings: members inside them,
+// members past them, moderators who aren't held to them, and rooms outside
+// any server that have none
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a server with one channel, `members` joined, and the given windows
+async fn server(app: &TestApp, owner: &TestUser, members: &[&TestUser], edit: u64, delete: u64) -> (String, String) {
+    let (_, space) = app.post("/rooms/create", json!({ "access_token": owner.access_token, "name": "Crew", "is_space": true })).await;
+    let server_id = space["room_id"].as_str().unwrap().to_string();
+    let (_, channel) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": "general", "parent_space_id": server_id }))
+        .await;
+    let channel_id = channel["room_id"].as_str().unwrap().to_string();
+    for member in members {
+        app.post("/rooms/invite", json!({ "access_token": owner.access_token, "room_id": server_id, "user_id": member.user_id })).await;
+        app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": server_id })).await;
+    }
+    let settings = json!({ "access_token": owner.access_token, "server_id": server_id, "edit_window_minutes": edit, "delete_window_minutes": delete });
+    let (status, body) = app.post("/servers/settings", settings).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    (server_id, channel_id)
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, body: &str) -> String {
+    let (status, sent) = app.post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": body })).await;
+    assert_eq!(status, StatusCode::OK, "{}", sent);
+    sent["event_id"].as_str().unwrap().to_string()
+}
+
+/// pretend a message was sent `minutes` earlier than it was; returns its new timestamp
+fn age(app: &TestApp, event_id: &str, minutes: i64) -> i64 {
+    let mut hs = app.homeserver.state.lock().unwrap();
+    let (_, event) = hs.timeline.iter_mut().find(|(_, e)| e["event_id"] == event_id).unwrap();
+    let ts = event["origin_server_ts"].as_i64().unwrap() - minutes * 60_000;
+    event["origin_server_ts"] = json!(ts);
+    ts
+}
+
+async fn edit(app: &TestApp, user: &TestUser, room_id: &str, event_id: &str) -> (StatusCode, Value) {
+    app.post("/rooms/edit", json!({ "access_token": user.access_token, "room_id": room_id, "event_id": event_id, "content": "edited" }))
+        .await
+}
+
+async fn delete(app: &TestApp, user: &TestUser, room_id: &str, event_id: &str) -> (StatusCode, Value) {
+    app.post("/rooms/redact", json!({ "access_token": user.access_token, "room_id": room_id, "event_id": event_id })).await
+}
+
+fn assert_expired((status, body): (StatusCode, Value), cutoff: i64) {
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(body["errcode"], "AGORA_WINDOW_EXPIRED");
+    assert_eq!(body["cutoff"], cutoff);
+}
+
+/// (editable, deletable) of a message as `user` sees it in /sync/room
+async fn buttons(app: &TestApp, user: &TestUser, room_id: &str, event_id: &str) -> (Value, Value) {
+    let (_, sync) = app.get(&format!("/sync/room?access_token={}&room_id={}", user.access_token, enc(room_id))).await;
+    let message = sync["messages"].as_array().unwrap().iter().find(|m| m["event_id"] == event_id).unwrap().clone();
+    (message["editable"].clone(), message["deletable"].clone())
+}
+
+#[tokio::test]
+async fn members_are_held_to_the_windows() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, channel_id) = server(&app, &alice, &[&bob], 5, 10).await;
+
+    let fresh = send(&app, &bob, &channel_id, "just now").await;
+    assert_eq!(buttons(&app, &bob, &channel_id, &fresh).await, (json!(true), json!(true)));
+    assert_eq!(edit(&app, &bob, &channel_id, &fresh).await.0, StatusCode::OK);
+
+    // past the edit window, still inside the delete window
+    let older = send(&app, &bob, &channel_id, "a while ago").await;
+    let sent_at = age(&app, &older, 7);
+    assert_eq!(buttons(&app, &bob, &channel_id, &older).await, (json!(false), json!(true)));
+    assert_expired(edit(&app, &bob, &channel_id, &older).await, sent_at + 5 * 60_000);
+
+    let oldest = send(&app, &bob, &channel_id, "ages ago").await;
+    let sent_at = age(&app, &oldest, 11);
+    assert_eq!(buttons(&app, &bob, &channel_id, &oldest).await, (json!(false), json!(false)));
+    assert_expired(delete(&app, &bob, &channel_id, &oldest).await, sent_at + 10 * 60_000);
+    assert_eq!(delete(&app, &bob, &channel_id, &older).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn moderators_skip_the_windows() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let (server_id, channel_id) = server(&app, &alice, &[&bob, &carol], 5, 5).await;
+    // carol's only power is a role with manage_messages
+    let cleaners = json!({
+        "id": "cleaners", "name": "cleaners", "color": "#5865f2", "hoist": false, "mentionable": false,
+        "permissions": {
+            "send_messages": true, "manage_channels": false, "manage_roles": false, "kick_members": false,
+            "ban_members": false, "mention_everyone": false, "manage_server": false, "administrator": false,
+            "manage_messages": true,
+        },
+        "power_level": 0,
+        "position": 0,
+    });
+    let roles = json!({ "access_token": alice.access_token, "server_id": server_id, "roles": [cleaners], "force": true });
+    assert_eq!(app.post("/servers/roles", roles).await.0, StatusCode::OK);
+    let assign = json!({ "access_token": alice.access_token, "server_id": server_id, "user_id": carol.user_id, "role_ids": ["cleaners"] });
+    assert_eq!(app.post("/servers/members/roles", assign).await.0, StatusCode::OK);
+
+    let own = send(&app, &alice, &channel_id, "owner's old message").await;
+    let bobs = send(&app, &bob, &channel_id, "bob's old message").await;
+    let carols = send(&app, &carol, &channel_id, "carol's old message").await;
+    for event_id in [&own, &bobs, &carols] {
+        age(&app, event_id, 60);
+    }
+
+    // redact power in the room
+    assert_eq!(buttons(&app, &alice, &channel_id, &own).await, (json!(true), json!(true)));
+    assert_eq!(edit(&app, &alice, &channel_id, &own).await.0, StatusCode::OK);
+    assert_eq!(delete(&app, &alice, &channel_id, &bobs).await.0, StatusCode::OK);
+    // the manage_messages role, which carries no power in the channel itself
+    assert_eq!(buttons(&app, &carol, &channel_id, &carols).await, (json!(true), json!(true)));
+    assert_eq!(edit(&app, &carol, &channel_id, &carols).await.0, StatusCode::OK);
+    assert_eq!(delete(&app, &carol, &channel_id, &carols).await.0, StatusCode::OK);
+    // bob has neither
+    let late = send(&app, &bob, &channel_id, "bob's late message").await;
+    age(&app, &late, 60);
+    assert_eq!(buttons(&app, &bob, &channel_id, &late).await, (json!(false), json!(false)));
+    // but nobody edits someone else's message
+    let fresh = send(&app, &bob, &channel_id, "mine").await;
+    assert_eq!(edit(&app, &alice, &channel_id, &fresh).await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn rooms_outside_a_server_have_no_windows() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "notes" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+
+    let message = send(&app, &alice, &room_id, "from last year").await;
+    age(&app, &message, 365 * 24 * 60);
+    assert_eq!(buttons(&app, &alice, &room_id, &message).await, (json!(true), json!(true)));
+    assert_eq!(edit(&app, &alice, &room_id, &message).await.0, StatusCode::OK);
+    assert_eq!(delete(&app, &alice, &room_id, &message).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn an_unlimited_window_is_zero_and_windows_are_capped() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, channel_id) = server(&app, &alice, &[&bob], 0, 0).await;
+    let message = send(&app, &bob, &channel_id, "old but fine").await;
+    age(&app, &message, 365 * 24 * 60);
+    assert_eq!(edit(&app, &bob, &channel_id, &message).await.0, StatusCode::OK);
+
+    let too_long = json!({ "access_token": alice.access_token, "server_id": server_id, "edit_window_minutes": u32::MAX });
+    assert_eq!(app.post("/servers/settings", too_long).await.0, StatusCode::BAD_REQUEST);
+    let (_, settings) = app.get(&format!("/servers/settings?access_token={}&server_id={}", bob.access_token, enc(&server_id))).await;
+    assert_eq!(settings, json!({ "edit_window_minutes": 0, "delete_window_minutes": 0 }));
+}
@@ -0,0 +1,344 @@
+// channel history outside the /sync loop
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn channel(app: &TestApp, owner: &TestUser) -> String {
+    let (status, room) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": "general" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, content: &str) {
+    let (status, _) = app
+        .post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": content }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+fn bodies(page: &Value) -> Vec<&str> {
+    page["messages"].as_array().unwrap().iter().map(|m| m["body"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn history_pages_backwards_until_the_start_of_the_room() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice).await;
+    for i in 1..=5 {
+        send(&app, &alice, &room_id, &format!("message {}", i)).await;
+    }
+
+    let url = format!("/rooms/messages?access_token={}&room_id={}&limit=3", alice.access_token, enc(&room_id));
+    let (status, page) = app.get(&url).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bodies(&page), ["message 5", "message 4", "message 3"]);
+    let message = &page["messages"][0];
+    assert_eq!(message["sender"], alice.user_id.as_str());
+    assert_eq!(message["msgtype"], "m.text");
+    assert!(message["event_id"].is_string());
+    assert!(message["origin_server_ts"].is_i64());
+    assert!(message.get("content").is_none());
+
+    let end = page["end"].as_str().expect("more history to load");
+    let (status, page) = app.get(&format!("{}&from={}", url, enc(end))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bodies(&page), ["message 2", "message 1"]);
+    assert!(page.get("end").is_none());
+
+    // and forwards again from the oldest message
+    let (_, page) = app.get(&format!("{}&dir=f", url)).await;
+    assert_eq!(bodies(&page), ["message 1", "message 2", "message 3"]);
+}
+
+#[tokio::test]
+async fn agora_messages_keep_their_content() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice).await;
+
+    let (status, _) = app
+        .post("/rooms/raid", json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "raider_id": alice.user_id,
+            "raider_name": "alice",
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, page) = app
+        .get(&format!("/rooms/messages?access_token={}&room_id={}", alice.access_token, enc(&room_id)))
+        .await;
+    let raid = &page["messages"][0];
+    assert_eq!(raid["msgtype"], "agora.raid");
+    assert_eq!(raid["body"], "[raid] alice is raiding!");
+    assert_eq!(raid["content"]["raider_name"], "alice");
+    assert_eq!(raid["content"]["countdown"], 5);
+}
+
+#[tokio::test]
+async fn history_needs_membership() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = channel(&app, &alice).await;
+    send(&app, &alice, &room_id, "members only").await;
+
+    let (status, body) = app
+        .get(&format!("/rooms/messages?access_token={}&room_id={}", bob.access_token, enc(&room_id)))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+}
+
+#[tokio::test]
+async fn deleted_messages_are_reported_by_sync() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = channel(&app, &alice).await;
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let message_id = |page: &Value, body: &str| {
+        page["messages"].as_array().unwrap().iter().find(|m| m["body"] == body).unwrap()["event_id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    send(&app, &alice, &room_id, "from alice").await;
+    send(&app, &bob, &room_id, "from bob").await;
+    let (_, page) = app
+        .get(&format!("/rooms/messages?access_token={}&room_id={}", bob.access_token, enc(&room_id)))
+        .await;
+    let (alice_message, bob_message) = (message_id(&page, "from alice"), message_id(&page, "from bob"));
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", bob.access_token)).await;
+    let since = sync["next_batch"].as_str().unwrap().to_string();
+
+    let redact = |user: &TestUser, event_id: &str| {
+        json!({ "access_token": user.access_token, "room_id": room_id, "event_id": event_id })
+    };
+    // bob can't delete alice's message, alice (the room admin) can delete bob's
+    let (status, _) = app.post("/rooms/redact", redact(&bob, &alice_message)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = app.post("/rooms/redact", redact(&alice, &bob_message)).await;
+    assert_eq!(status, StatusCode::OK);
+    // and everyone can delete their own
+    let (status, _) = app.post("/rooms/redact", redact(&alice, &alice_message)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, sync) = app.get(&format!("/sync?access_token={}&since={}", bob.access_token, since)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sync["messages"].as_array().unwrap().len(), 0);
+    assert_eq!(
+        sync["redacted"],
+        json!([
+            { "room_id": room_id, "event_id": bob_message },
+            { "room_id": room_id, "event_id": alice_message },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn reactions_are_counted_per_emoji() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = channel(&app, &alice).await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id })).await;
+    send(&app, &alice, &room_id, "ship it").await;
+    let (_, page) = app
+        .get(&format!("/rooms/messages?access_token={}&room_id={}", alice.access_token, enc(&room_id)))
+        .await;
+    let message = page["messages"][0]["event_id"].as_str().unwrap().to_string();
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    let since = sync["next_batch"].as_str().unwrap().to_string();
+
+    let react = |user: &TestUser, key: &str| {
+        json!({ "access_token": user.access_token, "room_id": room_id, "event_id": message, "key": key })
+    };
+    let (status, first) = app.post("/rooms/react", react(&alice, "🚀")).await;
+    assert_eq!(status, StatusCode::OK);
+    // the same reaction again is the same event
+    let (_, again) = app.post("/rooms/react", react(&alice, "🚀")).await;
+    assert_eq!(again["event_id"], first["event_id"]);
+    app.post("/rooms/react", react(&bob, "🚀")).await;
+    app.post("/rooms/react", react(&bob, "👀")).await;
+
+    let reactions_url = format!(
+        "/rooms/reactions?access_token={}&room_id={}&event_id={}",
+        bob.access_token,
+        enc(&room_id),
+        enc(&message)
+    );
+    let (status, body) = app.get(&reactions_url).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body["reactions"],
+        json!([
+            { "key": "🚀", "count": 2, "reacted": true },
+            { "key": "👀", "count": 1, "reacted": true },
+        ])
+    );
+
+    // live clients see the reactions arrive
+    let (_, sync) = app.get(&format!("/sync?access_token={}&since={}", alice.access_token, since)).await;
+    let reactions = sync["reactions"].as_array().unwrap();
+    assert_eq!(reactions.len(), 3);
+    assert_eq!(reactions[0]["target_event_id"], message.as_str());
+    assert_eq!(reactions[0]["key"], "🚀");
+    assert_eq!(reactions[0]["sender"], alice.user_id.as_str());
+    assert_eq!(reactions[0]["event_id"], first["event_id"]);
+    let since = sync["next_batch"].as_str().unwrap().to_string();
+
+    let (status, _) = app.post("/rooms/unreact", react(&bob, "🚀")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = app.get(&reactions_url).await;
+    assert_eq!(
+        body["reactions"],
+        json!([
+            { "key": "🚀", "count": 1, "reacted": false },
+            { "key": "👀", "count": 1, "reacted": true },
+        ])
+    );
+    let (_, sync) = app.get(&format!("/sync?access_token={}&since={}", alice.access_token, since)).await;
+    assert_eq!(sync["redacted"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn attachments_carry_their_url_and_info() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice).await;
+
+    let attachment = |msgtype: &str, url: &str| {
+        json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "content": "cat.png",
+            "msgtype": msgtype,
+            "url": url,
+            "info": { "mimetype": "image/png", "size": 2048, "w": 640, "h": 480 },
+        })
+    };
+    let (status, _) = app.post("/rooms/send", attachment("m.image", "mxc://localhost/abc123")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for (msgtype, url) in [("m.image", "https://example.org/cat.png"), ("m.image", "mxc://localhost"), ("m.sticker", "mxc://localhost/abc123")] {
+        let (status, body) = app.post("/rooms/send", attachment(msgtype, url)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", msgtype, url);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+    send(&app, &alice, &room_id, "nice cat").await;
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    let messages = sync["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["msgtype"], "m.image");
+    assert_eq!(messages[0]["content"], "cat.png");
+    assert_eq!(messages[0]["url"], "mxc://localhost/abc123");
+    assert_eq!(messages[0]["info"], json!({ "mimetype": "image/png", "size": 2048, "w": 640, "h": 480 }));
+    assert_eq!(messages[1]["msgtype"], "m.text");
+    assert!(messages[1].get("url").is_none());
+
+    let (_, page) = app
+        .get(&format!("/rooms/messages?access_token={}&room_id={}", alice.access_token, enc(&room_id)))
+        .await;
+    assert_eq!(page["messages"][1]["url"], "mxc://localhost/abc123");
+    assert_eq!(page["messages"][1]["info"]["w"], 640);
+}
+
+#[tokio::test]
+async fn formatted_messages_round_trip_as_sanitized_html() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice).await;
+
+    let markdown = "**hi** <img src=x onerror=alert(1)>\n\n```\nlet x = 1;\n```";
+    let (status, _) = app
+        .post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": room_id, "content": markdown, "formatted": true }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    send(&app, &alice, &room_id, "plain").await;
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    let html = sync["messages"][0]["formatted_body"].as_str().unwrap();
+    assert!(html.contains("<strong>hi</strong>"), "{}", html);
+    assert!(html.contains("<pre><code>let x = 1;\n</code></pre>"), "{}", html);
+    assert!(!html.contains("onerror"), "{}", html);
+    assert_eq!(sync["messages"][0]["content"], markdown);
+    assert!(sync["messages"][1].get("formatted_body").is_none());
+}
+
+#[tokio::test]
+async fn mentions_ping_members_and_everyone_needs_permission() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, server) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Lounge", "is_space": true }))
+        .await;
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general", "parent_space_id": server_id }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id })).await;
+
+    send(&app, &alice, &room_id, "hey @bob").await;
+    let sent = app.homeserver.inspect(|hs| hs.timeline.last().unwrap().1["content"].clone());
+    assert_eq!(sent["m.mentions"]["user_ids"], json!([bob.user_id]));
+    assert_eq!(sent["formatted_body"], format!("hey <a href=\"https://matrix.to/#/{0}\">@bob</a>", bob.user_id));
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", bob.access_token)).await;
+    assert_eq!(sync["messages"][0]["mentions_me"], true);
+    let (_, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    assert_eq!(sync["messages"][0]["mentions_me"], false);
+
+    let everyone = |user: &TestUser| json!({ "access_token": user.access_token, "room_id": room_id, "content": "@everyone standup" });
+    let (status, body) = app.post("/rooms/send", everyone(&bob)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    // a role with mention_everyone lets bob do it
+    let (status, _) = app
+        .post("/servers/roles", json!({
+            "access_token": alice.access_token,
+            "server_id": server_id,
+            "revision": 0,
+            "roles": [{
+                "id": "announcer", "name": "Announcer", "color": "#5865f2", "hoist": false, "mentionable": true,
+                "permissions": {
+                    "send_messages": true, "manage_channels": false, "manage_roles": false, "kick_members": false,
+                    "ban_members": false, "mention_everyone": true, "manage_server": false, "administrator": false,
+                },
+                "power_level": 0,
+            }],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .post("/servers/members/roles", json!({
+            "access_token": alice.access_token, "server_id": server_id, "user_id": bob.user_id, "role_ids": ["announcer"],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    let since = sync["next_batch"].as_str().unwrap().to_string();
+    let (status, _) = app.post("/rooms/send", everyone(&bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, sync) = app.get(&format!("/sync?access_token={}&since={}", alice.access_token, since)).await;
+    assert_eq!(sync["messages"][0]["content"], "@everyone standup");
+    assert_eq!(sync["messages"][0]["mentions_me"], true);
+}
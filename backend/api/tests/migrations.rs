@@ -0,0 +1,90 @@
+// the migrations against a fresh database: what /health/migrations reports,
+// the use counting the invite and registration token tables enforce, and
+// --seed-dev run twice over
+
+mod common;
+
+use agora_api::seed;
+use axum::http::StatusCode;
+use common::{enc, TestApp};
+use serde_json::json;
+use sqlx::PgPool;
+
+#[sqlx::test]
+async fn the_database_is_on_the_newest_migration(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let (status, body) = app.get("/health/migrations").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["clean"], true);
+    assert_eq!(body["up_to_date"], true);
+    assert!(body["applied_version"].as_i64().unwrap() >= 16, "{}", body);
+    assert_eq!(body["applied_version"], body["latest_version"]);
+}
+
+#[sqlx::test]
+async fn an_invite_code_is_redeemed_up_to_its_limit(pool: PgPool) {
+    let app = TestApp::with_db(pool.clone()).await;
+    let alice = app.register("alice").await;
+    let (_, server) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Crew", "is_space": true }))
+        .await;
+    let server_id = server["room_id"].as_str().unwrap();
+    sqlx::query("INSERT INTO server_invites (code, server_id, created_by, max_uses) VALUES ('two-uses', $1, $2, 2)")
+        .bind(server_id)
+        .bind(&alice.user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // a redemption takes a use only while one is left
+    let redeem = || {
+        sqlx::query("UPDATE server_invites SET uses = uses + 1 WHERE code = 'two-uses' AND (max_uses IS NULL OR uses < max_uses)")
+            .execute(&pool)
+    };
+    let preview = format!("/servers/preview?code_or_alias={}", enc("two-uses"));
+    for _ in 0..2 {
+        assert_eq!(app.get(&preview).await.0, StatusCode::OK);
+        assert_eq!(redeem().await.unwrap().rows_affected(), 1);
+    }
+    assert_eq!(redeem().await.unwrap().rows_affected(), 0);
+    assert_eq!(app.get(&preview).await.0, StatusCode::NOT_FOUND);
+
+    // and the table itself won't count past the limit
+    let overused = sqlx::query("UPDATE server_invites SET uses = 3 WHERE code = 'two-uses'").execute(&pool).await;
+    assert!(overused.is_err());
+}
+
+#[sqlx::test]
+async fn a_registration_token_cant_be_spent_below_zero(pool: PgPool) {
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    sqlx::query("INSERT INTO registration_tokens (token, uses_remaining, created_by) VALUES ('crew', 1, '@root:localhost')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let spend = "UPDATE registration_tokens SET uses_remaining = uses_remaining - 1 WHERE token = 'crew'";
+    sqlx::query(spend).execute(&pool).await.unwrap();
+    assert!(sqlx::query(spend).execute(&pool).await.is_err());
+    let left: Option<i32> = sqlx::query_scalar("SELECT uses_remaining FROM registration_tokens WHERE token = 'crew'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(left, Some(0));
+}
+
+#[sqlx::test]
+async fn seeding_twice_changes_nothing(pool: PgPool) {
+    let app = TestApp::with_db(pool.clone()).await;
+    seed::seed_dev(&app.state).await.unwrap();
+    let rooms = app.homeserver.inspect(|hs| hs.rooms.len());
+    let users = app.homeserver.inspect(|hs| hs.users.len());
+
+    seed::seed_dev(&app.state).await.unwrap();
+    assert_eq!(app.homeserver.inspect(|hs| hs.rooms.len()), rooms);
+    assert_eq!(app.homeserver.inspect(|hs| hs.users.len()), users);
+    assert_eq!(users, 2);
+    let friendships: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM friends WHERE status = 'accepted'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(friendships, 1);
+}
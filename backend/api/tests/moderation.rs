@@ -0,0 +1,273 @@
+// server-wide kick / ban / unban across the space and its channels
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a server with one channel, owned by `owner` and joined by `member`
+async fn server(app: &TestApp, owner: &TestUser, member: &TestUser) -> (String, String) {
+    let (status, server) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": "Test Server", "is_space": true }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+
+    let (status, channel) = app
+        .post("/rooms/create", json!({
+            "access_token": owner.access_token,
+            "name": "general",
+            "parent_space_id": server_id,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let channel_id = channel["room_id"].as_str().unwrap().to_string();
+
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    (server_id, channel_id)
+}
+
+fn membership(app: &TestApp, room_id: &str, user: &TestUser) -> Option<String> {
+    app.homeserver.inspect(|hs| hs.rooms[room_id].membership(&user.user_id).map(str::to_string))
+}
+
+fn action(moderator: &TestUser, server_id: &str, target: &TestUser) -> Value {
+    json!({
+        "access_token": moderator.access_token,
+        "server_id": server_id,
+        "user_id": target.user_id,
+        "reason": "spam",
+    })
+}
+
+#[tokio::test]
+async fn kick_removes_the_member_from_every_channel() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, channel_id) = server(&app, &alice, &bob).await;
+
+    let (status, body) = app.post("/servers/members/kick", action(&alice, &server_id, &bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["complete"], true);
+    // channels first, the space last
+    let rooms: Vec<&str> = body["rooms"].as_array().unwrap().iter().filter_map(|r| r["room_id"].as_str()).collect();
+    assert_eq!(rooms, [channel_id.as_str(), server_id.as_str()]);
+
+    assert_eq!(membership(&app, &server_id, &bob).as_deref(), Some("leave"));
+    assert_eq!(membership(&app, &channel_id, &bob).as_deref(), Some("leave"));
+
+    // a kick isn't a ban — bob can come back
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn ban_is_listed_and_blocks_rejoining_until_unbanned() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, channel_id) = server(&app, &alice, &bob).await;
+
+    let (status, body) = app.post("/servers/members/ban", action(&alice, &server_id, &bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["complete"], true);
+    assert_eq!(membership(&app, &server_id, &bob).as_deref(), Some("ban"));
+    assert_eq!(membership(&app, &channel_id, &bob).as_deref(), Some("ban"));
+
+    let (status, bans) = app
+        .get(&format!("/servers/bans?access_token={}&server_id={}", alice.access_token, enc(&server_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bans["bans"].as_array().unwrap().len(), 1);
+    assert_eq!(bans["bans"][0]["user_id"], bob.user_id.as_str());
+    assert_eq!(bans["bans"][0]["banned_by"], alice.user_id.as_str());
+    assert_eq!(bans["bans"][0]["reason"], "spam");
+    assert!(bans["bans"][0]["banned_at"].as_u64().unwrap() > 0);
+
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": channel_id }))
+        .await;
+    assert_ne!(status, StatusCode::OK);
+
+    let (status, body) = app.post("/servers/members/unban", action(&alice, &server_id, &bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["complete"], true);
+    assert_eq!(membership(&app, &server_id, &bob).as_deref(), Some("leave"));
+    assert_eq!(membership(&app, &channel_id, &bob).as_deref(), Some("leave"));
+
+    let (_, bans) = app
+        .get(&format!("/servers/bans?access_token={}&server_id={}", alice.access_token, enc(&server_id)))
+        .await;
+    assert_eq!(bans["bans"], json!([]));
+
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn partial_failures_are_reported_per_room() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let (server_id, channel_id) = server(&app, &alice, &bob).await;
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": carol.access_token, "room_id_or_alias": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // carol moderates the space but not the channel
+    app.homeserver.state.lock().unwrap().rooms.get_mut(&server_id).unwrap().set_power(&carol.user_id, 50);
+
+    let (status, body) = app.post("/servers/members/kick", action(&carol, &server_id, &bob)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["complete"], false);
+    assert_eq!(body["rooms"][0]["room_id"], channel_id.as_str());
+    assert_eq!(body["rooms"][0]["ok"], false);
+    assert_eq!(body["rooms"][0]["errcode"], "M_FORBIDDEN");
+    assert_eq!(body["rooms"][1]["ok"], true);
+    assert_eq!(membership(&app, &channel_id, &bob).as_deref(), Some("join"));
+}
+
+#[tokio::test]
+async fn members_without_power_are_refused() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, channel_id) = server(&app, &alice, &bob).await;
+
+    let (status, body) = app.post("/servers/members/ban", action(&bob, &server_id, &alice)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+    assert_eq!(membership(&app, &channel_id, &alice).as_deref(), Some("join"));
+
+    let (_, bans) = app
+        .get(&format!("/servers/bans?access_token={}&server_id={}", alice.access_token, enc(&server_id)))
+        .await;
+    assert_eq!(bans["bans"], json!([]));
+}
+
+#[tokio::test]
+async fn bans_are_paged_and_only_shown_to_those_who_ban() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let (server_id, _) = server(&app, &alice, &bob).await;
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": carol.access_token, "room_id_or_alias": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let dave = app.register("dave").await;
+    for target in [&bob, &dave] {
+        assert_eq!(app.post("/servers/members/ban", action(&alice, &server_id, target)).await.0, StatusCode::OK);
+    }
+
+    let bans = |user: &TestUser, page: &str| {
+        format!("/servers/bans?access_token={}&server_id={}{}", user.access_token, enc(&server_id), page)
+    };
+    let (status, first) = app.get(&bans(&alice, "&limit=1")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["total"], 2);
+    assert_eq!(first["items"].as_array().unwrap().len(), 1);
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let (status, second) = app.get(&bans(&alice, &format!("&limit=1&after={}", cursor))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(second.get("next_cursor").is_none());
+    let mut banned = vec![first["items"][0]["user_id"].clone(), second["items"][0]["user_id"].clone()];
+    banned.sort_by_key(|id| id.to_string());
+    assert_eq!(banned, [json!(bob.user_id), json!(dave.user_id)]);
+    assert_eq!(app.get(&bans(&alice, "&limit=1&after=garbage")).await.0, StatusCode::BAD_REQUEST);
+
+    // carol is only a member
+    let (status, body) = app.get(&bans(&carol, "")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["permission"], "ban_members");
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str) -> (StatusCode, Value) {
+    app.post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": "hi" })).await
+}
+
+fn timeout(moderator: &TestUser, server_id: &str, target: &TestUser, duration_secs: u64) -> Value {
+    json!({
+        "access_token": moderator.access_token,
+        "server_id": server_id,
+        "user_id": target.user_id,
+        "duration_secs": duration_secs,
+        "reason": "cool off",
+    })
+}
+
+#[tokio::test]
+async fn a_timed_out_member_cannot_post_or_react_until_lifted() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, channel_id) = server(&app, &alice, &bob).await;
+    let (_, sent) = send(&app, &alice, &channel_id).await;
+    let event_id = sent["event_id"].as_str().unwrap().to_string();
+
+    let (status, body) = app.post("/servers/members/timeout", timeout(&alice, &server_id, &bob, 600)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let until = body["timed_out_until"].as_i64().unwrap();
+    let stored = app.homeserver.inspect(|hs| {
+        hs.rooms[&server_id].state[&("agora.member.timeout".to_string(), bob.user_id.clone())]["content"].clone()
+    });
+    assert_eq!(stored["until"], until);
+    assert_eq!(stored["reason"], "cool off");
+
+    let (status, body) = send(&app, &bob, &channel_id).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "AGORA_TIMED_OUT");
+    assert_eq!(body["timed_out_until"], until);
+    let react = json!({ "access_token": bob.access_token, "room_id": channel_id, "event_id": event_id, "key": "👍" });
+    let (status, body) = app.post("/rooms/react", react.clone()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["timed_out_until"], until);
+
+    let lift = json!({ "access_token": alice.access_token, "server_id": server_id, "user_id": bob.user_id });
+    assert_eq!(app.request(Method::DELETE, "/servers/members/timeout", Some(lift)).await.0, StatusCode::OK);
+    assert_eq!(send(&app, &bob, &channel_id).await.0, StatusCode::OK);
+    assert_eq!(app.post("/rooms/react", react).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_timeout_stops_applying_once_it_runs_out() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, channel_id) = server(&app, &alice, &bob).await;
+
+    assert_eq!(app.post("/servers/members/timeout", timeout(&alice, &server_id, &bob, 1)).await.0, StatusCode::OK);
+    assert_eq!(send(&app, &bob, &channel_id).await.0, StatusCode::FORBIDDEN);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(send(&app, &bob, &channel_id).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn timeouts_are_bounded_and_need_kick_members() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, _) = server(&app, &alice, &bob).await;
+
+    for duration in [0, 28 * 24 * 60 * 60 + 1] {
+        let (status, body) = app.post("/servers/members/timeout", timeout(&alice, &server_id, &bob, duration)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+
+    let (status, body) = app.post("/servers/members/timeout", timeout(&bob, &server_id, &alice, 60)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["permission"], "kick_members");
+}
@@ -0,0 +1,80 @@
+// mutual servers and friends for the profile popout. the friends half needs
+// postgres, like flows.rs.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn join(app: &TestApp, user: &TestUser, room_id: &str) {
+    let (status, _) = app.post("/rooms/join", json!({ "access_token": user.access_token, "room_id_or_alias": room_id })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn befriend(app: &TestApp, a: &TestUser, b: &TestUser) {
+    app.post("/friends/add", json!({ "access_token": a.access_token, "user_id": a.user_id, "friend_id": b.user_id })).await;
+    let (status, _) = app
+        .post("/friends/accept", json!({ "access_token": b.access_token, "user_id": b.user_id, "friend_id": a.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn mutual(app: &TestApp, user: &TestUser, other: &TestUser) -> (StatusCode, Value) {
+    app.get(&format!(
+        "/users/mutual?access_token={}&user_id={}&other_user_id={}",
+        user.access_token,
+        enc(&user.user_id),
+        enc(&other.user_id),
+    ))
+    .await
+}
+
+#[sqlx::test]
+async fn shared_spaces_and_friends(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let dave = app.register("dave").await;
+
+    let shared = create(&app, &alice, json!({ "name": "Shared Server", "is_space": true })).await;
+    join(&app, &bob, &shared).await;
+    create(&app, &alice, json!({ "name": "Alice Only", "is_space": true })).await;
+    // a plain room they're both in isn't a server
+    let general = create(&app, &alice, json!({ "name": "general" })).await;
+    join(&app, &bob, &general).await;
+
+    befriend(&app, &alice, &carol).await;
+    befriend(&app, &carol, &bob).await;
+    befriend(&app, &alice, &dave).await;
+
+    let (status, body) = mutual(&app, &alice, &bob).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body, json!({
+        "servers": [{ "room_id": shared, "name": "Shared Server", "avatar_url": null }],
+        "friends": [{ "user_id": carol.user_id, "display_name": "carol" }],
+    }));
+
+    let (_, body) = mutual(&app, &bob, &dave).await;
+    assert_eq!(body, json!({ "servers": [], "friends": [] }));
+}
+
+#[tokio::test]
+async fn needs_a_valid_token() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (status, _) = app
+        .get(&format!("/users/mutual?access_token=nope&user_id={}&other_user_id=%40bob%3Alocalhost", enc(&alice.user_id)))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,132 @@
+// per-room notification levels: stored for the user, inherited from spaces,
+// and followed by sync's notify flag and by web push
+
+mod common;
+
+use agora_api::push::{PushConfig, PushFanOut, StubPushes};
+use agora_api::service_account::{self, ServiceAccount};
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::Duration;
+
+async fn create(app: &TestApp, owner: &TestUser, path: &str, body: Value) -> String {
+    let (status, room) = app.authed(owner, Method::POST, path, Some(body)).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn set_level(app: &TestApp, user: &TestUser, room_id: &str, level: &str) -> (StatusCode, Value) {
+    app.authed(user, Method::PUT, "/rooms/notifications", Some(json!({ "room_id": room_id, "level": level }))).await
+}
+
+/// the notify flag sync gives each of `user`'s messages, by body
+async fn notify_flags(app: &TestApp, user: &TestUser) -> Vec<(String, bool)> {
+    let (status, sync) = app.authed(user, Method::GET, "/sync", None).await;
+    assert_eq!(status, StatusCode::OK, "{}", sync);
+    sync["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| (m["content"].as_str().unwrap().to_string(), m["notify"].as_bool().unwrap()))
+        .collect()
+}
+
+#[sqlx::test]
+async fn levels_are_stored_and_temporary_ones_run_out(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let room_id = create(&app, &alice, "/rooms/create", json!({ "name": "general" })).await;
+
+    let (status, body) = set_level(&app, &alice, &room_id, "muted").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["rooms"][&room_id], json!({ "level": "muted", "until": null }));
+    // other clients find it in account data
+    let key = (alice.user_id.clone(), format!("agora.notifications.room.{}", room_id));
+    let content = app.homeserver.inspect(|hs| hs.account_data.get(&key).cloned());
+    assert_eq!(content.unwrap()["level"], "muted");
+
+    let until = chrono::Utc::now().timestamp_millis() + 300;
+    let body = json!({ "room_id": room_id, "level": "mentions", "until": until });
+    assert_eq!(app.authed(&alice, Method::PUT, "/rooms/notifications", Some(body)).await.0, StatusCode::OK);
+    let (_, body) = app.authed(&alice, Method::GET, "/notifications/settings", None).await;
+    assert_eq!(body["rooms"][&room_id], json!({ "level": "mentions", "until": until }));
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let (_, body) = app.authed(&alice, Method::GET, "/notifications/settings", None).await;
+    assert_eq!(body["rooms"], json!({}));
+
+    let past = json!({ "room_id": room_id, "level": "muted", "until": 1 });
+    assert_eq!(app.authed(&alice, Method::PUT, "/rooms/notifications", Some(past)).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(set_level(&app, &alice, &room_id, "loud").await.0, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn a_muted_server_mutes_its_channels_unless_they_say_otherwise(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+    let general = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": category_id })).await;
+    let memes = create(&app, &alice, "/rooms/create", json!({ "name": "memes", "parent_space_id": server_id })).await;
+    let elsewhere = create(&app, &alice, "/rooms/create", json!({ "name": "elsewhere" })).await;
+    for (room_id, content) in [(&general, "in general"), (&memes, "in memes"), (&elsewhere, "elsewhere")] {
+        let message = json!({ "room_id": room_id, "content": content });
+        assert_eq!(app.authed(&alice, Method::POST, "/rooms/send", Some(message)).await.0, StatusCode::OK);
+    }
+
+    set_level(&app, &alice, &server_id, "muted").await;
+    set_level(&app, &alice, &memes, "all").await;
+    let mut flags = notify_flags(&app, &alice).await;
+    flags.sort();
+    assert_eq!(flags, [("elsewhere".to_string(), true), ("in general".to_string(), false), ("in memes".to_string(), true)]);
+
+    // back to inheriting
+    set_level(&app, &alice, &memes, "default").await;
+    let flags = notify_flags(&app, &alice).await;
+    assert!(flags.iter().any(|flag| *flag == ("in memes".to_string(), false)), "{:?}", flags);
+}
+
+#[sqlx::test]
+async fn muted_and_mentions_only_rooms_hold_back_pushes(pool: PgPool) {
+    let pushes = StubPushes::default();
+    let stub = pushes.clone();
+    let app = TestApp::with_config(|state| {
+        state.db_pool = Some(pool);
+        state.push = Some(PushConfig::stub(stub));
+    })
+    .await;
+    app.state.sync_observers.register(PushFanOut);
+    // pushes go out from the service account's sync; the room brings it in
+    let bot = ServiceAccount { username: "agora".to_string(), password: "hunter2".to_string() };
+    bot.sign_in(&app.state).await.unwrap();
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = create(&app, &alice, "/rooms/create", json!({ "name": "general" })).await;
+    let invite = json!({ "room_id": room_id, "user_id": bob.user_id });
+    app.authed(&alice, Method::POST, "/rooms/invite", Some(invite)).await;
+    app.authed(&bob, Method::POST, "/rooms/invite/accept", Some(json!({ "room_id": room_id }))).await;
+    let subscription = json!({ "endpoint": "https://push.example/bob", "keys": { "p256dh": "key", "auth": "secret" } });
+    app.authed(&bob, Method::POST, "/notifications/subscribe", Some(subscription)).await;
+    let mut since = service_account::sync_once(&app.state, None).await.unwrap();
+
+    let send = |content: &str| {
+        app.authed(&alice, Method::POST, "/rooms/send", Some(json!({ "room_id": room_id, "content": content })))
+    };
+    set_level(&app, &bob, &room_id, "muted").await;
+    send("@bob muted").await;
+    since = service_account::sync_once(&app.state, since).await.unwrap();
+    set_level(&app, &bob, &room_id, "mentions").await;
+    send("@bob mentions").await;
+    service_account::sync_once(&app.state, since).await.unwrap();
+
+    // delivery runs on its own tasks; the muted one would have gone out first
+    for _ in 0..100 {
+        if !pushes.sent.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let snippets: Vec<String> = pushes.sent.lock().unwrap().iter().map(|(_, p)| p.snippet.clone()).collect();
+    assert_eq!(snippets, ["@bob mentions"]);
+}
@@ -0,0 +1,230 @@
+// presence: heartbeats (quiet users go idle, expired ones are announced
+// offline), the snapshot a websocket starts with, and per-connection filters
+
+mod common;
+
+use agora_api::app_state::{ConnectionQueue, PresenceEvent, QueueItem, WsEvent};
+use agora_api::routes::presence_ws::presence_snapshot;
+use agora_api::routes::users::sweep_presence;
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+const IDLE_AFTER: u64 = 150;
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+async fn set_presence(app: &TestApp, user: &TestUser, presence: &str) {
+    let body = json!({ "access_token": user.access_token, "user_id": user.user_id, "presence": presence });
+    assert_eq!(app.post("/presence/set", body).await.0, StatusCode::OK);
+}
+
+async fn heartbeat(app: &TestApp, user: &TestUser) {
+    let body = json!({ "access_token": user.access_token, "user_id": user.user_id });
+    assert_eq!(app.post("/presence/heartbeat", body).await.0, StatusCode::OK);
+}
+
+async fn presence(app: &TestApp, user: &TestUser) -> Value {
+    let (_, body) = app
+        .get(&format!("/presence/get?access_token={}&user_id={}", user.access_token, enc(&user.user_id)))
+        .await;
+    body
+}
+
+async fn next_event(queue: &ConnectionQueue) -> Value {
+    match queue.pop().await {
+        QueueItem::Event(event @ WsEvent::Presence(_)) => serde_json::to_value(event).unwrap(),
+        _ => panic!("expected a presence event"),
+    }
+}
+
+#[tokio::test]
+async fn quiet_users_go_idle_and_heartbeats_bring_them_back() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    set_presence(&app, &alice, "online").await;
+    set_presence(&app, &bob, "unavailable").await;
+    let (_, queue) = app.state.register_connection(16);
+
+    assert_eq!(sweep_presence(&app.state, now(), IDLE_AFTER).await, 0);
+    assert_eq!(presence(&app, &alice).await["last_active_ago"].as_i64().map(|ms| ms < 5000), Some(true));
+
+    // only the online user goes idle — a chosen status stays
+    assert_eq!(sweep_presence(&app.state, now() + IDLE_AFTER, IDLE_AFTER).await, 1);
+    assert_eq!(next_event(&queue).await, json!({ "user_id": alice.user_id, "presence": "idle" }));
+    assert_eq!(presence(&app, &alice).await["presence"], "idle");
+    assert_eq!(presence(&app, &bob).await["presence"], "unavailable");
+
+    heartbeat(&app, &alice).await;
+    assert_eq!(next_event(&queue).await, json!({ "user_id": alice.user_id, "presence": "online" }));
+    assert_eq!(presence(&app, &alice).await["presence"], "online");
+
+    // a heartbeat keeps a chosen status, and says nothing
+    heartbeat(&app, &bob).await;
+    assert_eq!(presence(&app, &bob).await["presence"], "unavailable");
+    assert_eq!(queue.depth(), 0);
+}
+
+#[tokio::test]
+async fn expired_presence_is_announced_once() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    set_presence(&app, &alice, "online").await;
+    let (_, queue) = app.state.register_connection(16);
+
+    // what redis does when the ttl runs out
+    app.redis.lock().unwrap().remove(&format!("presence:{}", alice.user_id));
+    assert_eq!(sweep_presence(&app.state, now(), IDLE_AFTER).await, 1);
+    assert_eq!(next_event(&queue).await, json!({ "user_id": alice.user_id, "presence": "offline" }));
+    assert_eq!(sweep_presence(&app.state, now(), IDLE_AFTER).await, 0);
+    assert_eq!(presence(&app, &alice).await["last_active_ago"], Value::Null);
+
+    // a heartbeat after expiry puts them back online
+    heartbeat(&app, &alice).await;
+    assert_eq!(next_event(&queue).await["presence"], "online");
+    assert_eq!(presence(&app, &alice).await["presence"], "online");
+}
+
+#[tokio::test]
+async fn going_offline_forgets_the_heartbeat() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    set_presence(&app, &alice, "online").await;
+    set_presence(&app, &alice, "offline").await;
+    let (_, queue) = app.state.register_connection(16);
+
+    // already announced by /presence/set, so the sweeper stays quiet
+    assert_eq!(sweep_presence(&app.state, now() + IDLE_AFTER, IDLE_AFTER).await, 0);
+    assert_eq!(queue.depth(), 0);
+}
+
+#[tokio::test]
+async fn the_snapshot_pages_through_every_presence_key() {
+    let app = TestApp::new().await;
+    {
+        let mut store = app.redis.lock().unwrap();
+        for i in 0..1200 {
+            store.insert(format!("presence:@user{:04}:localhost", i), "online".to_string());
+        }
+        store.insert("voice:room".to_string(), "{}".to_string());
+    }
+    let mut redis = app.state.get_redis().await.unwrap();
+
+    let snapshot = presence_snapshot(&mut redis, None).await.unwrap();
+    assert_eq!(snapshot.len(), 1200);
+    assert_eq!(snapshot[1199].user_id, "@user1199:localhost");
+    assert!(snapshot.iter().all(|p| p.presence == "online"));
+
+    // a filter only looks its users up; anyone without a key is left out
+    let filter: HashSet<String> = ["@user0007:localhost", "@nobody:localhost"].map(String::from).into();
+    let snapshot = presence_snapshot(&mut redis, Some(&filter)).await.unwrap();
+    assert_eq!(serde_json::to_value(&snapshot).unwrap(), json!([{ "user_id": "@user0007:localhost", "presence": "online" }]));
+}
+
+#[tokio::test]
+async fn a_filtered_connection_only_hears_about_its_users() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let filter = HashSet::from([bob.user_id.clone()]);
+    let (_, queue) = app.state.register_queue(ConnectionQueue::new(16).with_presence_filter(Some(filter)));
+
+    set_presence(&app, &alice, "online").await;
+    set_presence(&app, &bob, "online").await;
+    assert_eq!(next_event(&queue).await["user_id"], bob.user_id.as_str());
+    assert_eq!(queue.depth(), 0);
+}
+
+#[tokio::test]
+async fn a_stalled_connection_loses_its_oldest_events_and_nobody_elses() {
+    let app = TestApp::new().await;
+    let (_, stalled) = app.state.register_connection(4);
+    let (_, keeping_up) = app.state.register_connection(16);
+    let status = |i: usize| WsEvent::Presence(PresenceEvent { user_id: format!("@user{}:localhost", i), presence: "online".to_string() });
+
+    for i in 0..10 {
+        app.state.publish(status(i));
+    }
+    // the stalled one hears about the gap first, then the newest four
+    assert!(matches!(stalled.pop().await, QueueItem::Gap { dropped: 6 }));
+    for i in 6..10 {
+        assert_eq!(next_event(&stalled).await["user_id"], format!("@user{}:localhost", i));
+    }
+    assert_eq!((stalled.depth(), stalled.dropped()), (0, 6));
+    // the other got every event, in order, without a gap
+    for i in 0..10 {
+        assert_eq!(next_event(&keeping_up).await["user_id"], format!("@user{}:localhost", i));
+    }
+    assert_eq!((keeping_up.depth(), keeping_up.dropped()), (0, 0));
+
+    let (_, metrics) = app.get("/ws/metrics").await;
+    let dropped: Vec<u64> = metrics["connections"].as_array().unwrap().iter().map(|c| c["dropped"].as_u64().unwrap()).collect();
+    assert_eq!(dropped, [6, 0]);
+}
+
+#[tokio::test]
+async fn do_not_disturb_quiets_sync_but_not_messages() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, queue) = app.state.register_connection(16);
+
+    let body = json!({ "access_token": alice.access_token, "user_id": alice.user_id, "presence": "busy" });
+    assert_eq!(app.post("/presence/set", body).await.0, StatusCode::BAD_REQUEST);
+
+    set_presence(&app, &alice, "dnd").await;
+    assert_eq!(next_event(&queue).await["presence"], "dnd");
+    assert_eq!(presence(&app, &alice).await["presence"], "dnd");
+    let (_, body) = app
+        .get(&format!("/presence/get?access_token={}&user_id={}", bob.access_token, enc(&alice.user_id)))
+        .await;
+    assert_eq!(body["presence"], "dnd");
+
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": bob.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap();
+    app.post("/rooms/join", json!({ "access_token": alice.access_token, "room_id_or_alias": room_id })).await;
+    app.post("/rooms/send", json!({ "access_token": bob.access_token, "room_id": room_id, "content": "ping" })).await;
+
+    let (_, sync) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    assert_eq!(sync["suppress_notifications"], true);
+    assert!(sync["messages"].as_array().unwrap().iter().any(|m| m["content"] == "ping"));
+    let (_, sync) = app.get(&format!("/sync?access_token={}", bob.access_token)).await;
+    assert_eq!(sync["suppress_notifications"], false);
+}
+
+#[tokio::test]
+async fn invisible_users_look_offline_to_everyone_else() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, queue) = app.state.register_connection(16);
+
+    set_presence(&app, &alice, "invisible").await;
+    assert_eq!(next_event(&queue).await, json!({ "user_id": alice.user_id, "presence": "offline" }));
+    assert_eq!(presence(&app, &alice).await["presence"], "invisible");
+    let (_, body) = app
+        .get(&format!("/presence/get?access_token={}&user_id={}", bob.access_token, enc(&alice.user_id)))
+        .await;
+    assert_eq!(body["presence"], "offline");
+
+    let mut redis = app.state.get_redis().await.unwrap();
+    let snapshot = presence_snapshot(&mut redis, None).await.unwrap();
+    assert_eq!(serde_json::to_value(&snapshot).unwrap(), json!([{ "user_id": alice.user_id, "presence": "offline" }]));
+}
+
+#[tokio::test]
+async fn presence_survives_a_redis_restart() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    set_presence(&app, &alice, "dnd").await;
+
+    app.redis_server.bounce();
+    assert_eq!(presence(&app, &alice).await["presence"], "dnd");
+    app.redis_server.bounce();
+    set_presence(&app, &alice, "online").await;
+    assert_eq!(presence(&app, &alice).await["presence"], "online");
+}
@@ -0,0 +1,126 @@
+// private channels: only members with an allowed role get in
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn join(app: &TestApp, user: &TestUser, room_id: &str) -> StatusCode {
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": user.access_token, "room_id_or_alias": room_id }))
+        .await;
+    status
+}
+
+fn membership(app: &TestApp, room_id: &str, user: &TestUser) -> Option<String> {
+    app.homeserver.inspect(|hs| hs.rooms[room_id].membership(&user.user_id).map(str::to_string))
+}
+
+/// a "mods" role on the server, given to `member`
+async fn make_mod(app: &TestApp, owner: &TestUser, server_id: &str, member: &TestUser) {
+    let (status, _) = app
+        .post("/servers/roles", json!({
+            "access_token": owner.access_token,
+            "server_id": server_id,
+            "revision": 0,
+            "roles": [{
+                "id": "mods", "name": "Mods", "color": "#5865f2", "hoist": true, "mentionable": true,
+                "permissions": {
+                    "send_messages": true, "manage_channels": false, "manage_roles": false, "kick_members": true,
+                    "ban_members": false, "mention_everyone": false, "manage_server": false, "administrator": false,
+                },
+                "power_level": 0,
+            }],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app
+        .post("/servers/members/roles", json!({
+            "access_token": owner.access_token, "server_id": server_id, "user_id": member.user_id, "role_ids": ["mods"],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn private_channels_are_only_joined_with_an_allowed_role() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let mods = create(&app, &alice, json!({
+        "name": "mods-only",
+        "parent_space_id": server_id,
+        "private": true,
+        "allowed_role_ids": ["mods"],
+    }))
+    .await;
+
+    app.homeserver.inspect(|hs| {
+        let rules = &hs.rooms[&mods].state[&("m.room.join_rules".to_string(), String::new())]["content"];
+        assert_eq!(rules["join_rule"], "restricted");
+        assert_eq!(rules["allow"][0]["room_id"], server_id.as_str());
+    });
+
+    // public channels work as before; the private one is skipped
+    assert_eq!(join(&app, &bob, &server_id).await, StatusCode::OK);
+    assert_eq!(membership(&app, &general, &bob).as_deref(), Some("join"));
+    assert_eq!(membership(&app, &mods, &bob), None);
+
+    make_mod(&app, &alice, &server_id, &bob).await;
+    assert_eq!(join(&app, &bob, &server_id).await, StatusCode::OK);
+    assert_eq!(membership(&app, &mods, &bob).as_deref(), Some("join"));
+}
+
+#[tokio::test]
+async fn overrides_can_be_read_and_changed() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let channel = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+
+    let url = format!("/rooms/permissions/overrides?access_token={}&room_id={}", alice.access_token, enc(&channel));
+    let (status, overrides) = app.get(&url).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(overrides, json!({ "private": false, "allowed_role_ids": [] }));
+
+    let (status, _) = app
+        .post("/rooms/permissions/overrides", json!({
+            "access_token": alice.access_token,
+            "room_id": channel,
+            "private": true,
+            "allowed_role_ids": ["mods"],
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, overrides) = app.get(&url).await;
+    assert_eq!(overrides, json!({ "private": true, "allowed_role_ids": ["mods"] }));
+
+    assert_eq!(join(&app, &bob, &server_id).await, StatusCode::OK);
+    assert_eq!(membership(&app, &channel, &bob), None);
+
+    // members can't open it up for themselves
+    let (status, _) = app
+        .post("/rooms/permissions/overrides", json!({ "access_token": bob.access_token, "room_id": channel, "private": false }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // public again
+    let (status, _) = app
+        .post("/rooms/permissions/overrides", json!({ "access_token": alice.access_token, "room_id": channel, "private": false }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(join(&app, &bob, &server_id).await, StatusCode::OK);
+    assert_eq!(membership(&app, &channel, &bob).as_deref(), Some("join"));
+}
@@ -0,0 +1,130 @@
+// agora's profile card fields (bio, banner, accent colour, pronouns), merged
+// into the matrix profile. these need postgres, like flows.rs.
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn set(app: &TestApp, user: &TestUser, body: Value) -> (StatusCode, Value) {
+    let mut body = body;
+    body["access_token"] = json!(user.access_token);
+    body["user_id"] = json!(user.user_id);
+    app.request(Method::PUT, "/profile/set", Some(body)).await
+}
+
+async fn profile(app: &TestApp, viewer: &TestUser, user: &TestUser) -> Value {
+    let (status, body) = app
+        .get(&format!("/profile/get?access_token={}&user_id={}", viewer.access_token, enc(&user.user_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    body
+}
+
+#[sqlx::test]
+async fn agora_fields_are_merged_into_the_profile(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    let (status, _) = set(&app, &alice, json!({
+        "displayname": "Alice",
+        "bio": "  hi,\ni make\u{0007} maps  ",
+        "banner_url": "mxc://localhost/banner",
+        "accent_color": "#FF8800",
+        "pronouns": "she/her",
+    }))
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(profile(&app, &bob, &alice).await, json!({
+        "user_id": alice.user_id,
+        "displayname": "Alice",
+        "avatar_url": null,
+        "bio": "hi,\ni make maps",
+        "banner_url": "mxc://localhost/banner",
+        "accent_color": "#ff8800",
+        "pronouns": "she/her",
+    }));
+
+    // left out keeps a field, "" clears it
+    let (status, _) = set(&app, &alice, json!({ "pronouns": "", "bio": "still maps" })).await;
+    assert_eq!(status, StatusCode::OK);
+    let card = profile(&app, &bob, &alice).await;
+    assert_eq!(card["bio"], "still maps");
+    assert_eq!(card["pronouns"], Value::Null);
+    assert_eq!(card["accent_color"], "#ff8800");
+
+    let card = profile(&app, &alice, &bob).await;
+    assert_eq!((card["displayname"].clone(), card["bio"].clone()), (json!("bob"), Value::Null));
+}
+
+#[sqlx::test]
+async fn bad_fields_change_nothing(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+
+    for bad in [
+        json!({ "displayname": "Mallory", "bio": "a".repeat(191) }),
+        json!({ "accent_color": "orange" }),
+        json!({ "banner_url": "https://example.com/banner.png" }),
+        json!({ "pronouns": "x".repeat(41) }),
+    ] {
+        let (status, body) = set(&app, &alice, bad).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+    // 190 characters, not bytes
+    let (status, _) = set(&app, &alice, json!({ "bio": "é".repeat(190) })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let card = profile(&app, &alice, &alice).await;
+    assert_eq!(card["displayname"], "alice");
+    assert_eq!(card["accent_color"], Value::Null);
+
+    // the card written is the token's, whatever user_id says
+    let bob = app.register("bob").await;
+    let body = json!({ "access_token": bob.access_token, "user_id": alice.user_id, "bio": "pwned" });
+    let (status, _) = app.request(Method::PUT, "/profile/set", Some(body)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(profile(&app, &alice, &alice).await["bio"], "pwned");
+    assert_eq!(profile(&app, &alice, &bob).await["bio"], "pwned");
+}
+
+#[sqlx::test]
+async fn the_batch_endpoint_includes_agora_fields(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    set(&app, &bob, json!({ "pronouns": "he/him" })).await;
+
+    let ids = format!("{},{},{},@nobody:localhost", enc(&bob.user_id), enc(&alice.user_id), enc(&bob.user_id));
+    let (status, body) = app.get(&format!("/profile/batch?access_token={}&user_ids={}", alice.access_token, ids)).await;
+    assert_eq!(status, StatusCode::OK);
+    let profiles = body["profiles"].as_array().unwrap();
+    let summary: Vec<(&str, &Value, &Value)> = profiles
+        .iter()
+        .map(|p| (p["user_id"].as_str().unwrap(), &p["displayname"], &p["pronouns"]))
+        .collect();
+    assert_eq!(summary, [
+        (bob.user_id.as_str(), &json!("bob"), &json!("he/him")),
+        (alice.user_id.as_str(), &json!("alice"), &Value::Null),
+        ("@nobody:localhost", &Value::Null, &Value::Null),
+    ]);
+
+    let (status, _) = app.get(&format!("/profile/batch?access_token=nope&user_ids={}", ids)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn agora_fields_need_a_database() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (status, _) = set(&app, &alice, json!({ "bio": "hello" })).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    // matrix fields alone still work
+    let (status, _) = set(&app, &alice, json!({ "displayname": "Alice" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(profile(&app, &alice, &alice).await["bio"], Value::Null);
+}
@@ -0,0 +1,179 @@
+// web push: subscriptions from the settings page, pushes for DMs to users
+// who are away — from the service account's sync, whichever client sent the
+// message — and cleanup of subscriptions the push service has dropped
+
+mod common;
+
+use agora_api::push::{PushConfig, PushFanOut, PushPayload, StubPushes};
+use agora_api::service_account::{self, ServiceAccount};
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::Duration;
+
+async fn push_app(pool: PgPool) -> (TestApp, StubPushes) {
+    let pushes = StubPushes::default();
+    let stub = pushes.clone();
+    let app = TestApp::with_config(|state| {
+        state.db_pool = Some(pool);
+        state.push = Some(PushConfig::stub(stub));
+    })
+    .await;
+    app.state.sync_observers.register(PushFanOut);
+    (app, pushes)
+}
+
+fn subscription(endpoint: &str) -> Value {
+    json!({ "endpoint": endpoint, "keys": { "p256dh": "BNcRdreALRFXTkOOUHK1EtK2wtaz5Ry4YfYCA", "auth": "tBHItJI5svbpez7KI4CCXg" } })
+}
+
+/// a DM between two new friends
+async fn dm(app: &TestApp, alice: &TestUser, bob: &TestUser) -> String {
+    let body = |user: &TestUser, other: &TestUser| json!({ "access_token": user.access_token, "user_id": user.user_id, "friend_id": other.user_id });
+    assert_eq!(app.post("/friends/add", body(alice, bob)).await.0, StatusCode::OK);
+    assert_eq!(app.post("/friends/accept", body(bob, alice)).await.0, StatusCode::OK);
+    let (_, dm) = app.post("/friends/dm", body(alice, bob)).await;
+    app.post("/friends/dm", body(bob, alice)).await;
+    dm["room_id"].as_str().unwrap().to_string()
+}
+
+/// the service account, signed in the way main.rs does it. rooms made from
+/// here on bring it in themselves
+async fn sign_in_bot(app: &TestApp) {
+    let bot = ServiceAccount { username: "agora".to_string(), password: "hunter2".to_string() };
+    bot.sign_in(&app.state).await.unwrap();
+}
+
+/// what's been pushed once `count` pushes are out — delivery runs on its own
+/// tasks — and anything stray that was on the way has had its chance too
+async fn pushed(pushes: &StubPushes, count: usize) -> Vec<(String, PushPayload)> {
+    for _ in 0..100 {
+        if pushes.sent.lock().unwrap().len() >= count {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    pushes.sent.lock().unwrap().clone()
+}
+
+#[sqlx::test]
+async fn the_settings_page_can_subscribe_test_and_unsubscribe(pool: PgPool) {
+    let (app, pushes) = push_app(pool).await;
+    let alice = app.register("alice").await;
+
+    let (status, key) = app.authed(&alice, Method::GET, "/notifications/push-key", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(key["public_key"], "stub-public-key");
+
+    let endpoint = "https://push.example/alice-laptop";
+    let (status, _) = app.authed(&alice, Method::POST, "/notifications/subscribe", Some(subscription(endpoint))).await;
+    assert_eq!(status, StatusCode::OK);
+    // the server posts to the endpoint, so it has to be a push service's https url
+    let insecure = subscription("http://localhost:6379/");
+    assert_eq!(app.authed(&alice, Method::POST, "/notifications/subscribe", Some(insecure)).await.0, StatusCode::BAD_REQUEST);
+
+    let (status, body) = app.authed(&alice, Method::POST, "/notifications/test", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "sent": 1, "removed": 0 }));
+    let sent = pushes.sent.lock().unwrap().clone();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, endpoint);
+    assert_eq!(sent[0].1.kind, "test");
+
+    let unsubscribe = json!({ "endpoint": endpoint });
+    assert_eq!(app.authed(&alice, Method::DELETE, "/notifications/subscribe", Some(unsubscribe.clone())).await.0, StatusCode::OK);
+    assert_eq!(app.authed(&alice, Method::DELETE, "/notifications/subscribe", Some(unsubscribe)).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(app.authed(&alice, Method::POST, "/notifications/test", None).await.0, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn subscriptions_the_push_service_dropped_are_deleted(pool: PgPool) {
+    let (app, pushes) = push_app(pool).await;
+    let alice = app.register("alice").await;
+    for endpoint in ["https://push.example/old", "https://push.example/new"] {
+        app.authed(&alice, Method::POST, "/notifications/subscribe", Some(subscription(endpoint))).await;
+    }
+    pushes.gone.lock().unwrap().insert("https://push.example/old".to_string());
+
+    let (_, body) = app.authed(&alice, Method::POST, "/notifications/test", None).await;
+    assert_eq!(body, json!({ "sent": 1, "removed": 1 }));
+    let (_, body) = app.authed(&alice, Method::POST, "/notifications/test", None).await;
+    assert_eq!(body, json!({ "sent": 1, "removed": 0 }));
+}
+
+#[sqlx::test]
+async fn a_dm_is_pushed_to_a_recipient_who_is_away(pool: PgPool) {
+    let (app, pushes) = push_app(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    sign_in_bot(&app).await;
+    let room_id = dm(&app, &alice, &bob).await;
+    app.authed(&bob, Method::POST, "/notifications/subscribe", Some(subscription("https://push.example/bob"))).await;
+    let since = service_account::sync_once(&app.state, None).await.unwrap();
+
+    let long = "x".repeat(500);
+    let message = json!({ "room_id": room_id, "content": long });
+    assert_eq!(app.authed(&alice, Method::POST, "/rooms/send", Some(message)).await.0, StatusCode::OK);
+    // nothing until the sync brings it
+    assert!(pushes.sent.lock().unwrap().is_empty());
+    let since = service_account::sync_once(&app.state, since).await.unwrap();
+
+    let sent = pushed(&pushes, 1).await;
+    assert_eq!(sent.len(), 1, "{:?}", sent);
+    let (endpoint, payload) = sent[0].clone();
+    assert_eq!(endpoint, "https://push.example/bob");
+    assert_eq!(payload.kind, "dm");
+    assert_eq!(payload.sender, alice.user_id);
+    assert_eq!(payload.room_id, room_id);
+    assert!(payload.event_id.is_some());
+    // trimmed, so the encrypted payload fits what push services accept
+    assert_eq!(payload.snippet, format!("{}…", "x".repeat(100)));
+
+    // sent from some other matrix client, straight to the homeserver
+    let mut element = app.state.matrix();
+    element.access_token = Some(alice.access_token.clone());
+    element.send_message(room_id.clone(), "from my phone".to_string(), None).await.unwrap();
+    let since = service_account::sync_once(&app.state, since).await.unwrap();
+    let sent = pushed(&pushes, 2).await;
+    assert_eq!(sent.len(), 2, "{:?}", sent);
+    assert_eq!(sent[1].1.snippet, "from my phone");
+
+    // and a sync that brings nothing new pushes nothing
+    service_account::sync_once(&app.state, since).await.unwrap();
+    assert_eq!(pushed(&pushes, 3).await.len(), 2);
+}
+
+#[sqlx::test]
+async fn do_not_disturb_only_reaches_exempt_browsers(pool: PgPool) {
+    let (app, pushes) = push_app(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    sign_in_bot(&app).await;
+    let room_id = dm(&app, &alice, &bob).await;
+    let since = service_account::sync_once(&app.state, None).await.unwrap();
+    app.authed(&bob, Method::POST, "/notifications/subscribe", Some(subscription("https://push.example/bob-laptop"))).await;
+    let mut phone = subscription("https://push.example/bob-phone");
+    phone["during_dnd"] = json!(true);
+    app.authed(&bob, Method::POST, "/notifications/subscribe", Some(phone)).await;
+
+    let dnd = json!({ "presence": "dnd" });
+    assert_eq!(app.authed(&bob, Method::POST, "/presence/set", Some(dnd)).await.0, StatusCode::OK);
+    let message = json!({ "room_id": room_id, "content": "you up?" });
+    app.authed(&alice, Method::POST, "/rooms/send", Some(message)).await;
+    let since = service_account::sync_once(&app.state, since).await.unwrap();
+    assert_eq!(pushed(&pushes, 1).await.len(), 1);
+
+    // and nothing at all while bob has the app open
+    let online = json!({ "presence": "online" });
+    app.authed(&bob, Method::POST, "/presence/set", Some(online)).await;
+    let message = json!({ "room_id": room_id, "content": "guess not" });
+    app.authed(&alice, Method::POST, "/rooms/send", Some(message)).await;
+    service_account::sync_once(&app.state, since).await.unwrap();
+
+    let sent = pushed(&pushes, 2).await;
+    assert_eq!(sent.len(), 1, "{:?}", sent);
+    assert_eq!(sent[0].0, "https://push.example/bob-phone");
+    assert_eq!(sent[0].1.snippet, "you up?");
+}
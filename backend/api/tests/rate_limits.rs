@@ -0,0 +1,139 @@
+// rate limits: per route class and caller, out of redis token buckets. the
+// harness turns them off, so each test here puts back the ones it checks.
+
+mod common;
+
+use agora_api::rate_limit::{Limit, RateLimits};
+use agora_api::redis_manager::RedisManager;
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use common::TestApp;
+use serde_json::json;
+use tower::ServiceExt;
+
+async fn register(app: &TestApp, username: &str) -> (StatusCode, Option<String>) {
+    register_with(app, username, None).await
+}
+
+/// the same, with an `Authorization: Bearer` header of the caller's choosing
+async fn register_with(app: &TestApp, username: &str, bearer: Option<&str>) -> (StatusCode, Option<String>) {
+    let mut request = Request::post("/register").header("content-type", "application/json");
+    if let Some(token) = bearer {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let request = request
+        .body(Body::from(json!({ "username": username, "password": "hunter2" }).to_string()))
+        .unwrap();
+    let response = app.router.clone().oneshot(request).await.unwrap();
+    let retry_after = response.headers().get(header::RETRY_AFTER).map(|v| v.to_str().unwrap().to_string());
+    (response.status(), retry_after)
+}
+
+#[tokio::test]
+async fn the_sixth_registration_in_a_minute_is_refused() {
+    let app = TestApp::with_config(|state| state.rate_limits = RateLimits::default()).await;
+
+    for i in 0..5 {
+        let (status, _) = register(&app, &format!("user{}", i)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, retry_after) = register(&app, "user5").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = retry_after.unwrap().parse().unwrap();
+    assert!((1..=12).contains(&retry_after), "{}", retry_after);
+
+    let (status, body) = app.post("/register", json!({ "username": "user6", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["errcode"], "M_LIMIT_EXCEEDED");
+    assert_eq!(body["retry_after_ms"], retry_after * 1000);
+
+    // other route classes have buckets of their own
+    let (status, _) = app.post("/login", json!({ "username": "user0", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn health_checks_are_never_limited() {
+    let tight = RateLimits { default: Some(Limit::new(2, 60)), ..RateLimits::default() };
+    let app = TestApp::with_config(|state| state.rate_limits = tight).await;
+
+    for _ in 0..10 {
+        assert_eq!(app.get("/health").await.0, StatusCode::OK);
+    }
+    let uri = "/rooms?access_token=nope";
+    assert_eq!(app.get(uri).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(app.get(uri).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(app.get(uri).await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn without_redis_everything_goes_through() {
+    let app = TestApp::with_config(|state| {
+        state.rate_limits = RateLimits::default();
+        state.redis = RedisManager::disabled();
+    })
+    .await;
+
+    for i in 0..7 {
+        assert_eq!(register(&app, &format!("user{}", i)).await.0, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn made_up_tokens_dont_get_buckets_of_their_own() {
+    let tight = RateLimits { default: Some(Limit::new(2, 60)), ..RateLimits::default() };
+    let app = TestApp::with_config(|state| state.rate_limits = tight).await;
+
+    // registration goes by address whatever the caller claims to be
+    for i in 0..5 {
+        let (status, _) = register_with(&app, &format!("user{}", i), Some(&format!("fake{}", i))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert_eq!(register_with(&app, "user5", Some("fake5")).await.0, StatusCode::TOO_MANY_REQUESTS);
+
+    // and so does everything else until the token turns out to be real
+    for (i, expected) in [StatusCode::UNAUTHORIZED, StatusCode::UNAUTHORIZED, StatusCode::TOO_MANY_REQUESTS]
+        .into_iter()
+        .enumerate()
+    {
+        let request = Request::get("/rooms")
+            .header("authorization", format!("Bearer fake{}", i))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.router.clone().oneshot(request).await.unwrap().status(), expected);
+    }
+    let (_, login) = app.post("/login", json!({ "username": "user0", "password": "hunter2" })).await;
+    let user = common::TestUser {
+        user_id: login["user_id"].as_str().unwrap().to_string(),
+        access_token: login["access_token"].as_str().unwrap().to_string(),
+    };
+    assert_eq!(app.authed(&user, axum::http::Method::GET, "/rooms", None).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_tokens_past_the_address_limit_never_reach_the_homeserver() {
+    let tight = RateLimits { default: Some(Limit::new(2, 60)), ..RateLimits::default() };
+    let app = TestApp::with_config(|state| state.rate_limits = tight).await;
+    let whoamis = || async {
+        let requests = app.homeserver.server.received_requests().await.unwrap();
+        requests.iter().filter(|r| r.url.path().ends_with("/account/whoami")).count()
+    };
+
+    let mut statuses = Vec::new();
+    for i in 0..5 {
+        let request = Request::get("/rooms")
+            .header("authorization", format!("Bearer unknown{}", i))
+            .body(Body::empty())
+            .unwrap();
+        statuses.push(app.router.clone().oneshot(request).await.unwrap().status());
+    }
+    assert_eq!(statuses[..2], [StatusCode::UNAUTHORIZED; 2]);
+    assert_eq!(statuses[2..], [StatusCode::TOO_MANY_REQUESTS; 3]);
+    // one lookup for each token the address bucket let through, none after
+    assert_eq!(whoamis().await, 2);
+
+    // a token from login is known at once, so it has its own bucket from the start
+    let alice = app.register("alice").await;
+    assert_eq!(app.authed(&alice, axum::http::Method::GET, "/rooms", None).await.0, StatusCode::OK);
+    assert_eq!(whoamis().await, 2);
+}
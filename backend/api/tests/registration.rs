@@ -0,0 +1,105 @@
+// REGISTRATION_MODE: closed and token-only registration, and the admin
+// endpoints managing the tokens
+
+mod common;
+
+use agora_api::registration::RegistrationMode;
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::Duration;
+
+async fn token_app(pool: PgPool) -> (TestApp, TestUser) {
+    let app = TestApp::with_config(|state| {
+        state.db_pool = Some(pool);
+        state.registration_mode = RegistrationMode::Token;
+        state.admin_users.insert("@root:localhost".to_string());
+    })
+    .await;
+    // straight on the homeserver — the api wants a token by now
+    let root = app.state.matrix().register("root".to_string(), "hunter2".to_string()).await.unwrap();
+    (app, TestUser { user_id: root.user_id, access_token: root.access_token })
+}
+
+async fn register(app: &TestApp, username: &str, token: Option<&str>) -> (StatusCode, Value) {
+    app.post("/register", json!({ "username": username, "password": "hunter2", "registration_token": token })).await
+}
+
+#[tokio::test]
+async fn closed_registration_turns_everyone_away() {
+    let app = TestApp::with_config(|state| state.registration_mode = RegistrationMode::Closed).await;
+    let (status, body) = register(&app, "alice", None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "AGORA_REGISTRATION_CLOSED");
+    assert!(app.homeserver.inspect(|hs| hs.users.is_empty()));
+}
+
+#[sqlx::test]
+async fn a_token_runs_out_of_uses(pool: PgPool) {
+    let (app, root) = token_app(pool).await;
+    let (status, body) = register(&app, "alice", None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "AGORA_REGISTRATION_TOKEN_REQUIRED");
+
+    let create = json!({ "token": "crew-2026", "uses_allowed": 2 });
+    let (status, token) = app.authed(&root, Method::POST, "/admin/registration_tokens", Some(create)).await;
+    assert_eq!(status, StatusCode::OK, "{}", token);
+    assert_eq!(token["uses_remaining"], 2);
+
+    assert_eq!(register(&app, "alice", Some("crew-2026")).await.0, StatusCode::OK);
+    // a registration the homeserver turns down doesn't spend a use
+    assert_eq!(register(&app, "alice", Some("crew-2026")).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(register(&app, "bob", Some("crew-2026")).await.0, StatusCode::OK);
+    let (status, body) = register(&app, "carol", Some("crew-2026")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "AGORA_INVALID_REGISTRATION_TOKEN");
+
+    let (_, list) = app.authed(&root, Method::GET, "/admin/registration_tokens", None).await;
+    assert_eq!(list["tokens"][0]["token"], "crew-2026");
+    assert_eq!(list["tokens"][0]["uses_remaining"], 0);
+    assert_eq!(list["tokens"][0]["created_by"], root.user_id.as_str());
+}
+
+#[sqlx::test]
+async fn an_expired_token_lets_nobody_in(pool: PgPool) {
+    let (app, root) = token_app(pool).await;
+    let expires_at = chrono::Utc::now().timestamp_millis() + 300;
+    let create = json!({ "expires_at": expires_at });
+    let (status, token) = app.authed(&root, Method::POST, "/admin/registration_tokens", Some(create)).await;
+    assert_eq!(status, StatusCode::OK, "{}", token);
+    let token = token["token"].as_str().unwrap().to_string();
+    assert_eq!(register(&app, "alice", Some(&token)).await.0, StatusCode::OK);
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let (status, body) = register(&app, "bob", Some(&token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "AGORA_INVALID_REGISTRATION_TOKEN");
+}
+
+#[sqlx::test]
+async fn only_admins_manage_tokens(pool: PgPool) {
+    let (app, root) = token_app(pool).await;
+    let create = json!({ "token": "welcome" });
+    app.authed(&root, Method::POST, "/admin/registration_tokens", Some(create.clone())).await;
+    let (status, body) = app.authed(&root, Method::POST, "/admin/registration_tokens", Some(create)).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    let bad = json!({ "token": "no spaces" });
+    assert_eq!(app.authed(&root, Method::POST, "/admin/registration_tokens", Some(bad)).await.0, StatusCode::BAD_REQUEST);
+
+    let (_, alice) = register(&app, "alice", Some("welcome")).await;
+    let alice = TestUser {
+        user_id: alice["user_id"].as_str().unwrap().to_string(),
+        access_token: alice["access_token"].as_str().unwrap().to_string(),
+    };
+    for method in [Method::GET, Method::POST, Method::DELETE] {
+        let (status, body) = app.authed(&alice, method, "/admin/registration_tokens", Some(json!({ "token": "welcome" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["errcode"], "M_FORBIDDEN");
+    }
+
+    let delete = json!({ "token": "welcome" });
+    assert_eq!(app.authed(&root, Method::DELETE, "/admin/registration_tokens", Some(delete.clone())).await.0, StatusCode::OK);
+    assert_eq!(app.authed(&root, Method::DELETE, "/admin/registration_tokens", Some(delete)).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(register(&app, "bob", Some("welcome")).await.0, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,174 @@
+// reporting messages and the moderators' queue of reports
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+/// a server with one channel, owned by `owner` (the moderator) and joined by `members`
+async fn server(app: &TestApp, owner: &TestUser, members: &[&TestUser]) -> (String, String) {
+    let (_, server) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": "Crew", "is_space": true }))
+        .await;
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+    let (_, channel) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": "general", "parent_space_id": server_id }))
+        .await;
+    let channel_id = channel["room_id"].as_str().unwrap().to_string();
+    for member in members {
+        let join = json!({ "access_token": member.access_token, "room_id_or_alias": server_id });
+        assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    }
+    (server_id, channel_id)
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, content: &str) -> String {
+    let (status, body) = app
+        .post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": content }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["event_id"].as_str().unwrap().to_string()
+}
+
+async fn report(app: &TestApp, user: &TestUser, room_id: &str, event_id: &str, score: Option<i32>) -> (StatusCode, Value) {
+    app.post("/rooms/report", json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "event_id": event_id,
+        "reason": "spam",
+        "score": score,
+    }))
+    .await
+}
+
+async fn reports(app: &TestApp, user: &TestUser, server_id: &str, status: &str) -> (StatusCode, Value) {
+    let uri = format!("/servers/reports?server_id={}&status={}", enc(server_id), status);
+    app.authed(user, Method::GET, &uri, None).await
+}
+
+async fn resolve(app: &TestApp, user: &TestUser, server_id: &str, report_id: &Value, action: Value) -> (StatusCode, Value) {
+    let mut body = json!({ "access_token": user.access_token, "server_id": server_id, "report_id": report_id });
+    body.as_object_mut().unwrap().extend(action.as_object().unwrap().clone());
+    app.post("/servers/reports/resolve", body).await
+}
+
+#[sqlx::test]
+async fn members_report_messages_they_can_see(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let dave = app.register("dave").await;
+    let (server_id, channel_id) = server(&app, &alice, &[&bob, &carol]).await;
+    let event_id = send(&app, &bob, &channel_id, "buy cheap stuff").await;
+
+    let (status, body) = report(&app, &carol, &channel_id, &event_id, Some(-80)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body["report_id"].as_i64().is_some());
+    let sent = app.homeserver.inspect(|hs| hs.event_reports.clone());
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, carol.user_id);
+    assert_eq!(sent[0].3, json!({ "reason": "spam", "score": -80 }));
+
+    // once per message, never your own, only from inside the room
+    let (status, body) = report(&app, &carol, &channel_id, &event_id, None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["errcode"], "AGORA_ALREADY_REPORTED");
+    assert_eq!(report(&app, &bob, &channel_id, &event_id, None).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(report(&app, &dave, &channel_id, &event_id, None).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(report(&app, &carol, &channel_id, "$nope", None).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(report(&app, &alice, &channel_id, &event_id, Some(-101)).await.0, StatusCode::BAD_REQUEST);
+
+    // the queue is for moderators
+    let (status, _) = reports(&app, &carol, &server_id, "open").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = reports(&app, &alice, &server_id, "open").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let listed = body["reports"].as_array().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["event_id"], event_id.as_str());
+    assert_eq!(listed[0]["reporter_id"], carol.user_id.as_str());
+    assert_eq!(listed[0]["reported_user_id"], bob.user_id.as_str());
+    assert_eq!(listed[0]["score"], -80);
+    assert_eq!(listed[0]["status"], "open");
+    assert_eq!(reports(&app, &alice, &server_id, "closed").await.0, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn redacting_resolves_every_report_of_the_message(pool: PgPool) {
+    let app = TestApp::with_db(pool.clone()).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let dave = app.register("dave").await;
+    let (server_id, channel_id) = server(&app, &alice, &[&bob, &carol, &dave]).await;
+    let event_id = send(&app, &bob, &channel_id, "something nasty").await;
+
+    let (_, first) = report(&app, &carol, &channel_id, &event_id, None).await;
+    let (_, second) = report(&app, &dave, &channel_id, &event_id, None).await;
+
+    assert_eq!(resolve(&app, &bob, &server_id, &first["report_id"], json!({ "action": "redact" })).await.0, StatusCode::FORBIDDEN);
+    let (status, body) = resolve(&app, &alice, &server_id, &first["report_id"], json!({ "action": "redact" })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let mut resolved: Vec<i64> = serde_json::from_value(body["resolved"].clone()).unwrap();
+    resolved.sort();
+    assert_eq!(resolved, [first["report_id"].as_i64().unwrap(), second["report_id"].as_i64().unwrap()]);
+
+    let content = app.homeserver.inspect(|hs| {
+        hs.timeline.iter().find(|(_, e)| e["event_id"] == event_id.as_str()).unwrap().1["content"].clone()
+    });
+    assert_eq!(content, json!({}));
+
+    let (_, open) = reports(&app, &alice, &server_id, "open").await;
+    assert_eq!(open["reports"], json!([]));
+    let (_, actioned) = reports(&app, &alice, &server_id, "actioned").await;
+    assert_eq!(actioned["reports"].as_array().unwrap().len(), 2);
+    assert_eq!(actioned["reports"][0]["resolution"], "redact");
+    assert_eq!(actioned["reports"][0]["resolved_by"], alice.user_id.as_str());
+
+    let (status, body) = resolve(&app, &alice, &server_id, &second["report_id"], json!({ "action": "dismiss" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["errcode"], "AGORA_REPORT_RESOLVED");
+
+    let logged: Vec<(String, String)> = sqlx::query_as("SELECT action, target_user_id FROM moderation_actions")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(logged, [("redact".to_string(), bob.user_id.clone())]);
+}
+
+#[sqlx::test]
+async fn timing_out_or_dismissing_from_the_queue(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let (server_id, channel_id) = server(&app, &alice, &[&bob, &carol]).await;
+    let rude = send(&app, &bob, &channel_id, "rude").await;
+    let fine = send(&app, &bob, &channel_id, "fine actually").await;
+    let (_, rude_report) = report(&app, &carol, &channel_id, &rude, None).await;
+    let (_, fine_report) = report(&app, &carol, &channel_id, &fine, None).await;
+
+    let (status, _) = resolve(&app, &alice, &server_id, &rude_report["report_id"], json!({ "action": "timeout" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "a timeout needs a duration");
+    let action = json!({ "action": "timeout", "duration_secs": 600, "reason": "calm down" });
+    let (status, body) = resolve(&app, &alice, &server_id, &rude_report["report_id"], action).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body["timed_out_until"].as_i64().unwrap() > chrono::Utc::now().timestamp_millis());
+
+    // the timeout is the ordinary kind
+    let (status, body) = app
+        .post("/rooms/send", json!({ "access_token": bob.access_token, "room_id": channel_id, "content": "hello?" }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+
+    let (status, body) = resolve(&app, &alice, &server_id, &fine_report["report_id"], json!({ "action": "dismiss" })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, dismissed) = reports(&app, &alice, &server_id, "dismissed").await;
+    assert_eq!(dismissed["reports"][0]["event_id"], fine.as_str());
+    let (_, all) = reports(&app, &alice, &server_id, "all").await;
+    assert_eq!(all["reports"].as_array().unwrap().len(), 2);
+    assert_eq!(resolve(&app, &alice, &server_id, &json!(9999), json!({ "action": "dismiss" })).await.0, StatusCode::NOT_FOUND);
+}
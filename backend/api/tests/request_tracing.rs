@@ -0,0 +1,123 @@
+// request ids and spans: each request runs in a span named by its id, its
+// homeserver calls in child spans, and no token or password reaches the logs
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use common::TestApp;
+use serde_json::json;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tower::ServiceExt;
+
+/// the x-request-id an empty GET /health came back with, sending `id` if given
+async fn request_id(app: &TestApp, id: Option<&str>) -> String {
+    let mut request = Request::builder().uri("/health");
+    if let Some(id) = id {
+        request = request.header("x-request-id", id);
+    }
+    let response = app.router.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    response.headers()["x-request-id"].to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn responses_carry_the_request_id() {
+    let app = TestApp::new().await;
+
+    let made_up = request_id(&app, None).await;
+    assert!(uuid::Uuid::parse_str(&made_up).is_ok(), "{}", made_up);
+    assert_ne!(request_id(&app, None).await, made_up);
+    assert_eq!(request_id(&app, Some("checkout-42.retry:1")).await, "checkout-42.retry:1");
+    // not a usable id, so it gets one of its own
+    for unusable in ["two words", "x".repeat(129).as_str(), ""] {
+        let replaced = request_id(&app, Some(unusable)).await;
+        assert!(uuid::Uuid::parse_str(&replaced).is_ok(), "{:?} -> {}", unusable, replaced);
+    }
+}
+
+/// what gets logged while the guard from `capture` is held, as plain text
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Logs {
+    fn capture(&self) -> tracing::subscriber::DefaultGuard {
+        let logs = self.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter("agora_api=debug")
+            .with_writer(move || logs.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::set_default(subscriber)
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn spans_cover_homeserver_calls_without_secrets() {
+    let app = TestApp::new().await;
+    let logs = Logs::default();
+    let _guard = logs.capture();
+
+    let register = Request::post("/register")
+        .header("x-request-id", "signup-1")
+        .header("content-type", "application/json")
+        .body(Body::from(json!({ "username": "alice", "password": "hunter2" }).to_string()))
+        .unwrap();
+    let response = app.router.clone().oneshot(register).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let alice: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let token = alice["access_token"].as_str().unwrap();
+
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": token, "name": "general" })).await;
+    let send = json!({ "access_token": token, "room_id": room["room_id"], "content": "hi", "txn_id": "t1" });
+    assert_eq!(app.post("/rooms/send", send).await.0, StatusCode::OK);
+    app.get(&format!("/rooms?access_token={}", token)).await;
+    app.post("/webhooks/1/webhook-secret", json!({ "content": "hi" })).await;
+
+    let text = logs.text();
+    // the request's span, around the homeserver's: the uia round trip, then the registration
+    let registered: Vec<&str> = text.lines().filter(|l| l.contains("endpoint=/_matrix/client/v3/register")).collect();
+    assert_eq!(registered.len(), 2, "{}", text);
+    assert!(registered[0].contains("request{id=signup-1 method=POST path=/register"), "{}", registered[0]);
+    assert!(registered[0].contains("status=401"), "{}", registered[0]);
+    assert!(registered[1].contains("status=200 latency_ms="), "{}", registered[1]);
+    assert!(text.contains("/rooms/{room_id}/send/m.room.message/{txn_id}"), "{}", text);
+    assert!(text.contains("path=/webhooks/:webhook_id/:token"), "{}", text);
+
+    for secret in [token, "hunter2", "webhook-secret"] {
+        assert!(!text.contains(secret), "{} in\n{}", secret, text);
+    }
+}
+
+#[test]
+fn secrets_are_redacted_from_text() {
+    use agora_api::redact::redact;
+
+    assert_eq!(
+        redact(r#"{"user_id":"@a:localhost","access_token": "syt_abc\"def","device_id":"D"}"#),
+        r#"{"user_id":"@a:localhost","access_token": "<redacted>","device_id":"D"}"#,
+    );
+    assert_eq!(
+        redact("error sending request for url (http://hs/sync?access_token=syt_abc&since=s1)"),
+        "error sending request for url (http://hs/sync?access_token=<redacted>&since=s1)",
+    );
+    assert_eq!(redact(r#"{"new_password":"x","tokens":3}"#), r#"{"new_password":"<redacted>","tokens":3}"#);
+    assert_eq!(redact("Authorization: Bearer syt_abc"), "Authorization: Bearer <redacted>");
+    assert_eq!(redact("nothing secret, not even a token"), "nothing secret, not even a token");
+}
@@ -0,0 +1,110 @@
+// transient homeserver failures are retried with backoff — but only where a
+// retry can't repeat something that already happened
+
+mod common;
+
+use agora_api::matrix::client::{ApiVersion, HistoryVisibility, MatrixClient};
+use agora_api::matrix::retry::RetryPolicy;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const WHOAMI: &str = "/_matrix/client/v3/account/whoami";
+
+fn fast_retries(max_retries: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_retries,
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(500),
+    }
+}
+
+/// already knows the server speaks v3, so only the request under test is counted
+fn client(server: &MockServer, retry: RetryPolicy) -> MatrixClient {
+    let mut matrix = MatrixClient::new(server.uri())
+        .with_retry(retry)
+        .with_api_version(Arc::new(ApiVersion::V3.into()));
+    matrix.access_token = Some("token".to_string());
+    matrix
+}
+
+fn rate_limited(retry_after_ms: u64) -> ResponseTemplate {
+    ResponseTemplate::new(429).set_body_json(json!({
+        "errcode": "M_LIMIT_EXCEEDED",
+        "error": "too many requests",
+        "retry_after_ms": retry_after_ms,
+    }))
+}
+
+#[tokio::test]
+async fn rate_limits_are_retried_after_the_requested_delay() {
+    let server = MockServer::start().await;
+    // the first two answers are 429s, then the real one
+    Mock::given(method("GET"))
+        .and(path(WHOAMI))
+        .respond_with(rate_limited(100))
+        .up_to_n_times(2)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(WHOAMI))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "user_id": "@alice:localhost" })))
+        .mount(&server)
+        .await;
+
+    let started = Instant::now();
+    let whoami = client(&server, fast_retries(3)).whoami().await.unwrap();
+    assert_eq!(whoami.user_id, "@alice:localhost");
+    // two waits of retry_after_ms, not the 10ms backoff
+    assert!(started.elapsed() >= Duration::from_millis(200), "retried after {:?}", started.elapsed());
+    assert_eq!(server.received_requests().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn giving_up_returns_the_last_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path(WHOAMI)).respond_with(rate_limited(10)).mount(&server).await;
+
+    let err = client(&server, fast_retries(2)).whoami().await.unwrap_err();
+    assert!(err.is_rate_limited());
+    assert_eq!(server.received_requests().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn gateway_errors_are_retried_for_idempotent_requests_only() {
+    let server = MockServer::start().await;
+    Mock::given(wiremock::matchers::any())
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+    let matrix = client(&server, fast_retries(2));
+
+    // GET: retried
+    assert!(matrix.whoami().await.is_err());
+    assert_eq!(server.received_requests().await.unwrap().len(), 3);
+
+    // POST /createRoom: a 503 may have come after the room was made — no retry
+    server.reset().await;
+    Mock::given(wiremock::matchers::any())
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+    assert!(matrix.create_room("general".to_string(), None, false, HistoryVisibility::Shared).await.is_err());
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn connection_failures_back_off_and_give_up() {
+    // nothing listens on a port once its listener is dropped
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut matrix = MatrixClient::new(format!("http://127.0.0.1:{}", port)).with_retry(fast_retries(2));
+    matrix.access_token = Some("token".to_string());
+
+    let started = Instant::now();
+    assert!(matrix.whoami().await.is_err());
+    // 10ms + 20ms of backoff between the three attempts
+    assert!(started.elapsed() >= Duration::from_millis(30));
+}
@@ -0,0 +1,314 @@
+// server roles: what's stored in agora.roles / agora.member.roles, and the
+// power levels they map to
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn server(app: &TestApp, owner: &TestUser, members: &[&TestUser]) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": owner.access_token, "name": "Lounge", "is_space": true })).await;
+    let server_id = room["room_id"].as_str().unwrap().to_string();
+    for member in members {
+        app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": server_id })).await;
+    }
+    server_id
+}
+
+fn role(id: &str, power_level: i64) -> Value {
+    ranked(id, power_level, 0, "send_messages")
+}
+
+fn ranked(id: &str, power_level: i64, position: i64, permission: &str) -> Value {
+    let mut role = json!({
+        "id": id, "name": id, "color": "#5865f2", "hoist": false, "mentionable": true,
+        "permissions": {
+            "send_messages": true, "manage_channels": false, "manage_roles": false, "kick_members": false,
+            "ban_members": false, "mention_everyone": false, "manage_server": false, "administrator": false,
+        },
+        "power_level": power_level,
+        "position": position,
+    });
+    role["permissions"][permission] = json!(true);
+    role
+}
+
+async fn set_roles(app: &TestApp, owner: &TestUser, server_id: &str, roles: Vec<Value>) {
+    let body = json!({ "access_token": owner.access_token, "server_id": server_id, "roles": roles, "force": true });
+    let (status, body) = app.post("/servers/roles", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+async fn assign(app: &TestApp, owner: &TestUser, server_id: &str, member: &TestUser, role_ids: &[&str]) {
+    let body = json!({ "access_token": owner.access_token, "server_id": server_id, "user_id": member.user_id, "role_ids": role_ids });
+    assert_eq!(app.post("/servers/members/roles", body).await.0, StatusCode::OK);
+}
+
+async fn member_roles(app: &TestApp, viewer: &TestUser, server_id: &str, member: &TestUser) -> Value {
+    let (_, body) = app
+        .get(&format!(
+            "/servers/members/roles?access_token={}&server_id={}&user_id={}",
+            viewer.access_token,
+            enc(server_id),
+            enc(&member.user_id)
+        ))
+        .await;
+    body["role_ids"].clone()
+}
+
+fn power(app: &TestApp, server_id: &str, member: &TestUser) -> Value {
+    app.homeserver.inspect(|hs| {
+        hs.rooms[server_id].state[&("m.room.power_levels".into(), String::new())]["content"]["users"][&member.user_id].clone()
+    })
+}
+
+#[tokio::test]
+async fn deleting_a_role_takes_it_from_its_members() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let dave = app.register("dave").await;
+    let server_id = server(&app, &alice, &[&bob, &carol, &dave]).await;
+    set_roles(&app, &alice, &server_id, vec![role("mods", 50), role("helpers", 10)]).await;
+    assign(&app, &alice, &server_id, &bob, &["mods", "helpers"]).await;
+    assign(&app, &alice, &server_id, &carol, &["mods"]).await;
+    assign(&app, &alice, &server_id, &dave, &["helpers"]).await;
+    assert_eq!(power(&app, &server_id, &bob), 50);
+
+    let delete = json!({ "access_token": alice.access_token, "server_id": server_id, "role_id": "mods" });
+    let (status, body) = app.request(Method::DELETE, "/servers/roles", Some(delete.clone())).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["members_updated"], 2);
+
+    let (_, roles) = app.get(&format!("/servers/roles?access_token={}&server_id={}", alice.access_token, enc(&server_id))).await;
+    let ids: Vec<&str> = roles["roles"].as_array().unwrap().iter().map(|r| r["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, ["helpers"]);
+    assert_eq!(roles["revision"], body["revision"]);
+
+    assert_eq!(member_roles(&app, &alice, &server_id, &bob).await, json!(["helpers"]));
+    assert_eq!(member_roles(&app, &alice, &server_id, &carol).await, json!([]));
+    assert_eq!(member_roles(&app, &alice, &server_id, &dave).await, json!(["helpers"]));
+    // recomputed from what's left, like an assignment
+    assert_eq!(power(&app, &server_id, &bob), 10);
+    assert_eq!(power(&app, &server_id, &carol), 0);
+    assert_eq!(power(&app, &server_id, &dave), 10);
+
+    let (status, body) = app.request(Method::DELETE, "/servers/roles", Some(delete)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["errcode"], "M_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn deleting_a_role_needs_manage_roles() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = server(&app, &alice, &[&bob]).await;
+    set_roles(&app, &alice, &server_id, vec![role("mods", 50)]).await;
+
+    let delete = json!({ "access_token": bob.access_token, "server_id": server_id, "role_id": "mods" });
+    let (status, body) = app.request(Method::DELETE, "/servers/roles", Some(delete)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["permission"], "manage_roles");
+}
+
+async fn roles(app: &TestApp, viewer: &TestUser, server_id: &str) -> Value {
+    let (status, body) = app.get(&format!("/servers/roles?access_token={}&server_id={}", viewer.access_token, enc(server_id))).await;
+    assert_eq!(status, StatusCode::OK);
+    body
+}
+
+fn ids(roles: &Value) -> Vec<&str> {
+    roles["roles"].as_array().unwrap().iter().map(|r| r["id"].as_str().unwrap()).collect()
+}
+
+/// admins (3) > mods (2, manage_roles) > helpers (1), with bob a mod
+async fn ranked_server(app: &TestApp, alice: &TestUser, bob: &TestUser, carol: &TestUser) -> String {
+    let server_id = server(app, alice, &[bob, carol]).await;
+    let ranks = vec![
+        ranked("helpers", 10, 1, "send_messages"),
+        ranked("admins", 100, 3, "administrator"),
+        ranked("mods", 50, 2, "manage_roles"),
+    ];
+    set_roles(app, alice, server_id.as_str(), ranks).await;
+    assign(app, alice, &server_id, bob, &["mods"]).await;
+    server_id
+}
+
+fn assert_out_of_reach(response: (StatusCode, Value), role_id: &str) {
+    let (status, body) = response;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(body["errcode"], "AGORA_ROLE_HIERARCHY");
+    assert_eq!(body["role_id"], role_id);
+}
+
+#[tokio::test]
+async fn roles_are_listed_highest_first() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let server_id = ranked_server(&app, &alice, &bob, &carol).await;
+    assert_eq!(ids(&roles(&app, &alice, &server_id).await), ["admins", "mods", "helpers"]);
+}
+
+#[tokio::test]
+async fn a_moderator_cannot_reach_their_own_rank_or_above() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let server_id = ranked_server(&app, &alice, &bob, &carol).await;
+
+    let grant = |user: &TestUser, role_ids: &[&str]| {
+        json!({ "access_token": bob.access_token, "server_id": server_id, "user_id": user.user_id, "role_ids": role_ids })
+    };
+    assert_out_of_reach(app.post("/servers/members/roles", grant(&bob, &["mods", "admins"])).await, "admins");
+    assert_out_of_reach(app.post("/servers/members/roles", grant(&carol, &["mods"])).await, "mods");
+    // nor take their own role away from someone
+    assert_out_of_reach(app.post("/servers/members/roles", grant(&bob, &[])).await, "mods");
+    assert_eq!(member_roles(&app, &alice, &server_id, &bob).await, json!(["mods"]));
+    assert_eq!(app.post("/servers/members/roles", grant(&carol, &["helpers"])).await.0, StatusCode::OK);
+    assert_eq!(member_roles(&app, &alice, &server_id, &carol).await, json!(["helpers"]));
+
+    let current = roles(&app, &bob, &server_id).await;
+    let edit = |roles: Value| {
+        json!({ "access_token": bob.access_token, "server_id": server_id, "roles": roles, "revision": current["revision"] })
+    };
+    let mut promoted = current["roles"].clone();
+    promoted[1]["permissions"]["administrator"] = json!(true);
+    assert_out_of_reach(app.post("/servers/roles", edit(promoted)).await, "mods");
+    let mut above = current["roles"].clone();
+    above.as_array_mut().unwrap().push(ranked("owners", 100, 5, "administrator"));
+    assert_out_of_reach(app.post("/servers/roles", edit(above)).await, "owners");
+    let mut renamed = current["roles"].clone();
+    renamed[2]["name"] = json!("Helpers");
+    let (status, body) = app.post("/servers/roles", edit(renamed)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let delete = json!({ "access_token": bob.access_token, "server_id": server_id, "role_id": "admins" });
+    assert_out_of_reach(app.request(Method::DELETE, "/servers/roles", Some(delete)).await, "admins");
+}
+
+#[tokio::test]
+async fn reordering_renumbers_positions() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let server_id = ranked_server(&app, &alice, &bob, &carol).await;
+    let revision = roles(&app, &alice, &server_id).await["revision"].clone();
+
+    let reorder = |user: &TestUser, role_ids: &[&str], revision: &Value| {
+        json!({ "access_token": user.access_token, "server_id": server_id, "role_ids": role_ids, "revision": revision })
+    };
+    let (status, body) = app.post("/servers/roles/reorder", reorder(&alice, &["admins", "helpers"], &revision)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+
+    let (status, body) = app.post("/servers/roles/reorder", reorder(&alice, &["helpers", "admins", "mods"], &revision)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let listed = roles(&app, &alice, &server_id).await;
+    assert_eq!(ids(&listed), ["helpers", "admins", "mods"]);
+    let positions: Vec<&Value> = listed["roles"].as_array().unwrap().iter().map(|r| &r["position"]).collect();
+    assert_eq!(positions, [&json!(2), &json!(1), &json!(0)]);
+
+    // a stale order is refused with the current one
+    let (status, body) = app.post("/servers/roles/reorder", reorder(&alice, &["admins", "mods", "helpers"], &revision)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(ids(&body), ["helpers", "admins", "mods"]);
+
+    // bob (mods, now lowest) can't lift anything over himself
+    let revision = listed["revision"].clone();
+    let lifted = reorder(&bob, &["helpers", "mods", "admins"], &revision);
+    assert_out_of_reach(app.post("/servers/roles/reorder", lifted).await, "mods");
+}
+
+#[tokio::test]
+async fn joiners_get_the_default_role() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let server_id = server(&app, &alice, &[]).await;
+    set_roles(&app, &alice, &server_id, vec![role("mods", 50), role("members", 10)]).await;
+
+    let meta = |role_id: &str| json!({ "access_token": alice.access_token, "server_id": server_id, "default_role_id": role_id });
+    assert_eq!(app.post("/servers/meta", meta("nobody")).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(app.post("/servers/meta", meta("members")).await.0, StatusCode::OK);
+    let listed = roles(&app, &alice, &server_id).await;
+    let flagged: Vec<&Value> = listed["roles"].as_array().unwrap().iter().filter(|r| r["is_default"] == true).collect();
+    assert_eq!(flagged.len(), 1);
+    assert_eq!(flagged[0]["id"], "members");
+
+    // bob joins with nothing and gets it; carol's roles are hers already
+    assign(&app, &alice, &server_id, &carol, &["mods"]).await;
+    for member in [&bob, &carol] {
+        app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": server_id })).await;
+    }
+    assert_eq!(member_roles(&app, &alice, &server_id, &bob).await, json!(["members"]));
+    assert_eq!(power(&app, &server_id, &bob), 10);
+    assert_eq!(member_roles(&app, &alice, &server_id, &carol).await, json!(["mods"]));
+
+    let (status, body) = app
+        .post("/servers/members/joined", json!({ "access_token": bob.access_token, "server_id": server_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["role_ids"], json!(["members"]));
+
+    // deleting the role stops it being handed out
+    let delete = json!({ "access_token": alice.access_token, "server_id": server_id, "role_id": "members" });
+    assert_eq!(app.request(Method::DELETE, "/servers/roles", Some(delete)).await.0, StatusCode::OK);
+    let (_, meta) = app
+        .get(&format!("/servers/meta?access_token={}&server_id={}", alice.access_token, enc(&server_id)))
+        .await;
+    assert_eq!(meta["default_role_id"], Value::Null);
+}
+
+#[tokio::test]
+async fn a_stale_role_edit_conflicts_unless_forced() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = server(&app, &alice, &[&bob]).await;
+    set_roles(&app, &alice, &server_id, vec![ranked("admins", 50, 0, "administrator")]).await;
+    assign(&app, &alice, &server_id, &bob, &["admins"]).await;
+
+    // both open the settings screen on the same revision
+    let loaded = roles(&app, &bob, &server_id).await;
+    let base = loaded["revision"].as_u64().unwrap();
+    let save = |user: &TestUser, extra: &str, revision: Value, force: bool| {
+        let mut roles = loaded["roles"].clone();
+        roles.as_array_mut().unwrap().push(role(extra, 10));
+        json!({ "access_token": user.access_token, "server_id": server_id, "roles": roles, "revision": revision, "force": force })
+    };
+
+    let (status, body) = app.post("/servers/roles", save(&alice, "helpers", json!(base), false)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["revision"], base + 1);
+
+    // bob's save is based on what alice replaced: 409 with her roles to merge into
+    let (status, body) = app.post("/servers/roles", save(&bob, "guests", json!(base), false)).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["errcode"], "AGORA_REVISION_CONFLICT");
+    assert_eq!(body["revision"], base + 1);
+    assert_eq!(ids(&body), ["admins", "helpers"]);
+    // a save that doesn't say what it's based on can't be checked either
+    let (status, _) = app.post("/servers/roles", save(&bob, "guests", Value::Null, false)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    // forcing it through is for the owner only
+    let (status, _) = app.post("/servers/roles", save(&bob, "guests", json!(base), true)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(ids(&roles(&app, &alice, &server_id).await), ["admins", "helpers"]);
+
+    let (status, body) = app.post("/servers/roles", save(&bob, "guests", json!(base + 1), false)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = app.post("/servers/roles", save(&alice, "owners", json!(base), true)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["revision"], base + 3);
+    let current = roles(&app, &alice, &server_id).await;
+    assert_eq!(ids(&current), ["admins", "owners"]);
+    assert_eq!(current["revision"], base + 3);
+}
@@ -0,0 +1,104 @@
+// the aliases channels get from their names, and checking one is free
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, mut body: Value) -> Value {
+    body["access_token"] = json!(owner.access_token);
+    let (status, created) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", created);
+    created
+}
+
+fn canonical_alias(app: &TestApp, room_id: &str) -> Value {
+    let key = ("m.room.canonical_alias".to_string(), String::new());
+    app.homeserver
+        .inspect(|hs| hs.rooms[room_id].state.get(&key).map(|e| e["content"]["alias"].clone()))
+        .unwrap_or_default()
+}
+
+async fn check(app: &TestApp, user: &TestUser, alias: &str) -> (StatusCode, Value) {
+    let uri = format!("/rooms/alias/check?alias={}", urlencoding::encode(alias));
+    app.authed(user, Method::GET, &uri, None).await
+}
+
+#[tokio::test]
+async fn channels_with_a_taken_name_get_a_suffixed_alias() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let first = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let first_id = first["room_id"].as_str().unwrap();
+    assert!(first.get("alias").is_none(), "servers get their alias from the vanity slug");
+    let general = create(&app, &alice, json!({ "name": "General", "parent_space_id": first_id })).await;
+    assert_eq!(general["alias"], "#general:localhost");
+    assert_eq!(canonical_alias(&app, general["room_id"].as_str().unwrap()), "#general:localhost");
+
+    // the second server has a vanity slug to tell its #general apart
+    let second = create(&app, &alice, json!({ "name": "Band", "is_space": true })).await;
+    let second_id = second["room_id"].as_str().unwrap();
+    let meta = json!({ "access_token": alice.access_token, "server_id": second_id, "vanity_slug": "band" });
+    assert_eq!(app.post("/servers/meta", meta).await.0, StatusCode::OK);
+    let band_general = create(&app, &alice, json!({ "name": "general", "parent_space_id": second_id })).await;
+    let band_general_id = band_general["room_id"].as_str().unwrap().to_string();
+    assert_eq!(band_general["alias"], "#general-band:localhost");
+    assert_eq!(
+        app.homeserver.inspect(|hs| hs.aliases.get("#general-band:localhost").cloned()),
+        Some(band_general_id.clone())
+    );
+
+    // the third doesn't, and gets a few characters of the room id
+    let third = create(&app, &alice, json!({ "name": "Club", "is_space": true })).await;
+    let club_general = create(&app, &alice, json!({ "name": "general", "parent_space_id": third["room_id"] })).await;
+    let alias = club_general["alias"].as_str().unwrap();
+    let suffix = alias.strip_prefix("#general-").and_then(|a| a.strip_suffix(":localhost")).unwrap();
+    assert_eq!(suffix.len(), 4);
+    assert!(suffix.chars().all(|c| c.is_ascii_hexdigit()), "{}", alias);
+    assert_eq!(canonical_alias(&app, club_general["room_id"].as_str().unwrap()), alias);
+
+    // nobody lost the plain one
+    assert_eq!(
+        app.homeserver.inspect(|hs| hs.aliases.get("#general:localhost").cloned()).as_deref(),
+        general["room_id"].as_str()
+    );
+}
+
+#[tokio::test]
+async fn names_without_alias_characters_get_no_alias() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room = create(&app, &alice, json!({ "name": "!!!" })).await;
+    assert!(room.get("alias").is_none());
+    assert!(canonical_alias(&app, room["room_id"].as_str().unwrap()).is_null());
+}
+
+#[tokio::test]
+async fn checking_an_alias() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    // a channel name is checked as the alias create_room would try
+    let (status, body) = check(&app, &alice, "Off Topic").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body, json!({ "alias": "#off-topic:localhost", "available": true }));
+
+    let room = create(&app, &alice, json!({ "name": "off topic" })).await;
+    let (status, body) = check(&app, &alice, "#off-topic:localhost").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["available"], false);
+    assert_eq!(body["room_id"], room["room_id"]);
+
+    // a full alias without a server part is on ours
+    let (_, body) = check(&app, &alice, "#off-topic").await;
+    assert_eq!(body["alias"], "#off-topic:localhost");
+    assert_eq!(body["available"], false);
+
+    for invalid in ["!!!", "#", "#:localhost", "#two words:localhost"] {
+        let (status, body) = check(&app, &alice, invalid).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", invalid);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+}
@@ -0,0 +1,143 @@
+// the joined rooms list: dms told apart from channels, each room's last
+// message, and sorting by activity
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn create(app: &TestApp, user: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(user.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, content: &str) -> String {
+    let (status, body) = app
+        .post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": content }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["event_id"].as_str().unwrap().to_string()
+}
+
+/// /rooms as a list, with `query` appended
+async fn listed(app: &TestApp, user: &TestUser, query: &str) -> Vec<Value> {
+    let (status, body) = app.get(&format!("/rooms?access_token={}{}", user.access_token, query)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["rooms"].as_array().unwrap().clone()
+}
+
+fn room<'a>(rooms: &'a [Value], room_id: &str) -> &'a Value {
+    rooms.iter().find(|r| r["room_id"] == room_id).unwrap()
+}
+
+/// give an event a timestamp of its own, so orderings don't hang on the clock
+fn stamp(app: &TestApp, event_id: &str, ts: i64) {
+    let mut hs = app.homeserver.state.lock().unwrap();
+    let (_, event) = hs.timeline.iter_mut().find(|(_, e)| e["event_id"] == event_id).unwrap();
+    event["origin_server_ts"] = json!(ts);
+}
+
+#[sqlx::test]
+async fn dms_are_told_apart_from_channels(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let add = json!({ "access_token": alice.access_token, "friend_id": bob.user_id });
+    assert_eq!(app.post("/friends/add", add).await.0, StatusCode::OK);
+    let accept = json!({ "access_token": bob.access_token, "friend_id": alice.user_id });
+    assert_eq!(app.post("/friends/accept", accept).await.0, StatusCode::OK);
+    let (_, dm) = app
+        .post("/friends/dm", json!({ "access_token": alice.access_token, "friend_id": bob.user_id }))
+        .await;
+    let dm_id = dm["room_id"].as_str().unwrap();
+    let channel_id = create(&app, &alice, json!({ "name": "general" })).await;
+
+    let rooms = listed(&app, &alice, "").await;
+    assert_eq!(room(&rooms, dm_id)["is_direct"], true);
+    assert_eq!(room(&rooms, &channel_id)["is_direct"], false);
+
+    // bob's m.direct doesn't list it until he opens it, but the friendship does
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": dm_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    assert_eq!(room(&listed(&app, &bob, "").await, dm_id)["is_direct"], true);
+}
+
+#[tokio::test]
+async fn rooms_show_their_last_message_and_sort_by_it() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let quiet = create(&app, &alice, json!({ "name": "quiet" })).await;
+    let older = create(&app, &alice, json!({ "name": "older" })).await;
+    let newer = create(&app, &alice, json!({ "name": "newer" })).await;
+
+    let first = send(&app, &alice, &older, "first").await;
+    stamp(&app, &first, 1_000);
+    let hey = send(&app, &alice, &newer, "hey").await;
+    stamp(&app, &hey, 2_000);
+    let long = send(&app, &alice, &older, &"a".repeat(300)).await;
+    stamp(&app, &long, 3_000);
+
+    let rooms = listed(&app, &alice, "").await;
+    assert_eq!(room(&rooms, &newer)["last_message"], json!({ "sender": alice.user_id, "body": "hey", "ts": 2_000 }));
+    assert_eq!(room(&rooms, &older)["last_message"]["body"], "a".repeat(140));
+    assert_eq!(room(&rooms, &older)["last_activity_ts"], 3_000);
+    assert!(room(&rooms, &quiet).get("last_message").is_none());
+
+    let sorted = listed(&app, &alice, "&sort=activity").await;
+    let order: Vec<&str> = sorted.iter().map(|r| r["room_id"].as_str().unwrap()).collect();
+    assert_eq!(order, [older.as_str(), newer.as_str(), quiet.as_str()]);
+    let (status, _) = app.get(&format!("/rooms?access_token={}&sort=sideways", alice.access_token)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // an edit is activity, not a new last message; a redacted message falls away
+    let edit = json!({ "access_token": alice.access_token, "room_id": newer, "event_id": hey, "content": "hey!" });
+    assert_eq!(app.post("/rooms/edit", edit).await.0, StatusCode::OK);
+    let redact = json!({ "access_token": alice.access_token, "room_id": older, "event_id": long });
+    assert_eq!(app.post("/rooms/redact", redact).await.0, StatusCode::OK);
+    let rooms = listed(&app, &alice, "").await;
+    assert_eq!(room(&rooms, &newer)["last_message"]["body"], "hey");
+    assert!(room(&rooms, &newer)["last_activity_ts"].as_i64().unwrap() > 2_000);
+    assert_eq!(room(&rooms, &older)["last_message"]["body"], "first");
+}
+
+#[tokio::test]
+async fn previews_are_cached_only_where_every_member_sees_them() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let open = create(&app, &alice, json!({ "name": "open" })).await;
+    let staff = create(&app, &alice, json!({ "name": "staff", "private": true, "history_visibility": "invited" })).await;
+    send(&app, &alice, &open, "one").await;
+    send(&app, &alice, &staff, "one").await;
+    listed(&app, &alice, "").await;
+    assert!(app.redis.lock().unwrap().contains_key(&format!("room_preview:{}", open)));
+    assert!(!app.redis.lock().unwrap().contains_key(&format!("room_preview:{}", staff)));
+
+    // messages from other matrix clients show once the cached preview expires
+    let sent_elsewhere = |room_id: &str| {
+        let mut hs = app.homeserver.state.lock().unwrap();
+        let event = json!({
+            "type": "m.room.message",
+            "sender": alice.user_id,
+            "content": { "msgtype": "m.text", "body": "two" },
+            "event_id": format!("$elsewhere{}", hs.timeline.len()),
+            "origin_server_ts": chrono::Utc::now().timestamp_millis(),
+        });
+        hs.timeline.push((room_id.to_string(), event));
+    };
+    sent_elsewhere(&open);
+    sent_elsewhere(&staff);
+    let rooms = listed(&app, &alice, "").await;
+    assert_eq!(room(&rooms, &open)["last_message"]["body"], "one");
+    assert_eq!(room(&rooms, &staff)["last_message"]["body"], "two");
+    app.redis.lock().unwrap().clear();
+    assert_eq!(room(&listed(&app, &alice, "").await, &open)["last_message"]["body"], "two");
+
+    // sending through agora shows straight away
+    send(&app, &alice, &open, "three").await;
+    assert_eq!(room(&listed(&app, &alice, "").await, &open)["last_message"]["body"], "three");
+}
@@ -0,0 +1,128 @@
+// renaming channels and changing their topic / avatar after creation
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::json;
+
+async fn channel(app: &TestApp, owner: &TestUser, name: &str) -> String {
+    let (status, room) = app
+        .post("/rooms/create", json!({ "access_token": owner.access_token, "name": name }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn settings_are_written_and_returned() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice, "general").await;
+
+    let (status, room) = app
+        .post("/rooms/settings", json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "name": "  lobby ",
+            "topic": "say hi",
+            "avatar_url": "mxc://localhost/avatar",
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    assert_eq!(room["room_id"], room_id.as_str());
+    assert_eq!(room["name"], "lobby");
+    assert_eq!(room["topic"], "say hi");
+    assert_eq!(room["avatar_url"], "mxc://localhost/avatar");
+    assert_eq!(room["channel_type"], "text");
+
+    // left-out fields stay, an empty avatar removes it
+    let (status, room) = app
+        .post("/rooms/settings", json!({ "access_token": alice.access_token, "room_id": room_id, "avatar_url": "" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(room["name"], "lobby");
+    assert_eq!(room["topic"], "say hi");
+    assert!(room["avatar_url"].is_null());
+}
+
+#[tokio::test]
+async fn invalid_settings_are_rejected() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let room_id = channel(&app, &alice, "general").await;
+
+    let (status, body) = app
+        .post("/rooms/settings", json!({ "access_token": alice.access_token, "room_id": room_id, "name": " " }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+
+    let (status, _) = app
+        .post("/rooms/settings", json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "avatar_url": "https://example.org/cat.png",
+        }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // bob isn't in the room, let alone allowed to change it
+    let (status, _) = app
+        .post("/rooms/settings", json!({ "access_token": bob.access_token, "room_id": room_id, "name": "mine" }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn renaming_moves_a_name_derived_alias_and_keeps_the_old_one() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice, "General Chat").await;
+
+    // the alias create_room gave it
+    assert_eq!(app.homeserver.inspect(|hs| hs.aliases.get("#general-chat:localhost").cloned()), Some(room_id.clone()));
+
+    let (status, _) = app
+        .post("/rooms/settings", json!({ "access_token": alice.access_token, "room_id": room_id, "name": "Off Topic" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    app.homeserver.inspect(|hs| {
+        assert_eq!(hs.aliases.get("#off-topic:localhost"), Some(&room_id));
+        assert_eq!(hs.aliases.get("#general-chat:localhost"), Some(&room_id));
+        let canonical = &hs.rooms[&room_id].state[&("m.room.canonical_alias".to_string(), String::new())]["content"];
+        assert_eq!(canonical["alias"], "#off-topic:localhost");
+        assert_eq!(canonical["alt_aliases"], json!(["#general-chat:localhost"]));
+    });
+}
+
+#[tokio::test]
+async fn a_hand_picked_alias_is_left_alone() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel(&app, &alice, "general").await;
+
+    {
+        let mut hs = app.homeserver.state.lock().unwrap();
+        hs.aliases.insert("#cool-place:localhost".to_string(), room_id.clone());
+        let event = json!({
+            "type": "m.room.canonical_alias",
+            "state_key": "",
+            "sender": alice.user_id,
+            "content": { "alias": "#cool-place:localhost" },
+        });
+        hs.rooms.get_mut(&room_id).unwrap().state.insert(("m.room.canonical_alias".to_string(), String::new()), event);
+    }
+
+    let (status, _) = app
+        .post("/rooms/settings", json!({ "access_token": alice.access_token, "room_id": room_id, "name": "lobby" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    app.homeserver.inspect(|hs| {
+        assert!(!hs.aliases.contains_key("#lobby:localhost"));
+        let canonical = &hs.rooms[&room_id].state[&("m.room.canonical_alias".to_string(), String::new())]["content"];
+        assert_eq!(canonical["alias"], "#cool-place:localhost");
+    });
+}
@@ -0,0 +1,97 @@
+// /sync/room: one channel's new events, without the rest of the account
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn room(app: &TestApp, owner: &TestUser, name: &str) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": owner.access_token, "name": name })).await;
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, content: &str) {
+    let body = json!({ "access_token": user.access_token, "room_id": room_id, "content": content });
+    assert_eq!(app.post("/rooms/send", body).await.0, StatusCode::OK);
+}
+
+async fn room_sync(app: &TestApp, user: &TestUser, room_id: &str, query: &str) -> Value {
+    let (status, sync) = app
+        .get(&format!("/sync/room?access_token={}&room_id={}{}", user.access_token, enc(room_id), query))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", sync);
+    sync
+}
+
+fn contents(sync: &Value) -> Vec<&str> {
+    sync["messages"].as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn only_the_open_room_comes_back() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let general = room(&app, &alice, "general").await;
+    send(&app, &alice, &general, "hello general").await;
+    for i in 0..5 {
+        let other = room(&app, &alice, &format!("busy {}", i)).await;
+        for j in 0..5 {
+            send(&app, &alice, &other, &format!("noise {} in busy {}", j, i)).await;
+        }
+    }
+
+    let sync = room_sync(&app, &alice, &general, "").await;
+    assert_eq!(sync["room_id"], general.as_str());
+    assert_eq!(contents(&sync), ["hello general"]);
+    assert_eq!(sync["limited"], false);
+
+    // the whole account's sync carries every other room's traffic too
+    let (_, full) = app.get(&format!("/sync?access_token={}", alice.access_token)).await;
+    assert_eq!(full["messages"].as_array().unwrap().len(), 26);
+    let size = |v: &Value| serde_json::to_vec(v).unwrap().len();
+    assert!(size(&sync) * 10 < size(&full), "{} vs {} bytes", size(&sync), size(&full));
+
+    // nothing new since then
+    let since = sync["next_batch"].as_str().unwrap();
+    let quiet = room_sync(&app, &alice, &general, &format!("&since={}", since)).await;
+    assert_eq!(quiet["messages"], json!([]));
+    assert_eq!(quiet["prev_batch"], Value::Null);
+}
+
+#[tokio::test]
+async fn a_limited_room_hands_over_to_history() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let general = room(&app, &alice, "general").await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": general })).await;
+    let since = room_sync(&app, &bob, &general, "").await["next_batch"].as_str().unwrap().to_string();
+
+    for i in 1..=5 {
+        send(&app, &alice, &general, &format!("message {}", i)).await;
+    }
+    let sync = room_sync(&app, &bob, &general, &format!("&since={}&limit=2", since)).await;
+    assert_eq!(contents(&sync), ["message 4", "message 5"]);
+    assert_eq!(sync["limited"], true);
+
+    let prev_batch = sync["prev_batch"].as_str().expect("a token for older messages");
+    let (status, page) = app
+        .get(&format!(
+            "/rooms/messages?access_token={}&room_id={}&limit=3&from={}",
+            bob.access_token,
+            enc(&general),
+            enc(prev_batch)
+        ))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let bodies: Vec<&str> = page["messages"].as_array().unwrap().iter().map(|m| m["body"].as_str().unwrap()).collect();
+    assert_eq!(bodies, ["message 3", "message 2", "message 1"]);
+}
+
+#[tokio::test]
+async fn a_bad_token_is_unauthorized() {
+    let app = TestApp::new().await;
+    let (status, _) = app.get("/sync/room?access_token=nope&room_id=%21x%3Alocalhost").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,76 @@
+// room tags (favourites, low priority, user tags) and their order, listed with the rooms
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, user: &TestUser, name: &str) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": user.access_token, "name": name })).await;
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn tag(app: &TestApp, user: &TestUser, room_id: &str, tag: &str, order: Option<f64>) -> (StatusCode, Value) {
+    let body = json!({ "access_token": user.access_token, "room_id": room_id, "tag": tag, "order": order });
+    app.request(Method::PUT, "/rooms/tag", Some(body)).await
+}
+
+/// room id → tags, as /rooms lists them
+async fn listed_tags(app: &TestApp, user: &TestUser) -> Value {
+    let (status, body) = app.get(&format!("/rooms?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["rooms"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|room| (room["room_id"].as_str().unwrap().to_string(), room.get("tags").cloned().unwrap_or(Value::Null)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[tokio::test]
+async fn tagged_rooms_are_listed_with_their_tags() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let work = create(&app, &alice, "work").await;
+    let games = create(&app, &alice, "games").await;
+    let news = create(&app, &alice, "news").await;
+
+    assert_eq!(tag(&app, &alice, &work, "m.favourite", Some(0.25)).await.0, StatusCode::OK);
+    assert_eq!(tag(&app, &alice, &games, "m.favourite", Some(0.5)).await.0, StatusCode::OK);
+    assert_eq!(tag(&app, &alice, &games, "u.weekend", None).await.0, StatusCode::OK);
+    assert_eq!(tag(&app, &alice, &news, "m.lowpriority", None).await.0, StatusCode::OK);
+
+    let tags = listed_tags(&app, &alice).await;
+    assert_eq!(tags[&work], json!({ "m.favourite": { "order": 0.25 } }));
+    assert_eq!(tags[&games], json!({ "m.favourite": { "order": 0.5 }, "u.weekend": {} }));
+    assert_eq!(tags[&news], json!({ "m.lowpriority": {} }));
+
+    // dragging games above work
+    tag(&app, &alice, &games, "m.favourite", Some(0.125)).await;
+    let remove = json!({ "access_token": alice.access_token, "room_id": games, "tag": "u.weekend" });
+    assert_eq!(app.request(Method::DELETE, "/rooms/tag", Some(remove)).await.0, StatusCode::OK);
+    let tags = listed_tags(&app, &alice).await;
+    assert_eq!(tags[&games], json!({ "m.favourite": { "order": 0.125 } }));
+
+    // tags are per user
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": work });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    assert_eq!(listed_tags(&app, &bob).await[&work], Value::Null);
+}
+
+#[tokio::test]
+async fn only_known_tags_and_orders_in_range() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = create(&app, &alice, "lobby").await;
+
+    for (name, order) in [("m.server_notice", None), ("pinned", None), ("u.", None), ("m.favourite", Some(1.5)), ("m.favourite", Some(-0.1))] {
+        let (status, body) = tag(&app, &alice, &room_id, name, order).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{} {:?}", name, order);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+    assert!(app.homeserver.inspect(|hs| hs.room_tags.is_empty()));
+}
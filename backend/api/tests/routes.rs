@@ -0,0 +1,490 @@
+// route wiring and error mapping — no database needed
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::TestApp;
+use serde_json::json;
+
+/// every route the api mounts, as (method, path)
+const ROUTES: &[(&str, &str)] = &[
+    ("POST", "/register"),
+    ("POST", "/login"),
+    ("GET", "/whoami"),
+    ("POST", "/logout"),
+    ("POST", "/logout/all"),
+    ("POST", "/account/password"),
+    ("GET", "/devices"),
+    ("DELETE", "/devices"),
+    ("GET", "/admin/registration_tokens"),
+    ("POST", "/admin/registration_tokens"),
+    ("DELETE", "/admin/registration_tokens"),
+    ("GET", "/dms/search"),
+    ("GET", "/dms/recover"),
+    ("GET", "/account/email"),
+    ("POST", "/account/email"),
+    ("POST", "/account/email/prefs"),
+    ("GET", "/account/email/verify"),
+    ("GET", "/email/unsubscribe"),
+    ("GET", "/notifications/push-key"),
+    ("POST", "/notifications/subscribe"),
+    ("DELETE", "/notifications/subscribe"),
+    ("POST", "/notifications/test"),
+    ("GET", "/notifications/settings"),
+    ("PUT", "/rooms/notifications"),
+    ("GET", "/friends"),
+    ("POST", "/friends/add"),
+    ("POST", "/friends/accept"),
+    ("POST", "/friends/reject"),
+    ("DELETE", "/friends/remove"),
+    ("POST", "/friends/dm"),
+    ("POST", "/friends/block"),
+    ("POST", "/friends/unblock"),
+    ("GET", "/friends/blocked"),
+    ("GET", "/users/search"),
+    ("GET", "/users/mutual"),
+    ("GET", "/settings"),
+    ("PUT", "/settings"),
+    ("GET", "/health"),
+    ("GET", "/health/ready"),
+    ("GET", "/health/migrations"),
+    ("GET", "/health/features"),
+    ("POST", "/media/upload"),
+    ("GET", "/preview/url"),
+    ("GET", "/ws/presence"),
+    ("GET", "/ws/metrics"),
+    ("GET", "/ws/events"),
+    ("GET", "/rooms"),
+    ("POST", "/rooms/create"),
+    ("POST", "/rooms/join"),
+    ("GET", "/rooms/alias/check"),
+    ("POST", "/rooms/leave"),
+    ("POST", "/rooms/delete"),
+    ("GET", "/rooms/members"),
+    ("POST", "/rooms/invite"),
+    ("POST", "/rooms/invite/accept"),
+    ("POST", "/rooms/invite/reject"),
+    ("GET", "/rooms/messages"),
+    ("POST", "/rooms/send"),
+    ("POST", "/rooms/edit"),
+    ("POST", "/rooms/redact"),
+    ("POST", "/rooms/report"),
+    ("POST", "/rooms/react"),
+    ("POST", "/rooms/unreact"),
+    ("GET", "/rooms/reactions"),
+    ("GET", "/rooms/children"),
+    ("POST", "/rooms/add_child"),
+    ("POST", "/rooms/remove_child"),
+    ("POST", "/rooms/reorder"),
+    ("GET", "/rooms/state"),
+    ("POST", "/rooms/settings"),
+    ("GET", "/rooms/slowmode"),
+    ("POST", "/rooms/slowmode"),
+    ("POST", "/rooms/category/create"),
+    ("GET", "/rooms/permissions"),
+    ("POST", "/rooms/permissions"),
+    ("GET", "/rooms/permissions/overrides"),
+    ("POST", "/rooms/permissions/overrides"),
+    ("POST", "/rooms/raid"),
+    ("PUT", "/rooms/tag"),
+    ("DELETE", "/rooms/tag"),
+    ("POST", "/rooms/history_visibility"),
+    ("POST", "/rooms/typing"),
+    ("POST", "/rooms/announce"),
+    ("POST", "/rooms/announce/follow"),
+    ("DELETE", "/rooms/announce/follow"),
+    ("GET", "/rooms/announce/followers"),
+    ("GET", "/servers/meta"),
+    ("POST", "/servers/meta"),
+    ("GET", "/servers/welcome"),
+    ("POST", "/servers/welcome"),
+    ("GET", "/servers/settings"),
+    ("POST", "/servers/settings"),
+    ("GET", "/servers/vibes"),
+    ("POST", "/servers/vibes"),
+    ("GET", "/servers/roles"),
+    ("DELETE", "/servers/roles"),
+    ("POST", "/servers/roles"),
+    ("POST", "/servers/roles/reorder"),
+    ("GET", "/servers/members"),
+    ("GET", "/servers/members/roles"),
+    ("POST", "/servers/members/roles"),
+    ("POST", "/servers/members/joined"),
+    ("POST", "/servers/members/kick"),
+    ("POST", "/servers/members/ban"),
+    ("POST", "/servers/members/unban"),
+    ("GET", "/servers/bans"),
+    ("POST", "/servers/members/timeout"),
+    ("DELETE", "/servers/members/timeout"),
+    ("GET", "/servers/reports"),
+    ("POST", "/servers/reports/resolve"),
+    ("GET", "/servers/forum/threads"),
+    ("POST", "/servers/forum/thread"),
+    ("POST", "/servers/forum/thread/update"),
+    ("POST", "/servers/forum/thread/tags"),
+    ("GET", "/servers/forum/tags"),
+    ("POST", "/servers/forum/tags"),
+    ("POST", "/servers/delete"),
+    ("GET", "/servers/events"),
+    ("DELETE", "/servers/events"),
+    ("POST", "/servers/events/create"),
+    ("POST", "/servers/events/rsvp"),
+    ("GET", "/servers/invite"),
+    ("GET", "/servers/preview"),
+    ("GET", "/servers/webhooks"),
+    ("DELETE", "/servers/webhooks"),
+    ("POST", "/servers/webhooks/create"),
+    ("POST", "/webhooks/some-id/some-token"),
+    ("GET", "/servers/templates"),
+    ("POST", "/servers/create_from_template"),
+    ("GET", "/search/messages"),
+    ("GET", "/sync"),
+    ("GET", "/sync/room"),
+    ("POST", "/presence/set"),
+    ("GET", "/presence/get"),
+    ("POST", "/presence/heartbeat"),
+    ("GET", "/profile/get"),
+    ("PUT", "/profile/set"),
+    ("PUT", "/profile/avatar"),
+    ("GET", "/profile/batch"),
+    ("POST", "/voice/token"),
+    ("GET", "/voice/participants"),
+    ("GET", "/voice/states"),
+    ("POST", "/voice/webhook"),
+    ("POST", "/voice/moderate"),
+    ("GET", "/voice/stage"),
+    ("POST", "/voice/stage/speakers"),
+    ("POST", "/voice/stage/request"),
+    ("GET", "/voice/settings"),
+    ("POST", "/voice/settings"),
+    ("POST", "/voice/call"),
+    ("GET", "/voice/call/history"),
+    ("GET", "/voice/call/participants"),
+    ("GET", "/voice/vibe"),
+    ("POST", "/voice/vibe"),
+    ("GET", "/openapi.json"),
+];
+
+#[tokio::test]
+async fn every_route_is_mounted() {
+    let app = TestApp::new().await;
+    for (method, path) in ROUTES {
+        let method = Method::from_bytes(method.as_bytes()).unwrap();
+        // no body / no query: extractors reject, but never with "no such route"
+        let (status, _) = app.request(method.clone(), path, None).await;
+        assert!(
+            status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+            "{} {} is not mounted (got {})",
+            method,
+            path,
+            status
+        );
+    }
+}
+
+/// `path` fits the spec's `template`, where `{param}` stands for any one segment
+fn fits(template: &str, path: &str) -> bool {
+    let (template, path): (Vec<_>, Vec<_>) = (template.split('/').collect(), path.split('/').collect());
+    template.len() == path.len()
+        && template.iter().zip(&path).all(|(t, p)| t == p || (t.starts_with('{') && t.ends_with('}')))
+}
+
+/// every `$ref` in `value`
+fn refs<'a>(value: &'a serde_json::Value, out: &mut Vec<&'a str>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(serde_json::Value::String(target)) = map.get("$ref") {
+                out.push(target);
+            }
+            map.values().for_each(|v| refs(v, out));
+        }
+        serde_json::Value::Array(items) => items.iter().for_each(|v| refs(v, out)),
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn the_openapi_spec_covers_every_route() {
+    let app = TestApp::new().await;
+    let (status, body) = app.get("/openapi.json").await;
+    assert_eq!(status, StatusCode::OK);
+    let spec: utoipa::openapi::OpenApi = serde_json::from_value(body.clone()).expect("the spec doesn't parse");
+
+    let documented: Vec<(&str, &str)> = spec
+        .paths
+        .paths
+        .iter()
+        .flat_map(|(path, item)| {
+            [
+                ("GET", &item.get),
+                ("POST", &item.post),
+                ("PUT", &item.put),
+                ("DELETE", &item.delete),
+                ("PATCH", &item.patch),
+            ]
+            .into_iter()
+            .filter(|(_, operation)| operation.is_some())
+            .map(move |(method, _)| (method, path.as_str()))
+        })
+        .collect();
+
+    for (method, path) in ROUTES {
+        assert!(
+            documented.iter().any(|(m, template)| m == method && fits(template, path)),
+            "{} {} is missing from the spec",
+            method,
+            path
+        );
+    }
+    // and nothing documented that the router doesn't serve. an unmatched
+    // route is an empty 404; a handler's own 404 carries an errcode
+    for (method, template) in &documented {
+        let path = template.split('/').map(|s| if s.starts_with('{') { "x" } else { s }).collect::<Vec<_>>().join("/");
+        let (status, body) = app.request(Method::from_bytes(method.as_bytes()).unwrap(), &path, None).await;
+        assert!(
+            status != StatusCode::METHOD_NOT_ALLOWED && !(status == StatusCode::NOT_FOUND && body.is_null()),
+            "{} {} is documented but not served (got {})",
+            method,
+            template,
+            status
+        );
+    }
+
+    let mut targets = Vec::new();
+    refs(&body, &mut targets);
+    for target in targets {
+        let name = target.strip_prefix("#/components/schemas/").unwrap_or(target);
+        assert!(!body["components"]["schemas"][name].is_null(), "{} points nowhere", target);
+    }
+}
+
+#[tokio::test]
+async fn unknown_route_is_404() {
+    let app = TestApp::new().await;
+    let (status, _) = app.get("/definitely/not/a/route").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn database_endpoints_are_unavailable_without_a_database() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+
+    let (status, _) = app
+        .get(&format!("/friends?access_token={}&user_id={}", user.access_token, common::enc(&user.user_id)))
+        .await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    let (status, _) = app.get("/health/migrations").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn readiness_needs_the_homeserver_but_not_the_database() {
+    let app = TestApp::new().await;
+    let (status, body) = app.get("/health/ready").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["status"], "degraded");
+    assert_eq!(body["homeserver"]["status"], "ok");
+    assert_eq!(body["redis"]["status"], "ok");
+    assert_eq!(body["database"]["status"], "not_configured");
+
+    // nothing listens on port 1
+    let app = TestApp::with_config(|state| state.homeserver_url = "http://127.0.0.1:1".to_string()).await;
+    let (status, body) = app.get("/health/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "down");
+    assert_eq!(body["homeserver"]["status"], "down");
+    assert!(body["homeserver"]["error"].is_string());
+    // liveness doesn't care
+    assert_eq!(app.get("/health").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn login_errors_map_to_unauthorized() {
+    let app = TestApp::new().await;
+    app.register("alice").await;
+
+    let (status, _) = app.post("/login", json!({ "username": "alice", "password": "wrong" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user_id"], "@alice:localhost");
+    assert_eq!(body["home_server"], "localhost");
+}
+
+#[tokio::test]
+async fn duplicate_registration_is_rejected() {
+    let app = TestApp::new().await;
+    app.register("alice").await;
+    let (status, _) = app.post("/register", json!({ "username": "alice", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn malformed_json_is_rejected() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+    // missing required fields
+    let (status, _) = app.post("/rooms/create", json!({ "access_token": user.access_token, "topic": "no name" })).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn sessions_come_from_the_bearer_header() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+
+    let (status, body) = app.authed(&user, Method::GET, "/whoami", None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["user_id"], user.user_id.as_str());
+    let (status, body) = app.authed(&user, Method::POST, "/rooms/create", Some(json!({ "name": "lobby" }))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // the body token still works while clients move over
+    let (status, _) = app.post("/rooms/create", json!({ "access_token": user.access_token, "name": "old" })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = app.post("/rooms/create", json!({ "name": "no token" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["errcode"], "M_MISSING_TOKEN");
+    let (status, _) = app.get("/whoami?access_token=nope").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn features_report_the_homeserver_upload_limit() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+
+    // nothing cached and no token to fetch with
+    let (_, body) = app.get("/health/features").await;
+    assert!(body["max_upload_size"].is_null());
+
+    let (status, body) = app.get(&format!("/health/features?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["max_upload_size"], common::homeserver::UPLOAD_LIMIT);
+
+    // now cached for unauthenticated callers too
+    let (_, body) = app.get("/health/features").await;
+    assert_eq!(body["max_upload_size"], common::homeserver::UPLOAD_LIMIT);
+}
+
+#[tokio::test]
+async fn logout_invalidates_the_token_and_clears_presence() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+
+    let (status, _) = app
+        .post("/presence/set", json!({ "access_token": user.access_token, "user_id": user.user_id, "presence": "online" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(app.redis.lock().unwrap().contains_key(&format!("presence:{}", user.user_id)));
+
+    let (status, _) = app.post("/logout", json!({ "access_token": user.access_token })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!app.homeserver.inspect(|hs| hs.tokens.contains_key(&user.access_token)));
+    assert!(!app.redis.lock().unwrap().contains_key(&format!("presence:{}", user.user_id)));
+
+    // a token the homeserver no longer knows still logs out cleanly
+    let (status, _) = app.post("/logout", json!({ "access_token": user.access_token })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logout_all_ends_every_session() {
+    let app = TestApp::new().await;
+    let first = app.register("alice").await;
+    let (_, second) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    let second = second["access_token"].as_str().unwrap().to_string();
+
+    let (status, _) = app.post("/logout/all", json!({ "access_token": first.access_token })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(app.homeserver.inspect(|hs| !hs.tokens.contains_key(&first.access_token) && !hs.tokens.contains_key(&second)));
+}
+
+#[tokio::test]
+async fn password_change_checks_the_old_password() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+    let (_, other) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    let other = other["access_token"].as_str().unwrap().to_string();
+
+    let (status, body) = app
+        .post("/account/password", json!({
+            "access_token": user.access_token,
+            "old_password": "wrong",
+            "new_password": "correct horse",
+        }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    let (status, _) = app
+        .post("/account/password", json!({
+            "access_token": user.access_token,
+            "old_password": "hunter2",
+            "new_password": "correct horse",
+            "logout_devices": true,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the other session is gone, this one survives
+    assert!(app.homeserver.inspect(|hs| hs.tokens.contains_key(&user.access_token) && !hs.tokens.contains_key(&other)));
+    let (status, _) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = app.post("/login", json!({ "username": "alice", "password": "correct horse" })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn devices_can_be_listed_and_revoked() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+    let (_, other) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    let other_device = other["device_id"].as_str().unwrap().to_string();
+
+    let (status, body) = app.get(&format!("/devices?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    let devices = body["devices"].as_array().unwrap();
+    assert_eq!(devices.len(), 2);
+    let current: Vec<&str> = devices.iter().filter(|d| d["current"] == true).filter_map(|d| d["device_id"].as_str()).collect();
+    assert_eq!(current.len(), 1);
+    assert_ne!(current[0], other_device);
+    let own_device = current[0].to_string();
+
+    let delete = |device_id: &str, password: &str| {
+        json!({ "access_token": user.access_token, "device_id": device_id, "password": password })
+    };
+
+    let (status, _) = app.request(Method::DELETE, "/devices", Some(delete(&other_device, "wrong"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = app.request(Method::DELETE, "/devices", Some(delete(&other_device, "hunter2"))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["logged_out"], false);
+
+    // revoking the session in use is a logout
+    let (status, body) = app.request(Method::DELETE, "/devices", Some(delete(&own_device, "hunter2"))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["logged_out"], true);
+    assert!(app.homeserver.inspect(|hs| hs.tokens.is_empty()));
+}
+
+#[tokio::test]
+async fn whoami_validates_the_token() {
+    let app = TestApp::new().await;
+    let user = app.register("alice").await;
+
+    let (status, body) = app.get(&format!("/whoami?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user_id"], user.user_id.as_str());
+    assert!(body["device_id"].is_string());
+
+    let (status, body) = app.get("/whoami?access_token=stale").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["errcode"], "M_UNKNOWN_TOKEN");
+}
@@ -0,0 +1,186 @@
+// /search/messages: the indexer's sync written to postgres, searched per caller.
+// these need postgres, like flows.rs.
+
+mod common;
+
+use agora_api::search;
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+/// a service account as the indexer, the way main.rs sets it up
+async fn with_indexer(app: &TestApp) {
+    let indexer = app.register("search-indexer").await;
+    let mut client = app.state.matrix();
+    client.access_token = Some(indexer.access_token);
+    *app.state.matrix_client.write().await = Some(client);
+}
+
+/// a room `owner` made, with `members` joined. the indexer is brought in as
+/// the room is made
+async fn room(app: &TestApp, owner: &TestUser, members: &[&TestUser]) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": owner.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    for member in members {
+        app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": room_id })).await;
+    }
+    room_id
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, content: &str) -> String {
+    let body = json!({ "access_token": user.access_token, "room_id": room_id, "content": content });
+    let (status, sent) = app.post("/rooms/send", body).await;
+    assert_eq!(status, StatusCode::OK);
+    sent["event_id"].as_str().unwrap().to_string()
+}
+
+/// until the indexer has nothing left to do: the first pass joins, the next
+/// one sees the timeline
+async fn index(app: &TestApp) {
+    for _ in 0..3 {
+        search::index_once(&app.state).await.unwrap();
+    }
+}
+
+async fn search(app: &TestApp, user: &TestUser, query: &str) -> (StatusCode, Value) {
+    app.get(&format!("/search/messages?access_token={}&query={}", user.access_token, enc(query))).await
+}
+
+fn bodies(results: &Value) -> Vec<&str> {
+    results["results"].as_array().unwrap().iter().map(|r| r["body"].as_str().unwrap()).collect()
+}
+
+#[sqlx::test]
+async fn matches_from_every_joined_room_come_back_best_first(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    with_indexer(&app).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let general = room(&app, &alice, &[&bob]).await;
+    let random = room(&app, &bob, &[&alice]).await;
+    let private = room(&app, &bob, &[]).await;
+    index(&app).await;
+
+    send(&app, &alice, &general, "the deploy is done").await;
+    send(&app, &bob, &random, "deploy deploy deploy").await;
+    send(&app, &bob, &general, "lunch anyone?").await;
+    send(&app, &bob, &private, "secret deploy notes").await;
+    index(&app).await;
+
+    let (status, results) = search(&app, &alice, "deploy").await;
+    assert_eq!(status, StatusCode::OK, "{}", results);
+    assert_eq!(bodies(&results), ["deploy deploy deploy", "the deploy is done"]);
+    let first = &results["results"][0];
+    assert_eq!((&first["room_id"], &first["sender"]), (&json!(random), &json!(bob.user_id)));
+    assert!(first["timestamp"].as_i64().unwrap() > 0);
+
+    // bob is in all three
+    let (_, results) = app
+        .get(&format!(
+            "/search/messages?access_token={}&query=deploy&sender={}",
+            bob.access_token,
+            enc(&bob.user_id)
+        ))
+        .await;
+    assert_eq!(bodies(&results), ["deploy deploy deploy", "secret deploy notes"]);
+}
+
+#[sqlx::test]
+async fn channels_and_dms_are_indexed_without_an_invite(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    with_indexer(&app).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, server) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Crew", "is_space": true })).await;
+    let (_, channel) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general", "parent_space_id": server["room_id"] }))
+        .await;
+    let channel_id = channel["room_id"].as_str().unwrap();
+    let friends = |user: &TestUser, other: &TestUser| json!({ "access_token": user.access_token, "user_id": user.user_id, "friend_id": other.user_id });
+    app.post("/friends/add", friends(&alice, &bob)).await;
+    app.post("/friends/accept", friends(&bob, &alice)).await;
+    let (_, dm) = app.post("/friends/dm", friends(&alice, &bob)).await;
+    let dm_id = dm["room_id"].as_str().unwrap();
+
+    send(&app, &alice, channel_id, "deploy in the channel").await;
+    send(&app, &alice, dm_id, "deploy in the dm").await;
+    index(&app).await;
+    assert_eq!(bodies(&search(&app, &alice, "deploy").await.1), ["deploy in the dm", "deploy in the channel"]);
+}
+
+#[sqlx::test]
+async fn a_room_the_caller_is_not_in_is_refused(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    with_indexer(&app).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let private = room(&app, &bob, &[]).await;
+    index(&app).await;
+    send(&app, &bob, &private, "secret deploy notes").await;
+    index(&app).await;
+
+    let (status, body) = app
+        .get(&format!("/search/messages?access_token={}&query=deploy&room_id={}", alice.access_token, enc(&private)))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    let (status, body) = search(&app, &alice, "   ").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+}
+
+#[sqlx::test]
+async fn edits_and_redactions_keep_the_index_current(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    with_indexer(&app).await;
+    let alice = app.register("alice").await;
+    let general = room(&app, &alice, &[]).await;
+    index(&app).await;
+    let typo = send(&app, &alice, &general, "the deplyo is done").await;
+    let oops = send(&app, &alice, &general, "the password is hunter2").await;
+    index(&app).await;
+    assert_eq!(bodies(&search(&app, &alice, "hunter2").await.1), ["the password is hunter2"]);
+
+    let edit = json!({ "access_token": alice.access_token, "room_id": general, "event_id": typo, "content": "the deploy is done" });
+    assert_eq!(app.post("/rooms/edit", edit).await.0, StatusCode::OK);
+    let redact = json!({ "access_token": alice.access_token, "room_id": general, "event_id": oops });
+    assert_eq!(app.post("/rooms/redact", redact).await.0, StatusCode::OK);
+    index(&app).await;
+
+    assert_eq!(bodies(&search(&app, &alice, "deploy").await.1), ["the deploy is done"]);
+    assert_eq!(bodies(&search(&app, &alice, "deplyo").await.1), Vec::<&str>::new());
+    assert_eq!(bodies(&search(&app, &alice, "hunter2").await.1), Vec::<&str>::new());
+}
+
+#[sqlx::test]
+async fn blocked_senders_are_left_out(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    with_indexer(&app).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let general = room(&app, &alice, &[&bob]).await;
+    index(&app).await;
+    send(&app, &alice, &general, "deploy at noon").await;
+    send(&app, &bob, &general, "deploy at midnight").await;
+    index(&app).await;
+
+    let block = json!({ "access_token": alice.access_token, "user_id": alice.user_id, "friend_id": bob.user_id });
+    assert_eq!(app.post("/friends/block", block).await.0, StatusCode::OK);
+    assert_eq!(bodies(&search(&app, &alice, "deploy").await.1), ["deploy at noon"]);
+}
+
+#[sqlx::test]
+async fn without_an_indexer_search_is_unavailable(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    let (status, _) = search(&app, &alice, "deploy").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let (_, features) = app.get("/health/features").await;
+    assert_eq!(features["message_search"], false);
+
+    with_indexer(&app).await;
+    let (_, features) = app.get("/health/features").await;
+    assert_eq!(features["message_search"], true);
+}
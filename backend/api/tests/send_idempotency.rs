@@ -0,0 +1,128 @@
+// retried sends: a client resending with the txn_id it picked the first time
+// gets the first attempt's event back instead of posting twice
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a channel owned by alice that bob has joined, with slowmode on
+async fn slow_channel(app: &TestApp) -> (TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": room_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    let slowmode = json!({ "access_token": alice.access_token, "room_id": room_id, "seconds": 30 });
+    assert_eq!(app.post("/rooms/slowmode", slowmode).await.0, StatusCode::OK);
+    (alice, bob, room_id)
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, txn_id: &str) -> (StatusCode, Value) {
+    app.post("/rooms/send", json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "content": "hi",
+        "txn_id": txn_id,
+    }))
+    .await
+}
+
+/// the room's events of `msgtype` sent by `user`
+fn sent(app: &TestApp, user: &TestUser, room_id: &str, msgtype: &str) -> usize {
+    app.homeserver.inspect(|hs| {
+        hs.timeline
+            .iter()
+            .filter(|(r, e)| r == room_id && e["sender"] == user.user_id.as_str() && e["content"]["msgtype"] == msgtype)
+            .count()
+    })
+}
+
+#[tokio::test]
+async fn retried_messages_go_out_once() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = slow_channel(&app).await;
+
+    let (status, first) = send(&app, &bob, &room_id, "m-1").await;
+    assert_eq!(status, StatusCode::OK, "{}", first);
+    // the retry isn't held up by the slowmode wait the first attempt started
+    let (status, retry) = send(&app, &bob, &room_id, "m-1").await;
+    assert_eq!(status, StatusCode::OK, "{}", retry);
+    assert_eq!(retry["event_id"], first["event_id"]);
+    assert_eq!(sent(&app, &bob, &room_id, "m.text"), 1);
+
+    // a new txn_id is a new message, and the wait applies to it
+    assert_eq!(send(&app, &bob, &room_id, "m-2").await.0, StatusCode::TOO_MANY_REQUESTS);
+    // txn ids are the sender's own
+    let (status, other) = send(&app, &alice, &room_id, "m-1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(other["event_id"], first["event_id"]);
+
+    // without the remembered answer the homeserver still recognizes the txn id
+    app.redis.lock().unwrap().clear();
+    let (status, retry) = send(&app, &bob, &room_id, "m-1").await;
+    assert_eq!(status, StatusCode::OK, "{}", retry);
+    assert_eq!(retry["event_id"], first["event_id"]);
+    assert_eq!(sent(&app, &bob, &room_id, "m.text"), 1);
+}
+
+#[tokio::test]
+async fn retried_calls_and_raids_go_out_once() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap();
+
+    let ring = json!({
+        "access_token": alice.access_token,
+        "room_id": room_id,
+        "action": "ring",
+        "call_id": "c1",
+        "txn_id": "ring-1",
+    });
+    assert_eq!(app.post("/voice/call", ring.clone()).await.0, StatusCode::OK);
+    assert_eq!(app.post("/voice/call", ring).await.0, StatusCode::OK);
+    assert_eq!(sent(&app, &alice, room_id, "agora.call"), 1);
+
+    let raid = json!({
+        "access_token": alice.access_token,
+        "room_id": room_id,
+        "raider_id": alice.user_id,
+        "raider_name": "alice",
+        "txn_id": "raid-1",
+    });
+    assert_eq!(app.post("/rooms/raid", raid.clone()).await.0, StatusCode::OK);
+    assert_eq!(app.post("/rooms/raid", raid).await.0, StatusCode::OK);
+    assert_eq!(sent(&app, &alice, room_id, "agora.raid"), 1);
+}
+
+#[tokio::test]
+async fn txn_ids_must_fit_in_a_path() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap();
+
+    for txn_id in ["", "a/b", "ünïcode", &"x".repeat(65)] {
+        let (status, body) = send(&app, &alice, room_id, txn_id).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", txn_id);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+    let call = json!({
+        "access_token": alice.access_token,
+        "room_id": room_id,
+        "action": "ring",
+        "call_id": "c1",
+        "txn_id": "a b",
+    });
+    assert_eq!(app.post("/voice/call", call).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(sent(&app, &alice, room_id, "m.text") + sent(&app, &alice, room_id, "agora.call"), 0);
+}
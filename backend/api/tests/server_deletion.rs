@@ -0,0 +1,95 @@
+// deleting a server: only its owner can, by name, and it empties every room
+// under it. the row cleanup needs postgres, like flows.rs.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn create(app: &TestApp, owner: &TestUser, path: &str, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post(path, body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+fn membership(app: &TestApp, room_id: &str, user: &TestUser) -> Option<String> {
+    app.homeserver.inspect(|hs| hs.rooms[room_id].membership(&user.user_id).map(String::from))
+}
+
+async fn delete(app: &TestApp, user: &TestUser, server_id: &str, confirm_name: &str) -> (StatusCode, Value) {
+    let body = json!({ "access_token": user.access_token, "server_id": server_id, "confirm_name": confirm_name });
+    app.post("/servers/delete", body).await
+}
+
+#[sqlx::test]
+async fn the_owner_deletes_a_server_by_name(pool: PgPool) {
+    let app = TestApp::with_db(pool.clone()).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+    let nested_id = create(&app, &alice, "/rooms/create", json!({ "name": "nested", "parent_space_id": category_id })).await;
+    let general_id = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": server_id })).await;
+    let meta = json!({ "access_token": alice.access_token, "server_id": server_id, "vanity_slug": "crew" });
+    assert_eq!(app.post("/servers/meta", meta).await.0, StatusCode::OK);
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": server_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    assert_eq!(membership(&app, &nested_id, &bob).as_deref(), Some("join"));
+
+    sqlx::query("INSERT INTO server_invites (code, server_id, created_by) VALUES ('abc', $1, $2)")
+        .bind(&server_id)
+        .bind(&alice.user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO messages (event_id, room_id, sender, body, ts) VALUES ('$e', $1, $2, 'hi', 0)")
+        .bind(&general_id)
+        .bind(&bob.user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // members can't, and the owner has to name it
+    let (status, body) = delete(&app, &bob, &server_id, "Crew").await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    let (status, body) = delete(&app, &alice, &server_id, "crew").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "AGORA_CONFIRMATION_MISMATCH");
+    assert_eq!(membership(&app, &general_id, &bob).as_deref(), Some("join"));
+
+    let (status, report) = delete(&app, &alice, &server_id, "Crew").await;
+    assert_eq!(status, StatusCode::OK, "{}", report);
+    assert_eq!(report["complete"], true);
+    assert_eq!(report["members_removed"], 4);
+    // the vanity alias and the ones the channels got from their names
+    let mut aliases: Vec<&str> = report["aliases_removed"].as_array().unwrap().iter().map(|a| a.as_str().unwrap()).collect();
+    aliases.sort();
+    assert_eq!(aliases, ["#crew:localhost", "#general:localhost", "#nested:localhost"]);
+    let rooms: Vec<&str> = report["rooms"].as_array().unwrap().iter().map(|r| r["room_id"].as_str().unwrap()).collect();
+    assert_eq!(rooms.len(), 4);
+    assert_eq!(rooms.last(), Some(&server_id.as_str()));
+
+    for room_id in [&server_id, &category_id, &nested_id, &general_id] {
+        assert_eq!(membership(&app, room_id, &bob).as_deref(), Some("leave"));
+        assert_eq!(membership(&app, room_id, &alice).as_deref(), Some("leave"));
+    }
+    app.homeserver.inspect(|hs| {
+        let space = &hs.rooms[&server_id];
+        assert_eq!(space.state[&("agora.server.deleted".into(), String::new())]["content"]["deleted_by"], alice.user_id.as_str());
+        assert_eq!(space.state[&("m.room.join_rules".into(), String::new())]["content"]["join_rule"], "invite");
+        assert!(!hs.aliases.contains_key("#crew:localhost"));
+        assert!(!hs.aliases.contains_key("#general:localhost"));
+    });
+
+    // nobody gets back in
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": server_id });
+    assert_ne!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+
+    let invites: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM server_invites").fetch_one(&pool).await.unwrap();
+    let messages: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages").fetch_one(&pool).await.unwrap();
+    assert_eq!((invites, messages), (0, 0));
+}
@@ -0,0 +1,130 @@
+// scheduled server events: creating, listing, rsvps, cancelling, and the
+// announcement when one starts
+
+mod common;
+
+use agora_api::routes::server_events::announce_started_events;
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+const HOUR: i64 = 60 * 60 * 1000;
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn create_event(app: &TestApp, user: &TestUser, server_id: &str, fields: Value) -> (StatusCode, Value) {
+    let mut body = json!({ "access_token": user.access_token, "server_id": server_id });
+    body.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+    app.post("/servers/events/create", body).await
+}
+
+async fn event_names(app: &TestApp, user: &TestUser, server_id: &str) -> Vec<String> {
+    let (status, body) = app.get(&format!("/servers/events?access_token={}&server_id={}", user.access_token, enc(server_id))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["events"].as_array().unwrap().iter().map(|e| e["name"].as_str().unwrap().to_string()).collect()
+}
+
+async fn rsvp(app: &TestApp, user: &TestUser, server_id: &str, event_id: &str) -> (StatusCode, Value) {
+    app.post("/servers/events/rsvp", json!({ "access_token": user.access_token, "server_id": server_id, "event_id": event_id })).await
+}
+
+async fn cancel(app: &TestApp, user: &TestUser, server_id: &str, event_id: &str) -> StatusCode {
+    let body = json!({ "access_token": user.access_token, "server_id": server_id, "event_id": event_id });
+    app.request(Method::DELETE, "/servers/events", Some(body)).await.0
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[tokio::test]
+async fn members_rsvp_and_creators_cancel() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let hangout = create(&app, &alice, json!({ "name": "hangout", "channel_type": "voice", "parent_space_id": server_id })).await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id })).await;
+    let start = now() + HOUR;
+
+    for fields in [
+        json!({ "name": "past", "starts_at": now() - HOUR, "ends_at": now() + HOUR }),
+        json!({ "name": "backwards", "starts_at": start, "ends_at": start }),
+        json!({ "name": " ", "starts_at": start, "ends_at": start + HOUR }),
+        json!({ "name": "both", "starts_at": start, "ends_at": start + HOUR, "channel_id": hangout, "location": "the park" }),
+        json!({ "name": "text", "starts_at": start, "ends_at": start + HOUR, "channel_id": general }),
+    ] {
+        let (status, body) = create_event(&app, &alice, &server_id, fields).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    }
+
+    let (status, picnic) = create_event(&app, &alice, &server_id, json!({
+        "name": "picnic", "starts_at": start + HOUR, "ends_at": start + 2 * HOUR, "location": "the park",
+    }))
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", picnic);
+    let (_, game_night) = create_event(&app, &alice, &server_id, json!({
+        "name": "game night", "description": "bring snacks", "starts_at": start, "ends_at": start + HOUR, "channel_id": hangout,
+    }))
+    .await;
+    assert_eq!(game_night["created_by"], alice.user_id.as_str());
+    assert_eq!(event_names(&app, &bob, &server_id).await, ["game night", "picnic"]);
+
+    // rsvp toggles
+    let game_night_id = game_night["id"].as_str().unwrap();
+    let (status, body) = rsvp(&app, &bob, &server_id, game_night_id).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!((body["interested"].clone(), body["interested_count"].clone()), (json!(true), json!(1)));
+    assert_eq!(rsvp(&app, &alice, &server_id, game_night_id).await.1["interested_count"], 2);
+    assert_eq!(rsvp(&app, &bob, &server_id, game_night_id).await.1["interested"], false);
+
+    // members can schedule their own, and cancel only those
+    let picnic_id = picnic["id"].as_str().unwrap();
+    assert_eq!(cancel(&app, &bob, &server_id, picnic_id).await, StatusCode::FORBIDDEN);
+    let (status, movie) = create_event(&app, &bob, &server_id, json!({ "name": "movie", "starts_at": start, "ends_at": start + HOUR })).await;
+    assert_eq!(status, StatusCode::OK, "{}", movie);
+    assert_eq!(cancel(&app, &bob, &server_id, movie["id"].as_str().unwrap()).await, StatusCode::OK);
+    assert_eq!(cancel(&app, &alice, &server_id, picnic_id).await, StatusCode::OK);
+    assert_eq!(event_names(&app, &bob, &server_id).await, ["game night"]);
+    assert_eq!(rsvp(&app, &bob, &server_id, picnic_id).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn starting_events_are_announced_once() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let hangout = create(&app, &alice, json!({ "name": "hangout", "channel_type": "voice", "parent_space_id": server_id })).await;
+    let start = now() + HOUR;
+    let (_, game_night) = create_event(&app, &alice, &server_id, json!({
+        "name": "game night", "starts_at": start, "ends_at": start + HOUR, "channel_id": hangout,
+    }))
+    .await;
+    let (_, cancelled) = create_event(&app, &alice, &server_id, json!({
+        "name": "called off", "starts_at": start, "ends_at": start + HOUR, "channel_id": hangout,
+    }))
+    .await;
+    assert_eq!(cancel(&app, &alice, &server_id, cancelled["id"].as_str().unwrap()).await, StatusCode::OK);
+
+    assert_eq!(announce_started_events(&app.state, start - 1).await, 0);
+    assert_eq!(announce_started_events(&app.state, start).await, 1);
+    assert_eq!(announce_started_events(&app.state, start + 1).await, 0);
+
+    let announcements: Vec<Value> = app.homeserver.inspect(|hs| {
+        hs.timeline
+            .iter()
+            .filter(|(room_id, e)| *room_id == hangout && e["content"]["msgtype"] == "agora.event.starting")
+            .map(|(_, e)| e["content"].clone())
+            .collect()
+    });
+    assert_eq!(announcements.len(), 1);
+    assert_eq!(announcements[0]["server_event_id"], game_night["id"]);
+    assert_eq!(announcements[0]["body"], "game night is starting");
+}
@@ -0,0 +1,107 @@
+// the server member list: roles, presence and the order the sidebar shows
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+fn role(id: &str, position: i64, hoist: bool, color: &str) -> Value {
+    json!({
+        "id": id, "name": id, "color": color, "hoist": hoist, "mentionable": true,
+        "permissions": {
+            "send_messages": true, "manage_channels": false, "manage_roles": false, "kick_members": false,
+            "ban_members": false, "mention_everyone": false, "manage_server": false, "administrator": false,
+        },
+        "power_level": 0,
+        "position": position,
+    })
+}
+
+async fn assign(app: &TestApp, owner: &TestUser, server_id: &str, member: &TestUser, role_ids: &[&str]) {
+    let body = json!({ "access_token": owner.access_token, "server_id": server_id, "user_id": member.user_id, "role_ids": role_ids });
+    assert_eq!(app.post("/servers/members/roles", body).await.0, StatusCode::OK);
+}
+
+async fn set_presence(app: &TestApp, user: &TestUser, presence: &str) {
+    let body = json!({ "access_token": user.access_token, "user_id": user.user_id, "presence": presence });
+    assert_eq!(app.post("/presence/set", body).await.0, StatusCode::OK);
+}
+
+async fn members(app: &TestApp, viewer: &TestUser, server_id: &str, extra: &str) -> (StatusCode, Value) {
+    app.get(&format!("/servers/members?access_token={}&server_id={}{}", viewer.access_token, enc(server_id), extra)).await
+}
+
+fn localparts(body: &Value) -> Vec<String> {
+    body["members"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["user_id"].as_str().unwrap().trim_start_matches('@').split(':').next().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn members_are_grouped_by_hoisted_role_online_first() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let dave = app.register("dave").await;
+    let erin = app.register("erin").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Lounge", "is_space": true })).await;
+    let server_id = room["room_id"].as_str().unwrap().to_string();
+    for member in [&bob, &carol, &dave, &erin] {
+        app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": server_id })).await;
+    }
+
+    let roles = vec![role("fans", 3, false, "#00ff00"), role("mods", 2, true, ""), role("vips", 1, true, "#0000ff")];
+    let body = json!({ "access_token": alice.access_token, "server_id": server_id, "roles": roles, "force": true });
+    assert_eq!(app.post("/servers/roles", body).await.0, StatusCode::OK);
+    assign(&app, &alice, &server_id, &bob, &["mods", "fans"]).await;
+    assign(&app, &alice, &server_id, &carol, &["vips"]).await;
+    assign(&app, &alice, &server_id, &dave, &["fans"]).await;
+    set_presence(&app, &alice, "online").await;
+    set_presence(&app, &bob, "online").await;
+    set_presence(&app, &dave, "unavailable").await;
+    set_presence(&app, &erin, "invisible").await;
+
+    let (status, all) = members(&app, &dave, &server_id, "").await;
+    assert_eq!(status, StatusCode::OK, "{}", all);
+    assert_eq!(localparts(&all), ["bob", "alice", "dave", "carol", "erin"]);
+    assert_eq!(all["total"], 5);
+    let groups: Vec<&str> = all["groups"].as_array().unwrap().iter().map(|g| g["role_id"].as_str().unwrap()).collect();
+    assert_eq!(groups, ["mods", "vips"]);
+
+    let bob_entry = &all["members"][0];
+    assert_eq!(bob_entry["role_ids"], json!(["fans", "mods"]));
+    assert_eq!(bob_entry["hoisted_role_id"], "mods");
+    assert_eq!(bob_entry["color"], "#00ff00");
+    assert_eq!(bob_entry["presence"], "online");
+    assert!(all["members"][2].get("hoisted_role_id").is_none());
+    assert_eq!(all["members"][3]["hoisted_role_id"], "vips");
+    // invisible looks offline to everyone
+    assert_eq!(all["members"][4]["presence"], "offline");
+
+    // pages follow the same order
+    let mut seen = Vec::new();
+    let mut after = String::new();
+    loop {
+        let (status, page) = members(&app, &dave, &server_id, &format!("&limit=2{}", after)).await;
+        assert_eq!(status, StatusCode::OK, "{}", page);
+        seen.extend(localparts(&page));
+        match page["next_cursor"].as_str() {
+            Some(cursor) => after = format!("&after={}", enc(cursor)),
+            None => break,
+        }
+    }
+    assert_eq!(seen, localparts(&all));
+
+    let (_, found) = members(&app, &dave, &server_id, "&query=CAR").await;
+    assert_eq!(localparts(&found), ["carol"]);
+    assert_eq!(found["total"], 1);
+
+    assert_eq!(members(&app, &dave, &server_id, "&limit=2&after=junk").await.0, StatusCode::BAD_REQUEST);
+    let outsider = app.register("frank").await;
+    assert_ne!(members(&app, &outsider, &server_id, "").await.0, StatusCode::OK);
+}
@@ -0,0 +1,73 @@
+// ids and aliases the api builds itself must use the configured server name,
+// not assume the homeserver lives on localhost
+
+mod common;
+
+use agora_api::app_state::default_server_name;
+use axum::http::StatusCode;
+use common::TestApp;
+use serde_json::json;
+
+const DOMAIN: &str = "chat.example.org";
+
+#[test]
+fn server_name_defaults_to_the_homeserver_host() {
+    assert_eq!(default_server_name("http://localhost:8448"), "localhost");
+    assert_eq!(default_server_name("https://matrix.example.org"), "matrix.example.org");
+    assert_eq!(default_server_name("https://matrix.example.org:8448/"), "matrix.example.org");
+    assert_eq!(default_server_name("not a url"), "localhost");
+}
+
+#[tokio::test]
+async fn login_completes_short_usernames_with_the_server_name() {
+    let app = TestApp::with_server_name(DOMAIN).await;
+    let user = app.register("alice").await;
+    assert_eq!(user.user_id, "@alice:chat.example.org");
+
+    let (status, body) = app.post("/login", json!({ "username": "alice", "password": "hunter2" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["user_id"], "@alice:chat.example.org");
+    assert_eq!(body["home_server"], DOMAIN);
+}
+
+#[tokio::test]
+async fn aliases_and_space_links_use_the_server_name() {
+    let app = TestApp::with_server_name(DOMAIN).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    let (_, server) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "Lounge", "is_space": true }))
+        .await;
+    let server_id = server["room_id"].as_str().unwrap().to_string();
+
+    let (_, channel) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general", "parent_space_id": server_id }))
+        .await;
+    let channel_id = channel["room_id"].as_str().unwrap().to_string();
+
+    let (via_child, via_parent) = app.homeserver.inspect(|hs| {
+        let child = &hs.rooms[&server_id].state[&("m.space.child".to_string(), channel_id.clone())];
+        let parent = &hs.rooms[&channel_id].state[&("m.space.parent".to_string(), server_id.clone())];
+        (child["content"]["via"].clone(), parent["content"]["via"].clone())
+    });
+    assert_eq!(via_child, json!([DOMAIN]));
+    assert_eq!(via_parent, json!([DOMAIN]));
+
+    // the vanity slug becomes an alias on our domain
+    let (status, _) = app
+        .post("/servers/meta", json!({ "access_token": alice.access_token, "server_id": server_id, "vanity_slug": "lounge" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        app.homeserver.inspect(|hs| hs.aliases.get("#lounge:chat.example.org").cloned()),
+        Some(server_id.clone())
+    );
+
+    // and a bare name joins through it
+    let (status, joined) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": "lounge" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(joined["room_id"], server_id.as_str());
+}
@@ -0,0 +1,121 @@
+// server roles are enforced by the api: management endpoints check the caller's
+// role permissions on the server before anything reaches the homeserver
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+/// alice's server with a #general channel, and bob in both
+async fn server(app: &TestApp, alice: &TestUser, bob: &TestUser) -> (String, String) {
+    let server_id = create(app, alice, json!({ "name": "Lounge", "is_space": true })).await;
+    let general = create(app, alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    for room_id in [&server_id, &general] {
+        app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id })).await;
+    }
+    (server_id, general)
+}
+
+fn role(id: &str, permission: &str) -> Value {
+    let mut permissions = json!({
+        "send_messages": true, "manage_channels": false, "manage_roles": false, "kick_members": false,
+        "ban_members": false, "mention_everyone": false, "manage_server": false, "administrator": false,
+    });
+    permissions[permission] = json!(true);
+    json!({
+        "id": id, "name": id, "color": "#5865f2", "hoist": false, "mentionable": true,
+        "permissions": permissions, "power_level": 0,
+    })
+}
+
+/// `roles` on alice's server, with `member` holding all of them
+async fn grant(app: &TestApp, alice: &TestUser, server_id: &str, member: &TestUser, roles: Vec<Value>) {
+    let role_ids: Vec<Value> = roles.iter().map(|r| r["id"].clone()).collect();
+    let body = json!({ "access_token": alice.access_token, "server_id": server_id, "roles": roles, "force": true });
+    assert_eq!(app.post("/servers/roles", body).await.0, StatusCode::OK);
+    let body = json!({ "access_token": alice.access_token, "server_id": server_id, "user_id": member.user_id, "role_ids": role_ids });
+    assert_eq!(app.post("/servers/members/roles", body).await.0, StatusCode::OK);
+}
+
+fn assert_missing(response: (StatusCode, Value), permission: &str) {
+    let (status, body) = response;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(body["errcode"], "AGORA_MISSING_PERMISSION");
+    assert_eq!(body["permission"], permission);
+}
+
+#[tokio::test]
+async fn a_member_without_roles_can_manage_nothing() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, general) = server(&app, &alice, &bob).await;
+    let token = bob.access_token.clone();
+
+    let roles = json!({ "access_token": token, "server_id": server_id, "roles": [role("boss", "administrator")] });
+    assert_missing(app.post("/servers/roles", roles).await, "manage_roles");
+    let assign = json!({ "access_token": token, "server_id": server_id, "user_id": bob.user_id, "role_ids": ["boss"] });
+    assert_missing(app.post("/servers/members/roles", assign).await, "manage_roles");
+    let meta = json!({ "access_token": token, "server_id": server_id, "name": "Mine now" });
+    assert_missing(app.post("/servers/meta", meta).await, "manage_server");
+    let channel = json!({ "access_token": token, "name": "spam", "parent_space_id": server_id });
+    assert_missing(app.post("/rooms/create", channel).await, "manage_channels");
+    let category = json!({ "access_token": token, "name": "spam", "parent_space_id": server_id });
+    assert_missing(app.post("/rooms/category/create", category).await, "manage_channels");
+    let power = json!({ "access_token": token, "room_id": general, "user_id": bob.user_id, "power_level": 100 });
+    assert_missing(app.post("/rooms/permissions", power).await, "manage_roles");
+    let raid = json!({ "access_token": token, "room_id": general, "raider_id": bob.user_id, "raider_name": "bob" });
+    assert_missing(app.post("/rooms/raid", raid).await, "mention_everyone");
+
+    // nothing got through to the homeserver
+    let name = app.homeserver.inspect(|hs| hs.rooms[&server_id].state[&("m.room.name".into(), String::new())].clone());
+    assert_eq!(name["content"]["name"], "Lounge");
+    assert!(app.homeserver.inspect(|hs| hs.timeline.iter().all(|(_, e)| e["content"]["msgtype"] != "agora.raid")));
+}
+
+#[tokio::test]
+async fn a_role_grants_just_its_permission() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, general) = server(&app, &alice, &bob).await;
+    grant(&app, &alice, &server_id, &bob, vec![role("builder", "manage_channels")]).await;
+
+    let channel = json!({ "access_token": bob.access_token, "name": "memes", "parent_space_id": server_id });
+    assert_eq!(app.post("/rooms/create", channel).await.0, StatusCode::OK);
+    let raid = json!({ "access_token": bob.access_token, "room_id": general, "raider_id": bob.user_id, "raider_name": "bob" });
+    assert_missing(app.post("/rooms/raid", raid).await, "mention_everyone");
+}
+
+#[tokio::test]
+async fn the_administrator_role_overrides_every_flag() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (server_id, general) = server(&app, &alice, &bob).await;
+    grant(&app, &alice, &server_id, &bob, vec![role("boss", "administrator")]).await;
+
+    let raid = json!({ "access_token": bob.access_token, "room_id": general, "raider_id": bob.user_id, "raider_name": "bob" });
+    assert_eq!(app.post("/rooms/raid", raid).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rooms_outside_a_server_are_left_to_the_homeserver() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = create(&app, &alice, json!({ "name": "hangout" })).await;
+    let raid = json!({ "access_token": alice.access_token, "room_id": room_id, "raider_id": alice.user_id, "raider_name": "alice" });
+    assert_eq!(app.post("/rooms/raid", raid).await.0, StatusCode::OK);
+
+    let stranger = json!({ "access_token": "nope", "server_id": room_id, "name": "x" });
+    assert_eq!(app.post("/servers/meta", stranger).await.0, StatusCode::UNAUTHORIZED);
+}
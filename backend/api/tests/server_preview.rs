@@ -0,0 +1,145 @@
+// /servers/preview: what an invite link shows before joining — basic details
+// for any public server, channels and recent messages for world_readable ones.
+// the invite code case needs postgres, like flows.rs.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn make_world_readable(app: &TestApp, owner: &TestUser, room_id: &str) {
+    let mut matrix = app.state.matrix();
+    matrix.access_token = Some(owner.access_token.clone());
+    let content = json!({ "history_visibility": "world_readable" });
+    matrix
+        .send_state_event(room_id.to_string(), "m.room.history_visibility".to_string(), "".to_string(), content)
+        .await
+        .unwrap();
+}
+
+async fn preview(app: &TestApp, code_or_alias: &str) -> (StatusCode, Value) {
+    app.get(&format!("/servers/preview?code_or_alias={}", enc(code_or_alias))).await
+}
+
+#[tokio::test]
+async fn world_readable_servers_show_channels_and_messages() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let rules = create(&app, &alice, json!({ "name": "rules", "parent_space_id": server_id })).await;
+    let general = create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let meta = json!({ "access_token": alice.access_token, "server_id": server_id, "vanity_slug": "crew", "banner_url": "mxc://localhost/banner" });
+    assert_eq!(app.post("/servers/meta", meta).await.0, StatusCode::OK);
+    let welcome = json!({
+        "access_token": alice.access_token,
+        "server_id": server_id,
+        "revision": 0,
+        "welcome": { "description": "hi all", "welcome_channels": [{ "room_id": general, "description": "say hi" }] },
+    });
+    assert_eq!(app.post("/servers/welcome", welcome).await.0, StatusCode::OK);
+    let send = json!({ "access_token": alice.access_token, "room_id": general, "content": "welcome aboard" });
+    assert_eq!(app.post("/rooms/send", send).await.0, StatusCode::OK);
+
+    // private history: just what the summary says
+    let (status, basic) = preview(&app, "crew").await;
+    assert_eq!(status, StatusCode::OK, "{}", basic);
+    assert_eq!(basic["server_id"], server_id.as_str());
+    assert_eq!(basic["name"], "Crew");
+    assert_eq!(basic["member_count"], 1);
+    assert_eq!(basic["world_readable"], false);
+    assert_eq!(basic["channels"], json!([]));
+    assert!(basic["welcome"].is_null());
+
+    make_world_readable(&app, &alice, &server_id).await;
+    make_world_readable(&app, &alice, &general).await;
+    let (status, full) = preview(&app, "#crew:localhost").await;
+    assert_eq!(status, StatusCode::OK, "{}", full);
+    assert_eq!(full["world_readable"], true);
+    assert_eq!(full["banner_url"], "mxc://localhost/banner");
+    assert_eq!(full["welcome"]["description"], "hi all");
+    let mut channels: Vec<&str> = full["channels"].as_array().unwrap().iter().map(|c| c["room_id"].as_str().unwrap()).collect();
+    channels.sort();
+    let mut expected = vec![rules.as_str(), general.as_str()];
+    expected.sort();
+    assert_eq!(channels, expected);
+    assert_eq!(full["default_channel_id"], general.as_str());
+    assert_eq!(full["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(full["messages"][0]["body"], "welcome aboard");
+    assert_eq!(full["messages"][0]["sender"], alice.user_id.as_str());
+
+    // a logged-in caller reads with their own session
+    let bob = app.register("bob").await;
+    let uri = format!("/servers/preview?code_or_alias=crew&access_token={}", bob.access_token);
+    assert_eq!(app.get(&uri).await.1["messages"].as_array().unwrap().len(), 1);
+
+    assert_eq!(preview(&app, "nobody-here").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(preview(&app, "#nobody:localhost").await.0, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn invite_codes_resolve_until_revoked(pool: PgPool) {
+    let app = TestApp::with_db(pool.clone()).await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    sqlx::query("INSERT INTO server_invites (code, server_id, created_by) VALUES ('abc123', $1, $2)")
+        .bind(&server_id)
+        .bind(&alice.user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let (status, body) = preview(&app, "abc123").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["server_id"], server_id.as_str());
+
+    sqlx::query("UPDATE server_invites SET revoked_at = NOW() WHERE code = 'abc123'").execute(&pool).await.unwrap();
+    assert_eq!(preview(&app, "abc123").await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn welcome_screen_saves_check_the_revision() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    app.post("/rooms/invite", json!({ "access_token": alice.access_token, "room_id": server_id, "user_id": bob.user_id })).await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id })).await;
+    app.homeserver.state.lock().unwrap().rooms.get_mut(&server_id).unwrap().set_power(&bob.user_id, 50);
+
+    let save = |user: &TestUser, description: &str, revision: Value, force: bool| {
+        json!({
+            "access_token": user.access_token,
+            "server_id": server_id,
+            "welcome": { "description": description, "welcome_channels": [] },
+            "revision": revision,
+            "force": force,
+        })
+    };
+    let (_, loaded) = app.get(&format!("/servers/welcome?access_token={}&server_id={}", bob.access_token, enc(&server_id))).await;
+    assert_eq!(loaded["revision"], 0);
+
+    let (status, body) = app.post("/servers/welcome", save(&alice, "hi all", json!(0), false)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["revision"], 1);
+    let (status, body) = app.post("/servers/welcome", save(&bob, "hello", json!(0), false)).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["revision"], 1);
+    assert_eq!(body["welcome"]["description"], "hi all");
+    assert_eq!(app.post("/servers/welcome", save(&bob, "hello", json!(0), true)).await.0, StatusCode::FORBIDDEN);
+
+    let (status, body) = app.post("/servers/welcome", save(&alice, "hey", json!(0), true)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["revision"], 2);
+    let (_, current) = app.get(&format!("/servers/welcome?access_token={}&server_id={}", bob.access_token, enc(&server_id))).await;
+    assert_eq!(current["welcome"]["description"], "hey");
+}
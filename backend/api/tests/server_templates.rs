@@ -0,0 +1,118 @@
+// building a server from a template in one request
+
+mod common;
+
+use axum::http::StatusCode;
+use common::TestApp;
+use serde_json::{json, Value};
+
+fn state(app: &TestApp, room_id: &str, event_type: &str, state_key: &str) -> Value {
+    app.homeserver.inspect(|hs| {
+        hs.rooms[room_id].state.get(&(event_type.to_string(), state_key.to_string())).map(|e| e["content"].clone())
+    })
+    .unwrap_or(Value::Null)
+}
+
+#[tokio::test]
+async fn the_builtins_are_listed() {
+    let app = TestApp::new().await;
+    let (status, body) = app.get("/servers/templates").await;
+    assert_eq!(status, StatusCode::OK);
+    let ids: Vec<&str> = body["templates"].as_array().unwrap().iter().map(|t| t["id"].as_str().unwrap()).collect();
+    for id in ["gaming", "study", "community"] {
+        assert!(ids.contains(&id), "{} missing from {:?}", id, ids);
+    }
+}
+
+#[tokio::test]
+async fn a_builtin_template_builds_the_whole_server() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let body = json!({ "access_token": alice.access_token, "name": "Crew", "template": "gaming", "vanity_slug": "crew" });
+    let (status, tree) = app.post("/servers/create_from_template", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", tree);
+    let server_id = tree["server_id"].as_str().unwrap();
+    assert_eq!(tree["template"], "gaming");
+
+    let categories = tree["categories"].as_array().unwrap();
+    let names: Vec<&str> = categories.iter().map(|c| c["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["text channels", "voice channels"]);
+    let voice = &categories[1];
+    assert_eq!(voice["channels"][0]["name"], "game-night");
+    assert_eq!(voice["channels"][0]["type"], "voice");
+
+    // categories hang off the server and channels off their category, both ways
+    let category_id = voice["room_id"].as_str().unwrap();
+    let channel_id = voice["channels"][0]["room_id"].as_str().unwrap();
+    assert!(state(&app, server_id, "m.space.child", category_id).is_object());
+    assert!(state(&app, category_id, "m.space.child", channel_id).is_object());
+    assert!(state(&app, channel_id, "m.space.parent", category_id).is_object());
+    assert_eq!(state(&app, channel_id, "agora.room.type", "")["type"], "voice");
+
+    // meta, the default roles and the vanity alias
+    assert_eq!(state(&app, server_id, "agora.server.meta", "")["template"], "gaming");
+    assert_eq!(state(&app, server_id, "agora.roles", "")["roles"][0]["id"], "moderator");
+    assert_eq!(tree["roles"][0]["id"], "moderator");
+    let aliased = app.homeserver.inspect(|hs| hs.aliases.iter().any(|(alias, room)| alias.starts_with("#crew:") && room == server_id));
+    assert!(aliased);
+}
+
+#[tokio::test]
+async fn an_inline_template_is_checked_and_built() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let template = json!({
+        "channels": [{ "name": "lobby" }],
+        "categories": [{ "name": "talk", "channels": [{ "name": "stage", "type": "stage" }, { "name": "ideas", "type": "forum" }] }],
+        "roles": [],
+    });
+    let body = json!({ "access_token": alice.access_token, "name": "Mine", "template": template });
+    let (status, tree) = app.post("/servers/create_from_template", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", tree);
+    assert_eq!(tree["template"], Value::Null);
+    assert_eq!(tree["channels"][0]["name"], "lobby");
+    assert_eq!(tree["channels"][0]["type"], "text");
+    assert_eq!(tree["categories"][0]["channels"][1]["type"], "forum");
+    assert_eq!(tree["roles"], json!([]));
+    let server_id = tree["server_id"].as_str().unwrap();
+    assert_eq!(state(&app, server_id, "agora.roles", ""), Value::Null);
+
+    let bad = json!({ "channels": [{ "name": "lobby", "type": "hologram" }] });
+    let body = json!({ "access_token": alice.access_token, "name": "Mine", "template": bad });
+    let (status, error) = app.post("/servers/create_from_template", body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["errcode"], "M_INVALID_PARAM");
+
+    let body = json!({ "access_token": alice.access_token, "name": "Mine", "template": "spaceship" });
+    assert_eq!(app.post("/servers/create_from_template", body).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_failed_build_is_cleaned_up() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+
+    let body = json!({ "access_token": alice.access_token, "name": "Crew", "template": "custom", "vanity_slug": "crew" });
+    let (status, first) = app.post("/servers/create_from_template", body).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the slug is taken, so bob's server is taken down again
+    let body = json!({ "access_token": bob.access_token, "name": "Crew", "template": "study", "vanity_slug": "crew" });
+    let (status, error) = app.post("/servers/create_from_template", body).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", error);
+    assert_eq!(error["errcode"], "AGORA_TEMPLATE_FAILED");
+    assert_eq!(error["step"], "vanity_slug");
+    assert_eq!(error["cleaned_up"], true);
+    let created = error["created"].as_array().unwrap();
+    assert_eq!(created.len(), 1);
+    let space_id = created[0]["room_id"].as_str().unwrap();
+    let membership = app.homeserver.inspect(|hs| hs.rooms[space_id].membership(&bob.user_id).map(String::from));
+    assert_eq!(membership.as_deref(), Some("leave"));
+
+    // and alice keeps her alias
+    let owner = app.homeserver.inspect(|hs| hs.aliases.iter().find(|(a, _)| a.starts_with("#crew:")).map(|(_, r)| r.clone()));
+    assert_eq!(owner.as_deref(), first["server_id"].as_str());
+}
@@ -0,0 +1,102 @@
+// the service account: signing it in (or up), and its sync worker handing
+// what it sees to the registered observers
+
+mod common;
+
+use agora_api::app_state::AppState;
+use agora_api::matrix::client::SyncResponse;
+use agora_api::service_account::{self, ServiceAccount, SyncObserver};
+use axum::async_trait;
+use axum::http::{Method, StatusCode};
+use common::TestApp;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn bot() -> ServiceAccount {
+    ServiceAccount { username: "agora".to_string(), password: "hunter2".to_string() }
+}
+
+/// (room id, body) of every message it's shown
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+#[async_trait]
+impl SyncObserver for Recorder {
+    async fn observe(&self, _state: &AppState, sync: &SyncResponse) {
+        let joined = sync.rooms.as_ref().and_then(|r| r.join.as_ref());
+        for (room_id, room) in joined.into_iter().flatten() {
+            for event in room.timeline.iter().flat_map(|t| &t.events) {
+                if let Some(body) = event.content["body"].as_str() {
+                    self.0.lock().unwrap().push((room_id.clone(), body.to_string()));
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn the_account_is_registered_once_then_logged_in() {
+    let app = TestApp::new().await;
+    let first = bot().sign_in(&app.state).await.unwrap();
+    let second = bot().sign_in(&app.state).await.unwrap();
+
+    let user_id = format!("@agora:{}", app.state.server_name);
+    assert!(app.homeserver.inspect(|hs| hs.users.contains_key(&user_id)));
+    assert_ne!(first.access_token, second.access_token);
+    let current = app.state.matrix_client.read().await.clone().unwrap();
+    assert_eq!(current.access_token, second.access_token);
+
+    let wrong = ServiceAccount { password: "nope".to_string(), ..bot() };
+    assert!(wrong.sign_in(&app.state).await.is_err());
+}
+
+#[tokio::test]
+async fn observers_see_rooms_the_account_is_invited_to() {
+    let app = TestApp::new().await;
+    let seen = Recorder::default();
+    app.state.sync_observers.register(seen.clone());
+    bot().sign_in(&app.state).await.unwrap();
+    let alice = app.register("alice").await;
+    let (_, room) = app.authed(&alice, Method::POST, "/rooms/create", Some(json!({ "name": "general" }))).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let invite = json!({ "room_id": room_id, "user_id": format!("@agora:{}", app.state.server_name) });
+    assert_eq!(app.authed(&alice, Method::POST, "/rooms/invite", Some(invite)).await.0, StatusCode::OK);
+
+    // the first pass accepts the invite, the next one sees the room
+    let since = service_account::sync_once(&app.state, None).await.unwrap();
+    assert!(since.is_some());
+    let message = json!({ "room_id": room_id, "content": "hello bot" });
+    assert_eq!(app.authed(&alice, Method::POST, "/rooms/send", Some(message)).await.0, StatusCode::OK);
+    let since = service_account::sync_once(&app.state, since).await.unwrap();
+    assert_eq!(*seen.0.lock().unwrap(), [(room_id.clone(), "hello bot".to_string())]);
+
+    // and carrying on from there doesn't show it again
+    service_account::sync_once(&app.state, since).await.unwrap();
+    assert_eq!(seen.0.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn the_worker_keeps_its_position_in_redis() {
+    let app = TestApp::new().await;
+    // not signed in yet: the worker does that itself
+    let worker = tokio::spawn(service_account::run_sync_worker(app.state.clone(), Some(bot())));
+
+    let mut stored = None;
+    for _ in 0..50 {
+        stored = app.redis.lock().unwrap().get("service_sync:next_batch").cloned();
+        if stored.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    worker.abort();
+    assert!(stored.is_some(), "the worker never stored a sync position");
+    assert!(app.state.matrix_client.read().await.is_some());
+}
+
+#[tokio::test]
+async fn without_an_account_a_pass_does_nothing() {
+    let app = TestApp::new().await;
+    assert_eq!(service_account::sync_once(&app.state, None).await.unwrap(), None);
+}
@@ -0,0 +1,75 @@
+// per-user settings: a json blob in account data, patched rather than replaced
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+async fn get_settings(app: &TestApp, user: &TestUser) -> Value {
+    let (status, body) = app.get(&format!("/settings?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["settings"].clone()
+}
+
+async fn patch(app: &TestApp, user: &TestUser, settings: Value) -> (StatusCode, Value) {
+    app.request(Method::PUT, "/settings", Some(json!({ "access_token": user.access_token, "settings": settings }))).await
+}
+
+#[tokio::test]
+async fn devices_patch_their_own_keys() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    assert_eq!(get_settings(&app, &alice).await, json!({}));
+
+    let (status, body) = patch(&app, &alice, json!({ "theme": "dark", "notifications": { "sound": true, "desktop": true } })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    // another device flips one setting without knowing the rest
+    let (_, body) = patch(&app, &alice, json!({ "notifications": { "sound": false }, "keybinds": { "mute": "ctrl+m" } })).await;
+    let expected = json!({
+        "theme": "dark",
+        "notifications": { "sound": false, "desktop": true },
+        "keybinds": { "mute": "ctrl+m" },
+    });
+    assert_eq!(body["settings"], expected);
+    assert_eq!(get_settings(&app, &alice).await, expected);
+
+    // null removes a key
+    let (_, body) = patch(&app, &alice, json!({ "keybinds": null })).await;
+    assert!(body["settings"].get("keybinds").is_none());
+
+    // kept in the user's own account data
+    let stored = app.homeserver.inspect(|hs| hs.account_data[&(alice.user_id.clone(), "agora.settings".to_string())].clone());
+    assert_eq!(stored, body["settings"]);
+}
+
+#[tokio::test]
+async fn settings_are_bounded_and_need_a_session() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+
+    let (status, body) = patch(&app, &alice, json!({ "blob": "x".repeat(70 * 1024) })).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["errcode"], "M_TOO_LARGE");
+    let (status, _) = patch(&app, &alice, json!(["not", "an", "object"])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(get_settings(&app, &alice).await, json!({}));
+
+    let (status, _) = app.get("/settings?access_token=nope").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn postgres_stands_in_when_the_homeserver_refuses(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let alice = app.register("alice").await;
+    app.homeserver.state.lock().unwrap().reject_custom_account_data = true;
+
+    let (status, _) = patch(&app, &alice, json!({ "theme": "light", "compact": true })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = patch(&app, &alice, json!({ "compact": null })).await;
+    assert_eq!(body["settings"], json!({ "theme": "light" }));
+    assert_eq!(get_settings(&app, &alice).await, json!({ "theme": "light" }));
+    assert!(app.homeserver.inspect(|hs| hs.account_data.is_empty()));
+}
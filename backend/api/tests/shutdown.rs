@@ -0,0 +1,55 @@
+// graceful shutdown: a real listener this time, since what's under test is
+// how the server treats its connections once told to stop.
+
+use agora_api::shutdown::{self, Shutdown};
+use axum::routing::get;
+use axum::Router;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// a server whose one route takes `delay` to answer
+async fn spawn_server(delay: Duration, grace: Duration) -> (String, Shutdown, tokio::task::JoinHandle<std::io::Result<()>>) {
+    let app = Router::new().route(
+        "/slow",
+        get(move || async move {
+            tokio::time::sleep(delay).await;
+            "done"
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/slow", listener.local_addr().unwrap());
+    let shutdown = Shutdown::new();
+    let server = tokio::spawn(shutdown::serve(listener, app, shutdown.clone(), grace));
+    (url, shutdown, server)
+}
+
+#[tokio::test]
+async fn requests_in_flight_finish_during_shutdown() {
+    let (url, shutdown, server) = spawn_server(Duration::from_millis(300), Duration::from_secs(5)).await;
+
+    // built first, so the request is on its way before shutdown begins
+    let client = reqwest::Client::new();
+    let request = tokio::spawn(client.get(url.clone()).send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.begin();
+
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "done");
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+
+    // nobody is listening any more
+    assert!(reqwest::get(url).await.is_err());
+}
+
+#[tokio::test]
+async fn the_grace_period_bounds_the_wait() {
+    let (url, shutdown, server) = spawn_server(Duration::from_secs(30), Duration::from_millis(200)).await;
+
+    let _stuck = tokio::spawn(reqwest::get(url));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.begin();
+
+    // serve gives up on the stuck request rather than waiting it out
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+}
@@ -0,0 +1,102 @@
+// slowmode: one message per member per interval, moderators exempt
+
+mod common;
+
+use agora_api::redis_manager::RedisManager;
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::json;
+
+/// a channel owned by alice that bob has joined, with slowmode set to `seconds`
+async fn slow_channel(app: &TestApp, seconds: u64) -> (TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (status, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = app
+        .post("/rooms/slowmode", json!({ "access_token": alice.access_token, "room_id": room_id, "seconds": seconds }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["seconds"], seconds);
+    (alice, bob, room_id)
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str) -> (StatusCode, serde_json::Value) {
+    app.post("/rooms/send", json!({ "access_token": user.access_token, "room_id": room_id, "content": "hi" }))
+        .await
+}
+
+#[tokio::test]
+async fn members_wait_between_messages() {
+    let app = TestApp::new().await;
+    let (_, bob, room_id) = slow_channel(&app, 30).await;
+
+    let (status, _) = send(&app, &bob, &room_id).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(app.redis.lock().unwrap().contains_key(&format!("slowmode:{}:{}", room_id, bob.user_id)));
+
+    let (status, body) = send(&app, &bob, &room_id).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["errcode"], "M_LIMIT_EXCEEDED");
+    assert_eq!(body["retry_after"], 30);
+
+    // once the wait is over (the key expired) bob can talk again
+    app.redis.lock().unwrap().clear();
+    let (status, _) = send(&app, &bob, &room_id).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn moderators_are_exempt() {
+    let app = TestApp::new().await;
+    let (alice, _, room_id) = slow_channel(&app, 30).await;
+    for _ in 0..3 {
+        let (status, _) = send(&app, &alice, &room_id).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn without_redis_messages_go_through() {
+    let app = TestApp::with_config(|state| state.redis = RedisManager::disabled()).await;
+    let (_, bob, room_id) = slow_channel(&app, 30).await;
+    for _ in 0..2 {
+        let (status, _) = send(&app, &bob, &room_id).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn slowmode_is_readable_and_only_moderators_set_it() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = slow_channel(&app, 10).await;
+
+    let (status, body) = app
+        .get(&format!("/rooms/slowmode?access_token={}&room_id={}", bob.access_token, enc(&room_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["seconds"], 10);
+
+    // shown with the room so clients can count down before sending
+    let (_, rooms) = app.get(&format!("/rooms?access_token={}", bob.access_token)).await;
+    assert_eq!(rooms["rooms"][0]["slowmode_seconds"], 10);
+
+    let (status, _) = app
+        .post("/rooms/slowmode", json!({ "access_token": bob.access_token, "room_id": room_id, "seconds": 0 }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = app
+        .post("/rooms/slowmode", json!({ "access_token": alice.access_token, "room_id": room_id, "seconds": 86_400 }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+}
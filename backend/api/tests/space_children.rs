@@ -0,0 +1,257 @@
+// listing a space's children and the joined rooms: pages, and the cached state behind them
+
+mod common;
+
+use agora_api::matrix::client::MatrixClient;
+use agora_api::matrix::hierarchy;
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, path: &str, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post(path, body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn children(app: &TestApp, user: &TestUser, space_id: &str, extra: &str) -> Value {
+    let path = format!("/rooms/children?access_token={}&space_id={}{}", user.access_token, enc(space_id), extra);
+    let (status, body) = app.get(&path).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body
+}
+
+fn names(body: &Value) -> Vec<String> {
+    body["children"].as_array().unwrap().iter().map(|c| c["name"].as_str().unwrap_or_default().to_string()).collect()
+}
+
+#[tokio::test]
+async fn children_come_in_pages_of_direct_children() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+    create(&app, &alice, "/rooms/create", json!({ "name": "nested", "parent_space_id": category_id })).await;
+    let a = create(&app, &alice, "/rooms/create", json!({ "name": "a", "parent_space_id": server_id })).await;
+    let b = create(&app, &alice, "/rooms/create", json!({ "name": "b", "parent_space_id": server_id })).await;
+    let reorder = json!({ "access_token": alice.access_token, "space_id": server_id, "child_room_ids": [category_id, a, b] });
+    assert_eq!(app.post("/rooms/reorder", reorder).await.0, StatusCode::OK);
+
+    // a page holds direct children, each with what sits below it
+    let first = children(&app, &alice, &server_id, "&max_depth=2&limit=2").await;
+    assert_eq!(names(&first), ["talk", "a", "nested"]);
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let second = children(&app, &alice, &server_id, &format!("&max_depth=2&limit=2&after={}", enc(cursor))).await;
+    assert_eq!(names(&second), ["b"]);
+    assert!(second.get("next_cursor").is_none());
+
+    // without a limit it's everything, as before
+    let all = children(&app, &alice, &server_id, "&max_depth=2").await;
+    assert_eq!(names(&all), ["talk", "a", "b", "nested"]);
+    assert!(all.get("next_cursor").is_none());
+
+    let path = format!("/rooms/children?access_token={}&space_id={}&limit=2&after=junk", alice.access_token, enc(&server_id));
+    assert_eq!(app.get(&path).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn channel_state_is_cached_until_it_changes() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let channel_id = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": server_id })).await;
+    let secret_id = create(&app, &alice, "/rooms/create", json!({
+        "name": "secret",
+        "parent_space_id": server_id,
+        "private": true,
+    }))
+    .await;
+    children(&app, &alice, &server_id, "").await;
+
+    // channels are cached, spaces and private channels aren't
+    let key = |room_id: &str| format!("room_summary:{}", room_id);
+    {
+        let mut store = app.redis.lock().unwrap();
+        assert!(!store.contains_key(&key(&server_id)));
+        assert!(!store.contains_key(&key(&secret_id)));
+        let cached = store.get(&key(&channel_id)).cloned().unwrap();
+        store.insert(key(&channel_id), cached.replace("\"general\"", "\"stale\""));
+    }
+    assert!(names(&children(&app, &alice, &server_id, "").await).contains(&"stale".to_string()));
+
+    // editing the channel drops its entry
+    let edit = json!({ "access_token": alice.access_token, "room_id": channel_id, "topic": "hello" });
+    assert_eq!(app.post("/rooms/settings", edit).await.0, StatusCode::OK);
+    assert!(names(&children(&app, &alice, &server_id, "").await).contains(&"general".to_string()));
+}
+
+#[tokio::test]
+async fn a_warm_cache_lists_joined_rooms_without_reading_state() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let channel_id = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": server_id })).await;
+    create(&app, &alice, "/rooms/create", json!({
+        "name": "lounge",
+        "parent_space_id": server_id,
+        "channel_type": "voice",
+        "user_limit": 4,
+    }))
+    .await;
+    create(&app, &alice, "/rooms/create", json!({ "name": "secret", "parent_space_id": server_id, "private": true })).await;
+    let list = || app.authed(&alice, Method::GET, "/rooms", None);
+
+    let (status, cold) = list().await;
+    assert_eq!(status, StatusCode::OK, "{}", cold);
+    assert_eq!(cold["rooms"].as_array().unwrap().len(), 4);
+    let reads = app.homeserver.inspect(|hs| hs.state_reads);
+    let (_, warm) = list().await;
+    assert_eq!(warm, cold);
+    assert_eq!(app.homeserver.inspect(|hs| hs.state_reads), reads);
+
+    // renaming a channel drops its summary, and only that one is read again
+    let edit = json!({ "room_id": channel_id, "name": "lobby" });
+    assert_eq!(app.authed(&alice, Method::POST, "/rooms/settings", Some(edit)).await.0, StatusCode::OK);
+    let reads = app.homeserver.inspect(|hs| hs.state_reads);
+    let (_, renamed) = list().await;
+    let renamed_channel = renamed["rooms"].as_array().unwrap().iter().find(|r| r["room_id"] == channel_id.as_str()).unwrap();
+    assert_eq!(renamed_channel["name"], "lobby");
+    assert_eq!(app.homeserver.inspect(|hs| hs.state_reads), reads + 1);
+}
+
+async fn join(app: &TestApp, user: &TestUser, room_id: &str) {
+    let (status, body) = app.post("/rooms/join", json!({ "access_token": user.access_token, "room_id_or_alias": room_id })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+fn member_counts(body: &Value) -> Vec<Value> {
+    body["children"].as_array().unwrap().iter().map(|c| c["member_count"].clone()).collect()
+}
+
+#[tokio::test]
+async fn children_come_from_the_hierarchy_with_member_counts() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+    let nested_id = create(&app, &alice, "/rooms/create", json!({ "name": "nested", "parent_space_id": category_id })).await;
+    let general_id = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": server_id })).await;
+    let reorder = json!({ "access_token": alice.access_token, "space_id": server_id, "child_room_ids": [category_id, general_id] });
+    assert_eq!(app.post("/rooms/reorder", reorder).await.0, StatusCode::OK);
+    join(&app, &bob, &server_id).await;
+    join(&app, &carol, &general_id).await;
+
+    let all = children(&app, &bob, &server_id, "&max_depth=2").await;
+    assert_eq!(names(&all), ["talk", "general", "nested"]);
+    assert_eq!(member_counts(&all), [json!(2), json!(3), json!(2)]);
+    let nested = &all["children"][2];
+    assert_eq!(nested["room_id"], nested_id.as_str());
+    assert_eq!(nested["parent_id"], category_id.as_str());
+
+    // depth 1 stops at the category
+    assert_eq!(names(&children(&app, &bob, &server_id, "").await), ["talk", "general"]);
+}
+
+#[tokio::test]
+async fn children_are_walked_without_the_hierarchy() {
+    let app = TestApp::new().await;
+    app.homeserver.state.lock().unwrap().hierarchy_unsupported = true;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+    create(&app, &alice, "/rooms/create", json!({ "name": "nested", "parent_space_id": category_id })).await;
+    let general_id = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": server_id })).await;
+    let reorder = json!({ "access_token": alice.access_token, "space_id": server_id, "child_room_ids": [category_id, general_id] });
+    assert_eq!(app.post("/rooms/reorder", reorder).await.0, StatusCode::OK);
+
+    let all = children(&app, &alice, &server_id, "&max_depth=2").await;
+    assert_eq!(names(&all), ["talk", "general", "nested"]);
+    // member counts only come with the hierarchy
+    assert!(member_counts(&all).iter().all(Value::is_null));
+}
+
+/// a matrix client signed in as `user`, for writing state the api would refuse
+fn raw_client(app: &TestApp, user: &TestUser) -> MatrixClient {
+    let mut matrix = app.state.matrix();
+    matrix.access_token = Some(user.access_token.clone());
+    matrix
+}
+
+#[tokio::test]
+async fn a_space_cant_be_added_below_itself() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+
+    for (space_id, child_id) in [(&category_id, &server_id), (&server_id, &server_id)] {
+        let add = json!({ "access_token": alice.access_token, "space_id": space_id, "child_room_id": child_id });
+        let (status, body) = app.post("/rooms/add_child", add).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+        assert_eq!(body["errcode"], "AGORA_HIERARCHY_CYCLE");
+    }
+    // and a category can't hold a category of its own
+    let nested = json!({ "access_token": alice.access_token, "name": "deeper", "parent_space_id": category_id });
+    let (status, body) = app.post("/rooms/category/create", nested).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["errcode"], "AGORA_HIERARCHY_TOO_DEEP");
+}
+
+#[tokio::test]
+async fn joining_and_leaving_a_cyclic_server_terminates() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, "/rooms/create", json!({ "name": "Crew", "is_space": true })).await;
+    let category_id = create(&app, &alice, "/rooms/category/create", json!({ "name": "talk", "parent_space_id": server_id })).await;
+    let channel_id = create(&app, &alice, "/rooms/create", json!({ "name": "general", "parent_space_id": category_id })).await;
+    // the loop matrix itself allows: the category lists the server as its child
+    raw_client(&app, &alice)
+        .send_state_event(category_id.clone(), "m.space.child".to_string(), server_id.clone(), json!({ "via": ["localhost"] }))
+        .await
+        .unwrap();
+
+    let invite = json!({ "access_token": alice.access_token, "room_id": server_id, "user_id": bob.user_id });
+    app.post("/rooms/invite", invite).await;
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": server_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    let joined = |room_id: &str| app.homeserver.inspect(|hs| hs.rooms[room_id].membership(&bob.user_id) == Some("join"));
+    assert!(joined(&server_id) && joined(&category_id) && joined(&channel_id));
+
+    let tree = children(&app, &bob, &server_id, "&max_depth=4").await;
+    // everything below the server once, and the server itself not again
+    assert_eq!(names(&tree), ["talk", "general"]);
+
+    let leave = json!({ "access_token": bob.access_token, "room_id": server_id });
+    assert_eq!(app.post("/rooms/leave", leave).await.0, StatusCode::OK);
+    assert!(!joined(&server_id) && !joined(&category_id) && !joined(&channel_id));
+}
+
+#[tokio::test]
+async fn deep_nests_are_walked_to_the_depth_cap() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let matrix = raw_client(&app, &alice);
+    let mut spaces = Vec::new();
+    for i in 0..8 {
+        spaces.push(create(&app, &alice, "/rooms/create", json!({ "name": format!("level {}", i), "is_space": true })).await);
+    }
+    for pair in spaces.windows(2) {
+        matrix
+            .send_state_event(pair[0].clone(), "m.space.child".to_string(), pair[1].clone(), json!({ "via": ["localhost"] }))
+            .await
+            .unwrap();
+    }
+
+    let nodes = hierarchy::walk_space(&matrix, &spaces[0], hierarchy::max_depth()).await;
+    let reached: Vec<&str> = nodes.iter().map(|n| n.room_id.as_str()).collect();
+    assert_eq!(reached, spaces[..=hierarchy::max_depth()].iter().map(String::as_str).collect::<Vec<_>>());
+    assert!(nodes.iter().enumerate().all(|(depth, node)| node.depth == depth));
+    // a space can't go below one of its own descendants, only the other way round
+    assert!(hierarchy::creates_cycle(&matrix, &spaces[3], &spaces[1]).await);
+    assert!(!hierarchy::creates_cycle(&matrix, &spaces[7], &spaces[0]).await);
+}
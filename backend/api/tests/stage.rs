@@ -0,0 +1,113 @@
+// stage channels: chosen speakers talk, everyone else listens and can raise a hand
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a stage owned by alice that bob has joined
+async fn stage(app: &TestApp) -> (TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (status, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "town-hall", "channel_type": "stage" }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    (alice, bob, room_id)
+}
+
+async fn can_publish(app: &TestApp, user: &TestUser, room_id: &str) -> bool {
+    let (status, body) = app
+        .post("/voice/token", json!({ "access_token": user.access_token, "room_id": room_id, "user_id": user.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let key = jsonwebtoken::DecodingKey::from_secret(b"devsecret_agora_local_development_key_32chars");
+    let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+    let claims = jsonwebtoken::decode::<Value>(body["token"].as_str().unwrap(), &key, &validation).unwrap().claims;
+    claims["video"]["canPublish"].as_bool().unwrap()
+}
+
+async fn speakers(app: &TestApp, user: &TestUser, room_id: &str, target: &TestUser, action: &str) -> (StatusCode, Value) {
+    app.post("/voice/stage/speakers", json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "user_id": target.user_id,
+        "action": action,
+    }))
+    .await
+}
+
+#[tokio::test]
+async fn the_audience_listens_until_made_a_speaker() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = stage(&app).await;
+
+    // listen-only by default, even for the owner
+    assert!(!can_publish(&app, &bob, &room_id).await);
+    assert!(!can_publish(&app, &alice, &room_id).await);
+
+    let (status, _) = app.post("/voice/stage/request", json!({ "access_token": bob.access_token, "room_id": room_id })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, info) = app
+        .get(&format!("/voice/stage?access_token={}&room_id={}", alice.access_token, enc(&room_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(info, json!({ "speakers": [], "requests": [bob.user_id], "audience_count": 0 }));
+
+    let (status, info) = speakers(&app, &alice, &room_id, &bob, "add").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(info["speakers"], json!([bob.user_id]));
+    assert_eq!(info["requests"], json!([]));
+    assert!(can_publish(&app, &bob, &room_id).await);
+
+    // checked again on every token
+    let (status, _) = speakers(&app, &alice, &room_id, &bob, "remove").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!can_publish(&app, &bob, &room_id).await);
+}
+
+#[tokio::test]
+async fn only_moderators_choose_speakers() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = stage(&app).await;
+
+    let (status, body) = speakers(&app, &bob, &room_id, &bob, "add").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    // members may write their raised hands, not the speaker list
+    app.homeserver.inspect(|hs| {
+        let power = &hs.rooms[&room_id].state[&("m.room.power_levels".to_string(), String::new())]["content"];
+        assert_eq!(power["events"]["agora.stage.requests"], 0);
+        assert!(power["events"].get("agora.stage.speakers").is_none());
+    });
+
+    let (status, _) = speakers(&app, &alice, &room_id, &alice, "add").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(can_publish(&app, &alice, &room_id).await);
+}
+
+#[tokio::test]
+async fn stage_endpoints_need_a_stage() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "lounge", "channel_type": "voice" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+
+    let (status, body) = app.post("/voice/stage/request", json!({ "access_token": alice.access_token, "room_id": room_id })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+
+    let (status, _) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "odd", "channel_type": "hologram" }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
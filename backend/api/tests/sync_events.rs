@@ -0,0 +1,142 @@
+// /sync's typed arrays: calls and raids arrive parsed instead of as message
+// fallbacks, other agora.* events pass through, and messages keep their content
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+/// a channel alice owns and bob has joined
+async fn channel(app: &TestApp) -> (TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    (alice, bob, room_id)
+}
+
+async fn sync(app: &TestApp, user: &TestUser) -> Value {
+    let (status, sync) = app.get(&format!("/sync?access_token={}", user.access_token)).await;
+    assert_eq!(status, StatusCode::OK);
+    sync
+}
+
+fn without_ids(mut entry: Value) -> Value {
+    assert!(entry["event_id"].is_string());
+    assert!(entry["timestamp"].is_i64());
+    let fields = entry.as_object_mut().unwrap();
+    fields.remove("event_id");
+    fields.remove("timestamp");
+    entry
+}
+
+#[tokio::test]
+async fn a_raid_round_trips_with_its_countdown() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = channel(&app).await;
+
+    let (status, _) = app
+        .post("/rooms/raid", json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "raider_id": alice.user_id,
+            "raider_name": "Alice",
+            "message": "let's go!!!",
+            "countdown": 12,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let sync = sync(&app, &bob).await;
+    assert_eq!(sync["messages"], json!([]));
+    assert_eq!(without_ids(sync["raids"][0].clone()), json!({
+        "room_id": room_id,
+        "sender": alice.user_id,
+        "raider_id": alice.user_id,
+        "raider_name": "Alice",
+        "message": "let's go!!!",
+        "countdown": 12,
+    }));
+    assert_eq!(sync["raids"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn a_call_ring_round_trips() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = channel(&app).await;
+
+    let (status, _) = app
+        .post("/voice/call", json!({
+            "access_token": alice.access_token,
+            "room_id": room_id,
+            "action": "ring",
+            "call_id": "c1",
+            "from_user_id": alice.user_id,
+            "display_name": "Alice",
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let sync = sync(&app, &bob).await;
+    assert_eq!(sync["messages"], json!([]));
+    assert_eq!(without_ids(sync["calls"][0].clone()), json!({
+        "room_id": room_id,
+        "sender": alice.user_id,
+        "call_id": "c1",
+        "action": "ring",
+        "from": alice.user_id,
+        "display_name": "Alice",
+        "participants": [alice.user_id],
+    }));
+}
+
+#[tokio::test]
+async fn custom_events_pass_through_and_messages_keep_their_content() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = channel(&app).await;
+
+    app.post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": room_id, "content": "**hi**" })).await;
+    {
+        let mut hs = app.homeserver.state.lock().unwrap();
+        let moved = json!({
+            "type": "agora.voice.moved",
+            "sender": alice.user_id,
+            "content": { "user_id": bob.user_id, "to_room_id": "!elsewhere:localhost" },
+            "event_id": "$moved",
+            "origin_server_ts": 1,
+        });
+        // state events are not timeline traffic
+        let state = json!({
+            "type": "agora.room.type",
+            "sender": alice.user_id,
+            "state_key": "",
+            "content": { "type": "voice" },
+            "event_id": "$state",
+        });
+        hs.timeline.push((room_id.clone(), moved));
+        hs.timeline.push((room_id.clone(), state));
+    }
+
+    let sync = sync(&app, &bob).await;
+    assert_eq!(sync["events"], json!([{
+        "room_id": room_id,
+        "event_id": "$moved",
+        "type": "agora.voice.moved",
+        "sender": alice.user_id,
+        "timestamp": 1,
+        "content": { "user_id": bob.user_id, "to_room_id": "!elsewhere:localhost" },
+    }]));
+
+    let message = &sync["messages"][0];
+    assert_eq!(message["msgtype"], "m.text");
+    assert!(message["event_id"].is_string());
+    assert_eq!(message["raw_content"]["body"], "**hi**");
+    assert_eq!(message["raw_content"]["msgtype"], "m.text");
+    assert_eq!(sync["calls"], json!([]));
+    assert_eq!(sync["raids"], json!([]));
+}
@@ -0,0 +1,134 @@
+// limited timelines: what a sync skipped is backfilled, or reported as a gap
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn room(app: &TestApp, owner: &TestUser, member: &TestUser, name: &str) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": owner.access_token, "name": name })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    app.post("/rooms/join", json!({ "access_token": member.access_token, "room_id_or_alias": room_id })).await;
+    room_id
+}
+
+async fn send(app: &TestApp, user: &TestUser, room_id: &str, count: usize) {
+    for i in 1..=count {
+        let body = json!({ "access_token": user.access_token, "room_id": room_id, "content": format!("message {}", i) });
+        assert_eq!(app.post("/rooms/send", body).await.0, StatusCode::OK);
+    }
+}
+
+async fn sync(app: &TestApp, user: &TestUser, since: Option<&str>) -> Value {
+    let mut uri = format!("/sync?access_token={}", user.access_token);
+    if let Some(since) = since {
+        uri.push_str(&format!("&since={}", since));
+    }
+    let (status, sync) = app.get(&uri).await;
+    assert_eq!(status, StatusCode::OK);
+    sync
+}
+
+fn contents<'a>(sync: &'a Value, room_id: &str) -> Vec<&'a str> {
+    sync["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|m| m["room_id"] == room_id)
+        .map(|m| m["content"].as_str().unwrap())
+        .collect()
+}
+
+fn limit_timelines(app: &TestApp, limit: usize) {
+    app.homeserver.state.lock().unwrap().sync_timeline_limit = Some(limit);
+}
+
+#[tokio::test]
+async fn a_limited_timeline_is_backfilled_in_order() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let general = room(&app, &alice, &bob, "general").await;
+    app.post("/rooms/send", json!({ "access_token": alice.access_token, "room_id": general, "content": "seen" })).await;
+    limit_timelines(&app, 3);
+    let since = sync(&app, &bob, None).await["next_batch"].as_str().unwrap().to_string();
+
+    send(&app, &alice, &general, 8).await;
+    let caught_up = sync(&app, &bob, Some(&since)).await;
+    // back to `since` and no further: "seen" isn't sent again
+    let expected: Vec<String> = (1..=8).map(|i| format!("message {}", i)).collect();
+    assert_eq!(contents(&caught_up, &general), expected);
+    assert_eq!(caught_up["gaps"], json!([]));
+}
+
+#[tokio::test]
+async fn the_first_sync_is_not_backfilled() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let general = room(&app, &alice, &bob, "general").await;
+    send(&app, &alice, &general, 5).await;
+    limit_timelines(&app, 2);
+
+    let first = sync(&app, &bob, None).await;
+    assert_eq!(contents(&first, &general), ["message 4", "message 5"]);
+    assert_eq!(first["gaps"], json!([]));
+}
+
+#[tokio::test]
+async fn more_than_the_backfill_cap_leaves_a_gap_to_page_from() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let general = room(&app, &alice, &bob, "general").await;
+    limit_timelines(&app, 5);
+    let since = sync(&app, &bob, None).await["next_batch"].as_str().unwrap().to_string();
+
+    send(&app, &alice, &general, 110).await;
+    let caught_up = sync(&app, &bob, Some(&since)).await;
+    let delivered = contents(&caught_up, &general);
+    assert_eq!(delivered.len(), 105);
+    assert_eq!((delivered[0], delivered[104]), ("message 6", "message 110"));
+
+    let gaps = caught_up["gaps"].as_array().unwrap();
+    assert_eq!(gaps.len(), 1);
+    assert_eq!((&gaps[0]["room_id"], &gaps[0]["limited"]), (&json!(general), &json!(true)));
+    let prev_batch = gaps[0]["prev_batch"].as_str().unwrap();
+    let (_, page) = app
+        .get(&format!(
+            "/rooms/messages?access_token={}&room_id={}&limit=10&from={}",
+            bob.access_token,
+            enc(&general),
+            enc(prev_batch)
+        ))
+        .await;
+    let older: Vec<&str> = page["messages"].as_array().unwrap().iter().map(|m| m["body"].as_str().unwrap()).collect();
+    // back to the join that came before them
+    assert_eq!(older[..5], ["message 5", "message 4", "message 3", "message 2", "message 1"]);
+}
+
+#[tokio::test]
+async fn past_the_room_cap_limited_rooms_are_reported_as_gaps() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let mut rooms = Vec::new();
+    for i in 0..12 {
+        rooms.push(room(&app, &alice, &bob, &format!("room {}", i)).await);
+    }
+    limit_timelines(&app, 1);
+    let since = sync(&app, &bob, None).await["next_batch"].as_str().unwrap().to_string();
+    for room_id in &rooms {
+        send(&app, &alice, room_id, 3).await;
+    }
+
+    let caught_up = sync(&app, &bob, Some(&since)).await;
+    let complete = rooms.iter().filter(|room_id| contents(&caught_up, room_id).len() == 3).count();
+    assert_eq!(complete, 10);
+    let gaps = caught_up["gaps"].as_array().unwrap();
+    assert_eq!(gaps.len(), 2);
+    for gap in gaps {
+        assert_eq!(contents(&caught_up, gap["room_id"].as_str().unwrap()), ["message 3"]);
+    }
+}
@@ -0,0 +1,144 @@
+// channel language hints and translate_to: a mock libretranslate answering
+// batches, the redis cache in front of it, and batches that fail on their own
+
+mod common;
+
+use agora_api::translate::TranslateConfig;
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// "translates" each string by tagging it with the target language, and
+/// fails any batch with a "garbled" string in it
+struct Translator;
+
+impl Respond for Translator {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        let strings: Vec<&str> = body["q"].as_array().unwrap().iter().map(|q| q.as_str().unwrap()).collect();
+        if strings.iter().any(|q| q.contains("garbled")) {
+            return ResponseTemplate::new(500);
+        }
+        let lang = body["target"].as_str().unwrap();
+        let translated: Vec<String> = strings.iter().map(|q| format!("[{}] {}", lang, q)).collect();
+        ResponseTemplate::new(200).set_body_json(json!({ "translatedText": translated }))
+    }
+}
+
+async fn translating_app(rate_limit: u64) -> (TestApp, MockServer) {
+    let backend = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/translate")).respond_with(Translator).mount(&backend).await;
+    let api_url = backend.uri();
+    let app = TestApp::with_config(|state| {
+        state.translate = Some(TranslateConfig { api_url, api_key: None, rate_limit });
+    })
+    .await;
+    (app, backend)
+}
+
+async fn channel_with(app: &TestApp, alice: &TestUser, bodies: &[String]) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    for body in bodies {
+        let send = json!({ "access_token": alice.access_token, "room_id": room_id, "content": body });
+        assert_eq!(app.post("/rooms/send", send).await.0, StatusCode::OK);
+    }
+    room_id
+}
+
+async fn history(app: &TestApp, user: &TestUser, room_id: &str, extra: &str) -> Vec<Value> {
+    let url = format!("/rooms/messages?access_token={}&room_id={}&dir=f{}", user.access_token, enc(room_id), extra);
+    let (status, page) = app.get(&url).await;
+    assert_eq!(status, StatusCode::OK, "{}", page);
+    page["messages"].as_array().unwrap().clone()
+}
+
+async fn backend_calls(backend: &MockServer) -> usize {
+    backend.received_requests().await.unwrap().len()
+}
+
+#[tokio::test]
+async fn translations_are_attached_and_cached() {
+    let (app, backend) = translating_app(30).await;
+    let alice = app.register("alice").await;
+    let room_id = channel_with(&app, &alice, &["hallo".to_string(), "tschüss".to_string()]).await;
+
+    let messages = history(&app, &alice, &room_id, "&translate_to=en").await;
+    assert_eq!(messages[0]["body"], "hallo");
+    assert_eq!(messages[0]["translated_body"], "[en] hallo");
+    assert_eq!(messages[1]["translated_body"], "[en] tschüss");
+    assert_eq!(backend_calls(&backend).await, 1);
+
+    // the same events again come out of the cache, in sync's shape too
+    let (_, sync) = app
+        .get(&format!("/sync/room?access_token={}&room_id={}&translate_to=en", alice.access_token, enc(&room_id)))
+        .await;
+    let translated: Vec<&str> = sync["messages"].as_array().unwrap().iter().filter_map(|m| m["translated_body"].as_str()).collect();
+    assert_eq!(translated, ["[en] hallo", "[en] tschüss"]);
+    assert_eq!(backend_calls(&backend).await, 1);
+
+    // another language is another translation
+    assert_eq!(history(&app, &alice, &room_id, "&translate_to=fr").await[0]["translated_body"], "[fr] hallo");
+    assert_eq!(backend_calls(&backend).await, 2);
+    // without translate_to, or with a tag that isn't one, nothing is attached or asked for
+    for extra in ["", "&translate_to=not%20a%20tag"] {
+        assert!(history(&app, &alice, &room_id, extra).await.iter().all(|m| m.get("translated_body").is_none()));
+    }
+    assert_eq!(backend_calls(&backend).await, 2);
+}
+
+#[tokio::test]
+async fn a_failed_batch_only_loses_its_own_messages() {
+    let (app, backend) = translating_app(30).await;
+    let alice = app.register("alice").await;
+    // the first batch of 25 has the garbled message in it, the second doesn't
+    let mut bodies: Vec<String> = (1..=30).map(|i| format!("message {}", i)).collect();
+    bodies[3] = "garbled".to_string();
+    let room_id = channel_with(&app, &alice, &bodies).await;
+
+    let messages = history(&app, &alice, &room_id, "&translate_to=en").await;
+    assert_eq!(messages.len(), 30);
+    assert_eq!(backend_calls(&backend).await, 2);
+    assert!(messages[..25].iter().all(|m| m.get("translated_body").is_none()));
+    assert!(messages[25..].iter().all(|m| m["translated_body"] == format!("[en] {}", m["body"].as_str().unwrap())));
+    // the originals are untouched either way
+    assert_eq!(messages[3]["body"], "garbled");
+}
+
+#[tokio::test]
+async fn translation_calls_are_rate_limited_per_session() {
+    let (app, backend) = translating_app(1).await;
+    let alice = app.register("alice").await;
+    let room_id = channel_with(&app, &alice, &["eins".to_string()]).await;
+
+    assert_eq!(history(&app, &alice, &room_id, "&translate_to=en").await[0]["translated_body"], "[en] eins");
+    // the cache still answers, but a new language needs a call the limit won't allow
+    assert_eq!(history(&app, &alice, &room_id, "&translate_to=en").await[0]["translated_body"], "[en] eins");
+    assert!(history(&app, &alice, &room_id, "&translate_to=fr").await[0].get("translated_body").is_none());
+    assert_eq!(backend_calls(&backend).await, 1);
+}
+
+#[tokio::test]
+async fn without_a_backend_nothing_is_translated() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let room_id = channel_with(&app, &alice, &["hola".to_string()]).await;
+    assert!(history(&app, &alice, &room_id, "&translate_to=en").await[0].get("translated_body").is_none());
+}
+
+#[tokio::test]
+async fn channels_carry_a_checked_language_tag() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let create = |language: &str| json!({ "access_token": alice.access_token, "name": "geral", "language": language });
+    let (status, body) = app.post("/rooms/create", create("english!")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+
+    let (status, room) = app.post("/rooms/create", create("pt-BR")).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    let (_, rooms) = app.get(&format!("/rooms?access_token={}", alice.access_token)).await;
+    let geral = rooms["rooms"].as_array().unwrap().iter().find(|r| r["room_id"] == room["room_id"]).unwrap();
+    assert_eq!(geral["language"], "pt-BR");
+}
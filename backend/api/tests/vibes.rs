@@ -0,0 +1,121 @@
+// custom vibes: servers define their own ambiences next to the built-ins
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn set_vibes(app: &TestApp, user: &TestUser, server_id: &str, vibes: Value, revision: u64) -> (StatusCode, Value) {
+    app.post("/servers/vibes", json!({
+        "access_token": user.access_token,
+        "server_id": server_id,
+        "vibes": vibes,
+        "revision": revision,
+    }))
+    .await
+}
+
+async fn set_vibe(app: &TestApp, user: &TestUser, room_id: &str, vibe: &str) -> StatusCode {
+    let body = json!({ "access_token": user.access_token, "room_id": room_id, "vibe": vibe, "user_id": user.user_id });
+    app.post("/voice/vibe", body).await.0
+}
+
+async fn vibe(app: &TestApp, user: &TestUser, room_id: &str) -> Value {
+    let (status, body) = app
+        .get(&format!("/voice/vibe?access_token={}&room_id={}", user.access_token, enc(room_id)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    body
+}
+
+fn waves() -> Value {
+    json!({ "id": "waves", "name": "ocean waves", "icon": "🌊", "audio_mxc_url": "mxc://localhost/waves" })
+}
+
+#[tokio::test]
+async fn moderators_manage_the_server_list() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id })).await;
+
+    let url = format!("/servers/vibes?access_token={}&server_id={}", bob.access_token, enc(&server_id));
+    let (status, body) = app.get(&url).await;
+    assert_eq!(status, StatusCode::OK);
+    let builtin: Vec<&str> = body["builtin"].as_array().unwrap().iter().map(|v| v["id"].as_str().unwrap()).collect();
+    assert_eq!(builtin, ["none", "rain", "lofi", "campfire", "space"]);
+    assert_eq!(body["vibes"], json!([]));
+    assert_eq!(body["revision"], 0);
+
+    let (status, body) = set_vibes(&app, &bob, &server_id, json!([waves()]), 0).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    // built-in ids are taken, and a custom vibe needs its audio
+    for invalid in [
+        json!([{ "id": "rain", "name": "my rain", "audio_mxc_url": "mxc://localhost/rain" }]),
+        json!([{ "id": "hum", "name": "hum" }]),
+        json!([{ "id": "Hum!", "name": "hum", "audio_mxc_url": "mxc://localhost/hum" }]),
+        json!([waves(), waves()]),
+    ] {
+        let (status, body) = set_vibes(&app, &alice, &server_id, invalid, 0).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errcode"], "M_INVALID_PARAM");
+    }
+
+    let (status, body) = set_vibes(&app, &alice, &server_id, json!([waves()]), 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["revision"], 1);
+    let (_, body) = app.get(&url).await;
+    assert_eq!(body["vibes"], json!([waves()]));
+
+    // an edit based on the old list is refused with the current one
+    let (status, body) = set_vibes(&app, &alice, &server_id, json!([]), 0).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["vibes"], json!([waves()]));
+}
+
+#[tokio::test]
+async fn channels_use_their_servers_vibes() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    let (_, category) = app
+        .post("/rooms/category/create", json!({ "access_token": alice.access_token, "name": "voice", "parent_space_id": server_id }))
+        .await;
+    let category_id = category["room_id"].as_str().unwrap().to_string();
+    let lounge = create(&app, &alice, json!({ "name": "lounge", "channel_type": "voice", "parent_space_id": category_id })).await;
+    let elsewhere = create(&app, &alice, json!({ "name": "elsewhere", "channel_type": "voice" })).await;
+
+    assert_eq!(vibe(&app, &alice, &lounge).await["vibe"]["id"], "none");
+    let (status, _) = set_vibes(&app, &alice, &server_id, json!([waves()]), 0).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // found two levels up, and returned whole
+    assert_eq!(set_vibe(&app, &alice, &lounge, "waves").await, StatusCode::OK);
+    assert_eq!(vibe(&app, &alice, &lounge).await, json!({ "vibe": waves(), "set_by": alice.user_id }));
+
+    assert_eq!(set_vibe(&app, &alice, &lounge, "rain").await, StatusCode::OK);
+    let rain = vibe(&app, &alice, &lounge).await;
+    assert_eq!(rain["vibe"], json!({ "id": "rain", "name": "rain", "icon": "🌧️", "audio_mxc_url": null }));
+
+    assert_eq!(set_vibe(&app, &alice, &lounge, "thunder").await, StatusCode::BAD_REQUEST);
+    // a channel outside the server doesn't get its vibes
+    assert_eq!(set_vibe(&app, &alice, &elsewhere, "waves").await, StatusCode::BAD_REQUEST);
+
+    // once the server drops a vibe, channels playing it fall silent
+    assert_eq!(set_vibe(&app, &alice, &lounge, "waves").await, StatusCode::OK);
+    let (status, _) = set_vibes(&app, &alice, &server_id, json!([]), 1).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(vibe(&app, &alice, &lounge).await["vibe"]["id"], "none");
+}
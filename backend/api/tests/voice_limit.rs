@@ -0,0 +1,98 @@
+// voice channel user limits: full channels refuse tokens, moderators exempt
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::json;
+use wiremock::matchers::{any, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn token(app: &TestApp, user: &TestUser, room_id: &str) -> (StatusCode, serde_json::Value) {
+    app.post("/voice/token", json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "user_id": user.user_id,
+    }))
+    .await
+}
+
+// livekit is configured through the environment, so everything that needs it
+// runs in this one test
+#[tokio::test]
+async fn full_voice_channels_refuse_members_but_not_moderators() {
+    let livekit = MockServer::start().await;
+    Mock::given(path("/twirp/livekit.RoomService/ListParticipants"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "participants": [{ "identity": "@carol:localhost" }, { "identity": "@dave:localhost" }],
+        })))
+        .mount(&livekit)
+        .await;
+    Mock::given(any()).respond_with(ResponseTemplate::new(200).set_body_json(json!({}))).mount(&livekit).await;
+    std::env::set_var("LIVEKIT_HTTP_URL", livekit.uri());
+
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (status, room) = app
+        .post("/rooms/create", json!({
+            "access_token": alice.access_token,
+            "name": "lounge",
+            "channel_type": "voice",
+            "user_limit": 2,
+        }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    app.homeserver.inspect(|hs| {
+        let room_type = &hs.rooms[&room_id].state[&("agora.room.type".to_string(), String::new())]["content"];
+        assert_eq!(room_type, &json!({ "type": "voice", "user_limit": 2 }));
+    });
+
+    // the sidebar sees how full it is
+    let (_, rooms) = app.get(&format!("/rooms?access_token={}", bob.access_token)).await;
+    assert_eq!(rooms["rooms"][0]["user_limit"], 2);
+    assert_eq!(rooms["rooms"][0]["participant_count"], 2);
+
+    let (status, body) = token(&app, &bob, &room_id).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "room_full");
+    assert_eq!(body["user_limit"], 2);
+
+    let (status, body) = token(&app, &alice, &room_id).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // lifting the limit lets bob in
+    let (status, room) = app
+        .post("/rooms/settings", json!({ "access_token": alice.access_token, "room_id": room_id, "user_limit": 0 }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(room["user_limit"].is_null());
+    let (status, _) = token(&app, &bob, &room_id).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn only_voice_channels_take_a_limit() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general", "user_limit": 5 }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    app.homeserver.inspect(|hs| {
+        let room_type = &hs.rooms[&room_id].state[&("agora.room.type".to_string(), String::new())]["content"];
+        assert!(room_type.get("user_limit").is_none());
+    });
+
+    let (status, body) = app
+        .post("/rooms/settings", json!({ "access_token": alice.access_token, "room_id": room_id, "user_limit": 5 }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["errcode"], "M_INVALID_PARAM");
+}
@@ -0,0 +1,116 @@
+// moderators muting and disconnecting people in voice channels
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+use wiremock::matchers::{body_partial_json, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn moderate(app: &TestApp, user: &TestUser, room_id: &str, target: &str, action: &str) -> (StatusCode, Value) {
+    app.post("/voice/moderate", json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "target_user_id": target,
+        "action": action,
+    }))
+    .await
+}
+
+fn not_found() -> ResponseTemplate {
+    ResponseTemplate::new(404).set_body_json(json!({ "code": "not_found", "msg": "participant not found" }))
+}
+
+/// the calls livekit received for one twirp method
+async fn calls(livekit: &MockServer, method: &str) -> Vec<Value> {
+    livekit
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.url.path() == format!("/twirp/livekit.RoomService/{}", method))
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect()
+}
+
+// livekit is configured through the environment, so everything that needs it
+// runs in this one test
+#[tokio::test]
+async fn moderators_mute_and_disconnect_through_livekit() {
+    let livekit = MockServer::start().await;
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "lounge", "channel_type": "voice" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    Mock::given(path("/twirp/livekit.RoomService/GetParticipant"))
+        .and(body_partial_json(json!({ "identity": bob.user_id })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "identity": bob.user_id,
+            "tracks": [
+                { "sid": "TR_mic", "type": "AUDIO", "source": "MICROPHONE" },
+                { "sid": "TR_cam", "type": "VIDEO", "source": "CAMERA" },
+            ],
+        })))
+        .mount(&livekit)
+        .await;
+    Mock::given(path("/twirp/livekit.RoomService/GetParticipant")).respond_with(not_found()).mount(&livekit).await;
+    Mock::given(path("/twirp/livekit.RoomService/RemoveParticipant"))
+        .and(body_partial_json(json!({ "identity": bob.user_id })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .mount(&livekit)
+        .await;
+    Mock::given(path("/twirp/livekit.RoomService/RemoveParticipant")).respond_with(not_found()).mount(&livekit).await;
+    Mock::given(path("/twirp/livekit.RoomService/MutePublishedTrack"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .mount(&livekit)
+        .await;
+    std::env::set_var("LIVEKIT_HTTP_URL", livekit.uri());
+
+    // members can't moderate
+    let (status, body) = moderate(&app, &bob, &room_id, &alice.user_id, "mute").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+    assert!(calls(&livekit, "GetParticipant").await.is_empty());
+
+    // only the microphone is muted
+    let (status, _) = moderate(&app, &alice, &room_id, &bob.user_id, "mute").await;
+    assert_eq!(status, StatusCode::OK);
+    let muted = calls(&livekit, "MutePublishedTrack").await;
+    assert_eq!(muted.len(), 1);
+    assert_eq!(muted[0]["identity"], bob.user_id.as_str());
+    assert_eq!(muted[0]["track_sid"], "TR_mic");
+    assert_eq!(muted[0]["muted"], true);
+
+    let (status, _) = moderate(&app, &alice, &room_id, &bob.user_id, "disconnect").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(calls(&livekit, "RemoveParticipant").await.len(), 1);
+
+    // each action is recorded in the room
+    let recorded: Vec<Value> = app.homeserver.inspect(|hs| {
+        hs.timeline
+            .iter()
+            .filter(|(room, event)| *room == room_id && event["type"] == "agora.voice.moderation")
+            .map(|(_, event)| event.clone())
+            .collect()
+    });
+    assert_eq!(recorded.len(), 2);
+    assert_eq!(recorded[0]["sender"], alice.user_id.as_str());
+    assert_eq!(recorded[0]["content"], json!({ "action": "mute", "user_id": bob.user_id }));
+    assert_eq!(recorded[1]["content"]["action"], "disconnect");
+
+    // someone who isn't in the call
+    let (status, body) = moderate(&app, &alice, &room_id, "@carol:localhost", "disconnect").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["errcode"], "M_NOT_FOUND");
+    let (status, _) = moderate(&app, &alice, &room_id, "@carol:localhost", "unmute").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
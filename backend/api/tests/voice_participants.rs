@@ -0,0 +1,71 @@
+// /voice/participants read from livekit's ListParticipants, when the webhooks
+// aren't tracking the room
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp};
+use serde_json::json;
+use wiremock::matchers::{any, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+#[tokio::test]
+async fn participants_are_described_from_their_tracks() {
+    let livekit = MockServer::start().await;
+    Mock::given(path("/twirp/livekit.RoomService/ListParticipants"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "participants": [
+                {
+                    "sid": "PA_1",
+                    "identity": "@alice:localhost",
+                    "name": "Alice",
+                    "metadata": "",
+                    "joined_at": "1700000000",
+                    "tracks": [
+                        { "sid": "TR_mic", "type": "AUDIO", "source": "MICROPHONE", "muted": true },
+                        { "sid": "TR_screen", "type": "VIDEO", "source": "SCREEN_SHARE" },
+                    ],
+                },
+                // an older livekit: camelCase, numbers, no track sources, zero values left out
+                {
+                    "identity": "@bob:localhost",
+                    "joinedAt": 1700000100,
+                    "tracks": [{ "sid": "TR_mic" }, { "sid": "TR_cam", "type": "VIDEO" }],
+                },
+                { "identity": "@carol:localhost" },
+            ],
+        })))
+        .mount(&livekit)
+        .await;
+    Mock::given(any()).respond_with(ResponseTemplate::new(404)).mount(&livekit).await;
+    std::env::set_var("LIVEKIT_HTTP_URL", livekit.uri());
+
+    let app = TestApp::new().await;
+    let (status, body) = app.get(&format!("/voice/participants?room_name={}", enc("lounge_localhost"))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["participants"], json!([
+        {
+            "user_id": "@alice:localhost",
+            "display_name": "Alice",
+            "is_muted": true,
+            "is_screen_sharing": true,
+            "is_camera_on": false,
+            "joined_at": 1_700_000_000,
+        },
+        {
+            "user_id": "@bob:localhost",
+            "display_name": "@bob:localhost",
+            "is_muted": false,
+            "is_screen_sharing": false,
+            "is_camera_on": true,
+            "joined_at": 1_700_000_100,
+        },
+        {
+            "user_id": "@carol:localhost",
+            "display_name": "@carol:localhost",
+            "is_muted": false,
+            "is_screen_sharing": false,
+            "is_camera_on": false,
+        },
+    ]));
+}
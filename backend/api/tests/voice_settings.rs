@@ -0,0 +1,95 @@
+// per-channel voice settings: stored on the channel, handed out with tokens as
+// publisher hints, and pushed to livekit as the room's limit and metadata
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp};
+use serde_json::{json, Value};
+use wiremock::matchers::{any, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// the calls livekit received for one twirp method
+async fn calls(livekit: &MockServer, method: &str) -> Vec<Value> {
+    livekit
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.url.path() == format!("/twirp/livekit.RoomService/{}", method))
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect()
+}
+
+fn metadata(call: &Value) -> Value {
+    serde_json::from_str(call["metadata"].as_str().unwrap()).unwrap()
+}
+
+// livekit is configured through the environment, so everything that needs it
+// runs in this one test
+#[tokio::test]
+async fn settings_reach_clients_and_livekit() {
+    let livekit = MockServer::start().await;
+    // nobody has connected yet: livekit has no room to update
+    Mock::given(path("/twirp/livekit.RoomService/UpdateRoomMetadata"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({ "code": "not_found", "msg": "room not found" })))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&livekit)
+        .await;
+    Mock::given(path("/twirp/livekit.RoomService/ListRooms"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "rooms": [] })))
+        .with_priority(1)
+        .mount(&livekit)
+        .await;
+    Mock::given(any()).respond_with(ResponseTemplate::new(200).set_body_json(json!({}))).with_priority(2).mount(&livekit).await;
+    std::env::set_var("LIVEKIT_HTTP_URL", livekit.uri());
+
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "lounge", "channel_type": "voice" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let livekit_room = room_id.trim_start_matches('!').replace([':', '.'], "_");
+
+    let hints = json!({ "max_bitrate_kbps": 64, "dtx": true, "red": false, "preferred_codec": "opus", "max_participants": 5 });
+    let set = |settings: &Value| json!({ "access_token": alice.access_token, "room_id": room_id, "settings": settings });
+    assert_eq!(app.post("/voice/settings", set(&hints)).await.0, StatusCode::OK);
+    // no room running, so nothing to put the limit on yet
+    assert_eq!(calls(&livekit, "UpdateRoomMetadata").await.len(), 1);
+    assert!(calls(&livekit, "CreateRoom").await.is_empty());
+    let (_, stored) = app.get(&format!("/voice/settings?access_token={}&room_id={}", alice.access_token, enc(&room_id))).await;
+    assert_eq!(stored, hints);
+
+    // the first token creates the room with the limit, and carries the hints
+    let (status, token) = app.post("/voice/token", json!({ "access_token": alice.access_token, "room_id": room_id })).await;
+    assert_eq!(status, StatusCode::OK, "{}", token);
+    assert_eq!(token["settings"], hints);
+    let created = calls(&livekit, "CreateRoom").await;
+    assert_eq!(created.len(), 1);
+    assert_eq!(created[0]["name"], livekit_room.as_str());
+    assert_eq!(created[0]["max_participants"], 5);
+    assert_eq!(metadata(&created[0]), json!({ "matrix_room_id": room_id, "voice_settings": hints }));
+
+    // with the room running, a new limit goes to it straight away
+    let tighter = json!({ "max_bitrate_kbps": 32, "max_participants": 3 });
+    assert_eq!(app.post("/voice/settings", set(&tighter)).await.0, StatusCode::OK);
+    let updated = calls(&livekit, "UpdateRoomMetadata").await;
+    assert_eq!(updated[1]["room"], livekit_room.as_str());
+    assert_eq!(metadata(&updated[1])["voice_settings"]["max_bitrate_kbps"], 32);
+    let created = calls(&livekit, "CreateRoom").await;
+    assert_eq!(created.len(), 2);
+    assert_eq!(created[1]["max_participants"], 3);
+
+    // hints outside what the codecs do are refused, and nothing is sent
+    let silly = json!({ "max_bitrate_kbps": 9000 });
+    assert_eq!(app.post("/voice/settings", set(&silly)).await.0, StatusCode::BAD_REQUEST);
+    let unknown = json!({ "preferred_codec": "mp3" });
+    assert_eq!(app.post("/voice/settings", set(&unknown)).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(calls(&livekit, "UpdateRoomMetadata").await.len(), 2);
+
+    // every call carries an admin token
+    let requests = livekit.received_requests().await.unwrap();
+    assert!(requests.iter().all(|r| r.headers.get("authorization").is_some_and(|h| h.to_str().unwrap().starts_with("Bearer "))));
+}
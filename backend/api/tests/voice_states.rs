@@ -0,0 +1,131 @@
+// voice states: who is in each voice channel of a server, for the sidebar
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use wiremock::matchers::{body_partial_json, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+/// the livekit room name the api uses for a matrix room
+fn room_name(room_id: &str) -> String {
+    room_id.trim_start_matches('!').replace([':', '.'], "_")
+}
+
+/// what the webhooks would have recorded for someone in a room
+fn track(app: &TestApp, room_id: &str, identity: &str, name: &str, muted: bool) {
+    let mut store = app.redis.lock().unwrap();
+    let key = format!("voice:{}", room_name(room_id));
+    let mut hash: serde_json::Map<String, Value> =
+        store.get(&key).map(|h| serde_json::from_str(h).unwrap()).unwrap_or_default();
+    let metadata = json!({ "name": name, "joined_at": 1_700_000_000, "muted": muted });
+    hash.insert(identity.to_string(), metadata.to_string().into());
+    store.insert(key, Value::Object(hash).to_string());
+}
+
+async fn states(app: &TestApp, user: &TestUser, space_id: &str) -> (StatusCode, Value) {
+    app.get(&format!("/voice/states?access_token={}&space_id={}", user.access_token, enc(space_id))).await
+}
+
+// livekit is configured through the environment, so everything that needs it
+// runs in this one test
+#[tokio::test]
+async fn every_voice_channel_lists_who_is_in_it() {
+    let livekit = MockServer::start().await;
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+    create(&app, &alice, json!({ "name": "general", "parent_space_id": server_id })).await;
+    let lounge = create(&app, &alice, json!({ "name": "lounge", "channel_type": "voice", "parent_space_id": server_id })).await;
+    let stage = create(&app, &alice, json!({ "name": "town hall", "channel_type": "stage", "parent_space_id": server_id })).await;
+    let empty = create(&app, &alice, json!({ "name": "quiet", "channel_type": "voice", "parent_space_id": server_id })).await;
+
+    track(&app, &lounge, &alice.user_id, "alice's laptop", false);
+    track(&app, &lounge, &bob.user_id, "bob", true);
+    // the stage isn't tracked: livekit is asked about it, and only about it
+    // and the empty room
+    Mock::given(path("/twirp/livekit.RoomService/ListRooms"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "rooms": [{ "name": room_name(&stage) }] })))
+        .mount(&livekit)
+        .await;
+    Mock::given(path("/twirp/livekit.RoomService/ListParticipants"))
+        .and(body_partial_json(json!({ "room": room_name(&stage) })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "participants": [{
+                "identity": "@carol:localhost",
+                "name": "Carol",
+                "tracks": [{ "sid": "TR_mic", "type": "AUDIO", "muted": true }],
+            }],
+        })))
+        .mount(&livekit)
+        .await;
+    std::env::set_var("LIVEKIT_HTTP_URL", livekit.uri());
+
+    let (status, body) = states(&app, &alice, &server_id).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let channels = body["channels"].as_array().unwrap();
+    let channel = |room_id: &str| channels.iter().find(|c| c["room_id"] == room_id).unwrap()["participants"].clone();
+    assert_eq!(channels.len(), 3);
+    assert_eq!(channel(&lounge), json!([
+        { "user_id": alice.user_id, "display_name": "alice", "muted": false },
+        { "user_id": bob.user_id, "display_name": "bob", "muted": true },
+    ]));
+    // carol has no matrix profile here, so livekit's name is used
+    assert_eq!(channel(&stage), json!([{ "user_id": "@carol:localhost", "display_name": "Carol", "muted": true }]));
+    assert_eq!(channel(&empty), json!([]));
+
+    let list_rooms: Vec<Value> = livekit
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|r| r.url.path().ends_with("/ListRooms"))
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect();
+    assert_eq!(list_rooms.len(), 1);
+    let mut asked = list_rooms[0]["names"].as_array().unwrap().clone();
+    asked.sort_by_key(|n| n.as_str().unwrap().to_string());
+    let mut expected = vec![json!(room_name(&stage)), json!(room_name(&empty))];
+    expected.sort_by_key(|n| n.as_str().unwrap().to_string());
+    assert_eq!(asked, expected);
+
+    // polling again is served from the cache
+    let lookups = app.homeserver.inspect(|hs| hs.profile_lookups);
+    track(&app, &empty, &bob.user_id, "bob", false);
+    let (_, again) = states(&app, &alice, &server_id).await;
+    assert_eq!(again, body);
+    assert_eq!(livekit.received_requests().await.unwrap().len(), 2);
+
+    // once it expires, names still come from the process cache
+    app.redis.lock().unwrap().remove(&format!("voice_states:{}", server_id));
+    let (_, fresh) = states(&app, &alice, &server_id).await;
+    let bob_in_empty = fresh["channels"].as_array().unwrap().iter().find(|c| c["room_id"] == empty.as_str()).unwrap();
+    assert_eq!(bob_in_empty["participants"][0]["display_name"], "bob");
+    assert_eq!(app.homeserver.inspect(|hs| hs.profile_lookups), lookups);
+}
+
+#[tokio::test]
+async fn only_members_of_the_space_see_it() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let mallory = app.register("mallory").await;
+    let server_id = create(&app, &alice, json!({ "name": "Test Server", "is_space": true })).await;
+
+    let (status, body) = states(&app, &mallory, &server_id).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    let (status, body) = states(&app, &alice, &server_id).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "space_id": server_id, "channels": [] }));
+}
@@ -0,0 +1,121 @@
+// voice tokens: only for members, with grants that follow the room's power levels
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use serde_json::{json, Value};
+
+async fn token(app: &TestApp, user: &TestUser, room_id: &str) -> (StatusCode, Value) {
+    app.post("/voice/token", json!({
+        "access_token": user.access_token,
+        "room_id": room_id,
+        "user_id": user.user_id,
+    }))
+    .await
+}
+
+/// the livekit video grant of an issued token
+async fn grant(app: &TestApp, user: &TestUser, room_id: &str) -> Value {
+    let (status, body) = token(app, user, room_id).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let key = jsonwebtoken::DecodingKey::from_secret(b"devsecret_agora_local_development_key_32chars");
+    let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+    let claims = jsonwebtoken::decode::<Value>(body["token"].as_str().unwrap(), &key, &validation).unwrap().claims;
+    assert_eq!(claims["sub"], user.user_id.as_str());
+    claims["video"].clone()
+}
+
+/// a voice channel owned by alice that bob has joined
+async fn voice_channel(app: &TestApp) -> (TestUser, TestUser, String) {
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let (_, room) = app
+        .post("/rooms/create", json!({ "access_token": alice.access_token, "name": "lounge", "channel_type": "voice" }))
+        .await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let (status, _) = app
+        .post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": room_id }))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    (alice, bob, room_id)
+}
+
+fn edit_power_levels(app: &TestApp, room_id: &str, edit: impl FnOnce(&mut Value)) {
+    let mut hs = app.homeserver.state.lock().unwrap();
+    let event = hs.rooms.get_mut(room_id).unwrap().state.get_mut(&("m.room.power_levels".to_string(), String::new())).unwrap();
+    edit(&mut event["content"]);
+}
+
+#[tokio::test]
+async fn members_get_publishing_tokens() {
+    let app = TestApp::new().await;
+    let (_, bob, room_id) = voice_channel(&app).await;
+
+    let video = grant(&app, &bob, &room_id).await;
+    assert_eq!(video["roomJoin"], true);
+    assert_eq!(video["canPublish"], true);
+    assert_eq!(video["canSubscribe"], true);
+    assert!(video.get("canPublishSources").is_none());
+}
+
+#[tokio::test]
+async fn tokens_need_a_valid_session_and_membership() {
+    let app = TestApp::new().await;
+    let (alice, _, room_id) = voice_channel(&app).await;
+    let carol = app.register("carol").await;
+
+    let (status, body) = token(&app, &carol, &room_id).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    // the identity comes from the token, whatever user_id says
+    let (status, body) = app
+        .post("/voice/token", json!({ "access_token": carol.access_token, "room_id": room_id, "user_id": alice.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+
+    let (status, _) = app
+        .post("/voice/token", json!({ "access_token": "nope", "room_id": room_id, "user_id": alice.user_id }))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn members_below_the_posting_level_only_listen() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = voice_channel(&app).await;
+    edit_power_levels(&app, &room_id, |power| power["events_default"] = json!(10));
+
+    let video = grant(&app, &bob, &room_id).await;
+    assert_eq!(video["canPublish"], false);
+    assert_eq!(video["canSubscribe"], true);
+    assert!(video.get("canPublishSources").is_none());
+
+    assert_eq!(grant(&app, &alice, &room_id).await["canPublish"], true);
+}
+
+#[tokio::test]
+async fn screen_sharing_can_need_more_power() {
+    let app = TestApp::new().await;
+    let (alice, bob, room_id) = voice_channel(&app).await;
+    edit_power_levels(&app, &room_id, |power| power["events"]["agora.voice.screen_share"] = json!(50));
+
+    let video = grant(&app, &bob, &room_id).await;
+    assert_eq!(video["canPublish"], true);
+    assert_eq!(video["canPublishSources"], json!(["camera", "microphone"]));
+
+    assert!(grant(&app, &alice, &room_id).await.get("canPublishSources").is_none());
+}
+
+#[tokio::test]
+async fn muted_members_are_refused() {
+    let app = TestApp::new().await;
+    let (_, bob, room_id) = voice_channel(&app).await;
+    app.homeserver.state.lock().unwrap().rooms.get_mut(&room_id).unwrap().set_power(&bob.user_id, -1);
+
+    let (status, body) = token(&app, &bob, &room_id).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["errcode"], "M_FORBIDDEN");
+}
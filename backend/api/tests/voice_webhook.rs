@@ -0,0 +1,210 @@
+// livekit webhooks: who is in voice, tracked in redis and pushed to websockets
+
+mod common;
+
+use agora_api::app_state::{ConnectionQueue, QueueItem, WsEvent};
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{enc, TestApp};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use tower::ServiceExt;
+
+const ROOM: &str = "abc_localhost";
+
+/// sign a body the way livekit does, with the development key and secret
+fn sign(body: &str) -> String {
+    let claims = json!({
+        "iss": "devkey",
+        "exp": chrono::Utc::now().timestamp() + 300,
+        "sha256": STANDARD.encode(Sha256::digest(body.as_bytes())),
+    });
+    let key = jsonwebtoken::EncodingKey::from_secret(b"devsecret_agora_local_development_key_32chars");
+    jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+}
+
+async fn deliver(app: &TestApp, body: &str, authorization: &str) -> StatusCode {
+    let request = Request::post("/voice/webhook")
+        .header("content-type", "application/webhook+json")
+        .header("authorization", authorization)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.router.clone().oneshot(request).await.unwrap().status()
+}
+
+async fn webhook(app: &TestApp, event: &str, identity: Option<&str>) {
+    let mut body = json!({
+        "event": event,
+        "room": { "name": ROOM, "metadata": json!({ "matrix_room_id": "!abc:localhost" }).to_string() },
+    });
+    if let Some(identity) = identity {
+        body["participant"] = json!({ "identity": identity, "name": "someone", "joinedAt": "1700000000" });
+    }
+    let body = body.to_string();
+    assert_eq!(deliver(app, &body, &sign(&body)).await, StatusCode::OK);
+}
+
+async fn participants(app: &TestApp) -> Value {
+    let (status, body) = app.get(&format!("/voice/participants?room_name={}", enc(ROOM))).await;
+    assert_eq!(status, StatusCode::OK);
+    body["participants"].clone()
+}
+
+async fn participant_ids(app: &TestApp) -> Vec<String> {
+    let participants = participants(app).await;
+    participants.as_array().unwrap().iter().map(|p| p["user_id"].as_str().unwrap().to_string()).collect()
+}
+
+async fn next_event(queue: &ConnectionQueue) -> Value {
+    match queue.pop().await {
+        QueueItem::Event(event @ WsEvent::Voice(_)) => serde_json::to_value(event).unwrap(),
+        _ => panic!("expected a voice event"),
+    }
+}
+
+#[tokio::test]
+async fn joins_and_leaves_are_tracked_and_broadcast() {
+    let app = TestApp::new().await;
+    // a connection from someone in the channel; voice goes no further
+    let queue = ConnectionQueue::new(16).with_joined_rooms(HashSet::from(["!abc:localhost".to_string()]));
+    let (_, queue) = app.state.register_queue(queue);
+    let (_, outsider) = app.state.register_connection(16);
+
+    webhook(&app, "participant_joined", Some("@alice:localhost")).await;
+    webhook(&app, "participant_joined", Some("@bob:localhost")).await;
+
+    let tracked: Value = serde_json::from_str(&app.redis.lock().unwrap()[&format!("voice:{}", ROOM)]).unwrap();
+    let alice: Value = serde_json::from_str(tracked["@alice:localhost"].as_str().unwrap()).unwrap();
+    assert_eq!(alice, json!({ "name": "someone", "joined_at": 1_700_000_000, "muted": false }));
+    // read from redis — livekit itself isn't reachable in tests
+    assert_eq!(participant_ids(&app).await, ["@alice:localhost", "@bob:localhost"]);
+    assert_eq!(participants(&app).await[0], json!({
+        "user_id": "@alice:localhost",
+        "display_name": "someone",
+        "is_muted": false,
+        "is_screen_sharing": false,
+        "is_camera_on": false,
+        "joined_at": 1_700_000_000,
+    }));
+
+    assert_eq!(next_event(&queue).await, json!({
+        "type": "voice",
+        "action": "joined",
+        "room_id": "!abc:localhost",
+        "room_name": ROOM,
+        "user_id": "@alice:localhost",
+    }));
+    assert_eq!(next_event(&queue).await["user_id"], "@bob:localhost");
+    assert_eq!(outsider.depth(), 0);
+
+    webhook(&app, "participant_left", Some("@alice:localhost")).await;
+    assert_eq!(participant_ids(&app).await, ["@bob:localhost"]);
+    let left = next_event(&queue).await;
+    assert_eq!(left["action"], "left");
+    assert_eq!(left["user_id"], "@alice:localhost");
+
+    // whoever is still there when the room ends leaves with it
+    webhook(&app, "room_finished", None).await;
+    assert!(!app.redis.lock().unwrap().contains_key(&format!("voice:{}", ROOM)));
+    assert!(participant_ids(&app).await.is_empty());
+    let left = next_event(&queue).await;
+    assert_eq!(left["action"], "left");
+    assert_eq!(left["user_id"], "@bob:localhost");
+}
+
+#[tokio::test]
+async fn unsigned_or_tampered_webhooks_are_rejected() {
+    let app = TestApp::new().await;
+    let body = json!({
+        "event": "participant_joined",
+        "room": { "name": ROOM },
+        "participant": { "identity": "@mallory:localhost" },
+    })
+    .to_string();
+
+    assert_eq!(deliver(&app, &body, "").await, StatusCode::UNAUTHORIZED);
+
+    // signed for a different body
+    let signature = sign(&body.replace("mallory", "alice"));
+    assert_eq!(deliver(&app, &body, &signature).await, StatusCode::UNAUTHORIZED);
+
+    // signed with someone else's secret
+    let claims = json!({
+        "iss": "devkey",
+        "exp": chrono::Utc::now().timestamp() + 300,
+        "sha256": STANDARD.encode(Sha256::digest(body.as_bytes())),
+    });
+    let key = jsonwebtoken::EncodingKey::from_secret(b"not-our-secret");
+    let forged = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap();
+    assert_eq!(deliver(&app, &body, &forged).await, StatusCode::UNAUTHORIZED);
+
+    assert!(app.redis.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn microphone_mutes_are_recorded() {
+    let app = TestApp::new().await;
+    webhook(&app, "participant_joined", Some("@alice:localhost")).await;
+
+    let muted = |app: &TestApp| {
+        let tracked: Value = serde_json::from_str(&app.redis.lock().unwrap()[&format!("voice:{}", ROOM)]).unwrap();
+        let alice: Value = serde_json::from_str(tracked["@alice:localhost"].as_str().unwrap()).unwrap();
+        alice["muted"].clone()
+    };
+    let track_event = |event: &str, kind: &str| {
+        json!({
+            "event": event,
+            "room": { "name": ROOM },
+            "participant": { "identity": "@alice:localhost" },
+            "track": { "sid": "TR_1", "type": kind },
+        })
+        .to_string()
+    };
+    assert_eq!(muted(&app), false);
+
+    // a camera going dark isn't a mute
+    let body = track_event("track_muted", "VIDEO");
+    assert_eq!(deliver(&app, &body, &sign(&body)).await, StatusCode::OK);
+    assert_eq!(muted(&app), false);
+
+    let body = track_event("track_muted", "AUDIO");
+    assert_eq!(deliver(&app, &body, &sign(&body)).await, StatusCode::OK);
+    assert_eq!(muted(&app), true);
+
+    let body = track_event("track_unmuted", "AUDIO");
+    assert_eq!(deliver(&app, &body, &sign(&body)).await, StatusCode::OK);
+    assert_eq!(muted(&app), false);
+}
+
+#[tokio::test]
+async fn cameras_and_screen_shares_are_recorded() {
+    let app = TestApp::new().await;
+    webhook(&app, "participant_joined", Some("@alice:localhost")).await;
+    let track_event = |event: &str, track: Value| {
+        json!({
+            "event": event,
+            "room": { "name": ROOM },
+            "participant": { "identity": "@alice:localhost" },
+            "track": track,
+        })
+        .to_string()
+    };
+    let camera = json!({ "sid": "TR_cam", "type": "VIDEO", "source": "CAMERA" });
+    let screen = json!({ "sid": "TR_screen", "type": "VIDEO", "source": "SCREEN_SHARE" });
+
+    for body in [track_event("track_published", camera.clone()), track_event("track_published", screen.clone())] {
+        assert_eq!(deliver(&app, &body, &sign(&body)).await, StatusCode::OK);
+    }
+    let alice = participants(&app).await[0].clone();
+    assert_eq!((alice["is_camera_on"].clone(), alice["is_screen_sharing"].clone()), (json!(true), json!(true)));
+
+    // a paused camera is off; a stopped share is over
+    for body in [track_event("track_muted", camera), track_event("track_unpublished", screen)] {
+        assert_eq!(deliver(&app, &body, &sign(&body)).await, StatusCode::OK);
+    }
+    let alice = participants(&app).await[0].clone();
+    assert_eq!((alice["is_camera_on"].clone(), alice["is_screen_sharing"].clone()), (json!(false), json!(false)));
+    assert_eq!(alice["is_muted"], false);
+}
@@ -0,0 +1,153 @@
+// incoming webhooks: managing them per server, and posting through the url
+// in both the plain and the discord shape. these need postgres, like flows.rs.
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{enc, TestApp, TestUser};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+/// the service account, the way main.rs sets it up
+async fn with_poster(app: &TestApp) -> TestUser {
+    let poster = app.register("webhooks").await;
+    let mut client = app.state.matrix();
+    client.access_token = Some(poster.access_token.clone());
+    *app.state.webhook_client.write().await = Some(client);
+    poster
+}
+
+async fn create(app: &TestApp, owner: &TestUser, body: Value) -> String {
+    let mut body = body;
+    body["access_token"] = json!(owner.access_token);
+    let (status, room) = app.post("/rooms/create", body).await;
+    assert_eq!(status, StatusCode::OK, "{}", room);
+    room["room_id"].as_str().unwrap().to_string()
+}
+
+async fn create_webhook(app: &TestApp, user: &TestUser, server_id: &str, room_id: &str, name: &str) -> (StatusCode, Value) {
+    let body = json!({ "access_token": user.access_token, "server_id": server_id, "room_id": room_id, "name": name });
+    app.post("/servers/webhooks/create", body).await
+}
+
+/// message contents the webhook account posted in `room_id`
+fn posts(app: &TestApp, poster: &TestUser, room_id: &str) -> Vec<Value> {
+    app.homeserver.inspect(|hs| {
+        hs.timeline
+            .iter()
+            .filter(|(id, e)| id == room_id && e["sender"] == poster.user_id.as_str() && e["type"] == "m.room.message")
+            .map(|(_, e)| e["content"].clone())
+            .collect()
+    })
+}
+
+#[sqlx::test]
+async fn webhooks_post_into_their_channel(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let poster = with_poster(&app).await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let builds = create(&app, &alice, json!({ "name": "builds", "parent_space_id": server_id })).await;
+    let elsewhere = create(&app, &alice, json!({ "name": "elsewhere" })).await;
+    app.post("/rooms/join", json!({ "access_token": bob.access_token, "room_id_or_alias": server_id })).await;
+
+    assert_eq!(create_webhook(&app, &bob, &server_id, &builds, "CI").await.0, StatusCode::FORBIDDEN);
+    assert_eq!(create_webhook(&app, &alice, &server_id, &elsewhere, "CI").await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(create_webhook(&app, &alice, &server_id, &builds, " ").await.0, StatusCode::BAD_REQUEST);
+    let (status, webhook) = create_webhook(&app, &alice, &server_id, &builds, "CI").await;
+    assert_eq!(status, StatusCode::OK, "{}", webhook);
+    let url = webhook["url"].as_str().unwrap().to_string();
+    let webhook_id = webhook["id"].as_str().unwrap().to_string();
+
+    // the plain shape, then discord's
+    let (status, body) = app.post(&url, json!({ "content": "build #12 passed" })).await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+    let embed = json!({ "title": "Release v2", "description": "notes", "color": 5814783 });
+    let (status, body) = app.post(&url, json!({ "username": "GitHub", "content": "", "embeds": [embed] })).await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+    assert_eq!(app.post(&url, json!({ "content": " " })).await.0, StatusCode::BAD_REQUEST);
+
+    let posted = posts(&app, &poster, &builds);
+    assert_eq!(posted.len(), 2);
+    assert_eq!(posted[0]["body"], "build #12 passed");
+    assert_eq!(posted[0]["agora.webhook"]["id"], webhook_id.as_str());
+    assert_eq!(posted[0]["agora.webhook"]["name"], "CI");
+    assert_eq!(posted[1]["body"], "Release v2");
+    assert_eq!(posted[1]["agora.webhook"]["name"], "GitHub");
+    assert_eq!(posted[1]["agora.embeds"][0]["color"], 5814783);
+
+    // a wrong token is as good as no webhook
+    let wrong = format!("/webhooks/{}/not-the-token", webhook_id);
+    assert_eq!(app.post(&wrong, json!({ "content": "hi" })).await.0, StatusCode::NOT_FOUND);
+
+    // listed without the token, until deleted
+    let list_uri = format!("/servers/webhooks?access_token={}&server_id={}", alice.access_token, enc(&server_id));
+    let (status, listed) = app.get(&list_uri).await;
+    assert_eq!(status, StatusCode::OK, "{}", listed);
+    assert_eq!(listed["webhooks"].as_array().unwrap().len(), 1);
+    assert!(listed["webhooks"][0]["token"].is_null());
+    assert!(listed["webhooks"][0]["last_used_at"].is_i64());
+
+    let delete = json!({ "access_token": alice.access_token, "server_id": server_id, "webhook_id": webhook_id });
+    assert_eq!(app.request(Method::DELETE, "/servers/webhooks", Some(delete.clone())).await.0, StatusCode::OK);
+    assert_eq!(app.request(Method::DELETE, "/servers/webhooks", Some(delete)).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(app.post(&url, json!({ "content": "hi" })).await.0, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn webhooks_are_rate_limited(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    with_poster(&app).await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let builds = create(&app, &alice, json!({ "name": "builds", "parent_space_id": server_id })).await;
+    let (_, webhook) = create_webhook(&app, &alice, &server_id, &builds, "CI").await;
+    let url = webhook["url"].as_str().unwrap();
+
+    for _ in 0..5 {
+        assert_eq!(app.post(url, json!({ "content": "spam" })).await.0, StatusCode::NO_CONTENT);
+    }
+    let (status, body) = app.post(url, json!({ "content": "spam" })).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["errcode"], "M_LIMIT_EXCEEDED");
+}
+
+#[sqlx::test]
+async fn embeds_keep_only_safe_text_and_web_links(pool: PgPool) {
+    let app = TestApp::with_db(pool).await;
+    let poster = with_poster(&app).await;
+    let alice = app.register("alice").await;
+    let server_id = create(&app, &alice, json!({ "name": "Crew", "is_space": true })).await;
+    let builds = create(&app, &alice, json!({ "name": "builds", "parent_space_id": server_id })).await;
+    let (_, webhook) = create_webhook(&app, &alice, &server_id, &builds, "CI").await;
+    let url = webhook["url"].as_str().unwrap();
+
+    let embed = json!({
+        "title": "<script>alert(1)</script>Deploy <b>done</b>",
+        "description": "<img src=\"https://evil.example/x.png\" onerror=\"alert(1)\">see <a href=\"javascript:alert(1)\">logs</a>",
+        "url": "javascript:alert(1)",
+        "color": 0x1_000_000,
+        "author": { "name": "ci<iframe></iframe>", "url": "https://ci.example/runs/1", "icon_url": "data:image/png;base64,AAAA" },
+        "footer": { "text": "took 3m", "icon_url": "file:///etc/passwd" },
+        "image": { "url": "http://ci.example/graph.png" },
+        "thumbnail": { "url": "//ci.example/thumb.png" },
+        "fields": [{ "name": "branch", "value": "<em>main</em>", "inline": true, "extra": "dropped" }],
+        "provider": { "name": "not a field clients show" },
+    });
+    let (status, body) = app.post(url, json!({ "embeds": [embed] })).await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+
+    let posted = &posts(&app, &poster, &builds)[0]["agora.embeds"][0];
+    assert_eq!(
+        *posted,
+        json!({
+            "title": "Deploy <b>done</b>",
+            "description": "<img>see <a rel=\"noopener noreferrer\">logs</a>",
+            "author": { "name": "ci", "url": "https://ci.example/runs/1" },
+            "footer": { "text": "took 3m" },
+            "image": { "url": "http://ci.example/graph.png" },
+            "fields": [{ "name": "branch", "value": "<em>main</em>", "inline": true }],
+        })
+    );
+}
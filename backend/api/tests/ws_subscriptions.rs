@@ -0,0 +1,196 @@
+// what a websocket connection subscribes to: typing in the rooms it's in,
+// voice narrowed to those rooms, presence of the users it names
+
+mod common;
+
+use agora_api::app_state::{ConnectionQueue, QueueItem, Subscription, VoiceEvent, WsEvent};
+use axum::http::StatusCode;
+use common::{TestApp, TestUser};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// a room alice made and bob joined
+async fn room(app: &TestApp, alice: &TestUser, bob: &TestUser) -> String {
+    let (_, room) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "general" })).await;
+    let room_id = room["room_id"].as_str().unwrap().to_string();
+    let join = json!({ "access_token": bob.access_token, "room_id_or_alias": room_id });
+    assert_eq!(app.post("/rooms/join", join).await.0, StatusCode::OK);
+    room_id
+}
+
+async fn typing(app: &TestApp, user: &TestUser, room_id: &str, typing: bool) -> StatusCode {
+    app.post("/rooms/typing", json!({ "access_token": user.access_token, "room_id": room_id, "typing": typing }))
+        .await
+        .0
+}
+
+async fn next_event(queue: &ConnectionQueue) -> Value {
+    match queue.pop().await {
+        QueueItem::Event(event) => serde_json::to_value(event).unwrap(),
+        QueueItem::Gap { .. } => panic!("expected an event"),
+    }
+}
+
+fn voice(room_id: &str) -> WsEvent {
+    WsEvent::Voice(VoiceEvent {
+        kind: "voice",
+        action: "joined",
+        room_id: Some(room_id.to_string()),
+        room_name: room_id.to_string(),
+        user_id: "@someone:localhost".to_string(),
+    })
+}
+
+#[tokio::test]
+async fn typing_goes_to_connections_subscribed_to_the_room() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let room_id = room(&app, &alice, &bob).await;
+    let subscribed = ConnectionQueue::new(16);
+    subscribed.subscribe(Subscription { room_ids: Some(HashSet::from([room_id.clone()])), ..Subscription::default() });
+    let (_, subscribed) = app.state.register_queue(subscribed);
+    let (_, everything) = app.state.register_connection(16);
+    let member = ConnectionQueue::new(16).with_joined_rooms(HashSet::from([room_id.clone(), "!lounge:localhost".to_string()]));
+    let (_, member) = app.state.register_queue(member);
+
+    assert_eq!(typing(&app, &bob, &room_id, true).await, StatusCode::OK);
+    assert_eq!(next_event(&subscribed).await, json!({ "type": "typing", "room_id": room_id, "user_ids": [bob.user_id] }));
+    typing(&app, &alice, &room_id, true).await;
+    assert_eq!(next_event(&subscribed).await["user_ids"], json!([alice.user_id, bob.user_id]));
+    typing(&app, &bob, &room_id, false).await;
+    assert_eq!(next_event(&subscribed).await["user_ids"], json!([alice.user_id]));
+    // without a subscription to the room, no typing
+    assert_eq!(everything.depth(), 0);
+    // the homeserver hears it too, for other matrix clients
+    let typers = app.homeserver.inspect(|hs| hs.typing[&room_id].clone());
+    assert_eq!(typers.into_iter().collect::<Vec<_>>(), std::slice::from_ref(&alice.user_id));
+
+    // only from inside the room
+    assert_eq!(typing(&app, &carol, &room_id, true).await, StatusCode::FORBIDDEN);
+    assert_eq!(subscribed.depth(), 0);
+
+    // voice is narrowed to the subscribed rooms, and otherwise to the joined ones
+    app.state.publish(voice("!elsewhere:localhost"));
+    app.state.publish(voice(&room_id));
+    app.state.publish(voice("!lounge:localhost"));
+    assert_eq!(next_event(&subscribed).await["room_id"], room_id.as_str());
+    assert_eq!(subscribed.depth(), 0);
+    assert_eq!(next_event(&member).await["room_id"], room_id.as_str());
+    assert_eq!(next_event(&member).await["room_id"], "!lounge:localhost");
+    assert_eq!(member.depth(), 0);
+    assert_eq!(everything.depth(), 0);
+}
+
+/// the router on a real port, since a websocket upgrade needs a connection
+async fn serve(app: &TestApp) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = app.router.clone();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    format!("ws://{}", addr)
+}
+
+async fn next_frame(socket: &mut Socket) -> Value {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(10), socket.next())
+            .await
+            .expect("a frame within 10s")
+            .expect("socket still open")
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// presence frames up to and including the snapshot's end marker
+async fn snapshot(socket: &mut Socket) -> Vec<Value> {
+    let mut presence = Vec::new();
+    loop {
+        let frame = next_frame(socket).await;
+        if frame["snapshot_complete"] == true {
+            return presence;
+        }
+        presence.extend(frame.as_array().unwrap().iter().cloned());
+    }
+}
+
+#[tokio::test]
+async fn a_socket_subscribes_with_a_message() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let room_id = room(&app, &alice, &bob).await;
+    let (_, private) = app.post("/rooms/create", json!({ "access_token": alice.access_token, "name": "secret" })).await;
+    for user in [&alice, &carol] {
+        let body = json!({ "access_token": user.access_token, "user_id": user.user_id, "presence": "online" });
+        assert_eq!(app.post("/presence/set", body).await.0, StatusCode::OK);
+    }
+
+    let base = serve(&app).await;
+    let (mut socket, _) = connect_async(format!("{}/ws/presence?access_token={}", base, bob.access_token)).await.unwrap();
+    assert_eq!(snapshot(&mut socket).await.len(), 2);
+
+    let subscribe = json!({ "type": "subscribe", "user_ids": [alice.user_id], "room_ids": [room_id, private["room_id"]] });
+    socket.send(Message::Text(subscribe.to_string())).await.unwrap();
+    assert_eq!(snapshot(&mut socket).await, [json!({ "user_id": alice.user_id, "presence": "online" })]);
+    // bob isn't in the private room, so it's left out
+    assert_eq!(next_frame(&mut socket).await, json!({ "type": "subscribed", "room_ids": [room_id] }));
+
+    typing(&app, &alice, &room_id, true).await;
+    assert_eq!(next_frame(&mut socket).await, json!({ "type": "typing", "room_id": room_id, "user_ids": [alice.user_id] }));
+    typing(&app, &alice, private["room_id"].as_str().unwrap(), true).await;
+    for (user, presence) in [(&carol, "offline"), (&alice, "unavailable")] {
+        let body = json!({ "access_token": user.access_token, "user_id": user.user_id, "presence": presence });
+        app.post("/presence/set", body).await;
+    }
+    assert_eq!(next_frame(&mut socket).await, json!({ "user_id": alice.user_id, "presence": "unavailable" }));
+
+    // subscribing again picks up who's already typing
+    socket.send(Message::Text(json!({ "type": "subscribe", "room_ids": [room_id] }).to_string())).await.unwrap();
+    // and without user_ids, everyone's presence again — carol went offline
+    assert_eq!(snapshot(&mut socket).await, [json!({ "user_id": alice.user_id, "presence": "unavailable" })]);
+    assert_eq!(next_frame(&mut socket).await["type"], "subscribed");
+    assert_eq!(next_frame(&mut socket).await, json!({ "type": "typing", "room_id": room_id, "user_ids": [alice.user_id] }));
+
+    socket.send(Message::Text(json!({ "type": "unsubscribe" }).to_string())).await.unwrap();
+    let error = next_frame(&mut socket).await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["errcode"], "M_BAD_JSON");
+}
+
+#[tokio::test]
+async fn voice_in_a_private_room_stays_with_its_members() {
+    let app = TestApp::new().await;
+    let alice = app.register("alice").await;
+    let bob = app.register("bob").await;
+    let carol = app.register("carol").await;
+    let room_id = room(&app, &alice, &bob).await;
+    let base = serve(&app).await;
+
+    let mut sockets = Vec::new();
+    for user in [&bob, &carol] {
+        let (mut socket, _) = connect_async(format!("{}/ws/presence?access_token={}", base, user.access_token)).await.unwrap();
+        snapshot(&mut socket).await;
+        sockets.push(socket);
+    }
+    app.state.publish(voice(&room_id));
+    // something both hear, so carol's next frame shows what came before it
+    let online = json!({ "access_token": alice.access_token, "user_id": alice.user_id, "presence": "online" });
+    assert_eq!(app.post("/presence/set", online).await.0, StatusCode::OK);
+
+    let [bob_socket, carol_socket] = &mut sockets[..] else { unreachable!() };
+    assert_eq!(next_frame(bob_socket).await["room_id"], room_id.as_str());
+    assert_eq!(next_frame(bob_socket).await["user_id"], alice.user_id.as_str());
+    assert_eq!(next_frame(carol_socket).await, json!({ "user_id": alice.user_id, "presence": "online" }));
+}
@@ -2,7 +2,8 @@
  * shared presence store — websocket edition
  *
  * connects to /ws/presence once on login. the server sends the full snapshot
- * of currently-online users on connect, then pushes every subsequent change
+ * of currently-online users on connect (in chunks, ending with a
+ * snapshot_complete marker), then pushes every subsequent change
  * instantly. components call track(userId) so the store knows which users are
  * interesting (used for the one-shot HTTP fallback when the socket isn't ready
  * yet). the store itself is updated by the websocket, so all components
@@ -12,9 +13,13 @@
 
 import { writable, get } from 'svelte/store';
 
-// map from user_id → presence string ("online" | "offline" | "unavailable")
+// map from user_id → presence string ("online" | "offline" | "unavailable" | "idle" | "dnd")
 export const presenceMap = writable<Record<string, string>>({});
 
+// bumped whenever a friend request arrives or is accepted, so the friends
+// list can refetch without polling
+export const friendEvents = writable(0);
+
 // reference counts — how many components currently care about each user
 const watchCounts = new Map<string, number>();
 
@@ -80,7 +85,27 @@ function connect() {
 
 	socket.onmessage = (ev) => {
 		try {
-			const event = JSON.parse(ev.data) as { user_id: string; presence: string };
+			const data = JSON.parse(ev.data);
+			// the snapshot arrives as arrays of presence frames
+			if (Array.isArray(data)) {
+				presenceMap.update((m) => {
+					const next = { ...m };
+					for (const p of data as { user_id: string; presence: string }[]) next[p.user_id] = p.presence;
+					return next;
+				});
+				return;
+			}
+			if (data.snapshot_complete) return;
+			const event = data as { type?: string; user_id: string; presence: string };
+			if (event.type === 'friend_request_received' || event.type === 'friend_request_accepted') {
+				friendEvents.update((n) => n + 1);
+				return;
+			}
+			if (event.type === 'gap') {
+				// server dropped events because we fell behind — reconnect for a fresh snapshot
+				socket?.close();
+				return;
+			}
 			if (event.user_id && event.presence) {
 				presenceMap.update((m) => ({ ...m, [event.user_id]: event.presence }));
 			}
@@ -122,12 +147,15 @@ async function fetchOnce(userId: string) {
 /** helpers used by components */
 export function presenceDotClass(p: string): string {
 	if (p === 'online') return 'bg-green-500';
-	if (p === 'unavailable') return 'bg-yellow-500';
+	if (p === 'unavailable' || p === 'idle') return 'bg-yellow-500';
+	if (p === 'dnd') return 'bg-red-500';
 	return 'bg-muted-foreground/50';
 }
 
 export function presenceLabel(p: string): string {
 	if (p === 'online') return 'online';
 	if (p === 'unavailable') return 'away';
+	if (p === 'idle') return 'idle';
+	if (p === 'dnd') return 'do not disturb';
 	return 'offline';
 }